@@ -0,0 +1,366 @@
+//! Logic shared by the build.rs scripts in this workspace, mostly figuring out
+//! how to compile C code against Python.
+
+use serde::Deserialize;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+use std::sync::OnceLock;
+
+/// Environment variables that, when set, tell us where the target's headers
+/// are, in which case the host interpreter isn't run at all.
+const CROSS_VARIABLES: &[&str] = &[
+    "FIL_PYTHON_INCLUDE_DIR",
+    "PYO3_CROSS_INCLUDE_DIR",
+    "PYO3_CROSS_LIB_DIR",
+    "PYO3_CROSS_PYTHON_VERSION",
+];
+
+/// Python code that prints everything we need to know, as JSON, so we only
+/// have to run the interpreter once.
+const PROBE_SCRIPT: &str = r#"
+import json, sys, sysconfig
+print(json.dumps({
+    "include": sysconfig.get_path("include"),
+    "platinclude": sysconfig.get_path("platinclude"),
+    "version": "%d.%d" % sys.version_info[:2],
+    "implementation": sys.implementation.name,
+}))
+"#;
+
+/// The build configuration of the Python interpreter we're compiling against.
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+pub struct PythonConfig {
+    /// The interpreter we ran to get this information.
+    #[serde(skip)]
+    pub executable: String,
+    /// The "include" directory, with Python.h.
+    pub include: String,
+    /// The "platinclude" directory, with platform-specific headers.
+    pub platinclude: String,
+    /// Major and minor version, e.g. "3.11".
+    pub version: String,
+    /// E.g. "cpython".
+    pub implementation: String,
+}
+
+impl PythonConfig {
+    /// Parse the output of PROBE_SCRIPT.
+    fn from_probe_output(executable: &str, output: &str) -> Result<Self, String> {
+        let mut config: PythonConfig = serde_json::from_str(output.trim()).map_err(|e| {
+            format!(
+                "Couldn't parse build configuration reported by {} ({}). Set PYO3_PYTHON to a CPython 3.x interpreter.",
+                executable, e
+            )
+        })?;
+        config.executable = executable.to_string();
+        if config.implementation != "cpython" || !config.version.starts_with("3.") {
+            return Err(format!(
+                "{} is {} {}, but Fil needs CPython 3.x. Set PYO3_PYTHON to a CPython 3.x interpreter.",
+                executable, config.implementation, config.version
+            ));
+        }
+        Ok(config)
+    }
+
+    /// Configuration for a Python whose headers are in the given directory,
+    /// without running any interpreter. The version is taken from
+    /// PYO3_CROSS_PYTHON_VERSION if set, otherwise from the directory name
+    /// (e.g. "include/python3.11").
+    fn from_include_dir(include: &Path, version: Option<String>) -> Result<Self, String> {
+        if !include.join("Python.h").exists() {
+            return Err(format!(
+                "{} doesn't contain Python.h; it should be the target Python's include directory.",
+                include.display()
+            ));
+        }
+        let version = match version {
+            Some(version) => version,
+            None => version_from_dir_name(include).ok_or_else(|| {
+                format!(
+                    "Couldn't tell the Python version from {}; set PYO3_CROSS_PYTHON_VERSION, e.g. to 3.11.",
+                    include.display()
+                )
+            })?,
+        };
+        let include = include.to_string_lossy().into_owned();
+        Ok(PythonConfig {
+            executable: String::new(),
+            platinclude: include.clone(),
+            include,
+            version,
+            implementation: "cpython".to_string(),
+        })
+    }
+}
+
+/// Turn e.g. "/usr/include/python3.11" into "3.11".
+fn version_from_dir_name(dir: &Path) -> Option<String> {
+    let name = dir.file_name()?.to_str()?;
+    let version = name.strip_prefix("python")?.trim_end_matches(['d', 'm']);
+    if version.starts_with("3.") {
+        Some(version.to_string())
+    } else {
+        None
+    }
+}
+
+/// Find the include directory that goes with PYO3_CROSS_LIB_DIR, which
+/// typically looks like `<prefix>/lib`, with headers in
+/// `<prefix>/include/python3.X`.
+fn include_dir_for_lib_dir(lib_dir: &Path, version: Option<&str>) -> Result<PathBuf, String> {
+    let include_root = lib_dir
+        .parent()
+        .map(|prefix| prefix.join("include"))
+        .ok_or_else(|| format!("{} has no parent directory", lib_dir.display()))?;
+    let mut candidates: Vec<PathBuf> = std::fs::read_dir(&include_root)
+        .map_err(|e| format!("Couldn't read {}: {}", include_root.display(), e))?
+        .filter_map(|entry| entry.ok().map(|entry| entry.path()))
+        .filter(|path| match (version_from_dir_name(path), version) {
+            (Some(found), Some(wanted)) => found == wanted,
+            (found, None) => found.is_some(),
+            (None, _) => false,
+        })
+        .filter(|path| path.join("Python.h").exists())
+        .collect();
+    candidates.sort();
+    match candidates.len() {
+        1 => Ok(candidates.remove(0)),
+        0 => Err(format!(
+            "Couldn't find Python headers for PYO3_CROSS_LIB_DIR={} in {}; set FIL_PYTHON_INCLUDE_DIR.",
+            lib_dir.display(),
+            include_root.display()
+        )),
+        _ => Err(format!(
+            "Found multiple Python include directories in {}; set PYO3_CROSS_PYTHON_VERSION or FIL_PYTHON_INCLUDE_DIR.",
+            include_root.display()
+        )),
+    }
+}
+
+/// If the environment says where the target Python's headers are, use that
+/// instead of running an interpreter: when cross-compiling, the host Python's
+/// headers are the wrong ones.
+fn cross_config(env: &dyn Fn(&str) -> Option<String>) -> Option<Result<PythonConfig, String>> {
+    let version = env("PYO3_CROSS_PYTHON_VERSION");
+    if let Some(include) = env("FIL_PYTHON_INCLUDE_DIR").or_else(|| env("PYO3_CROSS_INCLUDE_DIR")) {
+        return Some(PythonConfig::from_include_dir(Path::new(&include), version));
+    }
+    let lib_dir = env("PYO3_CROSS_LIB_DIR")?;
+    Some(
+        include_dir_for_lib_dir(Path::new(&lib_dir), version.as_deref())
+            .and_then(|include| PythonConfig::from_include_dir(&include, version)),
+    )
+}
+
+/// Interpreters to try, in order: PYO3_PYTHON if it's set (in which case we
+/// don't try anything else), then python3, then python.
+fn candidate_interpreters(env: &dyn Fn(&str) -> Option<String>) -> Vec<String> {
+    match env("PYO3_PYTHON") {
+        Some(exe) => vec![exe],
+        None => vec!["python3".to_string(), "python".to_string()],
+    }
+}
+
+/// Run the probe script with the first interpreter that works.
+fn probe(env: &dyn Fn(&str) -> Option<String>) -> Result<PythonConfig, String> {
+    let mut errors = vec![];
+    for exe in candidate_interpreters(env) {
+        match Command::new(&exe).arg("-c").arg(PROBE_SCRIPT).output() {
+            Ok(output) if output.status.success() => {
+                return PythonConfig::from_probe_output(
+                    &exe,
+                    &String::from_utf8_lossy(&output.stdout),
+                );
+            }
+            Ok(output) => errors.push(format!(
+                "{} failed: {}",
+                exe,
+                String::from_utf8_lossy(&output.stderr).trim()
+            )),
+            Err(e) => errors.push(format!("couldn't run {}: {}", exe, e)),
+        }
+    }
+    Err(format!(
+        "Couldn't find a working Python interpreter ({}). Set PYO3_PYTHON to a CPython 3.x interpreter.",
+        errors.join("; ")
+    ))
+}
+
+/// Figure out the configuration either from cross-compilation variables or,
+/// failing that, by running an interpreter.
+fn resolve(env: &dyn Fn(&str) -> Option<String>) -> Result<PythonConfig, String> {
+    cross_config(env).unwrap_or_else(|| probe(env))
+}
+
+/// Read a non-empty environment variable.
+fn env_var(name: &str) -> Option<String> {
+    std::env::var(name).ok().filter(|value| !value.is_empty())
+}
+
+/// Get the Python build configuration. The interpreter is only run once, and
+/// the result is cached for the rest of the build script.
+///
+/// When cross-compiling, set FIL_PYTHON_INCLUDE_DIR (or PYO3_CROSS_INCLUDE_DIR,
+/// or PYO3_CROSS_LIB_DIR) so the target's headers are used; the host
+/// interpreter is then never run.
+pub fn python_config() -> Result<&'static PythonConfig, String> {
+    static CONFIG: OnceLock<Result<PythonConfig, String>> = OnceLock::new();
+    println!("cargo:rerun-if-env-changed=PYO3_PYTHON");
+    for name in CROSS_VARIABLES {
+        println!("cargo:rerun-if-env-changed={}", name);
+    }
+    CONFIG
+        .get_or_init(|| resolve(&env_var))
+        .as_ref()
+        .map_err(|e| e.clone())
+}
+
+/// A cc::Build for the target we're compiling for, which may not be the host.
+pub fn cc_build() -> cc::Build {
+    let mut build = cc::Build::new();
+    if let Ok(target) = std::env::var("TARGET") {
+        build.target(&target);
+    }
+    if let Ok(host) = std::env::var("HOST") {
+        build.host(&host);
+    }
+    build
+}
+
+/// Like python_config(), but on failure explain what went wrong and make the
+/// build script fail, instead of panicking with a backtrace.
+pub fn python_config_or_exit() -> &'static PythonConfig {
+    match python_config() {
+        Ok(config) => config,
+        Err(e) => {
+            eprintln!("error: {}", e);
+            std::process::exit(1);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{cross_config, resolve, PythonConfig};
+    use std::collections::HashMap;
+    use std::path::Path;
+
+    /// Create a fake Python install prefix with headers for the given version.
+    fn fake_prefix(version: &str) -> tempfile::TempDir {
+        let prefix = tempfile::tempdir().unwrap();
+        let include = prefix
+            .path()
+            .join("include")
+            .join(format!("python{}", version));
+        std::fs::create_dir_all(&include).unwrap();
+        std::fs::create_dir_all(prefix.path().join("lib")).unwrap();
+        std::fs::write(include.join("Python.h"), "").unwrap();
+        prefix
+    }
+
+    fn fake_env(vars: &[(&str, String)]) -> impl Fn(&str) -> Option<String> {
+        let vars: HashMap<String, String> = vars
+            .iter()
+            .map(|(k, v)| (k.to_string(), v.clone()))
+            .collect();
+        move |name| vars.get(name).cloned()
+    }
+
+    #[test]
+    fn parse_probe_output() {
+        let config = PythonConfig::from_probe_output(
+            "python3",
+            r#"{"include": "/usr/include/python3.11", "platinclude": "/usr/include/python3.11", "version": "3.11", "implementation": "cpython"}
+"#,
+        )
+        .unwrap();
+        assert_eq!(
+            config,
+            PythonConfig {
+                executable: "python3".to_string(),
+                include: "/usr/include/python3.11".to_string(),
+                platinclude: "/usr/include/python3.11".to_string(),
+                version: "3.11".to_string(),
+                implementation: "cpython".to_string(),
+            }
+        );
+    }
+
+    #[test]
+    fn reject_bad_interpreters() {
+        // PyPy:
+        let err = PythonConfig::from_probe_output(
+            "pypy3",
+            r#"{"include": "/x", "platinclude": "/x", "version": "3.10", "implementation": "pypy"}"#,
+        )
+        .unwrap_err();
+        assert!(err.contains("PYO3_PYTHON"));
+        // Python 2, or something else printing nonsense:
+        let err = PythonConfig::from_probe_output("python", "garbage").unwrap_err();
+        assert!(err.contains("PYO3_PYTHON"));
+    }
+
+    #[test]
+    fn no_cross_variables_means_probing() {
+        assert!(cross_config(&fake_env(&[])).is_none());
+    }
+
+    #[test]
+    fn cross_include_dir_skips_interpreter() {
+        let prefix = fake_prefix("3.10");
+        let include = prefix.path().join("include").join("python3.10");
+        for variable in ["FIL_PYTHON_INCLUDE_DIR", "PYO3_CROSS_INCLUDE_DIR"] {
+            // If the interpreter were run, this would fail:
+            let env = fake_env(&[
+                (variable, include.to_string_lossy().into_owned()),
+                ("PYO3_PYTHON", "/nonexistent/python".to_string()),
+            ]);
+            let config = resolve(&env).unwrap();
+            assert_eq!(config.version, "3.10");
+            assert_eq!(Path::new(&config.include), include);
+            assert_eq!(config.platinclude, config.include);
+            assert_eq!(config.executable, "");
+        }
+    }
+
+    #[test]
+    fn cross_lib_dir_finds_headers() {
+        let prefix = fake_prefix("3.12");
+        let env = fake_env(&[
+            (
+                "PYO3_CROSS_LIB_DIR",
+                prefix.path().join("lib").to_string_lossy().into_owned(),
+            ),
+            ("PYO3_PYTHON", "/nonexistent/python".to_string()),
+        ]);
+        let config = resolve(&env).unwrap();
+        assert_eq!(config.version, "3.12");
+        assert_eq!(
+            Path::new(&config.include),
+            prefix.path().join("include").join("python3.12")
+        );
+
+        // Asking for a version that isn't there is an error, not a fallback
+        // to the host interpreter:
+        let env = fake_env(&[
+            (
+                "PYO3_CROSS_LIB_DIR",
+                prefix.path().join("lib").to_string_lossy().into_owned(),
+            ),
+            ("PYO3_CROSS_PYTHON_VERSION", "3.9".to_string()),
+        ]);
+        assert!(resolve(&env)
+            .unwrap_err()
+            .contains("FIL_PYTHON_INCLUDE_DIR"));
+    }
+
+    #[test]
+    fn cross_include_dir_must_have_headers() {
+        let empty = tempfile::tempdir().unwrap();
+        let env = fake_env(&[(
+            "FIL_PYTHON_INCLUDE_DIR",
+            empty.path().to_string_lossy().into_owned(),
+        )]);
+        assert!(resolve(&env).unwrap_err().contains("Python.h"));
+    }
+}
@@ -0,0 +1,54 @@
+//! The `filprofiler._filprofiler_api` extension module, for profiling without
+//! LD_PRELOAD; the implementation is in pymemprofile_api::pymodule.
+//!
+//! Besides the Python functions, the shared library exports C functions for
+//! native hook layers, e.g. PEP 445 allocator hooks, that can't afford a call
+//! into Python for every allocation. Since Python loads extension modules
+//! with RTLD_LOCAL, look them up with dlsym() on a handle from
+//! `dlopen(_filprofiler_api.__file__, RTLD_NOW | RTLD_NOLOAD)`.
+
+// PyO3's generated wrappers don't follow the crate-wide lint.
+#![allow(unsafe_op_in_unsafe_fn)]
+
+use pymemprofile_api::memorytracking::FunctionId;
+use pymemprofile_api::pymodule;
+use pyo3::prelude::*;
+
+/// Record a new allocation from the current thread's callstack, if tracking
+/// is on.
+#[no_mangle]
+pub extern "C" fn filprofiler_api_add_allocation(address: usize, size: usize, line_number: u32) {
+    pymodule::add_allocation(address, size, line_number);
+}
+
+/// Record an allocation being freed.
+#[no_mangle]
+pub extern "C" fn filprofiler_api_free_allocation(address: usize) {
+    pymodule::free_allocation(address);
+}
+
+/// Add to the current thread's callstack; function_id is from the Python
+/// add_function().
+#[no_mangle]
+pub extern "C" fn filprofiler_api_start_call(
+    function_id: u64,
+    parent_line_number: u32,
+    line_number: u32,
+) {
+    pymodule::start_call(
+        FunctionId::new(function_id),
+        parent_line_number,
+        line_number,
+    );
+}
+
+/// Remove the most recent call from the current thread's callstack.
+#[no_mangle]
+pub extern "C" fn filprofiler_api_finish_call() {
+    pymodule::finish_call();
+}
+
+#[pymodule]
+fn _filprofiler_api(m: &Bound<'_, PyModule>) -> PyResult<()> {
+    pymodule::add_to_module(m)
+}
@@ -1,26 +1,168 @@
+use std::path::{Path, PathBuf};
 use std::process::Command;
 
-/// Get paths for C compilation builds, e.g. "include" or "platinclude".
-/// TODO this is copy/pasted multiple times...
-fn get_python_path(pathname: &str) -> String {
-    let exe = std::env::var("PYO3_PYTHON").unwrap_or_else(|_| "python".to_string());
-    let output = Command::new(exe)
-        .arg("-c")
-        .arg(format!(
-            "import sysconfig; print(sysconfig.get_path('{}'))",
-            pathname
-        ))
+/// How we're going to link on Linux.
+enum Linker {
+    /// lld installed on the system.
+    SystemLld,
+    /// The lld that ships with the Rust toolchain, found in the given
+    /// directory.
+    RustLld(PathBuf),
+    /// Whatever the default linker is, typically GNU ld.
+    Default,
+}
+
+/// Return whether the C compiler can link using an lld it finds on its own,
+/// or in the given extra search directory.
+///
+/// We ask the compiler driver to run the linker with `--version`, which is
+/// cheap and doesn't require writing any files.
+fn can_link_with_lld(search_dir: Option<&PathBuf>) -> bool {
+    let compiler = fil_build_helpers::cc_build().get_compiler();
+    let mut command = Command::new(compiler.path());
+    if let Some(search_dir) = search_dir {
+        command.arg(format!("-B{}", search_dir.to_string_lossy()));
+    }
+    match command.arg("-fuse-ld=lld").arg("-Wl,--version").output() {
+        Ok(output) => {
+            output.status.success() && String::from_utf8_lossy(&output.stdout).contains("LLD")
+        }
+        Err(_) => false,
+    }
+}
+
+/// The directory with the Rust toolchain's lld wrapper, if it has one.
+fn rust_lld_dir() -> Option<PathBuf> {
+    let rustc = std::env::var("RUSTC").unwrap_or_else(|_| "rustc".to_string());
+    let output = Command::new(rustc)
+        .arg("--print")
+        .arg("sysroot")
         .output()
-        .unwrap();
-    String::from_utf8(output.stdout).unwrap().trim().into()
+        .ok()?;
+    let sysroot = String::from_utf8(output.stdout).ok()?;
+    let host = std::env::var("HOST").ok()?;
+    let gcc_ld = PathBuf::from(sysroot.trim())
+        .join("lib")
+        .join("rustlib")
+        .join(host)
+        .join("bin")
+        .join("gcc-ld");
+    if gcc_ld.join("ld.lld").exists() {
+        Some(gcc_ld)
+    } else {
+        None
+    }
+}
+
+/// Figure out which linker to use. Can be overridden by setting the
+/// FIL_LINKER environment variable to "lld", "rust-lld", or "default".
+fn choose_linker() -> Linker {
+    match std::env::var("FIL_LINKER").as_deref() {
+        Ok("lld") => return Linker::SystemLld,
+        Ok("rust-lld") => {
+            return Linker::RustLld(
+                rust_lld_dir().expect("FIL_LINKER=rust-lld, but the Rust toolchain has no lld"),
+            )
+        }
+        Ok("default") => return Linker::Default,
+        _ => {}
+    }
+    if can_link_with_lld(None) {
+        return Linker::SystemLld;
+    }
+    if let Some(dir) = rust_lld_dir() {
+        if can_link_with_lld(Some(&dir)) {
+            return Linker::RustLld(dir);
+        }
+    }
+    Linker::Default
+}
+
+/// The file APIs wrapped to track temporary files, and the C functions that
+/// implement them; see src/exports.rs for the version used without lld.
+const TEMP_FILE_SYMBOLS: &[(&str, &str)] = &[
+    ("open", "fil_open_impl"),
+    ("open64", "fil_open_impl"),
+    ("openat", "fil_openat_impl"),
+    ("openat64", "fil_openat_impl"),
+    ("unlink", "fil_unlink_impl"),
+    ("unlinkat", "fil_unlinkat_impl"),
+    ("ftruncate", "fil_ftruncate_impl"),
+    ("ftruncate64", "fil_ftruncate_impl"),
+    ("fallocate", "fil_fallocate_impl"),
+    ("fallocate64", "fil_fallocate_impl"),
+    ("posix_fallocate", "fil_posix_fallocate_impl"),
+    ("posix_fallocate64", "fil_posix_fallocate_impl"),
+    ("close", "fil_close_impl"),
+];
+
+/// Emit the link arguments for linking with lld, optionally found in a specific
+/// directory.
+fn link_with_lld(cur_dir: &Path, search_dir: Option<&PathBuf>) {
+    if let Some(search_dir) = search_dir {
+        println!(
+            "cargo:rustc-cdylib-link-arg=-B{}",
+            search_dir.to_string_lossy()
+        );
+    }
+    println!("cargo:rustc-cdylib-link-arg=-fuse-ld=lld");
+
+    // On 64-bit Linux, mmap() is another way of saying mmap64, or vice versa,
+    // so we point to function of our own.
+    println!("cargo:rustc-cdylib-link-arg=-Wl,--defsym=mmap=fil_mmap_impl");
+    println!("cargo:rustc-cdylib-link-arg=-Wl,--defsym=mmap64=fil_mmap_impl");
+    // Likewise for the file APIs used to track temporary files:
+    for (name, implementation) in TEMP_FILE_SYMBOLS {
+        println!(
+            "cargo:rustc-cdylib-link-arg=-Wl,--defsym={}={}",
+            name, implementation
+        );
+    }
+
+    // Use a versionscript to limit symbol visibility.
+    println!(
+        "cargo:rustc-cdylib-link-arg=-Wl,--version-script={}/versionscript.txt",
+        cur_dir.to_string_lossy()
+    );
+}
+
+/// Generate memapi.h, with prototypes for the pymemprofile_* functions, into
+/// the given directory, using cbindgen; see cbindgen.toml. Since _filpreload.c
+/// only gets the prototypes from there, a Rust signature change that the C
+/// code doesn't match fails to compile.
+fn generate_header(cur_dir: &Path, out_dir: &Path) {
+    // The FFI is in this crate, and some of the types it uses in memapi:
+    println!("cargo:rerun-if-changed=cbindgen.toml");
+    println!("cargo:rerun-if-changed=src");
+    println!("cargo:rerun-if-changed=../memapi/src");
+    let config = cbindgen::Config::from_file(cur_dir.join("cbindgen.toml"))
+        .expect("Couldn't read cbindgen.toml");
+    match cbindgen::Builder::new()
+        .with_crate(cur_dir)
+        .with_config(config)
+        .generate()
+    {
+        Ok(bindings) => {
+            bindings.write_to_file(out_dir.join("memapi.h"));
+        }
+        Err(e) => {
+            eprintln!("error: Couldn't generate memapi.h: {}", e);
+            std::process::exit(1);
+        }
+    }
 }
 
 fn main() -> Result<(), std::io::Error> {
     println!("cargo:rerun-if-changed=src/_filpreload.c");
+    println!("cargo:rerun-if-env-changed=FIL_LINKER");
+    println!("cargo:rustc-check-cfg=cfg(fil_rust_exports)");
     let cur_dir = std::env::current_dir()?;
+    let mut build = fil_build_helpers::cc_build();
+    // This is the OS we're building for, which isn't necessarily the one the
+    // build script is running on:
+    let target_os = std::env::var("CARGO_CFG_TARGET_OS").unwrap_or_default();
 
-    #[cfg(target_os = "macos")]
-    {
+    if target_os == "macos" {
         // Limit symbol visibility.
         println!(
             "cargo:rustc-cdylib-link-arg=-Wl,-exported_symbols_list,{}/export_symbols.txt",
@@ -28,33 +170,48 @@ fn main() -> Result<(), std::io::Error> {
         );
     }
 
-    #[cfg(target_os = "linux")]
-    {
-        // On Linux GNU ld can't handle two version files (one from Rust, one from
-        // us) at the same time without blowing up.
-        println!("cargo:rustc-cdylib-link-arg=-fuse-ld=lld");
-
-        // On 64-bit Linux, mmap() is another way of saying mmap64, or vice versa,
-        // so we point to function of our own.
-        println!("cargo:rustc-cdylib-link-arg=-Wl,--defsym=mmap=fil_mmap_impl");
-        println!("cargo:rustc-cdylib-link-arg=-Wl,--defsym=mmap64=fil_mmap_impl");
+    if target_os == "linux" {
+        // GNU ld can't handle two version files (one from Rust, one from us)
+        // at the same time without blowing up, so we prefer lld:
+        match choose_linker() {
+            Linker::SystemLld => {
+                println!("cargo:warning=Linking with the system's lld.");
+                link_with_lld(&cur_dir, None);
+            }
+            Linker::RustLld(dir) => {
+                println!(
+                    "cargo:warning=System lld not found, linking with the Rust toolchain's lld in {}.",
+                    dir.to_string_lossy()
+                );
+                link_with_lld(&cur_dir, Some(&dir));
+            }
+            Linker::Default => {
+                // Without our own version script, only symbols exported by
+                // Rust code are visible. So the C code renames its public
+                // functions, and src/exports.rs exposes them under their real
+                // names. This adds an extra function call to every malloc(),
+                // so it's only a fallback.
+                println!("cargo:warning=lld not found, linking with the default linker; public C APIs will be re-exported via Rust.");
+                println!("cargo:rustc-cfg=fil_rust_exports");
+                build.define("FIL_RUST_EXPORTS", "1");
+            }
+        }
+    }
 
-        // Use a versionscript to limit symbol visibility.
-        println!(
-            "cargo:rustc-cdylib-link-arg=-Wl,--version-script={}/versionscript.txt",
-            cur_dir.to_string_lossy()
-        );
-    };
+    let out_dir = PathBuf::from(std::env::var("OUT_DIR").expect("OUT_DIR isn't set"));
+    generate_header(&cur_dir, &out_dir);
 
     // Compilation options are taken from Python's build configuration.
-    cc::Build::new()
+    let python = fil_build_helpers::python_config_or_exit();
+    build
         .file("src/_filpreload.c")
-        .include(get_python_path("include"))
-        .include(get_python_path("platinclude"))
+        .include(&out_dir)
+        .include(&python.include)
+        .include(&python.platinclude)
         .define("_GNU_SOURCE", "1")
         .define("NDEBUG", "1")
         .flag("-fno-omit-frame-pointer")
-        .flag(if cfg!(target_os = "linux") {
+        .flag(if target_os == "linux" {
             // Faster TLS for Linux.
             "-ftls-model=initial-exec"
         } else {
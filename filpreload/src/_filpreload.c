@@ -26,6 +26,11 @@
 #include <unistd.h>
 #include <stdbool.h>
 #include <errno.h>
+#ifdef __linux__
+#include <fcntl.h>
+#include <link.h>
+#include <stdarg.h>
+#endif
 
 #if PY_MINOR_VERSION < 9
     PyFrameObject *
@@ -45,10 +50,22 @@ PyCodeObject *PyFrame_GetCode(PyFrameObject *frame) {
 // Macro to create the publicly exposed symbol:
 #ifdef __APPLE__
 #define SYMBOL_PREFIX(func) reimplemented_##func
+#elif defined(FIL_RUST_EXPORTS)
+// Linking without lld, so the real symbol is exported from Rust (see build.rs
+// and exports.rs).
+#define SYMBOL_PREFIX(func) fil_c_##func
 #else
 #define SYMBOL_PREFIX(func) func
 #endif
 
+// Macro for the APIs called from Python, which likewise may be exported from
+// Rust:
+#ifdef FIL_RUST_EXPORTS
+#define PUBLIC_API(func) func##_c
+#else
+#define PUBLIC_API(func) func
+#endif
+
 // Macro to get the underlying function being wrapped:
 #ifdef __APPLE__
 #define REAL_IMPL(func) func
@@ -84,6 +101,27 @@ static int initialized = 0;
 // this on from start until finish.
 static _Atomic int tracking_allocations = ATOMIC_VAR_INIT(0);
 
+// Set by fil_shutdown(), until the next fil_reset(); tracking can't be started
+// in between.
+static _Atomic int shut_down = ATOMIC_VAR_INIT(0);
+
+// Whether to record the callstack responsible for each free(). Off by default,
+// since it doubles the work done per free(); enabled with FIL_TRACK_FREES=1 or
+// fil_set_free_tracking().
+static _Atomic int tracking_frees = ATOMIC_VAR_INIT(0);
+
+// Whether to track allocations made via jemalloc's and mimalloc's own APIs,
+// see the interposers for those below. Enabled with FIL_BUNDLED_ALLOCATORS=1.
+static int tracking_bundled_allocators = 0;
+
+// Whether to track file-backed mmap()s, separately from other memory. Enabled
+// with FIL_TRACK_MAPPED_FILES=1.
+static int tracking_mapped_files = 0;
+
+// Whether to track temporary files, separately from memory. Enabled with
+// FIL_TRACK_TEMP_FILES=1.
+static int tracking_temp_files = 0;
+
 // ID of Python code object extra data:
 static Py_ssize_t extra_code_index = -1;
 
@@ -169,27 +207,146 @@ struct FunctionLocation {
   Py_ssize_t function_name_length;
 };
 
-// Implemented in the Rust library:
-extern uint64_t pymemprofile_add_function_location(const char* filename, size_t filename_length, const char* function_name,
-                                                   size_t function_length);
-extern void pymemprofile_start_call(uint16_t parent_line_number,
-                                    uint64_t function_id,
-                                    uint16_t line_number);
-extern void pymemprofile_finish_call();
-extern void pymemprofile_new_line_number(uint16_t line_number);
-extern void pymemprofile_reset(const char *path);
-extern void pymemprofile_start_tracking();
-extern void pymemprofile_stop_tracking();
-extern void pymemprofile_dump_peak_to_flamegraph(const char *path);
-extern void pymemprofile_add_allocation(size_t address, size_t length,
-                                        uint16_t line_number);
-extern void pymemprofile_free_allocation(size_t address);
-extern void pymemprofile_add_anon_mmap(size_t address, size_t length,
-                                       uint16_t line_number);
-extern void pymemprofile_free_anon_mmap(size_t address, size_t length);
-extern void *pymemprofile_get_current_callstack();
-extern void pymemprofile_set_current_callstack(void *callstack);
-extern void pymemprofile_clear_current_callstack();
+// Implemented in the Rust library; generated by build.rs from the Rust
+// signatures:
+#include "memapi.h"
+
+// Names used by the public API:
+typedef PeakCallback fil_peak_callback;
+typedef RetentionProbe fil_retention_probe;
+typedef AllocationInfo fil_allocation_info;
+typedef OutputSinkCallback fil_output_sink;
+
+// Why malloc() and friends can't be interposed in this process, if they
+// can't; set by the constructor, see check_interposition().
+static const char *interposition_failure = NULL;
+
+#ifdef __linux__
+// Set if dlsym(RTLD_NEXT) can't be trusted to find the real functions, in
+// which case they're looked up in libc directly. That's slower, since it
+// happens on every first use rather than once, but still works.
+static int rtld_next_broken = 0;
+
+// For tests: __FIL_SIMULATE_BOOTSTRAP_FAILURE names a check that should fail
+// as if the environment were broken in that way.
+static int simulated_failure(const char *check) {
+  const char *simulate = getenv("__FIL_SIMULATE_BOOTSTRAP_FAILURE");
+  return simulate != NULL && strcmp(simulate, check) == 0;
+}
+
+// Whether the address is in this shared library.
+static int in_this_library(void *address) {
+  Dl_info ours, theirs;
+  if (!dladdr((void *)&initialized, &ours) || !dladdr(address, &theirs)) {
+    return 0;
+  }
+  return ours.dli_fbase == theirs.dli_fbase;
+}
+
+// Look up a function in libc, or in libpthread for older glibc, bypassing
+// symbol interposition altogether.
+static void *libc_symbol(const char *name) {
+  const char *libraries[] = {"libc.so.6", "libpthread.so.0"};
+  for (size_t i = 0; i < sizeof(libraries) / sizeof(libraries[0]); i++) {
+    void *handle = dlopen(libraries[i], RTLD_LAZY | RTLD_NOLOAD);
+    if (handle == NULL) {
+      continue;
+    }
+    void *symbol = dlsym(handle, name);
+    dlclose(handle);
+    if (symbol != NULL && !in_this_library(symbol)) {
+      return symbol;
+    }
+  }
+  return NULL;
+}
+
+// The real implementation of a function we wrap, or NULL if it can't be
+// found.
+static void *real_symbol(const char *name) {
+  if (rtld_next_broken) {
+    return libc_symbol(name);
+  }
+  return dlsym(RTLD_NEXT, name);
+}
+
+// Whether the dynamic linker thinks this thread's copy of our thread-local
+// storage is where our initial-exec accesses actually go, found by
+// dl_iterate_phdr(); see check_interposition().
+struct tls_check {
+  void *our_base;
+  int matches;
+};
+
+static int check_tls_block(struct dl_phdr_info *info, size_t size,
+                           void *data) {
+  (void)size;
+  struct tls_check *check = data;
+  if ((void *)info->dlpi_addr != check->our_base) {
+    return 0;
+  }
+  for (ElfW(Half) i = 0; i < info->dlpi_phnum; i++) {
+    if (info->dlpi_phdr[i].p_type != PT_TLS) {
+      continue;
+    }
+    char *start = info->dlpi_tls_data;
+    char *ours = (char *)&will_i_be_reentrant;
+    check->matches = start != NULL && ours >= start &&
+                     ours < start + info->dlpi_phdr[i].p_memsz;
+  }
+  return 1;
+}
+
+// Check whether the assumptions interposition relies on hold, returning why
+// not if they don't. Embedding Python via dlmopen() can break them, and we'd
+// rather pass everything through than crash. A broken dlsym(RTLD_NEXT) on its
+// own is worked around instead. A statically linked interpreter that doesn't
+// export Python's C API can't load us at all, so fil-profile checks for that
+// before preloading us.
+static const char *check_interposition() {
+  // dlmopen() puts us in a separate namespace, with its own copy of libc, so
+  // the rest of the process never calls our malloc():
+  Dl_info info;
+  struct link_map *map = NULL;
+  Lmid_t namespace = LM_ID_BASE;
+  if (dladdr1((void *)&initialized, &info, (void **)&map, RTLD_DL_LINKMAP) &&
+      map != NULL) {
+    dlinfo(map, RTLD_DI_LMID, &namespace);
+  }
+  if (namespace != LM_ID_BASE || simulated_failure("namespace")) {
+    return "it was loaded with dlmopen() into a separate linker namespace";
+  }
+
+  // The reentrancy counter lives in thread-local storage, compiled with the
+  // initial-exec model, which assumes a fixed offset from the thread pointer
+  // into space reserved when the program started. If we were loaded in a way
+  // that breaks that, the offset points at someone else's memory, so compare
+  // it with where the dynamic linker put our TLS block:
+  struct tls_check tls = {.our_base = NULL, .matches = 0};
+  if (map != NULL) {
+    tls.our_base = (void *)map->l_addr;
+    dl_iterate_phdr(check_tls_block, &tls);
+  }
+  if (!tls.matches || simulated_failure("tls")) {
+    return "its thread-local storage doesn't behave as expected";
+  }
+
+  const char *wrapped[] = {"mmap", "pthread_create", "fork"};
+  for (size_t i = 0; i < sizeof(wrapped) / sizeof(wrapped[0]); i++) {
+    void *next = dlsym(RTLD_NEXT, wrapped[i]);
+    if (next == NULL || in_this_library(next) ||
+        simulated_failure("rtld_next")) {
+      fprintf(stderr,
+              "=fil-profile= dlsym(RTLD_NEXT) couldn't find the real %s(), "
+              "looking up functions in libc directly instead.\n",
+              wrapped[i]);
+      rtld_next_broken = 1;
+      break;
+    }
+  }
+  return NULL;
+}
+#endif
 
 static void __attribute__((constructor)) constructor() {
   if (initialized) {
@@ -208,26 +365,42 @@ static void __attribute__((constructor)) constructor() {
   underlying_real_pthread_create = REAL_IMPL(pthread_create);
   underlying_real_fork = REAL_IMPL(fork);
 #else
-  underlying_real_mmap = dlsym(RTLD_NEXT, "mmap");
+  interposition_failure = check_interposition();
+  underlying_real_mmap = real_symbol("mmap");
   if (!underlying_real_mmap) {
     fprintf(stderr, "Couldn't load mmap(): %s\n", dlerror());
     exit(1);
   }
-  underlying_real_pthread_create = dlsym(RTLD_NEXT, "pthread_create");
+  underlying_real_pthread_create = real_symbol("pthread_create");
   if (!underlying_real_pthread_create) {
     fprintf(stderr, "Couldn't load pthread_create(): %s\n", dlerror());
     exit(1);
   }
-  underlying_real_fork = dlsym(RTLD_NEXT, "fork");
+  underlying_real_fork = real_symbol("fork");
   if (!underlying_real_fork) {
     fprintf(stderr, "Couldn't load fork(): %s\n", dlerror());
     exit(1);
   }
+  if (interposition_failure != NULL) {
+    // Leaving initialized unset means everything is passed straight through
+    // to the real implementations:
+    fprintf(stderr,
+            "=fil-profile= WARNING: Fil can't track memory in this process, "
+            "because %s. Allocations will be passed through untracked. To "
+            "profile it anyway, use the filprofiler._filprofiler_api module, "
+            "which doesn't need LD_PRELOAD.\n",
+            interposition_failure);
+    unsetenv("LD_PRELOAD");
+    return;
+  }
 #endif
   // Initialize Rust static state before we start doing any calls via malloc(),
   // to ensure we don't get unpleasant reentrancy issues.
   increment_reentrancy();
   pymemprofile_reset("/tmp");
+  // This is the main thread, which will be running Python, so it's not an
+  // unknown native thread even before its Python callstack is set up:
+  pymemprofile_clear_current_callstack();
   decrement_reentrancy();
 
   // Drop LD_PRELOAD so that Linux subprocesses don't have this preloaded.
@@ -239,13 +412,33 @@ static void __attribute__((constructor)) constructor() {
   // is fine to do.
   // unsetenv("DYLD_INSERT_LIBRARIES");
 
+  const char *track_frees = getenv("FIL_TRACK_FREES");
+  if (track_frees != NULL && strcmp(track_frees, "1") == 0) {
+    atomic_store_explicit(&tracking_frees, 1, memory_order_release);
+  }
+
+  const char *bundled_allocators = getenv("FIL_BUNDLED_ALLOCATORS");
+  if (bundled_allocators != NULL && strcmp(bundled_allocators, "1") == 0) {
+    tracking_bundled_allocators = 1;
+  }
+
+  const char *mapped_files = getenv("FIL_TRACK_MAPPED_FILES");
+  if (mapped_files != NULL && strcmp(mapped_files, "1") == 0) {
+    tracking_mapped_files = 1;
+  }
+
+  const char *temp_files = getenv("FIL_TRACK_TEMP_FILES");
+  if (temp_files != NULL && strcmp(temp_files, "1") == 0) {
+    tracking_temp_files = 1;
+  }
+
   initialized = 1;
 }
 
-static void start_call(uint64_t function_id, uint16_t line_number, PyFrameObject* current_frame) {
+static void start_call(uint64_t function_id, uint32_t line_number, PyFrameObject* current_frame) {
   if (should_track_memory()) {
     increment_reentrancy();
-    uint16_t parent_line_number = 0;
+    uint32_t parent_line_number = 0;
     if (current_frame != NULL) {
       PyFrameObject *parent = PyFrame_GetBack(current_frame);
       if (parent != NULL ){
@@ -286,14 +479,49 @@ fil_tracer(PyObject *obj, PyFrameObject *frame, int what, PyObject *arg) {
     PyCodeObject *code = PyFrame_GetCode(frame);
     _PyCode_GetExtra((PyObject *)code, extra_code_index, (void **)&function_id);
     if (function_id == 0) {
-      Py_ssize_t filename_length, function_length;
+      Py_ssize_t filename_length, function_length, module_length = 0,
+                                                   qualname_length;
       const char* filename = PyUnicode_AsUTF8AndSize(code->co_filename,
                                                      &filename_length);
       const char* function_name = PyUnicode_AsUTF8AndSize(code->co_name,
                                                           &function_length);
+      // Reports show module.Class.method, so methods of different classes
+      // don't get merged. Before 3.11 there's no co_qualname, so it's just
+      // module.method.
+#if PY_VERSION_HEX >= 0x030B0000
+      const char* qualname = PyUnicode_AsUTF8AndSize(code->co_qualname,
+                                                     &qualname_length);
+      PyObject* globals = PyFrame_GetGlobals(frame);
+#else
+      const char* qualname = function_name;
+      qualname_length = function_length;
+      PyObject* globals = frame->f_globals;
+      Py_XINCREF(globals);
+#endif
+      const char* module_name = NULL;
+      PyObject* module = NULL;
+      if (globals != NULL && PyDict_Check(globals)) {
+        module = PyDict_GetItemString(globals, "__name__");
+      }
+      if (module != NULL && PyUnicode_Check(module)) {
+        module_name = PyUnicode_AsUTF8AndSize(module, &module_length);
+      }
+      if (qualname == NULL) {
+        PyErr_Clear();
+        qualname = function_name;
+        qualname_length = function_length;
+      }
+      if (module_name == NULL) {
+        PyErr_Clear();
+        module_length = 0;
+      }
       increment_reentrancy();
-      function_id = pymemprofile_add_function_location(filename, (uint64_t)filename_length, function_name, (uint64_t)function_length);
+      function_id = pymemprofile_add_function_location_v2(
+          filename, (uint64_t)filename_length, function_name,
+          (uint64_t)function_length, module_name, (uint64_t)module_length,
+          qualname, (uint64_t)qualname_length);
       decrement_reentrancy();
+      Py_XDECREF(globals);
       _PyCode_SetExtra((PyObject *)code, extra_code_index,
                        (void *)function_id + 1);
       Py_DECREF(code);
@@ -332,31 +560,60 @@ fil_tracer(PyObject *obj, PyFrameObject *frame, int what, PyObject *arg) {
 
 /// Called after Python gets going, allowing us to call some necessary Python
 /// APIs.
-__attribute__((visibility("default"))) void fil_initialize_from_python() {
+__attribute__((visibility("default"))) void PUBLIC_API(fil_initialize_from_python)() {
   extra_code_index = _PyEval_RequestCodeExtraIndex(NULL);
 }
 
+/// Why memory can't be tracked in this process, or NULL if it can.
+__attribute__((visibility("default"))) const char *
+PUBLIC_API(fil_interposition_failure)() {
+  return interposition_failure;
+}
+
 /// Start memory tracing.
 __attribute__((visibility("default"))) void
-fil_start_tracking() {
+PUBLIC_API(fil_start_tracking)() {
+  if (atomic_load_explicit(&shut_down, memory_order_acquire)) {
+    return;
+  }
+  increment_reentrancy();
+  pymemprofile_check_bundled_allocators();
+  decrement_reentrancy();
   atomic_store_explicit(&tracking_allocations, 1, memory_order_release);
 }
 
 /// Clear previous allocations;
 __attribute__((visibility("default"))) void
-fil_reset(const char *default_path) {
+PUBLIC_API(fil_reset)(const char *default_path) {
   increment_reentrancy();
   pymemprofile_reset(default_path);
   decrement_reentrancy();
+  atomic_store_explicit(&shut_down, 0, memory_order_release);
 }
 
 /// End memory tracing.
-__attribute__((visibility("default"))) void fil_stop_tracking() {
+__attribute__((visibility("default"))) void PUBLIC_API(fil_stop_tracking)() {
+  atomic_store_explicit(&tracking_allocations, 0, memory_order_release);
+}
+
+/// Stop tracking, stop Fil's background threads, write a final report unless
+/// final_dump_path is NULL (if it's empty, to a new automatically-named
+/// directory in the output directory), and free the tracked data. Returns 0,
+/// -1 if the report couldn't be written, or -2 if Fil was already shut down.
+/// Until the next fil_reset(), other APIs do nothing, and those that return
+/// int return -2.
+__attribute__((visibility("default"))) int
+PUBLIC_API(fil_shutdown)(const char *final_dump_path) {
+  atomic_store_explicit(&shut_down, 1, memory_order_release);
   atomic_store_explicit(&tracking_allocations, 0, memory_order_release);
+  increment_reentrancy();
+  int result = pymemprofile_shutdown(final_dump_path);
+  decrement_reentrancy();
+  return result;
 }
 
 /// Register the C level Python tracer for the current thread.
-__attribute__((visibility("default"))) void register_fil_tracer() {
+__attribute__((visibility("default"))) void PUBLIC_API(register_fil_tracer)() {
   // C threads inherit their callstack from the creating Python thread. That's
   // fine. However, if a tracer is being registered, that means this is not a
   // pure C thread, it's a new Python thread with its own callstack.
@@ -367,27 +624,323 @@ __attribute__((visibility("default"))) void register_fil_tracer() {
   PyEval_SetTrace(fil_tracer, PyLong_FromLong(123));
 }
 
-/// Dump the current peak memory usage to disk.
+/// Set the directory that dumps with a NULL or empty path are written to.
+__attribute__((visibility("default"))) void
+PUBLIC_API(fil_set_output_directory)(const char *path) {
+  increment_reentrancy();
+  pymemprofile_set_output_directory(path);
+  decrement_reentrancy();
+}
+
+/// Record the Python version in the report's environment.
+__attribute__((visibility("default"))) void
+PUBLIC_API(fil_set_python_version)(const char *version) {
+  increment_reentrancy();
+  pymemprofile_set_python_version(version);
+  decrement_reentrancy();
+}
+
+/// Attach a key/value tag, e.g. a git commit, to the report's environment.
 __attribute__((visibility("default"))) void
-fil_dump_peak_to_flamegraph(const char *path) {
+PUBLIC_API(fil_add_metadata)(const char *key, const char *value) {
+  increment_reentrancy();
+  pymemprofile_add_metadata(key, value);
+  decrement_reentrancy();
+}
+
+/// Start a new named phase, e.g. "imports"; later allocations are tagged with
+/// it.
+__attribute__((visibility("default"))) void
+PUBLIC_API(fil_mark_phase)(const char *name) {
+  increment_reentrancy();
+  pymemprofile_mark_phase(name);
+  decrement_reentrancy();
+}
+
+/// Push a phase for the current thread, shown as a "[phase: <name>]" frame at
+/// the root of its callstacks until the matching fil_pop_phase().
+__attribute__((visibility("default"))) void
+PUBLIC_API(fil_push_phase)(const char *name) {
+  increment_reentrancy();
+  pymemprofile_push_phase(name);
+  decrement_reentrancy();
+}
+
+/// Pop the current thread's innermost phase.
+__attribute__((visibility("default"))) void PUBLIC_API(fil_pop_phase)() {
+  increment_reentrancy();
+  pymemprofile_pop_phase();
+  decrement_reentrancy();
+}
+
+/// Record a live Python object at the given address, created by the current
+/// callstack minus its innermost skip_frames frames. Object counts are tracked
+/// separately from memory.
+__attribute__((visibility("default"))) void
+PUBLIC_API(fil_add_object)(size_t address, const char *type_name,
+                           uint32_t skip_frames) {
+  increment_reentrancy();
+  pymemprofile_add_object(address, type_name, get_current_line_number(),
+                          skip_frames);
+  decrement_reentrancy();
+}
+
+/// Record that the object at the given address was destroyed.
+__attribute__((visibility("default"))) void
+PUBLIC_API(fil_remove_object)(size_t address) {
+  increment_reentrancy();
+  pymemprofile_remove_object(address);
+  decrement_reentrancy();
+}
+
+/// Dump the current peak memory usage to disk. If path is NULL or empty, a new
+/// automatically-named directory in the output directory is used. The path
+/// written to is stored in path_out, if it's not NULL. Returns the length of
+/// that path, -1 on error, or -3 if the registered output sink failed.
+__attribute__((visibility("default"))) int
+PUBLIC_API(fil_dump_peak_to_flamegraph)(const char *path, char *path_out,
+                                        size_t path_out_length) {
   // We want to prevent reentrant malloc() calls, but we want to run regardless
   // of whether this particular call is reentrant.
   increment_reentrancy();
-  pymemprofile_dump_peak_to_flamegraph(path);
+  int result =
+      pymemprofile_dump_peak_to_flamegraph(path, path_out, path_out_length);
+  decrement_reentrancy();
+  return result;
+}
+
+/// Start profiling a region of the program. Memory that's already live is left
+/// out of the region's report, or if group_pre_existing isn't 0 shown as a
+/// single "[pre-existing]" frame. Returns 0, or -1 if a region is already being
+/// profiled.
+__attribute__((visibility("default"))) int
+PUBLIC_API(fil_region_start)(int group_pre_existing) {
+  increment_reentrancy();
+  int result = pymemprofile_region_start(group_pre_existing);
+  decrement_reentrancy();
+  return result;
+}
+
+/// Finish profiling the region and write its report, with the same arguments
+/// and result as fil_dump_peak_to_flamegraph(). Returns -1 if no region was
+/// being profiled.
+__attribute__((visibility("default"))) int
+PUBLIC_API(fil_region_end)(const char *path, char *path_out,
+                           size_t path_out_length) {
+  increment_reentrancy();
+  int result = pymemprofile_region_end(path, path_out, path_out_length);
+  decrement_reentrancy();
+  return result;
+}
+
+/// Render the peak flamegraph SVG in memory. If max_bytes isn't 0 and the full
+/// SVG would be bigger, a pruned version is returned. Returns NULL on failure.
+/// The result must be freed with fil_free_string().
+__attribute__((visibility("default"))) char *
+PUBLIC_API(fil_render_peak_svg)(size_t max_bytes) {
+  increment_reentrancy();
+  char *result = pymemprofile_render_peak_svg(max_bytes);
+  decrement_reentrancy();
+  return result;
+}
+
+/// Free a string returned by fil_render_peak_svg().
+__attribute__((visibility("default"))) void
+PUBLIC_API(fil_free_string)(char *string) {
+  increment_reentrancy();
+  pymemprofile_free_string(string);
+  decrement_reentrancy();
+}
+
+/// Get current and peak tracked memory, as one consistent snapshot. Returns 0
+/// on success.
+__attribute__((visibility("default"))) int
+PUBLIC_API(fil_get_traced_memory)(uint64_t *current_out, uint64_t *peak_out) {
+  increment_reentrancy();
+  int result = pymemprofile_get_traced_memory(current_out, peak_out);
+  decrement_reentrancy();
+  return result;
+}
+
+/// Call the given callback whenever peak memory grows by at least
+/// min_delta_bytes. Pass NULL to unregister.
+__attribute__((visibility("default"))) void
+PUBLIC_API(fil_register_peak_callback)(fil_peak_callback callback,
+                                       void *user_data,
+                                       uint64_t min_delta_bytes) {
+  increment_reentrancy();
+  pymemprofile_register_peak_callback(callback, user_data, min_delta_bytes);
+  decrement_reentrancy();
+}
+
+/// Hand every report file to the given callback, in chunks, after it's been
+/// written to disk; a call with length 0 ends each file. Pass NULL to
+/// unregister.
+__attribute__((visibility("default"))) void
+PUBLIC_API(fil_register_output_sink)(fil_output_sink callback,
+                                     void *user_data) {
+  increment_reentrancy();
+  pymemprofile_register_output_sink(callback, user_data);
+  decrement_reentrancy();
+}
+
+/// Call the given probe whenever the peak report is written, to estimate how
+/// much memory the named cache retains. Pass NULL to unregister.
+__attribute__((visibility("default"))) void
+PUBLIC_API(fil_register_retention_probe)(const char *name,
+                                         fil_retention_probe probe,
+                                         void *user_data) {
+  increment_reentrancy();
+  pymemprofile_register_retention_probe(name, probe, user_data);
+  decrement_reentrancy();
+}
+
+/// Turn recording of the callstacks that free memory on (non-zero) or off.
+__attribute__((visibility("default"))) void
+PUBLIC_API(fil_set_free_tracking)(int enabled) {
+  atomic_store_explicit(&tracking_frees, enabled != 0, memory_order_release);
+}
+
+/// Check the profiler's internal bookkeeping is consistent, for testing.
+/// Returns the number of problems found, which are also printed to stderr.
+__attribute__((visibility("default"))) int PUBLIC_API(fil_self_check)() {
+  increment_reentrancy();
+  int result = pymemprofile_self_check();
   decrement_reentrancy();
+  return result;
+}
+
+/// Find the live allocations whose callstack includes the given function in
+/// the given file. Up to out_capacity are written to out; the return value is
+/// the total number found. Slow, meant for debugging.
+__attribute__((visibility("default"))) size_t
+PUBLIC_API(fil_find_allocations_by_function)(const char *file_name,
+                                             const char *function_name,
+                                             fil_allocation_info *out,
+                                             size_t out_capacity) {
+  increment_reentrancy();
+  size_t result = pymemprofile_find_allocations_by_function(
+      file_name, function_name, out, out_capacity);
+  decrement_reentrancy();
+  return result;
+}
+
+/// Write a report of how much of the peak each line of the given function in
+/// the given file is responsible for. Returns 0, or -1 on error, e.g. if
+/// nothing at peak was allocated from the function.
+__attribute__((visibility("default"))) int
+PUBLIC_API(fil_dump_function_detail)(const char *file_name,
+                                     const char *function_name,
+                                     const char *path) {
+  increment_reentrancy();
+  int result =
+      pymemprofile_dump_function_detail(file_name, function_name, path);
+  decrement_reentrancy();
+  return result;
+}
+
+/// Write a report of the callstacks whose memory survived at least
+/// FIL_SURVIVORS resets. Returns 0, or -1 on error, e.g. if FIL_SURVIVORS isn't
+/// set.
+__attribute__((visibility("default"))) int
+PUBLIC_API(fil_dump_survivors)(const char *path) {
+  increment_reentrancy();
+  int result = pymemprofile_dump_survivors(path);
+  decrement_reentrancy();
+  return result;
+}
+
+/// Write the peak snapshot as an Arrow IPC file to the given path. Returns 0,
+/// or -1 on error, including when Fil was built without Arrow support.
+__attribute__((visibility("default"))) int
+PUBLIC_API(fil_dump_peak_to_arrow)(const char *path) {
+  increment_reentrancy();
+  int result = pymemprofile_dump_peak_to_arrow(path);
+  decrement_reentrancy();
+  return result;
 }
 
 // *** End APIs called by Python ***
+
+// *** APIs for native code, see filprofiler/fil.h ***
+
+/// Bumped on incompatible changes; new functions can be added without it.
+__attribute__((visibility("default"))) int PUBLIC_API(fil_api_version)() {
+  return 1;
+}
+
+/// Native code reporting memory Fil can't see, e.g. a block from its own
+/// arena, attributed to the current callstack plus a "[domain: <domain>]"
+/// frame. Addresses only need to be unique within the domain.
+__attribute__((visibility("default"))) void
+PUBLIC_API(fil_report_allocation)(const char *domain, void *ptr, size_t size) {
+  if (should_track_memory()) {
+    increment_reentrancy();
+    pymemprofile_report_allocation(domain, (size_t)ptr, size,
+                                   get_current_line_number());
+    decrement_reentrancy();
+  }
+}
+
+/// The memory at ptr reported with fil_report_allocation() was freed.
+__attribute__((visibility("default"))) void
+PUBLIC_API(fil_report_free)(const char *domain, void *ptr) {
+  if (should_track_memory()) {
+    increment_reentrancy();
+    pymemprofile_report_free(domain, (size_t)ptr);
+    decrement_reentrancy();
+  }
+}
+
+/// Push a "[context: <name>]" frame onto the current thread's callstack, until
+/// the matching fil_pop_context().
+__attribute__((visibility("default"))) void
+PUBLIC_API(fil_push_context)(const char *name) {
+  increment_reentrancy();
+  pymemprofile_push_context(name, get_current_line_number());
+  decrement_reentrancy();
+}
+
+/// Pop the current thread's innermost context.
+__attribute__((visibility("default"))) void PUBLIC_API(fil_pop_context)() {
+  increment_reentrancy();
+  pymemprofile_pop_context();
+  decrement_reentrancy();
+}
+
+// *** End APIs for native code ***
+
+// Only allocations that succeeded are recorded; a failure usually means we're
+// out of memory.
 static void add_allocation(size_t address, size_t size) {
-  uint16_t line_number = get_current_line_number();
-  pymemprofile_add_allocation(address, size, line_number);
+  uint32_t line_number = get_current_line_number();
+  if (address != 0) {
+    pymemprofile_add_allocation(address, size, line_number);
+  } else if (size != 0) {
+    pymemprofile_allocation_failed(size, line_number);
+  }
+}
+
+// The size calloc() was asked for, or SIZE_MAX if it overflows, which is too
+// big to be recorded.
+static inline size_t calloc_size(size_t nmemb, size_t size) {
+  size_t result;
+  if (__builtin_mul_overflow(nmemb, size, &result)) {
+    return SIZE_MAX;
+  }
+  return result;
 }
 
 static void add_anon_mmap(size_t address, size_t size) {
-  uint16_t line_number = get_current_line_number();
+  uint32_t line_number = get_current_line_number();
   pymemprofile_add_anon_mmap(address, size, line_number);
 }
 
+static void add_file_mmap(size_t address, size_t size, int fd) {
+  uint32_t line_number = get_current_line_number();
+  pymemprofile_add_file_mmap(address, size, fd, line_number);
+}
+
 // Disable memory tracking after fork() in the child.
 __attribute__((visibility("default"))) pid_t SYMBOL_PREFIX(fork)(void) {
   // Make sure subprocesses on macOS don't preload this:
@@ -408,7 +961,7 @@ __attribute__((visibility("default"))) pid_t SYMBOL_PREFIX(fork)(void) {
     // C or only Python doesn't seem to work, need both for some reason.
     setenv("__FIL_STATUS", "subprocess", 1);
     // Clear any memory if we're in icky fork()-without-exec() mode.
-    fil_stop_tracking();
+    PUBLIC_API(fil_stop_tracking)();
   }
   return result;
 }
@@ -432,10 +985,9 @@ SYMBOL_PREFIX(calloc)(size_t nmemb, size_t size) {
   increment_reentrancy();
   void *result = REAL_IMPL(calloc)(nmemb, size);
   decrement_reentrancy();
-  size_t allocated = nmemb * size;
   if (should_track_memory()) {
     increment_reentrancy();
-    add_allocation((size_t)result, allocated);
+    add_allocation((size_t)result, calloc_size(nmemb, size));
     decrement_reentrancy();
   }
   return result;
@@ -450,12 +1002,14 @@ SYMBOL_PREFIX(realloc)(void *addr, size_t size) {
   // If realloc() fails due to lack of memory, this will result in memory still
   // existing but Fil thinking it's gone. However, at that point Fil will then
   // exit with OOM report, so... not the end of the world, and unlikely in
-  // practice.
+  // practice. If it fails because the size was too big to be real,
+  // pymemprofile_realloc_failed() puts the old allocation back.
+  size_t old_size = 0;
   if (should_track_memory() && ((size_t)addr != 0)) {
     increment_reentrancy();
     // Sometimes you'll get same address, so if we did add first and then
     // removed, it would remove the entry erroneously.
-    pymemprofile_free_allocation((size_t)addr);
+    old_size = pymemprofile_free_allocation((size_t)addr);
     decrement_reentrancy();
   }
   increment_reentrancy();
@@ -463,7 +1017,13 @@ SYMBOL_PREFIX(realloc)(void *addr, size_t size) {
   decrement_reentrancy();
   if (should_track_memory()) {
     increment_reentrancy();
-    add_allocation((size_t)result, size);
+    uint32_t line_number = get_current_line_number();
+    if (result != NULL) {
+      pymemprofile_update_allocation((size_t)addr, old_size, (size_t)result,
+                                     size, line_number);
+    } else if (size != 0) {
+      pymemprofile_realloc_failed((size_t)addr, old_size, size, line_number);
+    }
     decrement_reentrancy();
   }
   return result;
@@ -504,7 +1064,12 @@ __attribute__((visibility("default"))) void SYMBOL_PREFIX(free)(void *addr) {
   // bookkeeping metadata.
   if (should_track_memory()) {
     increment_reentrancy();
-    pymemprofile_free_allocation((size_t)addr);
+    if (atomic_load_explicit(&tracking_frees, memory_order_relaxed)) {
+      pymemprofile_free_allocation_from_callstack((size_t)addr,
+                                                  get_current_line_number());
+    } else {
+      pymemprofile_free_allocation((size_t)addr);
+    }
     decrement_reentrancy();
   }
   increment_reentrancy();
@@ -526,12 +1091,16 @@ fil_mmap_impl(void *addr, size_t length, int prot, int flags, int fd,
   }
 
   void *result = underlying_real_mmap(addr, length, prot, flags, fd, offset);
-  // For now we only track anonymous mmap()s:
   if (result != MAP_FAILED && (flags & MAP_ANONYMOUS) &&
       should_track_memory()) {
     increment_reentrancy();
     add_anon_mmap((size_t)result, length);
     decrement_reentrancy();
+  } else if (result != MAP_FAILED && tracking_mapped_files && fd >= 0 &&
+             should_track_memory()) {
+    increment_reentrancy();
+    add_file_mmap((size_t)result, length, fd);
+    decrement_reentrancy();
   }
   return result;
 }
@@ -558,6 +1127,356 @@ SYMBOL_PREFIX(aligned_alloc)(size_t alignment, size_t size) {
   return result;
 }
 
+#ifdef __linux__
+// Programs and extensions that use jemalloc or mimalloc directly call e.g.
+// je_malloc() or mi_malloc(), bypassing our malloc(). When the allocator is a
+// shared library we can interpose those too, forwarding to the real
+// implementation. It's usually loaded long after we are, quite possibly with
+// RTLD_LOCAL so RTLD_NEXT can't see it, so it's looked up on first use by
+// searching every loaded library.
+struct FindSymbol {
+  const char *name;
+  void *ours;
+  void *found;
+};
+
+// Whether two symbols are in the same library. Comparing addresses isn't
+// enough, since our exported version might be a wrapper, see exports.rs.
+static int same_library(void *a, void *b) {
+  Dl_info a_info, b_info;
+  if (!dladdr(a, &a_info) || !dladdr(b, &b_info)) {
+    return 0;
+  }
+  return a_info.dli_fbase == b_info.dli_fbase;
+}
+
+static int find_symbol_in_library(struct dl_phdr_info *info, size_t size,
+                                  void *data) {
+  (void)size;
+  struct FindSymbol *find = (struct FindSymbol *)data;
+  if (info->dlpi_name == NULL || info->dlpi_name[0] == '\0') {
+    return 0;
+  }
+  void *handle = dlopen(info->dlpi_name, RTLD_LAZY | RTLD_NOLOAD);
+  if (handle == NULL) {
+    return 0;
+  }
+  void *symbol = dlsym(handle, find->name);
+  dlclose(handle);
+  if (symbol != NULL && !same_library(symbol, find->ours)) {
+    find->found = symbol;
+    return 1;
+  }
+  return 0;
+}
+
+// Returns NULL if the real allocator isn't loaded, e.g. because someone found
+// our version with dlsym() and called it, in which case the wrapper fails the
+// way the allocator would when out of memory.
+static void *find_bundled_symbol(const char *name, void *ours) {
+  increment_reentrancy();
+  struct FindSymbol find = {name, ours, dlsym(RTLD_NEXT, name)};
+  if (find.found == NULL || same_library(find.found, ours)) {
+    find.found = NULL;
+    dl_iterate_phdr(find_symbol_in_library, &find);
+  }
+  decrement_reentrancy();
+  return find.found;
+}
+
+// The real function, looked up the first time it's needed. Racing threads
+// will find the same thing, so that's fine. If it isn't loaded, we try again
+// next time, since it might be by then.
+#define BUNDLED_REAL_IMPL(func, type, missing)                                 \
+  static _Atomic(type) real = NULL;                                            \
+  type real_##func = atomic_load_explicit(&real, memory_order_relaxed);        \
+  if (unlikely(real_##func == NULL)) {                                         \
+    real_##func =                                                              \
+        (type)find_bundled_symbol(#func, (void *)&SYMBOL_PREFIX(func));        \
+    if (real_##func == NULL) {                                                 \
+      missing;                                                                 \
+    }                                                                          \
+    atomic_store_explicit(&real, real_##func, memory_order_relaxed);           \
+  }
+
+static inline int should_track_bundled_memory() {
+  return tracking_bundled_allocators && should_track_memory();
+}
+
+// The real allocator's own mmap()s are only left visible when we're not
+// tracking it, so the memory is at least partially accounted for.
+#define CALL_BUNDLED_REAL_IMPL(call)                                           \
+  if (tracking_bundled_allocators) {                                           \
+    increment_reentrancy();                                                    \
+    call;                                                                      \
+    decrement_reentrancy();                                                    \
+  } else {                                                                     \
+    call;                                                                      \
+  }
+
+// The wrappers, given the function's parameters and the arguments to pass on
+// to the real one. They only differ in how the size is calculated, and for
+// reallocation in whether the old memory is kept if it fails.
+#define BUNDLED_ALLOC(func, params, args, size)                                \
+  typedef void *(*func##_function) params;                                     \
+  __attribute__((visibility("default"))) void *SYMBOL_PREFIX(func) params {    \
+    BUNDLED_REAL_IMPL(func, func##_function, errno = ENOMEM; return NULL);     \
+    void *result;                                                              \
+    CALL_BUNDLED_REAL_IMPL(result = real_##func args);                         \
+    if (should_track_bundled_memory()) {                                       \
+      increment_reentrancy();                                                  \
+      add_allocation((size_t)result, size);                                    \
+      decrement_reentrancy();                                                  \
+    }                                                                          \
+    return result;                                                             \
+  }
+
+#define BUNDLED_REALLOC(func, params, args, addr, size, keeps_on_failure)      \
+  typedef void *(*func##_function) params;                                     \
+  __attribute__((visibility("default"))) void *SYMBOL_PREFIX(func) params {    \
+    BUNDLED_REAL_IMPL(func, func##_function, errno = ENOMEM; return NULL);     \
+    /* Removal bookkeeping first, see realloc(). */                            \
+    size_t old_size = 0;                                                       \
+    if (should_track_bundled_memory() && ((size_t)addr != 0)) {                \
+      increment_reentrancy();                                                  \
+      old_size = pymemprofile_free_allocation((size_t)addr);                   \
+      decrement_reentrancy();                                                  \
+    }                                                                          \
+    void *result;                                                              \
+    CALL_BUNDLED_REAL_IMPL(result = real_##func args);                         \
+    if (should_track_bundled_memory()) {                                       \
+      increment_reentrancy();                                                  \
+      uint32_t line_number = get_current_line_number();                        \
+      if (result != NULL) {                                                    \
+        pymemprofile_update_allocation((size_t)addr, old_size, (size_t)result, \
+                                       size, line_number);                     \
+      } else if (size != 0 && keeps_on_failure) {                              \
+        pymemprofile_realloc_failed((size_t)addr, old_size, size,              \
+                                    line_number);                              \
+      } else if (size != 0) {                                                  \
+        pymemprofile_allocation_failed(size, line_number);                     \
+      }                                                                        \
+      decrement_reentrancy();                                                  \
+    }                                                                          \
+    return result;                                                             \
+  }
+
+#define BUNDLED_FREE(func, params, args, addr)                                 \
+  typedef void (*func##_function) params;                                      \
+  __attribute__((visibility("default"))) void SYMBOL_PREFIX(func) params {     \
+    BUNDLED_REAL_IMPL(func, func##_function, return);                          \
+    /* Bookkeeping first, see free(). */                                       \
+    if (should_track_bundled_memory()) {                                       \
+      increment_reentrancy();                                                  \
+      pymemprofile_free_allocation((size_t)addr);                              \
+      decrement_reentrancy();                                                  \
+    }                                                                          \
+    CALL_BUNDLED_REAL_IMPL(real_##func args);                                  \
+  }
+
+#define BUNDLED_ALLOCATOR(prefix)                                              \
+  BUNDLED_ALLOC(prefix##malloc, (size_t size), (size), size)                   \
+  BUNDLED_ALLOC(prefix##calloc, (size_t nmemb, size_t size), (nmemb, size),    \
+                calloc_size(nmemb, size))                                      \
+  BUNDLED_REALLOC(prefix##realloc, (void *addr, size_t size), (addr, size),    \
+                  addr, size, 1)                                               \
+  BUNDLED_FREE(prefix##free, (void *addr), (addr), addr)
+
+BUNDLED_ALLOCATOR(je_)
+BUNDLED_ALLOCATOR(mi_)
+
+// The rest of the entry points that allocate or free memory, so memory we
+// track can't be moved or freed behind our back. Memory from entry points we
+// don't wrap, e.g. mi_strdup() or mimalloc's heap API, isn't tracked, and
+// freeing it is ignored, since we never recorded its address.
+BUNDLED_ALLOC(je_aligned_alloc, (size_t alignment, size_t size),
+              (alignment, size), size)
+BUNDLED_ALLOC(je_mallocx, (size_t size, int flags), (size, flags), size)
+BUNDLED_REALLOC(je_rallocx, (void *addr, size_t size, int flags),
+                (addr, size, flags), addr, size, 1)
+BUNDLED_FREE(je_dallocx, (void *addr, int flags), (addr, flags), addr)
+BUNDLED_FREE(je_sdallocx, (void *addr, size_t size, int flags),
+             (addr, size, flags), addr)
+
+__attribute__((visibility("default"))) int
+SYMBOL_PREFIX(je_posix_memalign)(void **memptr, size_t alignment, size_t size) {
+  typedef int (*je_posix_memalign_function)(void **, size_t, size_t);
+  BUNDLED_REAL_IMPL(je_posix_memalign, je_posix_memalign_function,
+                    return ENOMEM);
+  int result;
+  CALL_BUNDLED_REAL_IMPL(result =
+                             real_je_posix_memalign(memptr, alignment, size));
+  if (!result && should_track_bundled_memory()) {
+    increment_reentrancy();
+    add_allocation((size_t)*memptr, size);
+    decrement_reentrancy();
+  }
+  return result;
+}
+
+BUNDLED_ALLOC(mi_zalloc, (size_t size), (size), size)
+BUNDLED_ALLOC(mi_mallocn, (size_t count, size_t size), (count, size),
+              calloc_size(count, size))
+BUNDLED_ALLOC(mi_malloc_aligned, (size_t size, size_t alignment),
+              (size, alignment), size)
+BUNDLED_ALLOC(mi_zalloc_aligned, (size_t size, size_t alignment),
+              (size, alignment), size)
+BUNDLED_ALLOC(mi_calloc_aligned, (size_t count, size_t size, size_t alignment),
+              (count, size, alignment), calloc_size(count, size))
+BUNDLED_REALLOC(mi_reallocn, (void *addr, size_t count, size_t size),
+                (addr, count, size), addr, calloc_size(count, size), 1)
+// Frees the old memory if it fails:
+BUNDLED_REALLOC(mi_reallocf, (void *addr, size_t size), (addr, size), addr,
+                size, 0)
+BUNDLED_REALLOC(mi_rezalloc, (void *addr, size_t size), (addr, size), addr,
+                size, 1)
+BUNDLED_REALLOC(mi_recalloc, (void *addr, size_t count, size_t size),
+                (addr, count, size), addr, calloc_size(count, size), 1)
+BUNDLED_REALLOC(mi_realloc_aligned,
+                (void *addr, size_t size, size_t alignment),
+                (addr, size, alignment), addr, size, 1)
+BUNDLED_FREE(mi_free_size, (void *addr, size_t size), (addr, size), addr)
+BUNDLED_FREE(mi_free_aligned, (void *addr, size_t alignment),
+             (addr, alignment), addr)
+BUNDLED_FREE(mi_free_size_aligned,
+             (void *addr, size_t size, size_t alignment),
+             (addr, size, alignment), addr)
+
+// Temporary files, see pymemprofile_api::temp_files. Like mmap(), these are
+// exposed via --defsym under both their names, e.g. open() and open64(),
+// which also keeps them clear of glibc's fortified inline versions. The real
+// functions are looked up on first use, since other libraries' constructors
+// may open files before ours has run.
+#define NEXT_REAL_IMPL(func, type)                                             \
+  static _Atomic(type) real = NULL;                                            \
+  type real_##func = atomic_load_explicit(&real, memory_order_relaxed);        \
+  if (unlikely(real_##func == NULL)) {                                         \
+    real_##func = (type)real_symbol(#func);                                    \
+    atomic_store_explicit(&real, real_##func, memory_order_relaxed);           \
+  }
+
+typedef int (*open_function)(const char *, int, ...);
+typedef int (*openat_function)(int, const char *, int, ...);
+typedef int (*unlink_function)(const char *);
+typedef int (*unlinkat_function)(int, const char *, int);
+typedef int (*ftruncate_function)(int, off_t);
+typedef int (*fallocate_function)(int, int, off_t, off_t);
+typedef int (*posix_fallocate_function)(int, off_t, off_t);
+typedef int (*close_function)(int);
+
+static inline int should_track_temp_files() {
+  return tracking_temp_files && should_track_memory();
+}
+
+// open()'s mode argument is only passed when a file might be created.
+#define OPEN_MODE(flags)                                                       \
+  mode_t mode = 0;                                                             \
+  if (((flags)&O_CREAT) || ((flags)&O_TMPFILE) == O_TMPFILE) {                 \
+    va_list args;                                                              \
+    va_start(args, flags);                                                     \
+    mode = va_arg(args, mode_t);                                               \
+    va_end(args);                                                              \
+  }
+
+static void temp_file_opened(int fd, int flags) {
+  if (fd >= 0 && (flags & (O_CREAT | O_TMPFILE)) && should_track_temp_files()) {
+    increment_reentrancy();
+    pymemprofile_temp_file_opened(fd, flags, get_current_line_number());
+    decrement_reentrancy();
+  }
+}
+
+static void temp_file_unlinked(int result, int directory_fd, const char *path) {
+  if (result == 0 && should_track_temp_files()) {
+    increment_reentrancy();
+    pymemprofile_temp_file_unlinked(directory_fd, path);
+    decrement_reentrancy();
+  }
+}
+
+static void temp_file_resized(int result, int fd, off_t bytes, int grow_only) {
+  if (result == 0 && bytes >= 0 && should_track_temp_files()) {
+    increment_reentrancy();
+    pymemprofile_temp_file_resized(fd, (uint64_t)bytes, grow_only);
+    decrement_reentrancy();
+  }
+}
+
+__attribute__((visibility("default"))) int fil_open_impl(const char *path,
+                                                         int flags, ...) {
+  OPEN_MODE(flags);
+  NEXT_REAL_IMPL(open, open_function);
+  int fd = real_open(path, flags, mode);
+  temp_file_opened(fd, flags);
+  return fd;
+}
+
+__attribute__((visibility("default"))) int
+fil_openat_impl(int directory_fd, const char *path, int flags, ...) {
+  OPEN_MODE(flags);
+  NEXT_REAL_IMPL(openat, openat_function);
+  int fd = real_openat(directory_fd, path, flags, mode);
+  temp_file_opened(fd, flags);
+  return fd;
+}
+
+__attribute__((visibility("default"))) int fil_unlink_impl(const char *path) {
+  NEXT_REAL_IMPL(unlink, unlink_function);
+  int result = real_unlink(path);
+  temp_file_unlinked(result, AT_FDCWD, path);
+  return result;
+}
+
+__attribute__((visibility("default"))) int
+fil_unlinkat_impl(int directory_fd, const char *path, int flags) {
+  NEXT_REAL_IMPL(unlinkat, unlinkat_function);
+  int result = real_unlinkat(directory_fd, path, flags);
+  if (!(flags & AT_REMOVEDIR)) {
+    temp_file_unlinked(result, directory_fd, path);
+  }
+  return result;
+}
+
+__attribute__((visibility("default"))) int fil_ftruncate_impl(int fd,
+                                                              off_t length) {
+  NEXT_REAL_IMPL(ftruncate, ftruncate_function);
+  int result = real_ftruncate(fd, length);
+  temp_file_resized(result, fd, length, 0);
+  return result;
+}
+
+__attribute__((visibility("default"))) int
+fil_fallocate_impl(int fd, int mode, off_t offset, off_t length) {
+  NEXT_REAL_IMPL(fallocate, fallocate_function);
+  int result = real_fallocate(fd, mode, offset, length);
+  // Other modes, e.g. punching holes, don't add space:
+  if (!(mode & ~FALLOC_FL_KEEP_SIZE)) {
+    temp_file_resized(result, fd, offset + length, 1);
+  }
+  return result;
+}
+
+__attribute__((visibility("default"))) int
+fil_posix_fallocate_impl(int fd, off_t offset, off_t length) {
+  NEXT_REAL_IMPL(posix_fallocate, posix_fallocate_function);
+  int result = real_posix_fallocate(fd, offset, length);
+  temp_file_resized(result, fd, offset + length, 1);
+  return result;
+}
+
+__attribute__((visibility("default"))) int fil_close_impl(int fd) {
+  // Bookkeeping first, since as soon as it's closed another thread may get
+  // the same descriptor, see free().
+  if (should_track_temp_files()) {
+    increment_reentrancy();
+    pymemprofile_temp_file_closed(fd);
+    decrement_reentrancy();
+  }
+  NEXT_REAL_IMPL(close, close_function);
+  return real_close(fd);
+}
+#endif
+
 // Argument for wrapper_pthread_start().
 struct NewThreadArgs {
   void *callstack;
@@ -630,3 +1549,13 @@ void call_if_tracking(void (*f)(void *), void *user_data) {
 int is_initialized() {
   return initialized;
 }
+
+// Whether jemalloc's and mimalloc's own APIs are being tracked.
+int is_tracking_bundled_allocators() {
+  return tracking_bundled_allocators;
+}
+
+// Expose tracking_allocations to Rust.
+int is_tracking_allocations() {
+  return atomic_load_explicit(&tracking_allocations, memory_order_acquire);
+}
@@ -0,0 +1,296 @@
+//! Opt-in crash handler, enabled with FIL_CRASH_HANDLER=1.
+//!
+//! If a C extension segfaults, the process dies before Fil gets to write a
+//! report. So the sampler thread periodically renders the peak into one of two
+//! pre-allocated buffers, and a SIGSEGV/SIGBUS/SIGABRT handler writes out the
+//! most recently published one using only async-signal-safe calls. We may well
+//! crash while the tracker lock is held, so the handler never touches it. That
+//! includes our own open() and close(), which track temporary files, so files
+//! are opened with raw system calls, see the sys module.
+//! Afterwards the previous signal handler is restored and the signal is
+//! re-raised, so core dumps and default behavior are preserved.
+
+use std::cell::UnsafeCell;
+use std::ffi::CString;
+use std::os::raw::{c_char, c_int, c_void};
+use std::path::Path;
+use std::sync::atomic::{AtomicBool, AtomicPtr, AtomicUsize, Ordering};
+use std::time::Duration;
+
+/// How often the snapshot is refreshed, if the peak has changed.
+pub const REFRESH_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Size of each snapshot buffer. Callstacks that don't fit are left out.
+const BUFFER_SIZE: usize = 8 * 1024 * 1024;
+
+const SIGNALS: [c_int; 3] = [libc::SIGSEGV, libc::SIGBUS, libc::SIGABRT];
+
+const FILENAME: &str = "crash-peak-memory.prof";
+
+// Top bit of CrashState::published is the buffer index, the rest is length:
+const INDEX_SHIFT: u32 = usize::BITS - 1;
+const NOTHING_PUBLISHED: usize = usize::MAX;
+
+/// Pre-allocated state shared with the signal handler.
+struct CrashState {
+    buffers: [UnsafeCell<Box<[u8]>>; 2],
+    published: AtomicUsize,
+    // The peak the published snapshot is from, so we can skip refreshing when
+    // nothing changed:
+    published_peak: AtomicUsize,
+    // NUL-terminated paths of the output directory and the file in it:
+    directory: AtomicPtr<c_char>,
+    file: AtomicPtr<c_char>,
+    previous_actions: UnsafeCell<[libc::sigaction; SIGNALS.len()]>,
+}
+
+// Buffers are only written by the sampler thread, and only the buffer that
+// isn't published. The previous actions are only written before the handler
+// is installed.
+unsafe impl Sync for CrashState {}
+
+static STATE: AtomicPtr<CrashState> = AtomicPtr::new(std::ptr::null_mut());
+static CRASHED: AtomicBool = AtomicBool::new(false);
+static INSTALLED: AtomicBool = AtomicBool::new(false);
+
+fn state() -> Option<&'static CrashState> {
+    unsafe { STATE.load(Ordering::Acquire).as_ref() }
+}
+
+/// Whether FIL_CRASH_HANDLER=1 is set.
+pub fn enabled_from_env() -> bool {
+    std::env::var("FIL_CRASH_HANDLER").as_deref() == Ok("1")
+}
+
+/// Install the signal handlers, if they aren't already installed.
+pub fn install() {
+    if INSTALLED.swap(true, Ordering::AcqRel) {
+        return;
+    }
+    if let Some(state) = state() {
+        unsafe { install_handlers(state) };
+        return;
+    }
+    let state = Box::leak(Box::new(CrashState {
+        buffers: [
+            UnsafeCell::new(vec![0; BUFFER_SIZE].into_boxed_slice()),
+            UnsafeCell::new(vec![0; BUFFER_SIZE].into_boxed_slice()),
+        ],
+        published: AtomicUsize::new(NOTHING_PUBLISHED),
+        published_peak: AtomicUsize::new(0),
+        directory: AtomicPtr::new(std::ptr::null_mut()),
+        file: AtomicPtr::new(std::ptr::null_mut()),
+        previous_actions: UnsafeCell::new(unsafe { std::mem::zeroed() }),
+    }));
+    STATE.store(state, Ordering::Release);
+    unsafe { install_handlers(state) };
+}
+
+/// Install the signal handlers, remembering the previous ones.
+unsafe fn install_handlers(state: &CrashState) {
+    unsafe {
+        let mut action: libc::sigaction = std::mem::zeroed();
+        action.sa_sigaction = handle_crash as *const () as libc::sighandler_t;
+        action.sa_flags = libc::SA_SIGINFO | libc::SA_ONSTACK;
+        libc::sigemptyset(&mut action.sa_mask);
+        let previous_actions = &mut *state.previous_actions.get();
+        for (signal, previous) in SIGNALS.iter().zip(previous_actions.iter_mut()) {
+            libc::sigaction(*signal, &action, previous);
+        }
+    }
+}
+
+/// Restore the previous signal handlers, if ours are installed. The buffers
+/// are kept, in case a handler is running right now, and for reuse if the
+/// handlers are installed again.
+pub fn uninstall() {
+    if !INSTALLED.swap(false, Ordering::AcqRel) {
+        return;
+    }
+    let Some(state) = state() else {
+        return;
+    };
+    unsafe {
+        let previous_actions = &*state.previous_actions.get();
+        for (signal, previous) in SIGNALS.iter().zip(previous_actions.iter()) {
+            libc::sigaction(*signal, previous, std::ptr::null_mut());
+        }
+    }
+    state.published.store(NOTHING_PUBLISHED, Ordering::Release);
+    state.published_peak.store(0, Ordering::Release);
+}
+
+/// Set where the snapshot gets written if we crash, and forget the previous
+/// snapshot, e.g. after the tracker is reset.
+pub fn reset(directory: &str) {
+    let Some(state) = state() else {
+        return;
+    };
+    // The directory usually doesn't exist until the report is written, so
+    // rather than opening it now we keep the paths around; mkdir() and open()
+    // are async-signal-safe. Old paths are leaked, in case the handler is
+    // using them right now.
+    let file = Path::new(directory).join(FILENAME);
+    let to_c = |path: &str| {
+        CString::new(path)
+            .map(|path| path.into_raw())
+            .unwrap_or(std::ptr::null_mut())
+    };
+    state.directory.store(to_c(directory), Ordering::Release);
+    state
+        .file
+        .store(to_c(&file.to_string_lossy()), Ordering::Release);
+    state.published.store(NOTHING_PUBLISHED, Ordering::Release);
+    state.published_peak.store(0, Ordering::Release);
+}
+
+/// Whether a snapshot of the given peak still needs to be published.
+pub fn needs_refresh(peak_bytes: usize) -> bool {
+    match state() {
+        Some(state) => peak_bytes > 0 && state.published_peak.load(Ordering::Acquire) != peak_bytes,
+        None => false,
+    }
+}
+
+/// Publish a new snapshot, given lines in the collapsed flamegraph format.
+/// Should only be called from the sampler thread.
+pub fn refresh(peak_bytes: usize, lines: impl Iterator<Item = String>) {
+    let Some(state) = state() else {
+        return;
+    };
+    if CRASHED.load(Ordering::Acquire) {
+        return;
+    }
+    let published = state.published.load(Ordering::Acquire);
+    let index = if published == NOTHING_PUBLISHED {
+        0
+    } else {
+        1 - (published >> INDEX_SHIFT)
+    };
+    let buffer = unsafe { &mut *state.buffers[index].get() };
+    let mut length = 0;
+    for line in lines {
+        let line = line.as_bytes();
+        if length + line.len() + 1 > buffer.len() {
+            continue;
+        }
+        buffer[length..length + line.len()].copy_from_slice(line);
+        length += line.len();
+        buffer[length] = b'\n';
+        length += 1;
+    }
+    state
+        .published
+        .store((index << INDEX_SHIFT) | length, Ordering::Release);
+    state.published_peak.store(peak_bytes, Ordering::Release);
+}
+
+/// The file APIs the handler needs. On Linux, build.rs points this library's
+/// open(), close() and friends at the wrappers that track temporary files,
+/// which take the tracker lock and look up the real functions lazily, so we
+/// make the system calls directly instead. Elsewhere the wrappers are only
+/// interposed for other libraries, so libc is fine.
+#[cfg(target_os = "linux")]
+mod sys {
+    use std::os::raw::{c_char, c_int};
+
+    pub unsafe fn mkdir(path: *const c_char, mode: libc::mode_t) -> c_int {
+        unsafe { libc::syscall(libc::SYS_mkdirat, libc::AT_FDCWD, path, mode) as c_int }
+    }
+
+    pub unsafe fn open(path: *const c_char, flags: c_int, mode: libc::mode_t) -> c_int {
+        unsafe { libc::syscall(libc::SYS_openat, libc::AT_FDCWD, path, flags, mode) as c_int }
+    }
+
+    pub unsafe fn close(fd: c_int) -> c_int {
+        unsafe { libc::syscall(libc::SYS_close, fd) as c_int }
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+mod sys {
+    use std::os::raw::{c_char, c_int};
+
+    pub use libc::{close, mkdir};
+
+    pub unsafe fn open(path: *const c_char, flags: c_int, mode: libc::mode_t) -> c_int {
+        unsafe { libc::open(path, flags, mode as libc::c_uint) }
+    }
+}
+
+/// Write all of the data, retrying on partial writes. Async-signal-safe.
+unsafe fn write_all(fd: c_int, mut data: &[u8]) {
+    while !data.is_empty() {
+        let written = unsafe { libc::write(fd, data.as_ptr() as *const c_void, data.len()) };
+        if written < 0 {
+            if std::io::Error::last_os_error().raw_os_error() == Some(libc::EINTR) {
+                continue;
+            }
+            return;
+        }
+        data = &data[written as usize..];
+    }
+}
+
+/// Write out the published snapshot. Async-signal-safe.
+unsafe fn write_snapshot(state: &CrashState) {
+    let published = state.published.load(Ordering::Acquire);
+    let directory = state.directory.load(Ordering::Acquire);
+    let file = state.file.load(Ordering::Acquire);
+    if published == NOTHING_PUBLISHED || directory.is_null() || file.is_null() {
+        unsafe {
+            write_all(
+                libc::STDERR_FILENO,
+                b"=fil-profile= Crashed before any peak memory snapshot was taken.\n",
+            )
+        };
+        return;
+    }
+    unsafe {
+        write_all(
+            libc::STDERR_FILENO,
+            b"=fil-profile= Crashed! Writing the last peak memory snapshot to ",
+        );
+        let file_path = std::ffi::CStr::from_ptr(file);
+        write_all(libc::STDERR_FILENO, file_path.to_bytes());
+        write_all(libc::STDERR_FILENO, b"\n");
+        // Might fail because it already exists, which is fine:
+        sys::mkdir(directory, 0o755);
+        let fd = sys::open(
+            file,
+            libc::O_WRONLY | libc::O_CREAT | libc::O_TRUNC | libc::O_CLOEXEC,
+            0o644,
+        );
+        if fd < 0 {
+            write_all(
+                libc::STDERR_FILENO,
+                b"=fil-profile= Couldn't open the snapshot file.\n",
+            );
+            return;
+        }
+        let index = published >> INDEX_SHIFT;
+        let length = published & !(1 << INDEX_SHIFT);
+        let buffer = &*state.buffers[index].get();
+        write_all(fd, &buffer[..length]);
+        sys::close(fd);
+    }
+}
+
+extern "C" fn handle_crash(signal: c_int, _info: *mut libc::siginfo_t, _context: *mut c_void) {
+    let Some(state) = state() else {
+        return;
+    };
+    // If another thread crashes at the same time, only write once:
+    if !CRASHED.swap(true, Ordering::AcqRel) {
+        unsafe { write_snapshot(state) };
+    }
+    // Restore the previous handler and re-raise. The signal is blocked while
+    // we're in the handler, so it gets delivered once we return.
+    unsafe {
+        let previous_actions = &*state.previous_actions.get();
+        if let Some(position) = SIGNALS.iter().position(|s| *s == signal) {
+            libc::sigaction(signal, &previous_actions[position], std::ptr::null_mut());
+        }
+        libc::raise(signal);
+    }
+}
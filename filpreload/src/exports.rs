@@ -0,0 +1,768 @@
+//! Re-export the public C APIs from Rust.
+//!
+//! Only used when linking without lld (see build.rs). In that case we can't
+//! use our own version script, and Rust's version script only exports symbols
+//! defined in Rust, so the C code renames its public functions and we expose
+//! them here under their real names. The list here should match
+//! versionscript.txt.
+
+use libc::{off64_t, off_t, pid_t, pthread_attr_t, pthread_t};
+use std::os::raw::{c_char, c_int, c_uint, c_void};
+
+use crate::peak_callback::PeakCallback;
+use crate::retention_probes::RetentionProbe;
+use pymemprofile_api::memorytracking::AllocationInfo;
+use pymemprofile_api::sinks::OutputSinkCallback;
+
+type StartRoutine = extern "C" fn(*mut c_void) -> *mut c_void;
+
+extern "C" {
+    fn fil_c_malloc(size: usize) -> *mut c_void;
+    fn fil_c_calloc(nmemb: usize, size: usize) -> *mut c_void;
+    fn fil_c_realloc(addr: *mut c_void, size: usize) -> *mut c_void;
+    fn fil_c_free(addr: *mut c_void);
+    fn fil_c_posix_memalign(memptr: *mut *mut c_void, alignment: usize, size: usize) -> c_int;
+    fn fil_c_aligned_alloc(alignment: usize, size: usize) -> *mut c_void;
+    fn fil_c_je_malloc(size: usize) -> *mut c_void;
+    fn fil_c_je_calloc(nmemb: usize, size: usize) -> *mut c_void;
+    fn fil_c_je_realloc(addr: *mut c_void, size: usize) -> *mut c_void;
+    fn fil_c_je_free(addr: *mut c_void);
+    fn fil_c_je_aligned_alloc(alignment: usize, size: usize) -> *mut c_void;
+    fn fil_c_je_posix_memalign(memptr: *mut *mut c_void, alignment: usize, size: usize) -> c_int;
+    fn fil_c_je_mallocx(size: usize, flags: c_int) -> *mut c_void;
+    fn fil_c_je_rallocx(addr: *mut c_void, size: usize, flags: c_int) -> *mut c_void;
+    fn fil_c_je_dallocx(addr: *mut c_void, flags: c_int);
+    fn fil_c_je_sdallocx(addr: *mut c_void, size: usize, flags: c_int);
+    fn fil_c_mi_malloc(size: usize) -> *mut c_void;
+    fn fil_c_mi_calloc(nmemb: usize, size: usize) -> *mut c_void;
+    fn fil_c_mi_realloc(addr: *mut c_void, size: usize) -> *mut c_void;
+    fn fil_c_mi_free(addr: *mut c_void);
+    fn fil_c_mi_zalloc(size: usize) -> *mut c_void;
+    fn fil_c_mi_mallocn(count: usize, size: usize) -> *mut c_void;
+    fn fil_c_mi_malloc_aligned(size: usize, alignment: usize) -> *mut c_void;
+    fn fil_c_mi_zalloc_aligned(size: usize, alignment: usize) -> *mut c_void;
+    fn fil_c_mi_calloc_aligned(count: usize, size: usize, alignment: usize) -> *mut c_void;
+    fn fil_c_mi_reallocn(addr: *mut c_void, count: usize, size: usize) -> *mut c_void;
+    fn fil_c_mi_reallocf(addr: *mut c_void, size: usize) -> *mut c_void;
+    fn fil_c_mi_rezalloc(addr: *mut c_void, size: usize) -> *mut c_void;
+    fn fil_c_mi_recalloc(addr: *mut c_void, count: usize, size: usize) -> *mut c_void;
+    fn fil_c_mi_realloc_aligned(addr: *mut c_void, size: usize, alignment: usize) -> *mut c_void;
+    fn fil_c_mi_free_size(addr: *mut c_void, size: usize);
+    fn fil_c_mi_free_aligned(addr: *mut c_void, alignment: usize);
+    fn fil_c_mi_free_size_aligned(addr: *mut c_void, size: usize, alignment: usize);
+    fn fil_c_fork() -> pid_t;
+    fn fil_c_pthread_create(
+        thread: *mut pthread_t,
+        attr: *const pthread_attr_t,
+        start_routine: StartRoutine,
+        arg: *mut c_void,
+    ) -> c_int;
+    fn fil_mmap_impl(
+        addr: *mut c_void,
+        length: usize,
+        prot: c_int,
+        flags: c_int,
+        fd: c_int,
+        offset: off_t,
+    ) -> *mut c_void;
+    fn fil_open_impl(path: *const c_char, flags: c_int, ...) -> c_int;
+    fn fil_openat_impl(directory_fd: c_int, path: *const c_char, flags: c_int, ...) -> c_int;
+    fn fil_unlink_impl(path: *const c_char) -> c_int;
+    fn fil_unlinkat_impl(directory_fd: c_int, path: *const c_char, flags: c_int) -> c_int;
+    fn fil_ftruncate_impl(fd: c_int, length: off_t) -> c_int;
+    fn fil_fallocate_impl(fd: c_int, mode: c_int, offset: off_t, length: off_t) -> c_int;
+    fn fil_posix_fallocate_impl(fd: c_int, offset: off_t, length: off_t) -> c_int;
+    fn fil_close_impl(fd: c_int) -> c_int;
+
+    fn fil_initialize_from_python_c();
+    fn fil_interposition_failure_c() -> *const c_char;
+    fn fil_start_tracking_c();
+    fn fil_reset_c(default_path: *const c_char);
+    fn fil_stop_tracking_c();
+    fn fil_shutdown_c(final_dump_path: *const c_char) -> c_int;
+    fn register_fil_tracer_c();
+    fn fil_set_output_directory_c(path: *const c_char);
+    fn fil_set_python_version_c(version: *const c_char);
+    fn fil_add_metadata_c(key: *const c_char, value: *const c_char);
+    fn fil_mark_phase_c(name: *const c_char);
+    fn fil_push_phase_c(name: *const c_char);
+    fn fil_pop_phase_c();
+    fn fil_add_object_c(address: usize, type_name: *const c_char, skip_frames: u32);
+    fn fil_remove_object_c(address: usize);
+    fn fil_dump_peak_to_flamegraph_c(
+        path: *const c_char,
+        path_out: *mut c_char,
+        path_out_length: usize,
+    ) -> c_int;
+    fn fil_region_start_c(group_pre_existing: c_int) -> c_int;
+    fn fil_region_end_c(
+        path: *const c_char,
+        path_out: *mut c_char,
+        path_out_length: usize,
+    ) -> c_int;
+    fn fil_render_peak_svg_c(max_bytes: usize) -> *mut c_char;
+    fn fil_free_string_c(string: *mut c_char);
+    fn fil_get_traced_memory_c(current_out: *mut u64, peak_out: *mut u64) -> c_int;
+    fn fil_register_peak_callback_c(
+        callback: Option<PeakCallback>,
+        user_data: *mut c_void,
+        min_delta_bytes: u64,
+    );
+    fn fil_register_output_sink_c(callback: Option<OutputSinkCallback>, user_data: *mut c_void);
+    fn fil_register_retention_probe_c(
+        name: *const c_char,
+        probe: Option<RetentionProbe>,
+        user_data: *mut c_void,
+    );
+    fn fil_set_free_tracking_c(enabled: c_int);
+    fn fil_self_check_c() -> c_int;
+    fn fil_find_allocations_by_function_c(
+        file_name: *const c_char,
+        function_name: *const c_char,
+        out: *mut AllocationInfo,
+        out_capacity: usize,
+    ) -> usize;
+    fn fil_dump_function_detail_c(
+        file_name: *const c_char,
+        function_name: *const c_char,
+        path: *const c_char,
+    ) -> c_int;
+    fn fil_dump_survivors_c(path: *const c_char) -> c_int;
+    fn fil_dump_peak_to_arrow_c(path: *const c_char) -> c_int;
+    fn fil_api_version_c() -> c_int;
+    fn fil_report_allocation_c(domain: *const c_char, ptr: *mut c_void, size: usize);
+    fn fil_report_free_c(domain: *const c_char, ptr: *mut c_void);
+    fn fil_push_context_c(name: *const c_char);
+    fn fil_pop_context_c();
+}
+
+/// # Safety
+/// Standard malloc() semantics.
+#[no_mangle]
+unsafe extern "C" fn malloc(size: usize) -> *mut c_void {
+    unsafe { fil_c_malloc(size) }
+}
+
+/// # Safety
+/// Standard calloc() semantics.
+#[no_mangle]
+unsafe extern "C" fn calloc(nmemb: usize, size: usize) -> *mut c_void {
+    unsafe { fil_c_calloc(nmemb, size) }
+}
+
+/// # Safety
+/// Standard realloc() semantics.
+#[no_mangle]
+unsafe extern "C" fn realloc(addr: *mut c_void, size: usize) -> *mut c_void {
+    unsafe { fil_c_realloc(addr, size) }
+}
+
+/// # Safety
+/// Standard free() semantics.
+#[no_mangle]
+unsafe extern "C" fn free(addr: *mut c_void) {
+    unsafe { fil_c_free(addr) }
+}
+
+/// # Safety
+/// Standard posix_memalign() semantics.
+#[no_mangle]
+unsafe extern "C" fn posix_memalign(
+    memptr: *mut *mut c_void,
+    alignment: usize,
+    size: usize,
+) -> c_int {
+    unsafe { fil_c_posix_memalign(memptr, alignment, size) }
+}
+
+/// # Safety
+/// Standard aligned_alloc() semantics.
+#[no_mangle]
+unsafe extern "C" fn aligned_alloc(alignment: usize, size: usize) -> *mut c_void {
+    unsafe { fil_c_aligned_alloc(alignment, size) }
+}
+
+/// # Safety
+/// Same semantics as jemalloc's je_malloc().
+#[no_mangle]
+unsafe extern "C" fn je_malloc(size: usize) -> *mut c_void {
+    unsafe { fil_c_je_malloc(size) }
+}
+
+/// # Safety
+/// Same semantics as jemalloc's je_calloc().
+#[no_mangle]
+unsafe extern "C" fn je_calloc(nmemb: usize, size: usize) -> *mut c_void {
+    unsafe { fil_c_je_calloc(nmemb, size) }
+}
+
+/// # Safety
+/// Same semantics as jemalloc's je_realloc().
+#[no_mangle]
+unsafe extern "C" fn je_realloc(addr: *mut c_void, size: usize) -> *mut c_void {
+    unsafe { fil_c_je_realloc(addr, size) }
+}
+
+/// # Safety
+/// Same semantics as jemalloc's je_free().
+#[no_mangle]
+unsafe extern "C" fn je_free(addr: *mut c_void) {
+    unsafe { fil_c_je_free(addr) }
+}
+
+/// # Safety
+/// Same semantics as jemalloc's je_aligned_alloc().
+#[no_mangle]
+unsafe extern "C" fn je_aligned_alloc(alignment: usize, size: usize) -> *mut c_void {
+    unsafe { fil_c_je_aligned_alloc(alignment, size) }
+}
+
+/// # Safety
+/// Same semantics as jemalloc's je_posix_memalign().
+#[no_mangle]
+unsafe extern "C" fn je_posix_memalign(
+    memptr: *mut *mut c_void,
+    alignment: usize,
+    size: usize,
+) -> c_int {
+    unsafe { fil_c_je_posix_memalign(memptr, alignment, size) }
+}
+
+/// # Safety
+/// Same semantics as jemalloc's je_mallocx().
+#[no_mangle]
+unsafe extern "C" fn je_mallocx(size: usize, flags: c_int) -> *mut c_void {
+    unsafe { fil_c_je_mallocx(size, flags) }
+}
+
+/// # Safety
+/// Same semantics as jemalloc's je_rallocx().
+#[no_mangle]
+unsafe extern "C" fn je_rallocx(addr: *mut c_void, size: usize, flags: c_int) -> *mut c_void {
+    unsafe { fil_c_je_rallocx(addr, size, flags) }
+}
+
+/// # Safety
+/// Same semantics as jemalloc's je_dallocx().
+#[no_mangle]
+unsafe extern "C" fn je_dallocx(addr: *mut c_void, flags: c_int) {
+    unsafe { fil_c_je_dallocx(addr, flags) }
+}
+
+/// # Safety
+/// Same semantics as jemalloc's je_sdallocx().
+#[no_mangle]
+unsafe extern "C" fn je_sdallocx(addr: *mut c_void, size: usize, flags: c_int) {
+    unsafe { fil_c_je_sdallocx(addr, size, flags) }
+}
+
+/// # Safety
+/// Same semantics as mimalloc's mi_malloc().
+#[no_mangle]
+unsafe extern "C" fn mi_malloc(size: usize) -> *mut c_void {
+    unsafe { fil_c_mi_malloc(size) }
+}
+
+/// # Safety
+/// Same semantics as mimalloc's mi_calloc().
+#[no_mangle]
+unsafe extern "C" fn mi_calloc(nmemb: usize, size: usize) -> *mut c_void {
+    unsafe { fil_c_mi_calloc(nmemb, size) }
+}
+
+/// # Safety
+/// Same semantics as mimalloc's mi_realloc().
+#[no_mangle]
+unsafe extern "C" fn mi_realloc(addr: *mut c_void, size: usize) -> *mut c_void {
+    unsafe { fil_c_mi_realloc(addr, size) }
+}
+
+/// # Safety
+/// Same semantics as mimalloc's mi_free().
+#[no_mangle]
+unsafe extern "C" fn mi_free(addr: *mut c_void) {
+    unsafe { fil_c_mi_free(addr) }
+}
+
+/// # Safety
+/// Same semantics as mimalloc's mi_zalloc().
+#[no_mangle]
+unsafe extern "C" fn mi_zalloc(size: usize) -> *mut c_void {
+    unsafe { fil_c_mi_zalloc(size) }
+}
+
+/// # Safety
+/// Same semantics as mimalloc's mi_mallocn().
+#[no_mangle]
+unsafe extern "C" fn mi_mallocn(count: usize, size: usize) -> *mut c_void {
+    unsafe { fil_c_mi_mallocn(count, size) }
+}
+
+/// # Safety
+/// Same semantics as mimalloc's mi_malloc_aligned().
+#[no_mangle]
+unsafe extern "C" fn mi_malloc_aligned(size: usize, alignment: usize) -> *mut c_void {
+    unsafe { fil_c_mi_malloc_aligned(size, alignment) }
+}
+
+/// # Safety
+/// Same semantics as mimalloc's mi_zalloc_aligned().
+#[no_mangle]
+unsafe extern "C" fn mi_zalloc_aligned(size: usize, alignment: usize) -> *mut c_void {
+    unsafe { fil_c_mi_zalloc_aligned(size, alignment) }
+}
+
+/// # Safety
+/// Same semantics as mimalloc's mi_calloc_aligned().
+#[no_mangle]
+unsafe extern "C" fn mi_calloc_aligned(count: usize, size: usize, alignment: usize) -> *mut c_void {
+    unsafe { fil_c_mi_calloc_aligned(count, size, alignment) }
+}
+
+/// # Safety
+/// Same semantics as mimalloc's mi_reallocn().
+#[no_mangle]
+unsafe extern "C" fn mi_reallocn(addr: *mut c_void, count: usize, size: usize) -> *mut c_void {
+    unsafe { fil_c_mi_reallocn(addr, count, size) }
+}
+
+/// # Safety
+/// Same semantics as mimalloc's mi_reallocf().
+#[no_mangle]
+unsafe extern "C" fn mi_reallocf(addr: *mut c_void, size: usize) -> *mut c_void {
+    unsafe { fil_c_mi_reallocf(addr, size) }
+}
+
+/// # Safety
+/// Same semantics as mimalloc's mi_rezalloc().
+#[no_mangle]
+unsafe extern "C" fn mi_rezalloc(addr: *mut c_void, size: usize) -> *mut c_void {
+    unsafe { fil_c_mi_rezalloc(addr, size) }
+}
+
+/// # Safety
+/// Same semantics as mimalloc's mi_recalloc().
+#[no_mangle]
+unsafe extern "C" fn mi_recalloc(addr: *mut c_void, count: usize, size: usize) -> *mut c_void {
+    unsafe { fil_c_mi_recalloc(addr, count, size) }
+}
+
+/// # Safety
+/// Same semantics as mimalloc's mi_realloc_aligned().
+#[no_mangle]
+unsafe extern "C" fn mi_realloc_aligned(
+    addr: *mut c_void,
+    size: usize,
+    alignment: usize,
+) -> *mut c_void {
+    unsafe { fil_c_mi_realloc_aligned(addr, size, alignment) }
+}
+
+/// # Safety
+/// Same semantics as mimalloc's mi_free_size().
+#[no_mangle]
+unsafe extern "C" fn mi_free_size(addr: *mut c_void, size: usize) {
+    unsafe { fil_c_mi_free_size(addr, size) }
+}
+
+/// # Safety
+/// Same semantics as mimalloc's mi_free_aligned().
+#[no_mangle]
+unsafe extern "C" fn mi_free_aligned(addr: *mut c_void, alignment: usize) {
+    unsafe { fil_c_mi_free_aligned(addr, alignment) }
+}
+
+/// # Safety
+/// Same semantics as mimalloc's mi_free_size_aligned().
+#[no_mangle]
+unsafe extern "C" fn mi_free_size_aligned(addr: *mut c_void, size: usize, alignment: usize) {
+    unsafe { fil_c_mi_free_size_aligned(addr, size, alignment) }
+}
+
+/// # Safety
+/// Standard fork() semantics.
+#[no_mangle]
+unsafe extern "C" fn fork() -> pid_t {
+    unsafe { fil_c_fork() }
+}
+
+/// # Safety
+/// Standard pthread_create() semantics.
+#[no_mangle]
+unsafe extern "C" fn pthread_create(
+    thread: *mut pthread_t,
+    attr: *const pthread_attr_t,
+    start_routine: StartRoutine,
+    arg: *mut c_void,
+) -> c_int {
+    unsafe { fil_c_pthread_create(thread, attr, start_routine, arg) }
+}
+
+/// # Safety
+/// Standard mmap() semantics.
+#[no_mangle]
+unsafe extern "C" fn mmap(
+    addr: *mut c_void,
+    length: usize,
+    prot: c_int,
+    flags: c_int,
+    fd: c_int,
+    offset: off_t,
+) -> *mut c_void {
+    unsafe { fil_mmap_impl(addr, length, prot, flags, fd, offset) }
+}
+
+/// # Safety
+/// Standard mmap64() semantics.
+#[no_mangle]
+unsafe extern "C" fn mmap64(
+    addr: *mut c_void,
+    length: usize,
+    prot: c_int,
+    flags: c_int,
+    fd: c_int,
+    offset: off64_t,
+) -> *mut c_void {
+    unsafe { fil_mmap_impl(addr, length, prot, flags, fd, offset) }
+}
+
+// open() and openat() are variadic, which Rust can't define. The mode is only
+// read when a file may be created, in which case the caller passed it, and on
+// Linux variadic arguments are passed like any others, so taking it as a
+// normal argument works.
+
+/// # Safety
+/// Standard open() semantics.
+#[no_mangle]
+unsafe extern "C" fn open(path: *const c_char, flags: c_int, mode: c_uint) -> c_int {
+    unsafe { fil_open_impl(path, flags, mode) }
+}
+
+/// # Safety
+/// Standard open64() semantics.
+#[no_mangle]
+unsafe extern "C" fn open64(path: *const c_char, flags: c_int, mode: c_uint) -> c_int {
+    unsafe { fil_open_impl(path, flags, mode) }
+}
+
+/// # Safety
+/// Standard openat() semantics.
+#[no_mangle]
+unsafe extern "C" fn openat(
+    directory_fd: c_int,
+    path: *const c_char,
+    flags: c_int,
+    mode: c_uint,
+) -> c_int {
+    unsafe { fil_openat_impl(directory_fd, path, flags, mode) }
+}
+
+/// # Safety
+/// Standard openat64() semantics.
+#[no_mangle]
+unsafe extern "C" fn openat64(
+    directory_fd: c_int,
+    path: *const c_char,
+    flags: c_int,
+    mode: c_uint,
+) -> c_int {
+    unsafe { fil_openat_impl(directory_fd, path, flags, mode) }
+}
+
+/// # Safety
+/// Standard unlink() semantics.
+#[no_mangle]
+unsafe extern "C" fn unlink(path: *const c_char) -> c_int {
+    unsafe { fil_unlink_impl(path) }
+}
+
+/// # Safety
+/// Standard unlinkat() semantics.
+#[no_mangle]
+unsafe extern "C" fn unlinkat(directory_fd: c_int, path: *const c_char, flags: c_int) -> c_int {
+    unsafe { fil_unlinkat_impl(directory_fd, path, flags) }
+}
+
+#[no_mangle]
+extern "C" fn ftruncate(fd: c_int, length: off_t) -> c_int {
+    unsafe { fil_ftruncate_impl(fd, length) }
+}
+
+#[no_mangle]
+extern "C" fn ftruncate64(fd: c_int, length: off64_t) -> c_int {
+    unsafe { fil_ftruncate_impl(fd, length) }
+}
+
+#[no_mangle]
+extern "C" fn fallocate(fd: c_int, mode: c_int, offset: off_t, length: off_t) -> c_int {
+    unsafe { fil_fallocate_impl(fd, mode, offset, length) }
+}
+
+#[no_mangle]
+extern "C" fn fallocate64(fd: c_int, mode: c_int, offset: off64_t, length: off64_t) -> c_int {
+    unsafe { fil_fallocate_impl(fd, mode, offset, length) }
+}
+
+#[no_mangle]
+extern "C" fn posix_fallocate(fd: c_int, offset: off_t, length: off_t) -> c_int {
+    unsafe { fil_posix_fallocate_impl(fd, offset, length) }
+}
+
+#[no_mangle]
+extern "C" fn posix_fallocate64(fd: c_int, offset: off64_t, length: off64_t) -> c_int {
+    unsafe { fil_posix_fallocate_impl(fd, offset, length) }
+}
+
+#[no_mangle]
+extern "C" fn close(fd: c_int) -> c_int {
+    unsafe { fil_close_impl(fd) }
+}
+
+#[no_mangle]
+extern "C" fn fil_initialize_from_python() {
+    unsafe { fil_initialize_from_python_c() }
+}
+
+#[no_mangle]
+extern "C" fn fil_interposition_failure() -> *const c_char {
+    unsafe { fil_interposition_failure_c() }
+}
+
+#[no_mangle]
+extern "C" fn fil_start_tracking() {
+    unsafe { fil_start_tracking_c() }
+}
+
+/// # Safety
+/// Intended for use from C.
+#[no_mangle]
+unsafe extern "C" fn fil_reset(default_path: *const c_char) {
+    unsafe { fil_reset_c(default_path) }
+}
+
+#[no_mangle]
+extern "C" fn fil_stop_tracking() {
+    unsafe { fil_stop_tracking_c() }
+}
+
+/// # Safety
+/// Intended for use from C.
+#[no_mangle]
+unsafe extern "C" fn fil_shutdown(final_dump_path: *const c_char) -> c_int {
+    unsafe { fil_shutdown_c(final_dump_path) }
+}
+
+#[no_mangle]
+extern "C" fn register_fil_tracer() {
+    unsafe { register_fil_tracer_c() }
+}
+
+/// # Safety
+/// Intended for use from C.
+#[no_mangle]
+unsafe extern "C" fn fil_set_output_directory(path: *const c_char) {
+    unsafe { fil_set_output_directory_c(path) }
+}
+
+/// # Safety
+/// Intended for use from C.
+#[no_mangle]
+unsafe extern "C" fn fil_set_python_version(version: *const c_char) {
+    unsafe { fil_set_python_version_c(version) }
+}
+
+/// # Safety
+/// Intended for use from C.
+#[no_mangle]
+unsafe extern "C" fn fil_add_metadata(key: *const c_char, value: *const c_char) {
+    unsafe { fil_add_metadata_c(key, value) }
+}
+
+/// # Safety
+/// Intended for use from C.
+#[no_mangle]
+unsafe extern "C" fn fil_mark_phase(name: *const c_char) {
+    unsafe { fil_mark_phase_c(name) }
+}
+
+/// # Safety
+/// Intended for use from C.
+#[no_mangle]
+unsafe extern "C" fn fil_push_phase(name: *const c_char) {
+    unsafe { fil_push_phase_c(name) }
+}
+
+#[no_mangle]
+extern "C" fn fil_pop_phase() {
+    unsafe { fil_pop_phase_c() }
+}
+
+/// # Safety
+/// Intended for use from C.
+#[no_mangle]
+unsafe extern "C" fn fil_add_object(address: usize, type_name: *const c_char, skip_frames: u32) {
+    unsafe { fil_add_object_c(address, type_name, skip_frames) }
+}
+
+#[no_mangle]
+extern "C" fn fil_remove_object(address: usize) {
+    unsafe { fil_remove_object_c(address) }
+}
+
+/// # Safety
+/// Intended for use from C.
+#[no_mangle]
+unsafe extern "C" fn fil_dump_peak_to_flamegraph(
+    path: *const c_char,
+    path_out: *mut c_char,
+    path_out_length: usize,
+) -> c_int {
+    unsafe { fil_dump_peak_to_flamegraph_c(path, path_out, path_out_length) }
+}
+
+#[no_mangle]
+extern "C" fn fil_region_start(group_pre_existing: c_int) -> c_int {
+    unsafe { fil_region_start_c(group_pre_existing) }
+}
+
+/// # Safety
+/// Intended for use from C.
+#[no_mangle]
+unsafe extern "C" fn fil_region_end(
+    path: *const c_char,
+    path_out: *mut c_char,
+    path_out_length: usize,
+) -> c_int {
+    unsafe { fil_region_end_c(path, path_out, path_out_length) }
+}
+
+#[no_mangle]
+extern "C" fn fil_render_peak_svg(max_bytes: usize) -> *mut c_char {
+    unsafe { fil_render_peak_svg_c(max_bytes) }
+}
+
+/// # Safety
+/// Intended for use from C.
+#[no_mangle]
+unsafe extern "C" fn fil_free_string(string: *mut c_char) {
+    unsafe { fil_free_string_c(string) }
+}
+
+/// # Safety
+/// Intended for use from C.
+#[no_mangle]
+unsafe extern "C" fn fil_get_traced_memory(current_out: *mut u64, peak_out: *mut u64) -> c_int {
+    unsafe { fil_get_traced_memory_c(current_out, peak_out) }
+}
+
+/// # Safety
+/// Intended for use from C.
+#[no_mangle]
+unsafe extern "C" fn fil_register_peak_callback(
+    callback: Option<PeakCallback>,
+    user_data: *mut c_void,
+    min_delta_bytes: u64,
+) {
+    unsafe { fil_register_peak_callback_c(callback, user_data, min_delta_bytes) }
+}
+
+/// # Safety
+/// Intended for use from C.
+#[no_mangle]
+unsafe extern "C" fn fil_register_output_sink(
+    callback: Option<OutputSinkCallback>,
+    user_data: *mut c_void,
+) {
+    unsafe { fil_register_output_sink_c(callback, user_data) }
+}
+
+/// # Safety
+/// Intended for use from C.
+#[no_mangle]
+unsafe extern "C" fn fil_register_retention_probe(
+    name: *const c_char,
+    probe: Option<RetentionProbe>,
+    user_data: *mut c_void,
+) {
+    unsafe { fil_register_retention_probe_c(name, probe, user_data) }
+}
+
+/// # Safety
+/// Intended for use from C.
+#[no_mangle]
+unsafe extern "C" fn fil_set_free_tracking(enabled: c_int) {
+    unsafe { fil_set_free_tracking_c(enabled) }
+}
+
+#[no_mangle]
+extern "C" fn fil_self_check() -> c_int {
+    unsafe { fil_self_check_c() }
+}
+
+/// # Safety
+/// Intended for use from C.
+#[no_mangle]
+unsafe extern "C" fn fil_find_allocations_by_function(
+    file_name: *const c_char,
+    function_name: *const c_char,
+    out: *mut AllocationInfo,
+    out_capacity: usize,
+) -> usize {
+    unsafe { fil_find_allocations_by_function_c(file_name, function_name, out, out_capacity) }
+}
+
+/// # Safety
+/// Intended for use from C.
+#[no_mangle]
+unsafe extern "C" fn fil_dump_function_detail(
+    file_name: *const c_char,
+    function_name: *const c_char,
+    path: *const c_char,
+) -> c_int {
+    unsafe { fil_dump_function_detail_c(file_name, function_name, path) }
+}
+
+/// # Safety
+/// Intended for use from C.
+#[no_mangle]
+unsafe extern "C" fn fil_dump_survivors(path: *const c_char) -> c_int {
+    unsafe { fil_dump_survivors_c(path) }
+}
+
+/// # Safety
+/// Intended for use from C.
+#[no_mangle]
+unsafe extern "C" fn fil_dump_peak_to_arrow(path: *const c_char) -> c_int {
+    unsafe { fil_dump_peak_to_arrow_c(path) }
+}
+
+#[no_mangle]
+extern "C" fn fil_api_version() -> c_int {
+    unsafe { fil_api_version_c() }
+}
+
+/// # Safety
+/// Intended for use from C.
+#[no_mangle]
+unsafe extern "C" fn fil_report_allocation(domain: *const c_char, ptr: *mut c_void, size: usize) {
+    unsafe { fil_report_allocation_c(domain, ptr, size) }
+}
+
+/// # Safety
+/// Intended for use from C.
+#[no_mangle]
+unsafe extern "C" fn fil_report_free(domain: *const c_char, ptr: *mut c_void) {
+    unsafe { fil_report_free_c(domain, ptr) }
+}
+
+/// # Safety
+/// Intended for use from C.
+#[no_mangle]
+unsafe extern "C" fn fil_push_context(name: *const c_char) {
+    unsafe { fil_push_context_c(name) }
+}
+
+#[no_mangle]
+extern "C" fn fil_pop_context() {
+    unsafe { fil_pop_context_c() }
+}
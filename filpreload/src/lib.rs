@@ -1,19 +1,45 @@
 #![deny(unsafe_op_in_unsafe_fn)]
 use parking_lot::Mutex;
+use pymemprofile_api::bundled_allocators;
+use pymemprofile_api::cgroup::{self, CgroupWatchdog};
+use pymemprofile_api::mapped_files;
 use pymemprofile_api::memorytracking::LineNumberInfo::LineNumber;
 use pymemprofile_api::memorytracking::{
-    AllocationTracker, CallSiteId, Callstack, FunctionId, IdentityCleaner, VecFunctionLocations,
-    PARENT_PROCESS,
+    qualified_name, AllocationInfo, AllocationKind, AllocationTracker, CallSiteId, Callstack,
+    CallstackId, ExitSummary, FunctionId, IdentityCleaner, ProcessUid, VecFunctionLocations,
+    WriteFunctionLocations, PARENT_PROCESS,
 };
 use pymemprofile_api::oom::{InfiniteMemory, OutOfMemoryEstimator, RealMemoryInfo};
-use std::cell::RefCell;
+use pymemprofile_api::regions::PreExisting;
+use pymemprofile_api::rotation;
+use pymemprofile_api::sinks::{self, CallbackSink, OutputSinkCallback};
+use pymemprofile_api::temp_files;
+use pymemprofile_api::threads;
+use std::cell::{Cell, RefCell};
+use std::collections::HashMap;
 use std::ffi::CStr;
 use std::os::raw::{c_char, c_int, c_void};
 use std::path::Path;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Once;
 
 #[macro_use]
 extern crate lazy_static;
 
+mod crash;
+// The libc replacements and public API, which _filpreload.c doesn't call:
+#[cfg(fil_rust_exports)]
+/// cbindgen:ignore
+mod exports;
+mod peak_callback;
+mod reentrancy;
+mod retention_probes;
+mod sampler;
+
+use peak_callback::{PeakCallback, PeakNotifier};
+use reentrancy::InTracker;
+use retention_probes::RetentionProbe;
+
 #[cfg(target_os = "linux")]
 use tikv_jemallocator::Jemalloc;
 
@@ -23,11 +49,30 @@ static GLOBAL: Jemalloc = Jemalloc;
 
 thread_local!(static THREAD_CALLSTACK: RefCell<Callstack> = RefCell::new(Callstack::new()));
 
+// Whether THREAD_CALLSTACK was ever set up, by start_call() or by inheriting it
+// when the thread was created. If not, this thread was started behind our
+// back, e.g. by native code before Fil was initialized, and THREAD_CALLSTACK
+// means nothing.
+thread_local!(static THREAD_REGISTERED: Cell<bool> = const { Cell::new(false) });
+
 struct TrackerState {
     oom: OutOfMemoryEstimator,
     allocations: AllocationTracker<VecFunctionLocations>,
+    peak_notifier: Option<PeakNotifier>,
+    // Synthetic functions for phase frames, so repeated names are cheap:
+    phase_frame_functions: HashMap<String, FunctionId>,
+    // Likewise for `[mapped file: <name>]` frames:
+    mapped_file_functions: HashMap<String, FunctionId>,
+    // And for `[context: <name>]` frames:
+    context_functions: HashMap<String, FunctionId>,
+    // Memory reported by native code, by domain: its `[domain: <name>]` frame
+    // and address namespace.
+    domains: HashMap<String, (FunctionId, ProcessUid)>,
 }
 
+// These are parking_lot mutexes, which don't get poisoned: if something
+// panics while a lock is held, later calls still get the lock, instead of every
+// subsequent allocation panicking too.
 lazy_static! {
     static ref TRACKER_STATE: Mutex<TrackerState> = Mutex::new(TrackerState {
         allocations: AllocationTracker::new("/tmp".to_string(), VecFunctionLocations::new()),
@@ -38,17 +83,48 @@ lazy_static! {
                 Box::new(RealMemoryInfo::default())
             }
         ),
+        peak_notifier: None,
+        phase_frame_functions: HashMap::new(),
+        mapped_file_functions: HashMap::new(),
+        context_functions: HashMap::new(),
+        domains: HashMap::new(),
     });
+    // Kept separate from TRACKER_STATE, so reading cgroup files doesn't block
+    // allocations:
+    static ref CGROUP_WATCHDOG: Mutex<Option<CgroupWatchdog>> =
+        Mutex::new(CgroupWatchdog::from_env());
+    // Where dumps without an explicit path go. If unset, the default path
+    // passed to reset() is used.
+    static ref OUTPUT_DIRECTORY: Mutex<Option<String>> = Mutex::new(None);
+}
+
+// Set by pymemprofile_shutdown(), cleared by reset():
+static SHUT_DOWN: AtomicBool = AtomicBool::new(false);
+
+/// Returned by APIs called after pymemprofile_shutdown().
+const ALREADY_SHUT_DOWN: c_int = -2;
+
+/// Returned by dumps when the registered output sink failed on some of the
+/// files, which were still written to disk.
+const OUTPUT_SINK_FAILED: c_int = -3;
+
+fn is_shut_down() -> bool {
+    SHUT_DOWN.load(Ordering::Acquire)
 }
 
 /// Register a new function/filename location.
-fn add_function(filename: String, function_name: String) -> FunctionId {
+fn add_function(
+    filename: String,
+    function_name: String,
+    qualified_name: Option<String>,
+) -> FunctionId {
     let tracker_state = TRACKER_STATE.try_lock();
     if let Some(mut tracker_state) = tracker_state {
-        tracker_state
-            .allocations
-            .functions
-            .add_function(filename, function_name)
+        tracker_state.allocations.functions.add_qualified_function(
+            filename,
+            function_name,
+            qualified_name,
+        )
     } else {
         // This will help in SIGUSR2 handler: dumping calls into Python, we
         // can't really acquire lock since it's in the middle of dumping. So
@@ -58,11 +134,12 @@ fn add_function(filename: String, function_name: String) -> FunctionId {
 }
 
 /// Add to per-thread function stack:
-fn start_call(call_site: FunctionId, parent_line_number: u16, line_number: u16) {
+fn start_call(call_site: FunctionId, parent_line_number: u32, line_number: u32) {
+    THREAD_REGISTERED.with(|registered| registered.set(true));
     THREAD_CALLSTACK.with(|cs| {
         cs.borrow_mut().start_call(
-            parent_line_number as u32,
-            CallSiteId::new(call_site, LineNumber(line_number as u32)),
+            parent_line_number,
+            CallSiteId::new(call_site, LineNumber(line_number)),
         );
     });
 }
@@ -75,6 +152,128 @@ fn finish_call() {
     });
 }
 
+/// The synthetic function with the given name, registering it with the given
+/// filename if it's not in the cache yet.
+fn synthetic_function(
+    cache: &mut HashMap<String, FunctionId>,
+    functions: &mut VecFunctionLocations,
+    filename: &str,
+    name: &str,
+) -> FunctionId {
+    match cache.get(name) {
+        Some(function) => *function,
+        None => {
+            let function = functions.add_function(filename.to_string(), name.to_string());
+            cache.insert(name.to_string(), function);
+            function
+        }
+    }
+}
+
+/// Push a `[phase: <name>]` frame onto the current thread's callstack.
+fn push_phase(name: &str) {
+    let function = {
+        let mut tracker_state = TRACKER_STATE.lock();
+        let tracker_state = &mut *tracker_state;
+        synthetic_function(
+            &mut tracker_state.phase_frame_functions,
+            &mut tracker_state.allocations.functions,
+            "[phase]",
+            name,
+        )
+    };
+    let pushed = THREAD_CALLSTACK.with(|cs| cs.borrow_mut().push_phase_frame(function));
+    static WARNED: Once = Once::new();
+    if !pushed {
+        WARNED.call_once(|| {
+            eprintln!(
+                "=fil-profile= WARNING: Phases are nested too deeply, ignoring phase {:?}.",
+                name
+            );
+        });
+    }
+}
+
+/// Pop the innermost phase frame from the current thread's callstack.
+fn pop_phase() {
+    THREAD_CALLSTACK.with(|cs| cs.borrow_mut().pop_phase_frame());
+}
+
+/// The frame and address namespace for memory reported under the given
+/// domain.
+fn domain(tracker_state: &mut TrackerState, name: &str) -> (FunctionId, ProcessUid) {
+    if let Some(domain) = tracker_state.domains.get(name) {
+        return *domain;
+    }
+    let function = tracker_state
+        .allocations
+        .functions
+        .add_function("[domain]".to_string(), name.to_string());
+    let domain = (
+        function,
+        ProcessUid::for_domain(tracker_state.domains.len() as u32),
+    );
+    tracker_state.domains.insert(name.to_string(), domain);
+    domain
+}
+
+/// Add memory that native code reported itself, see filprofiler/fil.h.
+fn report_allocation(domain_name: &str, address: usize, size: usize, line_number: u32) {
+    // Threads that never ran Python code only have the contexts pushed by
+    // native code, which is what we want here. Will fail during thread
+    // shutdown, but not much we can do at that point.
+    let Ok(callstack) = THREAD_CALLSTACK.try_with(|cs| cs.borrow().clone()) else {
+        return;
+    };
+    let mut tracker_state = TRACKER_STATE.lock();
+    let (function, process) = domain(&mut tracker_state, domain_name);
+    let allocations = &mut tracker_state.allocations;
+    let mut callstack = callstack.with_synthetic_leaf(line_number, function);
+    callstack.set_phase(allocations.current_phase());
+    let callstack_id = allocations.get_callstack_id(&callstack);
+    let allocated_bytes_before = allocations.get_current_allocated_bytes();
+    allocations.add_allocation(process, address, size, callstack_id);
+    reentrancy::count_recorded();
+    threads::record_allocation(
+        allocations
+            .get_current_allocated_bytes()
+            .saturating_sub(allocated_bytes_before),
+    );
+}
+
+/// Remove memory added with report_allocation().
+fn report_free(domain_name: &str, address: usize) {
+    let mut tracker_state = TRACKER_STATE.lock();
+    let Some((_, process)) = tracker_state.domains.get(domain_name).copied() else {
+        return;
+    };
+    let size = tracker_state
+        .allocations
+        .free_allocation(process, address)
+        .unwrap_or(0);
+    threads::record_free(size);
+}
+
+/// Push a `[context: <name>]` frame onto the current thread's callstack.
+fn push_context(name: &str, line_number: u32) {
+    let function = {
+        let mut tracker_state = TRACKER_STATE.lock();
+        let tracker_state = &mut *tracker_state;
+        synthetic_function(
+            &mut tracker_state.context_functions,
+            &mut tracker_state.allocations.functions,
+            "[context]",
+            name,
+        )
+    };
+    THREAD_CALLSTACK.with(|cs| cs.borrow_mut().push_synthetic_frame(line_number, function));
+}
+
+/// Pop the innermost context frame from the current thread's callstack.
+fn pop_context() {
+    THREAD_CALLSTACK.with(|cs| cs.borrow_mut().pop_synthetic_frame());
+}
+
 /// Get the current thread's callstack.
 fn get_current_callstack() -> Callstack {
     THREAD_CALLSTACK.with(|cs| (*cs.borrow()).clone())
@@ -83,6 +282,7 @@ fn get_current_callstack() -> Callstack {
 /// Set the current callstack. Typically should only be used when starting up
 /// new threads.
 fn set_current_callstack(callstack: &Callstack) {
+    THREAD_REGISTERED.with(|registered| registered.set(true));
     THREAD_CALLSTACK.with(|cs| {
         *cs.borrow_mut() = callstack.clone();
     })
@@ -93,6 +293,21 @@ extern "C" {
     fn free(address: *mut c_void);
 }
 
+/// The id of the current thread's callstack, or of the `[unknown native
+/// thread]` callstack if this thread's callstack was never set up.
+///
+/// Will fail during thread shutdown, but not much we can do at that point.
+fn current_callstack_id(
+    allocations: &mut AllocationTracker<VecFunctionLocations>,
+    line_number: u32,
+) -> Result<CallstackId, std::thread::AccessError> {
+    if !THREAD_REGISTERED.with(|registered| registered.get()) {
+        return Ok(allocations.get_callstack_id(&Callstack::unknown_native_thread()));
+    }
+    THREAD_CALLSTACK
+        .try_with(|tcs| allocations.callstack_id_for_allocation(&mut tcs.borrow_mut(), line_number))
+}
+
 /// Add a new allocation based off the current callstack.
 ///
 /// This can fail if the thread local with the Python stack is not available.
@@ -101,13 +316,21 @@ extern "C" {
 fn add_allocation(
     address: usize,
     size: usize,
-    line_number: u16,
-    is_mmap: bool,
+    line_number: u32,
+    kind: AllocationKind,
 ) -> Result<(), std::thread::AccessError> {
+    let is_mmap = kind == AllocationKind::Mmap;
     let mut tracker_state = TRACKER_STATE.lock();
+    // Too big to be real, see pymemprofile_api::size_ceiling; if it failed,
+    // that's no sign of being out of memory either:
+    if !tracker_state.allocations.allows_size(size) {
+        tracker_state.allocations.reject_allocation(size);
+        return Ok(());
+    }
     let current_allocated_bytes = tracker_state.allocations.get_current_allocated_bytes();
 
-    // Check if we're out of memory:
+    // Check if we're out of memory; an address of 0 means the allocation
+    // failed, see pymemprofile_allocation_failed():
     let oom = (address == 0)
         || tracker_state
             .oom
@@ -140,18 +363,10 @@ fn add_allocation(
 
     let allocations = &mut tracker_state.allocations;
     // Will fail during thread shutdown, but not much we can do at that point.
-    let callstack_id = THREAD_CALLSTACK.try_with(|tcs| {
-        let mut callstack = tcs.borrow_mut();
-        callstack.id_for_new_allocation(line_number as u32, |callstack| {
-            allocations.get_callstack_id(callstack)
-        })
+    allocations.record_allocation(address, size, kind, |allocations| {
+        current_callstack_id(allocations, line_number)
     })?;
-
-    if is_mmap {
-        allocations.add_anon_mmap(PARENT_PROCESS, address, size, callstack_id);
-    } else {
-        allocations.add_allocation(PARENT_PROCESS, address, size, callstack_id);
-    }
+    reentrancy::count_recorded();
 
     if oom {
         // Uh-oh, we're out of memory.
@@ -172,16 +387,43 @@ fn add_allocation(
         unsafe {
             _exit(53);
         }
-    };
+    } else {
+        let tracker_state = &mut *tracker_state;
+        if let Some(notifier) = &mut tracker_state.peak_notifier {
+            let allocations = &tracker_state.allocations;
+            let current_allocated_bytes = allocations.get_current_allocated_bytes();
+            if notifier.is_due(current_allocated_bytes) {
+                notifier.notify(
+                    current_allocated_bytes,
+                    allocations.dominant_callstack(),
+                    allocations.functions.cheap_clone(),
+                );
+            }
+        }
+    }
     Ok(())
 }
 
-/// Free an existing allocation.
-fn free_allocation(address: usize) {
+/// Free an existing allocation, returning its size, or 0 if it wasn't tracked.
+fn free_allocation(address: usize) -> usize {
+    TRACKER_STATE.lock().allocations.record_free(address)
+}
+
+/// Free an existing allocation, recording the current callstack as the one
+/// that freed it. Returns its size, or 0 if it wasn't tracked.
+fn free_allocation_from_callstack(address: usize, line_number: u32) -> usize {
     let mut tracker_state = TRACKER_STATE.lock();
 
     let allocations = &mut tracker_state.allocations;
-    allocations.free_allocation(PARENT_PROCESS, address);
+    // Will fail during thread shutdown, in which case just do a normal free.
+    let callstack_id = current_callstack_id(allocations, line_number);
+    let size = match callstack_id {
+        Ok(callstack_id) => allocations.free_allocation_from(PARENT_PROCESS, address, callstack_id),
+        Err(_) => allocations.free_allocation(PARENT_PROCESS, address),
+    }
+    .unwrap_or(0);
+    threads::record_free(size);
+    size
 }
 
 /// Get the size of an allocation, or 0 if it's not tracked.
@@ -195,8 +437,231 @@ fn get_allocation_size(address: usize) -> usize {
 fn reset(default_path: String) {
     // Make sure we initialize this static, to prevent deadlocks:
     pymemprofile_api::ffi::initialize();
+    SHUT_DOWN.store(false, Ordering::Release);
     let mut tracker_state = TRACKER_STATE.lock();
-    tracker_state.allocations.reset(default_path);
+    tracker_state.allocations.reset(default_path.clone());
+    threads::reset();
+    if let Some(notifier) = &mut tracker_state.peak_notifier {
+        notifier.reset();
+    }
+    let timeline_interval = tracker_state.allocations.timeline_interval();
+    let allocation_rates_interval = tracker_state.allocations.allocation_rates_interval();
+    let drift_interval = tracker_state.allocations.drift_interval();
+    let live_view_interval = tracker_state.allocations.live_view_interval();
+    let milestones_interval = tracker_state.allocations.milestones_interval();
+    let rotation_interval = tracker_state.allocations.rotation_interval();
+    drop(tracker_state);
+    if let Some(interval) = timeline_interval {
+        sampler::add_task("timeline", interval, || {
+            TRACKER_STATE.lock().allocations.sample_timeline();
+        });
+    }
+    if let Some(interval) = allocation_rates_interval {
+        sampler::add_task("allocation-rates", interval, || {
+            TRACKER_STATE.lock().allocations.sample_allocation_rates();
+        });
+    }
+    if let Some(interval) = drift_interval {
+        sampler::add_task("drift", interval, check_drift);
+    }
+    if let Some(interval) = live_view_interval {
+        // Rendering callstacks needs runpy's path, see below:
+        if unsafe { pyo3::ffi::Py_IsInitialized() } != 0 {
+            pymemprofile_api::python::get_runpy_path();
+        }
+        sampler::add_task("live-view", interval, update_live_view);
+    }
+    if let Some(interval) = milestones_interval {
+        if unsafe { pyo3::ffi::Py_IsInitialized() } != 0 {
+            pymemprofile_api::python::get_runpy_path();
+        }
+        sampler::add_task("milestones", interval, write_milestone_dumps);
+    }
+    if let Some(interval) = rotation_interval {
+        if unsafe { pyo3::ffi::Py_IsInitialized() } != 0 {
+            pymemprofile_api::python::get_runpy_path();
+        }
+        sampler::add_task("rotation", interval, rotate_window);
+    }
+    if let Some(watchdog) = CGROUP_WATCHDOG.lock().as_mut() {
+        watchdog.rearm();
+        sampler::add_task(
+            "cgroup-watchdog",
+            cgroup::CHECK_INTERVAL,
+            check_cgroup_limit,
+        );
+    }
+    if crash::enabled_from_env() {
+        // Rendering callstacks needs runpy's path, which needs the GIL, and
+        // the sampler thread doesn't have it:
+        if unsafe { pyo3::ffi::Py_IsInitialized() } != 0 {
+            pymemprofile_api::python::get_runpy_path();
+        }
+        crash::install();
+        crash::reset(&default_path);
+        sampler::add_task(
+            "crash-snapshot",
+            crash::REFRESH_INTERVAL,
+            refresh_crash_snapshot,
+        );
+    }
+}
+
+/// Called periodically by the sampler thread, to keep a copy of the peak that
+/// the crash handler can write out without taking any locks.
+fn refresh_crash_snapshot() {
+    if unsafe { is_tracking_allocations() } == 0 {
+        return;
+    }
+    let (peak, flamegraph_callstacks_factory) = {
+        let mut tracker_state = TRACKER_STATE.lock();
+        let allocations = &mut tracker_state.allocations;
+        let (_, peak) = allocations.get_traced_memory();
+        if !crash::needs_refresh(peak) {
+            return;
+        }
+        (peak, allocations.combine_callstacks(true, IdentityCleaner))
+    };
+    let flamegraph_callstacks = flamegraph_callstacks_factory();
+    crash::refresh(peak, flamegraph_callstacks.to_lines(false));
+}
+
+/// Called periodically by the sampler thread, to write out what changed in
+/// current memory usage for the live view.
+fn update_live_view() {
+    if unsafe { is_tracking_allocations() } == 0 {
+        return;
+    }
+    let Some((directory, write)) = TRACKER_STATE.lock().allocations.update_live_view() else {
+        return;
+    };
+    match write() {
+        Ok(true) => eprintln!(
+            "=fil-profile= Live view is in {}, to watch it run: python -m http.server -d {}",
+            directory.display(),
+            directory.display()
+        ),
+        Ok(false) => {}
+        Err(err) => {
+            static WARNED: AtomicBool = AtomicBool::new(false);
+            if !WARNED.swap(true, Ordering::Relaxed) {
+                eprintln!("=fil-profile= Failed to write the live view: {}", err);
+            }
+        }
+    }
+}
+
+/// Called periodically by the sampler thread, and before the peak is dumped,
+/// to write out the snapshots taken when tracked memory first went over each
+/// of FIL_MILESTONES.
+fn write_milestone_dumps() {
+    let _in_tracker = InTracker::enter();
+    let (default_path, dumps) = {
+        let mut tracker_state = TRACKER_STATE.lock();
+        let allocations = &mut tracker_state.allocations;
+        if !allocations.has_pending_milestone_dumps() {
+            return;
+        }
+        (
+            allocations.default_path.clone(),
+            allocations.milestone_dumps(),
+        )
+    };
+    let directory_path = Path::new(&default_path);
+    for (milestones, allocated_bytes, flamegraph_callstacks_factory) in dumps {
+        let flamegraph_callstacks = flamegraph_callstacks_factory();
+        for milestone in milestones {
+            flamegraph_callstacks.write_memory_flamegraphs(
+                directory_path,
+                &milestone.base_filename(),
+                &format!("Tracked memory when it first went over {}", milestone.label),
+                allocated_bytes,
+                false,
+            );
+        }
+    }
+}
+
+/// Called periodically by the sampler thread, to write out the current
+/// window's peak and start the next one once FIL_ROTATE_WINDOW is up.
+fn rotate_window() {
+    if unsafe { is_tracking_allocations() } == 0 {
+        return;
+    }
+    // Milestones crossed in the window that's ending:
+    write_milestone_dumps();
+    let _in_tracker = InTracker::enter();
+    let (default_path, (finished, peak, flamegraph_callstacks_factory)) = {
+        let mut tracker_state = TRACKER_STATE.lock();
+        let allocations = &mut tracker_state.allocations;
+        let Some(rotated) = allocations.rotate_window() else {
+            return;
+        };
+        (allocations.default_path.clone(), rotated)
+    };
+    let directory_path = Path::new(&default_path);
+    flamegraph_callstacks_factory().write_memory_flamegraphs(
+        directory_path,
+        &finished.base_filename(),
+        &format!("Peak tracked memory in window {}", finished.number),
+        peak,
+        false,
+    );
+    for expired in &finished.expired {
+        rotation::delete_window(directory_path, expired);
+    }
+}
+
+/// Called periodically by the sampler thread, to notice when tracked memory
+/// drifts away from what the allocator says is in use.
+fn check_drift() {
+    if unsafe { is_tracking_allocations() } == 0 {
+        return;
+    }
+    // Queried first, so the allocator's locks aren't taken with ours held:
+    let Ok(stats) = pymemprofile_api::allocator_stats::probe() else {
+        return;
+    };
+    let warning = TRACKER_STATE
+        .lock()
+        .allocations
+        .sample_drift(stats.in_use_bytes);
+    if let Some(warning) = warning {
+        eprintln!("=fil-profile= WARNING: {}", warning);
+    }
+}
+
+/// Called periodically by the sampler thread. If we're about to hit the
+/// cgroup memory limit, the OOM killer will likely kill the process without
+/// warning, so write out a report while we still can.
+fn check_cgroup_limit() {
+    if unsafe { is_tracking_allocations() } == 0 {
+        return;
+    }
+    let usage = match CGROUP_WATCHDOG.lock().as_mut() {
+        Some(watchdog) => watchdog.check().map(|usage| (usage, watchdog.threshold())),
+        None => None,
+    };
+    if let Some((usage, threshold)) = usage {
+        eprintln!(
+            "=fil-profile= WARNING: cgroup memory usage of {} bytes is over {:.0}% of the limit of {} bytes ({:?}), so the process may be killed by the out-of-memory killer. Writing a report in case that happens.",
+            usage.usage_bytes,
+            threshold * 100.0,
+            usage.limit_bytes,
+            usage.limit_path
+        );
+        let default_path = TRACKER_STATE.lock().allocations.default_path.clone();
+        // Current allocations rather than peak, which is lower-overhead since
+        // it doesn't involve extra copying or extra reports, and close to the
+        // limit the two are very similar anyway:
+        dump_to_flamegraph(
+            &default_path,
+            false,
+            "near-cgroup-limit",
+            "Current allocations near the cgroup memory limit",
+            false,
+        );
+    }
 }
 
 fn dump_to_flamegraph(
@@ -206,60 +671,205 @@ fn dump_to_flamegraph(
     title: &str,
     to_be_post_processed: bool,
 ) {
+    // Already inside the tracker if this is the out-of-memory dump:
+    let _in_tracker = InTracker::enter();
+    let recorded_before = reentrancy::recorded();
+
     // In order to render the flamegraph, we want to load source code using
     // Python's linecache. That means calling into Python, which might release
     // the GIL, allowing another thread to run, and it will try to allocation
     // and hit the TRACKER_STATE mutex. And now we're deadlocked. So we make
     // sure flamegraph rendering does not require TRACKER_STATE to be locked.
-    let (allocated_bytes, flamegraph_callstacks_factory) = {
+    let mut report = TRACKER_STATE.lock().allocations.peak_report(peak);
+    if peak {
+        report.metadata.bundled_allocators =
+            bundled_allocators::detect(tracking_bundled_allocators());
+        report.metadata.retained_by_caches = retention_probes::run_all();
+    }
+    report.write(Path::new(path), base_filename, title, to_be_post_processed);
+    debug_assert_eq!(
+        reentrancy::recorded(),
+        recorded_before,
+        "The dump recorded its own allocations"
+    );
+}
+
+/// Dump all callstacks in peak memory usage to format used by flamegraph.
+fn dump_peak_to_flamegraph(path: &str) {
+    // Milestones crossed since the sampler thread last checked:
+    write_milestone_dumps();
+    dump_to_flamegraph(path, true, "peak-memory", "Peak Tracked Memory Usage", true);
+}
+
+/// Print a short summary of the report written to the given directory.
+fn write_exit_summary(path: &str) {
+    if !ExitSummary::enabled() {
+        return;
+    }
+    let _in_tracker = InTracker::enter();
+    let summary_factory = TRACKER_STATE.lock().allocations.exit_summary();
+    summary_factory().write_to_stderr(Path::new(path));
+}
+
+/// Set the directory that dumps without an explicit path are written to.
+fn set_output_directory(path: Option<String>) {
+    *OUTPUT_DIRECTORY.lock() = path;
+}
+
+/// Finish the current region, if any, and write its report to the given
+/// directory. Returns false if no region was being profiled.
+fn write_region_report(path: &str) -> bool {
+    let _in_tracker = InTracker::enter();
+    // Like dump_to_flamegraph(), render without the lock held:
+    let (report_factory, mut metadata) = {
         let mut tracker_state = TRACKER_STATE.lock();
         let allocations = &mut tracker_state.allocations;
-
-        // Print warning if we're missing allocations.
-        allocations.warn_on_problems(peak);
-        let allocated_bytes = if peak {
-            allocations.get_peak_allocated_bytes()
-        } else {
-            allocations.get_current_allocated_bytes()
+        let Some(report_factory) = allocations.end_region() else {
+            return false;
         };
-        let flamegraph_callstacks_factory = allocations.combine_callstacks(peak, IdentityCleaner);
-        (allocated_bytes, flamegraph_callstacks_factory)
+        let mut metadata = allocations.report_metadata();
+        // These are about the whole run, not the region:
+        metadata.phases.clear();
+        metadata.peak_phase_frames.clear();
+        (report_factory, metadata)
     };
+    let report = report_factory();
+    let directory_path = Path::new(path);
+    report.write(directory_path, true);
+    metadata.region = Some(report.metadata.clone());
+    metadata.write(directory_path);
+    true
+}
 
-    let flamegraph_callstacks = flamegraph_callstacks_factory();
+/// Figure out where a dump should be written: the given path if there is one,
+/// otherwise a new automatically-named directory inside the output directory.
+fn resolve_dump_path(path: Option<String>, kind: &str) -> std::io::Result<String> {
+    if let Some(path) = path {
+        return Ok(path);
+    }
+    let parent = OUTPUT_DIRECTORY
+        .lock()
+        .clone()
+        .unwrap_or_else(|| TRACKER_STATE.lock().allocations.default_path.clone());
+    let directory = pymemprofile_api::util::create_report_directory(Path::new(&parent), kind)?;
+    Ok(directory.to_string_lossy().into_owned())
+}
 
-    eprintln!("=fil-profile= Preparing to write to {}", path);
-    let directory_path = Path::new(path);
+/// Convert a path from C, where NULL or an empty string mean there is no path.
+unsafe fn optional_path_from_c(path: *const c_char) -> Option<String> {
+    if path.is_null() {
+        return None;
+    }
+    let path = unsafe { CStr::from_ptr(path) }
+        .to_str()
+        .expect("Path wasn't UTF-8");
+    if path.is_empty() {
+        None
+    } else {
+        Some(path.to_string())
+    }
+}
 
-    let title = format!(
-        "{} ({:.1} MiB)",
-        title,
-        allocated_bytes as f64 / (1024.0 * 1024.0)
-    );
-    let subtitle = r#"Made with the Fil profiler. <a href="https://pythonspeed.com/fil/" style="text-decoration: underline;" target="_parent">Try it on your code!</a>"#;
-    flamegraph_callstacks.write_flamegraphs(
-        directory_path,
-        base_filename,
-        &title,
-        subtitle,
-        "bytes",
-        to_be_post_processed,
-    )
+/// Copy a path into a C buffer, NUL-terminated and truncated if necessary,
+/// and return its full length, like snprintf().
+unsafe fn path_to_c(path: &str, buffer: *mut c_char, buffer_length: usize) -> c_int {
+    if !buffer.is_null() && buffer_length > 0 {
+        let length = path.len().min(buffer_length - 1);
+        unsafe {
+            std::ptr::copy_nonoverlapping(path.as_ptr() as *const c_char, buffer, length);
+            *buffer.add(length) = 0;
+        }
+    }
+    path.len() as c_int
 }
 
-/// Dump all callstacks in peak memory usage to format used by flamegraph.
-fn dump_peak_to_flamegraph(path: &str) {
-    dump_to_flamegraph(path, true, "peak-memory", "Peak Tracked Memory Usage", true);
+#[no_mangle]
+extern "C" fn pymemprofile_add_allocation(address: usize, size: usize, line_number: u32) {
+    let Some(_in_tracker) = InTracker::enter() else {
+        return;
+    };
+    add_allocation(address, size, line_number, AllocationKind::Malloc).unwrap_or(());
+}
+
+/// A malloc() or similar of a non-zero size returned NULL. Either the size
+/// was too big to be real, e.g. a calloc() whose size overflowed, in which
+/// case size is usize::MAX and the program can deal with it, or we're out of
+/// memory.
+#[no_mangle]
+extern "C" fn pymemprofile_allocation_failed(size: usize, line_number: u32) {
+    let Some(_in_tracker) = InTracker::enter() else {
+        return;
+    };
+    add_allocation(0, size, line_number, AllocationKind::Malloc).unwrap_or(());
+}
+
+/// Like pymemprofile_allocation_failed(), for a realloc(). The old allocation
+/// was already removed with pymemprofile_free_allocation(), with old_size
+/// being the size it returned, but a failed realloc() leaves it as it was.
+#[no_mangle]
+extern "C" fn pymemprofile_realloc_failed(
+    old_address: usize,
+    old_size: usize,
+    new_size: usize,
+    line_number: u32,
+) {
+    let Some(_in_tracker) = InTracker::enter() else {
+        return;
+    };
+    // Only returns if we're not out of memory:
+    add_allocation(0, new_size, line_number, AllocationKind::Malloc).unwrap_or(());
+    if old_size != 0 {
+        // Put it back, albeit under the current callstack:
+        add_allocation(old_address, old_size, line_number, AllocationKind::Malloc).unwrap_or(());
+    }
+}
+
+/// Returns the size of the freed allocation, or 0 if it wasn't tracked.
+#[no_mangle]
+extern "C" fn pymemprofile_free_allocation(address: usize) -> usize {
+    let Some(_in_tracker) = InTracker::enter() else {
+        return 0;
+    };
+    free_allocation(address)
 }
 
+/// Like pymemprofile_free_allocation(), but also records the current callstack
+/// as the one doing the freeing.
 #[no_mangle]
-extern "C" fn pymemprofile_add_allocation(address: usize, size: usize, line_number: u16) {
-    add_allocation(address, size, line_number, false).unwrap_or(());
+extern "C" fn pymemprofile_free_allocation_from_callstack(
+    address: usize,
+    line_number: u32,
+) -> usize {
+    let Some(_in_tracker) = InTracker::enter() else {
+        return 0;
+    };
+    free_allocation_from_callstack(address, line_number)
 }
 
+/// Add the result of a realloc(). The old allocation should already have been
+/// removed with pymemprofile_free_allocation(), with old_size being the size it
+/// returned.
 #[no_mangle]
-extern "C" fn pymemprofile_free_allocation(address: usize) {
-    free_allocation(address);
+extern "C" fn pymemprofile_update_allocation(
+    old_address: usize,
+    old_size: usize,
+    new_address: usize,
+    new_size: usize,
+    line_number: u32,
+) {
+    let Some(_in_tracker) = InTracker::enter() else {
+        return;
+    };
+    let kind = if old_size == 0 {
+        // The old allocation wasn't tracked, so there's nothing to compare to:
+        AllocationKind::Malloc
+    } else {
+        AllocationKind::Realloc {
+            old_address,
+            old_size,
+        }
+    };
+    add_allocation(new_address, new_size, line_number, kind).unwrap_or(());
 }
 
 /// Returns allocation size, or 0 if not stored. Useful for tests, mostly.
@@ -268,9 +878,346 @@ extern "C" fn pymemprofile_get_allocation_size(address: usize) -> usize {
     get_allocation_size(address)
 }
 
+/// Get current and peak allocated bytes in one consistent snapshot, so current
+/// is never more than peak. Returns 0 on success, -1 if a pointer is NULL.
+///
+/// # Safety
+/// Pointers must be NULL or valid for writes.
+#[no_mangle]
+unsafe extern "C" fn pymemprofile_get_traced_memory(
+    current_out: *mut u64,
+    peak_out: *mut u64,
+) -> c_int {
+    if current_out.is_null() || peak_out.is_null() {
+        return -1;
+    }
+    if is_shut_down() {
+        return ALREADY_SHUT_DOWN;
+    }
+    let (current, peak) = TRACKER_STATE.lock().allocations.get_traced_memory();
+    unsafe {
+        *current_out = current as u64;
+        *peak_out = peak as u64;
+    }
+    0
+}
+
+/// Find live allocations whose callstack includes the given function in the
+/// given file, writing up to out_capacity of them to out. Returns how many
+/// were found in total, which may be more than out_capacity.
+///
+/// This scans all live allocations with the tracker locked, so it's slow.
+///
+/// # Safety
+/// The names must be NUL-terminated, and out must be valid for writing
+/// out_capacity entries (it can be NULL if out_capacity is 0).
+#[no_mangle]
+unsafe extern "C" fn pymemprofile_find_allocations_by_function(
+    file_name: *const c_char,
+    function_name: *const c_char,
+    out: *mut AllocationInfo,
+    out_capacity: usize,
+) -> usize {
+    let file_name = unsafe { CStr::from_ptr(file_name) }.to_string_lossy();
+    let function_name = unsafe { CStr::from_ptr(function_name) }.to_string_lossy();
+    let out: &mut [AllocationInfo] = if out.is_null() || out_capacity == 0 {
+        &mut []
+    } else {
+        unsafe { std::slice::from_raw_parts_mut(out, out_capacity) }
+    };
+    TRACKER_STATE
+        .lock()
+        .allocations
+        .find_allocations_by_function(&file_name, &function_name, out)
+}
+
+/// Write function-detail.json and function-detail.txt, showing how much of
+/// the peak each line of the given function in the given file is responsible
+/// for, to the given directory, or to a new automatically-named one if the
+/// path is NULL or empty. Returns 0, or -1 if nothing at peak was allocated
+/// from the function or the report couldn't be written.
+///
+/// # Safety
+/// The names and the path, if not NULL, must be NUL-terminated.
+#[no_mangle]
+unsafe extern "C" fn pymemprofile_dump_function_detail(
+    file_name: *const c_char,
+    function_name: *const c_char,
+    path: *const c_char,
+) -> c_int {
+    if is_shut_down() {
+        return ALREADY_SHUT_DOWN;
+    }
+    let _in_tracker = InTracker::enter();
+    let file_name = unsafe { CStr::from_ptr(file_name) }.to_string_lossy();
+    let function_name = unsafe { CStr::from_ptr(function_name) }.to_string_lossy();
+    let detail = TRACKER_STATE
+        .lock()
+        .allocations
+        .function_detail(&file_name, &function_name);
+    let Some(mut detail) = detail else {
+        eprintln!(
+            "=fil-profile= No memory at peak was allocated from {} in {}",
+            function_name, file_name
+        );
+        return -1;
+    };
+    let path = match resolve_dump_path(unsafe { optional_path_from_c(path) }, "function") {
+        Ok(path) => path,
+        Err(e) => {
+            eprintln!("=fil-profile= Couldn't create the report directory: {}", e);
+            return -1;
+        }
+    };
+    // Getting the source code calls into Python, so do it without the lock:
+    detail.add_source();
+    match detail.write(Path::new(&path)) {
+        Ok(()) => 0,
+        Err(e) => {
+            eprintln!("=fil-profile= Error writing per-line memory usage: {}", e);
+            -1
+        }
+    }
+}
+
+/// Write survivors.json and survivors.txt, listing the callstacks whose memory
+/// survived at least FIL_SURVIVORS resets, to the given directory, or to a new
+/// automatically-named one if the path is NULL or empty, see
+/// pymemprofile_api::survivors. Returns 0, -1 if FIL_SURVIVORS isn't set or
+/// the report couldn't be written, or OUTPUT_SINK_FAILED if the registered
+/// output sink failed.
+///
+/// # Safety
+/// The path, if not NULL, must be NUL-terminated.
+#[no_mangle]
+unsafe extern "C" fn pymemprofile_dump_survivors(path: *const c_char) -> c_int {
+    if is_shut_down() {
+        return ALREADY_SHUT_DOWN;
+    }
+    let _in_tracker = InTracker::enter();
+    let Some(report_factory) = TRACKER_STATE.lock().allocations.survivors_report() else {
+        eprintln!("=fil-profile= Set FIL_SURVIVORS to track memory that survives resets");
+        return -1;
+    };
+    let path = match resolve_dump_path(unsafe { optional_path_from_c(path) }, "survivors") {
+        Ok(path) => path,
+        Err(e) => {
+            eprintln!("=fil-profile= Couldn't create the report directory: {}", e);
+            return -1;
+        }
+    };
+    let dump = sinks::DumpScope::start();
+    // Like dump_to_flamegraph(), render without the lock held:
+    if let Err(e) = report_factory().write(Path::new(&path)) {
+        eprintln!("=fil-profile= Error writing survivors report: {}", e);
+        return -1;
+    }
+    if dump.sink_failed() {
+        return OUTPUT_SINK_FAILED;
+    }
+    0
+}
+
+/// Write the peak snapshot as an Arrow IPC file to the given path, see
+/// pymemprofile_api::arrow. Returns 0, or -1 on error, including when Fil was
+/// built without the arrow-export feature.
+///
+/// # Safety
+/// The path must be NUL-terminated.
+#[no_mangle]
+unsafe extern "C" fn pymemprofile_dump_peak_to_arrow(path: *const c_char) -> c_int {
+    if is_shut_down() {
+        return ALREADY_SHUT_DOWN;
+    }
+    let _in_tracker = InTracker::enter();
+    let path = unsafe { CStr::from_ptr(path) }
+        .to_str()
+        .expect("Path wasn't UTF-8")
+        .to_string();
+    let flamegraph_callstacks_factory = TRACKER_STATE
+        .lock()
+        .allocations
+        .combine_callstacks(true, IdentityCleaner);
+    // Like dump_to_flamegraph(), render without the lock held:
+    match flamegraph_callstacks_factory().write_arrow(Path::new(&path)) {
+        Ok(()) => 0,
+        Err(e) => {
+            eprintln!("=fil-profile= Error writing Arrow table: {}", e);
+            -1
+        }
+    }
+}
+
+/// Register a probe estimating how much memory a cache is retaining, called
+/// whenever the peak report is written, see crate::retention_probes.
+/// Registering a probe with the same name replaces it, and passing NULL as
+/// the probe unregisters it.
+///
+/// # Safety
+/// The name must be NUL-terminated.
+#[no_mangle]
+unsafe extern "C" fn pymemprofile_register_retention_probe(
+    name: *const c_char,
+    probe: Option<RetentionProbe>,
+    user_data: *mut c_void,
+) {
+    unsafe { retention_probes::register(name, probe, user_data) }
+}
+
+/// Register a callback to be called whenever peak memory grows by at least
+/// min_delta_bytes since the last notification. Passing NULL as the callback
+/// unregisters it.
+///
+/// The callback is called from a dedicated thread, outside the tracker lock,
+/// and any allocations it does are not tracked.
+#[no_mangle]
+extern "C" fn pymemprofile_register_peak_callback(
+    callback: Option<PeakCallback>,
+    user_data: *mut c_void,
+    min_delta_bytes: u64,
+) {
+    let mut tracker_state = TRACKER_STATE.lock();
+    tracker_state.peak_notifier = callback.map(|callback| {
+        let (_, peak) = tracker_state.allocations.get_traced_memory();
+        PeakNotifier::new(callback, user_data, min_delta_bytes as usize, peak)
+    });
+    drop(tracker_state);
+    if callback.is_some() {
+        peak_callback::start_notifier_thread();
+    }
+}
+
+/// Register a callback that every report file is handed to, in chunks, after
+/// it's been written to disk, see pymemprofile_api::sinks. Passing NULL as the
+/// callback unregisters it. If it fails, the dump returns OUTPUT_SINK_FAILED.
+///
+/// The callback is called by the thread doing the dump, outside the tracker
+/// lock, and any allocations it does are not tracked.
+#[no_mangle]
+extern "C" fn pymemprofile_register_output_sink(
+    callback: Option<OutputSinkCallback>,
+    user_data: *mut c_void,
+) {
+    sinks::register(callback.map(|callback| CallbackSink::new(callback, user_data)));
+}
+
+/// Check the tracker's internal state is consistent, printing any problems to
+/// stderr. Returns the number of problems found, so 0 means consistent.
+/// Intended for tests.
+#[no_mangle]
+extern "C" fn pymemprofile_self_check() -> c_int {
+    let mut tracker_state = TRACKER_STATE.lock();
+    // The peak is only updated lazily:
+    tracker_state.allocations.check_if_new_peak();
+    let problems = tracker_state.allocations.validate();
+    drop(tracker_state);
+    for problem in problems.iter() {
+        eprintln!("=fil-profile= Inconsistent state: {}", problem);
+    }
+    problems.len() as c_int
+}
+
+/// Warn about jemalloc or mimalloc being used alongside malloc(), once.
+#[no_mangle]
+extern "C" fn pymemprofile_check_bundled_allocators() {
+    bundled_allocators::warn_once(&bundled_allocators::detect(tracking_bundled_allocators()));
+}
+
+#[no_mangle]
+extern "C" fn pymemprofile_add_anon_mmap(address: usize, size: usize, line_number: u32) {
+    let Some(_in_tracker) = InTracker::enter() else {
+        return;
+    };
+    add_allocation(address, size, line_number, AllocationKind::Mmap).unwrap_or(());
+}
+
+/// Add a file-backed mmap(), tracked separately from other memory; see
+/// pymemprofile_api::mapped_files.
+#[no_mangle]
+extern "C" fn pymemprofile_add_file_mmap(address: usize, size: usize, fd: c_int, line_number: u32) {
+    let Some(_in_tracker) = InTracker::enter() else {
+        return;
+    };
+    // Do the syscalls before taking the lock:
+    let name = mapped_files::file_name(fd).unwrap_or_else(|| "<unknown>".to_string());
+    let callstack = if THREAD_REGISTERED.with(|registered| registered.get()) {
+        // Will fail during thread shutdown, but not much we can do at that point.
+        let Ok(callstack) = THREAD_CALLSTACK.try_with(|cs| cs.borrow().clone()) else {
+            return;
+        };
+        callstack
+    } else {
+        Callstack::unknown_native_thread()
+    };
+    let mut tracker_state = TRACKER_STATE.lock();
+    let tracker_state = &mut *tracker_state;
+    let function = synthetic_function(
+        &mut tracker_state.mapped_file_functions,
+        &mut tracker_state.allocations.functions,
+        "[mapped file]",
+        &name,
+    );
+    let allocations = &mut tracker_state.allocations;
+    let callstack_id =
+        allocations.get_callstack_id(&callstack.with_mapped_file(line_number, function));
+    allocations.add_file_mmap(PARENT_PROCESS, address, size, callstack_id);
+    reentrancy::count_recorded();
+}
+
+/// A file was opened with the given flags; if it's a temporary file, start
+/// tracking it, see pymemprofile_api::temp_files.
+#[no_mangle]
+extern "C" fn pymemprofile_temp_file_opened(fd: c_int, flags: c_int, line_number: u32) {
+    let Some(_in_tracker) = InTracker::enter() else {
+        return;
+    };
+    // Do the syscalls before taking the lock:
+    let Some(file) = temp_files::opened_file(fd, flags) else {
+        return;
+    };
+    let mut tracker_state = TRACKER_STATE.lock();
+    let allocations = &mut tracker_state.allocations;
+    let Ok(callstack_id) = current_callstack_id(allocations, line_number) else {
+        return;
+    };
+    allocations.temp_file_opened(fd, file, callstack_id);
+}
+
+/// A file was unlinked, relative to the given directory descriptor.
+///
+/// # Safety
+/// The path must be NUL-terminated.
+#[no_mangle]
+unsafe extern "C" fn pymemprofile_temp_file_unlinked(directory_fd: c_int, path: *const c_char) {
+    let Some(_in_tracker) = InTracker::enter() else {
+        return;
+    };
+    let path = unsafe { CStr::from_ptr(path) };
+    if let Some(path) = temp_files::unlinked_path(directory_fd, path) {
+        TRACKER_STATE.lock().allocations.temp_file_unlinked(&path);
+    }
+}
+
+/// A file was resized with ftruncate(), or with fallocate() if grow_only is
+/// set.
+#[no_mangle]
+extern "C" fn pymemprofile_temp_file_resized(fd: c_int, bytes: u64, grow_only: c_int) {
+    let Some(_in_tracker) = InTracker::enter() else {
+        return;
+    };
+    TRACKER_STATE
+        .lock()
+        .allocations
+        .temp_file_resized(fd, bytes as usize, grow_only != 0);
+}
+
+/// A file descriptor is about to be closed.
 #[no_mangle]
-extern "C" fn pymemprofile_add_anon_mmap(address: usize, size: usize, line_number: u16) {
-    add_allocation(address, size, line_number, true).unwrap_or(());
+extern "C" fn pymemprofile_temp_file_closed(fd: c_int) {
+    let Some(_in_tracker) = InTracker::enter() else {
+        return;
+    };
+    TRACKER_STATE.lock().allocations.temp_file_closed(fd);
 }
 
 #[no_mangle]
@@ -293,7 +1240,48 @@ unsafe extern "C" fn pymemprofile_add_function_location(
         ))
     };
 
-    let function_id = add_function(filename.to_string(), function_name.to_string());
+    let function_id = add_function(filename.to_string(), function_name.to_string(), None);
+    function_id.as_u64()
+}
+
+/// Like pymemprofile_add_function_location(), and also given the name of the
+/// function's module and its qualified name within it, e.g. `Parser.process`,
+/// which reports then show and aggregate by, see
+/// pymemprofile_api::memorytracking::qualified_name(). The module can be
+/// empty if it's unknown.
+///
+/// # Safety
+/// Intended for use from C APIs, what can I say.
+#[no_mangle]
+unsafe extern "C" fn pymemprofile_add_function_location_v2(
+    filename: *const c_char,
+    filename_length: u64,
+    function_name: *const c_char,
+    function_length: u64,
+    module_name: *const c_char,
+    module_length: u64,
+    qualname: *const c_char,
+    qualname_length: u64,
+) -> u64 {
+    let as_str = |pointer: *const c_char, length: u64| unsafe {
+        std::str::from_utf8_unchecked(std::slice::from_raw_parts(
+            pointer as *const u8,
+            length as usize,
+        ))
+    };
+    let module_name = if module_name.is_null() {
+        ""
+    } else {
+        as_str(module_name, module_length)
+    };
+    let function_id = add_function(
+        as_str(filename, filename_length).to_string(),
+        as_str(function_name, function_length).to_string(),
+        Some(qualified_name(
+            module_name,
+            as_str(qualname, qualname_length),
+        )),
+    );
     function_id.as_u64()
 }
 
@@ -301,9 +1289,9 @@ unsafe extern "C" fn pymemprofile_add_function_location(
 /// Intended for use from C APIs, what can I say.
 #[no_mangle]
 unsafe extern "C" fn pymemprofile_start_call(
-    parent_line_number: u16,
+    parent_line_number: u32,
     function_id: u64,
-    line_number: u16,
+    line_number: u32,
 ) {
     let function_id = FunctionId::new(function_id);
     start_call(function_id, parent_line_number, line_number);
@@ -328,12 +1316,322 @@ unsafe extern "C" fn pymemprofile_reset(default_path: *const c_char) {
 /// # Safety
 /// Intended for use from C.
 #[no_mangle]
-unsafe extern "C" fn pymemprofile_dump_peak_to_flamegraph(path: *const c_char) {
-    let path = unsafe { CStr::from_ptr(path) }
-        .to_str()
-        .expect("Path wasn't UTF-8")
-        .to_string();
+unsafe extern "C" fn pymemprofile_set_output_directory(path: *const c_char) {
+    set_output_directory(unsafe { optional_path_from_c(path) });
+}
+
+/// Record a live Python object created by the current callstack, leaving out
+/// the innermost `skip_frames` frames (e.g. of the Python API wrapper).
+///
+/// # Safety
+/// Intended for use from C.
+#[no_mangle]
+unsafe extern "C" fn pymemprofile_add_object(
+    address: usize,
+    type_name: *const c_char,
+    line_number: u32,
+    skip_frames: u32,
+) {
+    let type_name = unsafe { CStr::from_ptr(type_name) }.to_string_lossy();
+    let Ok(callstack) = THREAD_CALLSTACK.try_with(|cs| {
+        cs.borrow()
+            .caller_callstack(line_number, skip_frames as usize)
+    }) else {
+        return;
+    };
+    TRACKER_STATE
+        .lock()
+        .allocations
+        .add_object(&callstack, address, &type_name);
+}
+
+#[no_mangle]
+extern "C" fn pymemprofile_remove_object(address: usize) {
+    TRACKER_STATE.lock().allocations.remove_object(address);
+}
+
+/// Push a phase frame for the current thread.
+///
+/// # Safety
+/// Intended for use from C.
+#[no_mangle]
+unsafe extern "C" fn pymemprofile_push_phase(name: *const c_char) {
+    push_phase(&unsafe { CStr::from_ptr(name) }.to_string_lossy());
+}
+
+#[no_mangle]
+extern "C" fn pymemprofile_pop_phase() {
+    pop_phase();
+}
+
+/// Add memory reported by native code under the given domain.
+///
+/// # Safety
+/// Intended for use from C.
+#[no_mangle]
+unsafe extern "C" fn pymemprofile_report_allocation(
+    domain: *const c_char,
+    address: usize,
+    size: usize,
+    line_number: u32,
+) {
+    let Some(_in_tracker) = InTracker::enter() else {
+        return;
+    };
+    let domain = unsafe { CStr::from_ptr(domain) }.to_string_lossy();
+    report_allocation(&domain, address, size, line_number);
+}
+
+/// Remove memory added with pymemprofile_report_allocation().
+///
+/// # Safety
+/// Intended for use from C.
+#[no_mangle]
+unsafe extern "C" fn pymemprofile_report_free(domain: *const c_char, address: usize) {
+    let Some(_in_tracker) = InTracker::enter() else {
+        return;
+    };
+    let domain = unsafe { CStr::from_ptr(domain) }.to_string_lossy();
+    report_free(&domain, address);
+}
+
+/// Push a context frame for the current thread.
+///
+/// # Safety
+/// Intended for use from C.
+#[no_mangle]
+unsafe extern "C" fn pymemprofile_push_context(name: *const c_char, line_number: u32) {
+    push_context(
+        &unsafe { CStr::from_ptr(name) }.to_string_lossy(),
+        line_number,
+    );
+}
+
+#[no_mangle]
+extern "C" fn pymemprofile_pop_context() {
+    pop_context();
+}
+
+/// Record the Python version, for the report's environment.
+///
+/// # Safety
+/// Intended for use from C.
+#[no_mangle]
+unsafe extern "C" fn pymemprofile_set_python_version(version: *const c_char) {
+    let version = unsafe { CStr::from_ptr(version) }.to_string_lossy();
+    TRACKER_STATE
+        .lock()
+        .allocations
+        .set_python_version(&version);
+}
+
+/// Attach a user tag, e.g. a git commit, to the report's environment.
+///
+/// # Safety
+/// Intended for use from C.
+#[no_mangle]
+unsafe extern "C" fn pymemprofile_add_metadata(key: *const c_char, value: *const c_char) {
+    let key = unsafe { CStr::from_ptr(key) }.to_string_lossy();
+    let value = unsafe { CStr::from_ptr(value) }.to_string_lossy();
+    TRACKER_STATE.lock().allocations.add_metadata(&key, &value);
+}
+
+/// Start a new named phase; allocations from now on are tagged with it.
+///
+/// # Safety
+/// Intended for use from C.
+#[no_mangle]
+unsafe extern "C" fn pymemprofile_mark_phase(name: *const c_char) {
+    let name = unsafe { CStr::from_ptr(name) }.to_string_lossy();
+    TRACKER_STATE.lock().allocations.mark_phase(&name);
+}
+
+/// Dump the peak to the given directory, or to a new automatically-named one
+/// if the path is NULL or empty. The path that was used gets written to
+/// path_out. Returns the length of that path, -1 on error, or
+/// OUTPUT_SINK_FAILED if the registered output sink failed.
+///
+/// # Safety
+/// Intended for use from C.
+#[no_mangle]
+unsafe extern "C" fn pymemprofile_dump_peak_to_flamegraph(
+    path: *const c_char,
+    path_out: *mut c_char,
+    path_out_length: usize,
+) -> c_int {
+    if is_shut_down() {
+        return ALREADY_SHUT_DOWN;
+    }
+    let path = match resolve_dump_path(unsafe { optional_path_from_c(path) }, "peak") {
+        Ok(path) => path,
+        Err(e) => {
+            eprintln!("=fil-profile= Couldn't create the report directory: {}", e);
+            return -1;
+        }
+    };
+    let dump = sinks::DumpScope::start();
     dump_peak_to_flamegraph(&path);
+    // Tracking is stopped before the final report, e.g. at exit:
+    if unsafe { is_tracking_allocations() } == 0 {
+        write_exit_summary(&path);
+    }
+    let length = unsafe { path_to_c(&path, path_out, path_out_length) };
+    if dump.sink_failed() {
+        return OUTPUT_SINK_FAILED;
+    }
+    length
+}
+
+/// Done profiling, for programs that embed Python and want to clean up
+/// without exiting: stop the background threads, write a final report to the
+/// given directory unless the path is NULL (an empty path means a new
+/// automatically-named one), uninstall the crash handler, and free the
+/// tracked data. Returns 0, -1 if the report directory couldn't be created,
+/// OUTPUT_SINK_FAILED if the registered output sink failed, or
+/// ALREADY_SHUT_DOWN if this was already done. Until the next reset, the
+/// other APIs do nothing and return ALREADY_SHUT_DOWN, or NULL.
+///
+/// Tracking must already have been stopped.
+///
+/// # Safety
+/// Intended for use from C.
+#[no_mangle]
+unsafe extern "C" fn pymemprofile_shutdown(path: *const c_char) -> c_int {
+    if SHUT_DOWN.swap(true, Ordering::AcqRel) {
+        return ALREADY_SHUT_DOWN;
+    }
+    // Threads first, so they're not using the tracker as it's torn down:
+    sampler::shutdown();
+    peak_callback::stop_notifier_thread();
+    let mut result = 0;
+    if !path.is_null() {
+        match resolve_dump_path(unsafe { optional_path_from_c(path) }, "peak") {
+            Ok(path) => {
+                let dump = sinks::DumpScope::start();
+                dump_peak_to_flamegraph(&path);
+                write_exit_summary(&path);
+                if dump.sink_failed() {
+                    result = OUTPUT_SINK_FAILED;
+                }
+            }
+            Err(e) => {
+                eprintln!("=fil-profile= Couldn't create the report directory: {}", e);
+                result = -1;
+            }
+        }
+    }
+    crash::uninstall();
+    retention_probes::clear();
+    let mut tracker_state = TRACKER_STATE.lock();
+    tracker_state.peak_notifier = None;
+    tracker_state.allocations.shutdown();
+    result
+}
+
+/// Start profiling a region. Memory that's already live is left out of the
+/// region's report if group_pre_existing is 0, or shown as a single
+/// `[pre-existing]` frame otherwise. Returns 0, or -1 if a region is already
+/// being profiled, since they can't be nested.
+#[no_mangle]
+extern "C" fn pymemprofile_region_start(group_pre_existing: c_int) -> c_int {
+    if is_shut_down() {
+        return ALREADY_SHUT_DOWN;
+    }
+    let pre_existing = if group_pre_existing == 0 {
+        PreExisting::Exclude
+    } else {
+        PreExisting::Group
+    };
+    if TRACKER_STATE.lock().allocations.start_region(pre_existing) {
+        0
+    } else {
+        -1
+    }
+}
+
+/// Finish profiling the region, and write its report to the given directory,
+/// or to a new automatically-named one if the path is NULL or empty. The path
+/// that was used gets written to path_out. Returns the length of that path,
+/// -1 on error, e.g. if no region was being profiled, or OUTPUT_SINK_FAILED
+/// if the registered output sink failed.
+///
+/// # Safety
+/// Intended for use from C.
+#[no_mangle]
+unsafe extern "C" fn pymemprofile_region_end(
+    path: *const c_char,
+    path_out: *mut c_char,
+    path_out_length: usize,
+) -> c_int {
+    if is_shut_down() {
+        return ALREADY_SHUT_DOWN;
+    }
+    if !TRACKER_STATE.lock().allocations.in_region() {
+        return -1;
+    }
+    let path = match resolve_dump_path(unsafe { optional_path_from_c(path) }, "region") {
+        Ok(path) => path,
+        Err(e) => {
+            eprintln!("=fil-profile= Couldn't create the report directory: {}", e);
+            return -1;
+        }
+    };
+    let dump = sinks::DumpScope::start();
+    if !write_region_report(&path) {
+        return -1;
+    }
+    let length = unsafe { path_to_c(&path, path_out, path_out_length) };
+    if dump.sink_failed() {
+        return OUTPUT_SINK_FAILED;
+    }
+    length
+}
+
+/// Render the peak flamegraph SVG in memory, without writing anything to disk.
+/// If max_bytes isn't 0 and the full SVG would be bigger, a pruned version is
+/// returned instead. Returns NULL on failure, e.g. if nothing was allocated.
+///
+/// The result must be freed with pymemprofile_free_string(); it's allocated
+/// in a way the tracker doesn't see.
+#[no_mangle]
+extern "C" fn pymemprofile_render_peak_svg(max_bytes: usize) -> *mut c_char {
+    if is_shut_down() {
+        return std::ptr::null_mut();
+    }
+    let _in_tracker = InTracker::enter();
+    // Like dump_to_flamegraph(), render without the lock held, since getting
+    // the source code calls into Python.
+    let (allocated_bytes, flamegraph_callstacks_factory) = {
+        let mut tracker_state = TRACKER_STATE.lock();
+        let allocations = &mut tracker_state.allocations;
+        (
+            allocations.get_peak_allocated_bytes(),
+            allocations.combine_callstacks(true, IdentityCleaner),
+        )
+    };
+    let flamegraph_callstacks = flamegraph_callstacks_factory();
+    match flamegraph_callstacks.get_memory_flamegraph(
+        "Peak Tracked Memory Usage",
+        allocated_bytes,
+        true,
+        max_bytes,
+    ) {
+        Ok(svg) => pymemprofile_api::ffi::untracked_c_string(&svg),
+        Err(e) => {
+            eprintln!("=fil-profile= Error rendering SVG: {}", e);
+            std::ptr::null_mut()
+        }
+    }
+}
+
+/// Free a string returned by pymemprofile_render_peak_svg().
+///
+/// # Safety
+/// The string must be NULL, or come from pymemprofile_render_peak_svg() and
+/// not already be freed.
+#[no_mangle]
+unsafe extern "C" fn pymemprofile_free_string(string: *mut c_char) {
+    unsafe { pymemprofile_api::ffi::free_untracked_c_string(string) }
 }
 
 /// # Safety
@@ -362,7 +1660,7 @@ unsafe extern "C" fn pymemprofile_clear_current_callstack() {
     set_current_callstack(&callstack);
 }
 
-/// # A start at implementing public API from Rust
+// # A start at implementing public API from Rust
 
 /// Convert pointer into Rust closure.
 extern "C" fn trampoline<F>(user_data: *mut c_void)
@@ -382,11 +1680,21 @@ extern "C" {
     // Return whether C code has initialized.
     fn is_initialized() -> c_int;
 
+    // Return whether allocations are currently being tracked.
+    fn is_tracking_allocations() -> c_int;
+
+    // Return whether jemalloc's and mimalloc's own APIs are being tracked.
+    fn is_tracking_bundled_allocators() -> c_int;
+
     // Increment/decrement reentrancy counter.
     //fn fil_increment_reentrancy();
     //fn fil_decrement_reentrancy();
 }
 
+fn tracking_bundled_allocators() -> bool {
+    unsafe { is_tracking_bundled_allocators() == 1 }
+}
+
 struct FilMmapAPI;
 
 impl pymemprofile_api::mmap::MmapAPI for FilMmapAPI {
@@ -395,10 +1703,18 @@ impl pymemprofile_api::mmap::MmapAPI for FilMmapAPI {
     }
 
     fn remove_mmap(&self, address: usize, length: usize) {
+        let Some(_in_tracker) = InTracker::enter() else {
+            return;
+        };
         let mut tracker_state = TRACKER_STATE.lock();
 
         let allocations = &mut tracker_state.allocations;
+        let allocated_bytes_before = allocations.get_current_allocated_bytes();
         allocations.free_anon_mmap(PARENT_PROCESS, address, length);
+        allocations.free_file_mmap(PARENT_PROCESS, address, length);
+        threads::record_free(
+            allocated_bytes_before.saturating_sub(allocations.get_current_allocated_bytes()),
+        );
     }
 
     fn is_initialized(&self) -> bool {
@@ -407,15 +1723,298 @@ impl pymemprofile_api::mmap::MmapAPI for FilMmapAPI {
 }
 
 /// On macOS we're using reimplemented_* prefix.
+///
+/// # Safety
+/// Standard munmap() semantics.
 #[cfg(target_os = "macos")]
 #[no_mangle]
-pub extern "C" fn reimplemented_munmap(addr: *mut c_void, len: usize) -> c_int {
-    return unsafe { pymemprofile_api::mmap::munmap_wrapper(addr, len, &FilMmapAPI {}) };
+pub unsafe extern "C" fn reimplemented_munmap(addr: *mut c_void, len: usize) -> c_int {
+    unsafe { pymemprofile_api::mmap::munmap_wrapper(addr, len, &FilMmapAPI {}) }
 }
 
 /// On Linux we're using same name as the API we're replacing.
+///
+/// # Safety
+/// Standard munmap() semantics.
 #[cfg(target_os = "linux")]
 #[no_mangle]
-pub extern "C" fn munmap(addr: *mut c_void, len: usize) -> c_int {
-    return unsafe { pymemprofile_api::mmap::munmap_wrapper(addr, len, &FilMmapAPI {}) };
+pub unsafe extern "C" fn munmap(addr: *mut c_void, len: usize) -> c_int {
+    unsafe { pymemprofile_api::mmap::munmap_wrapper(addr, len, &FilMmapAPI {}) }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        add_allocation, dump_peak_to_flamegraph, finish_call, free_allocation,
+        free_allocation_from_callstack, get_current_callstack, is_tracking_allocations,
+        pymemprofile_add_allocation, pymemprofile_add_anon_mmap, pymemprofile_allocation_failed,
+        pymemprofile_dump_peak_to_flamegraph, pymemprofile_free_allocation,
+        pymemprofile_get_allocation_size, pymemprofile_realloc_failed, pymemprofile_region_start,
+        pymemprofile_register_peak_callback, pymemprofile_update_allocation, reset, sampler,
+        set_current_callstack, start_call, AllocationKind, InTracker, ALREADY_SHUT_DOWN,
+        TRACKER_STATE,
+    };
+    use parking_lot::Mutex;
+    use pymemprofile_api::memorytracking::{Callstack, PARENT_PROCESS};
+    use std::ffi::CString;
+    use std::os::raw::{c_char, c_int, c_void};
+    use std::time::Duration;
+
+    extern "C" {
+        fn fil_increment_reentrancy();
+        fn fil_decrement_reentrancy();
+        fn fil_start_tracking();
+        fn fil_stop_tracking();
+        fn fil_reset(default_path: *const c_char);
+        fn fil_shutdown(final_dump_path: *const c_char) -> c_int;
+    }
+
+    // The tracker is global, so tests that use it can't run in parallel:
+    static TEST_LOCK: Mutex<()> = Mutex::new(());
+
+    /// Allocations and frees via the FFI from a thread that never called
+    /// start_call() and didn't inherit a callstack get attributed to the
+    /// `[unknown native thread]` callstack, and don't touch other threads'
+    /// callstacks.
+    #[test]
+    fn unregistered_thread() {
+        let _lock = TEST_LOCK.lock();
+        reset("/tmp".to_string());
+        let function = TRACKER_STATE
+            .lock()
+            .allocations
+            .functions
+            .add_function("a.py".to_string(), "f".to_string());
+        start_call(function, 0, 1);
+        add_allocation(0x1000, 1000, 1, AllocationKind::Malloc).unwrap();
+        let python_callstack = get_current_callstack();
+
+        // Our pthread_create() passes on the creating thread's callstack unless
+        // it's called reentrantly, so this is like a thread started before Fil
+        // was initialized:
+        unsafe { fil_increment_reentrancy() };
+        let unregistered = std::thread::spawn(|| {
+            add_allocation(0x2000, 200, 0, AllocationKind::Malloc).unwrap();
+            // Frees of allocations from other threads work as usual:
+            assert_eq!(free_allocation(0x1000), 1000);
+            add_allocation(0x3000, 30, 0, AllocationKind::Malloc).unwrap();
+            assert_eq!(free_allocation_from_callstack(0x3000, 0), 30);
+            assert_eq!(free_allocation_from_callstack(0x4000, 0), 0);
+        });
+        unsafe { fil_decrement_reentrancy() };
+        unregistered.join().unwrap();
+        {
+            let tracker_state = TRACKER_STATE.lock();
+            let allocations = &tracker_state.allocations;
+            assert_eq!(allocations.get_current_allocated_bytes(), 200);
+            assert_eq!(
+                allocations.dominant_callstack(),
+                Some((Callstack::unknown_native_thread(), 200))
+            );
+            assert_eq!(allocations.validate(), Vec::<String>::new());
+        }
+
+        // A thread that inherited a callstack, the way pthread_create() does,
+        // uses it:
+        let inherited = python_callstack.clone();
+        std::thread::spawn(move || {
+            set_current_callstack(&inherited);
+            add_allocation(0x5000, 5000, 0, AllocationKind::Malloc).unwrap();
+        })
+        .join()
+        .unwrap();
+        let tracker_state = TRACKER_STATE.lock();
+        let (dominant, bytes) = tracker_state.allocations.dominant_callstack().unwrap();
+        assert_eq!(dominant.to_vec(), python_callstack.to_vec());
+        assert_eq!(bytes, 5000);
+    }
+
+    /// Writing a report allocates plenty, e.g. in Python's linecache, but none
+    /// of it gets recorded, even with tracking on.
+    #[test]
+    fn dump_does_not_record_itself() {
+        let _lock = TEST_LOCK.lock();
+        pyo3::prepare_freethreaded_python();
+        reset("/tmp".to_string());
+        // A large profile, with lots of distinct callstacks:
+        let functions: Vec<_> = {
+            let mut tracker_state = TRACKER_STATE.lock();
+            (0..200)
+                .map(|i| {
+                    tracker_state
+                        .allocations
+                        .functions
+                        .add_function(format!("module{}.py", i), format!("function{}", i))
+                })
+                .collect()
+        };
+        let mut address = 0x10000;
+        for (i, function) in functions.iter().enumerate() {
+            start_call(*function, 1, i as u32 + 1);
+            for line_number in 1..10 {
+                add_allocation(address, 1000, line_number, AllocationKind::Malloc).unwrap();
+                address += 1000;
+            }
+        }
+        for _ in &functions {
+            finish_call();
+        }
+        let current_allocated_bytes = || {
+            TRACKER_STATE
+                .lock()
+                .allocations
+                .get_current_allocated_bytes()
+        };
+        let before = current_allocated_bytes();
+        assert_eq!(before, 200 * 9 * 1000);
+
+        let directory =
+            std::env::temp_dir().join(format!("fil-dump-reentrancy-{}", std::process::id()));
+        std::fs::create_dir_all(&directory).unwrap();
+        unsafe { fil_start_tracking() };
+        dump_peak_to_flamegraph(directory.to_str().unwrap());
+        unsafe { fil_stop_tracking() };
+        assert!(directory.join("peak-memory.svg").exists());
+        assert_eq!(current_allocated_bytes(), before);
+        std::fs::remove_dir_all(&directory).unwrap();
+
+        // Calls into the FFI from inside the tracker are ignored:
+        {
+            let _in_tracker = InTracker::enter().unwrap();
+            pymemprofile_add_allocation(0x1, 123, 1);
+            assert_eq!(pymemprofile_free_allocation(0x10000), 0);
+        }
+        assert_eq!(current_allocated_bytes(), before);
+    }
+
+    /// Allocations too big to be real, e.g. from a calloc() whose size
+    /// overflowed, are counted but leave the totals and the peak alone, and
+    /// aren't taken as a sign of being out of memory when they fail.
+    #[test]
+    fn pathological_sizes() {
+        let _lock = TEST_LOCK.lock();
+        reset("/tmp".to_string());
+        pymemprofile_add_allocation(0x1000, 1000, 1);
+        pymemprofile_add_allocation(0x2000, usize::MAX, 1);
+        pymemprofile_allocation_failed(usize::MAX, 1);
+        pymemprofile_update_allocation(0x3000, 0, 0x4000, usize::MAX / 2, 1);
+        pymemprofile_add_anon_mmap(0x10000, usize::MAX - 4095, 1);
+        // A realloc() that fails leaves the old allocation in place:
+        assert_eq!(pymemprofile_free_allocation(0x1000), 1000);
+        pymemprofile_realloc_failed(0x1000, 1000, usize::MAX, 1);
+        assert_eq!(pymemprofile_get_allocation_size(0x1000), 1000);
+        assert_eq!(pymemprofile_get_allocation_size(0x2000), 0);
+        assert_eq!(pymemprofile_get_allocation_size(0x4000), 0);
+        let mut tracker_state = TRACKER_STATE.lock();
+        let allocations = &mut tracker_state.allocations;
+        assert_eq!(allocations.get_traced_memory(), (1000, 1000));
+        assert_eq!(allocations.validate(), Vec::<String>::new());
+        let rejected = allocations.report_metadata().rejected_allocations.unwrap();
+        assert_eq!((rejected.count, rejected.largest_bytes), (5, usize::MAX));
+    }
+
+    /// A panic in the middle of updating TRACKER_STATE, e.g. from a bug, only
+    /// loses that update: the lock isn't poisoned, and later allocations via
+    /// the FFI are still tracked.
+    #[test]
+    fn panic_while_locked_doesnt_break_tracker() {
+        let _lock = TEST_LOCK.lock();
+        reset("/tmp".to_string());
+        pymemprofile_add_allocation(0x1000, 100, 1);
+        let result = std::thread::spawn(|| {
+            let mut tracker_state = TRACKER_STATE.lock();
+            let callstack_id = tracker_state
+                .allocations
+                .get_callstack_id(&Callstack::new());
+            tracker_state
+                .allocations
+                .add_allocation(PARENT_PROCESS, 0x2000, 50, callstack_id);
+            panic!("deliberate panic with the tracker lock held");
+        })
+        .join();
+        assert!(result.is_err());
+
+        pymemprofile_add_allocation(0x3000, 1000, 1);
+        assert_eq!(pymemprofile_free_allocation(0x1000), 100);
+        assert_eq!(pymemprofile_get_allocation_size(0x2000), 50);
+        let mut tracker_state = TRACKER_STATE.lock();
+        let allocations = &mut tracker_state.allocations;
+        assert_eq!(allocations.get_traced_memory(), (1050, 1150));
+        assert_eq!(allocations.validate(), Vec::<String>::new());
+    }
+
+    extern "C" fn ignore_peak(_peak_bytes: u64, _summary: *const c_char, _user_data: *mut c_void) {}
+
+    /// Fil can be shut down and reset any number of times without leaking
+    /// threads or file handles, and in between the APIs say it's shut down.
+    #[test]
+    fn shutdown_cycles() {
+        let _lock = TEST_LOCK.lock();
+        pyo3::prepare_freethreaded_python();
+        let fil_threads = || {
+            std::fs::read_dir("/proc/self/task")
+                .unwrap()
+                .flatten()
+                .filter_map(|task| std::fs::read_to_string(task.path().join("comm")).ok())
+                .filter(|name| name.starts_with("fil-"))
+                .count()
+        };
+        // New threads name themselves once they're running, so give them a
+        // moment:
+        let wait_for_fil_threads = |expected| {
+            for _ in 0..100 {
+                if fil_threads() == expected {
+                    break;
+                }
+                std::thread::sleep(Duration::from_millis(10));
+            }
+            fil_threads()
+        };
+        let open_files = || std::fs::read_dir("/proc/self/fd").unwrap().count();
+        let mut open_files_after_first = None;
+        for cycle in 0..3 {
+            let tmp = CString::new("/tmp").unwrap();
+            unsafe { fil_reset(tmp.as_ptr()) };
+            sampler::add_task("test", Duration::from_millis(1), || {});
+            pymemprofile_register_peak_callback(Some(ignore_peak), std::ptr::null_mut(), 1);
+            add_allocation(0x1000, 1000, 1, AllocationKind::Malloc).unwrap();
+            assert_eq!(wait_for_fil_threads(2), 2);
+
+            let directory =
+                std::env::temp_dir().join(format!("fil-shutdown-{}-{}", std::process::id(), cycle));
+            let path = CString::new(directory.to_str().unwrap()).unwrap();
+            unsafe { fil_start_tracking() };
+            assert_eq!(unsafe { fil_shutdown(path.as_ptr()) }, 0);
+            assert!(directory.join("peak-memory.prof").exists());
+            std::fs::remove_dir_all(&directory).unwrap();
+            assert_eq!(fil_threads(), 0);
+            assert_eq!(
+                TRACKER_STATE
+                    .lock()
+                    .allocations
+                    .get_current_allocated_bytes(),
+                0
+            );
+
+            // Everything is a no-op until the next reset:
+            unsafe { fil_start_tracking() };
+            assert_eq!(unsafe { is_tracking_allocations() }, 0);
+            assert_eq!(unsafe { fil_shutdown(std::ptr::null()) }, ALREADY_SHUT_DOWN);
+            assert_eq!(pymemprofile_region_start(0), ALREADY_SHUT_DOWN);
+            assert_eq!(
+                unsafe {
+                    pymemprofile_dump_peak_to_flamegraph(path.as_ptr(), std::ptr::null_mut(), 0)
+                },
+                ALREADY_SHUT_DOWN
+            );
+            assert!(!directory.exists());
+
+            let open_files = open_files();
+            assert_eq!(
+                *open_files_after_first.get_or_insert(open_files),
+                open_files
+            );
+        }
+        reset("/tmp".to_string());
+    }
 }
@@ -0,0 +1,182 @@
+//! Notify a user-registered C callback when peak memory grows.
+//!
+//! Checking happens under the tracker lock on every allocation, so it has to
+//! be cheap; the actual callback runs on a dedicated notifier thread, outside
+//! the tracker lock. That thread marks itself as reentrant, so allocations
+//! made by the callback aren't tracked and can't trigger further
+//! notifications.
+
+use parking_lot::{Condvar, Mutex};
+use pymemprofile_api::linecache::LineCacher;
+use pymemprofile_api::memorytracking::{Callstack, VecFunctionLocations};
+use std::ffi::CString;
+use std::os::raw::{c_char, c_void};
+use std::thread::JoinHandle;
+
+/// The callback gets the new peak in bytes, a NUL-terminated human-readable
+/// summary that is only valid for the duration of the call, and the user data
+/// pointer it was registered with.
+pub type PeakCallback =
+    extern "C" fn(peak_bytes: u64, summary: *const c_char, user_data: *mut c_void);
+
+/// A registered callback, plus the bookkeeping needed to decide when to call
+/// it.
+pub struct PeakNotifier {
+    callback: PeakCallback,
+    // Stored as usize so it can be sent to the notifier thread; we never
+    // dereference it, just pass it back to the callback.
+    user_data: usize,
+    min_delta_bytes: usize,
+    last_notified_bytes: usize,
+}
+
+impl PeakNotifier {
+    pub fn new(
+        callback: PeakCallback,
+        user_data: *mut c_void,
+        min_delta_bytes: usize,
+        current_peak_bytes: usize,
+    ) -> Self {
+        Self {
+            callback,
+            user_data: user_data as usize,
+            min_delta_bytes: min_delta_bytes.max(1),
+            last_notified_bytes: current_peak_bytes,
+        }
+    }
+
+    /// Given the current allocated bytes, return whether a notification is
+    /// due. Current allocated bytes is always a lower bound on the peak, and
+    /// equal to it whenever a new peak is being set.
+    pub fn is_due(&mut self, current_allocated_bytes: usize) -> bool {
+        if current_allocated_bytes >= self.last_notified_bytes + self.min_delta_bytes {
+            self.last_notified_bytes = current_allocated_bytes;
+            true
+        } else {
+            false
+        }
+    }
+
+    /// Start counting from scratch, e.g. after the tracker is reset.
+    pub fn reset(&mut self) {
+        self.last_notified_bytes = 0;
+    }
+
+    /// Queue a notification for the notifier thread. Only the most recent
+    /// pending notification is kept; if the callback is slow, intermediate
+    /// peaks are skipped.
+    pub fn notify(
+        &self,
+        peak_bytes: usize,
+        dominant: Option<(Callstack, usize)>,
+        functions: VecFunctionLocations,
+    ) {
+        let mut pending = PENDING.lock();
+        if matches!(*pending, Pending::Stop) {
+            return;
+        }
+        *pending = Pending::Notify(Box::new(PendingNotification {
+            callback: self.callback,
+            user_data: self.user_data,
+            peak_bytes,
+            dominant,
+            functions,
+        }));
+        WAKEUP.notify_one();
+    }
+}
+
+struct PendingNotification {
+    callback: PeakCallback,
+    user_data: usize,
+    peak_bytes: usize,
+    dominant: Option<(Callstack, usize)>,
+    functions: VecFunctionLocations,
+}
+
+impl PendingNotification {
+    /// Create the summary passed to the callback.
+    fn summary(&self) -> String {
+        let mib = |bytes: usize| bytes as f64 / (1024.0 * 1024.0);
+        match &self.dominant {
+            Some((callstack, bytes)) => format!(
+                "New peak of {:.1} MiB; {:.1} MiB ({:.0}%) allocated from {}",
+                mib(self.peak_bytes),
+                mib(*bytes),
+                (*bytes as f64) * 100.0 / (self.peak_bytes.max(1) as f64),
+                callstack.as_string(false, &self.functions, ";", &mut LineCacher::default()),
+            ),
+            None => format!("New peak of {:.1} MiB", mib(self.peak_bytes)),
+        }
+    }
+}
+
+/// What the notifier thread should do next.
+enum Pending {
+    Nothing,
+    Notify(Box<PendingNotification>),
+    Stop,
+}
+
+lazy_static! {
+    static ref PENDING: Mutex<Pending> = Mutex::new(Pending::Nothing);
+    static ref WAKEUP: Condvar = Condvar::new();
+    static ref NOTIFIER_THREAD: Mutex<Option<JoinHandle<()>>> = Mutex::new(None);
+}
+
+extern "C" {
+    fn fil_increment_reentrancy();
+}
+
+/// Start the notifier thread, if it isn't already running.
+pub fn start_notifier_thread() {
+    let mut thread = NOTIFIER_THREAD.lock();
+    if thread.is_none() {
+        *thread = Some(
+            std::thread::Builder::new()
+                .name("fil-peak-notifier".to_string())
+                .spawn(notifier_thread)
+                .expect("=fil-profile= Couldn't start peak notifier thread"),
+        );
+    }
+}
+
+/// Stop the notifier thread, if it's running, and wait for it to exit. A
+/// pending notification is dropped, but one that's being delivered finishes
+/// first.
+pub fn stop_notifier_thread() {
+    let Some(thread) = NOTIFIER_THREAD.lock().take() else {
+        return;
+    };
+    *PENDING.lock() = Pending::Stop;
+    WAKEUP.notify_one();
+    let _ = thread.join();
+    *PENDING.lock() = Pending::Nothing;
+}
+
+fn notifier_thread() {
+    // Nothing in this thread should be tracked, including whatever the
+    // callback does:
+    unsafe { fil_increment_reentrancy() };
+    crate::reentrancy::InTracker::enter_permanently();
+    loop {
+        let notification = {
+            let mut pending = PENDING.lock();
+            loop {
+                match std::mem::replace(&mut *pending, Pending::Nothing) {
+                    Pending::Nothing => WAKEUP.wait(&mut pending),
+                    Pending::Notify(notification) => break notification,
+                    Pending::Stop => return,
+                }
+            }
+        };
+        // Summaries shouldn't have NULs, but just in case:
+        let summary =
+            CString::new(notification.summary().replace('\0', "")).expect("NUL bytes were removed");
+        (notification.callback)(
+            notification.peak_bytes as u64,
+            summary.as_ptr(),
+            notification.user_data as *mut c_void,
+        );
+    }
+}
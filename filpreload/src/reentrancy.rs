@@ -0,0 +1,84 @@
+//! A Rust-side reentrancy guard, complementing the C one.
+//!
+//! The C shim stops tracking while it's calling into Rust, but that only
+//! covers calls that go through the shim. This flag is owned by the Rust side:
+//! the FFI entry points that record allocations set it for their duration and
+//! ignore calls made while it's set, and dumps hold it throughout, so if some
+//! allocation made while tracking or writing a report ever reaches us, it's
+//! dropped rather than recorded, or deadlocking on TRACKER_STATE.
+
+use std::cell::Cell;
+use std::marker::PhantomData;
+
+thread_local!(static IN_TRACKER: Cell<bool> = const { Cell::new(false) });
+
+// Allocations recorded by this thread, so debug builds can check dumps don't
+// record their own:
+#[cfg(debug_assertions)]
+thread_local!(static RECORDED: Cell<u64> = const { Cell::new(0) });
+
+/// While this exists, the current thread is inside the tracker.
+pub struct InTracker {
+    // Must be dropped on the thread that created it:
+    _not_send: PhantomData<*const ()>,
+}
+
+impl InTracker {
+    /// Mark the current thread as inside the tracker, or return None if it
+    /// already is.
+    pub fn enter() -> Option<Self> {
+        if IN_TRACKER.with(|in_tracker| in_tracker.replace(true)) {
+            None
+        } else {
+            Some(Self {
+                _not_send: PhantomData,
+            })
+        }
+    }
+
+    /// Mark the current thread as inside the tracker for the rest of its life,
+    /// e.g. for Fil's own background threads.
+    pub fn enter_permanently() {
+        std::mem::forget(Self::enter());
+    }
+}
+
+impl Drop for InTracker {
+    fn drop(&mut self) {
+        IN_TRACKER.with(|in_tracker| in_tracker.set(false));
+    }
+}
+
+/// Note that the current thread recorded an allocation.
+#[inline]
+pub fn count_recorded() {
+    #[cfg(debug_assertions)]
+    RECORDED.with(|recorded| recorded.set(recorded.get() + 1));
+}
+
+/// How many allocations the current thread has recorded; always 0 in release
+/// builds.
+pub fn recorded() -> u64 {
+    #[cfg(debug_assertions)]
+    return RECORDED.with(|recorded| recorded.get());
+    #[cfg(not(debug_assertions))]
+    0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::InTracker;
+
+    #[test]
+    fn not_reentrant() {
+        let outer = InTracker::enter();
+        assert!(outer.is_some());
+        assert!(InTracker::enter().is_none());
+        // Other threads are unaffected:
+        assert!(std::thread::spawn(|| InTracker::enter().is_some())
+            .join()
+            .unwrap());
+        drop(outer);
+        assert!(InTracker::enter().is_some());
+    }
+}
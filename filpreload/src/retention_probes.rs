@@ -0,0 +1,157 @@
+//! User-registered probes that estimate how much memory a cache, e.g. a
+//! functools.lru_cache, is holding on to, called when the peak report is
+//! written.
+//!
+//! Cache memory is already tracked like any other memory, so the estimates
+//! are only reported alongside the peak, never added to it. Each probe runs on
+//! its own thread, outside the tracker lock and with the GIL released, and the
+//! dump only waits so long for it, FIL_RETENTION_PROBE_TIMEOUT_MS, 1000 by
+//! default. A probe that doesn't return in time may still be running, so it's
+//! never called again. Like the other background threads, probe threads are
+//! reentrant, so whatever the probe allocates isn't tracked.
+
+use parking_lot::Mutex;
+use pymemprofile_api::metadata::RetainedByCache;
+use std::ffi::CStr;
+use std::os::raw::{c_char, c_void};
+use std::sync::mpsc::{self, RecvTimeoutError};
+use std::time::Duration;
+
+/// Given the user data pointer it was registered with, return the estimated
+/// bytes the cache is retaining, or a negative number if it can't tell.
+pub type RetentionProbe = extern "C" fn(user_data: *mut c_void) -> i64;
+
+struct Probe {
+    name: String,
+    probe: RetentionProbe,
+    // Stored as usize so it can be sent to the probe's thread; we never
+    // dereference it, just pass it back to the probe.
+    user_data: usize,
+    // Set if the probe didn't return in time:
+    hung: bool,
+}
+
+lazy_static! {
+    static ref PROBES: Mutex<Vec<Probe>> = Mutex::new(vec![]);
+}
+
+extern "C" {
+    fn fil_increment_reentrancy();
+}
+
+/// Register a probe under the given name, replacing any existing probe with
+/// that name. If the probe is None, the existing one is unregistered.
+///
+/// # Safety
+/// The name must be NUL-terminated.
+pub unsafe fn register(name: *const c_char, probe: Option<RetentionProbe>, user_data: *mut c_void) {
+    let name = unsafe { CStr::from_ptr(name) }
+        .to_string_lossy()
+        .into_owned();
+    let mut probes = PROBES.lock();
+    let existing = probes.iter().position(|probe| probe.name == name);
+    match (probe, existing) {
+        (Some(probe), Some(index)) => {
+            probes[index] = Probe {
+                name,
+                probe,
+                user_data: user_data as usize,
+                hung: false,
+            }
+        }
+        (Some(probe), None) => probes.push(Probe {
+            name,
+            probe,
+            user_data: user_data as usize,
+            hung: false,
+        }),
+        (None, Some(index)) => {
+            probes.remove(index);
+        }
+        (None, None) => {}
+    }
+}
+
+/// Forget all the probes.
+pub fn clear() {
+    PROBES.lock().clear();
+}
+
+fn timeout() -> Duration {
+    let milliseconds = std::env::var("FIL_RETENTION_PROBE_TIMEOUT_MS")
+        .ok()
+        .and_then(|value| value.parse().ok())
+        .unwrap_or(1000);
+    Duration::from_millis(milliseconds)
+}
+
+/// Call all the probes, in the order they were registered. Must be called
+/// without the tracker lock held.
+pub fn run_all() -> Vec<RetainedByCache> {
+    // Copied out, so probes can (un)register probes without deadlocking:
+    let probes: Vec<(String, RetentionProbe, usize, bool)> = PROBES
+        .lock()
+        .iter()
+        .map(|probe| (probe.name.clone(), probe.probe, probe.user_data, probe.hung))
+        .collect();
+    if probes.is_empty() {
+        return vec![];
+    }
+    let timeout = timeout();
+    let run = || {
+        probes
+            .into_iter()
+            .map(|(name, probe, user_data, hung)| {
+                let result = if hung {
+                    Err("it didn't return in time when previously called".to_string())
+                } else {
+                    run_one(probe, user_data, timeout)
+                };
+                let (estimated_bytes, error) = match result {
+                    Ok(bytes) => (Some(bytes), None),
+                    Err(error) => (None, Some(error)),
+                };
+                RetainedByCache {
+                    name,
+                    estimated_bytes,
+                    error,
+                }
+            })
+            .collect()
+    };
+    // Probes written in Python need the GIL, so if this thread has it, let
+    // go of it while waiting:
+    if unsafe { pyo3::ffi::PyGILState_Check() } == 1 {
+        pyo3::Python::with_gil(|py| py.allow_threads(run))
+    } else {
+        run()
+    }
+}
+
+/// Run the probe on a new thread, waiting for at most the timeout.
+fn run_one(probe: RetentionProbe, user_data: usize, timeout: Duration) -> Result<u64, String> {
+    let (sender, receiver) = mpsc::channel();
+    std::thread::Builder::new()
+        .name("fil-retention".to_string())
+        .spawn(move || {
+            unsafe { fil_increment_reentrancy() };
+            crate::reentrancy::InTracker::enter_permanently();
+            let _ = sender.send(probe(user_data as *mut c_void));
+        })
+        .map_err(|e| format!("couldn't start a thread: {}", e))?;
+    match receiver.recv_timeout(timeout) {
+        Ok(bytes) if bytes >= 0 => Ok(bytes as u64),
+        Ok(_) => Err("the probe couldn't estimate it".to_string()),
+        Err(RecvTimeoutError::Timeout) => {
+            // It may still be running, so don't call it again:
+            for registered in PROBES.lock().iter_mut() {
+                if registered.probe as usize == probe as usize && registered.user_data == user_data
+                {
+                    registered.hung = true;
+                }
+            }
+            Err(format!("timed out after {} ms", timeout.as_millis()))
+        }
+        Err(RecvTimeoutError::Disconnected) => Err("the probe failed".to_string()),
+    }
+}
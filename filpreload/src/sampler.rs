@@ -0,0 +1,105 @@
+//! A background thread that periodically runs tasks, e.g. sampling the memory
+//! timeline or watching cgroup memory limits.
+//!
+//! Like the peak notifier thread, it marks itself as reentrant so its own
+//! allocations aren't tracked.
+
+use parking_lot::{Condvar, Mutex};
+use std::thread::JoinHandle;
+use std::time::{Duration, Instant};
+
+struct Task {
+    name: &'static str,
+    interval: Duration,
+    next_run: Instant,
+    run: fn(),
+}
+
+#[derive(Default)]
+struct Sampler {
+    tasks: Vec<Task>,
+    thread: Option<JoinHandle<()>>,
+    stopping: bool,
+}
+
+lazy_static! {
+    static ref SAMPLER: Mutex<Sampler> = Mutex::new(Sampler::default());
+    static ref WAKEUP: Condvar = Condvar::new();
+}
+
+extern "C" {
+    fn fil_increment_reentrancy();
+}
+
+/// Run `run` every `interval` on the sampler thread, starting the thread if it
+/// isn't already running. Adding a task with the same name as an existing one
+/// replaces it.
+pub fn add_task(name: &'static str, interval: Duration, run: fn()) {
+    let mut sampler = SAMPLER.lock();
+    sampler.tasks.retain(|task| task.name != name);
+    sampler.tasks.push(Task {
+        name,
+        interval,
+        next_run: Instant::now() + interval,
+        run,
+    });
+    if sampler.thread.is_none() {
+        sampler.thread = Some(
+            std::thread::Builder::new()
+                .name("fil-sampler".to_string())
+                .spawn(sampler_thread)
+                .expect("=fil-profile= Couldn't start sampler thread"),
+        );
+    }
+    // In case the new task is due before the thread would otherwise wake up:
+    WAKEUP.notify_one();
+}
+
+/// Remove all tasks and stop the sampler thread, waiting for it to exit. The
+/// next add_task() starts a new one.
+pub fn shutdown() {
+    let thread = {
+        let mut sampler = SAMPLER.lock();
+        sampler.tasks.clear();
+        sampler.stopping = true;
+        sampler.thread.take()
+    };
+    WAKEUP.notify_one();
+    if let Some(thread) = thread {
+        let _ = thread.join();
+    }
+    SAMPLER.lock().stopping = false;
+}
+
+fn sampler_thread() {
+    unsafe { fil_increment_reentrancy() };
+    crate::reentrancy::InTracker::enter_permanently();
+    let mut sampler = SAMPLER.lock();
+    while !sampler.stopping {
+        let now = Instant::now();
+        let due: Vec<fn()> = sampler
+            .tasks
+            .iter_mut()
+            .filter(|task| task.next_run <= now)
+            .map(|task| {
+                task.next_run = now + task.interval;
+                task.run
+            })
+            .collect();
+        if !due.is_empty() {
+            // Run tasks without the lock held, so they can add tasks:
+            drop(sampler);
+            for run in due {
+                run();
+            }
+            sampler = SAMPLER.lock();
+            continue;
+        }
+        match sampler.tasks.iter().map(|task| task.next_run).min() {
+            Some(next_run) => {
+                WAKEUP.wait_until(&mut sampler, next_run);
+            }
+            None => WAKEUP.wait(&mut sampler),
+        }
+    }
+}
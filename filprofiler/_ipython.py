@@ -3,14 +3,13 @@ IPython magic, specifically for Jupyter, allowing memory profiling from inside
 a Jupyter notebook.
 """
 
+from html import escape
 from pathlib import Path
 from textwrap import indent
 from tempfile import mkdtemp
 
 from IPython.core.magic import Magics, magics_class, cell_magic
-from IPython.display import IFrame, display, HTML
-
-from .api import profile
+from IPython.display import display, HTML
 
 
 HOPEFULLY_UNIQUE_VAR = "__arghbldsada__"
@@ -27,6 +26,9 @@ def __magic_run_with_fil():
 globals().update({}(__magic_run_with_fil))
 """
 
+# Bigger flamegraphs get pruned, so notebooks don't get too big:
+MAX_SVG_BYTES = 10 * 1024 * 1024
+
 
 @magics_class
 class FilMagics(Magics):
@@ -58,15 +60,34 @@ class FilMagics(Magics):
 
 def run_with_profile(code_to_profile):
     """Run some code under Fil, display result."""
+    from ._tracer import (
+        start_tracing,
+        stop_tracing,
+        disable_thread_pools,
+        check_if_fil_preloaded,
+        render_peak_svg,
+    )
+
+    check_if_fil_preloaded()
     topdir = Path("fil-result")
     if not topdir.exists():
         topdir.mkdir()
     tempdir = Path(mkdtemp(dir=topdir))
-    try:
-        return profile(code_to_profile, tempdir)
-    finally:
-        svg_path = tempdir / "peak-memory.svg"
-        if svg_path.exists():
-            display(IFrame(svg_path, width="100%", height="600"))
-        else:
-            display(HTML("No report generated, perhaps zero memory was allocated."))
+    # Like api.profile(), but the SVG is rendered in memory before tracing
+    # stops, and shown inline:
+    start_tracing(tempdir)
+    with disable_thread_pools():
+        try:
+            return code_to_profile()
+        finally:
+            svg = render_peak_svg(MAX_SVG_BYTES)
+            stop_tracing(tempdir)
+            if svg is not None:
+                display(
+                    HTML(
+                        f'<iframe srcdoc="{escape(svg)}" width="100%" height="600" '
+                        'frameborder="0"></iframe>'
+                    )
+                )
+            else:
+                display(HTML("No report generated, perhaps zero memory was allocated."))
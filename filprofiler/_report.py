@@ -6,6 +6,8 @@ post-fact in Python.
 """
 
 from datetime import datetime
+from html import escape
+import json
 import os
 import shlex
 import sys
@@ -21,9 +23,405 @@ Python: {sys.version}
 )
 
 
+def _read_metadata(output_path: str) -> dict:
+    """Load the metadata.json written alongside the flamegraphs, if any."""
+    try:
+        with open(os.path.join(output_path, "metadata.json")) as f:
+            return json.load(f)
+    except (OSError, ValueError):
+        return {}
+
+
+def _sampling_notice(metadata: dict) -> str:
+    """HTML warning if some allocations were sampled rather than exact."""
+    sampling = metadata.get("adaptive_sampling", {})
+    if not sampling.get("engaged"):
+        return ""
+    times = ", ".join(
+        "{} at {:.1f}s".format(
+            "started" if t["engaged"] else "stopped", t["seconds"]
+        )
+        for t in sampling.get("transitions", [])
+    )
+    return (
+        "<blockquote class=\"center\"><strong>Some of this profile is an "
+        "estimate.</strong> There were so many live allocations that small "
+        "allocations were sampled rather than tracked exactly ({}).</blockquote>"
+    ).format(times)
+
+
+def _region(metadata: dict) -> str:
+    """HTML explaining this is the report for a region, if it is."""
+    region = metadata.get("region")
+    if not region:
+        return ""
+    if region["pre_existing"] == "group":
+        pre_existing = "is shown as <tt>[pre-existing]</tt>"
+    else:
+        pre_existing = "isn't shown"
+    return (
+        '<p class="center">This report only covers a profiled region: at its '
+        "peak, {:.1f} MiB allocated inside the region was in use. Memory "
+        "allocated before the region started, {:.1f} MiB of which was still "
+        "in use at that point, {}.</p>"
+    ).format(
+        region["peak_bytes"] / (1024 * 1024),
+        region["pre_existing_bytes"] / (1024 * 1024),
+        pre_existing,
+    )
+
+
+def _anon_mmaps(metadata: dict) -> str:
+    """HTML note if adjacent anonymous mmap()s were coalesced."""
+    anon_mmaps = metadata.get("anon_mmaps")
+    if not anon_mmaps or not anon_mmaps.get("coalesced"):
+        return ""
+    return (
+        '<p class="center">{} adjacent anonymous <tt>mmap()</tt>s from the same '
+        "callstack were coalesced, leaving {} mappings (FIL_COALESCE_MMAPS=1).</p>"
+    ).format(anon_mmaps["coalesced"], anon_mmaps["mappings"])
+
+
+def _retained_by_caches(metadata: dict) -> str:
+    """HTML list of what registered retention probes estimated."""
+    retained = metadata.get("retained_by_caches")
+    if not retained:
+        return ""
+    items = []
+    for cache in retained:
+        if cache["estimated_bytes"] is not None:
+            amount = "~{:.1f} MiB".format(cache["estimated_bytes"] / (1024 * 1024))
+        else:
+            amount = "unknown: {}".format(escape(cache["error"] or "no estimate"))
+        items.append(
+            "<li><tt>[retained by cache: {}]</tt> {}</li>".format(
+                escape(cache["name"]), amount
+            )
+        )
+    return (
+        '<div class="center"><p>How much memory caches said they were holding '
+        "on to; these are estimates, and not added to the peak:</p><ul>{}</ul>"
+        "</div>"
+    ).format("".join(items))
+
+
+def _temp_files(metadata: dict) -> str:
+    """HTML saying how much was spilled to temporary files, if tracked."""
+    temp_files = metadata.get("temp_files")
+    if not temp_files:
+        return ""
+    items = "".join(
+        "<li>{:.1f} MiB from <tt>{}</tt></li>".format(
+            callstack["bytes"] / (1024 * 1024),
+            escape(callstack["innermost_frame"]),
+        )
+        for callstack in temp_files["callstacks"]
+    )
+    return (
+        '<div class="center"><p><tt>[spilled to temp files]</tt> At most {:.1f} '
+        "MiB was in unlinked temporary files, which use memory while they're "
+        "in the page cache; this isn't added to the peak. Created by:</p>"
+        "<ul>{}</ul></div>"
+    ).format(temp_files["peak_bytes"] / (1024 * 1024), items)
+
+
+def _tracker_budget(metadata: dict) -> str:
+    """HTML warning if the profiler exceeded FIL_TRACKER_BUDGET_MB."""
+    budget = metadata.get("tracker_budget")
+    if not budget or not budget.get("exceeded"):
+        return ""
+    return (
+        '<blockquote class="center"><strong>This profile is less detailed '
+        "than usual.</strong> The profiler's own memory use exceeded "
+        "FIL_TRACKER_BUDGET_MB={}, so from then on small allocations were "
+        "sampled and rolled up, and memory allocated from new callstacks was "
+        "reported as <tt>[callstack table full]</tt>.</blockquote>"
+    ).format(budget["limit_bytes"] // (1024 * 1024))
+
+
+def _drift(metadata: dict) -> str:
+    """HTML warning if FIL_DRIFT_CHECK=1 found tracked memory drifting."""
+    drift = metadata.get("drift")
+    if not drift or not drift.get("warning"):
+        return ""
+    sample = drift["warning"]["sample"]
+    return (
+        '<blockquote class="center"><strong>Tracked memory may be '
+        "wrong.</strong> After {:.0f} seconds the allocator had {:.1f} MiB {} "
+        "in use than Fil was tracking, which suggests some allocations or "
+        "frees weren't seen. Please "
+        '<a href="https://github.com/pythonspeed/filprofiler/issues/new">file '
+        "a bug</a>.</blockquote>"
+    ).format(
+        sample["seconds"],
+        abs(sample["divergence_bytes"]) / (1024 * 1024),
+        "more" if sample["divergence_bytes"] > 0 else "less",
+    )
+
+
+def _peak_trigger(metadata: dict) -> str:
+    """HTML saying which allocation reached the peak, if known."""
+    triggers = metadata.get("peak_triggers")
+    if not triggers:
+        return ""
+    trigger = triggers[-1]
+    return (
+        '<p class="center">Peak was reached while executing <tt>{}</tt>, '
+        "allocating {:.1f} MiB on thread {}.</p>"
+    ).format(
+        escape(trigger["innermost_frame"]),
+        trigger["bytes"] / (1024 * 1024),
+        trigger["thread_id"],
+    )
+
+
+def _allocator_stats(metadata: dict) -> str:
+    """HTML comparing tracked memory to what the allocator says."""
+    allocator = metadata.get("allocator")
+    if allocator is None:
+        return ""
+    stats = allocator.get("stats")
+    if stats is None:
+        return "<p>Allocator statistics are unavailable: {}.</p>".format(
+            allocator.get("unavailable_reason", "unknown reason")
+        )
+
+    def mib(num_bytes):
+        return "{:.1f} MiB".format(num_bytes / (1024 * 1024))
+
+    rows = [
+        ("Obtained from the OS", mib(stats["arena_bytes"])),
+        ("In use", mib(stats["in_use_bytes"])),
+        ("Free, but not released to the OS", mib(stats["free_bytes"])),
+    ]
+    if stats.get("releasable_bytes") is not None:
+        rows.append(
+            ("Releasable with <tt>malloc_trim()</tt>", mib(stats["releasable_bytes"]))
+        )
+    rows.append(("Tracked by Fil as allocated", mib(allocator["tracked_live_bytes"])))
+    if allocator.get("tracked_to_arena_ratio") is not None:
+        rows.append(
+            (
+                "Tracked as a fraction of allocator arena",
+                "{:.0f}%".format(allocator["tracked_to_arena_ratio"] * 100),
+            )
+        )
+    return (
+        "<p>When the report was written, according to {}:</p>\n"
+        "<table>\n{}\n</table>\n"
+        "<p>If much more memory is free than in use, the allocator is holding "
+        "on to memory—often due to fragmentation—so the process' memory usage "
+        "may not go down even after everything is freed.</p>"
+    ).format(
+        stats["source"],
+        "\n".join(
+            "<tr><td>{}</td><td>{}</td></tr>".format(name, value)
+            for (name, value) in rows
+        ),
+    )
+
+
+def _environment(metadata: dict) -> str:
+    """HTML table of what was profiled and where, for reproducibility."""
+    environment = metadata.get("environment")
+    if not environment:
+        return ""
+    rows = [
+        ("Fil", __version__),
+        ("Python", environment.get("python_version")),
+        ("Tracking code", environment.get("memapi_version")),
+        ("OS", environment.get("os")),
+        ("Kernel", environment.get("kernel")),
+        ("CPUs", environment.get("cpu_count")),
+    ]
+    limit = environment.get("cgroup_memory_limit_bytes")
+    if limit is not None:
+        rows.append(("cgroup memory limit", "{:.1f} MiB".format(limit / (1024 * 1024))))
+    rows.append(("Working directory", environment.get("working_directory")))
+    rows.append(
+        ("Command line", " ".join(map(shlex.quote, environment.get("command_line", []))))
+    )
+    for key, value in environment.get("configuration", {}).items():
+        rows.append((key, value))
+    for key, value in environment.get("user", {}).items():
+        rows.append((key, value))
+    return "<h2>Environment</h2>\n<table>\n{}\n</table>".format(
+        "\n".join(
+            "<tr><td>{}</td><td><tt>{}</tt></td></tr>".format(
+                escape(str(name)), escape(str(value))
+            )
+            for (name, value) in rows
+            if value is not None
+        )
+    )
+
+
+def _phases(metadata: dict) -> str:
+    """HTML summarizing memory by named phase, if any phases were used."""
+    phases = metadata.get("phases")
+    peak_phase_frames = metadata.get("peak_phase_frames")
+    if not phases and not peak_phase_frames:
+        return ""
+
+    result = "<h2>Memory by phase</h2>\n"
+    if peak_phase_frames:
+        result += "<p>Peak memory was reached in phase <tt>{}</tt>.</p>\n".format(
+            escape(" > ".join(peak_phase_frames))
+        )
+    if not phases:
+        return result
+
+    def mib(num_bytes):
+        return "{:.1f} MiB".format(num_bytes / (1024 * 1024))
+
+    return result + (
+        "<table>\n<tr><th>Phase</th><th>At peak</th><th>When the report was written</th></tr>\n"
+        "{}\n</table>"
+    ).format(
+        "\n".join(
+            "<tr><td>{}</td><td>{}</td><td>{}</td></tr>".format(
+                escape(phase["name"]),
+                mib(phase["peak_bytes"]),
+                mib(phase["current_bytes"]),
+            )
+            for phase in phases
+        )
+    )
+
+
+def _milestones(output_path: str, metadata: dict) -> str:
+    """HTML linking to the FIL_MILESTONES reports, if any were written."""
+    milestones = [
+        milestone
+        for milestone in metadata.get("milestones", [])
+        if os.path.exists(milestone["path"])
+    ]
+    if not milestones:
+        return ""
+    return (
+        "<h2>Milestones</h2>\n"
+        "<p>What was using memory the first time tracked memory went over each "
+        "size in FIL_MILESTONES.</p>\n"
+        "<table>\n<tr><th>Milestone</th><th>Reached after</th><th>Tracked memory</th></tr>\n"
+        "{}\n</table>"
+    ).format(
+        "\n".join(
+            '<tr><td><a href="{}" target="_blank">{}</a></td><td>{:.1f} seconds</td><td>{:.1f} MiB</td></tr>'.format(
+                escape(os.path.relpath(milestone["path"], output_path)),
+                escape(milestone["label"]),
+                milestone["seconds"],
+                milestone["allocated_bytes"] / (1024 * 1024),
+            )
+            for milestone in milestones
+        )
+    )
+
+
+def _windows(output_path: str, metadata: dict) -> str:
+    """HTML about FIL_ROTATE_WINDOW, linking to the windows still on disk."""
+    rotation = metadata.get("rotation")
+    if not rotation:
+        return ""
+    windows = [
+        window for window in rotation["windows"] if os.path.exists(window["path"])
+    ]
+    return (
+        "<h2>Windows</h2>\n"
+        "<p>The peak above is of window {} only, which started after {:.1f} "
+        "seconds; each window is {:.1f} seconds. Memory that was still allocated "
+        "when a window started is under <tt>[carried over]</tt>.</p>\n"
+        "<table>\n<tr><th>Window</th><th>Ended after</th><th>Peak tracked memory</th></tr>\n"
+        "{}\n</table>"
+    ).format(
+        rotation["current"],
+        rotation["current_started_seconds"],
+        rotation["interval_seconds"],
+        "\n".join(
+            '<tr><td><a href="{}" target="_blank">{}</a></td><td>{:.1f} seconds</td><td>{:.1f} MiB</td></tr>'.format(
+                escape(os.path.relpath(window["path"], output_path)),
+                window["number"],
+                window["seconds"],
+                window["peak_bytes"] / (1024 * 1024),
+            )
+            for window in windows
+        ),
+    )
+
+
+def _frees_graph(output_path: str) -> str:
+    """HTML for the flamegraph of where memory was freed, if it was recorded."""
+    if not os.path.exists(os.path.join(output_path, "frees.svg")):
+        return ""
+    return """
+<h2>Where memory was freed</h2>
+<p>Bytes freed by each callstack that called <tt>free()</tt>.
+See <a href="frees-pairs.txt">frees-pairs.txt</a> for which allocations were freed from where.</p>
+<div style="text-align: center;"><p><input type="button" onclick="fullScreen('#frees');" value="Full screen"> · <a href="frees.svg" target="_blank"><button>Open in new window</button></a></p>
+<iframe id="frees" src="frees.svg" width="100%" height="400" scrolling="auto" frameborder="0"></iframe>
+</div>
+"""
+
+
+def _objects_graph(output_path: str) -> str:
+    """HTML for the flamegraph of live Python objects, if any were tracked."""
+    if not os.path.exists(os.path.join(output_path, "objects.svg")):
+        return ""
+    return """
+<h2>Live Python objects</h2>
+<p>Number of live tracked objects, by the callstack that created them.
+See <a href="objects.json">objects.json</a> for a breakdown by type.</p>
+<div style="text-align: center;"><p><input type="button" onclick="fullScreen('#objects');" value="Full screen"> · <a href="objects.svg" target="_blank"><button>Open in new window</button></a></p>
+<iframe id="objects" src="objects.svg" width="100%" height="400" scrolling="auto" frameborder="0"></iframe>
+</div>
+"""
+
+
+def _mapped_files_graph(output_path: str) -> str:
+    """HTML for the flamegraph including file-backed mmap()s, if they were tracked."""
+    if not os.path.exists(os.path.join(output_path, "peak-memory-with-mapped-files.svg")):
+        return ""
+    return """
+<h2>Peak memory including mapped files</h2>
+<p>Memory-mapped files aren't included in the peak above, since the operating system can evict their pages and reload them from disk as needed.
+This is the peak of everything combined, with the mapped files shown as <tt>[mapped file: ...]</tt> frames.</p>
+<div style="text-align: center;"><p><input type="button" onclick="fullScreen('#mapped-files');" value="Full screen"> · <a href="peak-memory-with-mapped-files.svg" target="_blank"><button>Open in new window</button></a></p>
+<iframe id="mapped-files" src="peak-memory-with-mapped-files.svg" width="100%" height="400" scrolling="auto" frameborder="0"></iframe>
+</div>
+"""
+
+
+def _allocation_rates_graph(output_path: str) -> str:
+    """HTML for the flamegraph of allocations per second, if they were counted."""
+    if not os.path.exists(os.path.join(output_path, "allocation-rates.svg")):
+        return ""
+    return """
+<h2>Allocations per second</h2>
+<p>How often each callstack allocated, on average over the run; the more allocations, the more overhead Fil adds.
+See <a href="allocation-rates.txt">allocation-rates.txt</a> for the callstacks with the highest peak rate.</p>
+<div style="text-align: center;"><p><input type="button" onclick="fullScreen('#allocation-rates');" value="Full screen"> · <a href="allocation-rates.svg" target="_blank"><button>Open in new window</button></a></p>
+<iframe id="allocation-rates" src="allocation-rates.svg" width="100%" height="400" scrolling="auto" frameborder="0"></iframe>
+</div>
+"""
+
+
+def _timeline(output_path: str) -> str:
+    """HTML for the memory timeline, if it was recorded."""
+    if not os.path.exists(os.path.join(output_path, "timeline.html")):
+        return ""
+    return """
+<h2>Memory over time</h2>
+<p>Tracked memory over time, by top-level frame.
+The raw data is in <a href="timeline.json">timeline.json</a>.</p>
+<div style="text-align: center;"><p><a href="timeline.html" target="_blank"><button>Open in new window</button></a></p>
+<iframe id="timeline" src="timeline.html" width="100%" height="700" scrolling="auto" frameborder="0"></iframe>
+</div>
+"""
+
+
 def render_report(output_path: str, now: datetime) -> str:
     """Write out the HTML index and improve the SVGs."""
     index_path = os.path.join(output_path, "index.html")
+    metadata = _read_metadata(output_path)
     with open(index_path, "w") as index:
         index.write(
             """
@@ -75,6 +473,14 @@ def render_report(output_path: str, now: datetime) -> str:
 <p><code>{argv}</code><p>
 
 <h2>Profiling result</h2>
+{region}
+{tracker_budget}
+{drift}
+{anon_mmaps}
+{sampling_notice}
+{peak_trigger}
+{retained_by_caches}
+{temp_files}
 <div style="text-align: center;"><p><input type="button" onclick="fullScreen('#peak');" value="Full screen"> · <a href="peak-memory.svg" target="_blank"><button>Open in new window</button></a></p>
 <iframe id="peak" src="peak-memory.svg" width="100%" height="700" scrolling="auto" frameborder="0"></iframe>
 </div>
@@ -93,6 +499,20 @@ def render_report(output_path: str, now: datetime) -> str:
             <iframe id="peak-reversed" src="peak-memory-reversed.svg" width="100%" height="400" scrolling="auto" frameborder="0"></iframe><br>
 </div>
 
+{timeline}
+{mapped_files_graph}
+{frees_graph}
+{objects_graph}
+{allocation_rates_graph}
+<div class="center">
+{phases}
+{milestones}
+{windows}
+<h2>Allocator statistics</h2>
+{allocator_stats}
+{environment}
+</div>
+
 <div class="center">
 <blockquote><strong>Need help, or does something look wrong?</strong>
 <a href="https://pythonspeed.com/fil/docs/">Read the documentation</a>,
@@ -143,6 +563,24 @@ if you're using threads.</li>
                 now=now.ctime(),
                 argv=" ".join(map(shlex.quote, sys.argv)),
                 bugreport=DEBUGGING_INFO,
+                sampling_notice=_sampling_notice(metadata),
+                region=_region(metadata),
+                tracker_budget=_tracker_budget(metadata),
+                drift=_drift(metadata),
+                anon_mmaps=_anon_mmaps(metadata),
+                peak_trigger=_peak_trigger(metadata),
+                retained_by_caches=_retained_by_caches(metadata),
+                temp_files=_temp_files(metadata),
+                allocator_stats=_allocator_stats(metadata),
+                phases=_phases(metadata),
+                milestones=_milestones(output_path, metadata),
+                windows=_windows(output_path, metadata),
+                environment=_environment(metadata),
+                timeline=_timeline(output_path),
+                mapped_files_graph=_mapped_files_graph(output_path),
+                frees_graph=_frees_graph(output_path),
+                objects_graph=_objects_graph(output_path),
+                allocation_rates_graph=_allocation_rates_graph(output_path),
             )
         )
     return index_path
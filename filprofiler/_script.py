@@ -5,17 +5,20 @@ Command-line tools. Because of LD_PRELOAD, it's actually a two stage setup:
 2. Run the actual profiler CLI script.
 """
 
+import ast
+import importlib
+import importlib.util
 import json
 import sys
 import os
 from os import environ, execve, getpid, makedirs
 from os.path import abspath, dirname, join, exists
 from argparse import ArgumentParser, RawDescriptionHelpFormatter, REMAINDER
-from typing import List
+from typing import List, Optional
 import runpy
 import signal
 from shutil import which
-from ._utils import library_path, glibc_version, timestamp_now
+from ._utils import library_path, glibc_version, python_api_visible
 from ._cachegrind import benchmark
 from . import __version__, __file__
 
@@ -202,6 +205,15 @@ def stage_1():
     executable = sys.executable
 
     if sys.platform == "linux":
+        if not python_api_visible():
+            print(
+                "=fil-profile= Fil can't profile this Python, because its C API "
+                "isn't visible to shared libraries, as can happen with a "
+                "statically linked interpreter. Extension modules can't load "
+                "in it either, so use a dynamically linked Python instead.",
+                file=sys.stderr,
+            )
+            sys.exit(1)
         if glibc_version() >= (2, 30) and exists(LD_LINUX):
             # Launch with ld.so, which is more robust than relying on
             # environment variables.
@@ -216,6 +228,60 @@ def stage_1():
     execve(executable, [executable] + args, env=environ)
 
 
+def _top_level_imports(source_path: str) -> List[str]:
+    """
+    The absolute imports at the top level of the given Python file, or an
+    empty list if it can't be parsed.
+    """
+    try:
+        with open(source_path, "rb") as f:
+            tree = ast.parse(f.read(), source_path)
+    except (OSError, SyntaxError, ValueError):
+        return []
+    modules = []
+    for node in tree.body:
+        if isinstance(node, ast.Import):
+            modules.extend(alias.name for alias in node.names)
+        elif isinstance(node, ast.ImportFrom) and node.level == 0 and node.module:
+            modules.append(node.module)
+    return modules
+
+
+def _module_source_path(module: str) -> Optional[str]:
+    """The path of the module's source, if it can be found."""
+    try:
+        # Imports parent packages, which is fine since it's in the imports
+        # phase:
+        spec = importlib.util.find_spec(module)
+    except Exception:
+        return None
+    if spec is None:
+        return None
+    return spec.origin
+
+
+def _import_phase(source_path: Optional[str]):
+    """
+    Load the script's top-level imports up front in an ``imports`` phase, and
+    then switch to the ``main`` phase for the rest of the run.
+
+    This has to return before the script is run, so its frame doesn't end up
+    in the script's callstacks.
+    """
+    from ._tracer import mark_phase
+
+    mark_phase("imports")
+    if source_path is not None:
+        for module in _top_level_imports(source_path):
+            try:
+                importlib.import_module(module)
+            except Exception:
+                # The script's own import will raise the error again, at the
+                # same point it would have without Fil:
+                pass
+    mark_phase("main")
+
+
 def stage_2():
     """Main CLI interface for `fil-profile run`.
 
@@ -238,6 +304,7 @@ def stage_2():
         sys.argv = [module] + arguments.rest[2:]
         function = runpy.run_module
         func_args = (module,)
+        prepare = lambda: _import_phase(_module_source_path(module))
         func_kwargs = {"run_name": "__main__", "alter_sys": True}
     else:
         sys.argv = rest = arguments.rest
@@ -249,16 +316,19 @@ def stage_2():
         sys.path.insert(0, dirname(abspath(script)))
         function = runpy.run_path
         func_args = (script,)
+        prepare = lambda: _import_phase(script)
         func_kwargs = {"run_name": "__main__"}
 
     # Only import here since we don't want the parent process accessing any of
     # the _filpread.so code.
-    from ._tracer import trace_until_exit, create_report
+    from ._tracer import trace_until_exit, create_report, set_output_directory
 
+    set_output_directory(arguments.output_path)
     signal.signal(
         signal.SIGUSR2,
-        lambda *args: create_report(
-            os.path.join(arguments.output_path, timestamp_now())
+        lambda *args: print(
+            "=fil-profile= Wrote HTML report to " + create_report(),
+            file=sys.stderr,
         ),
     )
 
@@ -285,6 +355,7 @@ def stage_2():
         func_kwargs,
         arguments.output_path,
         not arguments.no_browser,
+        prepare,
     )
 
 
@@ -12,6 +12,12 @@ def get_allocations(
         "peak-memory-reversed.svg",
         "index.html",
         "peak-memory.prof",
+        "peak-functions.tsv",
+        "metadata.json",
+        "reallocs.json",
+        "reallocs.txt",
+        "threads.json",
+        "threads.txt",
     ],
     prof_file="peak-memory.prof",
     direct=False,
@@ -28,6 +34,9 @@ def get_allocations(
     result = {}
     with open(prof_path) as f:
         for line in f:
+            if line.startswith("# "):
+                # Environment header, see memapi/src/environment.rs:
+                continue
             *calls, size_kb = line.split(" ")
             calls = " ".join(calls)
             size_kb = int(int(size_kb) / 1024)
@@ -40,8 +49,12 @@ def get_allocations(
                 assert func_name[0] == "("
                 assert func_name[-1] == ")"
                 func_name = func_name[1:-1]
-                file_name, line = part1.split(":")
-                line = int(line)
+                if ":" in part1:
+                    file_name, line = part1.split(":")
+                    line = int(line)
+                else:
+                    # Line numbers were left out, with FIL_AGGREGATE_LINES=1:
+                    file_name, line = part1, None
                 path.append((file_name, func_name, line))
             if size_kb > 900:
                 result[tuple(path)] = size_kb
@@ -1,18 +1,29 @@
 """Trace code, so that libpymemprofile_api know's where we are."""
 
 import atexit
-from ctypes import PyDLL
-from datetime import datetime
+from ctypes import (
+    PyDLL,
+    Structure,
+    byref,
+    c_char_p,
+    c_size_t,
+    c_uint32,
+    c_uint64,
+    c_void_p,
+    create_string_buffer,
+    string_at,
+)
 import os
 import sys
 import threading
+import weakref
 import webbrowser
 from contextlib import contextmanager
 from pathlib import Path
-from typing import Union
+from typing import List, Optional, Tuple, Union
 import traceback
 
-from ._utils import timestamp_now, library_path
+from ._utils import timestamp_now, library_path, report_time
 from ._report import render_report
 
 
@@ -42,7 +53,10 @@ try:
     else:
         # macOS.
         preload = PyDLL(library_path("_filpreload"))
+    preload.fil_interposition_failure.restype = c_char_p
+    interposition_failure = preload.fil_interposition_failure()
     preload.fil_initialize_from_python()
+    preload.fil_set_python_version(sys.version.encode("utf-8"))
 except Exception as e:
     raise RuntimeError(
         f"""\
@@ -61,6 +75,15 @@ Full trackback:
 """
     )
 
+# Interposition was disabled at startup, e.g. because we were loaded with
+# dlmopen(), so profiling would silently record nothing:
+if interposition_failure is not None:
+    raise RuntimeError(
+        "Fil can't track memory in this process, because "
+        f"{interposition_failure.decode('utf-8')}. To profile it anyway, use the "
+        "filprofiler._filprofiler_api module, which doesn't need LD_PRELOAD."
+    )
+
 
 def start_tracing(output_path: Union[str, Path]):
     """Start tracing allocations."""
@@ -98,15 +121,209 @@ def stop_tracing(output_path: str) -> str:
     return result
 
 
-def create_report(output_path: Union[str, Path]) -> str:
-    preload.fil_dump_peak_to_flamegraph(str(output_path).encode("utf-8"))
-    now = datetime.now()
-    return render_report(output_path, now)
+def get_traced_memory() -> Tuple[int, int]:
+    """Return (current, peak) tracked memory in bytes, as a consistent pair."""
+    current = c_uint64()
+    peak = c_uint64()
+    if preload.fil_get_traced_memory(byref(current), byref(peak)) != 0:
+        raise RuntimeError("Failed to get traced memory")
+    return current.value, peak.value
+
+
+def set_free_tracking(enabled: bool):
+    """Turn recording of the callstacks that free memory on or off."""
+    preload.fil_set_free_tracking(1 if enabled else 0)
 
 
-def trace_until_exit(function, args, kwargs, output_path: str, open_browser: bool):
+def self_check() -> int:
+    """
+    Check the profiler's internal bookkeeping is consistent, returning the
+    number of problems found (which are also printed to stderr). For testing.
+    """
+    return preload.fil_self_check()
+
+
+class _AllocationInfo(Structure):
+    _fields_ = [("address", c_size_t), ("size", c_size_t)]
+
+
+def find_allocations_by_function(
+    file_name: str, function_name: str, max_results: int
+) -> Tuple[List[Tuple[int, int]], int]:
+    """
+    Return up to max_results (address, size) pairs of live allocations whose
+    callstack includes the given function in the given file, and the total
+    number found.
+    """
+    results = (_AllocationInfo * max_results)()
+    preload.fil_find_allocations_by_function.restype = c_size_t
+    total = preload.fil_find_allocations_by_function(
+        file_name.encode("utf-8"),
+        function_name.encode("utf-8"),
+        results,
+        c_size_t(max_results),
+    )
+    found = [(info.address, info.size) for info in results[: min(total, max_results)]]
+    return found, total
+
+
+def dump_function_detail(file_name: str, function_name: str, path: Union[str, Path]):
+    """
+    Write a report of how much of the peak each line of the given function in
+    the given file is responsible for to the given directory.
+    """
+    if (
+        preload.fil_dump_function_detail(
+            file_name.encode("utf-8"),
+            function_name.encode("utf-8"),
+            str(path).encode("utf-8"),
+        )
+        != 0
+    ):
+        raise RuntimeError(
+            f"Failed to write the per-line report for {function_name} in {file_name}"
+        )
+
+
+def dump_survivors(path: Union[str, Path]):
+    """
+    Write a report of the callstacks whose memory survived at least
+    FIL_SURVIVORS resets to the given directory.
+    """
+    if preload.fil_dump_survivors(str(path).encode("utf-8")) != 0:
+        raise RuntimeError(f"Failed to write the survivors report to {path}")
+
+
+def dump_peak_to_arrow(path: Union[str, Path]):
+    """Write the peak snapshot as an Arrow IPC file to the given path."""
+    if preload.fil_dump_peak_to_arrow(str(path).encode("utf-8")) != 0:
+        raise RuntimeError(f"Failed to write the Arrow table to {path}")
+
+
+def set_output_directory(path: Union[str, Path]):
+    """Set where reports without an explicit path get written."""
+    preload.fil_set_output_directory(str(path).encode("utf-8"))
+
+
+def add_metadata(key: str, value: str):
+    """Attach a tag to the report's environment."""
+    preload.fil_add_metadata(key.encode("utf-8"), value.encode("utf-8"))
+
+
+def mark_phase(name: str):
+    """Start a new named phase; later allocations are tagged with it."""
+    preload.fil_mark_phase(name.encode("utf-8"))
+
+
+def push_phase(name: str):
+    """Push a phase frame for the current thread."""
+    preload.fil_push_phase(name.encode("utf-8"))
+
+
+def pop_phase():
+    """Pop the current thread's innermost phase frame."""
+    preload.fil_pop_phase()
+
+
+def _remove_object(address: int):
+    preload.fil_remove_object(c_size_t(address))
+
+
+def track_object(obj, skip_frames: int):
+    """
+    Count the object as live until it's garbage collected, attributed to the
+    current callstack minus the innermost ``skip_frames`` Python frames (which
+    should include this function's).
+    """
+    type_ = type(obj)
+    type_name = "{}.{}".format(type_.__module__, type_.__qualname__)
+    finalizer = weakref.finalize(obj, _remove_object, id(obj))
+    # No point in reporting objects being destroyed at exit:
+    finalizer.atexit = False
+    preload.fil_add_object(
+        c_size_t(id(obj)), type_name.encode("utf-8"), c_uint32(skip_frames)
+    )
+
+
+# Returned by dumps when the output sink registered from native code with
+# fil_register_output_sink() failed; the report is still on disk:
+_OUTPUT_SINK_FAILED = -3
+
+
+def create_report(output_path: Optional[Union[str, Path]] = None) -> str:
+    """
+    Write out a report to the given directory, or if it's None to a new
+    automatically-named directory in the output directory.
+
+    Returns path to the index HTML page of the report.
+    """
+    # Plenty of room for any reasonable path:
+    path_out = create_string_buffer(4096)
+    length = preload.fil_dump_peak_to_flamegraph(
+        None if output_path is None else str(output_path).encode("utf-8"),
+        path_out,
+        len(path_out),
+    )
+    if length == _OUTPUT_SINK_FAILED:
+        raise RuntimeError("The report was written, but the output sink failed")
+    if length < 0 or length >= len(path_out):
+        raise RuntimeError("Failed to write the report")
+    return render_report(path_out.value.decode("utf-8"), report_time())
+
+
+def start_region(group_pre_existing: bool):
+    """Start profiling a region, raising RuntimeError if one already is."""
+    if preload.fil_region_start(1 if group_pre_existing else 0) != 0:
+        raise RuntimeError("A region is already being profiled")
+
+
+def end_region(output_path: Optional[Union[str, Path]] = None) -> str:
+    """
+    Finish profiling the region, and write out its report like
+    create_report().
+
+    Returns path to the index HTML page of the report.
+    """
+    path_out = create_string_buffer(4096)
+    length = preload.fil_region_end(
+        None if output_path is None else str(output_path).encode("utf-8"),
+        path_out,
+        len(path_out),
+    )
+    if length == _OUTPUT_SINK_FAILED:
+        raise RuntimeError(
+            "The region's report was written, but the output sink failed"
+        )
+    if length < 0 or length >= len(path_out):
+        raise RuntimeError("Failed to write the region's report")
+    return render_report(path_out.value.decode("utf-8"), report_time())
+
+
+def render_peak_svg(max_bytes: int = 0) -> Optional[str]:
+    """
+    Render the peak memory flamegraph as an SVG document, without writing
+    anything to disk. If max_bytes isn't 0 and the full SVG would be bigger, a
+    pruned version is returned instead.
+
+    Returns None if rendering failed, e.g. because nothing was allocated.
+    """
+    preload.fil_render_peak_svg.restype = c_void_p
+    address = preload.fil_render_peak_svg(c_size_t(max_bytes))
+    if not address:
+        return None
+    try:
+        return string_at(address).decode("utf-8")
+    finally:
+        preload.fil_free_string(c_void_p(address))
+
+
+def trace_until_exit(
+    function, args, kwargs, output_path: str, open_browser: bool, prepare=None
+):
     """
     Given function, run it under the tracer until the program exits.
+
+    If given, ``prepare()`` is called under the tracer before the function.
     """
 
     def shutdown():
@@ -138,6 +355,8 @@ def trace_until_exit(function, args, kwargs, output_path: str, open_browser: boo
     atexit.register(shutdown)
     with disable_thread_pools():
         start_tracing(os.path.join(output_path, timestamp_now()))
+        if prepare is not None:
+            prepare()
         function(*args, **kwargs)
 
 
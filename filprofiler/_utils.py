@@ -1,8 +1,9 @@
 """Utilities."""
 
 from importlib.util import find_spec
-from datetime import datetime
+from datetime import datetime, timezone
 import ctypes
+import os
 from typing import Tuple
 
 
@@ -17,6 +18,20 @@ def timestamp_now() -> str:
     return now.isoformat(timespec="milliseconds").replace(":", "-").replace(".", "_")
 
 
+def report_time() -> datetime:
+    """
+    Return the time to show in reports: SOURCE_DATE_EPOCH if it's set, else the
+    epoch with FIL_DETERMINISTIC=1, so reports can be reproducible, otherwise
+    the current time.
+    """
+    epoch = os.environ.get("SOURCE_DATE_EPOCH", "")
+    if epoch.isdigit():
+        return datetime.fromtimestamp(int(epoch), tz=timezone.utc)
+    if os.environ.get("FIL_DETERMINISTIC") == "1":
+        return datetime.fromtimestamp(0, tz=timezone.utc)
+    return datetime.now()
+
+
 def glibc_version() -> Tuple[int, int]:
     """Get the version of glibc."""
     libc = ctypes.CDLL("libc.so.6")
@@ -32,3 +47,18 @@ def _parse_glibc_version(version: bytes) -> Tuple[int, int]:
         # For unparseable versions, just assume it's old, and fallback to
         # LD_PRELOAD which always works.
         return (1, 1)
+
+
+def python_api_visible() -> bool:
+    """
+    Whether the interpreter's C API is visible to shared libraries. A
+    statically linked interpreter might not export it, in which case the Fil
+    shared library can't be loaded at all.
+    """
+    if os.environ.get("__FIL_SIMULATE_BOOTSTRAP_FAILURE") == "python_symbols":
+        return False
+    try:
+        ctypes.CDLL(None).PyEval_SetProfile
+    except AttributeError:
+        return False
+    return True
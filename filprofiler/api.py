@@ -14,7 +14,9 @@ instead of:
 # if Fil won't work. As such, all imports of ._tracer should not happen at
 # module level.
 
-from typing import Union, Callable, TypeVar
+from contextlib import contextmanager
+import functools
+from typing import Union, Callable, Iterator, List, Tuple, TypeVar
 from pathlib import Path
 
 _T = TypeVar("_T")
@@ -45,4 +47,253 @@ def profile(code_to_profile: Callable[[], _T], path: Union[str, Path]) -> _T:
             stop_tracing(path)
 
 
-__all__ = ["profile"]
+@contextmanager
+def profile_region(
+    path: Union[str, Path, None] = None, group_pre_existing: bool = False
+) -> Iterator[None]:
+    """
+    Context manager that writes a report of just the memory allocated inside
+    the ``with`` block to the given path, or to a new directory in the output
+    directory if it's ``None``.
+
+    The report's peak is of memory allocated inside the block that was still
+    live, so memory from before the block doesn't count, even if the block
+    frees it. With ``group_pre_existing=True`` the memory that was already
+    live is shown as a single ``[pre-existing]`` frame instead of being left
+    out. This can be used repeatedly, but regions can't be nested:
+    ``RuntimeError`` is raised if a region is already being profiled.
+
+    Memory is only tracked while Fil is tracing, e.g. when running under
+    ``fil-profile run``.
+    """
+    from ._tracer import check_if_fil_preloaded, start_region, end_region
+
+    check_if_fil_preloaded()
+    start_region(group_pre_existing)
+    try:
+        yield
+    finally:
+        end_region(path)
+
+
+def get_traced_memory() -> Tuple[int, int]:
+    """
+    Return the current and peak tracked memory in bytes, like
+    ``tracemalloc.get_traced_memory()``.
+
+    Both values come from the same snapshot, so current is never more than
+    peak.
+    """
+    from ._tracer import (
+        check_if_fil_preloaded,
+        get_traced_memory as _get_traced_memory,
+    )
+
+    check_if_fil_preloaded()
+    return _get_traced_memory()
+
+
+def find_allocations_by_function(
+    file_name: str, function_name: str, max_results: int = 1000
+) -> Tuple[List[Tuple[int, int]], int]:
+    """
+    Find live allocations whose callstack includes a call to the given
+    function in the given file, as they're shown in the report, e.g.
+    ``find_allocations_by_function("example.py", "load")``. Methods can also
+    be given by their own name, e.g. ``"load"`` for ``"Loader.load"``.
+
+    Returns a list of up to ``max_results`` ``(address, size)`` pairs, and
+    the total number of matching allocations, which may be bigger. This scans
+    every live allocation while blocking all other allocations, so it's slow;
+    it's meant for debugging, e.g. to inspect the memory with gdb.
+    """
+    from ._tracer import (
+        check_if_fil_preloaded,
+        find_allocations_by_function as _find_allocations_by_function,
+    )
+
+    check_if_fil_preloaded()
+    return _find_allocations_by_function(file_name, function_name, max_results)
+
+
+def dump_function_detail(file_name: str, function_name: str, path: Union[str, Path]):
+    """
+    Write a report of which lines of the given function in the given file, as
+    they're shown in the report, are responsible for how much of the peak, e.g.
+    ``dump_function_detail("example.py", "load", "load-detail")``. As with
+    ``find_allocations_by_function()``, methods can be given by their own name.
+
+    The directory will contain ``function-detail.txt`` and
+    ``function-detail.json``. Raises ``RuntimeError`` if none of the memory at
+    peak was allocated with the function in the callstack.
+    """
+    from ._tracer import (
+        check_if_fil_preloaded,
+        dump_function_detail as _dump_function_detail,
+    )
+
+    check_if_fil_preloaded()
+    _dump_function_detail(file_name, function_name, path)
+
+
+def dump_survivors(path: Union[str, Path]):
+    """
+    Write a report of memory that survived ``reset()``, e.g. between calls to
+    ``profile()``, to the given directory: the callstacks with memory that
+    survived at least as many resets as the ``FIL_SURVIVORS`` environment
+    variable says, with bytes and counts per generation, in
+    ``survivors.txt`` and ``survivors.json``.
+
+    Raises ``RuntimeError`` if ``FIL_SURVIVORS`` isn't set, or the report
+    can't be written.
+    """
+    from ._tracer import (
+        check_if_fil_preloaded,
+        dump_survivors as _dump_survivors,
+    )
+
+    check_if_fil_preloaded()
+    _dump_survivors(path)
+
+
+def dump_peak_to_arrow(path: Union[str, Path]):
+    """
+    Write the peak memory snapshot to the given path as an Arrow IPC file, one
+    row per callstack, e.g. for loading with ``pyarrow.ipc.open_file()`` or
+    ``polars.read_ipc()``.
+
+    Fil must have been built with the ``arrow-export`` feature; otherwise, or
+    if the file can't be written, ``RuntimeError`` is raised.
+    """
+    from ._tracer import (
+        check_if_fil_preloaded,
+        dump_peak_to_arrow as _dump_peak_to_arrow,
+    )
+
+    check_if_fil_preloaded()
+    _dump_peak_to_arrow(path)
+
+
+def set_free_tracking(enabled: bool):
+    """
+    Turn on or off recording of the callstack that frees each allocation.
+
+    When enabled, the report will include ``frees.svg``, a flamegraph of
+    bytes freed by each freeing callstack, and ``frees-pairs.txt``, listing
+    which allocation sites were freed from where. This makes every ``free()``
+    more expensive, so it's off by default; you can also enable it from the
+    start by setting ``FIL_TRACK_FREES=1``.
+    """
+    from ._tracer import (
+        check_if_fil_preloaded,
+        set_free_tracking as _set_free_tracking,
+    )
+
+    check_if_fil_preloaded()
+    _set_free_tracking(enabled)
+
+
+def add_metadata(key: str, value: str):
+    """
+    Attach a tag to the reports, e.g. ``add_metadata("git_commit", sha)``.
+
+    Tags are included with the details Fil records about the run, like the
+    Python version and command line, so months later you can tell what
+    produced a report. Adding a key again replaces its value.
+    """
+    from ._tracer import check_if_fil_preloaded, add_metadata as _add_metadata
+
+    check_if_fil_preloaded()
+    _add_metadata(str(key), str(value))
+
+
+def mark_phase(name: str):
+    """
+    Start a new named phase of the program, e.g. ``"loading"``.
+
+    Allocations are tagged with the phase that was active when they happened,
+    and the report will summarize peak memory by phase. Marking a phase name
+    that was used before switches back to it.
+
+    ``fil-profile run`` already marks an ``imports`` phase while the script's
+    top-level imports are loaded, and a ``main`` phase for the rest of the run.
+    """
+    from ._tracer import check_if_fil_preloaded, mark_phase as _mark_phase
+
+    check_if_fil_preloaded()
+    _mark_phase(name)
+
+
+@contextmanager
+def phase(name: str) -> Iterator[None]:
+    """
+    Context manager that annotates a region of code, e.g. ``"load data"``.
+
+    Allocations made by the current thread inside the ``with`` block show up
+    under a ``[phase: load data]`` frame at the root of the flamegraph. Phases
+    can be nested, and if the peak is reached inside a phase the report says
+    which one.
+    """
+    from ._tracer import check_if_fil_preloaded, push_phase, pop_phase
+
+    check_if_fil_preloaded()
+    push_phase(name)
+    try:
+        yield
+    finally:
+        pop_phase()
+
+
+def track_object(obj):
+    """
+    Count the object as live until it's garbage collected, attributed to the
+    callstack calling this function.
+
+    The report will then include ``objects.svg``, a flamegraph weighted by the
+    number of live objects rather than bytes, and ``objects.json``, breaking
+    down live objects by type and callstack. This is tracked separately from
+    memory, so it doesn't change the memory numbers. The object must support
+    weak references.
+    """
+    from ._tracer import check_if_fil_preloaded, track_object as _track_object
+
+    check_if_fil_preloaded()
+    # Skip this function's frame and _track_object's:
+    _track_object(obj, 2)
+
+
+def track_instances(cls):
+    """
+    Class decorator that calls ``track_object()`` on every new instance, so the
+    report shows where live instances were created.
+    """
+    from ._tracer import check_if_fil_preloaded, track_object as _track_object
+
+    check_if_fil_preloaded()
+    original_init = cls.__init__
+
+    @functools.wraps(original_init)
+    def __init__(self, *args, **kwargs):
+        original_init(self, *args, **kwargs)
+        # Skip this wrapper's frame and _track_object's:
+        _track_object(self, 2)
+
+    cls.__init__ = __init__
+    return cls
+
+
+__all__ = [
+    "profile",
+    "profile_region",
+    "get_traced_memory",
+    "find_allocations_by_function",
+    "dump_function_detail",
+    "dump_survivors",
+    "dump_peak_to_arrow",
+    "set_free_tracking",
+    "add_metadata",
+    "mark_phase",
+    "phase",
+    "track_object",
+    "track_instances",
+]
@@ -0,0 +1,109 @@
+/* Fil's API for native code, e.g. extensions with their own memory arenas, to
+ * report memory Fil can't see on its own.
+ *
+ * Copy this header into your project; it doesn't need Fil to build or run.
+ * Call fil_api_load() once, and then call the functions in the struct it
+ * fills in. If the program isn't running under Fil they do nothing.
+ *
+ *     static fil_api fil;
+ *     fil_api_load(&fil);
+ *     ...
+ *     fil.push_context("load_table");
+ *     void *block = arena_alloc(arena, size);
+ *     fil.report_allocation("myextension", block, size);
+ *     fil.pop_context();
+ *     ...
+ *     arena_free(arena, block);
+ *     fil.report_free("myextension", block);
+ *
+ * Reported memory shows up in Fil's reports under the callstack that reported
+ * it, with a "[domain: myextension]" leaf frame. Each domain has its own
+ * addresses, so they only need to be unique within it. Only report memory Fil
+ * doesn't already see: if the arena got its memory from malloc() or mmap(),
+ * Fil has already counted it.
+ *
+ * Contexts show up as "[context: <name>]" frames in the current thread's
+ * callstack, until they're popped. Pop them before returning to Python.
+ *
+ * Strings are copied, so they don't need to outlive the call.
+ */
+#ifndef FIL_H
+#define FIL_H
+
+#include <dlfcn.h>
+#include <stddef.h>
+
+#ifdef __cplusplus
+extern "C" {
+#endif
+
+/* The version of the API described here, as returned by fil_api_version(). */
+#define FIL_API_VERSION 1
+
+typedef struct fil_api {
+  /* 0 if not running under Fil. */
+  int version;
+  void (*report_allocation)(const char *domain, void *ptr, size_t size);
+  void (*report_free)(const char *domain, void *ptr);
+  void (*push_context)(const char *name);
+  void (*pop_context)(void);
+} fil_api;
+
+static void fil_noop_report_allocation(const char *domain, void *ptr,
+                                       size_t size) {
+  (void)domain;
+  (void)ptr;
+  (void)size;
+}
+
+static void fil_noop_report_free(const char *domain, void *ptr) {
+  (void)domain;
+  (void)ptr;
+}
+
+static void fil_noop_push_context(const char *name) { (void)name; }
+
+static void fil_noop_pop_context(void) {}
+
+static inline int fil_api_load_noops(fil_api *api) {
+  api->version = 0;
+  api->report_allocation = fil_noop_report_allocation;
+  api->report_free = fil_noop_report_free;
+  api->push_context = fil_noop_push_context;
+  api->pop_context = fil_noop_pop_context;
+  return 0;
+}
+
+/* Look up Fil's functions, falling back to ones that do nothing if it isn't
+ * loaded or its API is an incompatible version. Returns the version, or 0 in
+ * that case. */
+static inline int fil_api_load(fil_api *api) {
+  /* The program itself, and everything loaded globally, including Fil: */
+  void *program = dlopen(NULL, RTLD_LAZY);
+  if (program == NULL) {
+    return fil_api_load_noops(api);
+  }
+  int (*version)(void) = (int (*)(void))dlsym(program, "fil_api_version");
+  if (version == NULL || version() != FIL_API_VERSION) {
+    return fil_api_load_noops(api);
+  }
+  api->report_allocation = (void (*)(const char *, void *, size_t))dlsym(
+      program, "fil_report_allocation");
+  api->report_free =
+      (void (*)(const char *, void *))dlsym(program, "fil_report_free");
+  api->push_context =
+      (void (*)(const char *))dlsym(program, "fil_push_context");
+  api->pop_context = (void (*)(void))dlsym(program, "fil_pop_context");
+  if (api->report_allocation == NULL || api->report_free == NULL ||
+      api->push_context == NULL || api->pop_context == NULL) {
+    return fil_api_load_noops(api);
+  }
+  api->version = FIL_API_VERSION;
+  return api->version;
+}
+
+#ifdef __cplusplus
+}
+#endif
+
+#endif /* FIL_H */
@@ -1,8 +1,9 @@
 """Tests for filprofiler._utils."""
 
 import os
+from datetime import datetime, timezone
 
-from .._utils import library_path
+from .._utils import library_path, python_api_visible, report_time
 
 
 def test_library_path():
@@ -10,3 +11,25 @@ def test_library_path():
     path = library_path("_filpreload")
     assert os.path.exists(path)
     assert path.endswith(".so")
+
+
+def test_report_time(monkeypatch):
+    """SOURCE_DATE_EPOCH, or FIL_DETERMINISTIC, fixes the report time."""
+    monkeypatch.delenv("SOURCE_DATE_EPOCH", raising=False)
+    monkeypatch.delenv("FIL_DETERMINISTIC", raising=False)
+    assert abs((datetime.now() - report_time()).total_seconds()) < 60
+    monkeypatch.setenv("FIL_DETERMINISTIC", "1")
+    assert report_time() == datetime(1970, 1, 1, tzinfo=timezone.utc)
+    monkeypatch.setenv("SOURCE_DATE_EPOCH", "1700000000")
+    assert report_time() == datetime(2023, 11, 14, 22, 13, 20, tzinfo=timezone.utc)
+
+
+def test_python_api_visible(monkeypatch):
+    """
+    The interpreter running the tests exports its C API, and the check can be
+    made to fail for testing.
+    """
+    monkeypatch.delenv("__FIL_SIMULATE_BOOTSTRAP_FAILURE", raising=False)
+    assert python_api_visible()
+    monkeypatch.setenv("__FIL_SIMULATE_BOOTSTRAP_FAILURE", "python_symbols")
+    assert not python_api_visible()
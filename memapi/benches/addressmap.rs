@@ -0,0 +1,88 @@
+//! Compare AddressMap to the HashMap it replaced for tracking live
+//! allocations, on insert/remove-heavy workloads.
+
+use ahash::RandomState as ARandomState;
+use criterion::{criterion_group, criterion_main, Criterion};
+use pymemprofile_api::addressmap::AddressMap;
+use std::collections::HashMap;
+
+/// The operations both maps need to support.
+trait LiveAllocations: Default {
+    fn insert(&mut self, address: usize, value: u64);
+    fn remove(&mut self, address: usize) -> Option<u64>;
+}
+
+impl LiveAllocations for HashMap<usize, u64, ARandomState> {
+    fn insert(&mut self, address: usize, value: u64) {
+        HashMap::insert(self, address, value);
+    }
+
+    fn remove(&mut self, address: usize) -> Option<u64> {
+        HashMap::remove(self, &address)
+    }
+}
+
+impl LiveAllocations for AddressMap<u64> {
+    fn insert(&mut self, address: usize, value: u64) {
+        AddressMap::insert(self, address, value);
+    }
+
+    fn remove(&mut self, address: usize) -> Option<u64> {
+        AddressMap::remove(self, address)
+    }
+}
+
+/// Addresses like a bump allocator would hand out: 16-byte aligned, mostly
+/// increasing.
+fn addresses(count: usize) -> Vec<usize> {
+    (0..count).map(|i| 0x7f00_0000_0000 + i * 48).collect()
+}
+
+/// Allocate everything, then free in reverse order.
+fn grow_then_free<M: LiveAllocations>(addresses: &[usize]) -> M {
+    let mut map = M::default();
+    for (i, address) in addresses.iter().enumerate() {
+        map.insert(*address, i as u64);
+    }
+    for address in addresses.iter().rev() {
+        map.remove(*address);
+    }
+    map
+}
+
+/// Keep a window of live allocations, freeing the oldest as new ones come in;
+/// the map stays the same size, so this is mostly churn.
+fn churn<M: LiveAllocations>(addresses: &[usize], live: usize) -> M {
+    let mut map = M::default();
+    for (i, address) in addresses.iter().enumerate() {
+        map.insert(*address, i as u64);
+        if i >= live {
+            map.remove(addresses[i - live]);
+        }
+    }
+    map
+}
+
+fn bench_maps(c: &mut Criterion) {
+    let addresses = addresses(100_000);
+    let mut group = c.benchmark_group("grow_then_free");
+    group.bench_function("HashMap", |b| {
+        b.iter(|| grow_then_free::<HashMap<usize, u64, ARandomState>>(&addresses))
+    });
+    group.bench_function("AddressMap", |b| {
+        b.iter(|| grow_then_free::<AddressMap<u64>>(&addresses))
+    });
+    group.finish();
+
+    let mut group = c.benchmark_group("churn");
+    group.bench_function("HashMap", |b| {
+        b.iter(|| churn::<HashMap<usize, u64, ARandomState>>(&addresses, 1000))
+    });
+    group.bench_function("AddressMap", |b| {
+        b.iter(|| churn::<AddressMap<u64>>(&addresses, 1000))
+    });
+    group.finish();
+}
+
+criterion_group!(benches, bench_maps);
+criterion_main!(benches);
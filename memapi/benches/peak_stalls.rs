@@ -0,0 +1,71 @@
+//! Worst-case latency of individual tracked allocations while memory keeps
+//! climbing to millions of live allocations, i.e. while a new peak is reached
+//! on almost every operation. Average throughput is what the `addressmap`
+//! benchmark measures; this is about stalls, so it times each operation and
+//! reports the slowest ones.
+//!
+//! Run with `cargo bench --bench peak_stalls`.
+
+use pymemprofile_api::memorytracking::{
+    AllocationTracker, CallSiteId, Callstack, LineNumberInfo, VecFunctionLocations, PARENT_PROCESS,
+};
+use std::time::{Duration, Instant};
+
+const LIVE_ALLOCATIONS: usize = 5_000_000;
+const CALLSTACKS: usize = 10_000;
+
+fn main() {
+    // Keep the adaptive tracking from kicking in, so every operation is tracked
+    // the same way:
+    std::env::set_var("FIL_ADAPTIVE_HIGH_WATER", "0");
+    let mut tracker = AllocationTracker::new("/tmp".into(), VecFunctionLocations::new());
+    let function = tracker
+        .functions
+        .add_function("bench.py".into(), "climb".into());
+    let callstacks: Vec<_> = (0..CALLSTACKS)
+        .map(|i| {
+            tracker.get_callstack_id(&Callstack::from_vec(vec![CallSiteId::new(
+                function,
+                LineNumberInfo::LineNumber(i as u32),
+            )]))
+        })
+        .collect();
+
+    let mut latencies = Vec::with_capacity(LIVE_ALLOCATIONS);
+    let start = Instant::now();
+    let mut address = 0x7f00_0000_0000_usize;
+    for i in 0..LIVE_ALLOCATIONS {
+        let operation_start = Instant::now();
+        tracker.add_allocation(PARENT_PROCESS, address, 100, callstacks[i % CALLSTACKS]);
+        // Some churn along the way, reallocating the previous allocation from
+        // a different callstack:
+        if i % 2 == 0 {
+            tracker.free_allocation(PARENT_PROCESS, address);
+            tracker.add_allocation(
+                PARENT_PROCESS,
+                address,
+                100,
+                callstacks[(i * 7) % CALLSTACKS],
+            );
+        }
+        latencies.push(operation_start.elapsed());
+        address += 128;
+    }
+    let total = start.elapsed();
+
+    latencies.sort_unstable();
+    let percentile = |p: f64| latencies[((latencies.len() - 1) as f64 * p) as usize];
+    let over = |limit: Duration| latencies.iter().filter(|l| **l > limit).count();
+    println!("{} live allocations in {:?}", LIVE_ALLOCATIONS, total);
+    println!(
+        "latency: p50 {:?}, p99.99 {:?}, worst {:?}",
+        percentile(0.5),
+        percentile(0.9999),
+        latencies[latencies.len() - 1]
+    );
+    println!(
+        "operations over 100µs: {}, over 1ms: {}",
+        over(Duration::from_micros(100)),
+        over(Duration::from_millis(1))
+    );
+}
@@ -0,0 +1,27 @@
+//! The tracker's overhead on representative workloads, see
+//! pymemprofile_api::workloads; `fil-bench` runs the same ones for a fixed
+//! time instead.
+//!
+//! Run with `cargo bench -p pymemprofile_api --features bench --bench tracker`.
+
+use criterion::{criterion_group, criterion_main, Criterion, Throughput};
+use pymemprofile_api::workloads::{Bench, Workload};
+
+fn bench_workloads(c: &mut Criterion) {
+    pyo3::prepare_freethreaded_python();
+    let directory = tempfile::tempdir().unwrap();
+    let mut group = c.benchmark_group("tracker");
+    for workload in Workload::ALL {
+        let bench = Bench::new(workload, directory.path());
+        if workload == Workload::Dump {
+            // Each dump takes seconds:
+            group.sample_size(10);
+        }
+        group.throughput(Throughput::Elements(bench.run_round()));
+        group.bench_function(workload.name(), |b| b.iter(|| bench.run_round()));
+    }
+    group.finish();
+}
+
+criterion_group!(benches, bench_workloads);
+criterion_main!(benches);
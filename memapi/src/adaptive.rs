@@ -0,0 +1,224 @@
+//! Adaptive sampling of small allocations.
+//!
+//! Tracking every allocation exactly gets expensive, in both memory and CPU,
+//! when there are millions of live allocations. So once the number of live
+//! tracked allocations crosses a high-water mark, new small allocations are
+//! sampled: only every Nth one is recorded, with its size scaled up by N. Once
+//! the count drops below a low-water mark we go back to exact tracking.
+//!
+//! Each tracked allocation remembers its own (possibly scaled) size, so frees
+//! remain correct regardless of which mode an allocation was recorded in.
+
+use serde::Serialize;
+use std::time::Instant;
+
+/// Allocations this size or smaller get sampled when sampling is engaged.
+pub const SMALL_ALLOCATION_BYTES: usize = 1024;
+
+/// When sampling is engaged, record one in this many small allocations.
+pub const SAMPLE_EVERY: usize = 16;
+
+/// Default number of live allocations at which sampling is engaged.
+pub const DEFAULT_HIGH_WATER: usize = 5_000_000;
+
+/// A point where sampling was switched on or off.
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct SamplingTransition {
+    /// Whether sampling was engaged (true) or disengaged (false).
+    pub engaged: bool,
+    /// Seconds since tracking started.
+    pub seconds: f64,
+    /// Number of live tracked allocations at the time.
+    pub live_allocations: usize,
+}
+
+/// Decides whether allocations should be recorded exactly or sampled.
+pub struct AdaptiveSampling {
+    // 0 means adaptive sampling is disabled.
+    high_water: usize,
+    low_water: usize,
+    engaged: bool,
+    // Counts small allocations seen while engaged, to pick every Nth:
+    small_allocations_seen: usize,
+    started: Instant,
+    transitions: Vec<SamplingTransition>,
+    printed_notice: bool,
+    // Engaged for good, regardless of the number of live allocations:
+    forced: bool,
+}
+
+impl AdaptiveSampling {
+    /// Sampling engages above high_water live allocations, and disengages
+    /// below low_water. A high_water of 0 disables sampling.
+    pub fn new(high_water: usize, low_water: usize) -> Self {
+        Self {
+            high_water,
+            low_water: low_water.min(high_water),
+            engaged: false,
+            small_allocations_seen: 0,
+            started: Instant::now(),
+            transitions: vec![],
+            printed_notice: false,
+            forced: false,
+        }
+    }
+
+    /// Configure from FIL_ADAPTIVE_HIGH_WATER (0 disables) and
+    /// FIL_ADAPTIVE_LOW_WATER (defaults to 80% of the high-water mark).
+    pub fn from_env() -> Self {
+        let read = |name: &str| {
+            std::env::var(name)
+                .ok()
+                .and_then(|value| value.parse::<usize>().ok())
+        };
+        let high_water = read("FIL_ADAPTIVE_HIGH_WATER").unwrap_or(DEFAULT_HIGH_WATER);
+        let low_water = read("FIL_ADAPTIVE_LOW_WATER").unwrap_or(high_water / 5 * 4);
+        Self::new(high_water, low_water)
+    }
+
+    /// Given a new allocation's size, return the size to record it with, or
+    /// None if it shouldn't be recorded at all.
+    #[inline]
+    pub fn size_to_record(&mut self, size: usize) -> Option<usize> {
+        if !self.engaged || size > SMALL_ALLOCATION_BYTES {
+            return Some(size);
+        }
+        self.small_allocations_seen += 1;
+        if self.small_allocations_seen.is_multiple_of(SAMPLE_EVERY) {
+            Some(size * SAMPLE_EVERY)
+        } else {
+            None
+        }
+    }
+
+    /// Update the mode given the current number of live allocations.
+    #[inline]
+    pub fn update(&mut self, live_allocations: usize) {
+        if self.high_water == 0 || self.forced {
+            return;
+        }
+        if !self.engaged && live_allocations >= self.high_water {
+            self.transition(true, live_allocations);
+            if !self.printed_notice {
+                self.printed_notice = true;
+                eprintln!(
+                    "=fil-profile= NOTICE: Over {} live allocations, so 1 in {} allocations of {} bytes or less will be sampled until this drops below {}; affected parts of the profile are estimates.",
+                    self.high_water, SAMPLE_EVERY, SMALL_ALLOCATION_BYTES, self.low_water
+                );
+            }
+        } else if self.engaged && live_allocations < self.low_water {
+            self.transition(false, live_allocations);
+        }
+    }
+
+    fn transition(&mut self, engaged: bool, live_allocations: usize) {
+        self.engaged = engaged;
+        self.transitions.push(SamplingTransition {
+            engaged,
+            seconds: self.started.elapsed().as_secs_f64(),
+            live_allocations,
+        });
+    }
+
+    /// Engage sampling from now on, no matter how many live allocations there
+    /// are, e.g. because the tracker is using too much memory.
+    pub fn force(&mut self, live_allocations: usize) {
+        self.forced = true;
+        if !self.engaged {
+            self.transition(true, live_allocations);
+        }
+    }
+
+    /// Whether sampling is currently engaged.
+    pub fn is_engaged(&self) -> bool {
+        self.engaged
+    }
+
+    /// Whether sampling has been engaged at any point since the last reset.
+    pub fn was_ever_engaged(&self) -> bool {
+        !self.transitions.is_empty()
+    }
+
+    /// All the times sampling was switched on or off since the last reset.
+    pub fn transitions(&self) -> &[SamplingTransition] {
+        &self.transitions
+    }
+
+    /// Start over, e.g. when the tracker is reset.
+    pub fn reset(&mut self) {
+        let forced = self.forced;
+        *self = Self::new(self.high_water, self.low_water);
+        if forced {
+            self.force(0);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{AdaptiveSampling, SAMPLE_EVERY, SMALL_ALLOCATION_BYTES};
+
+    #[test]
+    fn exact_until_engaged() {
+        let mut sampling = AdaptiveSampling::new(10, 5);
+        sampling.update(9);
+        assert!(!sampling.is_engaged());
+        assert_eq!(sampling.size_to_record(1), Some(1));
+        sampling.update(10);
+        assert!(sampling.is_engaged());
+        // Large allocations are always exact:
+        assert_eq!(
+            sampling.size_to_record(SMALL_ALLOCATION_BYTES + 1),
+            Some(SMALL_ALLOCATION_BYTES + 1)
+        );
+        // Small allocations are sampled and scaled:
+        let recorded: Vec<_> = (0..SAMPLE_EVERY * 3)
+            .filter_map(|_| sampling.size_to_record(10))
+            .collect();
+        assert_eq!(recorded, vec![10 * SAMPLE_EVERY; 3]);
+    }
+
+    #[test]
+    fn forced() {
+        let mut sampling = AdaptiveSampling::new(0, 0);
+        sampling.update(100);
+        assert!(!sampling.is_engaged());
+        sampling.force(100);
+        assert!(sampling.is_engaged());
+        sampling.update(0);
+        assert!(sampling.is_engaged());
+        sampling.reset();
+        assert!(sampling.is_engaged());
+        assert_eq!(
+            sampling.size_to_record(SMALL_ALLOCATION_BYTES * 2),
+            Some(SMALL_ALLOCATION_BYTES * 2)
+        );
+    }
+
+    #[test]
+    fn hysteresis() {
+        let mut sampling = AdaptiveSampling::new(10, 5);
+        sampling.update(12);
+        sampling.update(6);
+        assert!(sampling.is_engaged());
+        sampling.update(4);
+        assert!(!sampling.is_engaged());
+        assert_eq!(sampling.size_to_record(1), Some(1));
+        let transitions: Vec<_> = sampling
+            .transitions()
+            .iter()
+            .map(|t| (t.engaged, t.live_allocations))
+            .collect();
+        assert_eq!(transitions, vec![(true, 12), (false, 4)]);
+        assert!(sampling.was_ever_engaged());
+        sampling.reset();
+        assert!(!sampling.was_ever_engaged());
+    }
+
+    #[test]
+    fn disabled() {
+        let mut sampling = AdaptiveSampling::new(0, 0);
+        sampling.update(usize::MAX);
+        assert!(!sampling.is_engaged());
+    }
+}
@@ -0,0 +1,107 @@
+//! Canonicalizing tagged pointers before they're used as keys.
+//!
+//! On arm64 the hardware ignores the top byte of addresses, so with memory
+//! tagging (MTE), or hardened allocators that keep metadata in the high bits,
+//! the pointer passed to free() may differ in its top byte from the one
+//! malloc() returned. Fil would then not find the allocation, and tracked
+//! memory would keep growing. So addresses are canonicalized whenever
+//! allocations are added, looked up or removed.
+//!
+//! Set with FIL_ADDRESS_TAGS: `top-byte` clears the top byte, `sign-extend`
+//! replaces it with copies of bit 55, following the arm64 convention for
+//! kernel addresses, and `off` leaves addresses alone. The default, `auto`,
+//! is `top-byte` on aarch64, where user-space addresses never use the top
+//! byte, and `off` elsewhere.
+
+/// How to turn a possibly-tagged address into the canonical one.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum AddressTags {
+    Off,
+    TopByte,
+    SignExtend,
+}
+
+impl AddressTags {
+    /// What `auto` means on this platform.
+    pub fn platform_default() -> Self {
+        if cfg!(target_arch = "aarch64") {
+            AddressTags::TopByte
+        } else {
+            AddressTags::Off
+        }
+    }
+
+    /// Parse a FIL_ADDRESS_TAGS value.
+    pub fn parse(value: &str) -> Option<Self> {
+        match value {
+            "auto" => Some(Self::platform_default()),
+            "off" => Some(AddressTags::Off),
+            "top-byte" => Some(AddressTags::TopByte),
+            "sign-extend" => Some(AddressTags::SignExtend),
+            _ => None,
+        }
+    }
+
+    /// Configure from FIL_ADDRESS_TAGS; an unknown value is warned about and
+    /// treated as `auto`.
+    pub fn from_env() -> Self {
+        match std::env::var("FIL_ADDRESS_TAGS") {
+            Ok(value) => Self::parse(&value).unwrap_or_else(|| {
+                eprintln!(
+                    "=fil-profile= Unknown FIL_ADDRESS_TAGS={:?}, expected auto, off, top-byte or sign-extend",
+                    value
+                );
+                Self::platform_default()
+            }),
+            Err(_) => Self::platform_default(),
+        }
+    }
+
+    /// The canonical form of the address.
+    #[inline]
+    pub fn canonicalize(self, address: usize) -> usize {
+        match self {
+            AddressTags::Off => address,
+            AddressTags::TopByte => address & (usize::MAX >> 8),
+            AddressTags::SignExtend => (((address << 8) as isize) >> 8) as usize,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::AddressTags;
+
+    #[test]
+    fn canonicalize() {
+        let address = 0x0000_ffff_1234_5670;
+        let tagged = 0x0b00_ffff_1234_5670;
+        assert_eq!(AddressTags::Off.canonicalize(tagged), tagged);
+        assert_eq!(AddressTags::TopByte.canonicalize(tagged), address);
+        assert_eq!(AddressTags::TopByte.canonicalize(address), address);
+        assert_eq!(AddressTags::SignExtend.canonicalize(tagged), address);
+        assert_eq!(
+            AddressTags::SignExtend.canonicalize(0x0bff_8000_0000_1000),
+            0xffff_8000_0000_1000
+        );
+        assert_eq!(
+            AddressTags::TopByte.canonicalize(0x0bff_8000_0000_1000),
+            0x00ff_8000_0000_1000
+        );
+    }
+
+    #[test]
+    fn parsing() {
+        assert_eq!(AddressTags::parse("off"), Some(AddressTags::Off));
+        assert_eq!(AddressTags::parse("top-byte"), Some(AddressTags::TopByte));
+        assert_eq!(
+            AddressTags::parse("sign-extend"),
+            Some(AddressTags::SignExtend)
+        );
+        assert_eq!(
+            AddressTags::parse("auto"),
+            Some(AddressTags::platform_default())
+        );
+        assert_eq!(AddressTags::parse("mte"), None);
+    }
+}
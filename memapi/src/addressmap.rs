@@ -0,0 +1,461 @@
+//! A map from memory addresses to values, for tracking live allocations.
+//!
+//! Every tracked malloc() and free() does an insert or a remove, so this is on
+//! the hot path. A general-purpose HashMap spends much of that time on keyed
+//! hashing, which addresses don't need. Instead this is an open-addressing
+//! table with linear probing:
+//!
+//! * Addresses are hashed with a single multiplication, keeping the top bits,
+//!   so the low bits being all zero due to alignment doesn't matter.
+//! * Entries are stored inline, with no per-entry heap allocation.
+//! * Removal uses backward-shift deletion, so there are no tombstones, and
+//!   lookups don't get slower as allocations churn.
+//! * Resizing is incremental: a few entries at a time are moved from the old
+//!   table as part of later inserts and removes. With millions of live
+//!   allocations, moving them all at once would stall the program for tens
+//!   of milliseconds every time the table doubled.
+
+use std::mem::MaybeUninit;
+
+/// Marks an unused slot. This is what a zeroed allocation contains, so new
+/// tables don't have to be filled in.
+const EMPTY: usize = 0;
+
+/// Marks a slot in the old table whose entry was moved to the new table, or
+/// removed, during a resize. Lookups have to probe past these.
+const MOVED: usize = usize::MAX;
+
+/// Smallest non-zero number of slots; must be a power of two.
+const MIN_CAPACITY: usize = 16;
+
+/// 2^64 divided by the golden ratio, for Fibonacci hashing.
+const MULTIPLIER: u64 = 0x9E37_79B9_7F4A_7C15;
+
+/// How many slots of the old table each insert or remove moves over while
+/// resizing. The load factor is at most 3/4, so after growing from N to 2N
+/// slots there are at least 3N/4 inserts before the next resize, which is
+/// plenty of time to get through all N old slots.
+const MIGRATE_PER_OPERATION: usize = 16;
+
+#[derive(Clone, Copy)]
+struct Slot<V: Copy> {
+    // EMPTY or MOVED if there's no entry:
+    address: usize,
+    // Only initialized if there's an entry:
+    value: MaybeUninit<V>,
+}
+
+/// A power-of-two number of slots.
+struct Table<V: Copy> {
+    slots: Box<[Slot<V>]>,
+    // 64 - log2(number of slots):
+    shift: u32,
+}
+
+impl<V: Copy> Table<V> {
+    fn new() -> Self {
+        Table {
+            slots: Box::new([]),
+            shift: 64,
+        }
+    }
+
+    fn with_capacity(capacity: usize) -> Self {
+        // All zeroes is a valid, EMPTY, Slot. Zeroed memory doesn't need to be
+        // written to here, so for big tables it comes straight from the OS and
+        // pages get faulted in as they're used.
+        let slots = unsafe { Box::new_zeroed_slice(capacity).assume_init() };
+        Table {
+            slots,
+            shift: 64 - capacity.trailing_zeros(),
+        }
+    }
+
+    fn capacity(&self) -> usize {
+        self.slots.len()
+    }
+
+    fn mask(&self) -> usize {
+        self.capacity() - 1
+    }
+
+    fn ideal_slot(&self, address: usize) -> usize {
+        ((address as u64).wrapping_mul(MULTIPLIER) >> self.shift) as usize
+    }
+
+    fn value(&self, slot: usize) -> &V {
+        // Slots with an address always have a value:
+        unsafe { self.slots[slot].value.assume_init_ref() }
+    }
+
+    fn value_mut(&mut self, slot: usize) -> &mut V {
+        unsafe { self.slots[slot].value.assume_init_mut() }
+    }
+
+    /// The slot the address is in, or None if it's not in the table.
+    fn find(&self, address: usize) -> Option<usize> {
+        if self.slots.is_empty() {
+            return None;
+        }
+        self.probe(address).ok()
+    }
+
+    /// Ok with the slot the address is in, or Err with the empty slot it
+    /// would go in. The table mustn't be empty.
+    fn probe(&self, address: usize) -> Result<usize, usize> {
+        let mask = self.mask();
+        let mut slot = self.ideal_slot(address);
+        loop {
+            match self.slots[slot].address {
+                a if a == address => return Ok(slot),
+                EMPTY => return Err(slot),
+                _ => slot = (slot + 1) & mask,
+            }
+        }
+    }
+
+    /// Insert an address that isn't in the table yet, in a table with room.
+    fn insert_new(&mut self, address: usize, value: V) {
+        let mask = self.mask();
+        let mut slot = self.ideal_slot(address);
+        while self.slots[slot].address != EMPTY {
+            slot = (slot + 1) & mask;
+        }
+        self.slots[slot] = Slot {
+            address,
+            value: MaybeUninit::new(value),
+        };
+    }
+
+    /// Remove the entry in the given slot. Only for tables without MOVED
+    /// slots.
+    fn remove_slot(&mut self, mut hole: usize) -> V {
+        let removed = *self.value(hole);
+        // Shift back following entries that would no longer be reachable from
+        // their ideal slot now that there's a hole:
+        let mask = self.mask();
+        let mut slot = hole;
+        loop {
+            slot = (slot + 1) & mask;
+            let current = self.slots[slot].address;
+            if current == EMPTY {
+                break;
+            }
+            let ideal = self.ideal_slot(current);
+            if (slot.wrapping_sub(ideal) & mask) >= (slot.wrapping_sub(hole) & mask) {
+                self.slots[hole] = self.slots[slot];
+                hole = slot;
+            }
+        }
+        self.slots[hole].address = EMPTY;
+        removed
+    }
+
+    fn iter(&self) -> impl Iterator<Item = (usize, &V)> {
+        self.slots
+            .iter()
+            .enumerate()
+            .filter(|(_, slot)| slot.address != EMPTY && slot.address != MOVED)
+            .map(|(index, slot)| (slot.address, self.value(index)))
+    }
+
+    fn values_mut(&mut self) -> impl Iterator<Item = &mut V> {
+        self.slots
+            .iter_mut()
+            .filter(|slot| slot.address != EMPTY && slot.address != MOVED)
+            // Slots with an address always have a value:
+            .map(|slot| unsafe { slot.value.assume_init_mut() })
+    }
+}
+
+pub struct AddressMap<V: Copy> {
+    table: Table<V>,
+    // Number of entries in table:
+    used: usize,
+    // While resizing, the previous table, whose entries are moved over by
+    // migrate_some(). Empty otherwise.
+    old: Table<V>,
+    // Entries still in the old table:
+    old_used: usize,
+    // Slots of the old table before this one have been moved over:
+    migrated: usize,
+    // Values for the two addresses that can't be stored in a table:
+    empty_address: Option<V>,
+    moved_address: Option<V>,
+}
+
+impl<V: Copy> AddressMap<V> {
+    pub fn new() -> Self {
+        AddressMap {
+            table: Table::new(),
+            used: 0,
+            old: Table::new(),
+            old_used: 0,
+            migrated: 0,
+            empty_address: None,
+            moved_address: None,
+        }
+    }
+
+    /// Where the value is stored for addresses that can't go in a table.
+    fn special(&mut self, address: usize) -> Option<&mut Option<V>> {
+        match address {
+            EMPTY => Some(&mut self.empty_address),
+            MOVED => Some(&mut self.moved_address),
+            _ => None,
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.used
+            + self.old_used
+            + usize::from(self.empty_address.is_some())
+            + usize::from(self.moved_address.is_some())
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Memory used by the tables, including any still being migrated from.
+    pub fn heap_bytes(&self) -> usize {
+        (self.table.slots.len() + self.old.slots.len()) * std::mem::size_of::<Slot<V>>()
+    }
+
+    /// How much heap_bytes() will go up by the next time the table grows.
+    pub fn growth_bytes(&self) -> usize {
+        self.table.slots.len().max(1) * 2 * std::mem::size_of::<Slot<V>>()
+    }
+
+    pub fn get(&self, address: usize) -> Option<&V> {
+        match address {
+            EMPTY => return self.empty_address.as_ref(),
+            MOVED => return self.moved_address.as_ref(),
+            _ => {}
+        }
+        if let Some(slot) = self.table.find(address) {
+            return Some(self.table.value(slot));
+        }
+        if self.old_used > 0 {
+            if let Some(slot) = self.old.find(address) {
+                return Some(self.old.value(slot));
+            }
+        }
+        None
+    }
+
+    pub fn contains_key(&self, address: usize) -> bool {
+        self.get(address).is_some()
+    }
+
+    /// Insert a value, returning the previous value for the address, if any.
+    pub fn insert(&mut self, address: usize, value: V) -> Option<V> {
+        if let Some(special) = self.special(address) {
+            return special.replace(value);
+        }
+        self.migrate_some();
+        // Keep the load factor at most 3/4, so probe sequences stay short:
+        if (self.used + self.old_used + 1) * 4 > self.table.capacity() * 3 {
+            self.grow();
+        }
+        let empty_slot = match self.table.probe(address) {
+            Ok(slot) => return Some(std::mem::replace(self.table.value_mut(slot), value)),
+            Err(slot) => slot,
+        };
+        if self.old_used > 0 {
+            if let Some(slot) = self.old.find(address) {
+                return Some(std::mem::replace(self.old.value_mut(slot), value));
+            }
+        }
+        self.table.slots[empty_slot] = Slot {
+            address,
+            value: MaybeUninit::new(value),
+        };
+        self.used += 1;
+        None
+    }
+
+    /// Remove an address, returning its value if it was in the map.
+    pub fn remove(&mut self, address: usize) -> Option<V> {
+        if let Some(special) = self.special(address) {
+            return special.take();
+        }
+        self.migrate_some();
+        if let Some(slot) = self.table.find(address) {
+            self.used -= 1;
+            return Some(self.table.remove_slot(slot));
+        }
+        if self.old_used > 0 {
+            if let Some(slot) = self.old.find(address) {
+                // Shifting entries back might move them to slots that were
+                // already migrated, so leave a marker instead:
+                self.old.slots[slot].address = MOVED;
+                self.old_used -= 1;
+                return Some(*self.old.value(slot));
+            }
+        }
+        None
+    }
+
+    /// Start resizing into a table with twice as many slots.
+    fn grow(&mut self) {
+        // Normally the previous resize finished long ago, but just in case:
+        while self.old.capacity() > 0 {
+            self.migrate_some();
+        }
+        let capacity = (self.table.capacity() * 2).max(MIN_CAPACITY);
+        self.old = std::mem::replace(&mut self.table, Table::with_capacity(capacity));
+        self.old_used = self.used;
+        self.used = 0;
+        self.migrated = 0;
+    }
+
+    /// If resizing, move entries from the next few slots of the old table.
+    fn migrate_some(&mut self) {
+        if self.old.capacity() == 0 {
+            return;
+        }
+        let end = (self.migrated + MIGRATE_PER_OPERATION).min(self.old.capacity());
+        for slot in self.migrated..end {
+            let address = self.old.slots[slot].address;
+            if address != EMPTY && address != MOVED {
+                self.old.slots[slot].address = MOVED;
+                self.table.insert_new(address, *self.old.value(slot));
+                self.used += 1;
+                self.old_used -= 1;
+            }
+        }
+        self.migrated = end;
+        if self.old_used == 0 {
+            self.old = Table::new();
+            self.migrated = 0;
+        }
+    }
+
+    pub fn clear(&mut self) {
+        *self = Self::new();
+    }
+
+    /// Iterate over (address, &value), in no particular order.
+    pub fn iter(&self) -> impl Iterator<Item = (usize, &V)> {
+        self.table
+            .iter()
+            .chain(self.old.iter())
+            .chain(self.empty_address.iter().map(|value| (EMPTY, value)))
+            .chain(self.moved_address.iter().map(|value| (MOVED, value)))
+    }
+
+    pub fn values(&self) -> impl Iterator<Item = &V> {
+        self.iter().map(|(_, value)| value)
+    }
+
+    pub fn values_mut(&mut self) -> impl Iterator<Item = &mut V> {
+        self.table
+            .values_mut()
+            .chain(self.old.values_mut())
+            .chain(self.empty_address.iter_mut())
+            .chain(self.moved_address.iter_mut())
+    }
+}
+
+impl<V: Copy> Default for AddressMap<V> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::AddressMap;
+    use proptest::prelude::*;
+    use std::collections::HashMap;
+
+    fn addresses() -> impl Strategy<Value = usize> {
+        prop_oneof![
+            // Aligned, and few enough that operations hit the same addresses:
+            (0..64_usize).prop_map(|i| i * 16),
+            // Clustered high addresses, including the MOVED marker:
+            (0..8_usize).prop_map(|i| usize::MAX - i),
+            any::<usize>(),
+        ]
+    }
+
+    proptest! {
+        // AddressMap behaves the same as a HashMap.
+        #[test]
+        fn same_as_hashmap(
+            operations in prop::collection::vec((any::<bool>(), addresses(), any::<u32>()), 0..500)
+        ) {
+            let mut map = AddressMap::new();
+            let mut expected = HashMap::new();
+            for (is_insert, address, value) in operations {
+                if is_insert {
+                    prop_assert_eq!(map.insert(address, value), expected.insert(address, value));
+                } else {
+                    prop_assert_eq!(map.remove(address), expected.remove(&address));
+                }
+                prop_assert_eq!(map.len(), expected.len());
+                prop_assert_eq!(map.get(address), expected.get(&address));
+            }
+            let contents: HashMap<usize, u32> =
+                map.iter().map(|(address, value)| (address, *value)).collect();
+            prop_assert_eq!(contents, expected);
+            map.clear();
+            prop_assert!(map.is_empty());
+        }
+    }
+
+    // Entries are all still there in the middle of resizing, whichever table
+    // they're in.
+    #[test]
+    fn incremental_resize() {
+        let mut map = AddressMap::new();
+        let mut expected = HashMap::new();
+        for i in 0..20_000_usize {
+            let address = 0x7f00_0000_0000 + i * 48;
+            assert_eq!(map.insert(address, i), None);
+            expected.insert(address, i);
+            if i % 3 == 0 {
+                // Remove and update older entries, which are likely to still
+                // be in the old table:
+                let older = 0x7f00_0000_0000 + (i / 2) * 48;
+                assert_eq!(map.remove(older), expected.remove(&older));
+                let updated = 0x7f00_0000_0000 + (i / 3) * 48;
+                if let Some(value) = expected.get_mut(&updated) {
+                    *value += 1;
+                    assert_eq!(map.insert(updated, *value), Some(*value - 1));
+                }
+            }
+            assert_eq!(map.len(), expected.len());
+            if i % 1000 == 0 {
+                let contents: HashMap<usize, usize> = map
+                    .iter()
+                    .map(|(address, value)| (address, *value))
+                    .collect();
+                assert_eq!(contents, expected);
+            }
+        }
+        for (address, value) in expected.iter() {
+            assert_eq!(map.get(*address), Some(value));
+        }
+    }
+
+    // Updating in place reaches every entry, including ones in the old table
+    // and the special addresses.
+    #[test]
+    fn values_mut() {
+        let mut map = AddressMap::new();
+        for i in 0..1000_usize {
+            map.insert(0x7f00_0000_0000 + i * 48, i);
+        }
+        map.insert(0, 1000);
+        map.insert(usize::MAX, 1001);
+        for value in map.values_mut() {
+            *value += 1;
+        }
+        assert_eq!(map.len(), 1002);
+        let mut values: Vec<usize> = map.values().copied().collect();
+        values.sort();
+        assert_eq!(values, (1..=1002).collect::<Vec<_>>());
+    }
+}
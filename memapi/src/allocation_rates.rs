@@ -0,0 +1,338 @@
+//! Which callstacks allocate most often, for when profiling overhead comes
+//! from an allocation storm rather than from where the bytes live.
+//!
+//! Opt-in, via FIL_ALLOCATION_RATES=1. Every allocation increments a counter
+//! for its callstack, and the sampler thread periodically turns the counts
+//! since its last visit into calls per second, every
+//! FIL_ALLOCATION_RATES_INTERVAL_MS (1000 by default). The report lists the
+//! callstacks with the highest peak rate, along with a flamegraph weighted by
+//! the average number of calls per second over the whole run.
+
+use crate::flamegraph::FlamegraphCallstacks;
+use crate::linecache::LineCacher;
+use crate::memorytracking::{Callstack, CallstackId, IdentityCleaner, ReadFunctionLocations};
+use crate::util::{new_hashmap, write_atomically};
+use ahash::RandomState as ARandomState;
+use serde::Serialize;
+use std::collections::HashMap;
+use std::path::Path;
+use std::time::{Duration, Instant};
+
+const DEFAULT_INTERVAL: Duration = Duration::from_millis(1000);
+
+/// How many callstacks to include in the table.
+const MAX_REPORTED_CALLSTACKS: usize = 50;
+
+/// Maximum number of stored overall rates; must be even.
+const MAX_SAMPLES: usize = 2000;
+
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+struct CallstackRate {
+    calls: u64,
+    peak_per_second: f64,
+    peak_at_seconds: f64,
+}
+
+/// Allocation counts per callstack, bucketed by time.
+pub struct AllocationRates {
+    interval: Duration,
+    start: Instant,
+    last_sample: Instant,
+    // Indexed by CallstackId, calls since the last sample:
+    current_calls: Vec<u64>,
+    // Indexed by CallstackId:
+    per_callstack: Vec<CallstackRate>,
+    // (seconds since start, calls per second for all callstacks combined);
+    // adjacent samples get merged once there are too many:
+    overall: Vec<(f64, f64)>,
+}
+
+impl AllocationRates {
+    pub fn new(interval: Duration) -> Self {
+        let now = Instant::now();
+        Self {
+            interval,
+            start: now,
+            last_sample: now,
+            current_calls: vec![],
+            per_callstack: vec![],
+            overall: vec![],
+        }
+    }
+
+    /// Create one if FIL_ALLOCATION_RATES=1 is set.
+    /// FIL_ALLOCATION_RATES_INTERVAL_MS sets how coarse the buckets are.
+    pub fn from_env() -> Option<Self> {
+        if std::env::var("FIL_ALLOCATION_RATES").as_deref() != Ok("1") {
+            return None;
+        }
+        let interval = std::env::var("FIL_ALLOCATION_RATES_INTERVAL_MS")
+            .ok()
+            .and_then(|value| value.parse().ok())
+            .filter(|millis| *millis > 0)
+            .map(Duration::from_millis)
+            .unwrap_or(DEFAULT_INTERVAL);
+        Some(Self::new(interval))
+    }
+
+    /// How often sample() should be called.
+    pub fn interval(&self) -> Duration {
+        self.interval
+    }
+
+    /// Record an allocation by the given callstack.
+    #[inline]
+    pub fn record(&mut self, callstack_id: CallstackId) {
+        let index = callstack_id as usize;
+        if index >= self.current_calls.len() {
+            self.current_calls.resize(index + 1, 0);
+        }
+        self.current_calls[index] += 1;
+    }
+
+    /// Close the current bucket.
+    pub fn sample(&mut self) {
+        let now = Instant::now();
+        let elapsed = now.duration_since(self.last_sample).as_secs_f64();
+        let since_start = now.duration_since(self.start).as_secs_f64();
+        self.last_sample = now;
+        self.sample_after(elapsed, since_start);
+    }
+
+    fn sample_after(&mut self, elapsed_seconds: f64, since_start_seconds: f64) {
+        if elapsed_seconds <= 0.0 {
+            return;
+        }
+        if self.per_callstack.len() < self.current_calls.len() {
+            self.per_callstack
+                .resize(self.current_calls.len(), CallstackRate::default());
+        }
+        let mut total_calls = 0;
+        for (calls, stats) in self
+            .current_calls
+            .iter_mut()
+            .zip(self.per_callstack.iter_mut())
+        {
+            if *calls == 0 {
+                continue;
+            }
+            stats.calls += *calls;
+            let per_second = *calls as f64 / elapsed_seconds;
+            if per_second > stats.peak_per_second {
+                stats.peak_per_second = per_second;
+                stats.peak_at_seconds = since_start_seconds;
+            }
+            total_calls += *calls;
+            *calls = 0;
+        }
+        self.overall
+            .push((since_start_seconds, total_calls as f64 / elapsed_seconds));
+        if self.overall.len() >= MAX_SAMPLES {
+            // Keep the higher rate of each pair, so bursts stay visible:
+            self.overall = self
+                .overall
+                .chunks(2)
+                .map(|pair| {
+                    *pair
+                        .iter()
+                        .max_by(|a, b| a.1.total_cmp(&b.1))
+                        .expect("chunks are never empty")
+                })
+                .collect();
+        }
+    }
+
+    pub fn reset(&mut self) {
+        *self = Self::new(self.interval);
+    }
+
+    /// Gather the data for the report; resolving callstacks into strings is
+    /// done later by the returned closure, so it can happen without locks
+    /// held. sample() should be called first, so recent allocations are
+    /// included.
+    pub fn report<FL: ReadFunctionLocations>(
+        &self,
+        id_to_callstack: &HashMap<CallstackId, &Callstack, ARandomState>,
+    ) -> impl FnOnce(FL) -> AllocationRatesReport<FL> {
+        let duration_seconds = self
+            .last_sample
+            .duration_since(self.start)
+            .as_secs_f64()
+            .max(f64::MIN_POSITIVE);
+        let mut by_callstack: HashMap<Callstack, usize, ARandomState> = new_hashmap();
+        let mut top = vec![];
+        for (callstack_id, stats) in self.per_callstack.iter().enumerate() {
+            if stats.calls == 0 {
+                continue;
+            }
+            if let Some(callstack) = id_to_callstack.get(&(callstack_id as CallstackId)) {
+                // Round up, so rare callers don't disappear:
+                let per_second = (stats.calls as f64 / duration_seconds).ceil() as usize;
+                *by_callstack.entry((*callstack).clone()).or_insert(0) += per_second;
+                top.push(((*callstack).clone(), *stats));
+            }
+        }
+        top.sort_by(|a, b| b.1.peak_per_second.total_cmp(&a.1.peak_per_second));
+        top.truncate(MAX_REPORTED_CALLSTACKS);
+        let total_calls = self.per_callstack.iter().map(|stats| stats.calls).sum();
+        let interval_seconds = self.interval.as_secs_f64();
+        let overall = self.overall.clone();
+        move |functions| {
+            let mut linecache = LineCacher::default();
+            let callstacks = top
+                .into_iter()
+                .map(|(callstack, stats)| CallstackRateReport {
+                    callstack: callstack.as_string(false, &functions, ";", &mut linecache),
+                    calls: stats.calls,
+                    average_per_second: stats.calls as f64 / duration_seconds,
+                    peak_per_second: stats.peak_per_second,
+                    peak_at_seconds: stats.peak_at_seconds,
+                })
+                .collect();
+            AllocationRatesReport {
+                flamegraph: FlamegraphCallstacks::new(by_callstack, functions, IdentityCleaner),
+                summary: AllocationRatesSummary {
+                    interval_seconds,
+                    duration_seconds,
+                    total_calls,
+                    callstacks,
+                    overall,
+                },
+            }
+        }
+    }
+}
+
+#[derive(Debug, Serialize)]
+pub struct CallstackRateReport {
+    pub callstack: String,
+    pub calls: u64,
+    pub average_per_second: f64,
+    /// The highest rate in any one bucket, and when that bucket ended.
+    pub peak_per_second: f64,
+    pub peak_at_seconds: f64,
+}
+
+#[derive(Debug, Serialize)]
+pub struct AllocationRatesSummary {
+    pub interval_seconds: f64,
+    pub duration_seconds: f64,
+    pub total_calls: u64,
+    /// The callstacks with the highest peak rate, highest first.
+    pub callstacks: Vec<CallstackRateReport>,
+    /// (seconds since start, calls per second) for all callstacks combined.
+    pub overall: Vec<(f64, f64)>,
+}
+
+impl AllocationRatesSummary {
+    /// A human-readable table.
+    pub fn to_table(&self) -> String {
+        let mut table = format!(
+            "{} allocations over {:.1}s, in {:.0}ms buckets.\n\n{:>12} {:>12} {:>10} {:>12}  callstack\n",
+            self.total_calls,
+            self.duration_seconds,
+            self.interval_seconds * 1000.0,
+            "peak/s",
+            "average/s",
+            "peak at",
+            "allocations"
+        );
+        for cs in &self.callstacks {
+            table.push_str(&format!(
+                "{:>12.0} {:>12.0} {:>9.1}s {:>12}  {}\n",
+                cs.peak_per_second,
+                cs.average_per_second,
+                cs.peak_at_seconds,
+                cs.calls,
+                cs.callstack
+            ));
+        }
+        table
+    }
+}
+
+/// Everything needed to write out the allocation rates report.
+pub struct AllocationRatesReport<FL: ReadFunctionLocations> {
+    pub flamegraph:
+        FlamegraphCallstacks<HashMap<Callstack, usize, ARandomState>, FL, IdentityCleaner>,
+    pub summary: AllocationRatesSummary,
+}
+
+impl<FL: ReadFunctionLocations> AllocationRatesReport<FL> {
+    /// Write allocation-rates.svg and friends, weighted by average
+    /// allocations per second, plus allocation-rates.json and
+    /// allocation-rates.txt.
+    pub fn write(&self, directory_path: &Path, to_be_post_processed: bool) {
+        let title = format!(
+            "Allocations per Second, by Callstack ({:.0}/s on average)",
+            self.summary.total_calls as f64 / self.summary.duration_seconds
+        );
+        self.flamegraph.write_flamegraphs(
+            directory_path,
+            "allocation-rates",
+            &title,
+            "Made with the Fil profiler.",
+            "allocations/s",
+            to_be_post_processed,
+        );
+        let json_path = directory_path.join("allocation-rates.json");
+        let result = serde_json::to_vec_pretty(&self.summary)
+            .map_err(std::io::Error::from)
+            .and_then(|data| write_atomically(&json_path, data))
+            .and_then(|_| {
+                write_atomically(
+                    &directory_path.join("allocation-rates.txt"),
+                    self.summary.to_table(),
+                )
+            });
+        match result {
+            Ok(_) => eprintln!("=fil-profile= Wrote allocation rates to {:?}", json_path),
+            Err(e) => eprintln!("=fil-profile= Error writing allocation rates: {}", e),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{AllocationRates, MAX_SAMPLES};
+    use std::time::Duration;
+
+    #[test]
+    fn peak_rate_per_callstack() {
+        let mut rates = AllocationRates::new(Duration::from_secs(1));
+        for _ in 0..10 {
+            rates.record(1);
+        }
+        rates.record(3);
+        rates.sample_after(1.0, 1.0);
+        // A burst in the second bucket:
+        for _ in 0..100 {
+            rates.record(1);
+        }
+        rates.sample_after(0.5, 1.5);
+        let stats = rates.per_callstack[1];
+        assert_eq!(stats.calls, 110);
+        assert_eq!(stats.peak_per_second, 200.0);
+        assert_eq!(stats.peak_at_seconds, 1.5);
+        assert_eq!(rates.per_callstack[3].calls, 1);
+        assert_eq!(rates.per_callstack[2].calls, 0);
+        assert_eq!(rates.overall, vec![(1.0, 11.0), (1.5, 200.0)]);
+        assert!(rates.current_calls.iter().all(|calls| *calls == 0));
+    }
+
+    #[test]
+    fn overall_rates_are_capped() {
+        let mut rates = AllocationRates::new(Duration::from_secs(1));
+        for i in 0..MAX_SAMPLES * 3 {
+            for _ in 0..(i % 7) {
+                rates.record(0);
+            }
+            rates.sample_after(1.0, i as f64);
+        }
+        assert!(rates.overall.len() < MAX_SAMPLES);
+        assert!(rates.overall.windows(2).all(|pair| pair[0].0 < pair[1].0));
+        // Merging keeps the higher rate, so there are fewer low ones:
+        let low = rates.overall.iter().filter(|(_, rate)| *rate < 1.0).count();
+        assert!(low < rates.overall.len() / 7);
+    }
+}
@@ -0,0 +1,207 @@
+//! Statistics from the underlying malloc() implementation.
+//!
+//! Fil tracks how much memory was allocated, but freed memory isn't
+//! necessarily returned to the operating system: glibc in particular keeps it
+//! around in its free lists, e.g. due to fragmentation. Comparing tracked bytes
+//! with what the allocator has actually obtained helps explain why RSS didn't
+//! go down even though everything was freed.
+
+use serde::Serialize;
+
+/// Numbers reported by the allocator.
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct AllocatorStats {
+    /// Where the numbers came from.
+    pub source: &'static str,
+    /// Bytes the allocator has obtained from the operating system.
+    pub arena_bytes: usize,
+    /// Bytes in allocations that haven't been freed.
+    pub in_use_bytes: usize,
+    /// Bytes sitting in the allocator's free lists, retained but not released
+    /// to the operating system.
+    pub free_bytes: usize,
+    /// Bytes at the top of the heap that could be released to the operating
+    /// system with malloc_trim(), if known.
+    pub releasable_bytes: Option<usize>,
+}
+
+/// Query the allocator now.
+#[cfg(all(target_os = "linux", target_env = "gnu"))]
+pub fn probe() -> Result<AllocatorStats, &'static str> {
+    use once_cell::sync::Lazy;
+
+    // Layout of glibc's struct mallinfo2.
+    #[repr(C)]
+    struct Mallinfo2 {
+        arena: usize,
+        ordblks: usize,
+        smblks: usize,
+        hblks: usize,
+        hblkhd: usize,
+        usmblks: usize,
+        fsmblks: usize,
+        uordblks: usize,
+        fordblks: usize,
+        keepcost: usize,
+    }
+    type Mallinfo2Fn = unsafe extern "C" fn() -> Mallinfo2;
+
+    // mallinfo2() was only added in glibc 2.33, so look it up at runtime
+    // rather than failing to load on older systems:
+    static MALLINFO2: Lazy<Option<Mallinfo2Fn>> = Lazy::new(|| {
+        let symbol = unsafe { libc::dlsym(libc::RTLD_DEFAULT, c"mallinfo2".as_ptr()) };
+        if symbol.is_null() {
+            None
+        } else {
+            Some(unsafe { std::mem::transmute::<*mut libc::c_void, Mallinfo2Fn>(symbol) })
+        }
+    });
+
+    let mallinfo2 = MALLINFO2.ok_or("mallinfo2() requires glibc 2.33 or later")?;
+    let info = unsafe { mallinfo2() };
+    Ok(AllocatorStats {
+        source: "glibc mallinfo2()",
+        // Main heap and arenas, plus chunks allocated directly with mmap():
+        arena_bytes: info.arena + info.hblkhd,
+        in_use_bytes: info.uordblks + info.hblkhd,
+        free_bytes: info.fordblks,
+        releasable_bytes: Some(info.keepcost),
+    })
+}
+
+#[cfg(target_os = "macos")]
+pub fn probe() -> Result<AllocatorStats, &'static str> {
+    use libc::{c_uint, c_void};
+
+    // Layout of malloc_statistics_t from <malloc/malloc.h>.
+    #[repr(C)]
+    #[derive(Default)]
+    struct MallocStatistics {
+        blocks_in_use: c_uint,
+        size_in_use: usize,
+        max_size_in_use: usize,
+        size_allocated: usize,
+    }
+
+    extern "C" {
+        fn malloc_zone_statistics(zone: *mut c_void, stats: *mut MallocStatistics);
+    }
+
+    let mut stats = MallocStatistics::default();
+    // A NULL zone means statistics for all zones:
+    unsafe { malloc_zone_statistics(std::ptr::null_mut(), &mut stats) };
+    Ok(AllocatorStats {
+        source: "macOS malloc_zone_statistics()",
+        arena_bytes: stats.size_allocated,
+        in_use_bytes: stats.size_in_use,
+        free_bytes: stats.size_allocated.saturating_sub(stats.size_in_use),
+        releasable_bytes: None,
+    })
+}
+
+#[cfg(not(any(all(target_os = "linux", target_env = "gnu"), target_os = "macos")))]
+pub fn probe() -> Result<AllocatorStats, &'static str> {
+    Err("not supported on this platform")
+}
+
+/// Allocator statistics compared to what Fil tracked, for the report.
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct AllocatorMetadata {
+    /// Bytes Fil tracks as currently allocated.
+    pub tracked_live_bytes: usize,
+    /// None if the allocator couldn't be queried.
+    pub stats: Option<AllocatorStats>,
+    /// Why stats are missing, if they are.
+    pub unavailable_reason: Option<&'static str>,
+    /// Tracked live bytes divided by the allocator's arena size. Low values
+    /// suggest fragmentation. Only approximate, since Fil also tracks memory
+    /// that doesn't come from malloc(), e.g. anonymous mmap().
+    pub tracked_to_arena_ratio: Option<f64>,
+}
+
+impl AllocatorMetadata {
+    /// Query the allocator now.
+    pub fn probe(tracked_live_bytes: usize) -> Self {
+        Self::new(tracked_live_bytes, probe())
+    }
+
+    fn new(tracked_live_bytes: usize, stats: Result<AllocatorStats, &'static str>) -> Self {
+        match stats {
+            Ok(stats) => Self {
+                tracked_live_bytes,
+                tracked_to_arena_ratio: if stats.arena_bytes > 0 {
+                    Some(tracked_live_bytes as f64 / stats.arena_bytes as f64)
+                } else {
+                    None
+                },
+                stats: Some(stats),
+                unavailable_reason: None,
+            },
+            Err(reason) => Self {
+                tracked_live_bytes,
+                stats: None,
+                unavailable_reason: Some(reason),
+                tracked_to_arena_ratio: None,
+            },
+        }
+    }
+
+    /// A one-line human-readable summary.
+    pub fn summary(&self) -> String {
+        let mib = |bytes: usize| bytes as f64 / (1024.0 * 1024.0);
+        match (&self.stats, self.unavailable_reason) {
+            (Some(stats), _) => format!(
+                "Allocator has {:.1} MiB from the OS, of which {:.1} MiB is in use and {:.1} MiB is free but not released to the OS ({:.1} MiB releasable); Fil tracks {:.1} MiB as allocated ({:.0}% of the allocator's arena).",
+                mib(stats.arena_bytes),
+                mib(stats.in_use_bytes),
+                mib(stats.free_bytes),
+                mib(stats.releasable_bytes.unwrap_or(0)),
+                mib(self.tracked_live_bytes),
+                self.tracked_to_arena_ratio.unwrap_or(0.0) * 100.0,
+            ),
+            (None, reason) => format!(
+                "Allocator statistics unavailable: {}.",
+                reason.unwrap_or("unknown reason")
+            ),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{AllocatorMetadata, AllocatorStats};
+
+    #[test]
+    fn ratio_and_summary() {
+        let stats = AllocatorStats {
+            source: "test",
+            arena_bytes: 4 * 1024 * 1024,
+            in_use_bytes: 1024 * 1024,
+            free_bytes: 3 * 1024 * 1024,
+            releasable_bytes: None,
+        };
+        let metadata = AllocatorMetadata::new(1024 * 1024, Ok(stats.clone()));
+        assert_eq!(metadata.tracked_to_arena_ratio, Some(0.25));
+        assert_eq!(metadata.stats, Some(stats));
+        assert!(metadata.summary().contains("3.0 MiB is free"));
+        assert!(metadata.summary().contains("(25% of"));
+
+        let metadata = AllocatorMetadata::new(123, Err("nope"));
+        assert_eq!(metadata.stats, None);
+        assert_eq!(metadata.tracked_to_arena_ratio, None);
+        assert_eq!(
+            metadata.summary(),
+            "Allocator statistics unavailable: nope."
+        );
+    }
+
+    #[cfg(all(target_os = "linux", target_env = "gnu"))]
+    #[test]
+    fn glibc_probe() {
+        // The test binary uses glibc's malloc, so this shows up:
+        let data = vec![1u8; 10_000];
+        let stats = super::probe().unwrap();
+        assert!(stats.in_use_bytes >= data.len());
+        assert!(stats.arena_bytes >= stats.in_use_bytes);
+    }
+}
@@ -0,0 +1,379 @@
+//! The peak snapshot as an Arrow IPC file, `peak.arrow`, one row per
+//! callstack, for loading into pandas, polars, DuckDB and the like. Enabled by
+//! the `arrow-export` cargo feature.
+//!
+//! The columns are:
+//!
+//! * `callstack`: list of struct{file, function, line}, outermost first. Fil's
+//!   own frames, e.g. `[phase: load]`, have an empty file and a null line.
+//! * `bytes`: uint64.
+//! * `allocation_count`: uint64, how many malloc()-style allocations were live;
+//!   mmap()s aren't counted. Null for callstacks merged to fit the report
+//!   budget, see crate::report_budget.
+//! * `thread_id`: uint64, the OS thread id of the thread that allocated with
+//!   this callstack, null if more than one did.
+//! * `phase`: the phase name (see crate::phases), null outside of phases or
+//!   when phases aren't shown.
+
+use crate::memorytracking::Frame;
+use crate::util::write_atomically_with;
+use arrow_array::builder::{
+    ArrayBuilder, ListBuilder, StringBuilder, StructBuilder, UInt32Builder, UInt64Builder,
+};
+use arrow_array::{ArrayRef, RecordBatch};
+use arrow_ipc::writer::FileWriter;
+use arrow_schema::{ArrowError, DataType, Field, Fields, Schema};
+use std::io::{self, Write};
+use std::path::Path;
+use std::sync::Arc;
+
+/// One row of the table.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct PeakRow {
+    pub frames: Vec<Frame>,
+    pub bytes: u64,
+    pub allocation_count: Option<u64>,
+    pub thread_id: Option<u64>,
+    pub phase: Option<String>,
+}
+
+/// Write the rows to the given path as an Arrow IPC file.
+pub fn write_peak_table(path: &Path, rows: &[PeakRow]) -> io::Result<()> {
+    write_atomically_with(path, |file| write_table(file, rows).map(|_| ()))
+}
+
+/// Write the rows to the given writer as an Arrow IPC file, returning the
+/// writer.
+fn write_table<W: Write>(out: W, rows: &[PeakRow]) -> io::Result<W> {
+    let batch = record_batch(rows).map_err(io::Error::other)?;
+    let mut writer = FileWriter::try_new(out, &batch.schema()).map_err(io::Error::other)?;
+    writer.write(&batch).map_err(io::Error::other)?;
+    writer.finish().map_err(io::Error::other)?;
+    writer.into_inner().map_err(io::Error::other)
+}
+
+fn frame_fields() -> Fields {
+    Fields::from(vec![
+        Field::new("file", DataType::Utf8, false),
+        Field::new("function", DataType::Utf8, false),
+        Field::new("line", DataType::UInt32, true),
+    ])
+}
+
+fn frame_field() -> Field {
+    Field::new("item", DataType::Struct(frame_fields()), false)
+}
+
+/// The table's schema, as described in the module documentation.
+pub fn schema() -> Schema {
+    Schema::new(vec![
+        Field::new("callstack", DataType::List(Arc::new(frame_field())), false),
+        Field::new("bytes", DataType::UInt64, false),
+        Field::new("allocation_count", DataType::UInt64, true),
+        Field::new("thread_id", DataType::UInt64, true),
+        Field::new("phase", DataType::Utf8, true),
+    ])
+}
+
+fn record_batch(rows: &[PeakRow]) -> Result<RecordBatch, ArrowError> {
+    let frame_builders: Vec<Box<dyn ArrayBuilder>> = vec![
+        Box::new(StringBuilder::new()),
+        Box::new(StringBuilder::new()),
+        Box::new(UInt32Builder::new()),
+    ];
+    let mut callstacks = ListBuilder::new(StructBuilder::new(frame_fields(), frame_builders))
+        .with_field(Arc::new(frame_field()));
+    let mut bytes = UInt64Builder::with_capacity(rows.len());
+    let mut allocation_counts = UInt64Builder::with_capacity(rows.len());
+    let mut thread_ids = UInt64Builder::with_capacity(rows.len());
+    let mut phases = StringBuilder::new();
+    for row in rows {
+        let frames = callstacks.values();
+        for frame in &row.frames {
+            frames
+                .field_builder::<StringBuilder>(0)
+                .expect("file is a string")
+                .append_value(&frame.file);
+            frames
+                .field_builder::<StringBuilder>(1)
+                .expect("function is a string")
+                .append_value(&frame.function);
+            frames
+                .field_builder::<UInt32Builder>(2)
+                .expect("line is a uint32")
+                .append_option(frame.line);
+            frames.append(true);
+        }
+        callstacks.append(true);
+        bytes.append_value(row.bytes);
+        allocation_counts.append_option(row.allocation_count);
+        thread_ids.append_option(row.thread_id);
+        phases.append_option(row.phase.as_deref());
+    }
+    let columns: Vec<ArrayRef> = vec![
+        Arc::new(callstacks.finish()),
+        Arc::new(bytes.finish()),
+        Arc::new(allocation_counts.finish()),
+        Arc::new(thread_ids.finish()),
+        Arc::new(phases.finish()),
+    ];
+    RecordBatch::try_new(Arc::new(schema()), columns)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{schema, write_table, PeakRow};
+    use crate::flamegraph::FlamegraphCallstacks;
+    use crate::memorytracking::{
+        AllocationTracker, CallSiteId, Callstack, Frame, IdentityCleaner,
+        LineNumberInfo::LineNumber, VecFunctionLocations, PARENT_PROCESS,
+    };
+    use crate::util::{current_thread_id, new_hashmap};
+    use ahash::RandomState as ARandomState;
+    use arrow_array::cast::AsArray;
+    use arrow_array::types::{UInt32Type, UInt64Type};
+    use arrow_array::Array;
+    use arrow_ipc::reader::FileReader;
+    use std::collections::HashMap;
+    use std::io::Cursor;
+
+    type Row = (Vec<String>, u64, Option<u64>, Option<u64>, Option<String>);
+
+    /// Read the table back with the arrow crates, with frames as
+    /// "file:line (function)".
+    fn read_back(data: Vec<u8>) -> Vec<Row> {
+        let reader = FileReader::try_new(Cursor::new(data), None).unwrap();
+        assert_eq!(*reader.schema(), schema());
+        let mut rows = vec![];
+        for batch in reader {
+            let batch = batch.unwrap();
+            let callstacks = batch.column(0).as_list::<i32>();
+            let bytes = batch.column(1).as_primitive::<UInt64Type>();
+            let allocation_counts = batch.column(2).as_primitive::<UInt64Type>();
+            let thread_ids = batch.column(3).as_primitive::<UInt64Type>();
+            let phases = batch.column(4).as_string::<i32>();
+            let option = |array: &dyn Array, i| array.is_valid(i).then_some(i);
+            for i in 0..batch.num_rows() {
+                let frames = callstacks.value(i);
+                let frames = frames.as_struct();
+                let files = frames.column(0).as_string::<i32>();
+                let functions = frames.column(1).as_string::<i32>();
+                let lines = frames.column(2).as_primitive::<UInt32Type>();
+                let frames = (0..frames.len())
+                    .map(|j| match option(lines, j) {
+                        Some(j) => {
+                            format!(
+                                "{}:{} ({})",
+                                files.value(j),
+                                lines.value(j),
+                                functions.value(j)
+                            )
+                        }
+                        None if files.value(j).is_empty() => functions.value(j).to_string(),
+                        None => format!("{} ({})", files.value(j), functions.value(j)),
+                    })
+                    .collect();
+                rows.push((
+                    frames,
+                    bytes.value(i),
+                    option(allocation_counts, i).map(|i| allocation_counts.value(i)),
+                    option(thread_ids, i).map(|i| thread_ids.value(i)),
+                    option(phases, i).map(|i| phases.value(i).to_string()),
+                ));
+            }
+        }
+        rows
+    }
+
+    fn rows() -> Vec<PeakRow> {
+        let frame = |file: &str, function: &str, line| Frame {
+            file: file.to_string(),
+            function: function.to_string(),
+            line,
+        };
+        vec![
+            PeakRow {
+                frames: vec![
+                    frame("a.py", "main", Some(12)),
+                    frame("b.py", "load", Some(7)),
+                ],
+                bytes: 3_000_000,
+                allocation_count: Some(3),
+                thread_id: Some(1234),
+                phase: Some("load".to_string()),
+            },
+            PeakRow {
+                frames: vec![frame("", "[No Python stack]", None)],
+                bytes: 1000,
+                allocation_count: None,
+                thread_id: None,
+                phase: None,
+            },
+        ]
+    }
+
+    #[test]
+    fn round_trip() {
+        let data = write_table(vec![], &rows()).unwrap();
+        assert_eq!(&data[..6], b"ARROW1");
+        assert_eq!(
+            read_back(data),
+            vec![
+                (
+                    vec!["a.py:12 (main)".to_string(), "b.py:7 (load)".to_string()],
+                    3_000_000,
+                    Some(3),
+                    Some(1234),
+                    Some("load".to_string())
+                ),
+                (
+                    vec!["[No Python stack]".to_string()],
+                    1000,
+                    None,
+                    None,
+                    None
+                ),
+            ]
+        );
+        // No rows works too:
+        assert_eq!(read_back(write_table(vec![], &[]).unwrap()), vec![]);
+    }
+
+    /// Write the flamegraph's Arrow table, and check it has the same
+    /// callstacks and bytes as peak.json, returning its rows.
+    fn check_matches_json<FL, UC>(
+        flamegraph: &FlamegraphCallstacks<HashMap<Callstack, usize, ARandomState>, FL, UC>,
+        peak_bytes: usize,
+    ) -> Vec<Row>
+    where
+        FL: crate::memorytracking::ReadFunctionLocations,
+        UC: crate::flamegraph::CallstackCleaner,
+    {
+        let directory = tempfile::tempdir().unwrap();
+        let path = directory.path().join("peak.arrow");
+        flamegraph.write_arrow(&path).unwrap();
+        let rows = read_back(std::fs::read(path).unwrap());
+        let mut from_arrow: Vec<(Vec<String>, usize)> = rows
+            .iter()
+            .map(|(frames, bytes, _, _, phase)| {
+                let phase = phase.as_ref().map(|phase| format!("[phase: {}]", phase));
+                (
+                    phase.into_iter().chain(frames.clone()).collect(),
+                    *bytes as usize,
+                )
+            })
+            .collect();
+        let json = flamegraph.to_json_report(peak_bytes);
+        let mut from_json: Vec<(Vec<String>, usize)> = json
+            .callstacks
+            .into_iter()
+            .map(|callstack| (callstack.frames, callstack.bytes))
+            .collect();
+        from_arrow.sort();
+        from_json.sort();
+        assert_eq!(from_arrow, from_json);
+        rows
+    }
+
+    /// The Arrow table has the same callstacks and bytes as peak.json.
+    #[test]
+    fn matches_json_report() {
+        pyo3::prepare_freethreaded_python();
+        let mut functions = VecFunctionLocations::new();
+        let main = functions.add_function("a.py".to_string(), "main".to_string());
+        let load = functions.add_function("b.py".to_string(), "load".to_string());
+        let data_file = functions.add_function("data.bin".to_string(), "".to_string());
+        let mut data = new_hashmap();
+        let mut cs = Callstack::from_vec(vec![CallSiteId::new(main, LineNumber(3))]);
+        data.insert(cs.clone(), 5000);
+        cs.start_call(4, CallSiteId::new(load, LineNumber(9)));
+        data.insert(cs.clone(), 70_000);
+        data.insert(cs.with_mapped_file(10, data_file), 1_000_000);
+        cs.set_phase(1);
+        data.insert(cs.clone(), 300);
+        data.insert(Callstack::new(), 20);
+        let flamegraph = FlamegraphCallstacks::new(data, functions, IdentityCleaner)
+            .with_phase_frames(vec!["training".to_string()]);
+        let rows = check_matches_json(&flamegraph, 1_100_000);
+        assert_eq!(
+            rows.iter().map(|(_, bytes, ..)| bytes).sum::<u64>(),
+            1_075_320
+        );
+        // Without a tracker there are no details:
+        assert!(rows
+            .iter()
+            .all(|(_, _, count, thread_id, _)| count.is_none() && thread_id.is_none()));
+    }
+
+    /// Allocation counts and threads come from the tracker, and are as of the
+    /// peak.
+    #[test]
+    fn tracker_details() {
+        pyo3::prepare_freethreaded_python();
+        let mut tracker = AllocationTracker::new(".".to_string(), VecFunctionLocations::new());
+        let main = tracker
+            .functions
+            .add_function("a.py".to_string(), "main".to_string());
+        let work = tracker
+            .functions
+            .add_function("b.py".to_string(), "work".to_string());
+        let main_id = tracker.get_callstack_id(&Callstack::from_vec(vec![CallSiteId::new(
+            main,
+            LineNumber(1),
+        )]));
+        let shared_id = tracker.get_callstack_id(&Callstack::from_vec(vec![CallSiteId::new(
+            main,
+            LineNumber(2),
+        )]));
+        let work_id = tracker.get_callstack_id(&Callstack::from_vec(vec![CallSiteId::new(
+            work,
+            LineNumber(5),
+        )]));
+        for i in 0..3 {
+            tracker.add_allocation(PARENT_PROCESS, 0x1000 + i, 100, main_id);
+        }
+        tracker.add_allocation(PARENT_PROCESS, 0x2000, 1000, shared_id);
+        // An mmap() adds bytes but isn't counted:
+        tracker.add_anon_mmap(PARENT_PROCESS, 0x100000, 4096, main_id);
+        let work_thread = std::thread::scope(|scope| {
+            scope
+                .spawn(|| {
+                    tracker.add_allocation(PARENT_PROCESS, 0x3000, 500, work_id);
+                    tracker.add_allocation(PARENT_PROCESS, 0x3100, 500, work_id);
+                    tracker.add_allocation(PARENT_PROCESS, 0x2100, 1000, shared_id);
+                    current_thread_id()
+                })
+                .join()
+                .unwrap()
+        });
+        // After the peak, which the table should still reflect:
+        tracker.free_allocation(PARENT_PROCESS, 0x1000);
+        tracker.free_allocation(PARENT_PROCESS, 0x3000);
+        tracker.add_allocation(PARENT_PROCESS, 0x4000, 10, main_id);
+        assert!(tracker.validate().is_empty(), "{:?}", tracker.validate());
+
+        let peak_bytes = tracker.get_peak_allocated_bytes();
+        let flamegraph = tracker.combine_callstacks(true, IdentityCleaner)();
+        let rows = check_matches_json(&flamegraph, peak_bytes);
+        let details: HashMap<String, (u64, Option<u64>, Option<u64>)> = rows
+            .into_iter()
+            .map(|(frames, bytes, count, thread_id, _)| {
+                (frames.join(";"), (bytes, count, thread_id))
+            })
+            .collect();
+        let main_thread = Some(current_thread_id());
+        assert_eq!(
+            details,
+            HashMap::from([
+                ("a.py:1 (main)".to_string(), (4396, Some(3), main_thread)),
+                ("a.py:2 (main)".to_string(), (2000, Some(2), None)),
+                (
+                    "b.py:5 (work)".to_string(),
+                    (1000, Some(2), Some(work_thread))
+                ),
+            ])
+        );
+        let total: u64 = details.values().map(|(bytes, ..)| bytes).sum();
+        assert_eq!(total as usize, peak_bytes);
+    }
+}
@@ -0,0 +1,67 @@
+//! Measuring Fil's tracking overhead on your own hardware.
+//!
+//! `fil-bench <workload> [seconds]` runs one of the workloads in
+//! pymemprofile_api::workloads over and over for the given time, 10 seconds by
+//! default, and prints how many operations per second the tracker handled
+//! and how much memory its own tables ended up using. Like Fil itself, the
+//! tracker is configured by FIL_* environment variables.
+
+use pymemprofile_api::workloads::{Bench, Workload};
+use std::process::ExitCode;
+use std::time::{Duration, Instant};
+
+fn usage() -> ExitCode {
+    let names: Vec<_> = Workload::ALL
+        .iter()
+        .map(|workload| workload.name())
+        .collect();
+    eprintln!("Usage: fil-bench <{}> [seconds]", names.join("|"));
+    ExitCode::from(2)
+}
+
+fn main() -> ExitCode {
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    let (workload, seconds) = match args.as_slice() {
+        [name] => (Workload::from_name(name), Some(10.0)),
+        [name, seconds] => (
+            Workload::from_name(name),
+            seconds.parse::<f64>().ok().filter(|seconds| *seconds > 0.0),
+        ),
+        _ => return usage(),
+    };
+    let (Some(workload), Some(seconds)) = (workload, seconds) else {
+        return usage();
+    };
+    let duration = Duration::from_secs_f64(seconds);
+
+    pyo3::prepare_freethreaded_python();
+    let directory = std::env::temp_dir().join(format!("fil-bench-{}", std::process::id()));
+    if let Err(e) = std::fs::create_dir_all(&directory) {
+        eprintln!("Couldn't create {:?}: {}", directory, e);
+        return ExitCode::FAILURE;
+    }
+    let setup_start = Instant::now();
+    let bench = Bench::new(workload, &directory);
+    let setup = setup_start.elapsed();
+
+    let start = Instant::now();
+    let mut rounds = 0u64;
+    let mut operations = 0u64;
+    while rounds == 0 || start.elapsed() < duration {
+        operations += bench.run_round();
+        rounds += 1;
+    }
+    let elapsed = start.elapsed();
+    let _ = std::fs::remove_dir_all(&directory);
+
+    println!("workload: {}", workload.name());
+    println!("setup: {:.2?}", setup);
+    println!("rounds: {} in {:.2?}", rounds, elapsed);
+    println!(
+        "{} per second: {:.0}",
+        workload.operation_name(),
+        operations as f64 / elapsed.as_secs_f64()
+    );
+    println!("tracker memory: {} KiB", bench.footprint_bytes() / 1024);
+    ExitCode::SUCCESS
+}
@@ -0,0 +1,41 @@
+//! Working with Fil's reports outside of Fil.
+//!
+//! `fil-report --validate peak.json [peak.json.zst ...]` checks JSON reports
+//! against the current schema, see pymemprofile_api::report_schema, so they
+//! can be verified before they're archived or processed. It exits with 1 if
+//! any of them are invalid.
+
+use pymemprofile_api::report_schema::validate;
+use std::process::ExitCode;
+
+const USAGE: &str = "Usage: fil-report --validate <peak.json or peak.json.zst>...";
+
+fn main() -> ExitCode {
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    let paths = match args.split_first() {
+        Some((command, paths)) if command == "--validate" && !paths.is_empty() => paths,
+        _ => {
+            eprintln!("{}", USAGE);
+            return ExitCode::from(2);
+        }
+    };
+    let mut result = ExitCode::SUCCESS;
+    for path in paths {
+        match std::fs::read(path)
+            .map_err(|e| e.to_string())
+            .and_then(|data| validate(&data))
+        {
+            Ok(report) => println!(
+                "{}: OK, format version {}, {} callstacks",
+                path,
+                report.format_version,
+                report.callstacks.len()
+            ),
+            Err(e) => {
+                eprintln!("{}: {}", path, e);
+                result = ExitCode::FAILURE;
+            }
+        }
+    }
+    result
+}
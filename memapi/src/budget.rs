@@ -0,0 +1,161 @@
+//! A limit on how much memory the tracker itself uses.
+//!
+//! A program with tens of millions of live allocations or distinct callstacks
+//! can make Fil's own bookkeeping grow to gigabytes, which distorts the very
+//! thing being measured and can run the machine out of memory. With
+//! FIL_TRACKER_BUDGET_MB set, the tracker periodically estimates its footprint
+//! from the sizes of its tables, and once the budget is exceeded it degrades:
+//! small allocations are sampled and rolled up into `[small allocations]`, and
+//! new callstacks are all attributed to `[callstack table full]`. The
+//! degradation is for good, and is noted in the report.
+
+use serde::Serialize;
+
+/// Only estimate the footprint once every this many allocations, since it's
+/// not free.
+const CHECK_EVERY: usize = 1024;
+
+/// The budget in bytes, given FIL_TRACKER_BUDGET_MB. One too big to count in
+/// bytes is warned about and treated as unlimited.
+fn limit_bytes(limit_mb: usize) -> usize {
+    limit_mb.checked_mul(1024 * 1024).unwrap_or_else(|| {
+        eprintln!(
+            "=fil-profile= Ignoring FIL_TRACKER_BUDGET_MB={}, it's too big, so the profiler's own memory use is unlimited",
+            limit_mb
+        );
+        0
+    })
+}
+
+/// Written to `metadata.json` if a budget was set.
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct TrackerBudgetMetadata {
+    pub limit_bytes: usize,
+    /// The estimated footprint when the report was written.
+    pub footprint_bytes: usize,
+    /// Whether the budget was exceeded at some point, in which case the
+    /// report is less detailed.
+    pub exceeded: bool,
+}
+
+impl TrackerBudgetMetadata {
+    /// The line printed in the text output.
+    pub fn summary(&self) -> String {
+        format!(
+            "WARNING: The profiler's own memory use exceeded FIL_TRACKER_BUDGET_MB={}, so small allocations were sampled and new callstacks reported as [callstack table full].",
+            self.limit_bytes / (1024 * 1024)
+        )
+    }
+}
+
+/// Decides when the tracker has outgrown its budget.
+pub struct TrackerBudget {
+    // 0 means unlimited:
+    limit_bytes: usize,
+    until_check: usize,
+    exceeded: bool,
+}
+
+impl TrackerBudget {
+    pub fn new(limit_bytes: usize) -> Self {
+        Self {
+            limit_bytes,
+            until_check: CHECK_EVERY,
+            exceeded: false,
+        }
+    }
+
+    /// Configure from FIL_TRACKER_BUDGET_MB; unlimited if unset.
+    pub fn from_env() -> Self {
+        Self::new(limit_bytes(
+            std::env::var("FIL_TRACKER_BUDGET_MB")
+                .ok()
+                .and_then(|value| value.parse::<usize>().ok())
+                .unwrap_or(0),
+        ))
+    }
+
+    /// Called on every allocation; true if it's time to call check().
+    #[inline]
+    pub fn due(&mut self) -> bool {
+        if self.limit_bytes == 0 || self.exceeded {
+            return false;
+        }
+        self.until_check -= 1;
+        if self.until_check == 0 {
+            self.until_check = CHECK_EVERY;
+            true
+        } else {
+            false
+        }
+    }
+
+    /// Given the estimated footprint, including room for the tables' next
+    /// growth, return true if the budget has just been exceeded, in which
+    /// case the caller should degrade.
+    pub fn check(&mut self, footprint_bytes: usize) -> bool {
+        if self.exceeded || self.limit_bytes == 0 || footprint_bytes <= self.limit_bytes {
+            return false;
+        }
+        self.exceeded = true;
+        eprintln!(
+            "=fil-profile= WARNING: The profiler's own memory use, estimated at {:.1} MiB allowing for growth, exceeds FIL_TRACKER_BUDGET_MB={}, so from now on small allocations will be sampled and new callstacks reported as [callstack table full].",
+            footprint_bytes as f64 / (1024.0 * 1024.0),
+            self.limit_bytes / (1024 * 1024),
+        );
+        true
+    }
+
+    pub fn is_exceeded(&self) -> bool {
+        self.exceeded
+    }
+
+    pub fn metadata(&self, footprint_bytes: usize) -> Option<TrackerBudgetMetadata> {
+        if self.limit_bytes == 0 {
+            return None;
+        }
+        Some(TrackerBudgetMetadata {
+            limit_bytes: self.limit_bytes,
+            footprint_bytes,
+            exceeded: self.exceeded,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{limit_bytes, TrackerBudget, CHECK_EVERY};
+
+    #[test]
+    fn checks_periodically() {
+        let mut budget = TrackerBudget::new(1000);
+        let due = (0..CHECK_EVERY * 3).filter(|_| budget.due()).count();
+        assert_eq!(due, 3);
+        assert!(!budget.check(1000));
+        assert!(budget.check(1001));
+        assert!(budget.is_exceeded());
+        // Only degrade once, and stop checking:
+        assert!(!budget.check(2000));
+        assert_eq!((0..CHECK_EVERY * 3).filter(|_| budget.due()).count(), 0);
+        assert!(budget.metadata(5).unwrap().exceeded);
+    }
+
+    #[test]
+    fn unlimited() {
+        let mut budget = TrackerBudget::new(0);
+        assert_eq!((0..CHECK_EVERY * 3).filter(|_| budget.due()).count(), 0);
+        assert!(!budget.check(usize::MAX));
+        assert_eq!(budget.metadata(5), None);
+    }
+
+    #[test]
+    fn too_big_is_unlimited() {
+        assert_eq!(limit_bytes(3), 3 * 1024 * 1024);
+        assert_eq!(
+            limit_bytes(usize::MAX / (1024 * 1024)),
+            usize::MAX & !0xFFFFF
+        );
+        assert_eq!(limit_bytes(usize::MAX / (1024 * 1024) + 1), 0);
+        assert_eq!(limit_bytes(usize::MAX), 0);
+    }
+}
@@ -0,0 +1,163 @@
+//! Detection of jemalloc and mimalloc.
+//!
+//! Programs and extensions that use these allocators' own APIs, e.g.
+//! mi_malloc(), bypass the malloc() Fil interposes, so their memory only
+//! partially shows up in the profile, as whatever mmap()s the allocator does.
+//! When the allocator is a shared library the LD_PRELOAD version can interpose
+//! its APIs too (FIL_BUNDLED_ALLOCATORS=1); either way the user should know.
+//!
+//! Allocators linked statically with hidden symbols, as is typical for Rust
+//! extensions, can't be detected or interposed.
+
+use parking_lot::Mutex;
+use serde::Serialize;
+use std::ffi::{CStr, CString};
+
+/// Allocator names and the symbol that identifies them.
+const ALLOCATORS: [(&str, &CStr); 2] = [("jemalloc", c"je_malloc"), ("mimalloc", c"mi_malloc")];
+
+/// An allocator found in a loaded library.
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct BundledAllocator {
+    pub name: &'static str,
+    /// Path of the library implementing it.
+    pub library: String,
+    /// Whether allocations made with it are tracked.
+    pub tracked: bool,
+}
+
+#[cfg(target_os = "linux")]
+fn loaded_libraries() -> Vec<CString> {
+    use libc::{c_int, c_void, dl_phdr_info, size_t};
+
+    unsafe extern "C" fn add_library(
+        info: *mut dl_phdr_info,
+        _size: size_t,
+        data: *mut c_void,
+    ) -> c_int {
+        let libraries = unsafe { &mut *(data as *mut Vec<CString>) };
+        let name = unsafe { (*info).dlpi_name };
+        if !name.is_null() {
+            let name = unsafe { CStr::from_ptr(name) };
+            // The main executable has an empty name:
+            if !name.is_empty() {
+                libraries.push(name.to_owned());
+            }
+        }
+        0
+    }
+
+    let mut libraries: Vec<CString> = vec![];
+    unsafe {
+        libc::dl_iterate_phdr(
+            Some(add_library),
+            &mut libraries as *mut Vec<CString> as *mut c_void,
+        );
+    }
+    libraries
+}
+
+#[cfg(target_os = "macos")]
+fn loaded_libraries() -> Vec<CString> {
+    let count = unsafe { libc::_dyld_image_count() };
+    (0..count)
+        .filter_map(|i| {
+            let name = unsafe { libc::_dyld_get_image_name(i) };
+            if name.is_null() {
+                None
+            } else {
+                Some(unsafe { CStr::from_ptr(name) }.to_owned())
+            }
+        })
+        .collect()
+}
+
+#[cfg(not(any(target_os = "linux", target_os = "macos")))]
+fn loaded_libraries() -> Vec<CString> {
+    vec![]
+}
+
+/// The base address and path of the library containing the given address.
+fn containing_library(address: *const libc::c_void) -> Option<(usize, String)> {
+    let mut info: libc::Dl_info = unsafe { std::mem::zeroed() };
+    if unsafe { libc::dladdr(address, &mut info) } == 0 || info.dli_fname.is_null() {
+        return None;
+    }
+    let path = unsafe { CStr::from_ptr(info.dli_fname) };
+    Some((info.dli_fbase as usize, path.to_string_lossy().into_owned()))
+}
+
+/// Find jemalloc and mimalloc in the currently loaded libraries, other than
+/// Fil's own interposers. `tracked` says whether their allocations are being
+/// tracked.
+///
+/// This uses dlopen(), so it must not be called with the tracker locked:
+/// another thread might be allocating while holding the dynamic loader's lock.
+pub fn detect(tracked: bool) -> Vec<BundledAllocator> {
+    let own_base = containing_library(detect as *const libc::c_void).map(|(base, _)| base);
+    let mut found: Vec<BundledAllocator> = vec![];
+    for library in loaded_libraries() {
+        let handle = unsafe { libc::dlopen(library.as_ptr(), libc::RTLD_LAZY | libc::RTLD_NOLOAD) };
+        if handle.is_null() {
+            continue;
+        }
+        for (name, symbol) in ALLOCATORS {
+            let address = unsafe { libc::dlsym(handle, symbol.as_ptr()) };
+            if address.is_null() {
+                continue;
+            }
+            // The library's dependencies are searched too, so this tells us
+            // where the allocator really is:
+            let Some((base, path)) = containing_library(address) else {
+                continue;
+            };
+            if Some(base) == own_base || found.iter().any(|a| a.library == path) {
+                continue;
+            }
+            found.push(BundledAllocator {
+                name,
+                library: path,
+                tracked,
+            });
+        }
+        unsafe { libc::dlclose(handle) };
+    }
+    found
+}
+
+lazy_static! {
+    static ref WARNED_LIBRARIES: Mutex<Vec<String>> = Mutex::new(vec![]);
+}
+
+/// Tell the user about allocators they haven't been told about yet.
+pub fn warn_once(allocators: &[BundledAllocator]) {
+    let mut warned = WARNED_LIBRARIES.lock();
+    for allocator in allocators {
+        if warned.contains(&allocator.library) {
+            continue;
+        }
+        warned.push(allocator.library.clone());
+        if allocator.tracked {
+            eprintln!(
+                "=fil-profile= Tracking allocations made with {} in {}.",
+                allocator.name, allocator.library
+            );
+        } else {
+            eprintln!(
+                "=fil-profile= WARNING: {} in {} allocates memory without going through malloc(), so its allocations are only partially tracked. Set FIL_BUNDLED_ALLOCATORS=1 to track them.",
+                allocator.name, allocator.library
+            );
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::detect;
+
+    #[test]
+    fn nothing_bundled() {
+        // The test binary uses the system allocator, and nothing else:
+        assert_eq!(detect(false), vec![]);
+    }
+}
@@ -0,0 +1,385 @@
+//! Watch cgroup (e.g. container) memory usage.
+//!
+//! In containers the process usually doesn't see malloc() fail when it runs
+//! out of memory: the kernel's OOM killer sends it SIGKILL first, and the
+//! report is lost. So we periodically compare the cgroup's memory usage to its
+//! limit, and warn the caller once it crosses a threshold, giving Fil a chance
+//! to write out a report first.
+//!
+//! Both cgroups v2 (`memory.max` and `memory.current`) and v1
+//! (`memory.limit_in_bytes` and `memory.usage_in_bytes`) are supported. Limits
+//! on ancestor cgroups apply too, so all the cgroups from the process' own up
+//! to the root of the mounted hierarchy are checked. If the files can't be
+//! found or read, the watchdog does nothing.
+
+use std::fs::read_to_string;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+/// How often usage should be checked.
+pub const CHECK_INTERVAL: Duration = Duration::from_millis(100);
+
+/// Default fraction of the limit at which we warn.
+const DEFAULT_THRESHOLD: f64 = 0.95;
+
+/// cgroups v1 reports "no limit" as a very large number, rounded down to the
+/// page size.
+const V1_UNLIMITED: u64 = 1 << 62;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Version {
+    V1,
+    V2,
+}
+
+impl Version {
+    fn limit_file(&self) -> &'static str {
+        match self {
+            Version::V1 => "memory.limit_in_bytes",
+            Version::V2 => "memory.max",
+        }
+    }
+
+    fn usage_file(&self) -> &'static str {
+        match self {
+            Version::V1 => "memory.usage_in_bytes",
+            Version::V2 => "memory.current",
+        }
+    }
+}
+
+/// A cgroup filesystem mount with the memory controller.
+#[derive(Debug, PartialEq)]
+struct Mount {
+    // The path within the hierarchy that is mounted:
+    root: String,
+    mount_point: PathBuf,
+    version: Version,
+}
+
+/// Find the memory cgroup mounts in the contents of /proc/self/mountinfo.
+fn parse_mountinfo(mountinfo: &str) -> Vec<Mount> {
+    let mut result = vec![];
+    for line in mountinfo.lines() {
+        // Optional fields end with a lone "-", followed by the filesystem
+        // type, source, and superblock options:
+        let Some((mount_fields, fs_fields)) = line.split_once(" - ") else {
+            continue;
+        };
+        let mount_fields: Vec<&str> = mount_fields.split(' ').collect();
+        let fs_fields: Vec<&str> = fs_fields.split(' ').collect();
+        if mount_fields.len() < 5 || fs_fields.len() < 3 {
+            continue;
+        }
+        let version = match fs_fields[0] {
+            "cgroup2" => Version::V2,
+            "cgroup" if fs_fields[2].split(',').any(|option| option == "memory") => Version::V1,
+            _ => continue,
+        };
+        result.push(Mount {
+            root: unescape(mount_fields[3]),
+            mount_point: PathBuf::from(unescape(mount_fields[4])),
+            version,
+        });
+    }
+    result
+}
+
+/// mountinfo escapes spaces and other whitespace as octal.
+fn unescape(field: &str) -> String {
+    field
+        .replace("\\040", " ")
+        .replace("\\011", "\t")
+        .replace("\\012", "\n")
+        .replace("\\134", "\\")
+}
+
+/// The directories of the memory cgroups this process is in, given the
+/// contents of /proc/self/cgroup and /proc/self/mountinfo, along with the
+/// mount point each is under.
+fn cgroup_directories(proc_cgroup: &str, mountinfo: &str) -> Vec<(PathBuf, PathBuf, Version)> {
+    let mounts = parse_mountinfo(mountinfo);
+    let mut result = vec![];
+    for line in proc_cgroup.lines() {
+        let mut parts = line.splitn(3, ':');
+        let (Some(hierarchy), Some(controllers), Some(path)) =
+            (parts.next(), parts.next(), parts.next())
+        else {
+            continue;
+        };
+        let version = if hierarchy == "0" && controllers.is_empty() {
+            Version::V2
+        } else if controllers.split(',').any(|c| c == "memory") {
+            Version::V1
+        } else {
+            continue;
+        };
+        let Some(mount) = mounts.iter().find(|mount| mount.version == version) else {
+            continue;
+        };
+        // Without a cgroup namespace, e.g. Docker on cgroups v1, the mount
+        // root may be the process' cgroup itself:
+        let relative = if mount.root == "/" {
+            path
+        } else {
+            path.strip_prefix(mount.root.as_str()).unwrap_or(path)
+        };
+        let directory = mount.mount_point.join(relative.trim_start_matches('/'));
+        result.push((directory, mount.mount_point.clone(), version));
+    }
+    result
+}
+
+/// Parse the contents of a limit file; None means unlimited.
+fn parse_limit(contents: &str) -> Option<u64> {
+    let contents = contents.trim();
+    if contents == "max" {
+        return None;
+    }
+    let limit: u64 = contents.parse().ok()?;
+    // A limit of 0 is nonsensical, and has been seen with cgroups v1 when no
+    // limit was set:
+    if limit == 0 || limit >= V1_UNLIMITED {
+        None
+    } else {
+        Some(limit)
+    }
+}
+
+fn read_number(path: &Path) -> Option<u64> {
+    read_to_string(path).ok()?.trim().parse().ok()
+}
+
+/// A cgroup with a memory limit.
+#[derive(Debug, PartialEq)]
+struct LimitedCgroup {
+    limit_path: PathBuf,
+    usage_path: PathBuf,
+}
+
+impl LimitedCgroup {
+    fn usage(&self) -> Option<CgroupMemoryUsage> {
+        let limit_bytes = parse_limit(&read_to_string(&self.limit_path).ok()?)?;
+        let usage_bytes = read_number(&self.usage_path)?;
+        Some(CgroupMemoryUsage {
+            usage_bytes,
+            limit_bytes,
+            limit_path: self.limit_path.clone(),
+        })
+    }
+}
+
+/// Find the cgroups, ours and its ancestors, that have a memory limit.
+fn limited_cgroups(directories: Vec<(PathBuf, PathBuf, Version)>) -> Vec<LimitedCgroup> {
+    let mut result = vec![];
+    for (directory, mount_point, version) in directories {
+        for ancestor in directory.ancestors() {
+            if !ancestor.starts_with(&mount_point) {
+                break;
+            }
+            let cgroup = LimitedCgroup {
+                limit_path: ancestor.join(version.limit_file()),
+                usage_path: ancestor.join(version.usage_file()),
+            };
+            if cgroup.usage().is_some() {
+                result.push(cgroup);
+            }
+        }
+    }
+    result
+}
+
+/// Memory usage of a cgroup.
+#[derive(Clone, Debug, PartialEq)]
+pub struct CgroupMemoryUsage {
+    pub usage_bytes: u64,
+    pub limit_bytes: u64,
+    /// Which limit this is, for debugging.
+    pub limit_path: PathBuf,
+}
+
+impl CgroupMemoryUsage {
+    fn fraction(&self) -> f64 {
+        self.usage_bytes as f64 / self.limit_bytes as f64
+    }
+}
+
+/// The lowest memory limit of the cgroups this process is in, if any.
+pub fn memory_limit() -> Option<u64> {
+    let proc_cgroup = read_to_string("/proc/self/cgroup").ok()?;
+    let mountinfo = read_to_string("/proc/self/mountinfo").ok()?;
+    limited_cgroups(cgroup_directories(&proc_cgroup, &mountinfo))
+        .iter()
+        .filter_map(|cgroup| Some(cgroup.usage()?.limit_bytes))
+        .min()
+}
+
+/// Checks whether cgroup memory usage is close to the limit.
+pub struct CgroupWatchdog {
+    cgroups: Vec<LimitedCgroup>,
+    threshold: f64,
+    triggered: bool,
+}
+
+impl CgroupWatchdog {
+    /// Find the current process' cgroup memory limits. Returns None if there
+    /// aren't any, they can't be read, or OOM detection is disabled. The
+    /// threshold can be set as a fraction of the limit with
+    /// FIL_CGROUP_THRESHOLD, e.g. 0.9.
+    pub fn from_env() -> Option<Self> {
+        if std::env::var("__FIL_DISABLE_OOM_DETECTION").as_deref() == Ok("1") {
+            return None;
+        }
+        let threshold = match std::env::var("FIL_CGROUP_THRESHOLD") {
+            Ok(value) => match value.parse::<f64>() {
+                Ok(threshold) if threshold > 0.0 && threshold <= 1.0 => threshold,
+                _ => {
+                    eprintln!(
+                        "=fil-profile= FIL_CGROUP_THRESHOLD should be a number between 0 and 1, using {} instead of {:?}",
+                        DEFAULT_THRESHOLD, value
+                    );
+                    DEFAULT_THRESHOLD
+                }
+            },
+            Err(_) => DEFAULT_THRESHOLD,
+        };
+        let proc_cgroup = read_to_string("/proc/self/cgroup").ok()?;
+        let mountinfo = read_to_string("/proc/self/mountinfo").ok()?;
+        Self::new(
+            limited_cgroups(cgroup_directories(&proc_cgroup, &mountinfo)),
+            threshold,
+        )
+    }
+
+    fn new(cgroups: Vec<LimitedCgroup>, threshold: f64) -> Option<Self> {
+        if cgroups.is_empty() {
+            return None;
+        }
+        Some(Self {
+            cgroups,
+            threshold,
+            triggered: false,
+        })
+    }
+
+    /// The fraction of the limit at which check() triggers.
+    pub fn threshold(&self) -> f64 {
+        self.threshold
+    }
+
+    /// Check memory usage. The first time usage crosses the threshold, returns
+    /// the cgroup in question; after that it returns None until rearm() is
+    /// called.
+    pub fn check(&mut self) -> Option<CgroupMemoryUsage> {
+        if self.triggered {
+            return None;
+        }
+        let usage = self
+            .cgroups
+            .iter()
+            .filter_map(|cgroup| cgroup.usage())
+            .find(|usage| usage.fraction() >= self.threshold)?;
+        self.triggered = true;
+        Some(usage)
+    }
+
+    /// Allow check() to trigger again, e.g. after the tracker is reset.
+    pub fn rearm(&mut self) {
+        self.triggered = false;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{cgroup_directories, limited_cgroups, parse_limit, CgroupWatchdog, Version};
+    use std::fs::{create_dir_all, write};
+    use std::path::PathBuf;
+
+    const MOUNTINFO: &str = "\
+24 30 0:22 / /sys rw,nosuid,nodev,noexec,relatime shared:7 - sysfs sysfs rw
+31 24 0:26 / /sys/fs/cgroup rw,nosuid,nodev,noexec,relatime shared:9 - cgroup2 cgroup2 rw,nsdelegate,memory_recursiveprot
+40 31 0:35 /docker/abc /sys/fs/cgroup/memory rw,nosuid - cgroup cgroup rw,memory
+41 31 0:36 / /sys/fs/cgroup/cpu rw,nosuid - cgroup cgroup rw,cpu,cpuacct
+";
+
+    #[test]
+    fn find_directories() {
+        let v2 = cgroup_directories("0::/user.slice/session-1.scope\n", MOUNTINFO);
+        assert_eq!(
+            v2,
+            vec![(
+                PathBuf::from("/sys/fs/cgroup/user.slice/session-1.scope"),
+                PathBuf::from("/sys/fs/cgroup"),
+                Version::V2
+            )]
+        );
+        // Mount root is stripped, and other controllers are ignored:
+        let v1 = cgroup_directories(
+            "5:cpu,cpuacct:/docker/abc\n4:memory:/docker/abc\n",
+            MOUNTINFO,
+        );
+        assert_eq!(
+            v1,
+            vec![(
+                PathBuf::from("/sys/fs/cgroup/memory"),
+                PathBuf::from("/sys/fs/cgroup/memory"),
+                Version::V1
+            )]
+        );
+        assert!(cgroup_directories("garbage", MOUNTINFO).is_empty());
+        assert!(cgroup_directories("0::/", "").is_empty());
+    }
+
+    #[test]
+    fn limits() {
+        assert_eq!(parse_limit("max\n"), None);
+        assert_eq!(parse_limit("1048576\n"), Some(1048576));
+        assert_eq!(parse_limit("9223372036854771712\n"), None);
+        assert_eq!(parse_limit("0"), None);
+        assert_eq!(parse_limit("nonsense"), None);
+    }
+
+    #[test]
+    fn nested_cgroups() {
+        let root = tempfile::tempdir().unwrap();
+        let mount_point = root.path().to_path_buf();
+        let parent = mount_point.join("parent");
+        let child = parent.join("child");
+        create_dir_all(&child).unwrap();
+        // The child has no limit, but the parent does:
+        write(child.join("memory.max"), "max\n").unwrap();
+        write(child.join("memory.current"), "100\n").unwrap();
+        write(parent.join("memory.max"), "1000\n").unwrap();
+        write(parent.join("memory.current"), "900\n").unwrap();
+
+        let cgroups = limited_cgroups(vec![(child.clone(), mount_point.clone(), Version::V2)]);
+        assert_eq!(cgroups.len(), 1);
+        let mut watchdog = CgroupWatchdog::new(cgroups, 0.95).unwrap();
+        assert_eq!(watchdog.check(), None);
+        write(parent.join("memory.current"), "960\n").unwrap();
+        let usage = watchdog.check().unwrap();
+        assert_eq!(usage.usage_bytes, 960);
+        assert_eq!(usage.limit_bytes, 1000);
+        assert_eq!(usage.limit_path, parent.join("memory.max"));
+        // Only triggers once, until rearmed:
+        assert_eq!(watchdog.check(), None);
+        watchdog.rearm();
+        assert!(watchdog.check().is_some());
+
+        // Unreadable files mean nothing is checked:
+        watchdog.rearm();
+        std::fs::remove_file(parent.join("memory.current")).unwrap();
+        assert_eq!(watchdog.check(), None);
+    }
+
+    #[test]
+    fn no_limits_means_no_watchdog() {
+        let root = tempfile::tempdir().unwrap();
+        let mount_point = root.path().to_path_buf();
+        write(mount_point.join("memory.max"), "max\n").unwrap();
+        write(mount_point.join("memory.current"), "100\n").unwrap();
+        let cgroups = limited_cgroups(vec![(mount_point.clone(), mount_point, Version::V2)]);
+        assert!(cgroups.is_empty());
+        assert!(CgroupWatchdog::new(cgroups, 0.95).is_none());
+    }
+}
@@ -0,0 +1,387 @@
+//! Noticing when tracked memory drifts away from what the allocator says is
+//! in use. If an allocation path is interposed but its matching free path
+//! isn't, or vice versa, the totals go slowly wrong without any error; this
+//! turns that into a warning.
+//!
+//! Opt-in, via FIL_DRIFT_CHECK=1, and only where crate::allocator_stats can
+//! query the allocator. Every FIL_DRIFT_INTERVAL_MS (5000 by default) the
+//! sampler thread compares tracked malloc() bytes, i.e. leaving out anonymous
+//! mmap()s, with the allocator's in-use bytes. The difference at the first
+//! sample is the baseline: memory allocated before tracking started, or by
+//! Fil itself. The divergence is how far the difference has moved since,
+//! allowing for the allocator's per-allocation overhead, relative to the
+//! larger of the two numbers. Once it's over FIL_DRIFT_RATIO (0.5 by default)
+//! and MIN_DIVERGENCE_BYTES for FIL_DRIFT_SAMPLES (3 by default) samples in a
+//! row, a warning is printed, once, and the report metadata records it.
+//! Samples taken while adaptive sampling is engaged are skipped, since tracked
+//! bytes are then an estimate.
+
+use serde::Serialize;
+use std::collections::VecDeque;
+use std::time::{Duration, Instant};
+
+const DEFAULT_INTERVAL: Duration = Duration::from_millis(5000);
+const DEFAULT_RATIO: f64 = 0.5;
+const DEFAULT_SAMPLES: usize = 3;
+
+/// Smaller divergence is never reported, whatever the ratio.
+pub const MIN_DIVERGENCE_BYTES: u64 = 16 * 1024 * 1024;
+
+/// The most the allocator is assumed to use on top of each allocation, e.g.
+/// glibc's chunk header plus rounding up to 16 bytes.
+const OVERHEAD_PER_ALLOCATION: u64 = 32;
+
+/// How many recent samples are kept for the trend.
+const TREND_SAMPLES: usize = 10;
+
+/// What was measured at one point in time.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct DriftReading {
+    /// Bytes Fil tracks as allocated with malloc() and friends.
+    pub tracked_bytes: u64,
+    /// How many allocations Fil tracks as live.
+    pub live_allocations: u64,
+    /// Bytes the allocator says are in use.
+    pub allocator_bytes: u64,
+}
+
+/// One sample, relative to the baseline.
+#[derive(Clone, Copy, Debug, PartialEq, Serialize)]
+pub struct DriftSample {
+    pub seconds: f64,
+    pub tracked_bytes: u64,
+    pub allocator_bytes: u64,
+    /// Positive if the allocator has more in use than tracking accounts for,
+    /// suggesting missed allocations; negative if less, suggesting missed
+    /// frees.
+    pub divergence_bytes: i64,
+    pub divergence_ratio: f64,
+}
+
+/// Recorded when the divergence stayed over the threshold.
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct DriftWarning {
+    /// The sample that triggered it.
+    pub sample: DriftSample,
+    /// Recent divergence ratios, oldest first, signed like divergence_bytes.
+    pub trend: Vec<f64>,
+}
+
+impl DriftWarning {
+    /// The warning printed to stderr.
+    pub fn summary(&self, settings: &DriftSettings) -> String {
+        let mib = |bytes: u64| bytes as f64 / (1024.0 * 1024.0);
+        let (more_or_less, likely) = if self.sample.divergence_bytes >= 0 {
+            ("more", "allocations that aren't being tracked")
+        } else {
+            ("less", "frees that aren't being tracked")
+        };
+        let trend: Vec<String> = self
+            .trend
+            .iter()
+            .map(|ratio| format!("{:+.0}%", ratio * 100.0))
+            .collect();
+        format!(
+            "Tracked memory has drifted from what the allocator reports: it has {:.1} MiB {} in use than tracking accounts for ({:.0}% divergence, over {:.0}% for {} samples in a row; recent: {}). This suggests {}, so the report may be wrong. Please file a bug at https://github.com/pythonspeed/filprofiler/issues/new/choose.",
+            mib(self.sample.divergence_bytes.unsigned_abs()),
+            more_or_less,
+            self.sample.divergence_ratio * 100.0,
+            settings.ratio * 100.0,
+            settings.samples,
+            trend.join(", "),
+            likely
+        )
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Serialize)]
+pub struct DriftSettings {
+    #[serde(skip)]
+    pub interval: Duration,
+    /// Divergence ratio that counts as drift.
+    pub ratio: f64,
+    /// How many samples in a row it has to be over the ratio.
+    pub samples: usize,
+}
+
+/// Drift detection results, as written to `metadata.json`.
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct DriftReport {
+    pub settings: DriftSettings,
+    /// How many samples were compared.
+    pub samples: u64,
+    /// The largest divergence seen, signed like divergence_bytes.
+    pub max_divergence_ratio: f64,
+    /// Set if the divergence stayed over the threshold.
+    pub warning: Option<DriftWarning>,
+}
+
+struct Baseline {
+    // allocator_bytes - tracked_bytes:
+    difference: i64,
+    live_allocations: u64,
+}
+
+/// Compares successive readings, see the module documentation.
+pub struct DriftDetector {
+    settings: DriftSettings,
+    start: Instant,
+    baseline: Option<Baseline>,
+    samples: u64,
+    // Consecutive samples over the threshold:
+    over_threshold: usize,
+    max_divergence_ratio: f64,
+    recent: VecDeque<DriftSample>,
+    warning: Option<DriftWarning>,
+}
+
+impl DriftDetector {
+    pub fn new(settings: DriftSettings) -> Self {
+        Self {
+            settings,
+            start: Instant::now(),
+            baseline: None,
+            samples: 0,
+            over_threshold: 0,
+            max_divergence_ratio: 0.0,
+            recent: VecDeque::with_capacity(TREND_SAMPLES),
+            warning: None,
+        }
+    }
+
+    /// Create one if FIL_DRIFT_CHECK=1 is set; FIL_DRIFT_INTERVAL_MS,
+    /// FIL_DRIFT_RATIO and FIL_DRIFT_SAMPLES override the defaults.
+    pub fn from_env() -> Option<Self> {
+        if std::env::var("FIL_DRIFT_CHECK").as_deref() != Ok("1") {
+            return None;
+        }
+        fn setting<T: std::str::FromStr + PartialOrd + Default>(name: &str) -> Option<T> {
+            std::env::var(name)
+                .ok()
+                .and_then(|value| value.parse().ok())
+                .filter(|value| *value > T::default())
+        }
+        Some(Self::new(DriftSettings {
+            interval: setting("FIL_DRIFT_INTERVAL_MS")
+                .map(Duration::from_millis)
+                .unwrap_or(DEFAULT_INTERVAL),
+            ratio: setting("FIL_DRIFT_RATIO").unwrap_or(DEFAULT_RATIO),
+            samples: setting("FIL_DRIFT_SAMPLES").unwrap_or(DEFAULT_SAMPLES),
+        }))
+    }
+
+    pub fn settings(&self) -> &DriftSettings {
+        &self.settings
+    }
+
+    /// Compare a new reading. Returns the warning if this is the sample that
+    /// triggered it; later samples are still recorded, but don't warn again.
+    pub fn observe(&mut self, reading: DriftReading) -> Option<&DriftWarning> {
+        let seconds = self.start.elapsed().as_secs_f64();
+        self.observe_at(seconds, reading)
+    }
+
+    fn observe_at(&mut self, seconds: f64, reading: DriftReading) -> Option<&DriftWarning> {
+        let difference = reading.allocator_bytes as i64 - reading.tracked_bytes as i64;
+        let baseline = self.baseline.get_or_insert(Baseline {
+            difference,
+            live_allocations: reading.live_allocations,
+        });
+        let mut divergence_bytes = difference - baseline.difference;
+        if divergence_bytes > 0 {
+            // More allocations mean more overhead the allocator counts as used:
+            let allowance = reading
+                .live_allocations
+                .saturating_sub(baseline.live_allocations)
+                * OVERHEAD_PER_ALLOCATION;
+            divergence_bytes = (divergence_bytes - allowance as i64).max(0);
+        }
+        let divergence_ratio = divergence_bytes as f64
+            / reading.tracked_bytes.max(reading.allocator_bytes).max(1) as f64;
+        let sample = DriftSample {
+            seconds,
+            tracked_bytes: reading.tracked_bytes,
+            allocator_bytes: reading.allocator_bytes,
+            divergence_bytes,
+            divergence_ratio,
+        };
+        self.samples += 1;
+        if divergence_ratio.abs() > self.max_divergence_ratio.abs() {
+            self.max_divergence_ratio = divergence_ratio;
+        }
+        if self.recent.len() == TREND_SAMPLES {
+            self.recent.pop_front();
+        }
+        self.recent.push_back(sample);
+        if divergence_ratio.abs() >= self.settings.ratio
+            && divergence_bytes.unsigned_abs() >= MIN_DIVERGENCE_BYTES
+        {
+            self.over_threshold += 1;
+        } else {
+            self.over_threshold = 0;
+        }
+        if self.over_threshold >= self.settings.samples && self.warning.is_none() {
+            self.warning = Some(DriftWarning {
+                sample,
+                trend: self
+                    .recent
+                    .iter()
+                    .map(|sample| sample.divergence_ratio)
+                    .collect(),
+            });
+            return self.warning.as_ref();
+        }
+        None
+    }
+
+    pub fn report(&self) -> DriftReport {
+        DriftReport {
+            settings: self.settings,
+            samples: self.samples,
+            max_divergence_ratio: self.max_divergence_ratio,
+            warning: self.warning.clone(),
+        }
+    }
+
+    /// Start over, e.g. when tracking is reset.
+    pub fn reset(&mut self) {
+        *self = Self::new(self.settings);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{DriftDetector, DriftReading, DriftSettings, MIN_DIVERGENCE_BYTES};
+    use std::time::Duration;
+
+    const MIB: u64 = 1024 * 1024;
+
+    fn detector() -> DriftDetector {
+        DriftDetector::new(DriftSettings {
+            interval: Duration::from_secs(1),
+            ratio: 0.5,
+            samples: 3,
+        })
+    }
+
+    fn reading(tracked_mib: u64, allocator_mib: u64) -> DriftReading {
+        DriftReading {
+            tracked_bytes: tracked_mib * MIB,
+            live_allocations: 1000,
+            allocator_bytes: allocator_mib * MIB,
+        }
+    }
+
+    #[test]
+    fn baseline_is_subtracted() {
+        let mut detector = detector();
+        // 50MiB allocated before tracking started:
+        for i in 0..10 {
+            assert!(detector
+                .observe_at(i as f64, reading(100 + 10 * i, 150 + 10 * i))
+                .is_none());
+        }
+        let report = detector.report();
+        assert_eq!(report.samples, 10);
+        assert_eq!(report.max_divergence_ratio, 0.0);
+        assert_eq!(report.warning, None);
+    }
+
+    #[test]
+    fn missed_frees_warn_once() {
+        let mut detector = detector();
+        detector.observe_at(0.0, reading(100, 100));
+        // Frees aren't seen, so tracked keeps growing while the allocator
+        // doesn't:
+        assert!(detector.observe_at(1.0, reading(200, 100)).is_none());
+        assert!(detector.observe_at(2.0, reading(300, 100)).is_none());
+        let warning = detector.observe_at(3.0, reading(400, 100)).unwrap().clone();
+        assert_eq!(warning.sample.divergence_bytes, -300 * MIB as i64);
+        assert_eq!(warning.sample.divergence_ratio, -0.75);
+        assert_eq!(warning.trend, vec![0.0, -0.5, -200.0 / 300.0, -0.75]);
+        let summary = warning.summary(detector.settings());
+        assert!(summary.contains("300.0 MiB less in use"), "{}", summary);
+        assert!(
+            summary.contains("recent: +0%, -50%, -67%, -75%"),
+            "{}",
+            summary
+        );
+        assert!(summary.contains("frees that aren't being tracked"));
+        // Only warns once, but keeps track:
+        assert!(detector.observe_at(4.0, reading(800, 100)).is_none());
+        let report = detector.report();
+        assert_eq!(report.warning, Some(warning));
+        assert_eq!(report.max_divergence_ratio, -0.875);
+    }
+
+    #[test]
+    fn needs_consecutive_samples() {
+        let mut detector = detector();
+        detector.observe_at(0.0, reading(100, 100));
+        for i in 1..10 {
+            // Missed allocations, but it keeps recovering:
+            let allocator = if i % 3 == 0 { 100 } else { 300 };
+            assert!(detector
+                .observe_at(i as f64, reading(100, allocator))
+                .is_none());
+        }
+        assert!(detector.report().max_divergence_ratio > 0.6);
+        detector.observe_at(10.0, reading(100, 300));
+        detector.observe_at(11.0, reading(100, 300));
+        let warning = detector
+            .observe_at(12.0, reading(100, 300))
+            .unwrap()
+            .clone();
+        assert!(warning
+            .summary(detector.settings())
+            .contains("allocations that aren't being tracked"));
+    }
+
+    #[test]
+    fn small_or_expected_divergence_is_ignored() {
+        let mut tiny = detector();
+        tiny.observe_at(0.0, reading(1, 1));
+        // Tiny programs can easily diverge by a large ratio:
+        for i in 1..10 {
+            let bytes = MIN_DIVERGENCE_BYTES - 1;
+            assert!(tiny
+                .observe_at(
+                    i as f64,
+                    DriftReading {
+                        tracked_bytes: MIB,
+                        live_allocations: 0,
+                        allocator_bytes: MIB + bytes,
+                    }
+                )
+                .is_none());
+        }
+        // Many new small allocations come with allocator overhead:
+        let mut with_overhead = detector();
+        with_overhead.observe_at(0.0, reading(100, 100));
+        for i in 1..10 {
+            let reading = DriftReading {
+                tracked_bytes: 100 * MIB,
+                live_allocations: 1000 + 5_000_000,
+                allocator_bytes: 100 * MIB + 5_000_000 * 32,
+            };
+            assert!(with_overhead.observe_at(i as f64, reading).is_none());
+        }
+        assert_eq!(with_overhead.report().max_divergence_ratio, 0.0);
+    }
+
+    #[test]
+    fn reset() {
+        let mut detector = detector();
+        detector.observe_at(0.0, reading(100, 100));
+        for i in 1..4 {
+            detector.observe_at(i as f64, reading(400, 100));
+        }
+        assert!(detector.report().warning.is_some());
+        detector.reset();
+        // New baseline:
+        detector.observe_at(0.0, reading(400, 100));
+        let report = detector.report();
+        assert_eq!(report.samples, 1);
+        assert_eq!(report.warning, None);
+    }
+}
@@ -0,0 +1,167 @@
+//! What was profiled and where, so a months-old report can still be
+//! reproduced: Python and Fil versions, the machine, the FIL_* configuration,
+//! the command line, and any tags the user attached with
+//! `filprofiler.api.add_metadata()`.
+//!
+//! It's captured when tracking is (re)started, and included in
+//! `metadata.json`, the HTML report, a comment in the SVGs, and `# key=value`
+//! header lines in the `.prof` files.
+
+use serde::Serialize;
+use std::collections::BTreeMap;
+
+#[derive(Clone, Debug, Default, PartialEq, Serialize)]
+pub struct Environment {
+    /// Passed in by the Python wrapper, since it knows best.
+    pub python_version: Option<String>,
+    /// The version of the Rust tracking code.
+    pub memapi_version: String,
+    pub os: String,
+    /// The kernel name and release, e.g. "Linux 6.1.0".
+    pub kernel: Option<String>,
+    pub cpu_count: Option<usize>,
+    /// The lowest cgroup memory limit that applies to this process, if any.
+    pub cgroup_memory_limit_bytes: Option<u64>,
+    /// Every FIL_* environment variable that was set.
+    pub configuration: BTreeMap<String, String>,
+    pub command_line: Vec<String>,
+    pub working_directory: Option<String>,
+    /// Tags attached by the user, e.g. a git commit.
+    pub user: BTreeMap<String, String>,
+}
+
+impl Environment {
+    /// Capture everything about the current process. The Python version and
+    /// user tags are kept, since they can be set before tracking starts.
+    pub fn capture(&mut self) {
+        self.memapi_version = env!("CARGO_PKG_VERSION").to_string();
+        self.os = std::env::consts::OS.to_string();
+        self.kernel = kernel();
+        self.cpu_count = std::thread::available_parallelism()
+            .map(|count| count.get())
+            .ok();
+        self.cgroup_memory_limit_bytes = crate::cgroup::memory_limit();
+        self.configuration = std::env::vars_os()
+            .filter_map(|(key, value)| {
+                let key = key.into_string().ok()?;
+                if !key.starts_with("FIL_") {
+                    return None;
+                }
+                Some((key, value.to_string_lossy().into_owned()))
+            })
+            .collect();
+        self.command_line = std::env::args_os()
+            .map(|arg| arg.to_string_lossy().into_owned())
+            .collect();
+        self.working_directory = std::env::current_dir()
+            .ok()
+            .map(|path| path.to_string_lossy().into_owned());
+    }
+
+    /// `# key=value` lines to put at the top of `.prof` files. Values are
+    /// compact JSON, so a line never ends in a number and tools that read the
+    /// collapsed stack format will skip it rather than parse it as a
+    /// callstack.
+    pub fn header_lines(&self) -> Vec<String> {
+        let serde_json::Value::Object(fields) =
+            serde_json::to_value(self).unwrap_or(serde_json::Value::Null)
+        else {
+            return vec![];
+        };
+        fields
+            .into_iter()
+            .map(|(key, value)| format!("# {}={}", key, value))
+            .collect()
+    }
+
+    /// An XML comment to embed in SVGs.
+    pub fn svg_comment(&self) -> String {
+        let json = serde_json::to_string(self).unwrap_or_default();
+        // XML comments can't contain "--"; every "-" is inside a JSON string,
+        // so it can be escaped without changing what the JSON decodes to:
+        format!(
+            "<!-- Fil environment: {} -->\n",
+            json.replace('-', "\\u002d")
+        )
+    }
+}
+
+/// The kernel name and release, from uname().
+fn kernel() -> Option<String> {
+    let mut name: libc::utsname = unsafe { std::mem::zeroed() };
+    if unsafe { libc::uname(&mut name) } != 0 {
+        return None;
+    }
+    let field = |chars: &[libc::c_char]| {
+        unsafe { std::ffi::CStr::from_ptr(chars.as_ptr()) }
+            .to_string_lossy()
+            .into_owned()
+    };
+    Some(format!("{} {}", field(&name.sysname), field(&name.release)))
+}
+
+/// Insert an SVG comment after the XML declaration, or at the start if there
+/// isn't one.
+pub fn add_svg_comment(svg: Vec<u8>, comment: &str) -> Vec<u8> {
+    let insert_at = if svg.starts_with(b"<?xml") {
+        svg.iter()
+            .position(|byte| *byte == b'>')
+            .map(|position| position + 1)
+            .unwrap_or(0)
+    } else {
+        0
+    };
+    let mut result = Vec::with_capacity(svg.len() + comment.len() + 1);
+    result.extend_from_slice(&svg[..insert_at]);
+    if insert_at > 0 {
+        result.push(b'\n');
+    }
+    result.extend_from_slice(comment.as_bytes());
+    result.extend_from_slice(&svg[insert_at..]);
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{add_svg_comment, Environment};
+
+    #[test]
+    fn header_lines_arent_callstacks() {
+        let mut environment = Environment::default();
+        environment.capture();
+        environment.python_version = Some("3.11.7 (main)".to_string());
+        environment
+            .user
+            .insert("dataset".to_string(), "sample 100".to_string());
+        assert!(environment.cpu_count.is_some());
+        assert_eq!(environment.memapi_version, env!("CARGO_PKG_VERSION"));
+        let lines = environment.header_lines();
+        assert!(lines.contains(&"# python_version=\"3.11.7 (main)\"".to_string()));
+        assert!(lines.contains(&"# user={\"dataset\":\"sample 100\"}".to_string()));
+        for line in lines {
+            assert!(line.starts_with("# "));
+            let last = line.rsplit(' ').next().unwrap();
+            assert!(last.parse::<usize>().is_err(), "{}", line);
+        }
+    }
+
+    #[test]
+    fn svg_comment() {
+        let environment = Environment {
+            command_line: vec!["fil-profile".to_string(), "--no-browser".to_string()],
+            ..Default::default()
+        };
+        let svg = add_svg_comment(
+            b"<?xml version=\"1.0\"?><svg></svg>".to_vec(),
+            &environment.svg_comment(),
+        );
+        let svg = String::from_utf8(svg).unwrap();
+        assert!(svg.starts_with("<?xml version=\"1.0\"?>\n<!-- Fil environment: {"));
+        assert!(svg.ends_with(" -->\n<svg></svg>"));
+        let comment = &svg["<?xml version=\"1.0\"?>\n<!-- ".len()..svg.len() - 16];
+        assert!(!comment.contains("--"));
+        let json: serde_json::Value =
+            serde_json::from_str(comment.strip_prefix("Fil environment: ").unwrap()).unwrap();
+        assert_eq!(json["command_line"][1], "--no-browser");
+    }
+}
@@ -58,3 +58,47 @@ unsafe impl Sync for Libc {}
 pub fn initialize() {
     Lazy::force(&LIBC);
 }
+
+// Room for the mapping's length, keeping the string 16-byte aligned:
+const UNTRACKED_HEADER_SIZE: usize = 16;
+
+/// Copy the data into a new NUL-terminated string, allocated directly with
+/// libc's mmap() so it's never seen by the tracker. Returns NULL if the
+/// allocation fails. Free it with free_untracked_c_string().
+pub fn untracked_c_string(data: &[u8]) -> *mut libc::c_char {
+    let length = UNTRACKED_HEADER_SIZE + data.len() + 1;
+    unsafe {
+        let base = (LIBC.mmap)(
+            std::ptr::null_mut(),
+            length,
+            libc::PROT_READ | libc::PROT_WRITE,
+            libc::MAP_PRIVATE | libc::MAP_ANONYMOUS,
+            -1,
+            0,
+        );
+        if base == libc::MAP_FAILED {
+            return std::ptr::null_mut();
+        }
+        (base as *mut usize).write(length);
+        let string = (base as *mut u8).add(UNTRACKED_HEADER_SIZE);
+        std::ptr::copy_nonoverlapping(data.as_ptr(), string, data.len());
+        // Anonymous mappings are zeroed, so it's already NUL-terminated.
+        string as *mut libc::c_char
+    }
+}
+
+/// Free a string created by untracked_c_string(). NULL is ignored.
+///
+/// # Safety
+/// The pointer must be NULL or come from untracked_c_string(), and not have
+/// been freed already.
+pub unsafe fn free_untracked_c_string(string: *mut libc::c_char) {
+    if string.is_null() {
+        return;
+    }
+    unsafe {
+        let base = (string as *mut u8).sub(UNTRACKED_HEADER_SIZE);
+        let length = (base as *const usize).read();
+        (LIBC.munmap)(base as *mut c_void, length);
+    }
+}
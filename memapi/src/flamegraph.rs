@@ -1,13 +1,38 @@
-use std::{borrow::Cow, fs, io::Write, path::Path};
+use std::{borrow::Cow, collections::HashMap, collections::HashSet, fs, io::Write, path::Path};
 
+use ahash::RandomState as ARandomState;
 use inferno::flamegraph;
 use itertools::Itertools;
 
 use crate::{
+    environment::{add_svg_comment, Environment},
+    gzip::GzipWriter,
     linecache::LineCacher,
-    memorytracking::{Callstack, ReadFunctionLocations},
+    memorytracking::{Callstack, LineNumberInfo, ReadFunctionLocations},
+    report_schema::PeakReport,
+    util::{
+        remove_stale_temporary_files, write_atomically, write_atomically_with,
+        write_file_atomically_with,
+    },
 };
 
+/// Whether memory flamegraphs should leave out line numbers, merging all the
+/// lines of a function into one frame, via FIL_AGGREGATE_LINES=1. Checked when
+/// the report is written, since the tracked data is the same either way.
+pub fn aggregate_lines() -> bool {
+    std::env::var("FIL_AGGREGATE_LINES").as_deref() == Ok("1")
+}
+
+/// Whether reports must be byte-for-byte identical for identical data, via
+/// FIL_DETERMINISTIC=1. Flamegraph input is then sorted, and any colors
+/// chosen by frame name are hashed rather than random. Currently inferno sorts
+/// its input anyway, and Fil colors frames by width, so this is so we don't
+/// depend on either. Checked when the report is written; the timestamp in the
+/// HTML report is handled on the Python side.
+pub fn deterministic() -> bool {
+    std::env::var("FIL_DETERMINISTIC").as_deref() == Ok("1")
+}
+
 /// Filter down to top 99% of samples.
 ///
 /// 1. Empty samples are dropped.
@@ -55,25 +80,84 @@ where
 
 /// Write strings to disk, one line per string.
 pub fn write_lines<I: IntoIterator<Item = String>>(lines: I, path: &Path) -> std::io::Result<()> {
-    let mut file = std::fs::File::create(path)?;
+    write_atomically_with(path, |file| lines_to(lines, file))
+}
+
+/// Like write_lines(), for files that are deleted once the report is written,
+/// so they're not handed to the output sink, see crate::sinks.
+fn write_scratch_lines<I: IntoIterator<Item = String>>(
+    lines: I,
+    path: &Path,
+) -> std::io::Result<()> {
+    write_file_atomically_with(path, |file| lines_to(lines, file))
+}
+
+fn lines_to<I: IntoIterator<Item = String>>(lines: I, file: &mut dyn Write) -> std::io::Result<()> {
     for line in lines {
         file.write_all(line.as_bytes())?;
         file.write_all(b"\n")?;
     }
-    file.flush()?;
     Ok(())
 }
 
+/// Write strings to a gzipped file, one line per string.
+fn write_gzipped_lines<I: IntoIterator<Item = String>>(
+    lines: I,
+    path: &Path,
+) -> std::io::Result<()> {
+    write_atomically_with(path, |file| {
+        let mut gzipped = GzipWriter::new(file)?;
+        for line in lines {
+            gzipped.write_all(line.as_bytes())?;
+            gzipped.write_all(b"\n")?;
+        }
+        gzipped.finish()?;
+        Ok(())
+    })
+}
+
+/// Bytes attributed to a single line of code, see
+/// FlamegraphCallstacks::function_table().
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct FunctionTableRow {
+    pub filename: String,
+    pub function: String,
+    pub line: u32,
+    /// Bytes allocated directly by this line.
+    pub self_bytes: usize,
+    /// Bytes allocated by this line or anything it called.
+    pub inclusive_bytes: usize,
+}
+
 /// A strategy for cleaning up callstacks before rendering them to text.
 pub trait CallstackCleaner {
     fn cleanup<'a>(&self, callstack: &'a Callstack) -> Cow<'a, Callstack>;
 }
 
+/// What's known about a callstack besides its bytes, for the Arrow table.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct CallstackDetails {
+    /// Live malloc()-style allocations; mmap()s aren't counted.
+    pub allocation_count: usize,
+    /// The thread that added memory with this callstack, None if several did.
+    pub thread_id: Option<u64>,
+}
+
 /// The data needed to create a flamegraph.
 pub struct FlamegraphCallstacks<D, FL: ReadFunctionLocations, UC> {
     data: D,
     functions: FL,
     callstack_cleaner: UC,
+    // If set, root each callstack under a frame for its phase:
+    phase_names: Option<Vec<String>>,
+    // If set, embedded in the .prof files and SVGs:
+    environment: Option<Environment>,
+    // If set, all the callstacks before they were merged to fit the report
+    // budget, see crate::report_budget:
+    full_data: Option<D>,
+    // If set, per-callstack details, see CallstackDetails. Callstacks merged
+    // to fit the report budget aren't in it:
+    details: Option<HashMap<Callstack, CallstackDetails, ARandomState>>,
 }
 
 impl<'a, D, FL, UC> FlamegraphCallstacks<D, FL, UC>
@@ -89,6 +173,56 @@ where
             data,
             functions,
             callstack_cleaner,
+            phase_names: None,
+            environment: None,
+            full_data: None,
+            details: None,
+        }
+    }
+
+    /// Root each callstack under a `[phase: <name>]` frame, given the phase
+    /// names indexed by phase id - 1.
+    pub fn with_phase_frames(mut self, phase_names: Vec<String>) -> Self {
+        self.phase_names = Some(phase_names);
+        self
+    }
+
+    /// Record where the profile came from in the files that get written, see
+    /// crate::environment.
+    pub fn with_environment(mut self, environment: Environment) -> Self {
+        self.environment = Some(environment);
+        self
+    }
+
+    /// Also write out the given unmerged callstacks, to a gzipped .prof.gz
+    /// file, see crate::report_budget.
+    pub fn with_full_data(mut self, full_data: D) -> Self {
+        self.full_data = Some(full_data);
+        self
+    }
+
+    /// Per-callstack details beyond the bytes, keyed like the data.
+    pub fn with_details(
+        mut self,
+        details: HashMap<Callstack, CallstackDetails, ARandomState>,
+    ) -> Self {
+        self.details = Some(details);
+        self
+    }
+
+    /// The .prof header lines, if there's an environment.
+    fn header_lines(&self) -> Vec<String> {
+        self.environment
+            .as_ref()
+            .map(|environment| environment.header_lines())
+            .unwrap_or_default()
+    }
+
+    /// Add the environment comment to an SVG, if there's an environment.
+    fn annotate_svg(&self, svg: Vec<u8>) -> Vec<u8> {
+        match &self.environment {
+            Some(environment) => add_svg_comment(svg, &environment.svg_comment()),
+            None => svg,
         }
     }
 
@@ -98,11 +232,24 @@ where
         &'a self,
         to_be_post_processed: bool,
     ) -> impl ExactSizeIterator<Item = String> + 'a {
-        let by_call = (&self.data).into_iter();
+        self.lines_for(&self.data, to_be_post_processed)
+    }
+
+    fn lines_for(
+        &'a self,
+        data: &'a D,
+        to_be_post_processed: bool,
+    ) -> impl ExactSizeIterator<Item = String> + 'a {
+        let by_call = data.into_iter();
         let mut linecache = LineCacher::default();
         by_call.map(move |(callstack, size)| {
+            let phase_frame = self
+                .phase_frame(callstack)
+                .map(|frame| format!("{};", frame))
+                .unwrap_or_default();
             format!(
-                "{} {}",
+                "{}{} {}",
+                phase_frame,
                 self.callstack_cleaner.cleanup(callstack).as_string(
                     to_be_post_processed,
                     &self.functions,
@@ -114,6 +261,137 @@ where
         })
     }
 
+    /// The `[phase: <name>]` root frame for the callstack, if phases are shown.
+    fn phase_frame(&self, callstack: &Callstack) -> Option<String> {
+        self.phase_name(callstack)
+            .map(|name| format!("[phase: {}]", name))
+    }
+
+    /// The name of the callstack's phase, if phases are shown.
+    fn phase_name(&self, callstack: &Callstack) -> Option<&str> {
+        self.phase_names
+            .as_ref()
+            .and_then(|names| names.get((callstack.phase() as usize).checked_sub(1)?))
+            .map(String::as_str)
+    }
+
+    /// The machine-readable version of the report, see crate::report_schema.
+    pub fn to_json_report(&'a self, peak_bytes: usize) -> PeakReport {
+        let mut linecache = LineCacher::default();
+        let callstacks = (&self.data).into_iter().map(|(callstack, size)| {
+            let mut frames: Vec<String> = self.phase_frame(callstack).into_iter().collect();
+            frames.extend(
+                self.callstack_cleaner
+                    .cleanup(callstack)
+                    .as_string(false, &self.functions, "\n", &mut linecache)
+                    .split('\n')
+                    .map(str::to_string),
+            );
+            (frames, *size)
+        });
+        PeakReport::new(peak_bytes, !aggregate_lines(), callstacks)
+    }
+
+    /// Write the report as an Arrow IPC file, see crate::arrow.
+    #[cfg(feature = "arrow-export")]
+    pub fn write_arrow(&'a self, path: &Path) -> std::io::Result<()> {
+        let mut rows: Vec<crate::arrow::PeakRow> = (&self.data)
+            .into_iter()
+            .map(|(callstack, size)| {
+                let details = self
+                    .details
+                    .as_ref()
+                    .and_then(|details| details.get(callstack));
+                crate::arrow::PeakRow {
+                    frames: self
+                        .callstack_cleaner
+                        .cleanup(callstack)
+                        .structured_frames(&self.functions),
+                    bytes: *size as u64,
+                    allocation_count: details.map(|details| details.allocation_count as u64),
+                    thread_id: details.and_then(|details| details.thread_id),
+                    phase: self.phase_name(callstack).map(str::to_string),
+                }
+            })
+            .collect();
+        // Same order as peak.json:
+        rows.sort_by(|a, b| b.bytes.cmp(&a.bytes).then_with(|| a.frames.cmp(&b.frames)));
+        crate::arrow::write_peak_table(path, &rows)
+    }
+
+    #[cfg(not(feature = "arrow-export"))]
+    pub fn write_arrow(&'a self, _path: &Path) -> std::io::Result<()> {
+        Err(std::io::Error::new(
+            std::io::ErrorKind::Unsupported,
+            "Fil was built without the arrow-export feature",
+        ))
+    }
+
+    /// Bytes per line of code, both self (where it's the innermost frame) and
+    /// inclusive (where it's anywhere in the callstack), sorted by inclusive
+    /// bytes. A line that appears multiple times in one callstack, e.g. due to
+    /// recursion, only counts that callstack's bytes once.
+    pub fn function_table(&'a self) -> Vec<FunctionTableRow> {
+        let mut rows: HashMap<(&str, &str, u32), FunctionTableRow> = HashMap::new();
+        let mut seen = HashSet::new();
+        for (callstack, size) in &self.data {
+            let callstack = self.callstack_cleaner.cleanup(callstack);
+            let frames = callstack.python_frames(&self.functions);
+            seen.clear();
+            let count = frames.len();
+            for (i, (id, (function, _, display_filename))) in frames.into_iter().enumerate() {
+                let line = match id.line_number {
+                    LineNumberInfo::LineNumber(line) => line,
+                    LineNumberInfo::BytecodeIndex(_)
+                    | LineNumberInfo::Omitted
+                    | LineNumberInfo::Synthetic => 0,
+                };
+                let key = (display_filename, function, line);
+                let row = rows.entry(key).or_insert_with(|| FunctionTableRow {
+                    filename: display_filename.to_string(),
+                    function: function.to_string(),
+                    line,
+                    self_bytes: 0,
+                    inclusive_bytes: 0,
+                });
+                if seen.insert(key) {
+                    row.inclusive_bytes += size;
+                }
+                if i == count - 1 {
+                    row.self_bytes += size;
+                }
+            }
+        }
+        let mut rows: Vec<FunctionTableRow> = rows.into_values().collect();
+        rows.sort_by(|a, b| {
+            b.inclusive_bytes
+                .cmp(&a.inclusive_bytes)
+                .then(b.self_bytes.cmp(&a.self_bytes))
+                .then_with(|| {
+                    (&a.filename, &a.function, a.line).cmp(&(&b.filename, &b.function, b.line))
+                })
+        });
+        rows
+    }
+
+    /// Write function_table() as tab-separated values.
+    pub fn write_function_table(&'a self, path: &Path) -> std::io::Result<()> {
+        // Tabs and newlines would break the format, unlikely as they are:
+        let clean = |s: &str| s.replace(['\t', '\n'], " ");
+        let header = "inclusive_bytes\tself_bytes\tfilename\tfunction\tline".to_string();
+        let rows = self.function_table().into_iter().map(|row| {
+            format!(
+                "{}\t{}\t{}\t{}\t{}",
+                row.inclusive_bytes,
+                row.self_bytes,
+                clean(&row.filename),
+                clean(&row.function),
+                row.line
+            )
+        });
+        write_lines(std::iter::once(header).chain(rows), path)
+    }
+
     /// Low-level interface for writing flamegraphs with post-processing:
     pub fn get_flamegraph_with_options(
         &'a self,
@@ -157,8 +435,7 @@ where
     ) -> Result<(), Box<dyn std::error::Error>> {
         let flamegraph =
             self.get_flamegraph(reversed, title, subtitle, count_name, to_be_post_processed)?;
-        let mut file = std::fs::File::create(path)?;
-        file.write_all(&flamegraph)?;
+        write_atomically(path, self.annotate_svg(flamegraph))?;
         Ok(())
     }
 
@@ -177,6 +454,8 @@ where
                 .expect("=fil-profile= Couldn't create the output directory.");
         } else if !directory_path.is_dir() {
             panic!("=fil-profile= Output path must be a directory.");
+        } else {
+            remove_stale_temporary_files(directory_path);
         }
 
         let raw_path_without_source_code = directory_path.join(format!("{}.prof", base_filename));
@@ -185,16 +464,38 @@ where
             directory_path.join(format!("{}-source.prof", base_filename));
 
         // Always write .prof file without source code, for use by tests and
-        // other automated post-processing.
-        if let Err(e) = write_lines(self.to_lines(false), &raw_path_without_source_code) {
+        // other automated post-processing. Lines are sorted so it's the same
+        // for the same data.
+        if let Err(e) = write_lines(
+            self.header_lines()
+                .into_iter()
+                .chain(self.to_lines(false).sorted()),
+            &raw_path_without_source_code,
+        ) {
             eprintln!("=fil-profile= Error writing raw profiling data: {}", e);
             return;
         }
 
+        if let Some(full_data) = &self.full_data {
+            let full_path = directory_path.join(format!("{}-full.prof.gz", base_filename));
+            let lines = self
+                .header_lines()
+                .into_iter()
+                .chain(self.lines_for(full_data, false).sorted());
+            match write_gzipped_lines(lines, &full_path) {
+                Ok(_) => eprintln!("=fil-profile= Wrote all callstacks to {:?}", full_path),
+                Err(e) => eprintln!("=fil-profile= Error writing {:?}: {}", full_path, e),
+            }
+        }
+
         // Optionally write version with source code for SVGs, if we're using
         // source code.
         if to_be_post_processed {
-            if let Err(e) = write_lines(self.to_lines(true), &raw_path_with_source_code) {
+            let mut lines: Vec<String> = self.to_lines(true).collect();
+            if deterministic() {
+                lines.sort();
+            }
+            if let Err(e) = write_scratch_lines(lines, &raw_path_with_source_code) {
                 eprintln!("=fil-profile= Error writing raw profiling data: {}", e);
                 return;
             }
@@ -237,6 +538,89 @@ where
             let _ = std::fs::remove_file(raw_path_with_source_code);
         }
     }
+
+    /// Render the memory flamegraph SVG in memory, with the standard Fil title
+    /// and subtitle. If it's bigger than max_bytes (unless that's 0), it's
+    /// re-rendered without source code and with progressively more of the
+    /// narrower frames pruned, until it fits; if it never does, an error is
+    /// returned.
+    pub fn get_memory_flamegraph(
+        &'a self,
+        title: &str,
+        allocated_bytes: usize,
+        to_be_post_processed: bool,
+        max_bytes: usize,
+    ) -> Result<Vec<u8>, Box<dyn std::error::Error>> {
+        let title = memory_title(title, allocated_bytes);
+        let full = self.get_flamegraph(
+            false,
+            &title,
+            MEMORY_SUBTITLE,
+            "bytes",
+            to_be_post_processed,
+        )?;
+        let full = self.annotate_svg(full);
+        if max_bytes == 0 || full.len() <= max_bytes {
+            return Ok(full);
+        }
+        for min_width in PRUNED_MIN_WIDTHS {
+            // Source code is left out, but post-processing is still needed for
+            // the subtitle:
+            let mut options = flamegraph_options(false, &title, "bytes", to_be_post_processed);
+            options.min_width = min_width;
+            options.pretty_xml = false;
+            let pruned = get_flamegraph_with_options(
+                self.to_lines(false),
+                to_be_post_processed,
+                options,
+                Some(MEMORY_SUBTITLE),
+            )?;
+            let pruned = self.annotate_svg(pruned);
+            if pruned.len() <= max_bytes {
+                return Ok(pruned);
+            }
+        }
+        Err(format!("flamegraph doesn't fit in {} bytes", max_bytes).into())
+    }
+
+    /// Write the flamegraphs for a memory report, with the standard Fil title
+    /// and subtitle.
+    pub fn write_memory_flamegraphs(
+        &'a self,
+        directory_path: &Path,
+        base_filename: &str,
+        title: &str,
+        allocated_bytes: usize,
+        to_be_post_processed: bool,
+    ) {
+        eprintln!(
+            "=fil-profile= Preparing to write to {}",
+            directory_path.display()
+        );
+        let title = memory_title(title, allocated_bytes);
+        self.write_flamegraphs(
+            directory_path,
+            base_filename,
+            &title,
+            MEMORY_SUBTITLE,
+            "bytes",
+            to_be_post_processed,
+        )
+    }
+}
+
+const MEMORY_SUBTITLE: &str = r#"Made with the Fil profiler. <a href="https://pythonspeed.com/fil/" style="text-decoration: underline;" target="_parent">Try it on your code!</a>"#;
+
+/// Minimum frame widths, as a percentage of the total, to try in order when a
+/// flamegraph is too big; the default is 0.2.
+const PRUNED_MIN_WIDTHS: [f64; 4] = [0.5, 1.0, 2.0, 5.0];
+
+fn memory_title(title: &str, allocated_bytes: usize) -> String {
+    format!(
+        "{} ({:.1} MiB)",
+        title,
+        allocated_bytes as f64 / (1024.0 * 1024.0)
+    )
 }
 
 /// Low-level interface for writing flamegraphs with post-processing:
@@ -248,7 +632,11 @@ pub fn get_flamegraph_with_options<I: IntoIterator<Item = String>>(
     subtitle: Option<&str>,
 ) -> Result<Vec<u8>, Box<dyn std::error::Error>> {
     let mut output = vec![];
-    let lines: Vec<String> = lines.into_iter().collect();
+    let mut lines: Vec<String> = lines.into_iter().collect();
+    if deterministic() {
+        lines.sort();
+        options.deterministic = true;
+    }
     match flamegraph::from_lines(&mut options, lines.iter().map(|s| s.as_ref()), &mut output) {
         Err(e) => Err(format!("{}", e).into()),
         Ok(_) => {
@@ -282,6 +670,17 @@ pub fn get_flamegraph<I: IntoIterator<Item = String>>(
     count_name: &str,
     to_be_post_processed: bool,
 ) -> Result<Vec<u8>, Box<dyn std::error::Error>> {
+    let options = flamegraph_options(reversed, title, count_name, to_be_post_processed);
+    get_flamegraph_with_options(lines, to_be_post_processed, options, Some(subtitle))
+}
+
+/// The options Fil renders flamegraphs with.
+fn flamegraph_options(
+    reversed: bool,
+    title: &str,
+    count_name: &str,
+    to_be_post_processed: bool,
+) -> flamegraph::Options<'static> {
     let title = format!("{}{}", title, if reversed { ", Reversed" } else { "" },);
     let mut options = flamegraph::Options::default();
     options.title = title;
@@ -300,15 +699,142 @@ pub fn get_flamegraph<I: IntoIterator<Item = String>>(
         // Can't put structured text into subtitle, so have to do a hack.
         options.subtitle = Some("__FIL-SUBTITLE-HERE__".to_string());
     }
-    get_flamegraph_with_options(lines, to_be_post_processed, options, Some(subtitle))
+    options
 }
 
 #[cfg(test)]
 mod tests {
-    use super::filter_to_useful_callstacks;
+    use super::{filter_to_useful_callstacks, FlamegraphCallstacks, FunctionTableRow};
+    use crate::memorytracking::{
+        CallSiteId, Callstack, IdentityCleaner, LineNumberInfo::LineNumber, VecFunctionLocations,
+    };
+    use crate::report_schema::JsonReport;
     use im::HashMap;
     use itertools::Itertools;
     use proptest::prelude::*;
+    use rusty_fork::rusty_fork_test;
+
+    #[test]
+    fn function_table() {
+        pyo3::prepare_freethreaded_python();
+        let mut functions = VecFunctionLocations::new();
+        let main = functions.add_function("a.py".to_string(), "main".to_string());
+        let recurse = functions.add_function("a.py".to_string(), "recurse".to_string());
+        let callstack = |calls: &[(_, u32)]| {
+            let mut cs = Callstack::new();
+            for (function, line) in calls {
+                cs.start_call(0, CallSiteId::new(*function, LineNumber(*line)));
+            }
+            cs
+        };
+        let data: std::collections::HashMap<Callstack, usize> = [
+            // recurse() calls itself from line 5, and allocates on line 6:
+            (
+                callstack(&[(main, 1), (recurse, 5), (recurse, 5), (recurse, 6)]),
+                100,
+            ),
+            (callstack(&[(main, 1), (recurse, 6)]), 10),
+            (callstack(&[(main, 2)]), 1),
+        ]
+        .into_iter()
+        .collect();
+        let flamegraph = FlamegraphCallstacks::new(data, functions, IdentityCleaner);
+        let row = |function: &str, line, self_bytes, inclusive_bytes| FunctionTableRow {
+            filename: "a.py".to_string(),
+            function: function.to_string(),
+            line,
+            self_bytes,
+            inclusive_bytes,
+        };
+        assert_eq!(
+            flamegraph.function_table(),
+            vec![
+                // Recursion doesn't double count:
+                row("recurse", 6, 110, 110),
+                row("main", 1, 0, 110),
+                row("recurse", 5, 0, 100),
+                row("main", 2, 1, 1),
+            ]
+        );
+    }
+
+    #[test]
+    fn memory_flamegraph_size_cap() {
+        pyo3::prepare_freethreaded_python();
+        let mut functions = VecFunctionLocations::new();
+        let main = functions.add_function("a.py".to_string(), "main".to_string());
+        // One big callstack, and lots of small ones that are wide enough to be
+        // rendered by default (0.4% of the total), but can be pruned:
+        let mut data: std::collections::HashMap<Callstack, usize> =
+            std::collections::HashMap::new();
+        data.insert(
+            Callstack::from_vec(vec![CallSiteId::new(main, LineNumber(1))]),
+            10_000,
+        );
+        for i in 0..50 {
+            let function = functions.add_function("b.py".to_string(), format!("tiny{}", i));
+            let cs = Callstack::from_vec(vec![
+                CallSiteId::new(main, LineNumber(2)),
+                CallSiteId::new(function, LineNumber(i)),
+            ]);
+            data.insert(cs, 50);
+        }
+        let flamegraph = FlamegraphCallstacks::new(data, functions, IdentityCleaner);
+        let full = flamegraph
+            .get_memory_flamegraph("Peak", 12_500, false, 0)
+            .unwrap();
+        let full = String::from_utf8(full).unwrap();
+        assert!(full.contains("tiny7"));
+
+        let capped = flamegraph
+            .get_memory_flamegraph("Peak", 12_500, false, full.len() - 1)
+            .unwrap();
+        assert!(capped.len() < full.len());
+        let capped = String::from_utf8(capped).unwrap();
+        assert!(capped.contains("a.py:1 (main)"));
+        assert!(!capped.contains("tiny7"));
+
+        assert!(flamegraph
+            .get_memory_flamegraph("Peak", 12_500, false, 100)
+            .is_err());
+    }
+
+    rusty_fork_test! {
+        /// With FIL_DETERMINISTIC=1, the same data gives byte-for-byte
+        /// identical files, regardless of hash map iteration order.
+        #[test]
+        fn deterministic_output() {
+            pyo3::prepare_freethreaded_python();
+            std::env::set_var("FIL_DETERMINISTIC", "1");
+            let write_report = |directory: &std::path::Path| {
+                let mut functions = VecFunctionLocations::new();
+                let main = functions.add_function("a.py".to_string(), "main".to_string());
+                let mut data = std::collections::HashMap::new();
+                for i in 0..100 {
+                    let function = functions.add_function("b.py".to_string(), format!("f{}", i));
+                    let cs = Callstack::from_vec(vec![
+                        CallSiteId::new(main, LineNumber(i % 7)),
+                        CallSiteId::new(function, LineNumber(i)),
+                    ]);
+                    data.insert(cs, 1000 + (i as usize % 3));
+                }
+                let flamegraph = FlamegraphCallstacks::new(data, functions, IdentityCleaner);
+                flamegraph.write_memory_flamegraphs(directory, "peak-memory", "Peak", 200_000, false);
+                flamegraph
+                    .to_json_report(200_000)
+                    .write(directory, JsonReport::Plain)
+                    .unwrap();
+            };
+            let first = tempfile::tempdir().unwrap();
+            let second = tempfile::tempdir().unwrap();
+            write_report(first.path());
+            write_report(second.path());
+            for filename in ["peak-memory.prof", "peak-memory.svg", "peak-memory-reversed.svg", "peak.json"] {
+                let read = |directory: &tempfile::TempDir| std::fs::read(directory.path().join(filename)).unwrap();
+                assert!(read(&first) == read(&second), "{} differs", filename);
+            }
+        }
+    }
 
     proptest! {
         #[test]
@@ -320,7 +846,7 @@ mod tests {
             let total_size_99 = (99 * total_size) / 100;
             let callstacks = allocated_sizes.iter().enumerate();
             let filtered : HashMap<usize,usize>  = filter_to_useful_callstacks(callstacks, total_size).collect();
-            let filtered_size :usize = filtered.values().into_iter().sum();
+            let filtered_size :usize = filtered.values().sum();
             if filtered_size >= total_size_99  {
                 if filtered.len() > 100 {
                     // Removing any item should take us to or below 99%
@@ -0,0 +1,291 @@
+//! Making frame names readable, and the same across machines.
+//!
+//! Native frames can have mangled C++ or Rust symbols as their function names,
+//! and paths differ from machine to machine depending on where the virtualenv
+//! lives, or which temporary directory pip built an extension in. Each of
+//! these transformations is opt-in:
+//!
+//! * FIL_DEMANGLE=1 demangles C++ and Rust symbols.
+//! * FIL_SHORTEN_PATHS=1 replaces everything up to `site-packages/` or
+//!   `dist-packages/` with `<site>`, and pip's temporary build directories
+//!   with `<build>`. FIL_SITE_PREFIXES and FIL_BUILD_PREFIXES are
+//!   `:`-separated lists of additional prefixes to replace.
+//! * FIL_COLLAPSE_INIT=1 shows `mypackage/__init__.py` as `mypackage`.
+//!
+//! They're applied once, when a function is first registered. Only the
+//! displayed filename changes; the original is still used to read source
+//! lines.
+
+use once_cell::sync::Lazy;
+use std::borrow::Cow;
+use std::ffi::{c_char, c_int, c_void, CStr, CString};
+
+/// Leftovers of `pip install`, e.g. `/tmp/pip-build-abc123`.
+const PIP_BUILD_DIRECTORIES: [&str; 3] = ["pip-build-", "pip-req-build-", "pip-install-"];
+
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct FrameNames {
+    pub demangle: bool,
+    pub shorten_paths: bool,
+    pub site_prefixes: Vec<String>,
+    pub build_prefixes: Vec<String>,
+    pub collapse_init: bool,
+}
+
+impl FrameNames {
+    /// Configure from FIL_DEMANGLE, FIL_SHORTEN_PATHS, FIL_SITE_PREFIXES,
+    /// FIL_BUILD_PREFIXES and FIL_COLLAPSE_INIT; by default names are left
+    /// alone.
+    pub fn from_env() -> Self {
+        let flag = |name| std::env::var(name).as_deref() == Ok("1");
+        let prefixes = |name| {
+            std::env::var(name)
+                .map(|value| {
+                    value
+                        .split(':')
+                        .map(|prefix| prefix.trim_end_matches('/'))
+                        .filter(|prefix| !prefix.is_empty())
+                        .map(|prefix| prefix.to_string())
+                        .collect()
+                })
+                .unwrap_or_default()
+        };
+        Self {
+            demangle: flag("FIL_DEMANGLE"),
+            shorten_paths: flag("FIL_SHORTEN_PATHS"),
+            site_prefixes: prefixes("FIL_SITE_PREFIXES"),
+            build_prefixes: prefixes("FIL_BUILD_PREFIXES"),
+            collapse_init: flag("FIL_COLLAPSE_INIT"),
+        }
+    }
+
+    /// The function name to show.
+    pub fn function<'a>(&self, name: &'a str) -> Cow<'a, str> {
+        if self.demangle {
+            if let Some(demangled) = demangle(name) {
+                return Cow::Owned(demangled);
+            }
+        }
+        Cow::Borrowed(name)
+    }
+
+    /// The filename to show.
+    pub fn filename<'a>(&self, path: &'a str) -> Cow<'a, str> {
+        let mut result = Cow::Borrowed(path);
+        if self.shorten_paths {
+            if let Some(shortened) = self.shorten_path(path) {
+                result = Cow::Owned(shortened);
+            }
+        }
+        if self.collapse_init {
+            if let Some(package) = result.strip_suffix("/__init__.py") {
+                if !package.is_empty() {
+                    result = Cow::Owned(package.to_string());
+                }
+            }
+        }
+        result
+    }
+
+    fn shorten_path(&self, path: &str) -> Option<String> {
+        // Configured prefixes first, since they're more specific:
+        for (prefixes, replacement) in [
+            (&self.site_prefixes, "<site>"),
+            (&self.build_prefixes, "<build>"),
+        ] {
+            for prefix in prefixes {
+                if let Some(rest) = path.strip_prefix(prefix.as_str()) {
+                    if rest.starts_with('/') {
+                        return Some(format!("{}{}", replacement, rest));
+                    }
+                }
+            }
+        }
+        for marker in ["/site-packages/", "/dist-packages/"] {
+            if let Some(index) = path.rfind(marker) {
+                return Some(format!("<site>/{}", &path[index + marker.len()..]));
+            }
+        }
+        let mut offset = 0;
+        for component in path.split('/') {
+            offset += component.len();
+            if PIP_BUILD_DIRECTORIES
+                .iter()
+                .any(|directory| component.starts_with(directory))
+            {
+                return Some(format!("<build>{}", &path[offset..]));
+            }
+            offset += 1;
+        }
+        None
+    }
+}
+
+/// Demangle a Rust or C++ symbol; None if it isn't one.
+fn demangle(name: &str) -> Option<String> {
+    // Legacy Rust symbols are valid C++ symbols too, so try Rust first; the
+    // alternate format leaves out the hash:
+    if let Ok(demangled) = rustc_demangle::try_demangle(name) {
+        let demangled = format!("{:#}", demangled);
+        if demangled != name {
+            return Some(demangled);
+        }
+    }
+    if name.starts_with("_Z") {
+        return demangle_cxx(name);
+    }
+    None
+}
+
+type CxaDemangle =
+    unsafe extern "C" fn(*const c_char, *mut c_char, *mut usize, *mut c_int) -> *mut c_char;
+
+/// __cxa_demangle() from the C++ runtime, if there is one. It's usually
+/// already loaded by any C++ extension; if not, try loading it.
+static CXA_DEMANGLE: Lazy<Option<CxaDemangle>> = Lazy::new(|| unsafe {
+    let name = c"__cxa_demangle";
+    let mut symbol = libc::dlsym(libc::RTLD_DEFAULT, name.as_ptr());
+    if symbol.is_null() {
+        let library = if cfg!(target_os = "macos") {
+            c"libc++abi.dylib"
+        } else {
+            c"libstdc++.so.6"
+        };
+        let handle = libc::dlopen(library.as_ptr(), libc::RTLD_NOW | libc::RTLD_LOCAL);
+        if !handle.is_null() {
+            symbol = libc::dlsym(handle, name.as_ptr());
+        }
+    }
+    if symbol.is_null() {
+        None
+    } else {
+        Some(std::mem::transmute::<*mut c_void, CxaDemangle>(symbol))
+    }
+});
+
+fn demangle_cxx(name: &str) -> Option<String> {
+    let cxa_demangle = (*CXA_DEMANGLE)?;
+    let name = CString::new(name).ok()?;
+    let mut status: c_int = 0;
+    let demangled = unsafe {
+        cxa_demangle(
+            name.as_ptr(),
+            std::ptr::null_mut(),
+            std::ptr::null_mut(),
+            &mut status,
+        )
+    };
+    if demangled.is_null() {
+        return None;
+    }
+    let result = unsafe { CStr::from_ptr(demangled) }
+        .to_string_lossy()
+        .into_owned();
+    unsafe { libc::free(demangled as *mut c_void) };
+    if status == 0 {
+        Some(result)
+    } else {
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::FrameNames;
+
+    #[test]
+    fn demangling() {
+        let names = FrameNames {
+            demangle: true,
+            ..Default::default()
+        };
+        assert_eq!(
+            names.function("_ZN4core3ptr13drop_in_place17h0123456789abcdefE"),
+            "core::ptr::drop_in_place"
+        );
+        assert_eq!(names.function("_RNvCs1234_7mycrate3foo"), "mycrate::foo");
+        assert_eq!(
+            names.function("_ZNSt6vectorIiSaIiEE9push_backERKi"),
+            "std::vector<int, std::allocator<int> >::push_back(int const&)"
+        );
+        // Python functions and malformed symbols are left alone:
+        assert_eq!(names.function("load_data"), "load_data");
+        assert_eq!(names.function("_Zzz"), "_Zzz");
+        assert_eq!(names.function("<module>"), "<module>");
+        // Off by default:
+        assert_eq!(
+            FrameNames::default().function("_ZNSt6vectorIiSaIiEE9push_backERKi"),
+            "_ZNSt6vectorIiSaIiEE9push_backERKi"
+        );
+    }
+
+    #[test]
+    fn shortening_paths() {
+        let names = FrameNames {
+            shorten_paths: true,
+            site_prefixes: vec!["/opt/vendored".to_string()],
+            build_prefixes: vec!["/home/ci/src".to_string()],
+            ..Default::default()
+        };
+        assert_eq!(
+            names.filename("/home/me/.venv/lib/python3.11/site-packages/pandas/core/frame.py"),
+            "<site>/pandas/core/frame.py"
+        );
+        assert_eq!(
+            names.filename("/usr/lib/python3/dist-packages/numpy/core/numeric.py"),
+            "<site>/numpy/core/numeric.py"
+        );
+        assert_eq!(
+            names.filename("/tmp/pip-build-abc123/numpy/core/src/multiarray/ctors.c"),
+            "<build>/numpy/core/src/multiarray/ctors.c"
+        );
+        assert_eq!(
+            names.filename("/tmp/pip-req-build-x_9z/src/ext.cpp"),
+            "<build>/src/ext.cpp"
+        );
+        assert_eq!(
+            names.filename("/opt/vendored/lib/thing.py"),
+            "<site>/lib/thing.py"
+        );
+        assert_eq!(
+            names.filename("/home/ci/src/ext/module.c"),
+            "<build>/ext/module.c"
+        );
+        // Prefixes only match whole path components:
+        assert_eq!(
+            names.filename("/opt/vendored2/thing.py"),
+            "/opt/vendored2/thing.py"
+        );
+        assert_eq!(names.filename("myscript.py"), "myscript.py");
+        // Synthetic frames are left alone:
+        assert_eq!(names.filename("[phase]"), "[phase]");
+        assert_eq!(
+            FrameNames::default().filename("/tmp/pip-build-abc123/x.c"),
+            "/tmp/pip-build-abc123/x.c"
+        );
+    }
+
+    #[test]
+    fn collapsing_init() {
+        let names = FrameNames {
+            collapse_init: true,
+            ..Default::default()
+        };
+        assert_eq!(
+            names.filename("/srv/app/mypackage/__init__.py"),
+            "/srv/app/mypackage"
+        );
+        assert_eq!(names.filename("/srv/app/init.py"), "/srv/app/init.py");
+        assert_eq!(names.filename("/__init__.py"), "/__init__.py");
+        // Combined with shortened paths:
+        let names = FrameNames {
+            shorten_paths: true,
+            collapse_init: true,
+            ..Default::default()
+        };
+        assert_eq!(
+            names.filename("/venv/lib/python3.12/site-packages/numpy/__init__.py"),
+            "<site>/numpy"
+        );
+    }
+}
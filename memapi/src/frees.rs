@@ -0,0 +1,243 @@
+//! Where memory gets freed.
+//!
+//! Opt-in, since it means figuring out the callstack on every free(), not just
+//! every allocation. For each free we record the callstack that freed the
+//! memory and the callstack that allocated it, so we can show a flamegraph of
+//! bytes freed per freeing callstack, and which allocation sites are freed
+//! from where.
+
+use crate::flamegraph::FlamegraphCallstacks;
+use crate::linecache::LineCacher;
+use crate::memorytracking::{Callstack, CallstackId, IdentityCleaner, ReadFunctionLocations};
+use crate::util::{new_hashmap, write_atomically};
+use ahash::RandomState as ARandomState;
+use serde::Serialize;
+use std::collections::HashMap;
+use std::path::Path;
+
+/// How many (allocation site, free site) pairs to include in the report.
+const MAX_REPORTED_PAIRS: usize = 50;
+
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+struct PairStats {
+    frees: u64,
+    bytes: usize,
+}
+
+/// Records which callstacks free memory.
+pub struct FreeTracker {
+    // Indexed by the freeing CallstackId:
+    bytes_freed: Vec<usize>,
+    // (allocating CallstackId, freeing CallstackId) -> stats:
+    pairs: HashMap<(CallstackId, CallstackId), PairStats, ARandomState>,
+}
+
+impl Default for FreeTracker {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl FreeTracker {
+    pub fn new() -> Self {
+        Self {
+            bytes_freed: vec![],
+            pairs: new_hashmap(),
+        }
+    }
+
+    /// Record memory allocated by one callstack being freed by another.
+    pub fn record(&mut self, allocated_by: CallstackId, freed_by: CallstackId, bytes: usize) {
+        let index = freed_by as usize;
+        if index >= self.bytes_freed.len() {
+            self.bytes_freed.resize(index + 1, 0);
+        }
+        self.bytes_freed[index] += bytes;
+        let pair = self.pairs.entry((allocated_by, freed_by)).or_default();
+        pair.frees += 1;
+        pair.bytes += bytes;
+    }
+
+    /// Gather the data for the report; resolving callstacks into strings is
+    /// done later by the returned closure, so it can happen without locks
+    /// held.
+    pub fn report<FL: ReadFunctionLocations>(
+        &self,
+        id_to_callstack: &HashMap<CallstackId, &Callstack, ARandomState>,
+    ) -> impl FnOnce(FL) -> FreesReport<FL> {
+        let get = |id: CallstackId| id_to_callstack.get(&id).map(|cs| (*cs).clone());
+        let by_freeing_callstack: HashMap<Callstack, usize, ARandomState> = self
+            .bytes_freed
+            .iter()
+            .enumerate()
+            .filter(|(_, bytes)| **bytes > 0)
+            .filter_map(|(id, bytes)| get(id as CallstackId).map(|cs| (cs, *bytes)))
+            .collect();
+        let total_bytes = self.bytes_freed.iter().sum();
+        let mut pairs: Vec<_> = self.pairs.iter().collect();
+        pairs.sort_by_key(|(_, stats)| std::cmp::Reverse(stats.bytes));
+        let pairs: Vec<(Callstack, Callstack, PairStats)> = pairs
+            .into_iter()
+            .filter_map(|((allocated_by, freed_by), stats)| {
+                Some((get(*allocated_by)?, get(*freed_by)?, *stats))
+            })
+            .take(MAX_REPORTED_PAIRS)
+            .collect();
+        move |functions| {
+            let mut linecache = LineCacher::default();
+            let pairs = pairs
+                .into_iter()
+                .map(|(allocated_by, freed_by, stats)| FreePairReport {
+                    allocated_by: allocated_by.as_string(false, &functions, ";", &mut linecache),
+                    freed_by: freed_by.as_string(false, &functions, ";", &mut linecache),
+                    frees: stats.frees,
+                    bytes: stats.bytes,
+                })
+                .collect();
+            FreesReport {
+                flamegraph: FlamegraphCallstacks::new(
+                    by_freeing_callstack,
+                    functions,
+                    IdentityCleaner,
+                ),
+                total_bytes,
+                pairs: FreePairsReport { pairs },
+            }
+        }
+    }
+}
+
+#[derive(Debug, Serialize)]
+pub struct FreePairReport {
+    pub allocated_by: String,
+    pub freed_by: String,
+    pub frees: u64,
+    pub bytes: usize,
+}
+
+#[derive(Debug, Serialize)]
+pub struct FreePairsReport {
+    /// The top (allocation site, free site) pairs, by bytes freed.
+    pub pairs: Vec<FreePairReport>,
+}
+
+impl FreePairsReport {
+    /// A human-readable table.
+    pub fn to_table(&self) -> String {
+        let mut table = format!(
+            "{:>14} {:>10}  allocated by\n{:>26}  freed by\n",
+            "bytes", "frees", ""
+        );
+        for pair in &self.pairs {
+            table.push_str(&format!(
+                "{:>14} {:>10}  {}\n{:>26}  {}\n",
+                pair.bytes, pair.frees, pair.allocated_by, "", pair.freed_by
+            ));
+        }
+        table
+    }
+}
+
+/// Everything needed to write out the frees report.
+pub struct FreesReport<FL: ReadFunctionLocations> {
+    pub flamegraph:
+        FlamegraphCallstacks<HashMap<Callstack, usize, ARandomState>, FL, IdentityCleaner>,
+    pub total_bytes: usize,
+    pub pairs: FreePairsReport,
+}
+
+impl<FL: ReadFunctionLocations> FreesReport<FL> {
+    /// Write frees.svg and friends, plus frees-pairs.json and frees-pairs.txt.
+    pub fn write(&self, directory_path: &Path, to_be_post_processed: bool) {
+        self.flamegraph.write_memory_flamegraphs(
+            directory_path,
+            "frees",
+            "Memory Freed, by Freeing Callstack",
+            self.total_bytes,
+            to_be_post_processed,
+        );
+        let json_path = directory_path.join("frees-pairs.json");
+        let result = serde_json::to_vec_pretty(&self.pairs)
+            .map_err(std::io::Error::from)
+            .and_then(|data| write_atomically(&json_path, data))
+            .and_then(|_| {
+                write_atomically(
+                    &directory_path.join("frees-pairs.txt"),
+                    self.pairs.to_table(),
+                )
+            });
+        match result {
+            Ok(_) => eprintln!(
+                "=fil-profile= Wrote allocation/free site pairs to {:?}",
+                json_path
+            ),
+            Err(e) => eprintln!(
+                "=fil-profile= Error writing allocation/free site pairs: {}",
+                e
+            ),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::FreeTracker;
+    use crate::memorytracking::{
+        CallSiteId, Callstack, LineNumberInfo::LineNumber, VecFunctionLocations,
+    };
+    use crate::util::new_hashmap;
+
+    #[test]
+    fn frees_report() {
+        pyo3::prepare_freethreaded_python();
+        let mut functions = VecFunctionLocations::new();
+        let fid = functions.add_function("a".to_string(), "af".to_string());
+        let callstacks: Vec<Callstack> = (1..=3)
+            .map(|line| {
+                let mut cs = Callstack::new();
+                cs.start_call(0, CallSiteId::new(fid, LineNumber(line)));
+                cs
+            })
+            .collect();
+
+        let mut tracker = FreeTracker::new();
+        // Allocated by 0, freed by 1 and 2:
+        tracker.record(0, 1, 100);
+        tracker.record(0, 1, 100);
+        tracker.record(0, 2, 1000);
+        // Allocated and freed by 2:
+        tracker.record(2, 2, 10);
+
+        let mut id_to_callstack = new_hashmap();
+        for (id, cs) in callstacks.iter().enumerate() {
+            id_to_callstack.insert(id as u32, cs);
+        }
+        let report = tracker.report(&id_to_callstack)(functions);
+        assert_eq!(report.total_bytes, 1210);
+        let mut lines: Vec<String> = report.flamegraph.to_lines(false).collect();
+        lines.sort();
+        assert_eq!(lines, vec!["a:2 (af) 200", "a:3 (af) 1010"]);
+        let pairs: Vec<_> = report
+            .pairs
+            .pairs
+            .iter()
+            .map(|p| {
+                (
+                    p.allocated_by.as_str(),
+                    p.freed_by.as_str(),
+                    p.frees,
+                    p.bytes,
+                )
+            })
+            .collect();
+        assert_eq!(
+            pairs,
+            vec![
+                ("a:1 (af)", "a:3 (af)", 1, 1000),
+                ("a:1 (af)", "a:2 (af)", 2, 200),
+                ("a:3 (af)", "a:3 (af)", 1, 10),
+            ]
+        );
+        assert!(report.pairs.to_table().contains("a:2 (af)"));
+    }
+}
@@ -0,0 +1,165 @@
+//! Drilling down into a single function: which of its lines are responsible
+//! for how much of the peak.
+//!
+//! Every callstack at peak that includes the function counts towards it,
+//! attributed to the line of the function's innermost frame. So when the
+//! function appears at multiple depths, e.g. due to recursion, a callstack is
+//! still only counted once, at the line that was running most recently, and
+//! the lines add up to the function's total.
+
+use crate::linecache::LineCacher;
+use crate::util::write_atomically;
+use serde::Serialize;
+use std::path::Path;
+
+/// Peak bytes attributed to a single line of the function.
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct LineDetail {
+    pub line: u32,
+    pub bytes: usize,
+    /// Percent of the function's total.
+    pub percent: f64,
+    /// The source code, if add_source() was called and it could be found.
+    pub source: String,
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct FunctionDetail {
+    pub filename: String,
+    pub function: String,
+    /// Peak bytes allocated with the function somewhere in the callstack.
+    pub total_bytes: usize,
+    pub peak_bytes: usize,
+    /// Largest first.
+    pub lines: Vec<LineDetail>,
+    // The file the source code comes from, which may differ from the filename
+    // that was asked for, see crate::frame_names:
+    #[serde(skip)]
+    source_filename: String,
+}
+
+impl FunctionDetail {
+    /// Create from peak bytes per line number, which needn't be unique.
+    pub fn new(
+        filename: &str,
+        function: &str,
+        source_filename: &str,
+        peak_bytes: usize,
+        line_bytes: impl IntoIterator<Item = (u32, usize)>,
+    ) -> Self {
+        let mut by_line: Vec<(u32, usize)> = vec![];
+        for (line, bytes) in line_bytes {
+            match by_line.iter_mut().find(|(existing, _)| *existing == line) {
+                Some((_, total)) => *total += bytes,
+                None => by_line.push((line, bytes)),
+            }
+        }
+        by_line.sort_by(|a, b| b.1.cmp(&a.1).then(a.0.cmp(&b.0)));
+        let total_bytes = by_line.iter().map(|(_, bytes)| bytes).sum();
+        let lines = by_line
+            .into_iter()
+            .map(|(line, bytes)| LineDetail {
+                line,
+                bytes,
+                percent: (bytes as f64) * 100.0 / (total_bytes as f64).max(1.0),
+                source: String::new(),
+            })
+            .collect();
+        Self {
+            filename: filename.to_string(),
+            function: function.to_string(),
+            total_bytes,
+            peak_bytes,
+            lines,
+            source_filename: source_filename.to_string(),
+        }
+    }
+
+    /// Fill in the source code of each line. This calls into Python, so it
+    /// shouldn't be done with the tracker locked.
+    pub fn add_source(&mut self) {
+        let mut linecache = LineCacher::default();
+        for line in self.lines.iter_mut() {
+            line.source = linecache
+                .get_source_line(&self.source_filename, line.line as usize)
+                .trim()
+                .to_string();
+        }
+    }
+
+    /// A human-readable table.
+    pub fn to_table(&self) -> String {
+        let mut table = format!(
+            "{} ({}): {} bytes at peak, {:.1}% of {} bytes\n\n",
+            self.filename,
+            self.function,
+            self.total_bytes,
+            (self.total_bytes as f64) * 100.0 / (self.peak_bytes as f64).max(1.0),
+            self.peak_bytes
+        );
+        table.push_str(&format!(
+            "{:>8} {:>14} {:>8}  source\n",
+            "line", "bytes", "percent"
+        ));
+        for line in &self.lines {
+            table.push_str(&format!(
+                "{:>8} {:>14} {:>7.1}%  {}\n",
+                line.line, line.bytes, line.percent, line.source
+            ));
+        }
+        table
+    }
+
+    /// Write function-detail.json and function-detail.txt to the given
+    /// directory.
+    pub fn write(&self, directory_path: &Path) -> std::io::Result<()> {
+        std::fs::create_dir_all(directory_path)?;
+        let json_path = directory_path.join("function-detail.json");
+        let data = serde_json::to_vec_pretty(self)?;
+        write_atomically(&json_path, data)?;
+        write_atomically(&directory_path.join("function-detail.txt"), self.to_table())?;
+        eprintln!(
+            "=fil-profile= Wrote per-line memory usage of {} to {:?}",
+            self.function, json_path
+        );
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::FunctionDetail;
+
+    #[test]
+    fn lines_are_combined_and_sorted() {
+        let detail = FunctionDetail::new(
+            "a.py",
+            "f",
+            "/src/a.py",
+            4000,
+            vec![(12, 100), (10, 300), (12, 200), (11, 100)],
+        );
+        assert_eq!(detail.total_bytes, 700);
+        let lines: Vec<_> = detail
+            .lines
+            .iter()
+            .map(|line| (line.line, line.bytes, format!("{:.1}", line.percent)))
+            .collect();
+        assert_eq!(
+            lines,
+            vec![
+                (10, 300, "42.9".to_string()),
+                (12, 300, "42.9".to_string()),
+                (11, 100, "14.3".to_string()),
+            ]
+        );
+        assert_eq!(
+            detail.to_table(),
+            "a.py (f): 700 bytes at peak, 17.5% of 4000 bytes\n\n    \
+             line          bytes  percent  source\n      \
+             10            300    42.9%  \n      \
+             12            300    42.9%  \n      \
+             11            100    14.3%  \n"
+        );
+    }
+}
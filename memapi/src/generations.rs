@@ -0,0 +1,205 @@
+//! What happens to memory that's live when tracking is reset.
+//!
+//! reset() can race with other threads: an allocation recorded just before a
+//! reset may well be freed just after it. Each reset starts a new generation,
+//! and the allocations and anonymous mmap()s that were live are kept around
+//! as the previous generation rather than forgotten. A free() that doesn't
+//! match the current generation is matched against the previous one, and is
+//! then dropped without touching the new totals, nor being counted as a free
+//! of an unknown address.
+//!
+//! Only one previous generation is kept, and only if it's not too big; it's
+//! dropped on the next reset, or once everything in it has been freed. With
+//! FIL_SURVIVORS its allocations are carried forward into the next one
+//! instead, see crate::survivors.
+
+use crate::addressmap::AddressMap;
+use crate::memorytracking::{CallstackId, ProcessUid};
+use crate::rangemap::RangeMap;
+use std::collections::BTreeMap;
+
+/// Previous generations with more live allocations than this are forgotten
+/// immediately, to bound the tracker's own memory use.
+pub const MAX_RETAINED_ALLOCATIONS: usize = 1 << 20;
+
+/// The allocations and mmaps that were live before the last reset.
+pub struct PreviousGeneration<A: Copy> {
+    pub generation: u64,
+    allocations: BTreeMap<ProcessUid, AddressMap<A>>,
+    anon_mmaps: BTreeMap<ProcessUid, RangeMap<CallstackId>>,
+    // How many frees were matched:
+    frees: usize,
+}
+
+impl<A: Copy> PreviousGeneration<A> {
+    /// Keep the maps that were live when the given generation ended, unless
+    /// there's nothing in them or too much to be worth keeping.
+    pub fn retain(
+        generation: u64,
+        allocations: BTreeMap<ProcessUid, AddressMap<A>>,
+        anon_mmaps: BTreeMap<ProcessUid, RangeMap<CallstackId>>,
+    ) -> Option<Self> {
+        let previous = Self {
+            generation,
+            allocations,
+            anon_mmaps,
+            frees: 0,
+        };
+        let total: usize = previous.allocations.values().map(|a| a.len()).sum();
+        if previous.is_empty() || total > MAX_RETAINED_ALLOCATIONS {
+            return None;
+        }
+        Some(previous)
+    }
+
+    /// Like retain(), also keeping the allocations that are still live from
+    /// the older previous generation, if any. The limit is on the total.
+    pub fn retain_with_older(
+        generation: u64,
+        mut allocations: BTreeMap<ProcessUid, AddressMap<A>>,
+        anon_mmaps: BTreeMap<ProcessUid, RangeMap<CallstackId>>,
+        older: Option<Self>,
+    ) -> Option<Self> {
+        if let Some(older) = older {
+            for (process, older_allocations) in older.allocations {
+                let current = allocations.entry(process).or_default();
+                for (address, allocation) in older_allocations.iter() {
+                    current.insert(address, *allocation);
+                }
+            }
+        }
+        Self::retain(generation, allocations, anon_mmaps)
+    }
+
+    /// An allocation was freed; return it if it was from this generation.
+    pub fn free_allocation(&mut self, process: ProcessUid, address: usize) -> Option<A> {
+        let removed = self
+            .allocations
+            .get_mut(&process)
+            .and_then(|allocations| allocations.remove(address));
+        if removed.is_some() {
+            self.frees += 1;
+        }
+        removed
+    }
+
+    /// An anonymous mmap() was unmapped, in whole or in part; return how many
+    /// bytes of it were from this generation.
+    pub fn free_anon_mmap(&mut self, process: ProcessUid, address: usize, size: usize) -> usize {
+        let removed: usize = self
+            .anon_mmaps
+            .get_mut(&process)
+            .map(|mmaps| mmaps.remove(address, size))
+            .unwrap_or_default()
+            .into_iter()
+            .map(|(_, bytes)| bytes)
+            .sum();
+        if removed > 0 {
+            self.frees += 1;
+        }
+        removed
+    }
+
+    /// The process exited, so its memory will never be freed; returns its
+    /// allocations.
+    pub fn drop_process(&mut self, process: ProcessUid) -> Option<AddressMap<A>> {
+        self.anon_mmaps.remove(&process);
+        self.allocations.remove(&process)
+    }
+
+    /// How many frees were matched to this generation.
+    pub fn frees(&self) -> usize {
+        self.frees
+    }
+
+    /// Whether everything in it has been freed.
+    pub fn is_empty(&self) -> bool {
+        self.allocations.values().all(|a| a.is_empty())
+            && self.anon_mmaps.values().all(|m| m.len() == 0)
+    }
+
+    /// Roughly how much memory the maps use.
+    pub fn heap_bytes(&self) -> usize {
+        self.allocations.values().map(|a| a.heap_bytes()).sum()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::PreviousGeneration;
+    use crate::addressmap::AddressMap;
+    use crate::memorytracking::PARENT_PROCESS;
+    use crate::rangemap::RangeMap;
+    use std::collections::BTreeMap;
+
+    #[test]
+    fn matches_frees_until_empty() {
+        let mut allocations = AddressMap::default();
+        allocations.insert(0x1000, ());
+        let mut mmaps = RangeMap::new();
+        mmaps.add(0x10000, 0x2000, 7);
+        let mut previous = PreviousGeneration::retain(
+            3,
+            BTreeMap::from([(PARENT_PROCESS, allocations)]),
+            BTreeMap::from([(PARENT_PROCESS, mmaps)]),
+        )
+        .unwrap();
+        assert_eq!(previous.free_allocation(PARENT_PROCESS, 0x2000), None);
+        assert_eq!(previous.free_allocation(PARENT_PROCESS, 0x1000), Some(()));
+        // Already freed:
+        assert_eq!(previous.free_allocation(PARENT_PROCESS, 0x1000), None);
+        assert_eq!(
+            previous.free_anon_mmap(PARENT_PROCESS, 0x11000, 0x4000),
+            0x1000
+        );
+        assert!(!previous.is_empty());
+        assert_eq!(
+            previous.free_anon_mmap(PARENT_PROCESS, 0x10000, 0x1000),
+            0x1000
+        );
+        assert!(previous.is_empty());
+        assert_eq!(previous.frees(), 3);
+    }
+
+    fn one_allocation(address: usize, value: u32) -> AddressMap<u32> {
+        let mut allocations = AddressMap::default();
+        allocations.insert(address, value);
+        allocations
+    }
+
+    #[test]
+    fn older_allocations_are_carried_forward() {
+        let older = PreviousGeneration::retain(
+            1,
+            BTreeMap::from([(PARENT_PROCESS, one_allocation(0x1000, 1))]),
+            BTreeMap::new(),
+        );
+        let mut previous = PreviousGeneration::retain_with_older(
+            2,
+            BTreeMap::from([(PARENT_PROCESS, one_allocation(0x2000, 2))]),
+            BTreeMap::new(),
+            older,
+        )
+        .unwrap();
+        assert_eq!(previous.free_allocation(PARENT_PROCESS, 0x1000), Some(1));
+        assert_eq!(previous.free_allocation(PARENT_PROCESS, 0x2000), Some(2));
+        assert!(previous.is_empty());
+        // Nothing newer to retain doesn't lose the older ones either:
+        let older = PreviousGeneration::retain(
+            1,
+            BTreeMap::from([(PARENT_PROCESS, one_allocation(0x1000, 1))]),
+            BTreeMap::new(),
+        );
+        assert!(
+            PreviousGeneration::retain_with_older(2, BTreeMap::new(), BTreeMap::new(), older)
+                .is_some()
+        );
+    }
+
+    #[test]
+    fn nothing_to_retain() {
+        let previous: Option<PreviousGeneration<()>> =
+            PreviousGeneration::retain(1, BTreeMap::new(), BTreeMap::new());
+        assert!(previous.is_none());
+    }
+}
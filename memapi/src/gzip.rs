@@ -0,0 +1,137 @@
+//! Writing gzip files, for report files that would be unreasonably big
+//! uncompressed. Compression is streamed, so the whole file never has to be in
+//! memory at once.
+
+use miniz_oxide::deflate::core::{
+    compress, create_comp_flags_from_zip_params, CompressorOxide, TDEFLFlush, TDEFLStatus,
+};
+use std::io::Write;
+
+const COMPRESSION_LEVEL: i32 = 6;
+
+const CRC32_TABLE: [u32; 256] = crc32_table();
+
+const fn crc32_table() -> [u32; 256] {
+    let mut table = [0u32; 256];
+    let mut i = 0;
+    while i < 256 {
+        let mut crc = i as u32;
+        let mut bit = 0;
+        while bit < 8 {
+            crc = if crc & 1 == 1 {
+                0xEDB88320 ^ (crc >> 1)
+            } else {
+                crc >> 1
+            };
+            bit += 1;
+        }
+        table[i] = crc;
+        i += 1;
+    }
+    table
+}
+
+fn update_crc32(crc: u32, data: &[u8]) -> u32 {
+    !data.iter().fold(!crc, |crc, byte| {
+        CRC32_TABLE[((crc ^ *byte as u32) & 0xFF) as usize] ^ (crc >> 8)
+    })
+}
+
+/// Compresses everything written to it; finish() must be called at the end.
+pub struct GzipWriter<W: Write> {
+    inner: W,
+    compressor: Box<CompressorOxide>,
+    buffer: Vec<u8>,
+    crc: u32,
+    // Modulo 2^32, as the format requires:
+    length: u32,
+}
+
+impl<W: Write> GzipWriter<W> {
+    pub fn new(mut inner: W) -> std::io::Result<Self> {
+        // Magic, deflate, no flags, no timestamp so output is reproducible, no
+        // extra flags, unknown OS:
+        inner.write_all(&[0x1f, 0x8b, 8, 0, 0, 0, 0, 0, 0, 255])?;
+        // Negative window bits means raw deflate, without a zlib header:
+        let flags = create_comp_flags_from_zip_params(COMPRESSION_LEVEL, -15, 0);
+        Ok(Self {
+            inner,
+            compressor: Box::new(CompressorOxide::new(flags)),
+            buffer: vec![0; 64 * 1024],
+            crc: 0,
+            length: 0,
+        })
+    }
+
+    fn deflate(&mut self, mut input: &[u8], flush: TDEFLFlush) -> std::io::Result<()> {
+        loop {
+            let (status, bytes_in, bytes_out) =
+                compress(&mut self.compressor, input, &mut self.buffer, flush);
+            self.inner.write_all(&self.buffer[..bytes_out])?;
+            input = &input[bytes_in..];
+            match status {
+                TDEFLStatus::Done => return Ok(()),
+                TDEFLStatus::Okay => {
+                    // When finishing, keep going until it's Done:
+                    if input.is_empty() && !matches!(flush, TDEFLFlush::Finish) {
+                        return Ok(());
+                    }
+                }
+                _ => return Err(std::io::Error::other("gzip compression failed")),
+            }
+        }
+    }
+
+    /// Write out the rest of the compressed data and the trailer.
+    pub fn finish(mut self) -> std::io::Result<W> {
+        self.deflate(&[], TDEFLFlush::Finish)?;
+        self.inner.write_all(&self.crc.to_le_bytes())?;
+        self.inner.write_all(&self.length.to_le_bytes())?;
+        Ok(self.inner)
+    }
+}
+
+impl<W: Write> Write for GzipWriter<W> {
+    fn write(&mut self, data: &[u8]) -> std::io::Result<usize> {
+        self.deflate(data, TDEFLFlush::None)?;
+        self.crc = update_crc32(self.crc, data);
+        self.length = self.length.wrapping_add(data.len() as u32);
+        Ok(data.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{update_crc32, GzipWriter};
+    use std::io::Write;
+
+    #[test]
+    fn crc32() {
+        assert_eq!(update_crc32(0, b"123456789"), 0xCBF43926);
+        assert_eq!(update_crc32(update_crc32(0, b"1234"), b"56789"), 0xCBF43926);
+    }
+
+    #[test]
+    fn round_trip() {
+        let data: Vec<u8> = (0..200_000u32)
+            .flat_map(|i| format!("a.py:{} (f) {}\n", i % 100, i).into_bytes())
+            .collect();
+        let mut writer = GzipWriter::new(vec![]).unwrap();
+        for chunk in data.chunks(7777) {
+            writer.write_all(chunk).unwrap();
+        }
+        let gzipped = writer.finish().unwrap();
+        assert_eq!(&gzipped[..3], &[0x1f, 0x8b, 8]);
+        assert!(gzipped.len() < data.len() / 4);
+        let trailer = &gzipped[gzipped.len() - 8..];
+        assert_eq!(trailer[..4], update_crc32(0, &data).to_le_bytes());
+        assert_eq!(trailer[4..], (data.len() as u32).to_le_bytes());
+        let decompressed =
+            miniz_oxide::inflate::decompress_to_vec(&gzipped[10..gzipped.len() - 8]).unwrap();
+        assert_eq!(decompressed, data);
+    }
+}
@@ -1,13 +1,55 @@
 #![deny(unsafe_op_in_unsafe_fn)]
+pub mod adaptive;
+pub mod address_tags;
+pub mod addressmap;
+pub mod allocation_rates;
+pub mod allocator_stats;
+#[cfg(feature = "arrow-export")]
+pub mod arrow;
+pub mod budget;
+pub mod bundled_allocators;
+pub mod cgroup;
+pub mod drift;
+pub mod environment;
 pub mod ffi;
 pub mod flamegraph;
+pub mod frame_names;
+pub mod frees;
+pub mod function_detail;
+pub mod generations;
+pub mod gzip;
+pub mod lifetimes;
 pub mod linecache;
+pub mod live;
+pub mod mapped_files;
 pub mod memorytracking;
+pub mod metadata;
+pub mod milestones;
 pub mod mmap;
+pub mod objects;
 pub mod oom;
+pub mod output_formats;
+pub mod peak_report;
+pub mod peak_triggers;
+pub mod phases;
+#[cfg(feature = "python-module")]
+pub mod pymodule;
 pub mod python;
 mod rangemap;
+pub mod reallocs;
+pub mod regions;
+pub mod report_budget;
+pub mod report_schema;
+pub mod rotation;
+pub mod sinks;
+pub mod size_ceiling;
+pub mod survivors;
+pub mod temp_files;
+pub mod threads;
+pub mod timeline;
 pub mod util;
+#[cfg(any(test, feature = "bench"))]
+pub mod workloads;
 
 #[macro_use]
 extern crate lazy_static;
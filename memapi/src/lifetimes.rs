@@ -0,0 +1,357 @@
+//! Allocation lifetime statistics.
+//!
+//! Opt-in, via FIL_LIFETIMES=1, since it stores an extra timestamp for every
+//! live allocation. When an allocation is freed, its lifetime is added to a
+//! per-callstack histogram with logarithmic buckets. The report lists, for the
+//! callstacks that allocated the most bytes, the median and p99 lifetime and
+//! the fraction of allocations that haven't been freed yet.
+
+use crate::linecache::LineCacher;
+use crate::memorytracking::{Callstack, CallstackId, ProcessUid, ReadFunctionLocations};
+use crate::util::{new_hashmap, write_atomically};
+use ahash::RandomState as ARandomState;
+use serde::Serialize;
+use std::collections::HashMap;
+use std::path::Path;
+
+/// Upper bounds of the histogram buckets, in microseconds. There's an
+/// additional final bucket for anything longer.
+const BUCKET_LIMITS_MICROS: [u64; 6] = [1_000, 10_000, 100_000, 1_000_000, 10_000_000, 100_000_000];
+const BUCKET_NAMES: [&str; 7] = ["<1ms", "<10ms", "<100ms", "<1s", "<10s", "<100s", ">=100s"];
+
+/// How many callstacks to include in the report.
+const MAX_REPORTED_CALLSTACKS: usize = 50;
+
+/// A cheap monotonic timestamp, in microseconds. On Linux this uses the
+/// coarse clock, which has a resolution of a few milliseconds but is much
+/// faster to read; that's fine for logarithmic buckets.
+#[cfg(target_os = "linux")]
+fn now_micros() -> u64 {
+    let mut ts = libc::timespec {
+        tv_sec: 0,
+        tv_nsec: 0,
+    };
+    unsafe { libc::clock_gettime(libc::CLOCK_MONOTONIC_COARSE, &mut ts) };
+    ts.tv_sec as u64 * 1_000_000 + ts.tv_nsec as u64 / 1_000
+}
+
+#[cfg(not(target_os = "linux"))]
+fn now_micros() -> u64 {
+    use once_cell::sync::Lazy;
+    use std::time::Instant;
+    static START: Lazy<Instant> = Lazy::new(Instant::now);
+    START.elapsed().as_micros() as u64
+}
+
+fn bucket(lifetime_micros: u64) -> usize {
+    BUCKET_LIMITS_MICROS
+        .iter()
+        .position(|limit| lifetime_micros < *limit)
+        .unwrap_or(BUCKET_LIMITS_MICROS.len())
+}
+
+/// Statistics for a single callstack.
+#[derive(Clone, Debug, Default, PartialEq)]
+struct CallstackLifetimes {
+    allocated_bytes: usize,
+    allocations: u64,
+    freed_histogram: [u64; BUCKET_NAMES.len()],
+}
+
+impl CallstackLifetimes {
+    fn freed(&self) -> u64 {
+        self.freed_histogram.iter().sum()
+    }
+
+    /// The bucket containing the given quantile of freed allocations.
+    fn quantile_bucket(&self, quantile: f64) -> Option<&'static str> {
+        let freed = self.freed();
+        if freed == 0 {
+            return None;
+        }
+        let target = ((freed as f64) * quantile).ceil().max(1.0) as u64;
+        let mut seen = 0;
+        for (i, count) in self.freed_histogram.iter().enumerate() {
+            seen += count;
+            if seen >= target {
+                return Some(BUCKET_NAMES[i]);
+            }
+        }
+        None
+    }
+}
+
+/// Tracks when live allocations were made, and the lifetimes of freed ones.
+pub struct LifetimeTracker {
+    allocation_times: HashMap<(ProcessUid, usize), u64, ARandomState>,
+    // Indexed by CallstackId:
+    per_callstack: Vec<CallstackLifetimes>,
+}
+
+impl Default for LifetimeTracker {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl LifetimeTracker {
+    pub fn new() -> Self {
+        Self {
+            allocation_times: new_hashmap(),
+            per_callstack: vec![],
+        }
+    }
+
+    /// Create one if FIL_LIFETIMES=1 is set.
+    pub fn from_env() -> Option<Self> {
+        if std::env::var("FIL_LIFETIMES").as_deref() == Ok("1") {
+            Some(Self::new())
+        } else {
+            None
+        }
+    }
+
+    fn stats(&mut self, callstack_id: CallstackId) -> &mut CallstackLifetimes {
+        let index = callstack_id as usize;
+        if index >= self.per_callstack.len() {
+            self.per_callstack
+                .resize(index + 1, CallstackLifetimes::default());
+        }
+        &mut self.per_callstack[index]
+    }
+
+    /// Record a new allocation.
+    pub fn add_allocation(
+        &mut self,
+        process: ProcessUid,
+        address: usize,
+        size: usize,
+        callstack_id: CallstackId,
+    ) {
+        self.add_allocation_at(process, address, size, callstack_id, now_micros());
+    }
+
+    fn add_allocation_at(
+        &mut self,
+        process: ProcessUid,
+        address: usize,
+        size: usize,
+        callstack_id: CallstackId,
+        now: u64,
+    ) {
+        let stats = self.stats(callstack_id);
+        stats.allocated_bytes += size;
+        stats.allocations += 1;
+        self.allocation_times.insert((process, address), now);
+    }
+
+    /// Record an allocation being freed.
+    pub fn free_allocation(
+        &mut self,
+        process: ProcessUid,
+        address: usize,
+        callstack_id: CallstackId,
+    ) {
+        self.free_allocation_at(process, address, callstack_id, now_micros());
+    }
+
+    fn free_allocation_at(
+        &mut self,
+        process: ProcessUid,
+        address: usize,
+        callstack_id: CallstackId,
+        now: u64,
+    ) {
+        if let Some(allocated_at) = self.allocation_times.remove(&(process, address)) {
+            let bucket = bucket(now.saturating_sub(allocated_at));
+            self.stats(callstack_id).freed_histogram[bucket] += 1;
+        }
+    }
+
+    /// All of a process' allocations went away.
+    pub fn drop_process(
+        &mut self,
+        process: ProcessUid,
+        allocations: impl Iterator<Item = (usize, CallstackId)>,
+    ) {
+        let now = now_micros();
+        for (address, callstack_id) in allocations {
+            self.free_allocation_at(process, address, callstack_id, now);
+        }
+    }
+
+    pub fn reset(&mut self) {
+        self.allocation_times.clear();
+        self.per_callstack.clear();
+    }
+
+    /// Gather the data for the report; resolving callstacks into strings is
+    /// done later by the returned closure, so it can happen without locks
+    /// held.
+    pub fn report<FL: ReadFunctionLocations>(
+        &self,
+        id_to_callstack: &HashMap<CallstackId, &Callstack, ARandomState>,
+    ) -> impl FnOnce(&FL) -> LifetimeReport {
+        let mut top: Vec<(Callstack, CallstackLifetimes)> = self
+            .per_callstack
+            .iter()
+            .enumerate()
+            .filter(|(_, stats)| stats.allocations > 0)
+            .filter_map(|(id, stats)| {
+                id_to_callstack
+                    .get(&(id as CallstackId))
+                    .map(|callstack| ((*callstack).clone(), stats.clone()))
+            })
+            .collect();
+        top.sort_by_key(|(_, stats)| std::cmp::Reverse(stats.allocated_bytes));
+        top.truncate(MAX_REPORTED_CALLSTACKS);
+        move |functions| {
+            let mut linecache = LineCacher::default();
+            LifetimeReport {
+                buckets: BUCKET_NAMES.to_vec(),
+                callstacks: top
+                    .into_iter()
+                    .map(|(callstack, stats)| CallstackLifetimeReport {
+                        callstack: callstack.as_string(false, functions, ";", &mut linecache),
+                        allocated_bytes: stats.allocated_bytes,
+                        allocations: stats.allocations,
+                        median_lifetime: stats.quantile_bucket(0.5),
+                        p99_lifetime: stats.quantile_bucket(0.99),
+                        never_freed_fraction: (stats.allocations - stats.freed()) as f64
+                            / stats.allocations as f64,
+                        freed_histogram: stats.freed_histogram.to_vec(),
+                    })
+                    .collect(),
+            }
+        }
+    }
+}
+
+#[derive(Debug, Serialize)]
+pub struct CallstackLifetimeReport {
+    pub callstack: String,
+    pub allocated_bytes: usize,
+    pub allocations: u64,
+    /// Bucket names, or None if nothing was freed.
+    pub median_lifetime: Option<&'static str>,
+    pub p99_lifetime: Option<&'static str>,
+    pub never_freed_fraction: f64,
+    /// Number of freed allocations in each bucket.
+    pub freed_histogram: Vec<u64>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct LifetimeReport {
+    pub buckets: Vec<&'static str>,
+    pub callstacks: Vec<CallstackLifetimeReport>,
+}
+
+impl LifetimeReport {
+    /// A human-readable table.
+    pub fn to_table(&self) -> String {
+        let mut table = format!(
+            "{:>14} {:>12} {:>8} {:>8} {:>11}  callstack\n",
+            "bytes", "allocations", "median", "p99", "never freed"
+        );
+        for cs in &self.callstacks {
+            table.push_str(&format!(
+                "{:>14} {:>12} {:>8} {:>8} {:>10.1}%  {}\n",
+                cs.allocated_bytes,
+                cs.allocations,
+                cs.median_lifetime.unwrap_or("-"),
+                cs.p99_lifetime.unwrap_or("-"),
+                cs.never_freed_fraction * 100.0,
+                cs.callstack
+            ));
+        }
+        table
+    }
+
+    /// Write lifetimes.json and lifetimes.txt to the given directory.
+    pub fn write(&self, directory_path: &Path) {
+        let json_path = directory_path.join("lifetimes.json");
+        let result = serde_json::to_vec_pretty(self)
+            .map_err(std::io::Error::from)
+            .and_then(|data| write_atomically(&json_path, data))
+            .and_then(|_| write_atomically(&directory_path.join("lifetimes.txt"), self.to_table()));
+        match result {
+            Ok(_) => eprintln!(
+                "=fil-profile= Wrote allocation lifetimes to {:?}",
+                json_path
+            ),
+            Err(e) => eprintln!("=fil-profile= Error writing allocation lifetimes: {}", e),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{bucket, LifetimeTracker};
+    use crate::memorytracking::{
+        CallSiteId, Callstack, FunctionId, LineNumberInfo::LineNumber, VecFunctionLocations,
+        PARENT_PROCESS,
+    };
+    use crate::util::new_hashmap;
+
+    #[test]
+    fn buckets() {
+        assert_eq!(bucket(0), 0);
+        assert_eq!(bucket(999), 0);
+        assert_eq!(bucket(1_000), 1);
+        assert_eq!(bucket(5_000_000), 4);
+        assert_eq!(bucket(u64::MAX), 6);
+    }
+
+    #[test]
+    fn lifetime_report() {
+        pyo3::prepare_freethreaded_python();
+        let mut functions = VecFunctionLocations::new();
+        let fid = functions.add_function("a".to_string(), "af".to_string());
+        let mut short = Callstack::new();
+        short.start_call(0, CallSiteId::new(fid, LineNumber(1)));
+        let mut long = Callstack::new();
+        long.start_call(0, CallSiteId::new(fid, LineNumber(2)));
+        let unknown =
+            Callstack::from_vec(vec![CallSiteId::new(FunctionId::UNKNOWN, LineNumber(0))]);
+
+        let mut tracker = LifetimeTracker::new();
+        // Callstack 0: 100 short-lived allocations of 10 bytes.
+        for address in 0..100 {
+            tracker.add_allocation_at(PARENT_PROCESS, address, 10, 0, 0);
+            tracker.free_allocation_at(PARENT_PROCESS, address, 0, 500);
+        }
+        // Callstack 1: 4 allocations of 1000 bytes, one freed after 2s, the
+        // rest still alive.
+        for address in 1000..1004 {
+            tracker.add_allocation_at(PARENT_PROCESS, address, 1000, 1, 0);
+        }
+        tracker.free_allocation_at(PARENT_PROCESS, 1000, 1, 2_000_000);
+        // Frees of unknown addresses are ignored:
+        tracker.free_allocation_at(PARENT_PROCESS, 12345, 2, 0);
+
+        let mut id_to_callstack = new_hashmap();
+        id_to_callstack.insert(0, &short);
+        id_to_callstack.insert(1, &long);
+        id_to_callstack.insert(2, &unknown);
+        let report = tracker.report(&id_to_callstack)(&functions);
+
+        assert_eq!(report.callstacks.len(), 2);
+        let first = &report.callstacks[0];
+        assert_eq!(first.callstack, "a:2 (af)");
+        assert_eq!(first.allocated_bytes, 4000);
+        assert_eq!(first.median_lifetime, Some("<10s"));
+        assert_eq!(first.never_freed_fraction, 0.75);
+        let second = &report.callstacks[1];
+        assert_eq!(second.callstack, "a:1 (af)");
+        assert_eq!(second.allocations, 100);
+        assert_eq!(second.median_lifetime, Some("<1ms"));
+        assert_eq!(second.p99_lifetime, Some("<1ms"));
+        assert_eq!(second.never_freed_fraction, 0.0);
+        assert!(report.to_table().contains("75.0%"));
+
+        tracker.reset();
+        assert!(tracker.report(&id_to_callstack)(&functions)
+            .callstacks
+            .is_empty());
+    }
+}
@@ -0,0 +1,424 @@
+//! A live view of current memory usage, for watching long-running jobs.
+//!
+//! Opt-in, via FIL_LIVE_VIEW=1. Every FIL_LIVE_VIEW_INTERVAL_MS (2000 by
+//! default) the sampler thread writes `live/live.json` in the output
+//! directory, with the callstacks currently using at least
+//! FIL_LIVE_VIEW_THRESHOLD_KB (1024 by default), plus `live/index.html`, a
+//! page that polls it and draws a flamegraph. Browsers don't let pages loaded
+//! from `file://` read other files, so the directory needs serving, e.g. with
+//! `python -m http.server`.
+//!
+//! Updates are incremental, since there may be millions of callstacks. With
+//! the tracker's lock held only the usage counts are scanned, and compared to
+//! the previous update to find which callstacks crossed the threshold or
+//! changed size. Rendering and writing happen afterwards: a callstack is only
+//! rendered to JSON the first time it shows up, and its bytes only when they
+//! change, and if nothing changed the file isn't rewritten at all.
+
+use crate::linecache::LineCacher;
+use crate::memorytracking::{Callstack, CallstackId, ReadFunctionLocations};
+use crate::util::{new_hashmap, write_atomically};
+use ahash::RandomState as ARandomState;
+use parking_lot::Mutex;
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::fmt::Write;
+use std::path::Path;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+const DEFAULT_INTERVAL: Duration = Duration::from_millis(2000);
+const DEFAULT_THRESHOLD_BYTES: usize = 1024 * 1024;
+
+/// Bumped on incompatible changes to `live.json`.
+pub const FORMAT_VERSION: u32 = 1;
+
+/// The page that polls `live.json`.
+const PAGE: &str = include_str!("live.html");
+
+/// What changed since the previous update, gathered with the tracker's lock
+/// held.
+#[derive(Debug, Default, PartialEq)]
+pub struct LiveUpdate {
+    generation: u64,
+    seconds: f64,
+    total_bytes: usize,
+    /// New bytes of callstacks that changed or crossed the threshold, by
+    /// CallstackId.
+    changed: Vec<(CallstackId, usize)>,
+    /// Callstacks that went below the threshold.
+    removed: Vec<CallstackId>,
+    /// Callstacks that haven't been rendered yet; filled in by the tracker.
+    pub new_callstacks: Vec<(CallstackId, Callstack)>,
+}
+
+impl LiveUpdate {
+    /// Whether there's anything to write.
+    pub fn is_empty(&self) -> bool {
+        self.changed.is_empty() && self.removed.is_empty()
+    }
+}
+
+/// Callstacks over the threshold, as last written.
+#[derive(Default)]
+struct LiveFile {
+    generation: u64,
+    sequence: u64,
+    wrote_page: bool,
+    // The start of each callstack's JSON object, up to its bytes; rendered
+    // once, and kept even once it's gone below the threshold, since it may
+    // well come back:
+    rendered: HashMap<CallstackId, String, ARandomState>,
+    current: BTreeMap<CallstackId, usize>,
+}
+
+impl LiveFile {
+    fn apply<FL: ReadFunctionLocations>(&mut self, update: LiveUpdate, functions: &FL) {
+        let mut linecache = LineCacher::default();
+        for (callstack_id, callstack) in update.new_callstacks {
+            let mut prefix = format!("{{\"id\":{},\"callstack\":", callstack_id);
+            let rendered = callstack.as_string(false, functions, ";", &mut linecache);
+            prefix.push_str(&serde_json::to_string(&rendered).unwrap());
+            prefix.push_str(",\"bytes\":");
+            self.rendered.insert(callstack_id, prefix);
+        }
+        for callstack_id in &update.removed {
+            self.current.remove(callstack_id);
+        }
+        for (callstack_id, bytes) in &update.changed {
+            self.current.insert(*callstack_id, *bytes);
+        }
+        self.sequence += 1;
+    }
+
+    fn to_json(
+        &self,
+        settings: &LiveSettings,
+        seconds: f64,
+        total_bytes: usize,
+        changed: &[CallstackId],
+    ) -> String {
+        let mut json = String::with_capacity(64 * (self.current.len() + 1));
+        write!(
+            json,
+            "{{\"format_version\":{},\"sequence\":{},\"seconds\":{:.3},\"interval_ms\":{},\"threshold_bytes\":{},\"total_bytes\":{},\"callstacks\":[",
+            FORMAT_VERSION,
+            self.sequence,
+            seconds,
+            settings.interval.as_millis(),
+            settings.threshold_bytes,
+            total_bytes
+        )
+        .unwrap();
+        // Every current callstack should have been rendered when it was added:
+        let callstacks = self.current.iter().filter_map(|(callstack_id, bytes)| {
+            self.rendered
+                .get(callstack_id)
+                .map(|prefix| (prefix, bytes))
+        });
+        for (index, (prefix, bytes)) in callstacks.enumerate() {
+            if index > 0 {
+                json.push(',');
+            }
+            json.push_str(prefix);
+            write!(json, "{}}}", bytes).unwrap();
+        }
+        json.push_str("],\"changed\":[");
+        for (index, callstack_id) in changed.iter().enumerate() {
+            if index > 0 {
+                json.push(',');
+            }
+            write!(json, "{}", callstack_id).unwrap();
+        }
+        json.push_str("]}");
+        json
+    }
+}
+
+/// How the live view is configured.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct LiveSettings {
+    pub interval: Duration,
+    pub threshold_bytes: usize,
+}
+
+/// Tracks what the live view last showed.
+pub struct LiveView {
+    settings: LiveSettings,
+    start: Instant,
+    generation: u64,
+    // Bytes as of the last update, for callstacks over the threshold:
+    reported: BTreeMap<CallstackId, usize>,
+    // Callstacks that were handed over for rendering:
+    known: HashSet<CallstackId, ARandomState>,
+    file: Arc<Mutex<LiveFile>>,
+}
+
+impl LiveView {
+    pub fn new(settings: LiveSettings) -> Self {
+        Self {
+            settings,
+            start: Instant::now(),
+            generation: 0,
+            reported: BTreeMap::new(),
+            known: HashSet::default(),
+            file: Arc::default(),
+        }
+    }
+
+    /// Create one if FIL_LIVE_VIEW=1 is set; see the module docs for the other
+    /// settings.
+    pub fn from_env() -> Option<Self> {
+        if std::env::var("FIL_LIVE_VIEW").as_deref() != Ok("1") {
+            return None;
+        }
+        let interval = std::env::var("FIL_LIVE_VIEW_INTERVAL_MS")
+            .ok()
+            .and_then(|value| value.parse().ok())
+            .filter(|millis| *millis > 0)
+            .map(Duration::from_millis)
+            .unwrap_or(DEFAULT_INTERVAL);
+        let threshold_bytes = std::env::var("FIL_LIVE_VIEW_THRESHOLD_KB")
+            .ok()
+            .and_then(|value| value.parse::<usize>().ok())
+            .map(|kib| kib * 1024)
+            .unwrap_or(DEFAULT_THRESHOLD_BYTES);
+        Some(Self::new(LiveSettings {
+            interval,
+            threshold_bytes,
+        }))
+    }
+
+    /// How often the live view should be updated.
+    pub fn interval(&self) -> Duration {
+        self.settings.interval
+    }
+
+    /// Compare current memory usage, indexed by CallstackId, to the previous
+    /// update. Callstacks for which `include` returns false are left out.
+    /// The callstacks listed by `needs_callstack()` then need adding to the
+    /// result's `new_callstacks` by the caller.
+    pub fn update<'a>(
+        &mut self,
+        current_memory_usage: impl Iterator<Item = &'a usize>,
+        total_bytes: usize,
+        include: impl Fn(CallstackId) -> bool,
+    ) -> LiveUpdate {
+        let threshold = self.settings.threshold_bytes.max(1);
+        let current = current_memory_usage
+            .enumerate()
+            .filter(|(_, bytes)| **bytes >= threshold)
+            .map(|(index, bytes)| (index as CallstackId, *bytes))
+            .filter(|(callstack_id, _)| include(*callstack_id));
+        let mut update = LiveUpdate {
+            generation: self.generation,
+            seconds: self.start.elapsed().as_secs_f64(),
+            total_bytes,
+            ..LiveUpdate::default()
+        };
+        // Both are in CallstackId order, so a merge finds the differences:
+        let mut previous = std::mem::take(&mut self.reported).into_iter().peekable();
+        for (callstack_id, bytes) in current {
+            while let Some((old_id, _)) = previous.next_if(|(old_id, _)| *old_id < callstack_id) {
+                update.removed.push(old_id);
+            }
+            match previous.next_if(|(old_id, _)| *old_id == callstack_id) {
+                Some((_, old_bytes)) if old_bytes == bytes => {}
+                _ => update.changed.push((callstack_id, bytes)),
+            }
+            self.reported.insert(callstack_id, bytes);
+        }
+        update.removed.extend(previous.map(|(old_id, _)| old_id));
+        update
+    }
+
+    /// The changed callstacks the live view hasn't rendered yet.
+    pub fn needs_callstack(&mut self, update: &LiveUpdate) -> HashSet<CallstackId, ARandomState> {
+        let mut needed: HashSet<CallstackId, ARandomState> = HashSet::default();
+        for (callstack_id, _) in &update.changed {
+            if self.known.insert(*callstack_id) {
+                needed.insert(*callstack_id);
+            }
+        }
+        needed
+    }
+
+    /// Write the update to `live.json` in the given directory, along with the
+    /// page if it's not there yet. Returns a closure, so that rendering and
+    /// writing can happen without the tracker's lock held; it returns whether
+    /// the page was written.
+    pub fn write<FL: ReadFunctionLocations>(
+        &self,
+        update: LiveUpdate,
+    ) -> impl FnOnce(&FL, &Path) -> std::io::Result<bool> {
+        let file = self.file.clone();
+        let settings = self.settings;
+        move |functions, directory| {
+            let mut file = file.lock();
+            if file.generation != update.generation {
+                // From before a reset:
+                return Ok(false);
+            }
+            if update.is_empty() && file.sequence > 0 {
+                return Ok(false);
+            }
+            let (seconds, total_bytes) = (update.seconds, update.total_bytes);
+            let changed: Vec<CallstackId> = update
+                .changed
+                .iter()
+                .map(|(callstack_id, _)| *callstack_id)
+                .collect();
+            file.apply(update, functions);
+            std::fs::create_dir_all(directory)?;
+            let new_page = !file.wrote_page;
+            if new_page {
+                write_atomically(&directory.join("index.html"), PAGE)?;
+                file.wrote_page = true;
+            }
+            write_atomically(
+                &directory.join("live.json"),
+                file.to_json(&settings, seconds, total_bytes, &changed),
+            )?;
+            Ok(new_page)
+        }
+    }
+
+    /// Start from scratch, e.g. after the tracker is reset.
+    pub fn reset(&mut self) {
+        self.start = Instant::now();
+        self.generation += 1;
+        self.reported.clear();
+        self.known.clear();
+        let mut file = self.file.lock();
+        *file = LiveFile {
+            generation: self.generation,
+            rendered: new_hashmap(),
+            ..LiveFile::default()
+        };
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{LiveSettings, LiveView};
+    use crate::memorytracking::{
+        CallSiteId, Callstack, LineNumberInfo::LineNumber, VecFunctionLocations,
+    };
+    use std::time::Duration;
+
+    fn live_view() -> LiveView {
+        LiveView::new(LiveSettings {
+            interval: Duration::from_millis(10),
+            threshold_bytes: 100,
+        })
+    }
+
+    #[test]
+    fn only_differences_are_updated() {
+        let mut live = live_view();
+        let update = live.update([50, 100, 200, 300].iter(), 650, |_| true);
+        assert_eq!(update.changed, [(1, 100), (2, 200), (3, 300)]);
+        assert!(update.removed.is_empty());
+        assert_eq!(update.total_bytes, 650);
+        assert_eq!(
+            live.needs_callstack(&update),
+            [1, 2, 3].into_iter().collect()
+        );
+
+        // Growing past the threshold, shrinking below it, and changing size:
+        let update = live.update([150, 99, 200, 400, 0].iter(), 849, |_| true);
+        assert_eq!(update.changed, [(0, 150), (3, 400)]);
+        assert_eq!(update.removed, [1]);
+        assert_eq!(live.needs_callstack(&update), [0].into_iter().collect());
+
+        // Nothing changed:
+        let update = live.update([150, 99, 200, 400, 0].iter(), 849, |_| true);
+        assert!(update.is_empty());
+
+        // Excluded callstacks are removed too, as are ones past the end:
+        let update = live.update([150].iter(), 150, |callstack_id| callstack_id != 0);
+        assert!(update.changed.is_empty());
+        assert_eq!(update.removed, [0, 2, 3]);
+        // Callstacks it's seen before don't need rendering again:
+        let update = live.update([150, 0, 200].iter(), 350, |_| true);
+        assert!(live.needs_callstack(&update).is_empty());
+    }
+
+    #[test]
+    fn written_incrementally() {
+        pyo3::prepare_freethreaded_python();
+        let mut functions = VecFunctionLocations::new();
+        let main = functions.add_function("main.py".to_string(), "<module>".to_string());
+        let callstack = |line| {
+            let mut cs = Callstack::new();
+            cs.start_call(0, CallSiteId::new(main, LineNumber(line)));
+            cs
+        };
+        let directory = tempfile::tempdir().unwrap();
+        let path = directory.path().join("live.json");
+        let read = || -> serde_json::Value {
+            serde_json::from_str(&std::fs::read_to_string(&path).unwrap()).unwrap()
+        };
+        let mut live = live_view();
+
+        let mut update = live.update([100, 200].iter(), 300, |_| true);
+        update.new_callstacks = vec![(0, callstack(1)), (1, callstack(2))];
+        assert!(live.write(update)(&functions, directory.path()).unwrap());
+        assert!(directory.path().join("index.html").exists());
+        let json = read();
+        assert_eq!(json["format_version"], 1);
+        assert_eq!(json["sequence"], 1);
+        assert_eq!(json["total_bytes"], 300);
+        assert_eq!(json["threshold_bytes"], 100);
+        assert_eq!(json["changed"], serde_json::json!([0, 1]));
+        assert_eq!(
+            json["callstacks"],
+            serde_json::json!([
+                {"id": 0, "callstack": "main.py:1 (<module>)", "bytes": 100},
+                {"id": 1, "callstack": "main.py:2 (<module>)", "bytes": 200},
+            ])
+        );
+
+        // Nothing changed, so nothing is written:
+        let update = live.update([100, 200].iter(), 300, |_| true);
+        assert!(!live.write(update)(&functions, directory.path()).unwrap());
+        assert_eq!(read()["sequence"], 1);
+
+        // Only the changed callstack gets new bytes; the other is unchanged,
+        // without being rendered again:
+        let update = live.update([0, 250].iter(), 250, |_| true);
+        assert!(update.new_callstacks.is_empty());
+        live.write(update)(&functions, directory.path()).unwrap();
+        let json = read();
+        assert_eq!(json["sequence"], 2);
+        assert_eq!(json["changed"], serde_json::json!([1]));
+        assert_eq!(
+            json["callstacks"],
+            serde_json::json!([{"id": 1, "callstack": "main.py:2 (<module>)", "bytes": 250}])
+        );
+    }
+
+    #[test]
+    fn reset() {
+        let mut live = live_view();
+        let functions = VecFunctionLocations::new();
+        let directory = tempfile::tempdir().unwrap();
+        let stale = live.update([100].iter(), 100, |_| true);
+        live.reset();
+        // Updates from before the reset are dropped:
+        live.write(stale)(&functions, directory.path()).unwrap();
+        assert!(!directory.path().join("live.json").exists());
+        // Afterwards everything is new again, and the first update is written
+        // even if it's empty:
+        let update = live.update([100].iter(), 100, |_| true);
+        assert_eq!(update.changed, [(0, 100)]);
+        assert_eq!(live.needs_callstack(&update), [0].into_iter().collect());
+        let update = live.update([].iter(), 0, |_| true);
+        live.write(update)(&functions, directory.path()).unwrap();
+        let json: serde_json::Value = serde_json::from_str(
+            &std::fs::read_to_string(directory.path().join("live.json")).unwrap(),
+        )
+        .unwrap();
+        assert_eq!(json["sequence"], 1);
+        assert_eq!(json["callstacks"], serde_json::json!([]));
+    }
+}
@@ -0,0 +1,233 @@
+//! File-backed mmap()s, e.g. pandas or pyarrow with `memory_map=True`, or the
+//! mmap module.
+//!
+//! Opt-in, via FIL_TRACK_MAPPED_FILES=1. The OS can drop mapped file pages
+//! whenever it likes and read them back from disk later, so they're not
+//! counted in the main peak: a 10GB memory-mapped Parquet file shouldn't
+//! drown out everything else. Instead they're tracked separately, with each
+//! callstack ending in a `[mapped file: <name>]` frame, and the report gets an
+//! extra flamegraph of everything combined, as of the combined peak.
+
+use crate::flamegraph::{aggregate_lines, FlamegraphCallstacks};
+use crate::memorytracking::{
+    Callstack, CallstackId, IdentityCleaner, ProcessUid, ReadFunctionLocations,
+};
+use crate::phases::NO_PHASE;
+use crate::rangemap::RangeMap;
+use ahash::RandomState as ARandomState;
+use im::Vector as ImVector;
+use std::collections::{BTreeMap, HashMap};
+use std::os::raw::c_int;
+use std::path::Path;
+
+/// Memory used by file-backed mmap()s.
+pub struct MappedFiles {
+    current_mmaps: BTreeMap<ProcessUid, RangeMap<CallstackId>>,
+    // Indexed by CallstackId:
+    current_usage: ImVector<usize>,
+    current_bytes: usize,
+    // At the peak of mapped files plus everything else:
+    peak_bytes: usize,
+    peak_usage: ImVector<usize>,
+    peak_other_usage: ImVector<usize>,
+}
+
+impl Default for MappedFiles {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl MappedFiles {
+    pub fn new() -> Self {
+        Self {
+            current_mmaps: BTreeMap::new(),
+            current_usage: ImVector::new(),
+            current_bytes: 0,
+            peak_bytes: 0,
+            peak_usage: ImVector::new(),
+            peak_other_usage: ImVector::new(),
+        }
+    }
+
+    pub fn add(
+        &mut self,
+        process: ProcessUid,
+        address: usize,
+        size: usize,
+        callstack_id: CallstackId,
+    ) {
+        self.current_mmaps
+            .entry(process)
+            .or_default()
+            .add(address, size, callstack_id);
+        let index = callstack_id as usize;
+        while self.current_usage.len() <= index {
+            self.current_usage.push_back(0);
+        }
+        self.current_usage[index] += size;
+        self.current_bytes += size;
+    }
+
+    /// Remove (part of) a mapping. check_if_new_peak() should be called first.
+    pub fn remove(&mut self, process: ProcessUid, address: usize, size: usize) {
+        if let Some(mmaps) = self.current_mmaps.get_mut(&process) {
+            for (callstack_id, removed) in mmaps.remove(address, size) {
+                self.current_usage[callstack_id as usize] -= removed;
+                self.current_bytes -= removed;
+            }
+        }
+    }
+
+    /// Forget all of a process's mappings. check_if_new_peak() should be
+    /// called first.
+    pub fn drop_process(&mut self, process: ProcessUid) {
+        if let Some(mmaps) = self.current_mmaps.remove(&process) {
+            for (size, callstack_id) in mmaps.into_iter() {
+                self.current_usage[callstack_id as usize] -= size;
+                self.current_bytes -= size;
+            }
+        }
+    }
+
+    pub fn current_bytes(&self) -> usize {
+        self.current_bytes
+    }
+
+    /// Check if mapped files plus the given other memory usage is a new peak.
+    /// Like AllocationTracker::check_if_new_peak(), snapshots are O(1).
+    pub fn check_if_new_peak(&mut self, other_bytes: usize, other_usage: &ImVector<usize>) {
+        if self.current_bytes + other_bytes > self.peak_bytes {
+            self.peak_bytes = self.current_bytes + other_bytes;
+            self.peak_usage.clone_from(&self.current_usage);
+            self.peak_other_usage.clone_from(other_usage);
+        }
+    }
+
+    /// Gather the combined peak for the report; converting function locations
+    /// is left to the returned closure, so it can happen without locks held.
+    pub fn report<FL: ReadFunctionLocations>(
+        &self,
+        id_to_callstack: &HashMap<CallstackId, &Callstack, ARandomState>,
+    ) -> impl FnOnce(FL) -> MappedFilesReport<FL> {
+        let mut data: HashMap<Callstack, usize, ARandomState> = crate::util::new_hashmap();
+        let line_numbers = !aggregate_lines();
+        let mut mapped_bytes = 0;
+        for (usage, is_mapped) in [(&self.peak_other_usage, false), (&self.peak_usage, true)] {
+            for (callstack_id, bytes) in usage.iter().enumerate() {
+                if *bytes == 0 {
+                    continue;
+                }
+                if is_mapped {
+                    mapped_bytes += bytes;
+                }
+                if let Some(callstack) = id_to_callstack.get(&(callstack_id as CallstackId)) {
+                    // The same callstack from different phases counts as one:
+                    let mut callstack = if line_numbers {
+                        (**callstack).clone()
+                    } else {
+                        callstack.without_line_numbers()
+                    };
+                    callstack.set_phase(NO_PHASE);
+                    *data.entry(callstack).or_insert(0) += bytes;
+                }
+            }
+        }
+        let peak_bytes = self.peak_bytes;
+        move |functions| MappedFilesReport {
+            flamegraph: FlamegraphCallstacks::new(data, functions, IdentityCleaner),
+            peak_bytes,
+            mapped_bytes,
+        }
+    }
+}
+
+/// Everything needed to write out the combined flamegraph.
+pub struct MappedFilesReport<FL: ReadFunctionLocations> {
+    pub flamegraph:
+        FlamegraphCallstacks<HashMap<Callstack, usize, ARandomState>, FL, IdentityCleaner>,
+    pub peak_bytes: usize,
+    /// How much of the peak was mapped files.
+    pub mapped_bytes: usize,
+}
+
+impl<FL: ReadFunctionLocations> MappedFilesReport<FL> {
+    /// Write peak-memory-with-mapped-files.svg and friends.
+    pub fn write(&self, directory_path: &Path, to_be_post_processed: bool) {
+        self.flamegraph.write_memory_flamegraphs(
+            directory_path,
+            "peak-memory-with-mapped-files",
+            &format!(
+                "Peak Tracked Memory Usage, Including {:.1} MiB of Mapped Files",
+                self.mapped_bytes as f64 / (1024.0 * 1024.0)
+            ),
+            self.peak_bytes,
+            to_be_post_processed,
+        );
+    }
+}
+
+/// The name, without the directory, of the file an open file descriptor
+/// refers to.
+#[cfg(target_os = "linux")]
+pub fn file_name(fd: c_int) -> Option<String> {
+    let path = std::fs::read_link(format!("/proc/self/fd/{}", fd)).ok()?;
+    Some(path.file_name()?.to_string_lossy().into_owned())
+}
+
+/// The name, without the directory, of the file an open file descriptor
+/// refers to.
+#[cfg(target_os = "macos")]
+pub fn file_name(fd: c_int) -> Option<String> {
+    let mut buffer = [0 as libc::c_char; libc::PATH_MAX as usize];
+    if unsafe { libc::fcntl(fd, libc::F_GETPATH, buffer.as_mut_ptr()) } == -1 {
+        return None;
+    }
+    let path = unsafe { std::ffi::CStr::from_ptr(buffer.as_ptr()) };
+    let path = Path::new(std::ffi::OsStr::from_bytes(path.to_bytes()));
+    Some(path.file_name()?.to_string_lossy().into_owned())
+}
+
+#[cfg(not(any(target_os = "linux", target_os = "macos")))]
+pub fn file_name(_fd: c_int) -> Option<String> {
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{file_name, MappedFiles};
+    use crate::memorytracking::PARENT_PROCESS;
+    use im::Vector as ImVector;
+    use std::os::unix::io::AsRawFd;
+
+    #[test]
+    fn combined_peak() {
+        let mut mapped = MappedFiles::new();
+        let other: ImVector<usize> = vec![100, 0].into_iter().collect();
+        mapped.add(PARENT_PROCESS, 0x1000, 1000, 1);
+        mapped.check_if_new_peak(100, &other);
+        assert_eq!(mapped.current_bytes(), 1000);
+        assert_eq!(mapped.peak_bytes, 1100);
+        // Partially unmapped:
+        mapped.remove(PARENT_PROCESS, 0x1000, 400);
+        assert_eq!(mapped.current_bytes(), 600);
+        mapped.check_if_new_peak(100, &other);
+        assert_eq!(mapped.peak_bytes, 1100);
+        assert_eq!(mapped.peak_usage, vec![0, 1000].into_iter().collect());
+        assert_eq!(mapped.peak_other_usage, other);
+        mapped.drop_process(PARENT_PROCESS);
+        assert_eq!(mapped.current_bytes(), 0);
+    }
+
+    #[test]
+    fn file_name_from_fd() {
+        let directory = tempfile::tempdir().unwrap();
+        let path = directory.path().join("data.parquet");
+        let file = std::fs::File::create(&path).unwrap();
+        assert_eq!(
+            file_name(file.as_raw_fd()),
+            Some("data.parquet".to_string())
+        );
+        assert_eq!(file_name(-1), None);
+    }
+}
@@ -1,25 +1,63 @@
+use crate::adaptive::{AdaptiveSampling, SMALL_ALLOCATION_BYTES};
+use crate::address_tags::AddressTags;
+use crate::addressmap::AddressMap;
+use crate::allocation_rates::{AllocationRates, AllocationRatesReport};
+use crate::allocator_stats::AllocatorMetadata;
+use crate::budget::TrackerBudget;
+use crate::drift::{DriftDetector, DriftReading};
+use crate::environment::Environment;
 use crate::flamegraph::filter_to_useful_callstacks;
-use crate::flamegraph::CallstackCleaner;
 use crate::flamegraph::FlamegraphCallstacks;
+use crate::flamegraph::{aggregate_lines, CallstackCleaner, CallstackDetails};
+use crate::frame_names::FrameNames;
+use crate::frees::{FreeTracker, FreesReport};
+use crate::function_detail::FunctionDetail;
+use crate::generations::PreviousGeneration;
+use crate::lifetimes::{LifetimeReport, LifetimeTracker};
 use crate::linecache::LineCacher;
+use crate::live::LiveView;
+use crate::mapped_files::{MappedFiles, MappedFilesReport};
+use crate::metadata::{
+    coalesce_mmaps, AdaptiveSamplingMetadata, AnonMmapsMetadata, ReportMetadata,
+};
+use crate::milestones::{Milestone, Milestones};
+use crate::objects::{ObjectTracker, ObjectsReport};
+use crate::peak_report::PeakReport;
+use crate::peak_triggers::{PeakTrigger, PeakTriggerReport, PeakTriggers};
+use crate::phases::{PhaseId, Phases, MAX_PHASE_FRAMES, NO_PHASE};
 use crate::python::get_runpy_path;
+use crate::reallocs::{ReallocReport, ReallocTracker};
+use crate::regions::{PreExisting, Region, RegionReport};
+use crate::report_budget;
+use crate::rotation::{FinishedWindow, Rotation};
+use crate::size_ceiling::SizeCeiling;
+use crate::survivors::{Survivors, SurvivorsReport};
+use crate::temp_files::{OpenedFile, TempFiles, TempFilesReport};
+use crate::threads;
+use crate::timeline::{Timeline, TimelineReport};
 
 use super::rangemap::RangeMap;
-use super::util::new_hashmap;
+use super::util::{current_thread_id, new_hashmap, peak_rss_bytes, write_to_stderr};
 use ahash::RandomState as ARandomState;
 use im::Vector as ImVector;
-use itertools::Itertools;
+use itertools::{Either, Itertools};
 use serde::Deserialize;
 use serde::Serialize;
 use std::borrow::Cow;
 use std::collections::BTreeMap;
 use std::collections::HashMap;
+use std::collections::HashSet;
+use std::fmt::Write;
+use std::os::raw::c_int;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::Duration;
 
 extern "C" {
     fn _exit(exit_code: std::os::raw::c_int);
 }
 
-#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize)]
 pub struct FunctionId(u64);
 
 impl FunctionId {
@@ -38,7 +76,25 @@ impl FunctionId {
 #[derive(Clone)]
 struct FunctionLocation {
     filename: String,
+    // If it differs from the filename, see crate::frame_names:
+    display_filename: Option<String>,
     function_name: String,
+    // If it differs from the function name, see qualified_name():
+    qualified_name: Option<String>,
+}
+
+/// The name to show for a Python function, given the name of the module it's
+/// in and its qualified name within it, e.g. `mymodule.Parser.process` rather
+/// than just `process`, so methods of different classes don't get merged.
+///
+/// The filename already says which script `__main__` is, so it's left out, and
+/// so is the module for module-level code, which is always `<module>`.
+pub fn qualified_name(module: &str, qualname: &str) -> String {
+    if module.is_empty() || module == "__main__" || qualname == "<module>" {
+        qualname.to_string()
+    } else {
+        format!("{}.{}", module, qualname)
+    }
 }
 
 /// Basic usage: first clone, once any locks are released, convert to
@@ -58,34 +114,72 @@ pub trait WriteFunctionLocations {
 
 pub trait ReadFunctionLocations {
     fn get_function_and_filename_and_display_filename(&self, id: FunctionId) -> (&str, &str, &str);
+
+    /// The function's name without any qualification, so e.g. `process`
+    /// rather than `mymodule.Parser.process`.
+    fn get_unqualified_function_name(&self, id: FunctionId) -> &str;
 }
 
 /// Stores FunctionLocations, returns a FunctionId
 #[derive(Clone)]
 pub struct VecFunctionLocations {
     functions: ImVector<FunctionLocation>,
+    names: Arc<FrameNames>,
 }
 
 impl VecFunctionLocations {
-    /// Create a new tracker.
+    /// Create a new tracker, prettifying names as configured by the
+    /// environment, see crate::frame_names.
     pub fn new() -> Self {
+        Self::with_frame_names(FrameNames::from_env())
+    }
+
+    pub fn with_frame_names(names: FrameNames) -> Self {
         Self {
             functions: ImVector::new(),
+            names: Arc::new(names),
         }
     }
 
     /// Register a function, get back its id.
     pub fn add_function(&mut self, filename: String, function_name: String) -> FunctionId {
+        self.add_qualified_function(filename, function_name, None)
+    }
+
+    /// Register a function that also has a qualified name, see
+    /// qualified_name(), which is what reports show and aggregate by. Get back
+    /// its id.
+    pub fn add_qualified_function(
+        &mut self,
+        filename: String,
+        function_name: String,
+        qualified_name: Option<String>,
+    ) -> FunctionId {
+        let display_filename = match self.names.filename(&filename) {
+            Cow::Owned(display_filename) => Some(display_filename),
+            Cow::Borrowed(_) => None,
+        };
+        let function_name = match self.names.function(&function_name) {
+            Cow::Owned(demangled) => demangled,
+            Cow::Borrowed(_) => function_name,
+        };
         self.functions.push_back(FunctionLocation {
             filename,
+            display_filename,
+            qualified_name: qualified_name.filter(|qualified| *qualified != function_name),
             function_name,
         });
-        // If we ever have 2 ** 32 or more functions in our program, this will
-        // break. Seems unlikely, even with long running workers.
+        // FunctionId::UNKNOWN is u64::MAX, which we'll never get to.
         FunctionId((self.functions.len() - 1) as u64)
     }
 }
 
+impl Default for VecFunctionLocations {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl ReadFunctionLocations for VecFunctionLocations {
     /// Get the function name and filename.
     fn get_function_and_filename_and_display_filename(&self, id: FunctionId) -> (&str, &str, &str) {
@@ -94,12 +188,24 @@ impl ReadFunctionLocations for VecFunctionLocations {
         }
         let location = &self.functions[id.0 as usize];
         (
-            &location.function_name,
-            &location.filename,
-            // TODO on Jupyter you might want to make display filename different...
+            location
+                .qualified_name
+                .as_deref()
+                .unwrap_or(&location.function_name),
             &location.filename,
+            location
+                .display_filename
+                .as_deref()
+                .unwrap_or(&location.filename),
         )
     }
+
+    fn get_unqualified_function_name(&self, id: FunctionId) -> &str {
+        if id == FunctionId::UNKNOWN {
+            return "UNKNOWN";
+        }
+        &self.functions[id.0 as usize].function_name
+    }
 }
 
 impl WriteFunctionLocations for VecFunctionLocations {
@@ -108,6 +214,7 @@ impl WriteFunctionLocations for VecFunctionLocations {
     fn cheap_clone(&self) -> Self {
         Self {
             functions: self.functions.clone(),
+            names: self.names.clone(),
         }
     }
 
@@ -117,10 +224,17 @@ impl WriteFunctionLocations for VecFunctionLocations {
 }
 
 /// Either the line number, or the bytecode index needed to get it.
-#[derive(Copy, Clone, Serialize, Deserialize, Hash, Eq, PartialEq, Debug)]
+#[derive(Copy, Clone, Serialize, Deserialize, Hash, Eq, PartialEq, PartialOrd, Ord, Debug)]
 pub enum LineNumberInfo {
     LineNumber(u32),
     BytecodeIndex(i32),
+    /// Left out of the report, so all lines of a function are one frame; see
+    /// FIL_AGGREGATE_LINES.
+    Omitted,
+    /// One of Fil's own frames in the middle of the Python callstack, e.g. a
+    /// `[context: <name>]` frame pushed by native code. The function's
+    /// filename is the kind of frame, in square brackets.
+    Synthetic,
 }
 
 impl LineNumberInfo {
@@ -135,7 +249,7 @@ impl LineNumberInfo {
 }
 
 /// A specific location: file + function + line number.
-#[derive(Clone, Debug, PartialEq, Eq, Copy, Hash, Serialize, Deserialize)]
+#[derive(Clone, Debug, PartialEq, Eq, PartialOrd, Ord, Copy, Hash, Serialize, Deserialize)]
 pub struct CallSiteId {
     /// The function + filename. We use IDs for performance reasons (faster hashing).
     pub function: FunctionId,
@@ -152,12 +266,64 @@ impl CallSiteId {
     }
 }
 
-/// The current Python callstack.
+/// Callstacks that don't correspond to any Python code, for allocations that
+/// get attributed somewhere other than where they happened.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize)]
+pub enum SyntheticCallstack {
+    /// Allocations smaller than this many bytes, see FIL_SMALL_ALLOCATIONS.
+    SmallAllocations(usize),
+    /// Allocations from threads that never ran any Python code, or anything
+    /// else that would set their callstack.
+    UnknownNativeThread,
+    /// New callstacks seen after the tracker exceeded its memory budget, see
+    /// crate::budget.
+    CallstackTableFull,
+    /// Memory from before the region being profiled, see crate::regions.
+    PreExisting,
+}
+
+/// The current Python callstack. The ordering is arbitrary, but stable, so
+/// reports can be made deterministic.
 #[derive(Derivative, Serialize, Deserialize)]
-#[derivative(Clone, PartialEq, Eq, Hash, Debug)]
+#[derivative(Clone, PartialEq, Eq, PartialOrd, Ord, Hash, Debug)]
 pub struct Callstack {
     calls: Vec<CallSiteId>,
-    #[derivative(Hash = "ignore", PartialEq = "ignore")]
+    // The phase of the program, see crate::phases:
+    #[serde(default)]
+    phase: PhaseId,
+    // Synthetic frames pushed with push_phase_frame(), outermost first:
+    #[serde(default)]
+    phase_frames: Vec<FunctionId>,
+    // Pushes that were ignored because of MAX_PHASE_FRAMES, so pops match up:
+    #[serde(skip)]
+    #[derivative(
+        Hash = "ignore",
+        PartialEq = "ignore",
+        PartialOrd = "ignore",
+        Ord = "ignore"
+    )]
+    ignored_phase_frames: usize,
+    // Set for synthetic callstacks, in which case the other fields are empty:
+    #[serde(default)]
+    synthetic: Option<SyntheticCallstack>,
+    // For file-backed mmap()s, a synthetic leaf frame naming the file, see
+    // crate::mapped_files:
+    #[serde(default)]
+    mapped_file: Option<FunctionId>,
+    // Stands in for callstacks merged into this one to fit the report budget,
+    // see crate::report_budget:
+    #[serde(default)]
+    merged: bool,
+    // Memory that was live when the current window started, shown under a
+    // `[carried over]` root frame, see crate::rotation:
+    #[serde(default)]
+    carried_over: bool,
+    #[derivative(
+        Hash = "ignore",
+        PartialEq = "ignore",
+        PartialOrd = "ignore",
+        Ord = "ignore"
+    )]
     cached_callstack_id: Option<(u32, CallstackId)>, // first bit is line number
 }
 
@@ -165,6 +331,13 @@ impl Callstack {
     pub fn new() -> Callstack {
         Callstack {
             calls: Vec::new(),
+            phase: NO_PHASE,
+            phase_frames: Vec::new(),
+            ignored_phase_frames: 0,
+            synthetic: None,
+            mapped_file: None,
+            merged: false,
+            carried_over: false,
             cached_callstack_id: None,
         }
     }
@@ -172,20 +345,213 @@ impl Callstack {
     pub fn from_vec(vec: Vec<CallSiteId>) -> Self {
         Self {
             calls: vec,
+            phase: NO_PHASE,
+            phase_frames: Vec::new(),
+            ignored_phase_frames: 0,
+            synthetic: None,
+            mapped_file: None,
+            merged: false,
+            carried_over: false,
             cached_callstack_id: None,
         }
     }
 
+    /// The synthetic callstack that allocations smaller than the given number
+    /// of bytes are attributed to when FIL_SMALL_ALLOCATIONS is set, rather
+    /// than their real callstack.
+    pub fn small_allocations(below: usize) -> Self {
+        Self {
+            synthetic: Some(SyntheticCallstack::SmallAllocations(below)),
+            ..Self::new()
+        }
+    }
+
+    /// The synthetic callstack for allocations from threads whose callstack
+    /// was never set, e.g. threads created by native code.
+    pub fn unknown_native_thread() -> Self {
+        Self {
+            synthetic: Some(SyntheticCallstack::UnknownNativeThread),
+            ..Self::new()
+        }
+    }
+
+    /// The synthetic callstack for new callstacks once the interner has been
+    /// frozen, see crate::budget.
+    pub fn callstack_table_full() -> Self {
+        Self {
+            synthetic: Some(SyntheticCallstack::CallstackTableFull),
+            ..Self::new()
+        }
+    }
+
+    /// The synthetic callstack for memory allocated before a region started,
+    /// see crate::regions.
+    pub fn pre_existing() -> Self {
+        Self {
+            synthetic: Some(SyntheticCallstack::PreExisting),
+            ..Self::new()
+        }
+    }
+
+    pub fn phase(&self) -> PhaseId {
+        self.phase
+    }
+
+    /// Set the phase, which should be done before calling
+    /// id_for_new_allocation() so the cached id doesn't get reused across
+    /// phases.
+    pub fn set_phase(&mut self, phase: PhaseId) {
+        if self.phase != phase {
+            self.phase = phase;
+            self.cached_callstack_id = None;
+        }
+    }
+
+    /// Push a synthetic frame that gets prepended to the Python callstack, with
+    /// the function name being the name of the phase. Returns false if there
+    /// are already MAX_PHASE_FRAMES, in which case it's ignored.
+    pub fn push_phase_frame(&mut self, function: FunctionId) -> bool {
+        if self.phase_frames.len() >= MAX_PHASE_FRAMES {
+            self.ignored_phase_frames += 1;
+            return false;
+        }
+        self.phase_frames.push(function);
+        self.cached_callstack_id = None;
+        true
+    }
+
+    /// Pop the innermost synthetic frame pushed by push_phase_frame().
+    pub fn pop_phase_frame(&mut self) {
+        if self.ignored_phase_frames > 0 {
+            self.ignored_phase_frames -= 1;
+        } else if self.phase_frames.pop().is_some() {
+            self.cached_callstack_id = None;
+        }
+    }
+
+    /// The synthetic phase frames, outermost first.
+    pub fn phase_frames(&self) -> &[FunctionId] {
+        &self.phase_frames
+    }
+
+    /// The callstack as seen from `skip_frames` frames further up, e.g. to
+    /// leave out the frames of an API wrapper function. If no frames are
+    /// skipped, the innermost frame's line number is set to `line_number`.
+    pub fn caller_callstack(&self, line_number: u32, skip_frames: usize) -> Callstack {
+        let mut callstack = self.clone();
+        callstack.cached_callstack_id = None;
+        if skip_frames > 0 {
+            let length = callstack.calls.len().saturating_sub(skip_frames);
+            callstack.calls.truncate(length);
+        } else {
+            callstack.set_line_number(line_number);
+        }
+        callstack
+    }
+
+    /// The callstack for memory reported by native code at the given line
+    /// number, ending in a synthetic frame, e.g. `[domain: <name>]`.
+    pub fn with_synthetic_leaf(&self, line_number: u32, function: FunctionId) -> Callstack {
+        let mut callstack = self.caller_callstack(line_number, 0);
+        callstack
+            .calls
+            .push(CallSiteId::new(function, LineNumberInfo::Synthetic));
+        callstack
+    }
+
+    /// Push a synthetic frame, e.g. `[context: <name>]`, called from the
+    /// given line of the current Python frame.
+    pub fn push_synthetic_frame(&mut self, parent_line_number: u32, function: FunctionId) {
+        self.start_call(
+            parent_line_number,
+            CallSiteId::new(function, LineNumberInfo::Synthetic),
+        );
+    }
+
+    /// Pop the innermost synthetic frame pushed by push_synthetic_frame(),
+    /// even if Python frames pushed later haven't been popped.
+    pub fn pop_synthetic_frame(&mut self) {
+        if let Some(index) = self
+            .calls
+            .iter()
+            .rposition(|call| call.line_number == LineNumberInfo::Synthetic)
+        {
+            self.calls.remove(index);
+            self.cached_callstack_id = None;
+        }
+    }
+
+    /// Set the line number of the innermost frame, unless it's a synthetic
+    /// frame, which doesn't have one.
+    fn set_line_number(&mut self, line_number: u32) {
+        if line_number == 0 {
+            return;
+        }
+        if let Some(call) = self.calls.last_mut() {
+            if call.line_number != LineNumberInfo::Synthetic {
+                call.line_number = LineNumberInfo::LineNumber(line_number);
+            }
+        }
+    }
+
+    /// The callstack for a file-backed mmap() made at the given line number,
+    /// ending in a `[mapped file: <name>]` frame, where the function's name is
+    /// the file's name.
+    pub fn with_mapped_file(&self, line_number: u32, function: FunctionId) -> Callstack {
+        let mut callstack = self.caller_callstack(line_number, 0);
+        callstack.mapped_file = Some(function);
+        callstack
+    }
+
+    /// The same callstack under a `[carried over]` root frame, for memory that
+    /// was live when a new window started, see crate::rotation.
+    pub fn carried_over(&self) -> Callstack {
+        let mut callstack = self.clone();
+        callstack.cached_callstack_id = None;
+        callstack.carried_over = true;
+        callstack
+    }
+
     pub fn to_vec(&self) -> Vec<CallSiteId> {
         self.calls.clone()
     }
 
-    pub fn start_call(&mut self, parent_line_number: u32, callsite_id: CallSiteId) {
-        if parent_line_number != 0 {
-            if let Some(call) = self.calls.last_mut() {
-                call.line_number = LineNumberInfo::LineNumber(parent_line_number);
+    /// How many frames there are, not counting phase frames.
+    pub fn depth(&self) -> usize {
+        self.calls.len() + self.mapped_file.is_some() as usize
+    }
+
+    /// The callstack that this one gets merged into when a report has too
+    /// many callstacks: its caller, with an `[other callstacks]` leaf frame.
+    /// A mapped file's frame is dropped first. None if there's no caller.
+    pub fn merged_into_caller(&self) -> Option<Callstack> {
+        if self.synthetic.is_some() {
+            return None;
+        }
+        let mut callstack = self.clone();
+        callstack.cached_callstack_id = None;
+        if callstack.mapped_file.take().is_none() {
+            callstack.calls.pop()?;
+        }
+        callstack.merged = true;
+        Some(callstack)
+    }
+
+    /// The same callstack with line numbers left out, so callstacks that only
+    /// differ by line are equal.
+    pub fn without_line_numbers(&self) -> Callstack {
+        let mut callstack = self.clone();
+        callstack.cached_callstack_id = None;
+        for call in callstack.calls.iter_mut() {
+            if call.line_number != LineNumberInfo::Synthetic {
+                call.line_number = LineNumberInfo::Omitted;
             }
         }
+        callstack
+    }
+
+    pub fn start_call(&mut self, parent_line_number: u32, callsite_id: CallSiteId) {
+        self.set_line_number(parent_line_number);
         self.calls.push(callsite_id);
         self.cached_callstack_id = None;
     }
@@ -208,11 +574,7 @@ impl Callstack {
         }
 
         // Set the new line number:
-        if line_number != 0 {
-            if let Some(call) = self.calls.last_mut() {
-                call.line_number = LineNumberInfo::LineNumber(line_number);
-            }
-        }
+        self.set_line_number(line_number);
 
         // Calculate callstack ID, cache it, and then return it;
         let callstack_id = get_callstack_id(self);
@@ -220,17 +582,13 @@ impl Callstack {
         callstack_id
     }
 
-    pub fn as_string<FL: ReadFunctionLocations>(
+    /// The Python frames with their function, filename and display filename,
+    /// outermost first.
+    pub fn python_frames<'a, FL: ReadFunctionLocations>(
         &self,
-        to_be_post_processed: bool,
-        functions: &FL,
-        separator: &'static str,
-        linecache: &mut LineCacher,
-    ) -> String {
-        if self.calls.is_empty() {
-            return "[No Python stack]".to_string();
-        }
-        let calls: Vec<(CallSiteId, (&str, &str, &str))> = self
+        functions: &'a FL,
+    ) -> Vec<(CallSiteId, (&'a str, &'a str, &'a str))> {
+        let mut calls: Vec<(CallSiteId, (&str, &str, &str))> = self
             .calls
             .iter()
             .map(|id| {
@@ -240,18 +598,207 @@ impl Callstack {
                 )
             })
             .collect();
-        let skip_prefix = if cfg!(feature = "fil4prod") {
-            0
-        } else {
+        if !cfg!(feature = "fil4prod") {
             // Due to implementation details we have some runpy() frames at the
             // start; remove them.
-            runpy_prefix_length(calls.iter())
-        };
+            let skip_prefix = runpy_prefix_length(calls.iter());
+            calls.drain(..skip_prefix);
+        }
         calls
-            .into_iter()
-            .skip(skip_prefix)
-            .map(|(id, (function, filename, display_filename))| {
-                if to_be_post_processed {
+    }
+
+    /// Just the innermost Python frame, as "filename:line (function)", or the
+    /// usual description for callstacks without Python frames.
+    pub fn innermost_frame<FL: ReadFunctionLocations>(&self, functions: &FL) -> String {
+        match self.calls.last() {
+            Some(id) if self.synthetic.is_none() => {
+                let (function, filename, display_filename) =
+                    functions.get_function_and_filename_and_display_filename(id.function);
+                frame_name(filename, display_filename, id.line_number, function)
+            }
+            _ => self.as_string(false, functions, ";", &mut LineCacher::default()),
+        }
+    }
+
+    /// The frames as_string() would show, outermost first, but with the file,
+    /// function and line kept apart. Fil's own frames, e.g. "[phase: load]" or
+    /// "[No Python stack]", have that as the function and an empty file.
+    pub fn structured_frames<FL: ReadFunctionLocations>(&self, functions: &FL) -> Vec<Frame> {
+        let fil_frame = |name: String| Frame {
+            file: String::new(),
+            function: name,
+            line: None,
+        };
+        if self.carried_over {
+            let callstack = Callstack {
+                carried_over: false,
+                ..self.clone()
+            };
+            let mut frames = vec![fil_frame("[carried over]".to_string())];
+            frames.extend(callstack.structured_frames(functions));
+            return frames;
+        }
+        let phase_frames = |callstack: &Callstack| -> Vec<Frame> {
+            callstack
+                .phase_frames
+                .iter()
+                .map(|function| {
+                    let (name, _, _) =
+                        functions.get_function_and_filename_and_display_filename(*function);
+                    fil_frame(format!("[phase: {}]", name))
+                })
+                .collect()
+        };
+        if self.merged {
+            let callstack = Callstack {
+                merged: false,
+                ..self.clone()
+            };
+            let mut frames = if callstack.calls.is_empty() {
+                phase_frames(&callstack)
+            } else {
+                callstack.structured_frames(functions)
+            };
+            frames.push(fil_frame("[other callstacks]".to_string()));
+            return frames;
+        }
+        if let Some(function) = self.mapped_file {
+            let callstack = Callstack {
+                mapped_file: None,
+                ..self.clone()
+            };
+            let (name, _, _) = functions.get_function_and_filename_and_display_filename(function);
+            let mut frames = callstack.structured_frames(functions);
+            frames.push(fil_frame(format!("[mapped file: {}]", name)));
+            return frames;
+        }
+        if self.synthetic.is_some() {
+            return vec![fil_frame(self.as_string(
+                false,
+                functions,
+                ";",
+                &mut LineCacher::default(),
+            ))];
+        }
+        let mut frames = phase_frames(self);
+        if self.calls.is_empty() {
+            frames.push(fil_frame("[No Python stack]".to_string()));
+            return frames;
+        }
+        frames.extend(self.python_frames(functions).into_iter().map(
+            |(id, (function, filename, display_filename))| match id.line_number {
+                LineNumberInfo::Synthetic => fil_frame(frame_name(
+                    filename,
+                    display_filename,
+                    id.line_number,
+                    function,
+                )),
+                line_number => Frame {
+                    file: display_filename.to_string(),
+                    function: function.to_string(),
+                    line: match line_number {
+                        LineNumberInfo::Omitted => None,
+                        line_number => Some(line_number.get_line_number()),
+                    },
+                },
+            },
+        ));
+        frames
+    }
+
+    pub fn as_string<FL: ReadFunctionLocations>(
+        &self,
+        to_be_post_processed: bool,
+        functions: &FL,
+        separator: &'static str,
+        linecache: &mut LineCacher,
+    ) -> String {
+        if self.carried_over {
+            let callstack = Callstack {
+                carried_over: false,
+                ..self.clone()
+            };
+            return format!(
+                "[carried over]{}{}",
+                separator,
+                callstack.as_string(to_be_post_processed, functions, separator, linecache)
+            );
+        }
+        if self.merged {
+            let callstack = Callstack {
+                merged: false,
+                ..self.clone()
+            };
+            // Rather than "[No Python stack]":
+            if callstack.calls.is_empty() {
+                return callstack
+                    .phase_frames
+                    .iter()
+                    .map(|function| {
+                        let (name, _, _) =
+                            functions.get_function_and_filename_and_display_filename(*function);
+                        format!("[phase: {}]", name)
+                    })
+                    .chain(std::iter::once("[other callstacks]".to_string()))
+                    .join(separator);
+            }
+            return format!(
+                "{}{}[other callstacks]",
+                callstack.as_string(to_be_post_processed, functions, separator, linecache),
+                separator
+            );
+        }
+        if let Some(function) = self.mapped_file {
+            let callstack = Callstack {
+                mapped_file: None,
+                ..self.clone()
+            };
+            let (name, _, _) = functions.get_function_and_filename_and_display_filename(function);
+            return format!(
+                "{}{}[mapped file: {}]",
+                callstack.as_string(to_be_post_processed, functions, separator, linecache),
+                separator,
+                name
+            );
+        }
+        let phase_frames = self.phase_frames.iter().map(|function| {
+            let (name, _, _) = functions.get_function_and_filename_and_display_filename(*function);
+            format!("[phase: {}]", name)
+        });
+        match self.synthetic {
+            Some(SyntheticCallstack::SmallAllocations(below)) => {
+                return if below.is_multiple_of(1024) {
+                    format!("[small allocations < {} KiB]", below / 1024)
+                } else {
+                    format!("[small allocations < {} bytes]", below)
+                };
+            }
+            Some(SyntheticCallstack::UnknownNativeThread) => {
+                return "[unknown native thread]".to_string();
+            }
+            Some(SyntheticCallstack::CallstackTableFull) => {
+                return "[callstack table full]".to_string();
+            }
+            Some(SyntheticCallstack::PreExisting) => {
+                return "[pre-existing]".to_string();
+            }
+            None => {}
+        }
+        if self.calls.is_empty() {
+            return phase_frames
+                .chain(std::iter::once("[No Python stack]".to_string()))
+                .join(separator);
+        }
+        let python_frames = self.python_frames(functions).into_iter().map(
+            |(id, (function, filename, display_filename))| {
+                let frame = frame_name(filename, display_filename, id.line_number, function);
+                // Without a line number there's no source code to show:
+                if to_be_post_processed
+                    && !matches!(
+                        id.line_number,
+                        LineNumberInfo::Omitted | LineNumberInfo::Synthetic
+                    )
+                {
                     // Get Python code.
                     let code = linecache
                         .get_source_line(filename, id.line_number.get_line_number() as usize);
@@ -277,23 +824,52 @@ impl Callstack {
                     // The \u{2800} is to ensure we don't have empty lines,
                     // and that whitespace doesn't get trimmed from start;
                     // we'll get rid of this in post-processing.
-                    format!(
-                        "{display_filename}:{line} ({function});\u{2800}{code}",
-                        display_filename = display_filename,
-                        line = id.line_number.get_line_number(),
-                        function = function,
-                        code = &code.trim_end(),
-                    )
+                    format!("{};\u{2800}{}", frame, code.trim_end())
                 } else {
-                    format!(
-                        "{display_filename}:{line} ({function})",
-                        display_filename = display_filename,
-                        line = id.line_number.get_line_number(),
-                        function = function,
-                    )
+                    frame
                 }
-            })
-            .join(separator)
+            },
+        );
+        phase_frames.chain(python_frames).join(separator)
+    }
+}
+
+impl Default for Callstack {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// A frame of a callstack, see Callstack::structured_frames().
+#[derive(Clone, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub struct Frame {
+    pub file: String,
+    pub function: String,
+    /// None if line numbers are left out, or for Fil's own frames.
+    pub line: Option<u32>,
+}
+
+/// A Python frame as shown in reports: "filename:line (function)", or
+/// "filename (function)" if line numbers are left out. Synthetic frames are
+/// "[kind: function]", where the filename is "[kind]".
+fn frame_name(
+    filename: &str,
+    display_filename: &str,
+    line_number: LineNumberInfo,
+    function: &str,
+) -> String {
+    if line_number == LineNumberInfo::Synthetic {
+        let kind = filename.trim_start_matches('[').trim_end_matches(']');
+        format!("[{}: {}]", kind, function)
+    } else if line_number == LineNumberInfo::Omitted {
+        format!("{} ({})", display_filename, function)
+    } else {
+        format!(
+            "{}:{} ({})",
+            display_filename,
+            line_number.get_line_number(),
+            function
+        )
     }
 }
 
@@ -316,37 +892,120 @@ pub type CallstackId = u32;
 /// Maps Functions to integer identifiers used in CallStacks.
 pub struct CallstackInterner {
     max_id: CallstackId,
+    // How many distinct callstacks we can have; the u32 ids are also packed
+    // into Allocation, so we don't want to make them bigger:
+    capacity: CallstackId,
+    overflowed: bool,
+    // Once frozen, new callstacks all get the [callstack table full] id:
+    frozen: bool,
+    // Memory used by the interned callstacks' own Vecs:
+    callstacks_heap_bytes: usize,
     callstack_to_id: HashMap<Callstack, u32, ARandomState>,
 }
 
 impl CallstackInterner {
     pub fn new() -> Self {
+        Self::with_capacity(CallstackId::MAX)
+    }
+
+    fn with_capacity(capacity: CallstackId) -> Self {
         CallstackInterner {
             max_id: 0,
+            capacity,
+            overflowed: false,
+            frozen: false,
+            callstacks_heap_bytes: 0,
             callstack_to_id: new_hashmap(),
         }
     }
 
+    /// Stop interning new callstacks, other than synthetic ones like
+    /// [callstack table full].
+    pub fn freeze(&mut self) {
+        self.frozen = true;
+    }
+
+    /// Estimated memory used by the interned callstacks.
+    pub fn heap_bytes(&self) -> usize {
+        self.callstack_to_id.capacity() * (std::mem::size_of::<(Callstack, CallstackId)>() + 1)
+            + self.callstacks_heap_bytes
+    }
+
+    /// How much heap_bytes() will go up by the next time the table grows.
+    pub fn growth_bytes(&self) -> usize {
+        if self.frozen {
+            return 0;
+        }
+        self.callstack_to_id.capacity().max(1)
+            * (std::mem::size_of::<(Callstack, CallstackId)>() + 1)
+    }
+
     /// Add a (possibly) new Function, returning its ID.
+    ///
+    /// Once we run out of ids, new callstacks get the id of the empty
+    /// callstack, so their memory is still counted, rather than the id
+    /// silently wrapping around and getting merged with some unrelated
+    /// callstack.
     pub fn get_or_insert_id<F: FnOnce()>(
         &mut self,
         callstack: Cow<Callstack>,
         call_on_new: F,
     ) -> CallstackId {
-        let max_id = &mut self.max_id;
         if let Some(result) = self.callstack_to_id.get(&*callstack) {
             *result
+        } else if self.max_id >= self.capacity - 1 && !callstack.calls.is_empty() {
+            // The last id is kept for the empty callstack, if it isn't
+            // already interned.
+            if !self.overflowed {
+                self.overflowed = true;
+                eprintln!(
+                    "=fil-profile= WARNING: Reached the limit of {} distinct callstacks, so memory allocated from new callstacks will be reported as [No Python stack].",
+                    self.capacity
+                );
+            }
+            self.get_or_insert_id(Cow::Owned(Callstack::new()), call_on_new)
+        } else if self.frozen && callstack.synthetic.is_none() {
+            // Synthetic callstacks are few, so they're still interned.
+            self.get_or_insert_id(Cow::Owned(Callstack::callstack_table_full()), call_on_new)
         } else {
-            let new_id = *max_id;
-            *max_id += 1;
-            self.callstack_to_id.insert(callstack.into_owned(), new_id);
+            let new_id = self.max_id;
+            self.max_id += 1;
+            let callstack = callstack.into_owned();
+            self.callstacks_heap_bytes += callstack.calls.capacity()
+                * std::mem::size_of::<CallSiteId>()
+                + callstack.phase_frames.capacity() * std::mem::size_of::<FunctionId>();
+            self.callstack_to_id.insert(callstack, new_id);
             call_on_new();
             new_id
         }
     }
 
+    /// Find the Callstack with the given ID. This is a linear scan, so it
+    /// should only be used rarely.
+    fn get_callstack(&self, id: CallstackId) -> Option<&Callstack> {
+        self.callstack_to_id
+            .iter()
+            .find(|(_, csid)| **csid == id)
+            .map(|(callstack, _)| callstack)
+    }
+
+    /// The Callstacks for just the given IDs, without building the whole
+    /// reverse map.
+    pub(crate) fn get_callstacks(
+        &self,
+        ids: &HashSet<CallstackId, ARandomState>,
+    ) -> Vec<(CallstackId, Callstack)> {
+        let mut result = Vec::with_capacity(ids.len());
+        for (callstack, csid) in self.callstack_to_id.iter() {
+            if ids.contains(csid) {
+                result.push((*csid, callstack.clone()));
+            }
+        }
+        result
+    }
+
     /// Get map from IDs to Callstacks.
-    fn get_reverse_map(&self) -> HashMap<CallstackId, &Callstack, ARandomState> {
+    pub(crate) fn get_reverse_map(&self) -> HashMap<CallstackId, &Callstack, ARandomState> {
         let mut result = new_hashmap();
         for (call_site, csid) in self.callstack_to_id.iter() {
             result.insert(*csid, call_site);
@@ -355,16 +1014,59 @@ impl CallstackInterner {
     }
 }
 
+impl Default for CallstackInterner {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 const MIB: usize = 1024 * 1024;
 const HIGH_32BIT: u32 = 1 << 31;
 
+// For callstacks that more than one thread added memory with:
+const MULTIPLE_THREADS: u64 = u64::MAX;
+
+/// Combine the threads recorded for a callstack, where 0 means none.
+fn merge_threads(a: u64, b: u64) -> u64 {
+    if a == 0 || a == b {
+        b
+    } else if b == 0 {
+        a
+    } else {
+        MULTIPLE_THREADS
+    }
+}
+
 /// A unique identifier for a process.
-#[derive(Clone, Copy, Debug, PartialEq, Ord, PartialOrd, Eq, Serialize, Deserialize)]
+#[derive(Clone, Copy, Debug, PartialEq, Ord, PartialOrd, Eq, Hash, Serialize, Deserialize)]
 #[serde(transparent)]
 pub struct ProcessUid(pub u32);
 
 pub const PARENT_PROCESS: ProcessUid = ProcessUid(0);
 
+/// What kind of allocation is being recorded, see
+/// AllocationTracker::record_allocation().
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum AllocationKind {
+    Malloc,
+    Mmap,
+    /// realloc() of the allocation at old_address, which was already removed
+    /// and had old_size bytes (0 if it wasn't tracked).
+    Realloc {
+        old_address: usize,
+        old_size: usize,
+    },
+}
+
+impl ProcessUid {
+    /// A separate address namespace for memory that native code reports itself
+    /// under the given domain, so e.g. an arena's blocks don't clash with the
+    /// malloc() the arena itself came from.
+    pub fn for_domain(index: u32) -> ProcessUid {
+        ProcessUid(HIGH_32BIT | index)
+    }
+}
+
 /// A specific call to malloc()/calloc().
 #[derive(Clone, Copy, Debug, PartialEq)]
 struct Allocation {
@@ -400,6 +1102,15 @@ impl Allocation {
     }
 }
 
+/// A live allocation, as found by
+/// AllocationTracker::find_allocations_by_function().
+#[repr(C)]
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct AllocationInfo {
+    pub address: usize,
+    pub size: usize,
+}
+
 /// A CallstackCleaner that leaves the callstack unchanged.
 pub struct IdentityCleaner;
 
@@ -412,7 +1123,9 @@ impl CallstackCleaner for IdentityCleaner {
 /// The main data structure tracking everything.
 pub struct AllocationTracker<FL: WriteFunctionLocations> {
     // malloc()/calloc():
-    current_allocations: BTreeMap<ProcessUid, HashMap<usize, Allocation, ARandomState>>,
+    current_allocations: BTreeMap<ProcessUid, AddressMap<Allocation>>,
+    // How their addresses are canonicalized, see crate::address_tags:
+    address_tags: AddressTags,
     // anonymous mmap(), i.e. not file backed:
     current_anon_mmaps: BTreeMap<ProcessUid, RangeMap<CallstackId>>,
 
@@ -427,8 +1140,18 @@ pub struct AllocationTracker<FL: WriteFunctionLocations> {
     // Both malloc() and mmap():
     current_memory_usage: ImVector<usize>, // Map CallstackId -> total memory usage
     peak_memory_usage: ImVector<usize>,    // Map CallstackId -> total memory usage
+    // Just malloc(), Map CallstackId -> number of live allocations:
+    current_allocation_counts: ImVector<usize>,
+    peak_allocation_counts: ImVector<usize>,
+    // Map CallstackId -> the thread that added memory with it, 0 if none has,
+    // or MULTIPLE_THREADS:
+    callstack_threads: Vec<u64>,
     current_allocated_bytes: usize,
     peak_allocated_bytes: usize,
+    // The allocation that most recently added memory, and the ones that
+    // raised the peak:
+    last_added: Option<PeakTrigger>,
+    peak_triggers: PeakTriggers,
     // Default directory to write out data lacking other info:
     pub default_path: String,
 
@@ -437,23 +1160,273 @@ pub struct AllocationTracker<FL: WriteFunctionLocations> {
 
     // free()/realloc() of unknown address. Not relevant for sampling profiler.
     failed_deallocations: usize,
+
+    // Number of entries in current_allocations, now and at the peak:
+    live_allocations: usize,
+    peak_live_allocations: usize,
+    // Switches small allocations to sampling when there are too many:
+    adaptive: AdaptiveSampling,
+    // Degrades the profile if the tracker itself uses too much memory:
+    budget: TrackerBudget,
+    // Opt-in allocation lifetime statistics:
+    lifetimes: Option<LifetimeTracker>,
+    // realloc() statistics:
+    reallocs: ReallocTracker,
+    // Where memory was freed, if any frees were recorded with their callstack:
+    frees: Option<FreeTracker>,
+    // Live Python objects, if any were reported:
+    objects: Option<ObjectTracker>,
+    // Opt-in memory usage over time:
+    timeline: Option<Timeline>,
+    allocation_rates: Option<AllocationRates>,
+    // Opt-in live view of current memory usage:
+    live_view: Option<LiveView>,
+    // Opt-in comparison with the allocator's own numbers:
+    drift: Option<DriftDetector>,
+    // Opt-in dumps when memory first reaches given sizes:
+    milestones: Option<Milestones>,
+    // Opt-in fixed windows, each with its own peak:
+    rotation: Option<Rotation>,
+    // Allocations too big to be real are ignored, see crate::size_ceiling:
+    size_ceiling: SizeCeiling,
+    // What survived reset(), if enabled, see crate::survivors:
+    survivors: Option<Survivors>,
+    // Opt-in file-backed mmap(), kept out of the main memory usage:
+    mapped_files: Option<MappedFiles>,
+    // Opt-in temporary files, likewise kept out of it:
+    temp_files: Option<TempFiles>,
+    // Named phases of the program, e.g. imports:
+    phases: Phases,
+    // Allocations smaller than this are attributed to a single synthetic
+    // callstack, if non-zero:
+    small_allocations_below: usize,
+    small_allocations_callstack_id: Option<CallstackId>,
+    // The region being profiled, if any:
+    region: Option<Region<Allocation>>,
+    // Incremented on every reset(), with what was live before the last one
+    // kept around, see crate::generations:
+    generation: u64,
+    previous_generation: Option<PreviousGeneration<Allocation>>,
+    // Captured on reset(), see crate::environment:
+    environment: Environment,
 }
 
 impl<FL: WriteFunctionLocations> AllocationTracker<FL> {
     pub fn new(default_path: String, functions: FL) -> AllocationTracker<FL> {
         AllocationTracker {
-            current_allocations: BTreeMap::from([(PARENT_PROCESS, new_hashmap())]),
+            current_allocations: BTreeMap::from([(PARENT_PROCESS, AddressMap::new())]),
+            address_tags: AddressTags::from_env(),
             current_anon_mmaps: BTreeMap::from([(PARENT_PROCESS, RangeMap::new())]),
             interner: CallstackInterner::new(),
             current_memory_usage: ImVector::new(),
             peak_memory_usage: ImVector::new(),
+            current_allocation_counts: ImVector::new(),
+            peak_allocation_counts: ImVector::new(),
+            callstack_threads: Vec::new(),
             functions,
             current_allocated_bytes: 0,
             peak_allocated_bytes: 0,
+            last_added: None,
+            peak_triggers: PeakTriggers::from_env(),
             missing_allocated_bytes: 0,
             failed_deallocations: 0,
             default_path,
+            live_allocations: 0,
+            peak_live_allocations: 0,
+            adaptive: AdaptiveSampling::from_env(),
+            budget: TrackerBudget::from_env(),
+            lifetimes: LifetimeTracker::from_env(),
+            reallocs: ReallocTracker::new(),
+            frees: None,
+            objects: None,
+            timeline: Timeline::from_env(),
+            allocation_rates: AllocationRates::from_env(),
+            live_view: LiveView::from_env(),
+            drift: DriftDetector::from_env(),
+            milestones: Milestones::from_env(),
+            rotation: Rotation::from_env(),
+            size_ceiling: SizeCeiling::from_env(),
+            survivors: Survivors::from_env(),
+            mapped_files: None,
+            temp_files: None,
+            phases: Phases::from_env(),
+            small_allocations_below: std::env::var("FIL_SMALL_ALLOCATIONS")
+                .ok()
+                .and_then(|value| value.parse().ok())
+                .unwrap_or(0),
+            small_allocations_callstack_id: None,
+            region: None,
+            generation: 0,
+            previous_generation: None,
+            environment: Environment::default(),
+        }
+    }
+
+    /// Roll up allocations smaller than the given size into a single
+    /// synthetic callstack; 0 disables this.
+    pub fn set_small_allocations_below(&mut self, below: usize) {
+        self.small_allocations_below = below;
+        self.small_allocations_callstack_id = None;
+    }
+
+    /// Record the Python version in the report's environment.
+    pub fn set_python_version(&mut self, version: &str) {
+        self.environment.python_version = Some(version.to_string());
+    }
+
+    /// Attach a user tag, e.g. a git commit, to the report's environment.
+    /// Tags are kept across reset().
+    pub fn add_metadata(&mut self, key: &str, value: &str) {
+        self.environment
+            .user
+            .insert(key.to_string(), value.to_string());
+    }
+
+    /// The live allocations, including any from before the current region.
+    fn all_allocations(&self) -> impl Iterator<Item = &AddressMap<Allocation>> {
+        self.current_allocations.values().chain(
+            self.region
+                .iter()
+                .flat_map(|region| region.allocations.values()),
+        )
+    }
+
+    /// The live anonymous mmap()s, including any from before the current
+    /// region.
+    fn all_anon_mmaps(&self) -> impl Iterator<Item = &RangeMap<CallstackId>> {
+        self.current_anon_mmaps.values().chain(
+            self.region
+                .iter()
+                .flat_map(|region| region.anon_mmaps.values()),
+        )
+    }
+
+    /// Roughly how much memory the tracker's own tables use.
+    pub fn footprint_bytes(&self) -> usize {
+        self.all_allocations()
+            .map(|allocations| allocations.heap_bytes())
+            .sum::<usize>()
+            + self.interner.heap_bytes()
+            + self
+                .previous_generation
+                .as_ref()
+                .map(|previous| previous.heap_bytes())
+                .unwrap_or(0)
+            + (self.current_memory_usage.len()
+                + self.peak_memory_usage.len()
+                + self.current_allocation_counts.len()
+                + self.peak_allocation_counts.len())
+                * std::mem::size_of::<usize>()
+            + self.callstack_threads.capacity() * std::mem::size_of::<u64>()
+    }
+
+    /// How much footprint_bytes() could go up by before the next budget check:
+    /// the tables grow by doubling, so the budget has to leave room for that.
+    fn growth_bytes(&self) -> usize {
+        self.current_allocations
+            .values()
+            .map(|allocations| allocations.growth_bytes())
+            .sum::<usize>()
+            + self.interner.growth_bytes()
+    }
+
+    /// Cut down on memory use once the budget is exceeded: sample small
+    /// allocations and roll them up into one callstack, and stop adding new
+    /// callstacks.
+    fn degrade(&mut self) {
+        self.interner.freeze();
+        self.adaptive.force(self.live_allocations);
+        self.set_small_allocations_below(self.small_allocations_below.max(SMALL_ALLOCATION_BYTES));
+    }
+
+    /// If an allocation of this size should be attributed to the synthetic
+    /// small allocations callstack, return its id. This skips looking up the
+    /// real callstack entirely, which is most of the cost of tracking an
+    /// allocation.
+    #[inline]
+    pub fn small_allocation_callstack_id(&mut self, size: usize) -> Option<CallstackId> {
+        if size >= self.small_allocations_below {
+            return None;
+        }
+        if let Some(callstack_id) = self.small_allocations_callstack_id {
+            return Some(callstack_id);
+        }
+        let callstack_id =
+            self.get_callstack_id(&Callstack::small_allocations(self.small_allocations_below));
+        self.small_allocations_callstack_id = Some(callstack_id);
+        Some(callstack_id)
+    }
+
+    /// The id to record a new allocation from the given line of a thread's
+    /// callstack with, reusing the callstack's cached id if the line didn't
+    /// change since its last allocation.
+    pub fn callstack_id_for_allocation(
+        &mut self,
+        callstack: &mut Callstack,
+        line_number: u32,
+    ) -> CallstackId {
+        callstack.set_phase(self.current_phase());
+        callstack.id_for_new_allocation(line_number, |callstack| self.get_callstack_id(callstack))
+    }
+
+    /// Record an allocation in the parent process, as the allocation hooks do:
+    /// small ones may be attributed to the small allocations callstack, and
+    /// otherwise the callstack comes from callstack_id(), which is only called
+    /// if it's needed, so the thread's callstack needn't be available for
+    /// small allocations. The allocating thread's statistics are updated too,
+    /// see crate::threads.
+    pub fn record_allocation<E>(
+        &mut self,
+        address: usize,
+        size: usize,
+        kind: AllocationKind,
+        callstack_id: impl FnOnce(&mut Self) -> Result<CallstackId, E>,
+    ) -> Result<(), E> {
+        let small_callstack_id = if kind == AllocationKind::Mmap {
+            None
+        } else {
+            self.small_allocation_callstack_id(size)
+        };
+        let callstack_id = match small_callstack_id {
+            Some(callstack_id) => callstack_id,
+            None => callstack_id(self)?,
+        };
+        let allocated_bytes_before = self.current_allocated_bytes;
+        match kind {
+            AllocationKind::Malloc => {
+                self.add_allocation(PARENT_PROCESS, address, size, callstack_id);
+            }
+            AllocationKind::Mmap => {
+                self.add_anon_mmap(PARENT_PROCESS, address, size, callstack_id);
+            }
+            AllocationKind::Realloc {
+                old_address,
+                old_size,
+            } => {
+                self.update_allocation(
+                    PARENT_PROCESS,
+                    old_address,
+                    old_size,
+                    address,
+                    size,
+                    callstack_id,
+                );
+            }
         }
+        // Sampling means the tracker may have recorded more or less than size:
+        threads::record_allocation(
+            self.current_allocated_bytes
+                .saturating_sub(allocated_bytes_before),
+        );
+        Ok(())
+    }
+
+    /// Record an allocation in the parent process being freed, as the free()
+    /// hook does, returning its size, or 0 if it wasn't tracked.
+    pub fn record_free(&mut self, address: usize) -> usize {
+        let size = self.free_allocation(PARENT_PROCESS, address).unwrap_or(0);
+        threads::record_free(size);
+        size
     }
 
     /// Print a traceback for the given CallstackId.
@@ -483,12 +1456,41 @@ impl<FL: WriteFunctionLocations> AllocationTracker<FL> {
         self.peak_allocated_bytes
     }
 
+    /// Current and peak allocated bytes, as a consistent pair: the peak is
+    /// brought up to date first, so current can never exceed peak.
+    pub fn get_traced_memory(&mut self) -> (usize, usize) {
+        self.check_if_new_peak();
+        (self.current_allocated_bytes, self.peak_allocated_bytes)
+    }
+
+    /// The callstack responsible for the most currently allocated bytes, and
+    /// how many bytes that is.
+    pub fn dominant_callstack(&self) -> Option<(Callstack, usize)> {
+        let (index, bytes) = self
+            .current_memory_usage
+            .iter()
+            .enumerate()
+            .max_by_key(|(_, bytes)| **bytes)?;
+        if *bytes == 0 {
+            return None;
+        }
+        self.interner
+            .get_callstack(index as CallstackId)
+            .map(|callstack| (callstack.clone(), *bytes))
+    }
+
     pub fn get_allocation_size(&self, process: ProcessUid, address: usize) -> usize {
+        let address = self.address_tags.canonicalize(address);
         if let Some(allocation) = self
             .current_allocations
             .get(&process)
-            .map(|a| a.get(&address))
-            .flatten()
+            .and_then(|a| a.get(address))
+            .or_else(|| {
+                self.region
+                    .as_ref()
+                    .and_then(|region| region.allocations.get(&process))
+                    .and_then(|a| a.get(address))
+            })
         {
             allocation.size()
         } else {
@@ -496,19 +1498,154 @@ impl<FL: WriteFunctionLocations> AllocationTracker<FL> {
         }
     }
 
-    /// Check if a new peak has been reached:
+    /// Find the live malloc()-style allocations whose callstack includes a
+    /// frame for the given function in the given file. Up to `out.len()` of
+    /// them are written to `out`, and the total number found is returned.
+    /// The function's name can be qualified, see qualified_name(), or not.
+    ///
+    /// This scans all callstacks and live allocations, so it's slow, and only
+    /// meant for debugging. The only memory it allocates is a flag per
+    /// distinct callstack.
+    pub fn find_allocations_by_function(
+        &self,
+        filename: &str,
+        function_name: &str,
+        out: &mut [AllocationInfo],
+    ) -> usize {
+        let functions = self.functions.cheap_clone().to_reader();
+        let mut matching_callstacks = vec![false; self.interner.max_id as usize];
+        for (callstack, callstack_id) in self.interner.callstack_to_id.iter() {
+            matching_callstacks[*callstack_id as usize] = callstack.calls.iter().any(|call| {
+                let (function, file, display_file) =
+                    functions.get_function_and_filename_and_display_filename(call.function);
+                (function == function_name
+                    || functions.get_unqualified_function_name(call.function) == function_name)
+                    && (file == filename || display_file == filename)
+            });
+        }
+        let mut found = 0;
+        for allocations in self.all_allocations() {
+            for (address, allocation) in allocations.iter() {
+                if !matching_callstacks[allocation.callstack_id as usize] {
+                    continue;
+                }
+                if let Some(slot) = out.get_mut(found) {
+                    *slot = AllocationInfo {
+                        address,
+                        size: allocation.size(),
+                    };
+                }
+                found += 1;
+            }
+        }
+        found
+    }
+
+    /// How much of the peak each line of the given function in the given file
+    /// is responsible for, see crate::function_detail. Returns None if no
+    /// memory at peak was allocated with the function in the callstack. As
+    /// with find_allocations_by_function(), the name can be qualified or not.
+    ///
+    /// This scans all callstacks, but not individual allocations, and is
+    /// unaffected by the filtering done for flamegraphs.
+    pub fn function_detail(
+        &mut self,
+        filename: &str,
+        function_name: &str,
+    ) -> Option<FunctionDetail> {
+        self.check_if_new_peak();
+        let functions = self.functions.cheap_clone().to_reader();
+        // For matching functions, the file their source code is in:
+        let mut matching: HashMap<FunctionId, Option<String>, ARandomState> = new_hashmap();
+        let mut line_bytes = vec![];
+        let mut source_filename = None;
+        for (callstack, callstack_id) in self.interner.callstack_to_id.iter() {
+            let bytes = self
+                .peak_memory_usage
+                .get(*callstack_id as usize)
+                .copied()
+                .unwrap_or(0);
+            if bytes == 0 {
+                continue;
+            }
+            for call in callstack.calls.iter().rev() {
+                let file = matching.entry(call.function).or_insert_with(|| {
+                    let (function, file, display_file) =
+                        functions.get_function_and_filename_and_display_filename(call.function);
+                    ((function == function_name
+                        || functions.get_unqualified_function_name(call.function) == function_name)
+                        && (file == filename || display_file == filename))
+                        .then(|| file.to_string())
+                });
+                if let Some(file) = file {
+                    let line = match call.line_number {
+                        LineNumberInfo::LineNumber(line) => line,
+                        LineNumberInfo::BytecodeIndex(_)
+                        | LineNumberInfo::Omitted
+                        | LineNumberInfo::Synthetic => 0,
+                    };
+                    line_bytes.push((line, bytes));
+                    source_filename.get_or_insert_with(|| file.clone());
+                    // Only the innermost frame counts:
+                    break;
+                }
+            }
+        }
+        Some(FunctionDetail::new(
+            filename,
+            function_name,
+            &source_filename?,
+            self.peak_allocated_bytes,
+            line_bytes,
+        ))
+    }
+
+    /// Check if a new peak has been reached.
+    ///
+    /// This runs after every allocation while memory is climbing, so it has to
+    /// be cheap: the snapshot only covers per-callstack totals, not individual
+    /// allocations, and cloning an im::Vector is O(1), with later changes to
+    /// the current usage copying just the chunks they touch.
     pub fn check_if_new_peak(&mut self) {
         if self.current_allocated_bytes > self.peak_allocated_bytes {
             self.peak_allocated_bytes = self.current_allocated_bytes;
             self.peak_memory_usage
                 .clone_from(&self.current_memory_usage);
+            self.peak_allocation_counts
+                .clone_from(&self.current_allocation_counts);
+            if let Some(last_added) = self.last_added {
+                self.peak_triggers
+                    .record(last_added, self.peak_allocated_bytes);
+            }
+            self.peak_live_allocations = self.live_allocations;
+        }
+        if let Some(mapped_files) = self.mapped_files.as_mut() {
+            mapped_files
+                .check_if_new_peak(self.current_allocated_bytes, &self.current_memory_usage);
+        }
+        if let Some(region) = self.region.as_mut() {
+            region.check_if_new_peak();
         }
     }
 
     fn add_memory_usage(&mut self, callstack_id: CallstackId, bytes: usize) {
         self.current_allocated_bytes += bytes;
+        let thread_id = current_thread_id();
+        self.last_added = Some(PeakTrigger {
+            callstack_id,
+            bytes,
+            thread_id,
+        });
         let index = callstack_id as usize;
         self.current_memory_usage[index] += bytes;
+        let thread = &mut self.callstack_threads[index];
+        *thread = merge_threads(*thread, thread_id);
+        if let Some(region) = self.region.as_mut() {
+            region.add(callstack_id, bytes);
+        }
+        if let Some(milestones) = self.milestones.as_mut() {
+            milestones.check(self.current_allocated_bytes, &self.current_memory_usage);
+        }
     }
 
     fn remove_memory_usage(&mut self, callstack_id: CallstackId, bytes: usize) {
@@ -516,14 +1653,75 @@ impl<FL: WriteFunctionLocations> AllocationTracker<FL> {
         let index = callstack_id as usize;
         // TODO what if goes below zero? add a check I guess, in case of bugs.
         self.current_memory_usage[index] -= bytes;
+        if let Some(region) = self.region.as_mut() {
+            region.remove(callstack_id, bytes);
+        }
+    }
+
+    /// Like remove_memory_usage(), for memory from before the current region.
+    fn remove_pre_existing_memory_usage(&mut self, callstack_id: CallstackId, bytes: usize) {
+        self.current_allocated_bytes -= bytes;
+        self.current_memory_usage[callstack_id as usize] -= bytes;
+        if let Some(region) = self.region.as_mut() {
+            region.remove_pre_existing(bytes);
+        }
     }
 
     pub fn get_callstack_id(&mut self, callstack: &Callstack) -> CallstackId {
+        let phase = self.phases.current();
+        let callstack = if callstack.phase == phase {
+            Cow::Borrowed(callstack)
+        } else {
+            let mut callstack = callstack.clone();
+            callstack.set_phase(phase);
+            Cow::Owned(callstack)
+        };
+        self.intern_callstack(&callstack)
+    }
+
+    /// Like get_callstack_id(), but keeping the callstack's own phase.
+    fn intern_callstack(&mut self, callstack: &Callstack) -> CallstackId {
         let current_memory_usage = &mut self.current_memory_usage;
-        self.interner
+        let current_allocation_counts = &mut self.current_allocation_counts;
+        let callstack_threads = &mut self.callstack_threads;
+        let callstack_id = self
+            .interner
             .get_or_insert_id(Cow::Borrowed(callstack), || {
-                current_memory_usage.push_back(0)
-            })
+                current_memory_usage.push_back(0);
+                current_allocation_counts.push_back(0);
+                callstack_threads.push(0);
+            });
+        self.phases.add_callstack(callstack_id, callstack.phase);
+        if let Some(timeline) = self.timeline.as_mut() {
+            timeline.add_callstack(callstack_id, callstack);
+        }
+        callstack_id
+    }
+
+    /// Switch to a new named phase; see crate::phases.
+    pub fn mark_phase(&mut self, name: &str) {
+        self.phases.mark(name);
+        // The synthetic small allocations callstack is per-phase too:
+        self.small_allocations_callstack_id = None;
+    }
+
+    /// The current phase, which new allocations get tagged with.
+    pub fn current_phase(&self) -> PhaseId {
+        self.phases.current()
+    }
+
+    /// Whether an allocation of this size is plausible, see
+    /// crate::size_ceiling. Bigger ones are ignored by add_allocation() and
+    /// friends.
+    #[inline]
+    pub fn allows_size(&self, size: usize) -> bool {
+        self.size_ceiling.allows(size)
+    }
+
+    /// Count an allocation that was ignored, or failed, because it was too
+    /// big to be real.
+    pub fn reject_allocation(&mut self, size: usize) {
+        self.size_ceiling.reject(size);
     }
 
     /// Add a new allocation based off the current callstack.
@@ -534,14 +1732,39 @@ impl<FL: WriteFunctionLocations> AllocationTracker<FL> {
         size: usize,
         callstack_id: CallstackId,
     ) {
+        if !self.allows_size(size) {
+            self.reject_allocation(size);
+            return;
+        }
+        let address = self.address_tags.canonicalize(address);
+        if self.budget.due()
+            && self
+                .budget
+                .check(self.footprint_bytes() + self.growth_bytes())
+        {
+            self.degrade();
+        }
+        // Every call counts, whether or not it ends up sampled:
+        if let Some(allocation_rates) = self.allocation_rates.as_mut() {
+            allocation_rates.record(callstack_id);
+        }
+        let size = match self.adaptive.size_to_record(size) {
+            Some(size) => size,
+            // Not sampled, so not recorded:
+            None => return,
+        };
         let alloc = Allocation::new(callstack_id, size);
         let compressed_size = alloc.size();
-        if let Some(previous) = self
+        let previous = self
             .current_allocations
             .entry(process)
             .or_default()
-            .insert(address, alloc)
-        {
+            .insert(address, alloc);
+        if previous.is_none() {
+            self.live_allocations += 1;
+            self.adaptive.update(self.live_allocations);
+        }
+        if let Some(previous) = previous {
             // In production use (proposed commercial product) allocations are
             // only sampled, so missing allocations are common and not the sign
             // of an error.
@@ -555,6 +1778,7 @@ impl<FL: WriteFunctionLocations> AllocationTracker<FL> {
                 self.missing_allocated_bytes += previous.size();
                 // Cleanup the previous allocation, since we never saw its free():
                 self.remove_memory_usage(previous.callstack_id, previous.size());
+                self.current_allocation_counts[previous.callstack_id as usize] -= 1;
                 if *crate::util::DEBUG_MODE {
                     self.print_traceback(
                         "The allocation from this traceback disappeared:",
@@ -571,28 +1795,101 @@ impl<FL: WriteFunctionLocations> AllocationTracker<FL> {
                 }
             }
         }
-        self.add_memory_usage(callstack_id, compressed_size as usize);
+        self.add_memory_usage(callstack_id, compressed_size);
+        self.current_allocation_counts[callstack_id as usize] += 1;
+        if let Some(lifetimes) = self.lifetimes.as_mut() {
+            lifetimes.add_allocation(process, address, compressed_size, callstack_id);
+        }
+    }
+
+    /// Add the result of a realloc(). The old allocation should already have
+    /// been removed with free_allocation(), which returned old_size.
+    pub fn update_allocation(
+        &mut self,
+        process: ProcessUid,
+        old_address: usize,
+        old_size: usize,
+        new_address: usize,
+        new_size: usize,
+        callstack_id: CallstackId,
+    ) {
+        if !self.allows_size(new_size) {
+            self.reject_allocation(new_size);
+            return;
+        }
+        let old_address = self.address_tags.canonicalize(old_address);
+        let new_address = self.address_tags.canonicalize(new_address);
+        self.add_allocation(process, new_address, new_size, callstack_id);
+        self.reallocs
+            .record(callstack_id, old_address, old_size, new_address, new_size);
     }
 
     /// Free an existing allocation, return how much was removed, if any.
     pub fn free_allocation(&mut self, process: ProcessUid, address: usize) -> Option<usize> {
+        self.remove_allocation(process, address)
+            .map(|removed| removed.size())
+    }
+
+    /// Like free_allocation(), but also record the callstack doing the
+    /// freeing.
+    pub fn free_allocation_from(
+        &mut self,
+        process: ProcessUid,
+        address: usize,
+        freeing_callstack_id: CallstackId,
+    ) -> Option<usize> {
+        let removed = self.remove_allocation(process, address)?;
+        self.frees.get_or_insert_with(FreeTracker::new).record(
+            removed.callstack_id,
+            freeing_callstack_id,
+            removed.size(),
+        );
+        Some(removed.size())
+    }
+
+    fn remove_allocation(&mut self, process: ProcessUid, address: usize) -> Option<Allocation> {
+        let address = self.address_tags.canonicalize(address);
         // Before we reduce memory, let's check if we've previously hit a peak:
         self.check_if_new_peak();
 
-        if let Some(removed) = self
+        let (removed, pre_existing) = match self
             .current_allocations
             .entry(process)
             .or_default()
-            .remove(&address)
+            .remove(address)
         {
-            self.remove_memory_usage(removed.callstack_id, removed.size());
-            Some(removed.size())
+            Some(removed) => (Some(removed), false),
+            None => (
+                self.region
+                    .as_mut()
+                    .and_then(|region| region.allocations.get_mut(&process))
+                    .and_then(|allocations| allocations.remove(address)),
+                true,
+            ),
+        };
+        if let Some(removed) = removed {
+            if pre_existing {
+                self.remove_pre_existing_memory_usage(removed.callstack_id, removed.size());
+            } else {
+                self.remove_memory_usage(removed.callstack_id, removed.size());
+            }
+            self.current_allocation_counts[removed.callstack_id as usize] -= 1;
+            self.live_allocations -= 1;
+            self.adaptive.update(self.live_allocations);
+            if let Some(lifetimes) = self.lifetimes.as_mut() {
+                lifetimes.free_allocation(process, address, removed.callstack_id);
+            }
+            Some(removed)
+        } else if self.free_previous_generation_allocation(process, address) {
+            // Allocated before the last reset, so nothing to update:
+            None
         } else {
             // This allocation doesn't exist; often this will be something
             // allocated before Fil tracking was started, but it might also be a
-            // bug.
+            // bug. Once sampling has kicked in it's expected, since most
+            // small allocations aren't recorded.
             #[cfg(not(feature = "fil4prod"))]
-            if *crate::util::DEBUG_MODE {
+            if *crate::util::DEBUG_MODE && !self.adaptive.was_ever_engaged() {
                 self.failed_deallocations += 1;
                 eprintln!(
                     "=fil-profile= Your program attempted to free an allocation at an address we don't know about:"
@@ -603,6 +1900,25 @@ impl<FL: WriteFunctionLocations> AllocationTracker<FL> {
         }
     }
 
+    /// If the address was allocated before the last reset(), forget it and
+    /// return true.
+    fn free_previous_generation_allocation(&mut self, process: ProcessUid, address: usize) -> bool {
+        let Some(previous) = self.previous_generation.as_mut() else {
+            return false;
+        };
+        let Some(freed) = previous.free_allocation(process, address) else {
+            return false;
+        };
+        if previous.is_empty() {
+            self.previous_generation = None;
+        }
+        if let Some(survivors) = self.survivors.as_mut() {
+            // The callstack id is the survivors' bucket, see reset():
+            survivors.free(freed.callstack_id, freed.size());
+        }
+        true
+    }
+
     /// Add a new anonymous mmap() based of the current callstack.
     pub fn add_anon_mmap(
         &mut self,
@@ -611,6 +1927,10 @@ impl<FL: WriteFunctionLocations> AllocationTracker<FL> {
         size: usize,
         callstack_id: CallstackId,
     ) {
+        if !self.allows_size(size) {
+            self.reject_allocation(size);
+            return;
+        }
         self.current_anon_mmaps
             .entry(process)
             .or_default()
@@ -630,6 +1950,77 @@ impl<FL: WriteFunctionLocations> AllocationTracker<FL> {
         {
             self.remove_memory_usage(callstack_id, removed);
         }
+        let pre_existing = self
+            .region
+            .as_mut()
+            .and_then(|region| region.anon_mmaps.get_mut(&process))
+            .map(|mmaps| mmaps.remove(address, size))
+            .unwrap_or_default();
+        for (callstack_id, removed) in pre_existing {
+            self.remove_pre_existing_memory_usage(callstack_id, removed);
+        }
+        if let Some(previous) = self.previous_generation.as_mut() {
+            if previous.free_anon_mmap(process, address, size) > 0 && previous.is_empty() {
+                self.previous_generation = None;
+            }
+        }
+    }
+
+    /// Add a new file-backed mmap(); these are tracked separately from the
+    /// main memory usage, see crate::mapped_files.
+    pub fn add_file_mmap(
+        &mut self,
+        process: ProcessUid,
+        address: usize,
+        size: usize,
+        callstack_id: CallstackId,
+    ) {
+        if !self.allows_size(size) {
+            self.reject_allocation(size);
+            return;
+        }
+        self.mapped_files.get_or_insert_with(MappedFiles::new).add(
+            process,
+            address,
+            size,
+            callstack_id,
+        );
+    }
+
+    pub fn free_file_mmap(&mut self, process: ProcessUid, address: usize, size: usize) {
+        if self.mapped_files.is_none() {
+            return;
+        }
+        self.check_if_new_peak();
+        if let Some(mapped_files) = self.mapped_files.as_mut() {
+            mapped_files.remove(process, address, size);
+        }
+    }
+
+    /// A file that is, or may become, a temporary file was opened; these are
+    /// tracked separately from memory, see crate::temp_files.
+    pub fn temp_file_opened(&mut self, fd: c_int, file: OpenedFile, callstack_id: CallstackId) {
+        self.temp_files
+            .get_or_insert_with(TempFiles::new)
+            .opened(fd, file, callstack_id);
+    }
+
+    pub fn temp_file_unlinked(&mut self, path: &Path) {
+        if let Some(temp_files) = self.temp_files.as_mut() {
+            temp_files.unlinked(path);
+        }
+    }
+
+    pub fn temp_file_resized(&mut self, fd: c_int, bytes: usize, grow_only: bool) {
+        if let Some(temp_files) = self.temp_files.as_mut() {
+            temp_files.resized(fd, bytes, grow_only);
+        }
+    }
+
+    pub fn temp_file_closed(&mut self, fd: c_int) {
+        if let Some(temp_files) = self.temp_files.as_mut() {
+            temp_files.closed(fd);
+        }
     }
 
     /// The process just died, remove all the allocations.
@@ -637,6 +2028,18 @@ impl<FL: WriteFunctionLocations> AllocationTracker<FL> {
         // Before we reduce memory, let's check if we've previously hit a peak:
         self.check_if_new_peak();
 
+        if let Some(mapped_files) = self.mapped_files.as_mut() {
+            mapped_files.drop_process(process);
+        }
+        if let Some(previous) = self.previous_generation.as_mut() {
+            let dropped = previous.drop_process(process);
+            if let (Some(survivors), Some(dropped)) = (self.survivors.as_mut(), dropped) {
+                for allocation in dropped.values() {
+                    survivors.free(allocation.callstack_id, allocation.size());
+                }
+            }
+        }
+
         // Drop anon mmaps, call remove_memory_usage on all entries.
         if let Some(mmaps_for_process) = self.current_anon_mmaps.remove(&process) {
             for (size, callstack_id) in mmaps_for_process.into_iter() {
@@ -644,10 +2047,49 @@ impl<FL: WriteFunctionLocations> AllocationTracker<FL> {
             }
         }
 
+        // Same for anything from before the current region:
+        let (pre_existing_allocations, pre_existing_mmaps) = match self.region.as_mut() {
+            Some(region) => (
+                region.allocations.remove(&process),
+                region.anon_mmaps.remove(&process),
+            ),
+            None => (None, None),
+        };
+        for (size, callstack_id) in pre_existing_mmaps.into_iter().flat_map(|m| m.into_iter()) {
+            self.remove_pre_existing_memory_usage(callstack_id, size);
+        }
+        if let Some(allocations) = pre_existing_allocations {
+            for allocation in allocations.values() {
+                self.remove_pre_existing_memory_usage(allocation.callstack_id, allocation.size());
+                self.current_allocation_counts[allocation.callstack_id as usize] -= 1;
+            }
+            self.live_allocations -= allocations.len();
+            self.adaptive.update(self.live_allocations);
+            if let Some(lifetimes) = self.lifetimes.as_mut() {
+                lifetimes.drop_process(
+                    process,
+                    allocations
+                        .iter()
+                        .map(|(address, allocation)| (address, allocation.callstack_id)),
+                );
+            }
+        }
+
         // Drop allocations, call remove_memory_usage on all entries.
         if let Some(allocations_for_process) = self.current_allocations.remove(&process) {
             for allocation in allocations_for_process.values() {
                 self.remove_memory_usage(allocation.callstack_id, allocation.size());
+                self.current_allocation_counts[allocation.callstack_id as usize] -= 1;
+            }
+            self.live_allocations -= allocations_for_process.len();
+            self.adaptive.update(self.live_allocations);
+            if let Some(lifetimes) = self.lifetimes.as_mut() {
+                lifetimes.drop_process(
+                    process,
+                    allocations_for_process
+                        .iter()
+                        .map(|(address, allocation)| (address, allocation.callstack_id)),
+                );
             }
         }
     }
@@ -670,66 +2112,240 @@ impl<FL: WriteFunctionLocations> AllocationTracker<FL> {
         // edge cases that make it slightly inconsistent (e.g. see the
         // unexpected code path in add_allocation() above), and blowing up
         // without giving the user their data just because of a small
-        // inconsistency doesn't seem ideal. Perhaps if validate() merely
-        // reported problems, or maybe validate() should only be enabled in
-        // development mode.
-        //self.validate();
+        // inconsistency doesn't seem ideal. Tests and pymemprofile_self_check()
+        // can call validate() instead.
 
         // We get a LOT of tiny allocations. To reduce overhead of creating
         // flamegraph (which currently loads EVERYTHING into memory), just do
-        // the top 99% of allocations.
-        let callstacks = if peak {
+        // the top 99% of allocations, unless there's a report budget, see
+        // crate::report_budget.
+        let (callstacks, allocation_counts) = if peak {
             self.check_if_new_peak();
-            &self.peak_memory_usage
+            (&self.peak_memory_usage, &self.peak_allocation_counts)
         } else {
-            &self.current_memory_usage
+            (&self.current_memory_usage, &self.current_allocation_counts)
         };
+        // Only the Arrow table has room for the details:
+        let allocation_counts = cfg!(feature = "arrow-export").then_some(allocation_counts);
+        self.combine_memory_usage(callstacks, allocation_counts, callstack_cleaner)
+    }
+
+    /// Like combine_callstacks(), for the given map of CallstackId -> memory
+    /// usage. If allocation counts are given, the flamegraph gets
+    /// CallstackDetails too.
+    fn combine_memory_usage<CC: CallstackCleaner>(
+        &self,
+        callstacks: &ImVector<usize>,
+        allocation_counts: Option<&ImVector<usize>>,
+        callstack_cleaner: CC,
+    ) -> impl FnOnce() -> FlamegraphCallstacks<HashMap<Callstack, usize, ARandomState>, FL::Reader, CC>
+    {
         let sum = callstacks.iter().sum();
         let id_to_callstack = self.interner.get_reverse_map();
-        let data = filter_to_useful_callstacks(callstacks.iter().enumerate(), sum)
-            .into_iter()
-            .filter_map(|(k, v)| {
-                id_to_callstack
-                    .get(&(k as CallstackId))
-                    .map(|cs| ((**cs).clone(), v))
-            })
-            .collect();
+        let phases = &self.phases;
+        let line_numbers = !aggregate_lines();
+        let budget = report_budget::max_report_stacks();
+        let useful = match budget {
+            Some(_) => Either::Left(
+                callstacks
+                    .iter()
+                    .enumerate()
+                    .map(|(k, v)| (k, *v))
+                    .filter(|(_, v)| *v > 0),
+            ),
+            None => Either::Right(filter_to_useful_callstacks(
+                callstacks.iter().enumerate(),
+                sum,
+            )),
+        };
+        let mut data: HashMap<Callstack, usize, ARandomState> = new_hashmap();
+        // Callstack -> (allocation count, thread):
+        let mut details: HashMap<Callstack, (usize, u64), ARandomState> = new_hashmap();
+        for (k, v) in useful
+            // Excluded phases still count towards the total, they're just not
+            // shown:
+            .filter(|(k, _)| !phases.is_excluded(*k as CallstackId))
+        {
+            if let Some(cs) = id_to_callstack.get(&(k as CallstackId)) {
+                let mut cs = if line_numbers {
+                    (**cs).clone()
+                } else {
+                    cs.without_line_numbers()
+                };
+                // Unless phases are shown, the same callstack from different
+                // phases should be merged:
+                if !phases.root_frames() {
+                    cs.set_phase(NO_PHASE);
+                }
+                if let Some(allocation_counts) = allocation_counts {
+                    let (count, thread) = details.entry(cs.clone()).or_default();
+                    *count += allocation_counts.get(k).copied().unwrap_or(0);
+                    *thread = merge_threads(*thread, self.callstack_threads[k]);
+                }
+                *data.entry(cs).or_insert(0) += v;
+            }
+        }
+        let phase_frames = if phases.root_frames() {
+            Some(phases.names().to_vec())
+        } else {
+            None
+        };
+        let with_details = allocation_counts.is_some();
         let functions_writer = self.functions.cheap_clone();
+        let environment = self.environment.clone();
 
         // Return a closure, so we can delay doing the ReadFunctionLocations
-        // conversion if necessary:
-        || FlamegraphCallstacks::new(data, functions_writer.to_reader(), callstack_cleaner)
+        // conversion, and merging callstacks to fit the budget, if necessary:
+        move || {
+            let (data, full_data) = match budget {
+                Some(max) => {
+                    let full_data = if report_budget::full_report_stacks() {
+                        Some(data.clone())
+                    } else {
+                        None
+                    };
+                    (report_budget::fit_to_budget(data, max), full_data)
+                }
+                None => (data, None),
+            };
+            let mut flamegraph =
+                FlamegraphCallstacks::new(data, functions_writer.to_reader(), callstack_cleaner)
+                    .with_environment(environment);
+            if let Some(full_data) = full_data {
+                flamegraph = flamegraph.with_full_data(full_data);
+            }
+            if with_details {
+                let details = details
+                    .into_iter()
+                    .map(|(cs, (allocation_count, thread))| {
+                        let thread_id =
+                            (thread != 0 && thread != MULTIPLE_THREADS).then_some(thread);
+                        (
+                            cs,
+                            CallstackDetails {
+                                allocation_count,
+                                thread_id,
+                            },
+                        )
+                    })
+                    .collect();
+                flamegraph = flamegraph.with_details(details);
+            }
+            match phase_frames {
+                Some(names) => flamegraph.with_phase_frames(names),
+                None => flamegraph,
+            }
+        }
     }
 
     /// Clear memory we won't be needing anymore, since we're going to exit out.
     pub fn oom_break_glass(&mut self) {
+        self.region = None;
         self.current_allocations.clear();
+        self.live_allocations = 0;
         self.peak_memory_usage.clear();
+        self.peak_allocation_counts.clear();
+        self.lifetimes = None;
+        self.frees = None;
+        self.objects = None;
+        if let Some(milestones) = self.milestones.as_mut() {
+            milestones.take_pending();
+        }
     }
 
-    /// Validate internal state is in a good state. This won't pass until
+    /// Check that internal state is consistent, returning a description of
+    /// each problem found. The peak checks won't pass until
     /// check_if_new_peak() is called.
-    fn validate(&self) {
-        assert!(self.peak_allocated_bytes >= self.current_allocated_bytes);
-        let current_allocations: usize = self
-            .current_anon_mmaps
-            .values()
-            .map(|maps| maps.size())
-            .sum::<usize>()
-            + self
-                .current_allocations
-                .values()
-                .flat_map(|allocs| allocs.iter())
-                .map(|(_, alloc)| alloc.size())
-                .sum::<usize>();
-        assert!(
-            current_allocations == self.current_allocated_bytes,
-            "{} != {}",
-            current_allocations,
-            self.current_allocated_bytes
-        );
-        assert!(self.current_memory_usage.iter().sum::<usize>() == self.current_allocated_bytes);
-        assert!(self.peak_memory_usage.iter().sum::<usize>() == self.peak_allocated_bytes);
+    #[must_use]
+    pub fn validate(&self) -> Vec<String> {
+        let mut problems = vec![];
+        if self.peak_allocated_bytes < self.current_allocated_bytes {
+            problems.push(format!(
+                "peak bytes {} < current bytes {}",
+                self.peak_allocated_bytes, self.current_allocated_bytes
+            ));
+        }
+
+        // Recompute per-callstack usage from the live allocations and mmaps:
+        let mut usage: HashMap<CallstackId, usize, ARandomState> = new_hashmap();
+        let mut counts: HashMap<CallstackId, usize, ARandomState> = new_hashmap();
+        for (_, alloc) in self.all_allocations().flat_map(|allocs| allocs.iter()) {
+            *usage.entry(alloc.callstack_id).or_default() += alloc.size();
+            *counts.entry(alloc.callstack_id).or_default() += 1;
+        }
+        for maps in self.all_anon_mmaps() {
+            for (size, callstack_id) in maps.iter() {
+                *usage.entry(*callstack_id).or_default() += size;
+            }
+        }
+        let live_bytes: usize = usage.values().sum();
+        if live_bytes != self.current_allocated_bytes {
+            problems.push(format!(
+                "live allocations add up to {} bytes, but current bytes is {}",
+                live_bytes, self.current_allocated_bytes
+            ));
+        }
+        for (callstack_id, bytes) in usage.iter() {
+            if *callstack_id >= self.interner.max_id {
+                problems.push(format!(
+                    "callstack {} is used by live allocations but isn't interned",
+                    callstack_id
+                ));
+            }
+            let recorded = self
+                .current_memory_usage
+                .get(*callstack_id as usize)
+                .copied()
+                .unwrap_or(0);
+            if recorded != *bytes {
+                problems.push(format!(
+                    "callstack {} has {} live bytes, but its usage is {}",
+                    callstack_id, bytes, recorded
+                ));
+            }
+        }
+
+        for (callstack_id, recorded) in self.current_allocation_counts.iter().enumerate() {
+            let count = counts
+                .get(&(callstack_id as CallstackId))
+                .copied()
+                .unwrap_or(0);
+            if *recorded != count {
+                problems.push(format!(
+                    "callstack {} has {} live allocations, but its count is {}",
+                    callstack_id, count, recorded
+                ));
+            }
+        }
+
+        let current_usage: usize = self.current_memory_usage.iter().sum();
+        if current_usage != self.current_allocated_bytes {
+            problems.push(format!(
+                "per-callstack usage adds up to {} bytes, but current bytes is {}",
+                current_usage, self.current_allocated_bytes
+            ));
+        }
+        let peak_usage: usize = self.peak_memory_usage.iter().sum();
+        if peak_usage != self.peak_allocated_bytes {
+            problems.push(format!(
+                "peak per-callstack usage adds up to {} bytes, but peak bytes is {}",
+                peak_usage, self.peak_allocated_bytes
+            ));
+        }
+        let live_allocations: usize = self.all_allocations().map(|allocs| allocs.len()).sum();
+        if live_allocations != self.live_allocations {
+            problems.push(format!(
+                "{} allocations in the map, but the live count is {}",
+                live_allocations, self.live_allocations
+            ));
+        }
+        problems
+    }
+
+    /// Panic if validate() finds any problems.
+    fn assert_valid(&self) {
+        let problems = self.validate();
+        assert!(problems.is_empty(), "{}", problems.join("\n"));
     }
 
     /// Warn of untracked allocations; only relevant if you are profiling _all_
@@ -748,89 +2364,868 @@ impl<FL: WriteFunctionLocations> AllocationTracker<FL> {
         }
     }
 
-    /// Reset internal state in way that doesn't invalidate e.g. thread-local
-    /// caching of callstack ID.
-    pub fn reset(&mut self, default_path: String) {
-        self.current_allocations.clear();
-        self.current_anon_mmaps = BTreeMap::from([(PARENT_PROCESS, RangeMap::new())]);
-        for i in self.current_memory_usage.iter_mut() {
-            *i = 0;
+    /// Names of the phase frames that were active for the allocation that
+    /// reached the peak, outermost first.
+    pub fn peak_phase_frames(&self) -> Vec<String> {
+        let Some(peak_callstack) = self.peak_triggers.latest().map(|t| t.callstack_id) else {
+            return vec![];
+        };
+        let Some((callstack, _)) = self
+            .interner
+            .callstack_to_id
+            .iter()
+            .find(|(_, id)| **id == peak_callstack)
+        else {
+            return vec![];
+        };
+        if callstack.phase_frames().is_empty() {
+            return vec![];
         }
-        self.peak_memory_usage = ImVector::new();
-        self.current_allocated_bytes = 0;
-        self.peak_allocated_bytes = 0;
-        self.default_path = default_path;
-        self.validate();
+        let functions = self.functions.cheap_clone().to_reader();
+        callstack
+            .phase_frames()
+            .iter()
+            .map(|function| {
+                let (name, _, _) =
+                    functions.get_function_and_filename_and_display_filename(*function);
+                name.to_string()
+            })
+            .collect()
     }
-}
-
-#[cfg(test)]
-mod tests {
-    use crate::memorytracking::{
-        IdentityCleaner, ProcessUid, ReadFunctionLocations, WriteFunctionLocations, PARENT_PROCESS,
-    };
-
-    use super::LineNumberInfo::LineNumber;
-    use super::{
-        Allocation, AllocationTracker, CallSiteId, Callstack, CallstackInterner, FunctionId,
-        VecFunctionLocations, HIGH_32BIT, MIB,
-    };
-    use proptest::prelude::*;
-    use std::borrow::Cow;
-    use std::collections::HashMap;
 
-    fn new_tracker() -> AllocationTracker<VecFunctionLocations> {
-        AllocationTracker::new(".".to_string(), VecFunctionLocations::new())
+    /// The allocations that raised the peak, most recent last. Returns a
+    /// factory for the same reasons as lifetime_report().
+    pub fn peak_triggers_report(&mut self) -> impl FnOnce() -> Vec<PeakTriggerReport> {
+        self.check_if_new_peak();
+        let gather = self.peak_triggers.report(&self.interner.get_reverse_map());
+        let functions_writer = self.functions.cheap_clone();
+        move || gather(&functions_writer.to_reader())
     }
 
-    proptest! {
-        // Allocation sizes smaller than 2 ** 31 are round-tripped.
-        #[test]
-        fn small_allocation(size in 0..(HIGH_32BIT - 1)) {
-            let allocation = Allocation::new(0, size as usize);
-            prop_assert_eq!(size as usize, allocation.size());
+    /// Gather a report of the peak, or if peak is false of current
+    /// allocations, to be written once the tracker is unlocked, see
+    /// crate::peak_report.
+    pub fn peak_report(&mut self, peak: bool) -> PeakReport<FL::Reader>
+    where
+        FL: 'static,
+    {
+        // Print warning if we're missing allocations.
+        self.warn_on_problems(peak);
+        let allocated_bytes = if peak {
+            self.get_peak_allocated_bytes()
+        } else {
+            self.get_current_allocated_bytes()
+        };
+        let callstacks = Box::new(self.combine_callstacks(peak, IdentityCleaner));
+        if peak {
+            // So the timeline covers the whole run, even if it was short:
+            self.sample_timeline();
+            self.sample_allocation_rates();
+        }
+        PeakReport {
+            peak,
+            allocated_bytes,
+            callstacks,
+            metadata: self.report_metadata(),
+            peak_triggers: Box::new(self.peak_triggers_report()),
+            lifetimes: self
+                .lifetime_report()
+                .map(|factory| Box::new(factory) as Box<dyn FnOnce() -> _>),
+            reallocs: Box::new(self.realloc_report()),
+            frees: self
+                .frees_report()
+                .map(|factory| Box::new(factory) as Box<dyn FnOnce() -> _>),
+            objects: self
+                .objects_report()
+                .map(|factory| Box::new(factory) as Box<dyn FnOnce() -> _>),
+            timeline: self
+                .timeline_report()
+                .map(|factory| Box::new(factory) as Box<dyn FnOnce() -> _>),
+            mapped_files: self
+                .mapped_files_report()
+                .map(|factory| Box::new(factory) as Box<dyn FnOnce() -> _>),
+            temp_files: self
+                .temp_files_report()
+                .map(|factory| Box::new(factory) as Box<dyn FnOnce() -> _>),
+            allocation_rates: self
+                .allocation_rates_report()
+                .map(|factory| Box::new(factory) as Box<dyn FnOnce() -> _>),
         }
+    }
 
-        // Allocation sizes larger than 2 ** 31 are stored as MiBs, with some
-        // loss of resolution.
-        #[test]
-        fn large_allocation(size in (HIGH_32BIT as usize)..(1 << 50)) {
-            let allocation = Allocation::new(0, size as usize);
-            let result_size = allocation.size();
-            let diff = if size < result_size {
-                result_size - size
-            } else {
-                size - result_size
-            };
-            prop_assert!(diff <= MIB / 2)
+    /// Information about how the data for the report was gathered.
+    pub fn report_metadata(&self) -> ReportMetadata {
+        ReportMetadata {
+            adaptive_sampling: AdaptiveSamplingMetadata {
+                engaged: self.adaptive.was_ever_engaged(),
+                transitions: self.adaptive.transitions().to_vec(),
+            },
+            allocator: AllocatorMetadata::probe(self.current_allocated_bytes),
+            phases: self
+                .phases
+                .summary(&self.current_memory_usage, &self.peak_memory_usage),
+            peak_phase_frames: self.peak_phase_frames(),
+            peak_triggers: vec![],
+            bundled_allocators: vec![],
+            line_numbers: !aggregate_lines(),
+            tracker_budget: self.budget.metadata(self.footprint_bytes()),
+            region: None,
+            environment: self.environment.clone(),
+            anon_mmaps: self.anon_mmaps_metadata(coalesce_mmaps()),
+            retained_by_caches: vec![],
+            temp_files: None,
+            drift: self.drift.as_ref().map(DriftDetector::report),
+            milestones: self
+                .milestones
+                .as_ref()
+                .map(|milestones| milestones.report(Path::new(&self.default_path)))
+                .unwrap_or_default(),
+            rotation: self.rotation.as_ref().map(Rotation::report),
+            rejected_allocations: self.size_ceiling.report(),
         }
+    }
 
-        // Test for https://github.com/pythonspeed/filprofiler/issues/66
-        #[test]
-        fn correct_allocation_size_tracked(size in (1 as usize)..(1<< 50)) {
-            let mut tracker = new_tracker();
-            let cs_id = tracker.get_callstack_id(&Callstack::new());
-            tracker.add_allocation(PARENT_PROCESS, 0, size, cs_id);
-            tracker.add_anon_mmap(PARENT_PROCESS, 1, size * 2, cs_id);
-            // We don't track (large) allocations exactly right, but they should
-            // be quite close:
-            let ratio = ((size * 3) as f64) / (tracker.current_memory_usage[0] as f64);
-            prop_assert!(0.999 < ratio);
-            prop_assert!(ratio < 1.001);
-            tracker.free_allocation(PARENT_PROCESS, 0);
-            tracker.free_anon_mmap(PARENT_PROCESS, 1, size * 2);
-            // Once we've freed everything, it should be _exactly_ 0.
-            prop_assert_eq!(&im::vector![0], &tracker.current_memory_usage);
-            tracker.check_if_new_peak();
-            tracker.validate();
+    /// Count the live anonymous mmap()s, optionally merging those that are
+    /// contiguous and from the same callstack into one.
+    fn anon_mmaps_metadata(&self, coalesce: bool) -> AnonMmapsMetadata {
+        let mut metadata = AnonMmapsMetadata {
+            mappings: 0,
+            bytes: 0,
+            coalesced: 0,
+        };
+        for maps in self.all_anon_mmaps() {
+            metadata.bytes += maps.iter().map(|(size, _)| size).sum::<usize>();
+            metadata.mappings += maps.len();
+            if coalesce {
+                metadata.coalesced += maps.len() - maps.coalesced().len();
+            }
+        }
+        metadata.mappings -= metadata.coalesced;
+        metadata
+    }
+
+    /// Enable allocation lifetime statistics, regardless of FIL_LIFETIMES.
+    pub fn enable_lifetimes(&mut self) {
+        if self.lifetimes.is_none() {
+            self.lifetimes = Some(LifetimeTracker::new());
+        }
+    }
+
+    /// Allocation lifetime statistics, if enabled. Like combine_callstacks(),
+    /// returns a factory so that function locations can be converted without
+    /// locks held.
+    pub fn lifetime_report(&self) -> Option<impl FnOnce() -> LifetimeReport> {
+        let lifetimes = self.lifetimes.as_ref()?;
+        let gather = lifetimes.report(&self.interner.get_reverse_map());
+        let functions_writer = self.functions.cheap_clone();
+        Some(move || gather(&functions_writer.to_reader()))
+    }
+
+    /// What survived resets, if FIL_SURVIVORS is set. Returns a factory for the
+    /// same reasons as lifetime_report().
+    pub fn survivors_report(&self) -> Option<impl FnOnce() -> SurvivorsReport> {
+        let gather = self
+            .survivors
+            .as_ref()?
+            .report(self.generation, &self.interner.get_reverse_map());
+        let functions_writer = self.functions.cheap_clone();
+        Some(move || gather(&functions_writer.to_reader()))
+    }
+
+    /// realloc() statistics. Returns a factory for the same reasons as
+    /// lifetime_report().
+    pub fn realloc_report(&self) -> impl FnOnce() -> ReallocReport {
+        let gather = self.reallocs.report(&self.interner.get_reverse_map());
+        let functions_writer = self.functions.cheap_clone();
+        move || gather(&functions_writer.to_reader())
+    }
+
+    /// Where memory was freed, if any frees were recorded with a callstack.
+    /// Returns a factory for the same reasons as combine_callstacks().
+    pub fn frees_report(&self) -> Option<impl FnOnce() -> FreesReport<FL::Reader>> {
+        let frees = self.frees.as_ref()?;
+        let gather = frees.report(&self.interner.get_reverse_map());
+        let functions_writer = self.functions.cheap_clone();
+        Some(move || gather(functions_writer.to_reader()))
+    }
+
+    /// The peak of mapped files plus everything else, if any file-backed
+    /// mmap()s were tracked. Returns a factory for the same reasons as
+    /// combine_callstacks().
+    pub fn mapped_files_report(
+        &mut self,
+    ) -> Option<impl FnOnce() -> MappedFilesReport<FL::Reader>> {
+        self.check_if_new_peak();
+        let gather = self
+            .mapped_files
+            .as_ref()?
+            .report(&self.interner.get_reverse_map());
+        let functions_writer = self.functions.cheap_clone();
+        Some(move || gather(functions_writer.to_reader()))
+    }
+
+    /// The most spilled to temporary files, if any were tracked. Returns a
+    /// factory for the same reasons as combine_callstacks().
+    pub fn temp_files_report(&self) -> Option<impl FnOnce() -> TempFilesReport> {
+        let gather = self
+            .temp_files
+            .as_ref()?
+            .report(&self.interner.get_reverse_map());
+        let functions_writer = self.functions.cheap_clone();
+        Some(move || gather(&functions_writer.to_reader()))
+    }
+
+    /// Record a live Python object created by the given callstack. This is
+    /// tracked separately from memory, see crate::objects.
+    pub fn add_object(&mut self, callstack: &Callstack, address: usize, type_name: &str) {
+        self.objects
+            .get_or_insert_with(ObjectTracker::new)
+            .add(callstack, address, type_name);
+    }
+
+    /// Record a Python object going away.
+    pub fn remove_object(&mut self, address: usize) {
+        if let Some(objects) = self.objects.as_mut() {
+            objects.remove(address);
+        }
+    }
+
+    /// Live Python object counts, if any objects were reported. Returns a
+    /// factory for the same reasons as combine_callstacks().
+    pub fn objects_report(&self) -> Option<impl FnOnce() -> ObjectsReport<FL::Reader>> {
+        let gather = self.objects.as_ref()?.report();
+        let functions_writer = self.functions.cheap_clone();
+        Some(move || gather(functions_writer.to_reader()))
+    }
+
+    /// How often tracking should be compared with the allocator, if drift
+    /// checking is enabled, see crate::drift.
+    pub fn drift_interval(&self) -> Option<Duration> {
+        self.drift.as_ref().map(|drift| drift.settings().interval)
+    }
+
+    /// Compare tracked malloc() bytes with the allocator's in-use bytes,
+    /// returning the warning to print if they've drifted apart.
+    pub fn sample_drift(&mut self, allocator_bytes: usize) -> Option<String> {
+        // Tracked bytes are only an estimate while sampling:
+        if self.drift.is_none() || self.adaptive.is_engaged() {
+            return None;
+        }
+        let anon_mmap_bytes: usize = self
+            .all_anon_mmaps()
+            .flat_map(|maps| maps.iter())
+            .map(|(size, _)| size)
+            .sum();
+        let reading = DriftReading {
+            tracked_bytes: (self.current_allocated_bytes - anon_mmap_bytes) as u64,
+            live_allocations: self.live_allocations as u64,
+            allocator_bytes: allocator_bytes as u64,
+        };
+        let drift = self.drift.as_mut()?;
+        let settings = *drift.settings();
+        drift
+            .observe(reading)
+            .map(|warning| warning.summary(&settings))
+    }
+
+    /// How often the memory timeline should be sampled, if it's enabled.
+    pub fn timeline_interval(&self) -> Option<Duration> {
+        self.timeline.as_ref().map(|timeline| timeline.interval())
+    }
+
+    /// Record a sample of current memory usage for the timeline, if enabled.
+    pub fn sample_timeline(&mut self) {
+        if let Some(timeline) = self.timeline.as_mut() {
+            timeline.sample(self.current_memory_usage.iter());
+        }
+    }
+
+    /// Memory usage over time, if enabled. Returns a factory for the same
+    /// reasons as lifetime_report().
+    pub fn timeline_report(&self) -> Option<impl FnOnce() -> TimelineReport> {
+        let gather = self.timeline.as_ref()?.report();
+        let functions_writer = self.functions.cheap_clone();
+        Some(move || gather(&functions_writer.to_reader()))
+    }
+
+    /// How often allocation rates should be sampled, if they're enabled.
+    pub fn allocation_rates_interval(&self) -> Option<Duration> {
+        self.allocation_rates
+            .as_ref()
+            .map(|allocation_rates| allocation_rates.interval())
+    }
+
+    /// Close the current allocation rate bucket, if enabled.
+    pub fn sample_allocation_rates(&mut self) {
+        if let Some(allocation_rates) = self.allocation_rates.as_mut() {
+            allocation_rates.sample();
+        }
+    }
+
+    /// The callstacks that allocate most often, if enabled. Returns a factory
+    /// for the same reasons as combine_callstacks().
+    pub fn allocation_rates_report(
+        &self,
+    ) -> Option<impl FnOnce() -> AllocationRatesReport<FL::Reader>> {
+        let allocation_rates = self.allocation_rates.as_ref()?;
+        let gather = allocation_rates.report(&self.interner.get_reverse_map());
+        let functions_writer = self.functions.cheap_clone();
+        Some(move || gather(functions_writer.to_reader()))
+    }
+
+    /// How often to check for milestone snapshots to write, if FIL_MILESTONES
+    /// is set, see crate::milestones.
+    pub fn milestones_interval(&self) -> Option<Duration> {
+        self.milestones
+            .as_ref()
+            .map(|_| crate::milestones::CHECK_INTERVAL)
+    }
+
+    /// Whether any milestones were crossed since milestone_dumps() was last
+    /// called.
+    pub fn has_pending_milestone_dumps(&self) -> bool {
+        self.milestones
+            .as_ref()
+            .is_some_and(|milestones| milestones.has_pending())
+    }
+
+    /// The milestones crossed since this was last called, each group with the
+    /// tracked bytes at the time and a factory for the callstacks then, like
+    /// combine_callstacks().
+    #[allow(clippy::type_complexity)]
+    pub fn milestone_dumps(
+        &mut self,
+    ) -> Vec<(
+        Vec<Milestone>,
+        usize,
+        impl FnOnce() -> FlamegraphCallstacks<
+            HashMap<Callstack, usize, ARandomState>,
+            FL::Reader,
+            IdentityCleaner,
+        >,
+    )> {
+        let pending = match self.milestones.as_mut() {
+            Some(milestones) => milestones.take_pending(),
+            None => return vec![],
+        };
+        pending
+            .into_iter()
+            .map(|dump| {
+                let factory = self.combine_memory_usage(&dump.memory_usage, None, IdentityCleaner);
+                (dump.milestones, dump.allocated_bytes, factory)
+            })
+            .collect()
+    }
+
+    /// How often to check whether the current window is over, if
+    /// FIL_ROTATE_WINDOW is set, see crate::rotation.
+    pub fn rotation_interval(&self) -> Option<Duration> {
+        self.rotation.as_ref().map(Rotation::check_interval)
+    }
+
+    /// If the current window is over, end it and start the next one,
+    /// returning the window that ended, its peak, and a factory for its
+    /// callstacks at the peak, like combine_callstacks(). While a region is
+    /// being profiled the window carries on.
+    #[allow(clippy::type_complexity)]
+    pub fn rotate_window(
+        &mut self,
+    ) -> Option<(
+        FinishedWindow,
+        usize,
+        impl FnOnce() -> FlamegraphCallstacks<
+            HashMap<Callstack, usize, ARandomState>,
+            FL::Reader,
+            IdentityCleaner,
+        >,
+    )> {
+        if self.in_region() || !self.rotation.as_ref()?.is_due() {
+            return None;
+        }
+        let factory = self.combine_callstacks(true, IdentityCleaner);
+        let peak_bytes = self.peak_allocated_bytes;
+        let finished = self
+            .rotation
+            .as_mut()?
+            .rotate(peak_bytes, Path::new(&self.default_path));
+        self.start_window();
+        Some((finished, peak_bytes, factory))
+    }
+
+    /// Start a new window: memory that's live moves to `[carried over]`
+    /// callstacks, and the peak starts over from there. Callstacks that
+    /// were already carried over stay as they are.
+    fn start_window(&mut self) {
+        let id_to_callstack = self.interner.get_reverse_map();
+        let to_carry: Vec<(CallstackId, Callstack)> = self
+            .current_memory_usage
+            .iter()
+            .enumerate()
+            .filter(|(_, bytes)| **bytes > 0)
+            .filter_map(|(callstack_id, _)| {
+                let callstack = id_to_callstack.get(&(callstack_id as CallstackId))?;
+                (!callstack.carried_over)
+                    .then(|| (callstack_id as CallstackId, callstack.carried_over()))
+            })
+            .collect();
+        let mut carried: HashMap<CallstackId, CallstackId, ARandomState> = new_hashmap();
+        for (callstack_id, callstack) in to_carry {
+            let carried_id = self.intern_callstack(&callstack);
+            // If the callstack table is full it may come back the same:
+            if carried_id != callstack_id {
+                let bytes = std::mem::take(&mut self.current_memory_usage[callstack_id as usize]);
+                self.current_memory_usage[carried_id as usize] += bytes;
+                let count =
+                    std::mem::take(&mut self.current_allocation_counts[callstack_id as usize]);
+                self.current_allocation_counts[carried_id as usize] += count;
+                self.callstack_threads[carried_id as usize] = merge_threads(
+                    self.callstack_threads[carried_id as usize],
+                    self.callstack_threads[callstack_id as usize],
+                );
+                carried.insert(callstack_id, carried_id);
+            }
+        }
+        for allocation in self
+            .current_allocations
+            .values_mut()
+            .flat_map(|allocations| allocations.values_mut())
+        {
+            if let Some(carried_id) = carried.get(&allocation.callstack_id) {
+                allocation.callstack_id = *carried_id;
+            }
+        }
+        for callstack_id in self
+            .current_anon_mmaps
+            .values_mut()
+            .flat_map(|anon_mmaps| anon_mmaps.values_mut())
+        {
+            if let Some(carried_id) = carried.get(callstack_id) {
+                *callstack_id = *carried_id;
+            }
+        }
+        self.peak_allocated_bytes = self.current_allocated_bytes;
+        self.peak_memory_usage
+            .clone_from(&self.current_memory_usage);
+        self.peak_allocation_counts
+            .clone_from(&self.current_allocation_counts);
+        self.peak_live_allocations = self.live_allocations;
+        self.last_added = None;
+        self.peak_triggers.reset();
+    }
+
+    /// How often the live view should be updated, if it's enabled.
+    pub fn live_view_interval(&self) -> Option<Duration> {
+        self.live_view
+            .as_ref()
+            .map(|live_view| live_view.interval())
+    }
+
+    /// Find what changed in current memory usage for the live view, if
+    /// enabled. Returns the directory it's written to, and a factory that
+    /// writes it out, so that can happen without locks held; the factory
+    /// returns whether the page was written for the first time.
+    pub fn update_live_view(
+        &mut self,
+    ) -> Option<(PathBuf, impl FnOnce() -> std::io::Result<bool>)> {
+        let live_view = self.live_view.as_mut()?;
+        let phases = &self.phases;
+        let mut update = live_view.update(
+            self.current_memory_usage.iter(),
+            self.current_allocated_bytes,
+            |callstack_id| !phases.is_excluded(callstack_id),
+        );
+        let needed = live_view.needs_callstack(&update);
+        if !needed.is_empty() {
+            update.new_callstacks = self.interner.get_callstacks(&needed);
+        }
+        let write = live_view.write(update);
+        let functions_writer = self.functions.cheap_clone();
+        // default_path is this run's report directory, which changes with
+        // every dump, so the live view goes next to it rather than inside:
+        let default_path = Path::new(&self.default_path);
+        let directory = default_path.parent().unwrap_or(default_path).join("live");
+        let write_directory = directory.clone();
+        Some((directory, move || {
+            write(&functions_writer.to_reader(), &write_directory)
+        }))
+    }
+
+    /// The summary printed once the final report is written. Returns a factory
+    /// for the same reasons as lifetime_report().
+    pub fn exit_summary(&mut self) -> impl FnOnce() -> ExitSummary {
+        self.check_if_new_peak();
+        let id_to_callstack = self.interner.get_reverse_map();
+        // The same callstack from different phases counts as one:
+        let mut by_callstack: HashMap<Callstack, usize, ARandomState> = new_hashmap();
+        for (callstack_id, bytes) in self.peak_memory_usage.iter().enumerate() {
+            if *bytes == 0 {
+                continue;
+            }
+            if let Some(callstack) = id_to_callstack.get(&(callstack_id as CallstackId)) {
+                let mut callstack = (**callstack).clone();
+                callstack.set_phase(NO_PHASE);
+                *by_callstack.entry(callstack).or_insert(0) += bytes;
+            }
+        }
+        let mut top_callstacks: Vec<(Callstack, usize)> = by_callstack.into_iter().collect();
+        top_callstacks.sort_by_key(|(_, bytes)| std::cmp::Reverse(*bytes));
+        top_callstacks.truncate(EXIT_SUMMARY_CALLSTACKS);
+        let peak_bytes = self.peak_allocated_bytes;
+        let peak_allocations = self.peak_live_allocations;
+        let functions_writer = self.functions.cheap_clone();
+        move || {
+            let functions = functions_writer.to_reader();
+            ExitSummary {
+                peak_bytes,
+                peak_allocations,
+                peak_rss_bytes: peak_rss_bytes(),
+                top_callstacks: top_callstacks
+                    .into_iter()
+                    .map(|(callstack, bytes)| (callstack.innermost_frame(&functions), bytes))
+                    .collect(),
+            }
+        }
+    }
+
+    /// Start profiling a region, see crate::regions. Returns false if a region
+    /// is already being profiled, since they can't be nested.
+    pub fn start_region(&mut self, pre_existing: PreExisting) -> bool {
+        if self.region.is_some() {
+            return false;
+        }
+        let allocations = std::mem::replace(
+            &mut self.current_allocations,
+            BTreeMap::from([(PARENT_PROCESS, AddressMap::new())]),
+        );
+        let anon_mmaps = std::mem::replace(
+            &mut self.current_anon_mmaps,
+            BTreeMap::from([(PARENT_PROCESS, RangeMap::new())]),
+        );
+        self.region = Some(Region::new(
+            pre_existing,
+            allocations,
+            anon_mmaps,
+            self.current_allocated_bytes,
+        ));
+        true
+    }
+
+    /// Whether a region is being profiled.
+    pub fn in_region(&self) -> bool {
+        self.region.is_some()
+    }
+
+    /// Finish profiling the current region, if any, returning a factory for
+    /// its report; like combine_callstacks(), function locations are
+    /// converted by the factory, so it can be called without locks held.
+    pub fn end_region(&mut self) -> Option<impl FnOnce() -> RegionReport<FL::Reader>> {
+        self.check_if_new_peak();
+        let region = self.region.take()?;
+        let gather = region.report(&self.interner.get_reverse_map());
+        let (allocations, anon_mmaps) = region.into_maps();
+        for (process, mut pre_existing) in allocations {
+            let current = self.current_allocations.entry(process).or_default();
+            // Insert the smaller map's entries into the bigger one:
+            if pre_existing.len() > current.len() {
+                std::mem::swap(current, &mut pre_existing);
+            }
+            for (address, allocation) in pre_existing.iter() {
+                current.insert(address, *allocation);
+            }
+        }
+        for (process, pre_existing) in anon_mmaps {
+            self.current_anon_mmaps
+                .entry(process)
+                .or_default()
+                .append(pre_existing);
+        }
+        let functions_writer = self.functions.cheap_clone();
+        Some(move || gather(functions_writer.to_reader()))
+    }
+
+    /// The number of times reset() has been called.
+    pub fn generation(&self) -> u64 {
+        self.generation
+    }
+
+    /// Reset internal state in way that doesn't invalidate e.g. thread-local
+    /// caching of callstack ID.
+    ///
+    /// Still-live allocations and mmaps are kept as the previous generation,
+    /// so that frees racing with the reset, e.g. on other threads, don't
+    /// affect the new totals, see crate::generations.
+    pub fn reset(&mut self, default_path: String) {
+        let mut allocations = std::mem::take(&mut self.current_allocations);
+        let mut anon_mmaps = std::mem::replace(
+            &mut self.current_anon_mmaps,
+            BTreeMap::from([(PARENT_PROCESS, RangeMap::new())]),
+        );
+        if let Some(region) = self.region.take() {
+            let (pre_existing_allocations, pre_existing_mmaps) = region.into_maps();
+            for (process, pre_existing) in pre_existing_allocations {
+                let current = allocations.entry(process).or_default();
+                for (address, allocation) in pre_existing.iter() {
+                    current.insert(address, *allocation);
+                }
+            }
+            for (process, pre_existing) in pre_existing_mmaps {
+                anon_mmaps.entry(process).or_default().append(pre_existing);
+            }
+        }
+        self.previous_generation = match self.survivors.as_mut() {
+            Some(survivors) => {
+                // Still-live allocations are added to the survivors, and kept
+                // with their bucket instead of their callstack id, so frees
+                // can be matched to it. What's left from older generations is
+                // carried forward:
+                let mut bucketed = BTreeMap::new();
+                for (process, allocations) in allocations {
+                    let mut process_bucketed = AddressMap::new();
+                    for (address, allocation) in allocations.iter() {
+                        let bucket = survivors.add(
+                            allocation.callstack_id,
+                            self.generation,
+                            allocation.size(),
+                        );
+                        process_bucketed.insert(
+                            address,
+                            Allocation {
+                                callstack_id: bucket,
+                                ..*allocation
+                            },
+                        );
+                    }
+                    bucketed.insert(process, process_bucketed);
+                }
+                let previous = PreviousGeneration::retain_with_older(
+                    self.generation,
+                    bucketed,
+                    anon_mmaps,
+                    self.previous_generation.take(),
+                );
+                if previous.is_none() {
+                    // Nothing left, or too much to keep:
+                    survivors.forget();
+                }
+                previous
+            }
+            // Anything left from the generation before is dropped:
+            None => PreviousGeneration::retain(self.generation, allocations, anon_mmaps),
+        };
+        self.generation += 1;
+        for i in self.current_memory_usage.iter_mut() {
+            *i = 0;
+        }
+        for i in self.current_allocation_counts.iter_mut() {
+            *i = 0;
+        }
+        self.callstack_threads.fill(0);
+        self.peak_memory_usage = ImVector::new();
+        self.peak_allocation_counts = ImVector::new();
+        self.current_allocated_bytes = 0;
+        self.peak_allocated_bytes = 0;
+        self.last_added = None;
+        self.peak_triggers.reset();
+        self.default_path = default_path;
+        self.live_allocations = 0;
+        self.peak_live_allocations = 0;
+        self.adaptive.reset();
+        if let Some(lifetimes) = self.lifetimes.as_mut() {
+            lifetimes.reset();
+        }
+        self.reallocs.reset();
+        self.frees = None;
+        self.objects = None;
+        self.mapped_files = None;
+        self.temp_files = None;
+        if let Some(timeline) = self.timeline.as_mut() {
+            timeline.reset();
+        }
+        if let Some(allocation_rates) = self.allocation_rates.as_mut() {
+            allocation_rates.reset();
+        }
+        if let Some(live_view) = self.live_view.as_mut() {
+            live_view.reset();
+        }
+        if let Some(drift) = self.drift.as_mut() {
+            drift.reset();
+        }
+        if let Some(milestones) = self.milestones.as_mut() {
+            milestones.reset();
+        }
+        if let Some(rotation) = self.rotation.as_mut() {
+            rotation.reset();
+        }
+        self.size_ceiling.reset();
+        self.environment.capture();
+        self.assert_valid();
+    }
+
+    /// Forget everything that's only needed for reports, once the embedding
+    /// program is done with profiling. Function locations and interned
+    /// callstacks are kept, since Python code objects and threads' callstacks
+    /// refer to them by id; they're sized by the code that ran, not by how
+    /// much it allocated. Afterwards the tracker is as if it was just reset,
+    /// and can be used again.
+    pub fn shutdown(&mut self) {
+        self.reset(self.default_path.clone());
+        // Frees of old allocations don't even need matching anymore:
+        self.previous_generation = None;
+        if let Some(survivors) = self.survivors.as_mut() {
+            survivors.clear();
+        }
+    }
+}
+
+/// How many callstacks the exit summary lists.
+const EXIT_SUMMARY_CALLSTACKS: usize = 3;
+
+/// A few lines printed to stderr once the final report is written, so there's
+/// some feedback without opening the report, a bit like what time(1) prints.
+/// Set FIL_NO_SUMMARY=1 to turn it off.
+#[derive(Clone, Debug, PartialEq)]
+pub struct ExitSummary {
+    pub peak_bytes: usize,
+    /// Tracked allocations that were live at the peak.
+    pub peak_allocations: usize,
+    /// The most memory the process ever had resident, if known.
+    pub peak_rss_bytes: Option<usize>,
+    /// The innermost frame of the biggest callstacks at the peak, and their
+    /// bytes.
+    pub top_callstacks: Vec<(String, usize)>,
+}
+
+impl ExitSummary {
+    /// Whether the summary should be printed at all.
+    pub fn enabled() -> bool {
+        std::env::var("FIL_NO_SUMMARY").as_deref() != Ok("1")
+    }
+
+    /// The summary, ending with the report directory and the files in it.
+    pub fn format(&self, report_directory: &Path, report_files: &[String]) -> String {
+        let mib = |bytes: usize| bytes as f64 / (1024.0 * 1024.0);
+        let mut result = format!(
+            "=fil-profile= Peak tracked memory: {:.1} MiB in {} allocations",
+            mib(self.peak_bytes),
+            self.peak_allocations
+        );
+        if let Some(rss) = self.peak_rss_bytes {
+            write!(result, ", peak RSS {:.1} MiB", mib(rss)).unwrap();
+        }
+        result.push('\n');
+        for (frame, bytes) in &self.top_callstacks {
+            writeln!(result, "=fil-profile= {:>10.1} MiB  {}", mib(*bytes), frame).unwrap();
+        }
+        writeln!(
+            result,
+            "=fil-profile= Reports in {}: {}",
+            report_directory.display(),
+            report_files.join(", ")
+        )
+        .unwrap();
+        result
+    }
+
+    /// Print the summary for the given report directory. At exit Python's
+    /// stdio may already be torn down, and eprintln!() panics if stderr is
+    /// gone, so this uses plain write() calls.
+    pub fn write_to_stderr(&self, report_directory: &Path) {
+        let mut report_files: Vec<String> = std::fs::read_dir(report_directory)
+            .map(|entries| {
+                entries
+                    .flatten()
+                    .filter_map(|entry| entry.file_name().into_string().ok())
+                    .collect()
+            })
+            .unwrap_or_default();
+        report_files.sort();
+        write_to_stderr(self.format(report_directory, &report_files).as_bytes());
+    }
+}
+
+#[cfg(test)]
+#[allow(clippy::needless_range_loop)]
+mod tests {
+    use crate::memorytracking::{
+        IdentityCleaner, ProcessUid, ReadFunctionLocations, WriteFunctionLocations, PARENT_PROCESS,
+    };
+
+    use super::LineNumberInfo::LineNumber;
+    use super::{
+        qualified_name, Allocation, AllocationInfo, AllocationTracker, CallSiteId, Callstack,
+        CallstackId, CallstackInterner, ExitSummary, Frame, FunctionId, VecFunctionLocations,
+        HIGH_32BIT, MIB,
+    };
+    use crate::adaptive::{AdaptiveSampling, SAMPLE_EVERY};
+    use crate::address_tags::AddressTags;
+    use crate::budget::TrackerBudget;
+    use crate::drift::{DriftDetector, DriftSettings};
+    use crate::frame_names::FrameNames;
+    use crate::linecache::LineCacher;
+    use crate::metadata::AnonMmapsMetadata;
+    use crate::milestones::{self, Milestones};
+    use crate::peak_triggers::PeakTriggerReport;
+    use crate::phases::{Phases, MAX_PHASE_FRAMES};
+    use crate::regions::PreExisting;
+    use crate::rotation::Rotation;
+    use crate::size_ceiling::SizeCeiling;
+    use crate::survivors::{Survivors, Tally};
+    use crate::temp_files::OpenedFile;
+    use crate::util::current_thread_id;
+    use proptest::prelude::*;
+    use rusty_fork::rusty_fork_test;
+    use std::borrow::Cow;
+    use std::collections::HashMap;
+    use std::time::Duration;
+
+    fn new_tracker() -> AllocationTracker<VecFunctionLocations> {
+        AllocationTracker::new(".".to_string(), VecFunctionLocations::new())
+    }
+
+    proptest! {
+        // Allocation sizes smaller than 2 ** 31 are round-tripped.
+        #[test]
+        fn small_allocation(size in 0..(HIGH_32BIT - 1)) {
+            let allocation = Allocation::new(0, size as usize);
+            prop_assert_eq!(size as usize, allocation.size());
+        }
+
+        // Allocation sizes larger than 2 ** 31 are stored as MiBs, with some
+        // loss of resolution.
+        #[test]
+        fn large_allocation(size in (HIGH_32BIT as usize)..(1 << 50)) {
+            let allocation = Allocation::new(0, size);
+            let result_size = allocation.size();
+            let diff = result_size.abs_diff(size);
+            prop_assert!(diff <= MIB / 2)
+        }
+
+        // Test for https://github.com/pythonspeed/filprofiler/issues/66
+        #[test]
+        fn correct_allocation_size_tracked(size in 1_usize..(1<< 50)) {
+            let mut tracker = new_tracker();
+            let cs_id = tracker.get_callstack_id(&Callstack::new());
+            tracker.add_allocation(PARENT_PROCESS, 0, size, cs_id);
+            tracker.add_anon_mmap(PARENT_PROCESS, 1, size * 2, cs_id);
+            // We don't track (large) allocations exactly right, but they should
+            // be quite close:
+            let ratio = ((size * 3) as f64) / (tracker.current_memory_usage[0] as f64);
+            prop_assert!(0.999 < ratio);
+            prop_assert!(ratio < 1.001);
+            tracker.free_allocation(PARENT_PROCESS, 0);
+            tracker.free_anon_mmap(PARENT_PROCESS, 1, size * 2);
+            // Once we've freed everything, it should be _exactly_ 0.
+            prop_assert_eq!(&im::vector![0], &tracker.current_memory_usage);
+            tracker.check_if_new_peak();
+            tracker.assert_valid();
         }
 
         #[test]
         fn current_allocated_matches_sum_of_allocations(
             // Allocated bytes. Will use index as the memory address.
-            allocated_sizes in prop::collection::vec((0..2 as u32, 1..100 as usize), 10..20),
+            allocated_sizes in prop::collection::vec((0..2_u32, 1..100_usize), 10..20),
             // Allocations to free.
-            free_indices in prop::collection::btree_set(0..10 as usize, 1..5)
+            free_indices in prop::collection::btree_set(0..10_usize, 1..5)
         ) {
             let mut tracker = new_tracker();
             let mut expected_memory_usage = im::vector![];
@@ -840,7 +3235,7 @@ mod tests {
                 let mut cs = Callstack::new();
                 cs.start_call(0, CallSiteId::new(FunctionId::new(i as u64), LineNumber(0)));
                 let cs_id = tracker.get_callstack_id(&cs);
-                tracker.add_allocation(process, i as usize, allocation_size, cs_id);
+                tracker.add_allocation(process, i, allocation_size, cs_id);
                 expected_memory_usage.push_back(allocation_size);
             }
             let mut expected_sum = allocated_sizes.iter().map(|t| t.1).sum();
@@ -859,15 +3254,15 @@ mod tests {
             }
             prop_assert_eq!(tracker.peak_allocated_bytes, expected_peak);
             tracker.check_if_new_peak();
-            tracker.validate();
+            tracker.assert_valid();
         }
 
         #[test]
         fn current_allocated_anon_maps_matches_sum_of_allocations(
             // Allocated bytes. Will use index as the memory address.
-            allocated_sizes in prop::collection::vec((0..2 as u32, 1..100 as usize), 10..20),
+            allocated_sizes in prop::collection::vec((0..2_u32, 1..100_usize), 10..20),
             // Allocations to free.
-            free_indices in prop::collection::btree_set(0..10 as usize, 1..5)
+            free_indices in prop::collection::btree_set(0..10_usize, 1..5)
         ) {
             let mut tracker = new_tracker();
             let mut expected_memory_usage = im::vector![];
@@ -879,7 +3274,7 @@ mod tests {
                 let mut cs = Callstack::new();
                 cs.start_call(0, CallSiteId::new(FunctionId::new(i as u64),  LineNumber(0)));
                 let csid = tracker.get_callstack_id(&cs);
-                tracker.add_anon_mmap(process, addresses[i] as usize, allocation_size, csid);
+                tracker.add_anon_mmap(process, addresses[i], allocation_size, csid);
                 expected_memory_usage.push_back(allocation_size);
             }
             let mut expected_sum = allocated_sizes.iter().map(|t|t.1).sum();
@@ -897,14 +3292,69 @@ mod tests {
             }
             prop_assert_eq!(tracker.peak_allocated_bytes, expected_peak);
             tracker.check_if_new_peak();
-            tracker.validate();
+            tracker.assert_valid();
+        }
+
+        // Random sequences of operations keep the tracker consistent with a
+        // simple model of what should be live.
+        #[test]
+        fn random_operations_keep_state_consistent(
+            operations in prop::collection::vec((0..5_u8, 0..2_u32, 0..8_usize, 1..1000_usize), 1..100)
+        ) {
+            let mut tracker = new_tracker();
+            let callstacks: Vec<_> = (0..3).map(|i| {
+                let mut cs = Callstack::new();
+                cs.start_call(0, CallSiteId::new(FunctionId::new(i), LineNumber(0)));
+                cs
+            }).collect();
+            // Live (process, address) -> size, for allocations and mmaps:
+            let mut allocations = HashMap::new();
+            let mut mmaps = HashMap::new();
+            for (i, (operation, process, slot, size)) in operations.into_iter().enumerate() {
+                let process = ProcessUid(process);
+                // Slots are far enough apart that mmaps never overlap:
+                let address = slot * 1000;
+                match operation {
+                    0 => {
+                        let cs_id = tracker.get_callstack_id(&callstacks[i % callstacks.len()]);
+                        tracker.add_allocation(process, address, size, cs_id);
+                        allocations.insert((process, address), size);
+                    }
+                    1 => {
+                        let removed = tracker.free_allocation(process, address);
+                        prop_assert_eq!(removed, allocations.remove(&(process, address)));
+                    }
+                    2 => {
+                        if let Some(old_size) = mmaps.remove(&(process, address)) {
+                            tracker.free_anon_mmap(process, address, old_size);
+                        }
+                        let cs_id = tracker.get_callstack_id(&callstacks[i % callstacks.len()]);
+                        tracker.add_anon_mmap(process, address, size, cs_id);
+                        mmaps.insert((process, address), size);
+                    }
+                    3 => {
+                        if let Some(old_size) = mmaps.remove(&(process, address)) {
+                            tracker.free_anon_mmap(process, address, old_size);
+                        }
+                    }
+                    _ => {
+                        tracker.reset(".".to_string());
+                        allocations.clear();
+                        mmaps.clear();
+                    }
+                }
+                tracker.check_if_new_peak();
+                prop_assert_eq!(tracker.validate(), Vec::<String>::new());
+                let expected: usize = allocations.values().sum::<usize>() + mmaps.values().sum::<usize>();
+                prop_assert_eq!(tracker.current_allocated_bytes, expected);
+            }
         }
 
         #[test]
         fn drop_process_removes_that_process_allocations_and_mmaps(
             // Allocated bytes. Will use index as the memory address.
-            allocated_sizes in prop::collection::vec((0..2 as u32, 1..100 as usize), 10..20),
-            allocated_mmaps in prop::collection::vec((0..2 as u32, 1..100 as usize), 10..20),
+            allocated_sizes in prop::collection::vec((0..2_u32, 1..100_usize), 10..20),
+            allocated_mmaps in prop::collection::vec((0..2_u32, 1..100_usize), 10..20),
         ) {
             let mut tracker = new_tracker();
             let mut expected_memory_usage : usize = 0;
@@ -916,7 +3366,7 @@ mod tests {
                 let mut cs = Callstack::new();
                 cs.start_call(0, CallSiteId::new(FunctionId::new(i as u64), LineNumber(0)));
                 let cs_id = tracker.get_callstack_id(&cs);
-                tracker.add_allocation(process, i as usize, allocation_size, cs_id);
+                tracker.add_allocation(process, i, allocation_size, cs_id);
                 expected_memory_usage += allocation_size;
             }
             for i in 0..allocated_mmaps.len() {
@@ -925,7 +3375,7 @@ mod tests {
                 let mut cs = Callstack::new();
                 cs.start_call(0, CallSiteId::new(FunctionId::new(i as u64), LineNumber(0)));
                 let csid = tracker.get_callstack_id(&cs);
-                tracker.add_anon_mmap(process, mmap_addresses[i] as usize, allocation_size, csid);
+                tracker.add_anon_mmap(process, mmap_addresses[i], allocation_size, csid);
                 expected_memory_usage += allocation_size;
             }
             prop_assert_eq!(tracker.current_allocated_bytes, expected_memory_usage);
@@ -937,7 +3387,7 @@ mod tests {
             prop_assert_eq!(tracker.current_allocated_bytes, expected_memory_usage);
             prop_assert_eq!(tracker.peak_allocated_bytes, expected_peak);
             tracker.check_if_new_peak();
-            tracker.validate();
+            tracker.assert_valid();
         }
 
     }
@@ -1030,48 +3480,134 @@ mod tests {
     }
 
     #[test]
-    fn callstack_id_for_new_allocation() {
-        let mut interner = CallstackInterner::new();
-
-        let mut cs1 = Callstack::new();
-        let id0 =
-            cs1.id_for_new_allocation(0, |cs| interner.get_or_insert_id(Cow::Borrowed(&cs), || ()));
-        let id0b =
-            cs1.id_for_new_allocation(0, |cs| interner.get_or_insert_id(Cow::Borrowed(&cs), || ()));
-        assert_eq!(id0, id0b);
-
-        let fid1 = FunctionId::new(1u64);
+    fn callstack_interner_overflow() {
+        let mut interner = CallstackInterner::with_capacity(3);
+        let callstacks: Vec<Callstack> = (1..=4)
+            .map(|line| {
+                Callstack::from_vec(vec![CallSiteId::new(FunctionId::new(0), LineNumber(line))])
+            })
+            .collect();
+        let mut new_ids = 0;
+        let ids: Vec<_> = callstacks
+            .iter()
+            .map(|cs| interner.get_or_insert_id(Cow::Borrowed(cs), || new_ids += 1))
+            .collect();
+        // The last id goes to the empty callstack, which is shared by all
+        // callstacks that didn't fit:
+        assert_eq!(ids, vec![0, 1, 2, 2]);
+        assert_eq!(new_ids, 3);
+        assert_eq!(interner.get_callstack(2), Some(&Callstack::new()));
+        // Existing callstacks still get their ids:
+        assert_eq!(
+            interner.get_or_insert_id(Cow::Borrowed(&callstacks[1]), || ()),
+            1
+        );
+        assert_eq!(
+            interner.get_or_insert_id(Cow::Owned(Callstack::new()), || ()),
+            2
+        );
+    }
 
-        cs1.start_call(0, CallSiteId::new(fid1, LineNumber(2)));
-        let id1 =
-            cs1.id_for_new_allocation(1, |cs| interner.get_or_insert_id(Cow::Borrowed(&cs), || ()));
-        let id2 =
-            cs1.id_for_new_allocation(2, |cs| interner.get_or_insert_id(Cow::Borrowed(&cs), || ()));
-        let id1b =
-            cs1.id_for_new_allocation(1, |cs| interner.get_or_insert_id(Cow::Borrowed(&cs), || ()));
-        assert_eq!(id1, id1b);
+    #[test]
+    fn many_functions_and_callstacks() {
+        // Large apps can have a lot of these; make sure nothing gets merged.
+        pyo3::prepare_freethreaded_python();
+        const FUNCTIONS: usize = 200_000;
+        const CALLSTACKS: usize = 1_000_000;
+        let mut tracker = new_tracker();
+        let function_ids: Vec<FunctionId> = (0..FUNCTIONS)
+            .map(|i| {
+                tracker
+                    .functions
+                    .add_function(format!("file{}.py", i), format!("func{}", i))
+            })
+            .collect();
+        let callstack = |i: usize| {
+            Callstack::from_vec(vec![
+                CallSiteId::new(
+                    function_ids[(i * 7919) % FUNCTIONS],
+                    LineNumber((i / FUNCTIONS) as u32 + 70_000),
+                ),
+                CallSiteId::new(function_ids[i % FUNCTIONS], LineNumber(1)),
+            ])
+        };
+        for i in 0..CALLSTACKS {
+            let cs_id = tracker.get_callstack_id(&callstack(i));
+            assert_eq!(cs_id as usize, i);
+            tracker.add_allocation(PARENT_PROCESS, i, 1 + i % 7, cs_id);
+        }
+        // Second time around we get the same ids:
+        for i in 0..CALLSTACKS {
+            let cs_id = tracker.get_callstack_id(&callstack(i));
+            assert_eq!(cs_id as usize, i);
+            tracker.add_allocation(PARENT_PROCESS, CALLSTACKS + i, 10, cs_id);
+        }
+        for i in 0..CALLSTACKS {
+            assert_eq!(tracker.current_memory_usage[i], 11 + i % 7);
+        }
+        let reverse_map = tracker.interner.get_reverse_map();
+        assert_eq!(reverse_map.len(), CALLSTACKS);
+        let functions = tracker.functions.cheap_clone().to_reader();
+        for i in (0..CALLSTACKS).step_by(997) {
+            assert_eq!(*reverse_map[&(i as CallstackId)], callstack(i));
+            let (caller, callee) = ((i * 7919) % FUNCTIONS, i % FUNCTIONS);
+            assert_eq!(
+                callstack(i).as_string(false, &functions, ";", &mut LineCacher::default()),
+                format!(
+                    "file{}.py:{} (func{});file{}.py:1 (func{})",
+                    caller,
+                    i / FUNCTIONS + 70_000,
+                    caller,
+                    callee,
+                    callee
+                )
+            );
+        }
+    }
+
+    #[test]
+    fn callstack_id_for_new_allocation() {
+        let mut interner = CallstackInterner::new();
+
+        let mut cs1 = Callstack::new();
+        let id0 =
+            cs1.id_for_new_allocation(0, |cs| interner.get_or_insert_id(Cow::Borrowed(cs), || ()));
+        let id0b =
+            cs1.id_for_new_allocation(0, |cs| interner.get_or_insert_id(Cow::Borrowed(cs), || ()));
+        assert_eq!(id0, id0b);
+
+        let fid1 = FunctionId::new(1u64);
+
+        cs1.start_call(0, CallSiteId::new(fid1, LineNumber(2)));
+        let id1 =
+            cs1.id_for_new_allocation(1, |cs| interner.get_or_insert_id(Cow::Borrowed(cs), || ()));
+        let id2 =
+            cs1.id_for_new_allocation(2, |cs| interner.get_or_insert_id(Cow::Borrowed(cs), || ()));
+        let id1b =
+            cs1.id_for_new_allocation(1, |cs| interner.get_or_insert_id(Cow::Borrowed(cs), || ()));
+        assert_eq!(id1, id1b);
         assert_ne!(id2, id0);
         assert_ne!(id2, id1);
 
         cs1.start_call(3, CallSiteId::new(fid1, LineNumber(2)));
         let id3 =
-            cs1.id_for_new_allocation(4, |cs| interner.get_or_insert_id(Cow::Borrowed(&cs), || ()));
+            cs1.id_for_new_allocation(4, |cs| interner.get_or_insert_id(Cow::Borrowed(cs), || ()));
         assert_ne!(id3, id0);
         assert_ne!(id3, id1);
         assert_ne!(id3, id2);
 
         cs1.finish_call();
         let id2b =
-            cs1.id_for_new_allocation(2, |cs| interner.get_or_insert_id(Cow::Borrowed(&cs), || ()));
+            cs1.id_for_new_allocation(2, |cs| interner.get_or_insert_id(Cow::Borrowed(cs), || ()));
         assert_eq!(id2, id2b);
         let id1c =
-            cs1.id_for_new_allocation(1, |cs| interner.get_or_insert_id(Cow::Borrowed(&cs), || ()));
+            cs1.id_for_new_allocation(1, |cs| interner.get_or_insert_id(Cow::Borrowed(cs), || ()));
         assert_eq!(id1, id1c);
 
         // Check for cache invalidation in start_call:
         cs1.start_call(1, CallSiteId::new(fid1, LineNumber(1)));
         let id4 =
-            cs1.id_for_new_allocation(1, |cs| interner.get_or_insert_id(Cow::Borrowed(&cs), || ()));
+            cs1.id_for_new_allocation(1, |cs| interner.get_or_insert_id(Cow::Borrowed(cs), || ()));
         assert_ne!(id4, id0);
         assert_ne!(id4, id1);
         assert_ne!(id4, id2);
@@ -1080,7 +3616,7 @@ mod tests {
         // Check for cache invalidation in finish_call:
         cs1.finish_call();
         let id1d =
-            cs1.id_for_new_allocation(1, |cs| interner.get_or_insert_id(Cow::Borrowed(&cs), || ()));
+            cs1.id_for_new_allocation(1, |cs| interner.get_or_insert_id(Cow::Borrowed(cs), || ()));
         assert_eq!(id1, id1d);
     }
 
@@ -1138,7 +3674,7 @@ mod tests {
         assert_eq!(tracker.peak_allocated_bytes, 2123);
         assert_eq!(tracker.peak_memory_usage, previous_peak);
         assert_eq!(tracker.current_allocations.len(), 1);
-        assert!(tracker.current_allocations[&PARENT_PROCESS].contains_key(&3));
+        assert!(tracker.current_allocations[&PARENT_PROCESS].contains_key(3));
         assert!(tracker.current_anon_mmaps[&PARENT_PROCESS].size() > 0);
 
         // Add anonymous mmap() that does go past previous peak:
@@ -1167,7 +3703,197 @@ mod tests {
         assert_eq!(tracker.peak_allocated_bytes, 3123);
         assert_eq!(tracker.current_anon_mmaps[&PARENT_PROCESS].size(), 1000);
         tracker.check_if_new_peak();
-        tracker.validate();
+        tracker.assert_valid();
+    }
+
+    #[test]
+    fn frees_after_reset_dont_touch_new_totals() {
+        let mut tracker = new_tracker();
+        let cs_id = tracker.get_callstack_id(&Callstack::new());
+        tracker.add_allocation(PARENT_PROCESS, 0x1000, 100, cs_id);
+        tracker.add_anon_mmap(PARENT_PROCESS, 0x10000, 0x2000, cs_id);
+        tracker.reset(".".to_string());
+        assert_eq!(tracker.generation(), 1);
+        tracker.add_allocation(PARENT_PROCESS, 0x2000, 50, cs_id);
+        assert_eq!(tracker.free_allocation(PARENT_PROCESS, 0x1000), None);
+        tracker.free_anon_mmap(PARENT_PROCESS, 0x10000, 0x1000);
+        assert_eq!(tracker.current_allocated_bytes, 50);
+        assert_eq!(tracker.previous_generation.as_ref().unwrap().frees(), 2);
+        // Once everything from before the reset is freed, it's dropped:
+        tracker.free_anon_mmap(PARENT_PROCESS, 0x11000, 0x1000);
+        assert!(tracker.previous_generation.is_none());
+        assert_eq!(tracker.failed_deallocations, 0);
+        tracker.assert_valid();
+    }
+
+    #[test]
+    fn survivors_are_tracked_across_resets() {
+        let mut tracker = new_tracker();
+        tracker.survivors = Some(Survivors::new(2));
+        let leaking = tracker.get_callstack_id(&Callstack::new());
+        let mut other = Callstack::new();
+        other.start_call(0, CallSiteId::new(FunctionId::new(1), LineNumber(1)));
+        let temporary = tracker.get_callstack_id(&other);
+        // Every generation leaks 100 bytes, and frees what it allocated from
+        // the other callstack before the reset:
+        for generation in 0..3 {
+            let address = 0x1000 * (generation + 1);
+            tracker.add_allocation(PARENT_PROCESS, address, 100, leaking);
+            tracker.add_allocation(PARENT_PROCESS, address + 0x100, 50, temporary);
+            tracker.free_allocation(PARENT_PROCESS, address + 0x100);
+            tracker.reset(".".to_string());
+        }
+        let survivors = tracker.survivors.as_ref().unwrap();
+        assert_eq!(
+            survivors.live(),
+            Tally {
+                count: 3,
+                bytes: 300
+            }
+        );
+        // Freeing one of them after it survived two resets is noticed:
+        assert_eq!(tracker.free_allocation(PARENT_PROCESS, 0x2000), None);
+        let survivors = tracker.survivors.as_ref().unwrap();
+        assert_eq!(
+            survivors.live(),
+            Tally {
+                count: 2,
+                bytes: 200
+            }
+        );
+        assert_eq!(tracker.current_allocated_bytes, 0);
+        assert_eq!(tracker.failed_deallocations, 0);
+        tracker.assert_valid();
+        tracker.shutdown();
+        assert_eq!(tracker.survivors.as_ref().unwrap().live(), Tally::default());
+    }
+
+    #[test]
+    fn reset_racing_with_allocations() {
+        let tracker = std::sync::Arc::new(parking_lot::Mutex::new(new_tracker()));
+        let cs_id = tracker.lock().get_callstack_id(&Callstack::new());
+        let workers: Vec<_> = (1..=4)
+            .map(|thread| {
+                let tracker = tracker.clone();
+                std::thread::spawn(move || {
+                    for i in 0..5000 {
+                        let address = thread * 0x1000_0000 + i * 0x100;
+                        let size = 1 + (i % 200);
+                        let generation = {
+                            let mut tracker = tracker.lock();
+                            tracker.add_allocation(PARENT_PROCESS, address, size, cs_id);
+                            tracker.add_anon_mmap(PARENT_PROCESS, address + 0x80, 16, cs_id);
+                            tracker.generation()
+                        };
+                        std::thread::yield_now();
+                        let mut tracker = tracker.lock();
+                        let freed = tracker.free_allocation(PARENT_PROCESS, address);
+                        tracker.free_anon_mmap(PARENT_PROCESS, address + 0x80, 16);
+                        if tracker.generation() == generation {
+                            assert_eq!(freed, Some(size));
+                        } else {
+                            // Allocated before a reset:
+                            assert_eq!(freed, None);
+                        }
+                    }
+                })
+            })
+            .collect();
+        let resetter = {
+            let tracker = tracker.clone();
+            std::thread::spawn(move || {
+                for _ in 0..200 {
+                    // reset() validates the new state:
+                    tracker.lock().reset(".".to_string());
+                    std::thread::yield_now();
+                }
+            })
+        };
+        for worker in workers {
+            worker.join().unwrap();
+        }
+        resetter.join().unwrap();
+        let tracker = tracker.lock();
+        assert_eq!(tracker.generation(), 200);
+        assert_eq!(tracker.current_allocated_bytes, 0);
+        assert_eq!(tracker.live_allocations, 0);
+        assert!(tracker.current_memory_usage.iter().all(|bytes| *bytes == 0));
+        assert!(tracker.previous_generation.is_none());
+        tracker.assert_valid();
+    }
+
+    #[test]
+    fn shutdown_forgets_everything_but_ids() {
+        let mut tracker = new_tracker();
+        let fid = tracker
+            .functions
+            .add_function("a".to_string(), "af".to_string());
+        let mut cs = Callstack::new();
+        cs.start_call(0, CallSiteId::new(fid, LineNumber(2)));
+        let cs_id = tracker.get_callstack_id(&cs);
+        tracker.add_allocation(PARENT_PROCESS, 0x1000, 100, cs_id);
+        tracker.add_anon_mmap(PARENT_PROCESS, 0x10000, 0x1000, cs_id);
+        tracker.shutdown();
+        assert_eq!(tracker.get_current_allocated_bytes(), 0);
+        assert_eq!(tracker.get_peak_allocated_bytes(), 0);
+        assert!(tracker.previous_generation.is_none());
+        assert!(tracker.current_allocations.values().all(|a| a.is_empty()));
+        // Frees of memory from before are ignored:
+        assert_eq!(tracker.free_allocation(PARENT_PROCESS, 0x1000), None);
+        tracker.free_anon_mmap(PARENT_PROCESS, 0x10000, 0x1000);
+        // Ids held elsewhere are still good:
+        assert_eq!(tracker.get_callstack_id(&cs), cs_id);
+        tracker.add_allocation(PARENT_PROCESS, 0x2000, 50, cs_id);
+        assert_eq!(tracker.get_current_allocated_bytes(), 50);
+        tracker.check_if_new_peak();
+        tracker.assert_valid();
+    }
+
+    #[test]
+    fn coalesced_anon_mmaps() {
+        let mut tracker = new_tracker();
+        let cs1_id = tracker.get_callstack_id(&Callstack::new());
+        let mut cs2 = Callstack::new();
+        let fid = tracker
+            .functions
+            .add_function("a".to_string(), "af".to_string());
+        cs2.start_call(0, CallSiteId::new(fid, LineNumber(2)));
+        let cs2_id = tracker.get_callstack_id(&cs2);
+        // One logical buffer built out of three mmap()s:
+        tracker.add_anon_mmap(PARENT_PROCESS, 0x3000, 0x1000, cs1_id);
+        tracker.add_anon_mmap(PARENT_PROCESS, 0x1000, 0x2000, cs1_id);
+        tracker.add_anon_mmap(PARENT_PROCESS, 0x4000, 0x1000, cs1_id);
+        // Adjacent, but a different callstack:
+        tracker.add_anon_mmap(PARENT_PROCESS, 0x5000, 0x1000, cs2_id);
+        assert_eq!(
+            tracker.anon_mmaps_metadata(false),
+            AnonMmapsMetadata {
+                mappings: 4,
+                bytes: 0x5000,
+                coalesced: 0
+            }
+        );
+        assert_eq!(
+            tracker.anon_mmaps_metadata(true),
+            AnonMmapsMetadata {
+                mappings: 2,
+                bytes: 0x5000,
+                coalesced: 2
+            }
+        );
+        // The underlying mappings are still separate, so freeing part of the
+        // buffer works as usual:
+        tracker.free_anon_mmap(PARENT_PROCESS, 0x2000, 0x2000);
+        assert_eq!(tracker.current_memory_usage[cs1_id as usize], 0x2000);
+        assert_eq!(
+            tracker.anon_mmaps_metadata(true),
+            AnonMmapsMetadata {
+                mappings: 3,
+                bytes: 0x3000,
+                coalesced: 0
+            }
+        );
+        tracker.assert_valid();
     }
 
     #[test]
@@ -1192,7 +3918,7 @@ mod tests {
         let id3 = CallSiteId::new(fid3, LineNumber(3));
         let mut cs1 = Callstack::new();
         cs1.start_call(0, id1);
-        cs1.start_call(0, id2.clone());
+        cs1.start_call(0, id2);
         let mut cs2 = Callstack::new();
         cs2.start_call(0, id3);
         let mut cs3 = Callstack::new();
@@ -1236,6 +3962,502 @@ mod tests {
         assert_eq!(expected2, result2);
     }
 
+    #[test]
+    fn traced_memory_is_consistent() {
+        let mut tracker = new_tracker();
+        let cs_id = tracker.get_callstack_id(&Callstack::new());
+        tracker.add_allocation(PARENT_PROCESS, 1, 1000, cs_id);
+        // The peak hasn't been updated yet, but the snapshot must still be
+        // coherent:
+        assert_eq!(tracker.get_traced_memory(), (1000, 1000));
+        tracker.add_allocation(PARENT_PROCESS, 2, 500, cs_id);
+        tracker.free_allocation(PARENT_PROCESS, 1);
+        assert_eq!(tracker.get_traced_memory(), (500, 1500));
+        tracker.reset(".".to_string());
+        assert_eq!(tracker.get_traced_memory(), (0, 0));
+    }
+
+    #[test]
+    fn tagged_addresses_match_untagged() {
+        let address = 0x0000_ffff_1234_5670;
+        let tagged = |tag: usize| address | (tag << 56);
+        let mut tracker = new_tracker();
+        tracker.address_tags = AddressTags::TopByte;
+        let cs_id = tracker.get_callstack_id(&Callstack::new());
+        tracker.add_allocation(PARENT_PROCESS, tagged(0x3), 1000, cs_id);
+        assert_eq!(tracker.get_allocation_size(PARENT_PROCESS, address), 1000);
+        assert_eq!(
+            tracker.get_allocation_size(PARENT_PROCESS, tagged(0x7)),
+            1000
+        );
+        // realloc() that gets a differently-tagged pointer back:
+        assert_eq!(
+            tracker.free_allocation(PARENT_PROCESS, tagged(0x5)),
+            Some(1000)
+        );
+        tracker.update_allocation(PARENT_PROCESS, tagged(0x5), 1000, tagged(0xa), 2000, cs_id);
+        assert_eq!(tracker.get_current_allocated_bytes(), 2000);
+        assert_eq!(tracker.free_allocation(PARENT_PROCESS, address), Some(2000));
+        assert_eq!(tracker.get_current_allocated_bytes(), 0);
+        assert_eq!(tracker.free_allocation(PARENT_PROCESS, tagged(0xa)), None);
+        assert!(tracker.validate().is_empty());
+
+        // Without canonicalization they're different addresses:
+        tracker.address_tags = AddressTags::Off;
+        tracker.add_allocation(PARENT_PROCESS, tagged(0x3), 1000, cs_id);
+        assert_eq!(tracker.free_allocation(PARENT_PROCESS, address), None);
+        assert_eq!(
+            tracker.free_allocation(PARENT_PROCESS, tagged(0x3)),
+            Some(1000)
+        );
+    }
+
+    #[test]
+    fn adaptive_sampling_transitions() {
+        let mut tracker = new_tracker();
+        tracker.adaptive = AdaptiveSampling::new(4, 2);
+        let cs_id = tracker.get_callstack_id(&Callstack::new());
+        // Exact until we hit 4 live allocations:
+        for address in 1..=4 {
+            tracker.add_allocation(PARENT_PROCESS, address, 10, cs_id);
+        }
+        assert_eq!(tracker.get_current_allocated_bytes(), 40);
+        assert!(tracker.adaptive.is_engaged());
+        // Now small allocations get sampled, large ones don't:
+        for address in 100..(100 + 2 * SAMPLE_EVERY) {
+            tracker.add_allocation(PARENT_PROCESS, address, 10, cs_id);
+        }
+        tracker.add_allocation(PARENT_PROCESS, 1000, 1_000_000, cs_id);
+        assert_eq!(
+            tracker.get_current_allocated_bytes(),
+            40 + 2 * 10 * SAMPLE_EVERY + 1_000_000
+        );
+        tracker.check_if_new_peak();
+        tracker.assert_valid();
+        // Freeing everything, including allocations that were never
+        // recorded, gets us back to zero and back to exact tracking:
+        for address in (1..=4).chain(100..(100 + 2 * SAMPLE_EVERY)).chain([1000]) {
+            tracker.free_allocation(PARENT_PROCESS, address);
+        }
+        assert_eq!(tracker.get_current_allocated_bytes(), 0);
+        assert!(!tracker.adaptive.is_engaged());
+        tracker.assert_valid();
+        let metadata = tracker.report_metadata();
+        assert!(metadata.adaptive_sampling.engaged);
+        assert_eq!(metadata.adaptive_sampling.transitions.len(), 2);
+
+        tracker.reset(".".to_string());
+        assert!(!tracker.report_metadata().adaptive_sampling.engaged);
+    }
+
+    #[test]
+    fn lifetimes_are_tracked_when_enabled() {
+        let mut tracker = new_tracker();
+        assert!(tracker.lifetime_report().is_none());
+        tracker.enable_lifetimes();
+        let cs_id = tracker.get_callstack_id(&Callstack::new());
+        tracker.add_allocation(PARENT_PROCESS, 1, 100, cs_id);
+        tracker.add_allocation(PARENT_PROCESS, 2, 100, cs_id);
+        tracker.add_allocation(ProcessUid(1), 3, 100, cs_id);
+        tracker.free_allocation(PARENT_PROCESS, 1);
+        tracker.drop_process(ProcessUid(1));
+        let report = tracker.lifetime_report().unwrap()();
+        assert_eq!(report.callstacks.len(), 1);
+        assert_eq!(report.callstacks[0].allocated_bytes, 300);
+        assert_eq!(report.callstacks[0].allocations, 3);
+        assert_eq!(report.callstacks[0].freed_histogram.iter().sum::<u64>(), 2);
+
+        tracker.reset(".".to_string());
+        assert!(tracker.lifetime_report().unwrap()().callstacks.is_empty());
+    }
+
+    #[test]
+    fn realloc_growth_loop() {
+        pyo3::prepare_freethreaded_python();
+        let mut tracker = new_tracker();
+        assert!(tracker.realloc_report()().callstacks.is_empty());
+        let fid = tracker
+            .functions
+            .add_function("a".to_string(), "af".to_string());
+        let mut grower = Callstack::new();
+        grower.start_call(0, CallSiteId::new(fid, LineNumber(1)));
+        let grower_id = tracker.get_callstack_id(&grower);
+        let other_id = tracker.get_callstack_id(&Callstack::new());
+        tracker.add_allocation(PARENT_PROCESS, 1, 10, other_id);
+        tracker.add_allocation(PARENT_PROCESS, 2, 10, grower_id);
+        for (address, size) in (2..).zip([20, 40, 80, 160]) {
+            let old_size = tracker.free_allocation(PARENT_PROCESS, address).unwrap();
+            tracker.update_allocation(
+                PARENT_PROCESS,
+                address,
+                old_size,
+                address + 1,
+                size,
+                grower_id,
+            );
+        }
+        assert_eq!(tracker.get_current_allocated_bytes(), 170);
+        let report = tracker.realloc_report()();
+        assert_eq!(report.callstacks.len(), 1);
+        assert_eq!(report.callstacks[0].callstack, "a:1 (af)");
+        assert_eq!(report.callstacks[0].stats.grew, 4);
+        assert_eq!(report.callstacks[0].stats.moved, 4);
+        assert_eq!(report.callstacks[0].stats.bytes_grown, 150);
+
+        tracker.reset(".".to_string());
+        assert!(tracker.realloc_report()().callstacks.is_empty());
+    }
+
+    #[test]
+    fn frees_recorded_with_callstack() {
+        let mut tracker = new_tracker();
+        let allocator = tracker.get_callstack_id(&Callstack::new());
+        let freer = tracker.get_callstack_id(&Callstack::from_vec(vec![CallSiteId::new(
+            FunctionId::UNKNOWN,
+            LineNumber(0),
+        )]));
+        tracker.add_allocation(PARENT_PROCESS, 1, 100, allocator);
+        tracker.add_allocation(PARENT_PROCESS, 2, 50, allocator);
+        // Plain frees aren't recorded:
+        tracker.free_allocation(PARENT_PROCESS, 2);
+        assert!(tracker.frees_report().is_none());
+        assert_eq!(
+            tracker.free_allocation_from(PARENT_PROCESS, 1, freer),
+            Some(100)
+        );
+        // Unknown addresses are ignored:
+        assert_eq!(tracker.free_allocation_from(PARENT_PROCESS, 3, freer), None);
+        tracker.check_if_new_peak();
+        tracker.assert_valid();
+        let report = tracker.frees_report().unwrap()();
+        assert_eq!(report.total_bytes, 100);
+        assert_eq!(report.pairs.pairs.len(), 1);
+        assert_eq!(report.pairs.pairs[0].frees, 1);
+
+        tracker.reset(".".to_string());
+        assert!(tracker.frees_report().is_none());
+    }
+
+    #[test]
+    fn dominant_callstack() {
+        let mut tracker = new_tracker();
+        assert_eq!(tracker.dominant_callstack(), None);
+        let fid = tracker
+            .functions
+            .add_function("a".to_string(), "af".to_string());
+        let mut cs1 = Callstack::new();
+        cs1.start_call(0, CallSiteId::new(fid, LineNumber(1)));
+        let mut cs2 = Callstack::new();
+        cs2.start_call(0, CallSiteId::new(fid, LineNumber(2)));
+        let cs1_id = tracker.get_callstack_id(&cs1);
+        let cs2_id = tracker.get_callstack_id(&cs2);
+        tracker.add_allocation(PARENT_PROCESS, 1, 100, cs1_id);
+        tracker.add_allocation(PARENT_PROCESS, 2, 60, cs2_id);
+        tracker.add_allocation(PARENT_PROCESS, 3, 60, cs2_id);
+        assert_eq!(tracker.dominant_callstack(), Some((cs2.clone(), 120)));
+        tracker.free_allocation(PARENT_PROCESS, 2);
+        tracker.free_allocation(PARENT_PROCESS, 3);
+        assert_eq!(tracker.dominant_callstack(), Some((cs1, 100)));
+        tracker.free_allocation(PARENT_PROCESS, 1);
+        assert_eq!(tracker.dominant_callstack(), None);
+    }
+
+    #[test]
+    fn find_allocations_by_function() {
+        let mut tracker = new_tracker();
+        let fid1 = tracker
+            .functions
+            .add_function("a.py".to_string(), "outer".to_string());
+        let fid2 = tracker
+            .functions
+            .add_function("b.py".to_string(), "inner".to_string());
+        let mut cs1 = Callstack::new();
+        cs1.start_call(0, CallSiteId::new(fid1, LineNumber(1)));
+        let mut cs2 = cs1.clone();
+        cs2.start_call(2, CallSiteId::new(fid2, LineNumber(3)));
+        let cs1_id = tracker.get_callstack_id(&cs1);
+        let cs2_id = tracker.get_callstack_id(&cs2);
+        tracker.add_allocation(PARENT_PROCESS, 1, 100, cs1_id);
+        tracker.add_allocation(PARENT_PROCESS, 2, 200, cs2_id);
+        tracker.add_allocation(PARENT_PROCESS, 3, 300, cs2_id);
+        tracker.add_anon_mmap(PARENT_PROCESS, 0x1000, 4096, cs2_id);
+
+        let mut out = [AllocationInfo::default(); 4];
+        assert_eq!(
+            tracker.find_allocations_by_function("a.py", "outer", &mut out),
+            3
+        );
+        let mut found = out[..3].to_vec();
+        found.sort_by_key(|info| info.address);
+        assert_eq!(
+            found,
+            vec![
+                AllocationInfo {
+                    address: 1,
+                    size: 100
+                },
+                AllocationInfo {
+                    address: 2,
+                    size: 200
+                },
+                AllocationInfo {
+                    address: 3,
+                    size: 300
+                },
+            ]
+        );
+        // Truncated to the capacity, but the total is still returned:
+        let mut out = [AllocationInfo::default(); 1];
+        assert_eq!(
+            tracker.find_allocations_by_function("b.py", "inner", &mut out),
+            2
+        );
+        assert!(out[0].address == 2 || out[0].address == 3);
+        assert_eq!(
+            tracker.find_allocations_by_function("b.py", "inner", &mut []),
+            2
+        );
+        // Both filename and function need to match:
+        assert_eq!(
+            tracker.find_allocations_by_function("a.py", "inner", &mut []),
+            0
+        );
+        assert_eq!(
+            tracker.find_allocations_by_function("c.py", "other", &mut []),
+            0
+        );
+    }
+
+    #[test]
+    fn function_detail() {
+        let mut tracker = new_tracker();
+        let main = tracker
+            .functions
+            .add_function("a.py".to_string(), "main".to_string());
+        let recurse = tracker
+            .functions
+            .add_function("b.py".to_string(), "recurse".to_string());
+        let cs = |calls: &[(FunctionId, u32)]| {
+            Callstack::from_vec(
+                calls
+                    .iter()
+                    .map(|(function, line)| CallSiteId::new(*function, LineNumber(*line)))
+                    .collect(),
+            )
+        };
+        // recurse() calls itself on line 5, and allocates on line 6:
+        let deep = cs(&[(main, 1), (recurse, 5), (recurse, 5), (recurse, 6)]);
+        let shallow = cs(&[(main, 2), (recurse, 6)]);
+        let calling = cs(&[(main, 3), (recurse, 5)]);
+        let other = cs(&[(main, 4)]);
+        for (address, (callstack, size)) in [(deep, 100), (shallow, 50), (calling, 30), (other, 20)]
+            .iter()
+            .enumerate()
+        {
+            let id = tracker.get_callstack_id(callstack);
+            tracker.add_allocation(PARENT_PROCESS, address + 1, *size, id);
+        }
+        // Freed before the report, so not part of the peak:
+        let late = cs(&[(recurse, 7)]);
+        let late_id = tracker.get_callstack_id(&late);
+        tracker.check_if_new_peak();
+        tracker.free_allocation(PARENT_PROCESS, 4);
+        tracker.add_allocation(PARENT_PROCESS, 5, 10, late_id);
+
+        let detail = tracker.function_detail("b.py", "recurse").unwrap();
+        assert_eq!((detail.total_bytes, detail.peak_bytes), (180, 200));
+        let lines: Vec<_> = detail
+            .lines
+            .iter()
+            .map(|line| (line.line, line.bytes))
+            .collect();
+        // Each callstack only counts once, at the innermost frame:
+        assert_eq!(lines, vec![(6, 150), (5, 30)]);
+
+        let detail = tracker.function_detail("a.py", "main").unwrap();
+        assert_eq!(detail.total_bytes, 200);
+        assert_eq!(detail.lines.len(), 4);
+        assert!(tracker.function_detail("a.py", "recurse").is_none());
+    }
+
+    #[test]
+    fn exit_summary() {
+        let mut tracker = new_tracker();
+        let fid = tracker
+            .functions
+            .add_function("a.py".to_string(), "af".to_string());
+        let mut cs1 = Callstack::new();
+        cs1.start_call(0, CallSiteId::new(fid, LineNumber(1)));
+        let mut cs2 = cs1.clone();
+        cs2.start_call(1, CallSiteId::new(fid, LineNumber(2)));
+        let cs1_id = tracker.get_callstack_id(&cs1);
+        let cs2_id = tracker.get_callstack_id(&cs2);
+        let small_id = tracker.get_callstack_id(&Callstack::small_allocations(1024));
+        tracker.add_allocation(PARENT_PROCESS, 1, 3 * 1024 * 1024, cs2_id);
+        tracker.add_allocation(PARENT_PROCESS, 2, 1024 * 1024, cs1_id);
+        tracker.add_allocation(PARENT_PROCESS, 3, 2 * 1024 * 1024, small_id);
+        tracker.check_if_new_peak();
+        // Not at the peak anymore:
+        tracker.free_allocation(PARENT_PROCESS, 1);
+        tracker.add_allocation(PARENT_PROCESS, 4, 100, cs1_id);
+
+        let summary = tracker.exit_summary()();
+        assert_eq!(summary.peak_bytes, 6 * 1024 * 1024);
+        assert_eq!(summary.peak_allocations, 3);
+        assert_eq!(
+            summary.top_callstacks,
+            vec![
+                ("a.py:2 (af)".to_string(), 3 * 1024 * 1024),
+                ("[small allocations < 1 KiB]".to_string(), 2 * 1024 * 1024),
+                ("a.py:1 (af)".to_string(), 1024 * 1024),
+            ]
+        );
+        let summary = ExitSummary {
+            peak_rss_bytes: Some(10 * 1024 * 1024),
+            ..summary
+        };
+        assert_eq!(
+            summary.format(
+                std::path::Path::new("/tmp/report"),
+                &["metadata.json".to_string(), "peak-memory.svg".to_string()]
+            ),
+            "=fil-profile= Peak tracked memory: 6.0 MiB in 3 allocations, peak RSS 10.0 MiB
+=fil-profile=        3.0 MiB  a.py:2 (af)
+=fil-profile=        2.0 MiB  [small allocations < 1 KiB]
+=fil-profile=        1.0 MiB  a.py:1 (af)
+=fil-profile= Reports in /tmp/report: metadata.json, peak-memory.svg
+"
+        );
+    }
+
+    #[test]
+    fn peak_triggers() {
+        pyo3::prepare_freethreaded_python();
+        let mut tracker = new_tracker();
+        assert_eq!(tracker.peak_triggers_report()(), vec![]);
+        let fid = tracker
+            .functions
+            .add_function("a.py".to_string(), "af".to_string());
+        let mut cs1 = Callstack::new();
+        cs1.start_call(0, CallSiteId::new(fid, LineNumber(1)));
+        let mut cs2 = cs1.clone();
+        cs2.start_call(1, CallSiteId::new(fid, LineNumber(2)));
+        let cs1_id = tracker.get_callstack_id(&cs1);
+        let cs2_id = tracker.get_callstack_id(&cs2);
+        tracker.add_allocation(PARENT_PROCESS, 1, 1000, cs1_id);
+        tracker.add_allocation(PARENT_PROCESS, 2, 2000, cs2_id);
+        // The peak was reached by the last allocation before the free:
+        tracker.free_allocation(PARENT_PROCESS, 1);
+        // Not a new peak:
+        tracker.add_allocation(PARENT_PROCESS, 3, 500, cs1_id);
+        tracker.free_allocation(PARENT_PROCESS, 3);
+
+        let reports = tracker.peak_triggers_report()();
+        assert_eq!(
+            reports,
+            vec![PeakTriggerReport {
+                callstack: "a.py:1 (af);a.py:2 (af)".to_string(),
+                innermost_frame: "a.py:2 (af)".to_string(),
+                bytes: 2000,
+                thread_id: current_thread_id(),
+                peak_bytes: 3000,
+            }]
+        );
+        assert!(reports[0].summary().starts_with(
+            "Peak was reached while executing: a.py:2 (af) (allocating 0.0 MiB on thread "
+        ));
+    }
+
+    #[test]
+    fn prettified_frame_names() {
+        let mut functions = VecFunctionLocations::with_frame_names(FrameNames {
+            demangle: true,
+            shorten_paths: true,
+            collapse_init: true,
+            ..Default::default()
+        });
+        let fid = functions.add_function(
+            "/venv/lib/python3.11/site-packages/ext/__init__.py".to_string(),
+            "_ZN3ext4loadEv".to_string(),
+        );
+        let functions = functions.to_reader();
+        let (function, filename, display_filename) =
+            functions.get_function_and_filename_and_display_filename(fid);
+        assert_eq!(function, "ext::load()");
+        // The original is still needed to read the source code:
+        assert_eq!(
+            filename,
+            "/venv/lib/python3.11/site-packages/ext/__init__.py"
+        );
+        assert_eq!(display_filename, "<site>/ext");
+    }
+
+    #[test]
+    fn qualified_names_keep_methods_apart() {
+        assert_eq!(
+            qualified_name("app.jobs", "Parser.process"),
+            "app.jobs.Parser.process"
+        );
+        assert_eq!(
+            qualified_name("__main__", "Parser.process"),
+            "Parser.process"
+        );
+        assert_eq!(qualified_name("app.jobs", "<module>"), "<module>");
+        assert_eq!(qualified_name("", "f.<locals>.g"), "f.<locals>.g");
+
+        pyo3::prepare_freethreaded_python();
+        let mut tracker = new_tracker();
+        let parser = tracker.functions.add_qualified_function(
+            "jobs.py".to_string(),
+            "process".to_string(),
+            Some("jobs.Parser.process".to_string()),
+        );
+        let writer = tracker.functions.add_qualified_function(
+            "jobs.py".to_string(),
+            "process".to_string(),
+            Some("jobs.Writer.process".to_string()),
+        );
+        // Old-style registration, and a qualified name that adds nothing:
+        let plain = tracker
+            .functions
+            .add_function("jobs.py".to_string(), "process".to_string());
+        let same = tracker.functions.add_qualified_function(
+            "jobs.py".to_string(),
+            "main".to_string(),
+            Some("main".to_string()),
+        );
+        for (address, function) in [parser, writer, plain, same].into_iter().enumerate() {
+            let mut cs = Callstack::new();
+            cs.start_call(0, CallSiteId::new(function, LineNumber(1)));
+            let cs_id = tracker.get_callstack_id(&cs);
+            tracker.add_allocation(PARENT_PROCESS, address + 1, 1000, cs_id);
+        }
+        tracker.check_if_new_peak();
+        let mut lines: Vec<String> = tracker.combine_callstacks(true, IdentityCleaner)()
+            .to_lines(false)
+            .collect();
+        lines.sort();
+        assert_eq!(
+            lines,
+            vec![
+                "jobs.py:1 (jobs.Parser.process) 1000",
+                "jobs.py:1 (jobs.Writer.process) 1000",
+                "jobs.py:1 (main) 1000",
+                "jobs.py:1 (process) 1000",
+            ]
+        );
+
+        // Lookups by function work with either name:
+        assert_eq!(
+            tracker.find_allocations_by_function("jobs.py", "jobs.Parser.process", &mut []),
+            1
+        );
+        assert_eq!(
+            tracker.find_allocations_by_function("jobs.py", "process", &mut []),
+            3
+        );
+    }
+
     #[test]
     fn test_unknown_function_id() {
         let func_locations = VecFunctionLocations::new().to_reader();
@@ -1246,5 +4468,727 @@ mod tests {
         assert_eq!(function, "UNKNOWN");
     }
 
-    // TODO test to_lines(false)
+    #[test]
+    fn phases_split_callstacks() {
+        pyo3::prepare_freethreaded_python();
+        let make_tracker = |phases| {
+            let mut tracker = new_tracker();
+            tracker.phases = phases;
+            let fid = tracker
+                .functions
+                .add_function("a".to_string(), "af".to_string());
+            let mut cs = Callstack::new();
+            cs.start_call(0, CallSiteId::new(fid, LineNumber(1)));
+            tracker.mark_phase("imports");
+            let imports_id = cs.id_for_new_allocation(1, |cs| tracker.get_callstack_id(cs));
+            tracker.add_allocation(PARENT_PROCESS, 1, 1000, imports_id);
+            tracker.mark_phase("main");
+            // The phase changed, so the cached id mustn't be reused:
+            cs.set_phase(tracker.current_phase());
+            let main_id = cs.id_for_new_allocation(1, |cs| tracker.get_callstack_id(cs));
+            assert_ne!(imports_id, main_id);
+            tracker.add_allocation(PARENT_PROCESS, 2, 234, main_id);
+            tracker.check_if_new_peak();
+            tracker
+        };
+
+        // By default the phases are merged in the flamegraph:
+        let mut tracker = make_tracker(Phases::new(false, vec![]));
+        let lines: Vec<String> = tracker.combine_callstacks(true, IdentityCleaner)()
+            .to_lines(false)
+            .collect();
+        assert_eq!(lines, vec!["a:1 (af) 1234"]);
+        let summary: Vec<(String, usize)> = tracker
+            .report_metadata()
+            .phases
+            .into_iter()
+            .map(|phase| (phase.name, phase.peak_bytes))
+            .collect();
+        assert_eq!(
+            summary,
+            vec![("imports".to_string(), 1000), ("main".to_string(), 234)]
+        );
+
+        // Phases can be shown as root frames:
+        let mut tracker = make_tracker(Phases::new(true, vec![]));
+        let mut lines: Vec<String> = tracker.combine_callstacks(true, IdentityCleaner)()
+            .to_lines(false)
+            .collect();
+        lines.sort();
+        assert_eq!(
+            lines,
+            vec![
+                "[phase: imports];a:1 (af) 1000",
+                "[phase: main];a:1 (af) 234"
+            ]
+        );
+
+        // Excluded phases are left out of the flamegraph, but not the totals:
+        let mut tracker = make_tracker(Phases::new(false, vec!["imports".to_string()]));
+        let lines: Vec<String> = tracker.combine_callstacks(true, IdentityCleaner)()
+            .to_lines(false)
+            .collect();
+        assert_eq!(lines, vec!["a:1 (af) 234"]);
+        assert_eq!(tracker.get_traced_memory(), (1234, 1234));
+    }
+
+    #[test]
+    fn phase_frames() {
+        pyo3::prepare_freethreaded_python();
+        let mut tracker = new_tracker();
+        let fid = tracker
+            .functions
+            .add_function("a".to_string(), "af".to_string());
+        let load = tracker
+            .functions
+            .add_function("[phase]".to_string(), "load".to_string());
+        let epoch = tracker
+            .functions
+            .add_function("[phase]".to_string(), "epoch".to_string());
+        let mut cs = Callstack::new();
+        cs.start_call(0, CallSiteId::new(fid, LineNumber(1)));
+        let plain_id = cs.id_for_new_allocation(1, |cs| tracker.get_callstack_id(cs));
+        tracker.add_allocation(PARENT_PROCESS, 1, 100, plain_id);
+
+        assert!(cs.push_phase_frame(load));
+        assert!(cs.push_phase_frame(epoch));
+        let phased_id = cs.id_for_new_allocation(1, |cs| tracker.get_callstack_id(cs));
+        assert_ne!(plain_id, phased_id);
+        tracker.add_allocation(PARENT_PROCESS, 2, 1000, phased_id);
+        tracker.check_if_new_peak();
+        cs.pop_phase_frame();
+        cs.pop_phase_frame();
+        assert_eq!(
+            cs.id_for_new_allocation(1, |cs| tracker.get_callstack_id(cs)),
+            plain_id
+        );
+        tracker.free_allocation(PARENT_PROCESS, 2);
+
+        let mut lines: Vec<String> = tracker.combine_callstacks(true, IdentityCleaner)()
+            .to_lines(false)
+            .collect();
+        lines.sort();
+        assert_eq!(
+            lines,
+            vec!["[phase: load];[phase: epoch];a:1 (af) 1000", "a:1 (af) 100"]
+        );
+        assert_eq!(
+            tracker.report_metadata().peak_phase_frames,
+            vec!["load".to_string(), "epoch".to_string()]
+        );
+
+        // Runaway nesting is capped, and pops still match up with pushes:
+        let mut cs = Callstack::new();
+        for _ in 0..MAX_PHASE_FRAMES {
+            assert!(cs.push_phase_frame(load));
+        }
+        assert!(!cs.push_phase_frame(epoch));
+        assert_eq!(cs.phase_frames().len(), MAX_PHASE_FRAMES);
+        cs.pop_phase_frame();
+        assert_eq!(cs.phase_frames().len(), MAX_PHASE_FRAMES);
+        cs.pop_phase_frame();
+        assert_eq!(cs.phase_frames().len(), MAX_PHASE_FRAMES - 1);
+    }
+
+    #[test]
+    fn small_allocations_rolled_up() {
+        pyo3::prepare_freethreaded_python();
+        let mut tracker = new_tracker();
+        assert_eq!(tracker.small_allocation_callstack_id(10), None);
+        tracker.set_small_allocations_below(1024);
+        assert_eq!(tracker.small_allocation_callstack_id(1024), None);
+        let small_id = tracker.small_allocation_callstack_id(48).unwrap();
+        assert_eq!(tracker.small_allocation_callstack_id(1023), Some(small_id));
+
+        let fid = tracker
+            .functions
+            .add_function("a".to_string(), "af".to_string());
+        let mut cs = Callstack::new();
+        cs.start_call(0, CallSiteId::new(fid, LineNumber(1)));
+        let cs_id = tracker.get_callstack_id(&cs);
+        tracker.add_allocation(PARENT_PROCESS, 1, 2000, cs_id);
+        tracker.add_allocation(PARENT_PROCESS, 2, 48, small_id);
+        tracker.add_allocation(PARENT_PROCESS, 3, 500, small_id);
+        tracker.add_allocation(PARENT_PROCESS, 4, 100, small_id);
+        // Frees find the synthetic callstack from the allocation; the peak was
+        // before the free:
+        assert_eq!(tracker.free_allocation(PARENT_PROCESS, 4), Some(100));
+        assert_eq!(tracker.current_memory_usage[small_id as usize], 548);
+        tracker.check_if_new_peak();
+        tracker.assert_valid();
+
+        let mut lines: Vec<String> = tracker.combine_callstacks(true, IdentityCleaner)()
+            .to_lines(false)
+            .collect();
+        lines.sort();
+        assert_eq!(
+            lines,
+            vec!["[small allocations < 1 KiB] 648", "a:1 (af) 2000"]
+        );
+    }
+
+    #[test]
+    fn footprint_stays_under_budget() {
+        pyo3::prepare_freethreaded_python();
+        const LIMIT: usize = 4 * 1024 * 1024;
+        let run = |limit| {
+            let mut tracker = new_tracker();
+            tracker.budget = TrackerBudget::new(limit);
+            let fid = tracker
+                .functions
+                .add_function("a".to_string(), "af".to_string());
+            let mut max_footprint = 0;
+            for i in 0..300_000 {
+                // Every allocation comes from a new callstack. Most are small,
+                // since the budget can't stop big allocations from being
+                // recorded, only make them share a callstack:
+                let mut cs = Callstack::new();
+                cs.start_call(0, CallSiteId::new(fid, LineNumber(i)));
+                let size = if i % 100 == 0 { 5000 } else { 100 };
+                let cs_id = tracker
+                    .small_allocation_callstack_id(size)
+                    .unwrap_or_else(|| tracker.get_callstack_id(&cs));
+                tracker.add_allocation(PARENT_PROCESS, i as usize * 8, size, cs_id);
+                max_footprint = max_footprint.max(tracker.footprint_bytes());
+            }
+            tracker.check_if_new_peak();
+            tracker.assert_valid();
+            (tracker, max_footprint)
+        };
+
+        let (_, unlimited_footprint) = run(0);
+        assert!(unlimited_footprint > LIMIT * 4);
+
+        let (mut tracker, max_footprint) = run(LIMIT);
+        assert!(max_footprint <= LIMIT);
+        let metadata = tracker.report_metadata();
+        assert!(metadata.tracker_budget.unwrap().exceeded);
+        assert!(metadata.adaptive_sampling.engaged);
+        let lines: Vec<String> = tracker.combine_callstacks(true, IdentityCleaner)()
+            .to_lines(false)
+            .collect();
+        assert!(lines
+            .iter()
+            .any(|line| line.starts_with("[callstack table full] ")));
+        assert!(lines
+            .iter()
+            .any(|line| line.starts_with("[small allocations < 1 KiB] ")));
+    }
+
+    #[test]
+    fn region_peak_is_relative_to_its_start() {
+        pyo3::prepare_freethreaded_python();
+        let mut tracker = new_tracker();
+        let fid = tracker
+            .functions
+            .add_function("a".to_string(), "af".to_string());
+        let mut cs1 = Callstack::new();
+        cs1.start_call(0, CallSiteId::new(fid, LineNumber(1)));
+        let mut cs2 = Callstack::new();
+        cs2.start_call(0, CallSiteId::new(fid, LineNumber(2)));
+        let cs1_id = tracker.get_callstack_id(&cs1);
+        let cs2_id = tracker.get_callstack_id(&cs2);
+        tracker.add_allocation(PARENT_PROCESS, 1, 1000, cs1_id);
+        tracker.add_anon_mmap(PARENT_PROCESS, 0x10000, 2000, cs1_id);
+
+        assert!(tracker.start_region(PreExisting::Exclude));
+        // No nesting:
+        assert!(!tracker.start_region(PreExisting::Group));
+        assert!(tracker.in_region());
+        tracker.add_allocation(PARENT_PROCESS, 2, 300, cs2_id);
+        // Freeing memory from before the region doesn't count against it:
+        assert_eq!(tracker.free_allocation(PARENT_PROCESS, 1), Some(1000));
+        tracker.free_anon_mmap(PARENT_PROCESS, 0x10000, 500);
+        tracker.add_allocation(PARENT_PROCESS, 3, 500, cs1_id);
+        tracker.add_allocation(PARENT_PROCESS, 4, 50, cs1_id);
+        assert_eq!(tracker.free_allocation(PARENT_PROCESS, 4), Some(50));
+        assert_eq!(tracker.get_allocation_size(PARENT_PROCESS, 3), 500);
+        assert_eq!(tracker.get_current_allocated_bytes(), 2300);
+        tracker.check_if_new_peak();
+        tracker.assert_valid();
+
+        let report = tracker.end_region().unwrap()();
+        assert!(!tracker.in_region());
+        let mut lines: Vec<String> = report.flamegraph.to_lines(false).collect();
+        lines.sort();
+        assert_eq!(lines, vec!["a:1 (af) 550", "a:2 (af) 300"]);
+        assert_eq!(report.total_bytes, 850);
+        assert_eq!(report.metadata.peak_bytes, 850);
+        assert_eq!(report.metadata.pre_existing_bytes, 1500);
+        assert!(tracker.end_region().is_none());
+
+        // Everything is back to normal, and the rest of the mmap can still be
+        // freed:
+        tracker.free_anon_mmap(PARENT_PROCESS, 0x10000, 2000);
+        assert_eq!(tracker.free_allocation(PARENT_PROCESS, 2), Some(300));
+        assert_eq!(tracker.get_current_allocated_bytes(), 500);
+        tracker.assert_valid();
+
+        // Regions can be repeated, and can group pre-existing memory:
+        assert!(tracker.start_region(PreExisting::Group));
+        tracker.add_allocation(PARENT_PROCESS, 5, 100, cs2_id);
+        let report = tracker.end_region().unwrap()();
+        let mut lines: Vec<String> = report.flamegraph.to_lines(false).collect();
+        lines.sort();
+        assert_eq!(lines, vec!["[pre-existing] 500", "a:2 (af) 100"]);
+        assert_eq!(report.total_bytes, 600);
+        tracker.assert_valid();
+    }
+
+    #[test]
+    fn mapped_files_tracked_separately() {
+        pyo3::prepare_freethreaded_python();
+        let mut tracker = new_tracker();
+        assert!(tracker.mapped_files_report().is_none());
+        let fid = tracker
+            .functions
+            .add_function("a".to_string(), "af".to_string());
+        let file_fid = tracker
+            .functions
+            .add_function("[mapped file]".to_string(), "data.parquet".to_string());
+        let mut cs = Callstack::new();
+        cs.start_call(0, CallSiteId::new(fid, LineNumber(1)));
+        let cs_id = tracker.get_callstack_id(&cs);
+        let mapped_cs_id = tracker.get_callstack_id(&cs.with_mapped_file(2, file_fid));
+        assert_ne!(cs_id, mapped_cs_id);
+        tracker.add_allocation(PARENT_PROCESS, 1, 2000, cs_id);
+        tracker.add_file_mmap(PARENT_PROCESS, 0x10000, 10000, mapped_cs_id);
+        tracker.free_file_mmap(PARENT_PROCESS, 0x10000, 10000);
+        tracker.add_allocation(PARENT_PROCESS, 2, 3000, cs_id);
+        // The main peak doesn't include the mapped file:
+        tracker.check_if_new_peak();
+        assert_eq!(tracker.get_peak_allocated_bytes(), 5000);
+        tracker.assert_valid();
+
+        // The combined peak was when the file was mapped:
+        let report = tracker.mapped_files_report().unwrap()();
+        assert_eq!(report.peak_bytes, 12000);
+        assert_eq!(report.mapped_bytes, 10000);
+        let mut lines: Vec<String> = report.flamegraph.to_lines(false).collect();
+        lines.sort();
+        assert_eq!(
+            lines,
+            vec![
+                "a:1 (af) 2000",
+                "a:2 (af);[mapped file: data.parquet] 10000"
+            ]
+        );
+    }
+
+    #[test]
+    fn drift_leaves_out_anon_mmaps() {
+        let mut tracker = new_tracker();
+        assert!(tracker.sample_drift(0).is_none());
+        tracker.drift = Some(DriftDetector::new(DriftSettings {
+            interval: std::time::Duration::from_secs(1),
+            ratio: 0.5,
+            samples: 2,
+        }));
+        let cs_id = tracker.get_callstack_id(&Callstack::new());
+        tracker.add_allocation(PARENT_PROCESS, 1, 100 * MIB, cs_id);
+        // Fil itself allocated 10MiB before the first sample:
+        assert!(tracker.sample_drift(110 * MIB).is_none());
+        // The allocator doesn't know about anonymous mmap()s:
+        tracker.add_anon_mmap(PARENT_PROCESS, 1 << 30, 500 * MIB, cs_id);
+        assert!(tracker.sample_drift(110 * MIB).is_none());
+        assert!(tracker.sample_drift(110 * MIB).is_none());
+        // But it does know about these, if they're never tracked:
+        assert!(tracker.sample_drift(310 * MIB).is_none());
+        let warning = tracker.sample_drift(310 * MIB).unwrap();
+        assert!(warning.contains("200.0 MiB more in use"), "{}", warning);
+        let report = tracker.report_metadata().drift.unwrap();
+        assert_eq!(report.samples, 5);
+        assert!(report.warning.is_some());
+        tracker.reset("/tmp".to_string());
+        assert!(tracker.report_metadata().drift.unwrap().warning.is_none());
+    }
+
+    #[test]
+    fn milestones_snapshot_current_usage() {
+        pyo3::prepare_freethreaded_python();
+        let mut tracker = new_tracker();
+        tracker.milestones = Some(Milestones::new(
+            milestones::parse("1000,2000,3000").unwrap(),
+        ));
+        let fid = tracker
+            .functions
+            .add_function("a".to_string(), "af".to_string());
+        let mut cs1 = Callstack::new();
+        cs1.start_call(0, CallSiteId::new(fid, LineNumber(1)));
+        let mut cs2 = Callstack::new();
+        cs2.start_call(0, CallSiteId::new(fid, LineNumber(2)));
+        let cs1_id = tracker.get_callstack_id(&cs1);
+        let cs2_id = tracker.get_callstack_id(&cs2);
+        tracker.add_allocation(PARENT_PROCESS, 1, 1500, cs1_id);
+        // Crosses two at once, with an mmap():
+        tracker.add_anon_mmap(PARENT_PROCESS, 1 << 20, 1600, cs2_id);
+        // Later usage isn't in the snapshots:
+        tracker.free_allocation(PARENT_PROCESS, 1);
+        tracker.add_allocation(PARENT_PROCESS, 2, 1200, cs1_id);
+        assert!(tracker.has_pending_milestone_dumps());
+        let dumps: Vec<_> = tracker
+            .milestone_dumps()
+            .into_iter()
+            .map(|(milestones, allocated_bytes, factory)| {
+                let labels: Vec<String> = milestones.into_iter().map(|m| m.label).collect();
+                let mut lines: Vec<String> = factory().to_lines(false).collect();
+                lines.sort();
+                (labels, allocated_bytes, lines)
+            })
+            .collect();
+        assert_eq!(
+            dumps,
+            vec![
+                (
+                    vec!["1000".to_string()],
+                    1500,
+                    vec!["a:1 (af) 1500".to_string()]
+                ),
+                (
+                    vec!["2000".to_string(), "3000".to_string()],
+                    3100,
+                    vec!["a:1 (af) 1500".to_string(), "a:2 (af) 1600".to_string()]
+                ),
+            ]
+        );
+        assert!(!tracker.has_pending_milestone_dumps());
+        assert_eq!(tracker.report_metadata().milestones.len(), 3);
+        tracker.reset("/tmp".to_string());
+        assert!(tracker.report_metadata().milestones.is_empty());
+    }
+
+    #[test]
+    fn too_big_allocations_are_ignored() {
+        let mut tracker = new_tracker();
+        tracker.size_ceiling = SizeCeiling::new(1 << 30);
+        let cs_id = tracker.get_callstack_id(&Callstack::new());
+        tracker.add_allocation(PARENT_PROCESS, 1, 1000, cs_id);
+        tracker.add_allocation(PARENT_PROCESS, 2, (1 << 30) + 1, cs_id);
+        tracker.update_allocation(PARENT_PROCESS, 1, 1000, 3, usize::MAX, cs_id);
+        tracker.add_anon_mmap(PARENT_PROCESS, 1 << 20, 1 << 40, cs_id);
+        tracker.add_file_mmap(PARENT_PROCESS, 1 << 40, 1 << 40, cs_id);
+        assert_eq!(tracker.get_traced_memory(), (1000, 1000));
+        assert_eq!(tracker.free_allocation(PARENT_PROCESS, 2), None);
+        assert_eq!(tracker.validate(), Vec::<String>::new());
+        let rejected = tracker.report_metadata().rejected_allocations.unwrap();
+        assert_eq!((rejected.count, rejected.largest_bytes), (4, usize::MAX));
+        tracker.reset("/tmp".to_string());
+        assert!(tracker.report_metadata().rejected_allocations.is_none());
+    }
+
+    #[test]
+    fn rotation_carries_live_memory_over() {
+        pyo3::prepare_freethreaded_python();
+        let mut tracker = new_tracker();
+        assert!(tracker.rotate_window().is_none());
+        tracker.rotation = Some(Rotation::new(Duration::ZERO, 2));
+        let fid = tracker
+            .functions
+            .add_function("a".to_string(), "af".to_string());
+        let mut cs1 = Callstack::new();
+        cs1.start_call(0, CallSiteId::new(fid, LineNumber(1)));
+        let mut cs2 = Callstack::new();
+        cs2.start_call(0, CallSiteId::new(fid, LineNumber(2)));
+        let cs1_id = tracker.get_callstack_id(&cs1);
+        let cs2_id = tracker.get_callstack_id(&cs2);
+        tracker.add_allocation(PARENT_PROCESS, 1, 1000, cs1_id);
+        tracker.add_anon_mmap(PARENT_PROCESS, 1 << 20, 2000, cs2_id);
+        tracker.add_allocation(PARENT_PROCESS, 2, 500, cs1_id);
+        tracker.free_allocation(PARENT_PROCESS, 2);
+        let sorted = |mut lines: Vec<String>| {
+            lines.sort();
+            lines
+        };
+
+        let (finished, peak, factory) = tracker.rotate_window().unwrap();
+        assert_eq!((finished.number, peak), (1, 3500));
+        assert_eq!(
+            sorted(factory().to_lines(false).collect()),
+            vec!["a:1 (af) 1500", "a:2 (af) 2000"]
+        );
+        // The new window starts with what's still live, carried over:
+        assert_eq!(tracker.get_peak_allocated_bytes(), 3000);
+        tracker.add_allocation(PARENT_PROCESS, 3, 100, cs1_id);
+        tracker.free_allocation(PARENT_PROCESS, 1);
+        assert_eq!(tracker.validate(), Vec::<String>::new());
+        let (finished, peak, factory) = tracker.rotate_window().unwrap();
+        assert_eq!((finished.number, peak), (2, 3100));
+        assert_eq!(
+            sorted(factory().to_lines(false).collect()),
+            vec![
+                "[carried over];a:1 (af) 1000",
+                "[carried over];a:2 (af) 2000",
+                "a:1 (af) 100"
+            ]
+        );
+        // The peak can be the window's start; carrying over again doesn't
+        // nest:
+        tracker.free_anon_mmap(PARENT_PROCESS, 1 << 20, 2000);
+        let (finished, peak, factory) = tracker.rotate_window().unwrap();
+        assert_eq!(finished.expired, vec!["window-1".to_string()]);
+        assert_eq!(peak, 2100);
+        assert_eq!(
+            sorted(factory().to_lines(false).collect()),
+            vec![
+                "[carried over];a:1 (af) 100",
+                "[carried over];a:2 (af) 2000"
+            ]
+        );
+        assert_eq!(tracker.get_peak_allocated_bytes(), 100);
+        assert_eq!(tracker.validate(), Vec::<String>::new());
+        assert_eq!(tracker.report_metadata().rotation.unwrap().current, 4);
+
+        // No rotating in the middle of a region:
+        assert!(tracker.start_region(PreExisting::Group));
+        assert!(tracker.rotate_window().is_none());
+        assert!(tracker.end_region().is_some());
+        assert!(tracker.rotate_window().is_some());
+    }
+
+    #[test]
+    fn temp_files_tracked_separately() {
+        pyo3::prepare_freethreaded_python();
+        let mut tracker = new_tracker();
+        assert!(tracker.temp_files_report().is_none());
+        let fid = tracker
+            .functions
+            .add_function("a".to_string(), "spill".to_string());
+        let mut cs = Callstack::new();
+        cs.start_call(0, CallSiteId::new(fid, LineNumber(1)));
+        let cs_id = tracker.get_callstack_id(&cs);
+        tracker.add_allocation(PARENT_PROCESS, 1, 2000, cs_id);
+        let path = std::path::Path::new("/tmp/spill");
+        tracker.temp_file_opened(5, OpenedFile::InTempDirectory(path.into()), cs_id);
+        tracker.temp_file_resized(5, 100_000, false);
+        tracker.temp_file_unlinked(path);
+        tracker.temp_file_closed(5);
+        tracker.check_if_new_peak();
+        assert_eq!(tracker.get_peak_allocated_bytes(), 2000);
+        tracker.assert_valid();
+
+        let report = tracker.temp_files_report().unwrap()();
+        assert_eq!(report.peak_bytes, 100_000);
+        assert_eq!(report.callstacks.len(), 1);
+        assert_eq!(report.callstacks[0].innermost_frame, "a:1 (spill)");
+        assert_eq!(report.callstacks[0].bytes, 100_000);
+        tracker.reset("/tmp".to_string());
+        assert!(tracker.temp_files_report().is_none());
+    }
+
+    #[test]
+    fn callstack_without_line_numbers() {
+        pyo3::prepare_freethreaded_python();
+        let mut functions = VecFunctionLocations::new();
+        let fid1 = functions.add_function("a".to_string(), "af".to_string());
+        let fid2 = functions.add_function("b".to_string(), "bf".to_string());
+        let mut cs1 = Callstack::new();
+        cs1.start_call(0, CallSiteId::new(fid1, LineNumber(1)));
+        cs1.start_call(0, CallSiteId::new(fid2, LineNumber(10)));
+        let mut cs2 = Callstack::new();
+        cs2.start_call(0, CallSiteId::new(fid1, LineNumber(2)));
+        cs2.start_call(0, CallSiteId::new(fid2, LineNumber(20)));
+        assert_ne!(cs1, cs2);
+        assert_eq!(cs1.without_line_numbers(), cs2.without_line_numbers());
+        let mut linecache = LineCacher::default();
+        for to_be_post_processed in [false, true] {
+            assert_eq!(
+                cs1.without_line_numbers().as_string(
+                    to_be_post_processed,
+                    &functions,
+                    ";",
+                    &mut linecache
+                ),
+                "a (af);b (bf)"
+            );
+        }
+        assert_eq!(
+            cs1.without_line_numbers().innermost_frame(&functions),
+            "b (bf)"
+        );
+    }
+
+    #[test]
+    fn structured_frames_match_as_string() {
+        pyo3::prepare_freethreaded_python();
+        let mut functions = VecFunctionLocations::new();
+        let fid1 = functions.add_function("a".to_string(), "af".to_string());
+        let fid2 = functions.add_function("b".to_string(), "bf".to_string());
+        let phase = functions.add_function("load".to_string(), "".to_string());
+        let data = functions.add_function("data.bin".to_string(), "".to_string());
+        let mut cs = Callstack::new();
+        cs.push_phase_frame(phase);
+        cs.start_call(0, CallSiteId::new(fid1, LineNumber(1)));
+        cs.start_call(0, CallSiteId::new(fid2, LineNumber(10)));
+        let mapped = cs.with_mapped_file(11, data);
+        let context = functions.add_function("[context]".to_string(), "load".to_string());
+        let domain = functions.add_function("[domain]".to_string(), "arena".to_string());
+        let mut in_context = cs.clone();
+        in_context.push_synthetic_frame(11, context);
+        let reported = in_context.with_synthetic_leaf(0, domain);
+        let mut only_phases = Callstack::new();
+        only_phases.push_phase_frame(phase);
+        let callstacks = [
+            cs.clone(),
+            cs.without_line_numbers(),
+            mapped.clone(),
+            mapped.merged_into_caller().unwrap(),
+            reported.clone(),
+            reported.without_line_numbers(),
+            cs.merged_into_caller().unwrap(),
+            cs.merged_into_caller()
+                .unwrap()
+                .merged_into_caller()
+                .unwrap(),
+            only_phases,
+            Callstack::new(),
+            Callstack::pre_existing(),
+        ];
+        for callstack in callstacks {
+            let frames = callstack.structured_frames(&functions);
+            let joined = frames
+                .iter()
+                .map(|frame| match (frame.file.as_str(), frame.line) {
+                    ("", _) => frame.function.clone(),
+                    (file, Some(line)) => format!("{}:{} ({})", file, line, frame.function),
+                    (file, None) => format!("{} ({})", file, frame.function),
+                })
+                .collect::<Vec<_>>()
+                .join(";");
+            assert_eq!(
+                joined,
+                callstack.as_string(false, &functions, ";", &mut LineCacher::default())
+            );
+        }
+        assert_eq!(
+            cs.structured_frames(&functions)[2],
+            Frame {
+                file: "b".to_string(),
+                function: "bf".to_string(),
+                line: Some(10)
+            }
+        );
+    }
+
+    #[test]
+    fn synthetic_frames() {
+        pyo3::prepare_freethreaded_python();
+        let mut functions = VecFunctionLocations::new();
+        let fid = functions.add_function("a".to_string(), "af".to_string());
+        let context = functions.add_function("[context]".to_string(), "load".to_string());
+        let domain = functions.add_function("[domain]".to_string(), "arena".to_string());
+        let as_string =
+            |cs: &Callstack| cs.as_string(false, &functions, ";", &mut LineCacher::default());
+        let mut cs = Callstack::new();
+        cs.start_call(0, CallSiteId::new(fid, LineNumber(1)));
+        cs.push_synthetic_frame(2, context);
+        // Line numbers only go to Python frames:
+        assert_eq!(cs.id_for_new_allocation(3, |_| 7), 7);
+        assert_eq!(
+            as_string(&cs.with_synthetic_leaf(4, domain)),
+            "a:2 (af);[context: load];[domain: arena]"
+        );
+        assert_eq!(
+            cs.with_synthetic_leaf(4, domain)
+                .innermost_frame(&functions),
+            "[domain: arena]"
+        );
+        assert_eq!(cs.caller_callstack(5, 0), cs);
+        // Python called from inside the context:
+        cs.start_call(6, CallSiteId::new(fid, LineNumber(10)));
+        assert_eq!(as_string(&cs), "a:2 (af);[context: load];a:10 (af)");
+        assert_eq!(
+            as_string(&cs.without_line_numbers()),
+            "a (af);[context: load];a (af)"
+        );
+        cs.finish_call();
+        cs.pop_synthetic_frame();
+        assert_eq!(as_string(&cs), "a:2 (af)");
+        // Popping without a context does nothing:
+        cs.pop_synthetic_frame();
+        assert_eq!(as_string(&cs), "a:2 (af)");
+    }
+
+    rusty_fork_test! {
+        /// FIL_AGGREGATE_LINES=1 merges callstacks that only differ by line
+        /// number, when the report is written.
+        #[test]
+        fn aggregate_lines() {
+            pyo3::prepare_freethreaded_python();
+            let mut tracker = new_tracker();
+            let fid = tracker
+                .functions
+                .add_function("a".to_string(), "af".to_string());
+            for line in 1..=3 {
+                let mut cs = Callstack::new();
+                cs.start_call(0, CallSiteId::new(fid, LineNumber(line)));
+                let cs_id = tracker.get_callstack_id(&cs);
+                tracker.add_allocation(PARENT_PROCESS, line as usize, 100, cs_id);
+            }
+            let mut lines: Vec<String> = tracker.combine_callstacks(true, IdentityCleaner)()
+                .to_lines(false)
+                .collect();
+            lines.sort();
+            assert_eq!(lines, vec!["a:1 (af) 100", "a:2 (af) 100", "a:3 (af) 100"]);
+            assert!(tracker.report_metadata().line_numbers);
+
+            // The same data, reported without line numbers:
+            std::env::set_var("FIL_AGGREGATE_LINES", "1");
+            let lines: Vec<String> = tracker.combine_callstacks(true, IdentityCleaner)()
+                .to_lines(false)
+                .collect();
+            assert_eq!(lines, vec!["a (af) 300"]);
+            assert!(!tracker.report_metadata().line_numbers);
+        }
+
+        /// With FIL_MAX_REPORT_STACKS, callstacks that would otherwise be
+        /// filtered out are merged into their callers, and with
+        /// FIL_FULL_REPORT_STACKS=1 they're all written out too.
+        #[test]
+        fn report_budget() {
+            pyo3::prepare_freethreaded_python();
+            let mut tracker = new_tracker();
+            let fid = tracker
+                .functions
+                .add_function("a".to_string(), "af".to_string());
+            let gid = tracker
+                .functions
+                .add_function("b".to_string(), "bf".to_string());
+            let mut cs = Callstack::new();
+            cs.start_call(0, CallSiteId::new(fid, LineNumber(1)));
+            let cs_id = tracker.get_callstack_id(&cs);
+            tracker.add_allocation(PARENT_PROCESS, 1, 1_000_000, cs_id);
+            for line in 1..=200 {
+                let mut cs = Callstack::new();
+                cs.start_call(0, CallSiteId::new(fid, LineNumber(2)));
+                cs.start_call(2, CallSiteId::new(gid, LineNumber(line)));
+                let cs_id = tracker.get_callstack_id(&cs);
+                tracker.add_allocation(PARENT_PROCESS, 100 + line as usize, 10, cs_id);
+            }
+            // Normally the tiny ones are mostly filtered out:
+            let lines = tracker.combine_callstacks(true, IdentityCleaner)().to_lines(false).len();
+            assert!(lines < 200);
+
+            std::env::set_var("FIL_MAX_REPORT_STACKS", "20");
+            std::env::set_var("FIL_FULL_REPORT_STACKS", "1");
+            let flamegraph = tracker.combine_callstacks(true, IdentityCleaner)();
+            let lines: Vec<String> = flamegraph.to_lines(false).collect();
+            assert_eq!(lines.len(), 20);
+            let total: usize = lines
+                .iter()
+                .map(|line| line.rsplit(' ').next().unwrap().parse::<usize>().unwrap())
+                .sum();
+            assert_eq!(total, 1_000_000 + 200 * 10);
+            assert!(lines.contains(&"a:1 (af) 1000000".to_string()));
+            assert!(lines.contains(&"a:2 (af);[other callstacks] 1820".to_string()));
+
+            let directory = tempfile::tempdir().unwrap();
+            flamegraph.write_flamegraphs(directory.path(), "peak", "", "", "bytes", false);
+            let gzipped = std::fs::read(directory.path().join("peak-full.prof.gz")).unwrap();
+            let full = miniz_oxide::inflate::decompress_to_vec(&gzipped[10..gzipped.len() - 8])
+                .unwrap();
+            let full = String::from_utf8(full).unwrap();
+            assert_eq!(full.lines().filter(|line| !line.starts_with('#')).count(), 201);
+            assert!(full.contains("a:2 (af);b:7 (bf) 10\n"));
+        }
+    }
 }
@@ -0,0 +1,187 @@
+//! Information about how a report was made, written as `metadata.json` next
+//! to the flamegraphs.
+
+use crate::adaptive::SamplingTransition;
+use crate::allocator_stats::AllocatorMetadata;
+use crate::budget::TrackerBudgetMetadata;
+use crate::bundled_allocators::BundledAllocator;
+use crate::drift::DriftReport;
+use crate::environment::Environment;
+use crate::milestones::MilestoneReport;
+use crate::peak_triggers::PeakTriggerReport;
+use crate::phases::PhaseSummary;
+use crate::regions::RegionMetadata;
+use crate::rotation::RotationReport;
+use crate::size_ceiling::RejectedAllocationsReport;
+use crate::temp_files::TempFilesReport;
+use crate::util::write_atomically;
+use serde::Serialize;
+use std::path::Path;
+
+#[derive(Clone, Debug, Serialize)]
+pub struct AdaptiveSamplingMetadata {
+    /// Whether small allocations were sampled at any point, in which case some
+    /// of the profile is an estimate.
+    pub engaged: bool,
+    /// When sampling was switched on and off.
+    pub transitions: Vec<SamplingTransition>,
+}
+
+/// Whether adjacent anonymous mmap()s from the same callstack are reported as
+/// one mapping, via FIL_COALESCE_MMAPS=1. glibc and numpy sometimes build one
+/// logical buffer out of several adjacent mmap()s. Checked when the report is
+/// written, since the tracked mappings are the same either way.
+pub fn coalesce_mmaps() -> bool {
+    std::env::var("FIL_COALESCE_MMAPS").as_deref() == Ok("1")
+}
+
+/// The anonymous mmap()s that were live when the report was written.
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct AnonMmapsMetadata {
+    /// How many mappings there are, after coalescing if it was enabled.
+    pub mappings: usize,
+    pub bytes: usize,
+    /// How many mmap()s were merged into an adjacent one from the same
+    /// callstack; always 0 unless FIL_COALESCE_MMAPS=1.
+    pub coalesced: usize,
+}
+
+impl AnonMmapsMetadata {
+    /// The line printed in the text output.
+    pub fn summary(&self) -> String {
+        format!(
+            "Coalesced {} adjacent anonymous mmap()s from the same callstack, leaving {} mappings.",
+            self.coalesced, self.mappings
+        )
+    }
+}
+
+/// How much memory a cache says it's holding on to, from a probe registered
+/// with fil_register_retention_probe(). This is an estimate by the cache's
+/// own code, and is usually also tracked like any other memory, so it's never
+/// added to the peak.
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct RetainedByCache {
+    pub name: String,
+    /// None if the probe failed or didn't return in time.
+    pub estimated_bytes: Option<u64>,
+    /// Why there's no estimate.
+    pub error: Option<String>,
+}
+
+impl RetainedByCache {
+    /// The line printed in the text output.
+    pub fn summary(&self) -> String {
+        match (self.estimated_bytes, &self.error) {
+            (Some(bytes), _) => format!(
+                "[retained by cache: {}] ~{:.1} MiB (an estimate, not included in the peak)",
+                self.name,
+                bytes as f64 / (1024.0 * 1024.0)
+            ),
+            (None, error) => format!(
+                "[retained by cache: {}] unknown: {}",
+                self.name,
+                error.as_deref().unwrap_or("no estimate")
+            ),
+        }
+    }
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub struct ReportMetadata {
+    pub adaptive_sampling: AdaptiveSamplingMetadata,
+    /// What the allocator itself says, at the time the report was written.
+    pub allocator: AllocatorMetadata,
+    /// Bytes per named phase, empty if no phases were marked.
+    pub phases: Vec<PhaseSummary>,
+    /// The phase frames of the allocation that reached the peak, outermost
+    /// first.
+    pub peak_phase_frames: Vec<String>,
+    /// The allocations that raised the peak, most recent last, see
+    /// crate::peak_triggers. Filled in by the caller, since rendering
+    /// callstacks can't be done with the tracker locked.
+    pub peak_triggers: Vec<PeakTriggerReport>,
+    /// jemalloc or mimalloc found in loaded libraries, see
+    /// crate::bundled_allocators. Filled in by the caller, since detecting
+    /// them can't be done with the tracker locked.
+    pub bundled_allocators: Vec<BundledAllocator>,
+    /// Whether flamegraph frames have line numbers, "file:line (function)",
+    /// or were aggregated per function, "file (function)", because
+    /// FIL_AGGREGATE_LINES=1 was set.
+    pub line_numbers: bool,
+    /// Set if FIL_TRACKER_BUDGET_MB was, see crate::budget.
+    pub tracker_budget: Option<TrackerBudgetMetadata>,
+    /// Set if this is the report for a region, see crate::regions. Filled in
+    /// by the caller.
+    pub region: Option<RegionMetadata>,
+    /// What was profiled and where, see crate::environment.
+    pub environment: Environment,
+    pub anon_mmaps: AnonMmapsMetadata,
+    /// What registered retention probes estimated, in the order they were
+    /// registered. Filled in by the caller, since the probes can't be called
+    /// with the tracker locked.
+    pub retained_by_caches: Vec<RetainedByCache>,
+    /// Set if FIL_TRACK_TEMP_FILES=1 saw any temporary files, see
+    /// crate::temp_files. Filled in by the caller, since rendering callstacks
+    /// can't be done with the tracker locked.
+    pub temp_files: Option<TempFilesReport>,
+    /// Set if FIL_DRIFT_CHECK=1, see crate::drift.
+    pub drift: Option<DriftReport>,
+    /// The FIL_MILESTONES sizes reached so far, see crate::milestones.
+    pub milestones: Vec<MilestoneReport>,
+    /// Set if FIL_ROTATE_WINDOW is, see crate::rotation.
+    pub rotation: Option<RotationReport>,
+    /// Set if any allocations were too big to be real, see
+    /// crate::size_ceiling.
+    pub rejected_allocations: Option<RejectedAllocationsReport>,
+}
+
+impl ReportMetadata {
+    /// Write to `metadata.json` in the given directory.
+    pub fn write(&self, directory_path: &Path) {
+        let path = directory_path.join("metadata.json");
+        let result = serde_json::to_vec_pretty(self)
+            .map_err(std::io::Error::from)
+            .and_then(|data| write_atomically(&path, data));
+        if let Err(e) = result {
+            eprintln!("=fil-profile= Error writing {:?}: {}", path, e);
+        }
+        eprintln!("=fil-profile= {}", self.allocator.summary());
+        if let Some(budget) = self
+            .tracker_budget
+            .as_ref()
+            .filter(|budget| budget.exceeded)
+        {
+            eprintln!("=fil-profile= {}", budget.summary());
+        }
+        if self.anon_mmaps.coalesced > 0 {
+            eprintln!("=fil-profile= {}", self.anon_mmaps.summary());
+        }
+        if !self.peak_phase_frames.is_empty() {
+            eprintln!(
+                "=fil-profile= Peak memory was reached in phase {}.",
+                self.peak_phase_frames.join(" > ")
+            );
+        }
+        if let Some(trigger) = self.peak_triggers.last() {
+            eprintln!("=fil-profile= {}", trigger.summary());
+        }
+        for retained in &self.retained_by_caches {
+            eprintln!("=fil-profile= {}", retained.summary());
+        }
+        if let Some(temp_files) = &self.temp_files {
+            eprintln!("=fil-profile= {}", temp_files.summary());
+        }
+        if let Some(rejected) = &self.rejected_allocations {
+            eprintln!("=fil-profile= WARNING: {}", rejected.summary());
+        }
+        if let Some(drift) = &self.drift {
+            if let Some(warning) = &drift.warning {
+                eprintln!(
+                    "=fil-profile= WARNING: {}",
+                    warning.summary(&drift.settings)
+                );
+            }
+        }
+    }
+}
@@ -0,0 +1,296 @@
+//! Reports written the first time tracked memory goes over each of a list of
+//! sizes, e.g. `FIL_MILESTONES=8GB,16GB,24GB`, so you can see how what's using
+//! memory changes as it grows. Sizes are powers of 1024, with an optional
+//! B/KB/MB/GB/TB/PB suffix (KiB etc. also work), and can be fractional, e.g.
+//! `1.5GB`.
+//!
+//! Checking happens on every allocation, so it's just a comparison with the
+//! next milestone. When one is crossed, the current per-callstack usage is
+//! snapshotted, which is cheap since it's an im::Vector, and the sampler
+//! thread renders it to `milestone-<size>.svg` in the report directory later,
+//! outside the tracker lock. An allocation that crosses several milestones at
+//! once gets one dump per milestone, all of the same snapshot.
+//!
+//! Each milestone is dumped once per reset. Milestones are about the running
+//! total for the whole profile, so profiling a region doesn't affect them;
+//! reset() starts them over, along with the peak.
+
+use im::Vector as ImVector;
+use serde::Serialize;
+use std::path::Path;
+use std::time::{Duration, Instant};
+
+/// How often the sampler thread checks for snapshots to write.
+pub const CHECK_INTERVAL: Duration = Duration::from_millis(100);
+
+/// A size to dump a report at.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Milestone {
+    /// As written in FIL_MILESTONES, e.g. "8GB".
+    pub label: String,
+    pub bytes: usize,
+}
+
+impl Milestone {
+    /// The file name the report is written to, without extension.
+    pub fn base_filename(&self) -> String {
+        format!("milestone-{}", self.label)
+    }
+}
+
+/// Parse a size like "8GB", "512 MiB" or "100", in powers of 1024, or return
+/// an error saying why it didn't make sense.
+pub fn parse_size(size: &str) -> Result<usize, String> {
+    let label: String = size.split_whitespace().collect();
+    let digits = label
+        .find(|c: char| !(c.is_ascii_digit() || c == '.'))
+        .unwrap_or(label.len());
+    let (number, unit) = label.split_at(digits);
+    let multiplier: u64 = match unit.to_ascii_uppercase().as_str() {
+        "" | "B" => 1,
+        "K" | "KB" | "KIB" => 1 << 10,
+        "M" | "MB" | "MIB" => 1 << 20,
+        "G" | "GB" | "GIB" => 1 << 30,
+        "T" | "TB" | "TIB" => 1 << 40,
+        "P" | "PB" | "PIB" => 1 << 50,
+        _ => return Err(format!("unknown unit in {:?}", size)),
+    };
+    let number: f64 = number
+        .parse()
+        .map_err(|_| format!("{:?} isn't a size", size))?;
+    let bytes = (number * multiplier as f64) as usize;
+    if bytes == 0 {
+        return Err(format!("{:?} isn't a size", size));
+    }
+    Ok(bytes)
+}
+
+/// Parse a FIL_MILESTONES value, returning the milestones smallest first, or
+/// an error saying which size didn't make sense.
+pub fn parse(value: &str) -> Result<Vec<Milestone>, String> {
+    let mut milestones = vec![];
+    for size in value.split(',').map(str::trim).filter(|s| !s.is_empty()) {
+        let bytes = parse_size(size)?;
+        let label: String = size.split_whitespace().collect();
+        milestones.push(Milestone { label, bytes });
+    }
+    milestones.sort_by_key(|milestone| milestone.bytes);
+    milestones.dedup_by_key(|milestone| milestone.bytes);
+    Ok(milestones)
+}
+
+/// A snapshot taken when one or more milestones were crossed, waiting to be
+/// written out.
+pub struct PendingMilestoneDump {
+    /// The milestones crossed by the same allocation.
+    pub milestones: Vec<Milestone>,
+    pub allocated_bytes: usize,
+    /// Map CallstackId -> memory usage, at the time.
+    pub(crate) memory_usage: ImVector<usize>,
+}
+
+/// A milestone that was reached, as written to `metadata.json`.
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct MilestoneReport {
+    pub label: String,
+    pub bytes: usize,
+    /// Tracked memory right after the allocation that crossed it.
+    pub allocated_bytes: usize,
+    /// Since tracking started.
+    pub seconds: f64,
+    /// The flamegraph, which is in the directory tracking started with, not
+    /// necessarily the one the final report is in.
+    pub path: String,
+}
+
+/// Which milestones have been reached, see the module documentation.
+pub struct Milestones {
+    // Smallest first:
+    milestones: Vec<Milestone>,
+    // Index of the next milestone to be reached:
+    next: usize,
+    // Its size, or usize::MAX if they've all been reached:
+    next_bytes: usize,
+    start: Instant,
+    // (index, allocated bytes, seconds) for each milestone reached:
+    reached: Vec<(usize, usize, f64)>,
+    pending: Vec<PendingMilestoneDump>,
+}
+
+impl Milestones {
+    pub fn new(milestones: Vec<Milestone>) -> Self {
+        let mut result = Self {
+            milestones,
+            next: 0,
+            next_bytes: usize::MAX,
+            start: Instant::now(),
+            reached: vec![],
+            pending: vec![],
+        };
+        result.reset();
+        result
+    }
+
+    /// Configure from FIL_MILESTONES, if it's set; a value that can't be
+    /// parsed is warned about and ignored.
+    pub fn from_env() -> Option<Self> {
+        let value = std::env::var("FIL_MILESTONES").ok()?;
+        match parse(&value) {
+            Ok(milestones) if !milestones.is_empty() => Some(Self::new(milestones)),
+            Ok(_) => None,
+            Err(e) => {
+                eprintln!("=fil-profile= Ignoring FIL_MILESTONES: {}", e);
+                None
+            }
+        }
+    }
+
+    /// Called whenever tracked memory goes up, with the new total and the
+    /// per-callstack usage.
+    #[inline]
+    pub fn check(&mut self, allocated_bytes: usize, memory_usage: &ImVector<usize>) {
+        if allocated_bytes >= self.next_bytes {
+            self.crossed(allocated_bytes, memory_usage);
+        }
+    }
+
+    #[cold]
+    fn crossed(&mut self, allocated_bytes: usize, memory_usage: &ImVector<usize>) {
+        let seconds = self.start.elapsed().as_secs_f64();
+        let first = self.next;
+        while self.next < self.milestones.len()
+            && self.milestones[self.next].bytes <= allocated_bytes
+        {
+            self.reached.push((self.next, allocated_bytes, seconds));
+            self.next += 1;
+        }
+        self.next_bytes = self
+            .milestones
+            .get(self.next)
+            .map_or(usize::MAX, |milestone| milestone.bytes);
+        self.pending.push(PendingMilestoneDump {
+            milestones: self.milestones[first..self.next].to_vec(),
+            allocated_bytes,
+            memory_usage: memory_usage.clone(),
+        });
+    }
+
+    pub fn has_pending(&self) -> bool {
+        !self.pending.is_empty()
+    }
+
+    /// The snapshots that haven't been written yet, oldest first.
+    pub fn take_pending(&mut self) -> Vec<PendingMilestoneDump> {
+        std::mem::take(&mut self.pending)
+    }
+
+    /// The milestones reached so far, for dumps written to the given
+    /// directory.
+    pub fn report(&self, directory: &Path) -> Vec<MilestoneReport> {
+        self.reached
+            .iter()
+            .map(|(index, allocated_bytes, seconds)| {
+                let milestone = &self.milestones[*index];
+                MilestoneReport {
+                    label: milestone.label.clone(),
+                    bytes: milestone.bytes,
+                    allocated_bytes: *allocated_bytes,
+                    seconds: *seconds,
+                    path: directory
+                        .join(format!("{}.svg", milestone.base_filename()))
+                        .to_string_lossy()
+                        .into_owned(),
+                }
+            })
+            .collect()
+    }
+
+    /// Start over, so every milestone gets dumped again. Snapshots that
+    /// weren't written yet are dropped, since they belong to the old report.
+    pub fn reset(&mut self) {
+        self.next = 0;
+        self.next_bytes = self
+            .milestones
+            .first()
+            .map_or(usize::MAX, |milestone| milestone.bytes);
+        self.start = Instant::now();
+        self.reached.clear();
+        self.pending.clear();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{parse, parse_size, Milestone, Milestones};
+    use im::Vector as ImVector;
+    use std::path::Path;
+
+    fn milestone(label: &str, bytes: usize) -> Milestone {
+        Milestone {
+            label: label.to_string(),
+            bytes,
+        }
+    }
+
+    #[test]
+    fn parsing() {
+        assert_eq!(
+            parse("16GB, 8 GB,512mib,1.5K,100").unwrap(),
+            vec![
+                milestone("100", 100),
+                milestone("1.5K", 1536),
+                milestone("512mib", 512 << 20),
+                milestone("8GB", 8 << 30),
+                milestone("16GB", 16 << 30),
+            ]
+        );
+        assert_eq!(parse("").unwrap(), vec![]);
+        assert!(parse("8XB").is_err());
+        assert!(parse("GB").is_err());
+        assert!(parse("0MB").is_err());
+        assert_eq!(parse_size("2 PB"), Ok(2 << 50));
+    }
+
+    #[test]
+    fn one_dump_per_milestone_per_reset() {
+        let mut milestones = Milestones::new(parse("10,20,30,100").unwrap());
+        let usage: ImVector<usize> = ImVector::from(vec![5, 7]);
+        milestones.check(9, &usage);
+        assert!(!milestones.has_pending());
+        // One allocation crossing two milestones:
+        milestones.check(25, &usage);
+        // Going down and back up again doesn't repeat them:
+        milestones.check(15, &usage);
+        milestones.check(25, &usage);
+        milestones.check(31, &usage);
+        let pending = milestones.take_pending();
+        assert_eq!(
+            pending
+                .iter()
+                .map(|dump| (dump.milestones.clone(), dump.allocated_bytes))
+                .collect::<Vec<_>>(),
+            vec![
+                (vec![milestone("10", 10), milestone("20", 20)], 25),
+                (vec![milestone("30", 30)], 31),
+            ]
+        );
+        assert_eq!(pending[0].memory_usage, usage);
+        assert!(!milestones.has_pending());
+        let report = milestones.report(Path::new("/reports"));
+        assert_eq!(
+            report
+                .iter()
+                .map(|reached| (reached.label.as_str(), reached.allocated_bytes))
+                .collect::<Vec<_>>(),
+            vec![("10", 25), ("20", 25), ("30", 31)]
+        );
+        assert_eq!(report[2].path, "/reports/milestone-30.svg");
+
+        milestones.check(40, &usage);
+        milestones.reset();
+        assert!(!milestones.has_pending());
+        assert!(milestones.report(Path::new("/reports")).is_empty());
+        milestones.check(1000, &usage);
+        assert_eq!(milestones.take_pending()[0].milestones.len(), 4);
+    }
+}
@@ -0,0 +1,271 @@
+//! Live Python object counts per callstack.
+//!
+//! Bytes are one dimension; how many objects of which type are still alive,
+//! and which callstack created them, is another that helps find reference
+//! leaks. Objects are reported from Python (see `filprofiler.api`), and the
+//! counts are kept entirely separately from byte tracking, including using a
+//! separate callstack interner, so that enabling this can't change the memory
+//! numbers.
+
+use crate::flamegraph::FlamegraphCallstacks;
+use crate::linecache::LineCacher;
+use crate::memorytracking::{
+    Callstack, CallstackId, CallstackInterner, IdentityCleaner, ReadFunctionLocations,
+};
+use crate::util::{new_hashmap, write_atomically};
+use ahash::RandomState as ARandomState;
+use serde::Serialize;
+use std::borrow::Cow;
+use std::collections::HashMap;
+use std::path::Path;
+
+/// How many (callstack, type) entries to include in objects.json.
+const MAX_REPORTED_ENTRIES: usize = 100;
+
+/// Interned type name.
+type TypeNameId = u32;
+
+/// Tracks live objects by the callstack that created them and their type.
+pub struct ObjectTracker {
+    interner: CallstackInterner,
+    type_names: Vec<String>,
+    type_name_ids: HashMap<String, TypeNameId, ARandomState>,
+    // Object address -> where it came from:
+    live: HashMap<usize, (CallstackId, TypeNameId), ARandomState>,
+    counts: HashMap<(CallstackId, TypeNameId), usize, ARandomState>,
+}
+
+impl Default for ObjectTracker {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl ObjectTracker {
+    pub fn new() -> Self {
+        Self {
+            interner: CallstackInterner::new(),
+            type_names: vec![],
+            type_name_ids: new_hashmap(),
+            live: new_hashmap(),
+            counts: new_hashmap(),
+        }
+    }
+
+    fn type_name_id(&mut self, type_name: &str) -> TypeNameId {
+        if let Some(id) = self.type_name_ids.get(type_name) {
+            return *id;
+        }
+        let id = self.type_names.len() as TypeNameId;
+        self.type_names.push(type_name.to_string());
+        self.type_name_ids.insert(type_name.to_string(), id);
+        id
+    }
+
+    /// Record a new live object created by the given callstack. If an object
+    /// at that address was already recorded, it's replaced.
+    pub fn add(&mut self, callstack: &Callstack, address: usize, type_name: &str) {
+        self.remove(address);
+        let type_name = self.type_name_id(type_name);
+        let callstack_id = self
+            .interner
+            .get_or_insert_id(Cow::Borrowed(callstack), || ());
+        self.live.insert(address, (callstack_id, type_name));
+        *self.counts.entry((callstack_id, type_name)).or_insert(0) += 1;
+    }
+
+    /// Record an object going away. Returns whether it was known.
+    pub fn remove(&mut self, address: usize) -> bool {
+        let Some(key) = self.live.remove(&address) else {
+            return false;
+        };
+        if let Some(count) = self.counts.get_mut(&key) {
+            *count -= 1;
+            if *count == 0 {
+                self.counts.remove(&key);
+            }
+        }
+        true
+    }
+
+    /// How many objects are currently alive.
+    pub fn live_objects(&self) -> usize {
+        self.live.len()
+    }
+
+    /// Gather the data for the report; resolving callstacks into strings is
+    /// done later by the returned closure, so it can happen without locks
+    /// held.
+    pub fn report<FL: ReadFunctionLocations>(&self) -> impl FnOnce(FL) -> ObjectsReport<FL> {
+        let id_to_callstack = self.interner.get_reverse_map();
+        let mut by_callstack: HashMap<Callstack, usize, ARandomState> = new_hashmap();
+        let mut by_type: HashMap<&str, usize> = HashMap::new();
+        for ((callstack_id, type_name), count) in self.counts.iter() {
+            if let Some(callstack) = id_to_callstack.get(callstack_id) {
+                *by_callstack.entry((*callstack).clone()).or_insert(0) += count;
+            }
+            *by_type
+                .entry(&self.type_names[*type_name as usize])
+                .or_insert(0) += count;
+        }
+        let mut types: Vec<TypeCount> = by_type
+            .into_iter()
+            .map(|(type_name, count)| TypeCount {
+                type_name: type_name.to_string(),
+                count,
+            })
+            .collect();
+        types.sort_by(|a, b| b.count.cmp(&a.count).then(a.type_name.cmp(&b.type_name)));
+        let mut entries: Vec<_> = self.counts.iter().collect();
+        entries.sort_by_key(|(_, count)| std::cmp::Reverse(**count));
+        let entries: Vec<(Callstack, String, usize)> = entries
+            .into_iter()
+            .filter_map(|((callstack_id, type_name), count)| {
+                Some((
+                    (*id_to_callstack.get(callstack_id)?).clone(),
+                    self.type_names[*type_name as usize].clone(),
+                    *count,
+                ))
+            })
+            .take(MAX_REPORTED_ENTRIES)
+            .collect();
+        let total_objects = self.live_objects();
+        move |functions| {
+            let mut linecache = LineCacher::default();
+            let callstacks = entries
+                .into_iter()
+                .map(|(callstack, type_name, count)| CallstackTypeCount {
+                    callstack: callstack.as_string(false, &functions, ";", &mut linecache),
+                    type_name,
+                    count,
+                })
+                .collect();
+            ObjectsReport {
+                flamegraph: FlamegraphCallstacks::new(by_callstack, functions, IdentityCleaner),
+                summary: ObjectsSummary {
+                    total_objects,
+                    types,
+                    callstacks,
+                },
+            }
+        }
+    }
+}
+
+#[derive(Debug, Serialize, PartialEq)]
+pub struct TypeCount {
+    #[serde(rename = "type")]
+    pub type_name: String,
+    pub count: usize,
+}
+
+#[derive(Debug, Serialize, PartialEq)]
+pub struct CallstackTypeCount {
+    pub callstack: String,
+    #[serde(rename = "type")]
+    pub type_name: String,
+    pub count: usize,
+}
+
+#[derive(Debug, Serialize)]
+pub struct ObjectsSummary {
+    pub total_objects: usize,
+    /// Live objects per type, most common first.
+    pub types: Vec<TypeCount>,
+    /// The top (callstack, type) combinations, most common first.
+    pub callstacks: Vec<CallstackTypeCount>,
+}
+
+/// Everything needed to write out the objects report.
+pub struct ObjectsReport<FL: ReadFunctionLocations> {
+    pub flamegraph:
+        FlamegraphCallstacks<HashMap<Callstack, usize, ARandomState>, FL, IdentityCleaner>,
+    pub summary: ObjectsSummary,
+}
+
+impl<FL: ReadFunctionLocations> ObjectsReport<FL> {
+    /// Write objects.svg and friends, weighted by object count, plus
+    /// objects.json.
+    pub fn write(&self, directory_path: &Path, to_be_post_processed: bool) {
+        let title = format!(
+            "Live Python Objects, by Callstack ({} objects)",
+            self.summary.total_objects
+        );
+        self.flamegraph.write_flamegraphs(
+            directory_path,
+            "objects",
+            &title,
+            "Made with the Fil profiler.",
+            "objects",
+            to_be_post_processed,
+        );
+        let json_path = directory_path.join("objects.json");
+        let result = serde_json::to_vec_pretty(&self.summary)
+            .map_err(std::io::Error::from)
+            .and_then(|data| write_atomically(&json_path, data));
+        match result {
+            Ok(_) => eprintln!("=fil-profile= Wrote object counts to {:?}", json_path),
+            Err(e) => eprintln!("=fil-profile= Error writing object counts: {}", e),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{ObjectTracker, TypeCount};
+    use crate::memorytracking::{
+        CallSiteId, Callstack, LineNumberInfo::LineNumber, VecFunctionLocations,
+    };
+
+    #[test]
+    fn objects_report() {
+        pyo3::prepare_freethreaded_python();
+        let mut functions = VecFunctionLocations::new();
+        let fid = functions.add_function("a".to_string(), "af".to_string());
+        let callstacks: Vec<Callstack> = (1..=2)
+            .map(|line| {
+                let mut cs = Callstack::new();
+                cs.start_call(0, CallSiteId::new(fid, LineNumber(line)));
+                cs
+            })
+            .collect();
+
+        let mut tracker = ObjectTracker::new();
+        tracker.add(&callstacks[0], 1, "Foo");
+        tracker.add(&callstacks[0], 2, "Foo");
+        tracker.add(&callstacks[0], 3, "Bar");
+        tracker.add(&callstacks[1], 4, "Foo");
+        tracker.add(&callstacks[1], 5, "Foo");
+        // Same address again replaces the previous object:
+        tracker.add(&callstacks[1], 5, "Bar");
+        assert!(tracker.remove(2));
+        assert!(!tracker.remove(2));
+        assert!(!tracker.remove(100));
+        assert_eq!(tracker.live_objects(), 4);
+
+        let report = tracker.report()(functions);
+        let mut lines: Vec<String> = report.flamegraph.to_lines(false).collect();
+        lines.sort();
+        assert_eq!(lines, vec!["a:1 (af) 2", "a:2 (af) 2"]);
+        assert_eq!(report.summary.total_objects, 4);
+        assert_eq!(
+            report.summary.types,
+            vec![
+                TypeCount {
+                    type_name: "Bar".to_string(),
+                    count: 2
+                },
+                TypeCount {
+                    type_name: "Foo".to_string(),
+                    count: 2
+                },
+            ]
+        );
+        assert_eq!(report.summary.callstacks.len(), 4);
+        assert!(report
+            .summary
+            .callstacks
+            .iter()
+            .all(|entry| entry.count == 1));
+    }
+}
@@ -0,0 +1,65 @@
+//! Which formats the peak report is written in, from FIL_OUTPUT_FORMATS, a
+//! comma-separated list, e.g. `FIL_OUTPUT_FORMATS=svg,arrow`. The flamegraph
+//! SVGs are always written, since the HTML report shows them, so `svg` is
+//! accepted but changes nothing. `json` and `json.zst` are the same as
+//! FIL_JSON_REPORT=1 and FIL_JSON_REPORT=zstd (see crate::report_schema), and
+//! `arrow` writes `peak.arrow` (see crate::arrow). Checked when the report is
+//! written.
+
+/// A format the peak report can be written in.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum OutputFormat {
+    Svg,
+    Json,
+    ZstdJson,
+    Arrow,
+}
+
+/// Parse a FIL_OUTPUT_FORMATS value; unknown formats are warned about and
+/// ignored.
+pub fn parse(value: &str) -> Vec<OutputFormat> {
+    value
+        .split(',')
+        .map(str::trim)
+        .filter(|format| !format.is_empty())
+        .filter_map(|format| match format {
+            "svg" => Some(OutputFormat::Svg),
+            "json" => Some(OutputFormat::Json),
+            "json.zst" => Some(OutputFormat::ZstdJson),
+            "arrow" => Some(OutputFormat::Arrow),
+            _ => {
+                eprintln!(
+                    "=fil-profile= Unknown format {:?} in FIL_OUTPUT_FORMATS, expected svg, json, json.zst or arrow",
+                    format
+                );
+                None
+            }
+        })
+        .collect()
+}
+
+/// Whether FIL_OUTPUT_FORMATS asks for the given format.
+pub fn requested(format: OutputFormat) -> bool {
+    std::env::var("FIL_OUTPUT_FORMATS")
+        .map(|value| parse(&value).contains(&format))
+        .unwrap_or(false)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{parse, OutputFormat};
+
+    #[test]
+    fn parsing() {
+        assert_eq!(
+            parse("svg, arrow,json.zst"),
+            [
+                OutputFormat::Svg,
+                OutputFormat::Arrow,
+                OutputFormat::ZstdJson
+            ]
+        );
+        assert_eq!(parse("json,,parquet"), [OutputFormat::Json]);
+        assert_eq!(parse(""), []);
+    }
+}
@@ -0,0 +1,124 @@
+//! Everything that goes into a report of the peak, or of current allocations:
+//! what's taken from the tracker while it's locked, by
+//! AllocationTracker::peak_report(), and the code that writes it out once the
+//! lock is released. Both the LD_PRELOAD version and crate::pymodule write
+//! their reports with it, so they have the same files.
+//!
+//! Most of the work happens in write(), without the lock: rendering the
+//! flamegraphs loads source code via Python's linecache, which might release
+//! the GIL and let another thread allocate, and that thread would deadlock on
+//! the tracker lock.
+
+use crate::allocation_rates::AllocationRatesReport;
+use crate::bundled_allocators;
+use crate::flamegraph::FlamegraphCallstacks;
+use crate::frees::FreesReport;
+use crate::lifetimes::LifetimeReport;
+use crate::mapped_files::MappedFilesReport;
+use crate::memorytracking::{Callstack, IdentityCleaner, ReadFunctionLocations};
+use crate::metadata::ReportMetadata;
+use crate::objects::ObjectsReport;
+use crate::output_formats::{self, OutputFormat};
+use crate::peak_triggers::PeakTriggerReport;
+use crate::reallocs::ReallocReport;
+use crate::report_schema;
+use crate::temp_files::TempFilesReport;
+use crate::threads;
+use crate::timeline::TimelineReport;
+use ahash::RandomState as ARandomState;
+use std::collections::HashMap;
+use std::path::Path;
+
+type Factory<T> = Box<dyn FnOnce() -> T>;
+
+/// A report, gathered under the tracker lock, ready to be written.
+pub struct PeakReport<FL: ReadFunctionLocations> {
+    /// Whether this is the peak rather than current allocations. Reports of
+    /// current allocations, e.g. when running out of memory, are kept minimal
+    /// and only have the flamegraphs.
+    pub(crate) peak: bool,
+    pub(crate) allocated_bytes: usize,
+    pub(crate) callstacks:
+        Factory<FlamegraphCallstacks<HashMap<Callstack, usize, ARandomState>, FL, IdentityCleaner>>,
+    /// Callers fill in what the tracker doesn't know about before writing,
+    /// e.g. which bundled allocators were tracked.
+    pub metadata: ReportMetadata,
+    pub(crate) peak_triggers: Factory<Vec<PeakTriggerReport>>,
+    pub(crate) lifetimes: Option<Factory<LifetimeReport>>,
+    pub(crate) reallocs: Factory<ReallocReport>,
+    pub(crate) frees: Option<Factory<FreesReport<FL>>>,
+    pub(crate) objects: Option<Factory<ObjectsReport<FL>>>,
+    pub(crate) timeline: Option<Factory<TimelineReport>>,
+    pub(crate) mapped_files: Option<Factory<MappedFilesReport<FL>>>,
+    pub(crate) temp_files: Option<Factory<TempFilesReport>>,
+    pub(crate) allocation_rates: Option<Factory<AllocationRatesReport<FL>>>,
+}
+
+impl<FL: ReadFunctionLocations> PeakReport<FL> {
+    /// Write the report to the given directory, with the flamegraphs named
+    /// after base_filename, e.g. `peak-memory`.
+    pub fn write(
+        self,
+        directory_path: &Path,
+        base_filename: &str,
+        title: &str,
+        to_be_post_processed: bool,
+    ) {
+        let flamegraph_callstacks = (self.callstacks)();
+        flamegraph_callstacks.write_memory_flamegraphs(
+            directory_path,
+            base_filename,
+            title,
+            self.allocated_bytes,
+            to_be_post_processed,
+        );
+        if !self.peak {
+            return;
+        }
+        let table_path = directory_path.join("peak-functions.tsv");
+        if let Err(e) = flamegraph_callstacks.write_function_table(&table_path) {
+            eprintln!("=fil-profile= Error writing {:?}: {}", table_path, e);
+        }
+        if let Some(json_report) = report_schema::json_report() {
+            match flamegraph_callstacks
+                .to_json_report(self.allocated_bytes)
+                .write(directory_path, json_report)
+            {
+                Ok(json_path) => eprintln!("=fil-profile= Wrote JSON report to {:?}", json_path),
+                Err(e) => eprintln!("=fil-profile= Error writing JSON report: {}", e),
+            }
+        }
+        if output_formats::requested(OutputFormat::Arrow) {
+            let arrow_path = directory_path.join("peak.arrow");
+            match flamegraph_callstacks.write_arrow(&arrow_path) {
+                Ok(()) => eprintln!("=fil-profile= Wrote Arrow table to {:?}", arrow_path),
+                Err(e) => eprintln!("=fil-profile= Error writing Arrow table: {}", e),
+            }
+        }
+        let mut metadata = self.metadata;
+        metadata.peak_triggers = (self.peak_triggers)();
+        bundled_allocators::warn_once(&metadata.bundled_allocators);
+        metadata.temp_files = self.temp_files.map(|factory| factory());
+        metadata.write(directory_path);
+        if let Some(lifetimes) = self.lifetimes {
+            lifetimes().write(directory_path);
+        }
+        (self.reallocs)().write(directory_path);
+        threads::report().write(directory_path);
+        if let Some(frees) = self.frees {
+            frees().write(directory_path, to_be_post_processed);
+        }
+        if let Some(objects) = self.objects {
+            objects().write(directory_path, to_be_post_processed);
+        }
+        if let Some(timeline) = self.timeline {
+            timeline().write(directory_path);
+        }
+        if let Some(mapped_files) = self.mapped_files {
+            mapped_files().write(directory_path, to_be_post_processed);
+        }
+        if let Some(allocation_rates) = self.allocation_rates {
+            allocation_rates().write(directory_path, to_be_post_processed);
+        }
+    }
+}
@@ -0,0 +1,149 @@
+//! What the program was doing when the peak was reached: the allocation that
+//! set the final high-water mark, as opposed to everything that was live at
+//! that point.
+//!
+//! New peaks are only noticed when memory is about to go down, so the
+//! allocation recorded is the last one before that happened. By default only
+//! the final peak is kept; FIL_PEAK_TRIGGERS=N keeps the last N.
+
+use crate::linecache::LineCacher;
+use crate::memorytracking::{Callstack, CallstackId, ReadFunctionLocations};
+use ahash::RandomState as ARandomState;
+use serde::Serialize;
+use std::collections::{HashMap, VecDeque};
+
+/// The allocation that raised the peak.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct PeakTrigger {
+    pub callstack_id: CallstackId,
+    /// Size of the allocation.
+    pub bytes: usize,
+    pub thread_id: u64,
+}
+
+/// A PeakTrigger made human-readable, as written to `metadata.json`.
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct PeakTriggerReport {
+    /// The callstack, outermost frame first, separated by ";".
+    pub callstack: String,
+    /// Just the innermost frame.
+    pub innermost_frame: String,
+    pub bytes: usize,
+    pub thread_id: u64,
+    /// The new peak.
+    pub peak_bytes: usize,
+}
+
+impl PeakTriggerReport {
+    /// The line printed in the text output.
+    pub fn summary(&self) -> String {
+        format!(
+            "Peak was reached while executing: {} (allocating {:.1} MiB on thread {})",
+            self.innermost_frame,
+            self.bytes as f64 / (1024.0 * 1024.0),
+            self.thread_id,
+        )
+    }
+}
+
+/// The most recent allocations that raised the peak.
+pub struct PeakTriggers {
+    keep: usize,
+    // Oldest first, with the peak each one reached:
+    recent: VecDeque<(PeakTrigger, usize)>,
+}
+
+impl PeakTriggers {
+    pub fn new(keep: usize) -> Self {
+        Self {
+            keep: keep.max(1),
+            recent: VecDeque::new(),
+        }
+    }
+
+    /// Configure from FIL_PEAK_TRIGGERS.
+    pub fn from_env() -> Self {
+        Self::new(
+            std::env::var("FIL_PEAK_TRIGGERS")
+                .ok()
+                .and_then(|value| value.parse().ok())
+                .unwrap_or(1),
+        )
+    }
+
+    /// Record that the given allocation raised the peak to peak_bytes.
+    pub fn record(&mut self, trigger: PeakTrigger, peak_bytes: usize) {
+        if self.recent.len() >= self.keep {
+            self.recent.pop_front();
+        }
+        self.recent.push_back((trigger, peak_bytes));
+    }
+
+    /// The allocation that reached the current peak, if any.
+    pub fn latest(&self) -> Option<&PeakTrigger> {
+        self.recent.back().map(|(trigger, _)| trigger)
+    }
+
+    pub fn reset(&mut self) {
+        self.recent.clear();
+    }
+
+    /// Gather the data for the report; converting to text is left to the
+    /// returned closure, since that may need to call into Python.
+    pub fn report<FL: ReadFunctionLocations>(
+        &self,
+        id_to_callstack: &HashMap<CallstackId, &Callstack, ARandomState>,
+    ) -> impl FnOnce(&FL) -> Vec<PeakTriggerReport> {
+        let recent: Vec<(Callstack, PeakTrigger, usize)> = self
+            .recent
+            .iter()
+            .filter_map(|(trigger, peak_bytes)| {
+                id_to_callstack
+                    .get(&trigger.callstack_id)
+                    .map(|callstack| ((*callstack).clone(), *trigger, *peak_bytes))
+            })
+            .collect();
+        move |functions| {
+            let mut linecache = LineCacher::default();
+            recent
+                .into_iter()
+                .map(|(callstack, trigger, peak_bytes)| PeakTriggerReport {
+                    callstack: callstack.as_string(false, functions, ";", &mut linecache),
+                    innermost_frame: callstack.innermost_frame(functions),
+                    bytes: trigger.bytes,
+                    thread_id: trigger.thread_id,
+                    peak_bytes,
+                })
+                .collect()
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{PeakTrigger, PeakTriggers};
+
+    fn trigger(callstack_id: u32) -> PeakTrigger {
+        PeakTrigger {
+            callstack_id,
+            bytes: 100,
+            thread_id: 1,
+        }
+    }
+
+    #[test]
+    fn keeps_the_most_recent() {
+        let mut triggers = PeakTriggers::new(2);
+        assert_eq!(triggers.latest(), None);
+        triggers.record(trigger(1), 100);
+        triggers.record(trigger(2), 200);
+        triggers.record(trigger(3), 300);
+        assert_eq!(triggers.latest(), Some(&trigger(3)));
+        assert_eq!(
+            triggers.recent.iter().copied().collect::<Vec<_>>(),
+            vec![(trigger(2), 200), (trigger(3), 300)]
+        );
+        triggers.reset();
+        assert_eq!(triggers.latest(), None);
+    }
+}
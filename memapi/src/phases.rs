@@ -0,0 +1,223 @@
+//! Named phases of a program's run, e.g. "imports" and "main".
+//!
+//! The current phase is part of each callstack's identity, so the same Python
+//! callstack allocating in two different phases is tracked separately. That
+//! lets reports summarize memory per phase, and optionally either root each
+//! callstack under a `[phase: <name>]` frame (FIL_PHASE_FRAMES=1), or leave
+//! out some phases from the flamegraphs while still counting them in the
+//! totals (e.g. FIL_EXCLUDE_PHASES=imports).
+//!
+//! Separately, each thread can push and pop nested phases, which show up as
+//! `[phase: <name>]` frames at the root of its callstacks. Those are stored
+//! in the Callstack itself, as synthetic functions.
+
+use crate::memorytracking::CallstackId;
+use im::Vector as ImVector;
+use serde::Serialize;
+
+pub type PhaseId = u16;
+
+/// The phase before any phase is marked.
+pub const NO_PHASE: PhaseId = 0;
+
+/// How deeply phase frames (see Callstack::push_phase_frame()) can be nested;
+/// deeper pushes are ignored, so runaway nesting doesn't make every callstack
+/// huge.
+pub const MAX_PHASE_FRAMES: usize = 64;
+
+/// Bytes allocated by a phase's callstacks.
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct PhaseSummary {
+    pub name: String,
+    pub peak_bytes: usize,
+    pub current_bytes: usize,
+}
+
+/// Keeps track of phases, and which phase each callstack is from.
+pub struct Phases {
+    // The name of phase N is names[N - 1]:
+    names: Vec<String>,
+    current: PhaseId,
+    // Indexed by CallstackId:
+    callstack_phases: Vec<PhaseId>,
+    root_frames: bool,
+    excluded: Vec<String>,
+}
+
+impl Phases {
+    pub fn new(root_frames: bool, excluded: Vec<String>) -> Self {
+        Self {
+            names: vec![],
+            current: NO_PHASE,
+            callstack_phases: vec![],
+            root_frames,
+            excluded,
+        }
+    }
+
+    /// Configure from FIL_PHASE_FRAMES and FIL_EXCLUDE_PHASES.
+    pub fn from_env() -> Self {
+        let root_frames = std::env::var("FIL_PHASE_FRAMES").as_deref() == Ok("1");
+        let excluded = std::env::var("FIL_EXCLUDE_PHASES")
+            .map(|phases| {
+                phases
+                    .split(',')
+                    .map(|name| name.trim().to_string())
+                    .filter(|name| !name.is_empty())
+                    .collect()
+            })
+            .unwrap_or_default();
+        Self::new(root_frames, excluded)
+    }
+
+    /// Switch to the given phase. Marking a phase that was already seen
+    /// switches back to it.
+    pub fn mark(&mut self, name: &str) {
+        if let Some(index) = self.names.iter().position(|existing| existing == name) {
+            self.current = index as PhaseId + 1;
+        } else if self.names.len() < PhaseId::MAX as usize {
+            self.names.push(name.to_string());
+            self.current = self.names.len() as PhaseId;
+        } else {
+            eprintln!(
+                "=fil-profile= WARNING: Too many distinct phases, ignoring phase {:?}.",
+                name
+            );
+        }
+    }
+
+    /// The phase new callstacks should be tagged with.
+    pub fn current(&self) -> PhaseId {
+        self.current
+    }
+
+    /// The name of a phase, or None for NO_PHASE.
+    pub fn name(&self, phase: PhaseId) -> Option<&str> {
+        if phase == NO_PHASE {
+            None
+        } else {
+            self.names.get(phase as usize - 1).map(|name| name.as_str())
+        }
+    }
+
+    /// Names of all phases, indexed by phase id - 1.
+    pub fn names(&self) -> &[String] {
+        &self.names
+    }
+
+    /// Whether any phase was ever marked.
+    pub fn in_use(&self) -> bool {
+        !self.names.is_empty()
+    }
+
+    /// Whether callstacks should be rooted under a frame for their phase.
+    pub fn root_frames(&self) -> bool {
+        self.root_frames
+    }
+
+    /// Record the phase of a callstack.
+    pub fn add_callstack(&mut self, callstack_id: CallstackId, phase: PhaseId) {
+        let index = callstack_id as usize;
+        if index >= self.callstack_phases.len() {
+            self.callstack_phases.resize(index + 1, NO_PHASE);
+            self.callstack_phases[index] = phase;
+        }
+    }
+
+    /// Whether the given callstack should be left out of flamegraphs.
+    pub fn is_excluded(&self, callstack_id: CallstackId) -> bool {
+        if self.excluded.is_empty() {
+            return false;
+        }
+        let phase = self
+            .callstack_phases
+            .get(callstack_id as usize)
+            .copied()
+            .unwrap_or(NO_PHASE);
+        match self.name(phase) {
+            Some(name) => self.excluded.iter().any(|excluded| excluded == name),
+            None => false,
+        }
+    }
+
+    /// Bytes per phase, given the per-callstack current and peak memory usage.
+    /// Empty if no phases were marked.
+    pub fn summary(&self, current: &ImVector<usize>, peak: &ImVector<usize>) -> Vec<PhaseSummary> {
+        if !self.in_use() {
+            return vec![];
+        }
+        let mut result: Vec<PhaseSummary> = std::iter::once("[no phase]")
+            .chain(self.names.iter().map(|name| name.as_str()))
+            .map(|name| PhaseSummary {
+                name: name.to_string(),
+                peak_bytes: 0,
+                current_bytes: 0,
+            })
+            .collect();
+        for (callstack_id, phase) in self.callstack_phases.iter().enumerate() {
+            let summary = &mut result[*phase as usize];
+            summary.current_bytes += current.get(callstack_id).copied().unwrap_or(0);
+            summary.peak_bytes += peak.get(callstack_id).copied().unwrap_or(0);
+        }
+        // Allocations from before the first phase are usually not interesting:
+        if result[0].peak_bytes == 0 && result[0].current_bytes == 0 {
+            result.remove(0);
+        }
+        result
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{PhaseSummary, Phases, NO_PHASE};
+    use im::Vector as ImVector;
+
+    #[test]
+    fn marking_and_summary() {
+        let mut phases = Phases::new(false, vec!["imports".to_string()]);
+        assert_eq!(phases.current(), NO_PHASE);
+        assert!(phases
+            .summary(&ImVector::new(), &ImVector::new())
+            .is_empty());
+
+        phases.add_callstack(0, phases.current());
+        phases.mark("imports");
+        let imports = phases.current();
+        phases.add_callstack(1, imports);
+        phases.mark("main");
+        let main = phases.current();
+        phases.add_callstack(2, main);
+        // Going back to an existing phase reuses it:
+        phases.mark("imports");
+        assert_eq!(phases.current(), imports);
+        phases.add_callstack(3, imports);
+        // Already known callstacks keep their phase:
+        phases.add_callstack(2, imports);
+
+        assert_eq!(phases.name(imports), Some("imports"));
+        assert_eq!(phases.name(main), Some("main"));
+        assert_eq!(phases.name(NO_PHASE), None);
+        assert!(!phases.is_excluded(0));
+        assert!(phases.is_excluded(1));
+        assert!(!phases.is_excluded(2));
+        assert!(phases.is_excluded(3));
+
+        let current: ImVector<usize> = vec![0, 10, 20, 30].into_iter().collect();
+        let peak: ImVector<usize> = vec![0, 100, 200, 300].into_iter().collect();
+        assert_eq!(
+            phases.summary(&current, &peak),
+            vec![
+                PhaseSummary {
+                    name: "imports".to_string(),
+                    peak_bytes: 400,
+                    current_bytes: 40
+                },
+                PhaseSummary {
+                    name: "main".to_string(),
+                    peak_bytes: 200,
+                    current_bytes: 20
+                },
+            ]
+        );
+    }
+}
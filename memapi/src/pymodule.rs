@@ -0,0 +1,289 @@
+//! A `_filprofiler_api` Python extension module, for profiling without
+//! LD_PRELOAD.
+//!
+//! Instead of interposing malloc() and friends, allocations are reported
+//! explicitly, e.g. by PEP 445 allocator hooks, and the callstack is tracked
+//! via sys.setprofile() calling start_call()/finish_call(). The same
+//! AllocationTracker and report writing code as the LD_PRELOAD version is
+//! used, so reports are identical.
+//!
+//! The plain Rust functions are for use by native hook layers; the module
+//! wraps them for use from Python. The filapi crate builds the actual
+//! extension module, and exports C versions of the hook functions.
+
+// PyO3's generated wrappers don't follow the crate-wide lint.
+#![allow(unsafe_op_in_unsafe_fn)]
+
+use crate::bundled_allocators;
+use crate::memorytracking::LineNumberInfo::LineNumber;
+use crate::memorytracking::{
+    AllocationKind, AllocationTracker, CallSiteId, Callstack, ExitSummary, FunctionId,
+    VecFunctionLocations,
+};
+use parking_lot::Mutex;
+use pyo3::prelude::*;
+use std::cell::RefCell;
+use std::path::Path;
+use std::sync::atomic::{AtomicBool, Ordering};
+
+thread_local!(static THREAD_CALLSTACK: RefCell<Callstack> = RefCell::new(Callstack::new()));
+
+lazy_static! {
+    static ref TRACKER: Mutex<AllocationTracker<VecFunctionLocations>> = Mutex::new(
+        AllocationTracker::new("/tmp".to_string(), VecFunctionLocations::new())
+    );
+}
+
+/// Whether allocations are being recorded.
+static TRACKING: AtomicBool = AtomicBool::new(false);
+
+/// Start recording allocations.
+pub fn start() {
+    TRACKING.store(true, Ordering::SeqCst);
+}
+
+/// Stop recording allocations.
+pub fn stop() {
+    TRACKING.store(false, Ordering::SeqCst);
+}
+
+/// Clear all tracked allocations, and set the default output directory.
+pub fn reset(default_path: String) {
+    TRACKER.lock().reset(default_path);
+}
+
+/// Register a new function/filename location.
+pub fn add_function(filename: String, function_name: String) -> FunctionId {
+    TRACKER
+        .lock()
+        .functions
+        .add_function(filename, function_name)
+}
+
+/// Add to the current thread's function stack.
+pub fn start_call(function_id: FunctionId, parent_line_number: u32, line_number: u32) {
+    THREAD_CALLSTACK.with(|cs| {
+        cs.borrow_mut().start_call(
+            parent_line_number,
+            CallSiteId::new(function_id, LineNumber(line_number)),
+        );
+    });
+}
+
+/// Remove the most recent call from the current thread's function stack.
+pub fn finish_call() {
+    THREAD_CALLSTACK.with(|cs| {
+        cs.borrow_mut().finish_call();
+    });
+}
+
+/// Record a new allocation from the current callstack, if tracking is on.
+pub fn add_allocation(address: usize, size: usize, line_number: u32) {
+    if !TRACKING.load(Ordering::Relaxed) {
+        return;
+    }
+    // Will fail during thread shutdown, but not much we can do at that point.
+    let _ = TRACKER
+        .lock()
+        .record_allocation(address, size, AllocationKind::Malloc, |tracker| {
+            THREAD_CALLSTACK.try_with(|tcs| {
+                tracker.callstack_id_for_allocation(&mut tcs.borrow_mut(), line_number)
+            })
+        });
+}
+
+/// Record an allocation being freed.
+pub fn free_allocation(address: usize) {
+    TRACKER.lock().record_free(address);
+}
+
+/// Current and peak allocated bytes.
+pub fn allocated_bytes() -> (usize, usize) {
+    TRACKER.lock().get_traced_memory()
+}
+
+/// Write flamegraphs of peak memory usage to the given directory, or the
+/// default path if none is given.
+pub fn dump_peak(path: Option<String>) {
+    // Rendering loads source code via Python's linecache, so don't hold the
+    // lock while doing it.
+    let (path, mut report) = {
+        let mut tracker = TRACKER.lock();
+        let path = path.unwrap_or_else(|| tracker.default_path.clone());
+        (path, tracker.peak_report(true))
+    };
+    // Nothing is interposed here, so they're never tracked:
+    report.metadata.bundled_allocators = bundled_allocators::detect(false);
+    let directory_path = Path::new(&path);
+    report.write(
+        directory_path,
+        "peak-memory",
+        "Peak Tracked Memory Usage",
+        true,
+    );
+    // Tracking is stopped before the final report:
+    if !TRACKING.load(Ordering::SeqCst) && ExitSummary::enabled() {
+        let summary_factory = TRACKER.lock().exit_summary();
+        summary_factory().write_to_stderr(directory_path);
+    }
+}
+
+#[pyfunction]
+#[pyo3(name = "start")]
+fn py_start() {
+    start();
+}
+
+#[pyfunction]
+#[pyo3(name = "stop")]
+fn py_stop() {
+    stop();
+}
+
+#[pyfunction]
+#[pyo3(name = "reset")]
+fn py_reset(default_path: String) {
+    reset(default_path);
+}
+
+/// Change settings without clearing tracked allocations.
+#[pyfunction]
+#[pyo3(name = "configure", signature = (*, default_path=None))]
+fn py_configure(default_path: Option<String>) {
+    if let Some(default_path) = default_path {
+        TRACKER.lock().default_path = default_path;
+    }
+}
+
+#[pyfunction]
+#[pyo3(name = "dump_peak", signature = (path=None))]
+fn py_dump_peak(py: Python<'_>, path: Option<String>) {
+    // Rendering may call back into Python, but otherwise doesn't need the GIL:
+    py.allow_threads(|| dump_peak(path));
+}
+
+#[pyfunction]
+#[pyo3(name = "add_function")]
+fn py_add_function(filename: String, function_name: String) -> u64 {
+    add_function(filename, function_name).as_u64()
+}
+
+#[pyfunction]
+#[pyo3(name = "start_call")]
+fn py_start_call(function_id: u64, parent_line_number: u32, line_number: u32) {
+    start_call(
+        FunctionId::new(function_id),
+        parent_line_number,
+        line_number,
+    );
+}
+
+#[pyfunction]
+#[pyo3(name = "finish_call")]
+fn py_finish_call() {
+    finish_call();
+}
+
+#[pyfunction]
+#[pyo3(name = "add_allocation", signature = (address, size, line_number=0))]
+fn py_add_allocation(address: usize, size: usize, line_number: u32) {
+    add_allocation(address, size, line_number);
+}
+
+#[pyfunction]
+#[pyo3(name = "free_allocation")]
+fn py_free_allocation(address: usize) {
+    free_allocation(address);
+}
+
+#[pyfunction]
+#[pyo3(name = "allocated_bytes")]
+fn py_allocated_bytes() -> (usize, usize) {
+    allocated_bytes()
+}
+
+/// Add the Python functions to the given module.
+pub fn add_to_module(m: &Bound<'_, PyModule>) -> PyResult<()> {
+    m.add_function(wrap_pyfunction!(py_start, m)?)?;
+    m.add_function(wrap_pyfunction!(py_stop, m)?)?;
+    m.add_function(wrap_pyfunction!(py_reset, m)?)?;
+    m.add_function(wrap_pyfunction!(py_configure, m)?)?;
+    m.add_function(wrap_pyfunction!(py_dump_peak, m)?)?;
+    m.add_function(wrap_pyfunction!(py_add_function, m)?)?;
+    m.add_function(wrap_pyfunction!(py_start_call, m)?)?;
+    m.add_function(wrap_pyfunction!(py_finish_call, m)?)?;
+    m.add_function(wrap_pyfunction!(py_add_allocation, m)?)?;
+    m.add_function(wrap_pyfunction!(py_free_allocation, m)?)?;
+    m.add_function(wrap_pyfunction!(py_allocated_bytes, m)?)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use pyo3::prelude::*;
+    use pyo3::types::IntoPyDict;
+    use rusty_fork::rusty_fork_test;
+
+    #[pymodule]
+    fn _filprofiler_api(m: &Bound<'_, PyModule>) -> PyResult<()> {
+        super::add_to_module(m)
+    }
+
+    rusty_fork_test! {
+        /// The module can be driven from Python and writes the same reports
+        /// as the LD_PRELOAD version.
+        #[test]
+        fn drive_module_from_python() {
+            pyo3::prepare_freethreaded_python();
+            let dir = tempfile::tempdir().unwrap();
+            let path = dir.path().to_str().unwrap().to_string();
+            Python::with_gil(|py| {
+                let api = pyo3::wrap_pymodule!(_filprofiler_api)(py);
+                let api = api.bind(py);
+                api.call_method1("reset", ("/nowhere",))?;
+                api.call_method("configure", (), Some(&[("default_path", &path)].into_py_dict_bound(py)))?;
+                let af: u64 = api.call_method1("add_function", ("a", "af"))?.extract()?;
+                let bf: u64 = api.call_method1("add_function", ("b", "bf"))?.extract()?;
+
+                // Not tracking yet, so ignored:
+                api.call_method1("add_allocation", (1, 100))?;
+                assert_eq!(api.call_method0("allocated_bytes")?.extract::<(usize, usize)>()?, (0, 0));
+
+                api.call_method0("start")?;
+                api.call_method1("start_call", (af, 0, 1))?;
+                api.call_method1("add_allocation", (1, 1000))?;
+                api.call_method1("start_call", (bf, 1, 2))?;
+                api.call_method1("add_allocation", (2, 234, 3))?;
+                api.call_method0("finish_call")?;
+                api.call_method1("free_allocation", (1,))?;
+                api.call_method1("add_allocation", (3, 10, 1))?;
+                api.call_method0("stop")?;
+                api.call_method1("add_allocation", (4, 100000))?;
+                assert_eq!(api.call_method0("allocated_bytes")?.extract::<(usize, usize)>()?, (244, 1234));
+
+                api.call_method0("dump_peak")?;
+                Ok::<(), PyErr>(())
+            }).unwrap();
+
+            let mut lines: Vec<String> = std::fs::read_to_string(dir.path().join("peak-memory.prof"))
+                .unwrap()
+                .lines()
+                // Skip the environment header:
+                .filter(|line| !line.starts_with("# "))
+                .map(|line| line.to_string())
+                .collect();
+            lines.sort();
+            assert_eq!(lines, vec!["a:1 (af) 1000", "a:1 (af);b:3 (bf) 234"]);
+            // The same files as the LD_PRELOAD version, see crate::peak_report:
+            for name in [
+                "peak-memory.svg",
+                "peak-functions.tsv",
+                "metadata.json",
+                "reallocs.json",
+                "threads.json",
+            ] {
+                assert!(dir.path().join(name).exists(), "{}", name);
+            }
+        }
+    }
+}
@@ -117,6 +117,42 @@ impl<V: Clone> RangeMap<V> {
         removed
     }
 
+    /// Move all of another map's ranges into this one; they shouldn't
+    /// overlap.
+    pub fn append(&mut self, other: RangeMap<V>) {
+        self.ranges.extend(other.ranges);
+    }
+
+    /// How many ranges there are.
+    pub fn len(&self) -> usize {
+        self.ranges.len()
+    }
+
+    /// Return (start, length, &value) for each run of ranges that are
+    /// contiguous in address space and have the same value, sorted by start.
+    /// The ranges themselves are left alone, so a later partial remove() still
+    /// works.
+    pub fn coalesced(&self) -> Vec<(usize, usize, &V)>
+    where
+        V: PartialEq,
+    {
+        let mut sorted: Vec<&(Range, V)> = self.ranges.iter().collect();
+        sorted.sort_by_key(|(range, _)| range.start);
+        let mut result: Vec<(usize, usize, &V)> = Vec::with_capacity(sorted.len());
+        for (range, value) in sorted {
+            match result.last_mut() {
+                Some((start, length, previous))
+                    if *start + *length == range.start && *previous == value =>
+                {
+                    *length += range.size();
+                }
+                _ => result.push((range.start, range.size(), value)),
+            }
+        }
+        result
+    }
+
+    #[cfg(test)]
     pub fn size(&self) -> usize {
         self.ranges.iter().map(|(r, _)| r.size()).sum()
     }
@@ -126,6 +162,15 @@ impl<V: Clone> RangeMap<V> {
         self.ranges.into_iter().map(|(r, v)| (r.size(), v))
     }
 
+    /// Return iterator of (length, &value).
+    pub fn iter(&self) -> impl Iterator<Item = (usize, &V)> {
+        self.ranges.iter().map(|(r, v)| (r.size(), v))
+    }
+
+    pub fn values_mut(&mut self) -> impl Iterator<Item = &mut V> {
+        self.ranges.iter_mut().map(|(_, v)| v)
+    }
+
     #[cfg(test)]
     pub fn as_hashmap(&self) -> HashMap<usize, (usize, &V)> {
         self.ranges
@@ -220,6 +265,30 @@ mod tests {
             .boxed()
     }
 
+    #[test]
+    fn coalesced() {
+        let mut rangemap: RangeMap<u32> = RangeMap::new();
+        // Added out of order:
+        rangemap.add(200, 100, 1);
+        rangemap.add(100, 100, 1);
+        rangemap.add(300, 50, 1);
+        // Adjacent, but a different value:
+        rangemap.add(350, 50, 2);
+        // Same value, but not adjacent:
+        rangemap.add(500, 10, 2);
+        assert_eq!(rangemap.len(), 5);
+        assert_eq!(
+            rangemap.coalesced(),
+            vec![(100, 250, &1), (350, 50, &2), (500, 10, &2)]
+        );
+        // Partial removal still works on the underlying ranges:
+        assert_eq!(rangemap.remove(150, 100), vec![(1, 50), (1, 50)]);
+        assert_eq!(
+            rangemap.coalesced(),
+            vec![(100, 50, &1), (250, 100, &1), (350, 50, &2), (500, 10, &2)]
+        );
+    }
+
     proptest! {
         /// We can add and remove ranges and get the same result in the real and
         /// stupid range maps.
@@ -228,8 +297,8 @@ mod tests {
             let mut real_rangemap : RangeMap<usize> = RangeMap::new();
             let mut stupid_rangemap: StupidRangeMap<usize> = StupidRangeMap::new();
             for (start, length) in add_ranges {
-                real_rangemap.add(start, length, start * (length as usize));
-                stupid_rangemap.add(start, length, start * (length as usize));
+                real_rangemap.add(start, length, start * length);
+                stupid_rangemap.add(start, length, start * length);
                 prop_assert_eq!(real_rangemap.size(), stupid_rangemap.size());
                 prop_assert_eq!(real_rangemap.as_hashmap(), stupid_rangemap.as_hashmap());
             }
@@ -0,0 +1,232 @@
+//! Statistics on realloc() behavior.
+//!
+//! Buffer-growth patterns, e.g. C extensions doubling a buffer as they append
+//! to it, can cause large transient spikes: while the data is copied, both the
+//! old and new buffers exist. For each callstack that calls realloc(), we
+//! count whether the memory moved or was resized in place, and whether it grew
+//! or shrank.
+
+use crate::linecache::LineCacher;
+use crate::memorytracking::{Callstack, CallstackId, ReadFunctionLocations};
+use crate::util::write_atomically;
+use ahash::RandomState as ARandomState;
+use serde::Serialize;
+use std::collections::HashMap;
+use std::path::Path;
+
+/// How many callstacks to include in the report.
+const MAX_REPORTED_CALLSTACKS: usize = 50;
+
+/// Statistics for a single callstack.
+#[derive(Clone, Debug, Default, PartialEq, Serialize)]
+pub struct CallstackReallocs {
+    pub reallocs: u64,
+    /// Reallocs where the memory had to move to a new address.
+    pub moved: u64,
+    pub grew: u64,
+    pub shrank: u64,
+    pub bytes_grown: usize,
+    pub bytes_shrunk: usize,
+    /// Bytes that were copied when memory moved, at least nominally: large
+    /// allocations may be moved by remapping pages instead.
+    pub bytes_copied: usize,
+}
+
+/// Tracks realloc() activity per callstack.
+#[derive(Default)]
+pub struct ReallocTracker {
+    // Indexed by CallstackId:
+    per_callstack: Vec<CallstackReallocs>,
+}
+
+impl ReallocTracker {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Record a realloc() from the given callstack.
+    pub fn record(
+        &mut self,
+        callstack_id: CallstackId,
+        old_address: usize,
+        old_size: usize,
+        new_address: usize,
+        new_size: usize,
+    ) {
+        let index = callstack_id as usize;
+        if index >= self.per_callstack.len() {
+            self.per_callstack
+                .resize(index + 1, CallstackReallocs::default());
+        }
+        let stats = &mut self.per_callstack[index];
+        stats.reallocs += 1;
+        if old_address != new_address {
+            stats.moved += 1;
+            stats.bytes_copied += old_size.min(new_size);
+        }
+        if new_size > old_size {
+            stats.grew += 1;
+            stats.bytes_grown += new_size - old_size;
+        } else if new_size < old_size {
+            stats.shrank += 1;
+            stats.bytes_shrunk += old_size - new_size;
+        }
+    }
+
+    /// Whether any reallocs were recorded.
+    pub fn is_empty(&self) -> bool {
+        self.per_callstack.iter().all(|stats| stats.reallocs == 0)
+    }
+
+    pub fn reset(&mut self) {
+        self.per_callstack.clear();
+    }
+
+    /// Gather the data for the report; resolving callstacks into strings is
+    /// done later by the returned closure, so it can happen without locks
+    /// held.
+    pub fn report<FL: ReadFunctionLocations>(
+        &self,
+        id_to_callstack: &HashMap<CallstackId, &Callstack, ARandomState>,
+    ) -> impl FnOnce(&FL) -> ReallocReport {
+        let mut top: Vec<(Callstack, CallstackReallocs)> = self
+            .per_callstack
+            .iter()
+            .enumerate()
+            .filter(|(_, stats)| stats.reallocs > 0)
+            .filter_map(|(id, stats)| {
+                id_to_callstack
+                    .get(&(id as CallstackId))
+                    .map(|callstack| ((*callstack).clone(), stats.clone()))
+            })
+            .collect();
+        // Copying is the expensive part, and the cause of transient spikes:
+        top.sort_by_key(|(_, stats)| {
+            std::cmp::Reverse((stats.bytes_copied, stats.bytes_grown, stats.reallocs))
+        });
+        top.truncate(MAX_REPORTED_CALLSTACKS);
+        move |functions| {
+            let mut linecache = LineCacher::default();
+            ReallocReport {
+                callstacks: top
+                    .into_iter()
+                    .map(|(callstack, stats)| CallstackReallocReport {
+                        callstack: callstack.as_string(false, functions, ";", &mut linecache),
+                        stats,
+                    })
+                    .collect(),
+            }
+        }
+    }
+}
+
+#[derive(Debug, Serialize)]
+pub struct CallstackReallocReport {
+    pub callstack: String,
+    #[serde(flatten)]
+    pub stats: CallstackReallocs,
+}
+
+#[derive(Debug, Serialize)]
+pub struct ReallocReport {
+    /// The top reallocating callstacks, by bytes copied.
+    pub callstacks: Vec<CallstackReallocReport>,
+}
+
+impl ReallocReport {
+    /// A human-readable table.
+    pub fn to_table(&self) -> String {
+        let mut table = format!(
+            "{:>10} {:>10} {:>10} {:>10} {:>14} {:>14} {:>14}  callstack\n",
+            "reallocs", "moved", "grew", "shrank", "bytes grown", "bytes shrunk", "bytes copied"
+        );
+        for cs in &self.callstacks {
+            let stats = &cs.stats;
+            table.push_str(&format!(
+                "{:>10} {:>10} {:>10} {:>10} {:>14} {:>14} {:>14}  {}\n",
+                stats.reallocs,
+                stats.moved,
+                stats.grew,
+                stats.shrank,
+                stats.bytes_grown,
+                stats.bytes_shrunk,
+                stats.bytes_copied,
+                cs.callstack
+            ));
+        }
+        table
+    }
+
+    /// Write reallocs.json and reallocs.txt to the given directory.
+    pub fn write(&self, directory_path: &Path) {
+        let json_path = directory_path.join("reallocs.json");
+        let result = serde_json::to_vec_pretty(self)
+            .map_err(std::io::Error::from)
+            .and_then(|data| write_atomically(&json_path, data))
+            .and_then(|_| write_atomically(&directory_path.join("reallocs.txt"), self.to_table()));
+        match result {
+            Ok(_) => eprintln!(
+                "=fil-profile= Wrote realloc() statistics to {:?}",
+                json_path
+            ),
+            Err(e) => eprintln!("=fil-profile= Error writing realloc() statistics: {}", e),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::ReallocTracker;
+    use crate::memorytracking::{
+        CallSiteId, Callstack, LineNumberInfo::LineNumber, VecFunctionLocations,
+    };
+    use crate::util::new_hashmap;
+
+    #[test]
+    fn realloc_report() {
+        pyo3::prepare_freethreaded_python();
+        let mut functions = VecFunctionLocations::new();
+        let fid = functions.add_function("a".to_string(), "af".to_string());
+        let mut growing = Callstack::new();
+        growing.start_call(0, CallSiteId::new(fid, LineNumber(1)));
+        let mut shrinking = Callstack::new();
+        shrinking.start_call(0, CallSiteId::new(fid, LineNumber(2)));
+
+        let mut tracker = ReallocTracker::new();
+        assert!(tracker.is_empty());
+        // Callstack 0 doubles a buffer, moving back and forth between two
+        // addresses:
+        let mut size = 16;
+        for i in 0..10 {
+            let new_address = if i % 2 == 0 { 1 } else { 2 };
+            tracker.record(0, 3 - new_address, size, new_address, size * 2);
+            size *= 2;
+        }
+        // Callstack 1 shrinks in place:
+        tracker.record(1, 100, 1000, 100, 10);
+        assert!(!tracker.is_empty());
+
+        let mut id_to_callstack = new_hashmap();
+        id_to_callstack.insert(0, &growing);
+        id_to_callstack.insert(1, &shrinking);
+        let report = tracker.report(&id_to_callstack)(&functions);
+        assert_eq!(report.callstacks.len(), 2);
+        let first = &report.callstacks[0];
+        assert_eq!(first.callstack, "a:1 (af)");
+        assert_eq!(first.stats.reallocs, 10);
+        assert_eq!(first.stats.moved, 10);
+        assert_eq!(first.stats.grew, 10);
+        assert_eq!(first.stats.shrank, 0);
+        assert_eq!(first.stats.bytes_grown, 16 * 1023);
+        assert_eq!(first.stats.bytes_copied, 16 * 1023);
+        let second = &report.callstacks[1];
+        assert_eq!(second.callstack, "a:2 (af)");
+        assert_eq!(second.stats.moved, 0);
+        assert_eq!(second.stats.shrank, 1);
+        assert_eq!(second.stats.bytes_shrunk, 990);
+        assert!(report.to_table().contains("a:2 (af)"));
+
+        tracker.reset();
+        assert!(tracker.is_empty());
+    }
+}
@@ -0,0 +1,222 @@
+//! Profiling just a marked region of the program, see
+//! `filprofiler.api.profile_region()`.
+//!
+//! When a region starts, the allocations and anonymous mmap()s that are live
+//! get moved out of the tracker's maps and into the Region, so a free() can
+//! tell memory from before the region apart from memory allocated inside it.
+//! The region's peak is of memory allocated inside it and still live, so
+//! freeing pre-existing memory can't make it go negative. The pre-existing
+//! memory is either left out of the region's report, or shown as a single
+//! `[pre-existing]` frame.
+//!
+//! Regions can't be nested. When a region ends its maps are merged back in,
+//! and the tracker carries on as if it had never started.
+
+use crate::addressmap::AddressMap;
+use crate::flamegraph::{aggregate_lines, FlamegraphCallstacks};
+use crate::memorytracking::{
+    Callstack, CallstackId, IdentityCleaner, ProcessUid, ReadFunctionLocations,
+};
+use crate::phases::NO_PHASE;
+use crate::rangemap::RangeMap;
+use ahash::RandomState as ARandomState;
+use im::Vector as ImVector;
+use serde::Serialize;
+use std::collections::{BTreeMap, HashMap};
+use std::path::Path;
+
+/// What to do with memory that was already live when the region started.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum PreExisting {
+    /// Leave it out of the report.
+    Exclude,
+    /// Show it as a single `[pre-existing]` frame.
+    Group,
+}
+
+/// Written to the region report's `metadata.json`.
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct RegionMetadata {
+    pub pre_existing: PreExisting,
+    /// Memory allocated inside the region, at the region's peak.
+    pub peak_bytes: usize,
+    /// Memory from before the region that was still live at its peak.
+    pub pre_existing_bytes: usize,
+}
+
+/// A region that's currently being profiled.
+pub struct Region<A: Copy> {
+    pre_existing: PreExisting,
+    // Moved out of the tracker when the region started:
+    pub(crate) allocations: BTreeMap<ProcessUid, AddressMap<A>>,
+    pub(crate) anon_mmaps: BTreeMap<ProcessUid, RangeMap<CallstackId>>,
+    pre_existing_bytes: usize,
+    // Memory allocated inside the region, indexed by CallstackId:
+    current_usage: ImVector<usize>,
+    current_bytes: usize,
+    peak_bytes: usize,
+    peak_usage: ImVector<usize>,
+    pre_existing_bytes_at_peak: usize,
+}
+
+impl<A: Copy> Region<A> {
+    /// Start a region, taking over the currently live allocations and mmaps.
+    pub fn new(
+        pre_existing: PreExisting,
+        allocations: BTreeMap<ProcessUid, AddressMap<A>>,
+        anon_mmaps: BTreeMap<ProcessUid, RangeMap<CallstackId>>,
+        pre_existing_bytes: usize,
+    ) -> Self {
+        Self {
+            pre_existing,
+            allocations,
+            anon_mmaps,
+            pre_existing_bytes,
+            current_usage: ImVector::new(),
+            current_bytes: 0,
+            peak_bytes: 0,
+            peak_usage: ImVector::new(),
+            pre_existing_bytes_at_peak: pre_existing_bytes,
+        }
+    }
+
+    /// Memory was allocated inside the region.
+    pub fn add(&mut self, callstack_id: CallstackId, bytes: usize) {
+        let index = callstack_id as usize;
+        while self.current_usage.len() <= index {
+            self.current_usage.push_back(0);
+        }
+        self.current_usage[index] += bytes;
+        self.current_bytes += bytes;
+    }
+
+    /// Memory allocated inside the region was freed. check_if_new_peak()
+    /// should be called first.
+    pub fn remove(&mut self, callstack_id: CallstackId, bytes: usize) {
+        if let Some(usage) = self.current_usage.get_mut(callstack_id as usize) {
+            *usage -= bytes;
+            self.current_bytes -= bytes;
+        }
+    }
+
+    /// Memory from before the region was freed.
+    pub fn remove_pre_existing(&mut self, bytes: usize) {
+        self.pre_existing_bytes -= bytes;
+    }
+
+    pub fn current_bytes(&self) -> usize {
+        self.current_bytes
+    }
+
+    /// Like AllocationTracker::check_if_new_peak(), snapshots are O(1).
+    pub fn check_if_new_peak(&mut self) {
+        if self.current_bytes > self.peak_bytes {
+            self.peak_bytes = self.current_bytes;
+            self.peak_usage.clone_from(&self.current_usage);
+            self.pre_existing_bytes_at_peak = self.pre_existing_bytes;
+        }
+    }
+
+    /// Gather the region's peak for the report; converting function locations
+    /// is left to the returned closure, so it can happen without locks held.
+    pub fn report<FL: ReadFunctionLocations>(
+        &self,
+        id_to_callstack: &HashMap<CallstackId, &Callstack, ARandomState>,
+    ) -> impl FnOnce(FL) -> RegionReport<FL> {
+        let mut data: HashMap<Callstack, usize, ARandomState> = crate::util::new_hashmap();
+        let line_numbers = !aggregate_lines();
+        for (callstack_id, bytes) in self.peak_usage.iter().enumerate() {
+            if *bytes == 0 {
+                continue;
+            }
+            if let Some(callstack) = id_to_callstack.get(&(callstack_id as CallstackId)) {
+                // The same callstack from different phases counts as one:
+                let mut callstack = if line_numbers {
+                    (**callstack).clone()
+                } else {
+                    callstack.without_line_numbers()
+                };
+                callstack.set_phase(NO_PHASE);
+                *data.entry(callstack).or_insert(0) += bytes;
+            }
+        }
+        let metadata = RegionMetadata {
+            pre_existing: self.pre_existing,
+            peak_bytes: self.peak_bytes,
+            pre_existing_bytes: self.pre_existing_bytes_at_peak,
+        };
+        let mut total_bytes = self.peak_bytes;
+        if self.pre_existing == PreExisting::Group && metadata.pre_existing_bytes > 0 {
+            data.insert(Callstack::pre_existing(), metadata.pre_existing_bytes);
+            total_bytes += metadata.pre_existing_bytes;
+        }
+        move |functions| RegionReport {
+            flamegraph: FlamegraphCallstacks::new(data, functions, IdentityCleaner),
+            total_bytes,
+            metadata,
+        }
+    }
+
+    /// Hand back the allocations and mmaps the region took over, minus any
+    /// that were freed.
+    pub fn into_maps(
+        self,
+    ) -> (
+        BTreeMap<ProcessUid, AddressMap<A>>,
+        BTreeMap<ProcessUid, RangeMap<CallstackId>>,
+    ) {
+        (self.allocations, self.anon_mmaps)
+    }
+}
+
+/// Everything needed to write out the region's flamegraphs.
+pub struct RegionReport<FL: ReadFunctionLocations> {
+    pub flamegraph:
+        FlamegraphCallstacks<HashMap<Callstack, usize, ARandomState>, FL, IdentityCleaner>,
+    /// What the flamegraph adds up to.
+    pub total_bytes: usize,
+    pub metadata: RegionMetadata,
+}
+
+impl<FL: ReadFunctionLocations> RegionReport<FL> {
+    /// Write peak-memory.svg and friends, just like a normal report.
+    pub fn write(&self, directory_path: &Path, to_be_post_processed: bool) {
+        self.flamegraph.write_memory_flamegraphs(
+            directory_path,
+            "peak-memory",
+            "Peak Tracked Memory Usage in Region",
+            self.total_bytes,
+            to_be_post_processed,
+        );
+        let table_path = directory_path.join("peak-functions.tsv");
+        if let Err(e) = self.flamegraph.write_function_table(&table_path) {
+            eprintln!("=fil-profile= Error writing {:?}: {}", table_path, e);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{PreExisting, Region};
+    use std::collections::BTreeMap;
+
+    #[test]
+    fn peak_is_of_memory_allocated_in_region() {
+        let mut region: Region<()> =
+            Region::new(PreExisting::Exclude, BTreeMap::new(), BTreeMap::new(), 1000);
+        region.add(1, 300);
+        region.check_if_new_peak();
+        // Freeing pre-existing memory doesn't lower the region's usage:
+        region.remove_pre_existing(1000);
+        region.add(2, 200);
+        region.check_if_new_peak();
+        assert_eq!(region.current_bytes(), 500);
+        region.remove(1, 300);
+        region.check_if_new_peak();
+        assert_eq!(region.current_bytes(), 200);
+        assert_eq!(region.peak_bytes, 500);
+        assert_eq!(region.peak_usage, vec![0, 300, 200].into_iter().collect());
+        assert_eq!(region.pre_existing_bytes_at_peak, 0);
+    }
+}
@@ -0,0 +1,226 @@
+//! Fitting reports with vast numbers of callstacks into a fixed budget.
+//!
+//! Normally only the callstacks making up the top 99% of memory are shown, up
+//! to 10,000 of them. With FIL_MAX_REPORT_STACKS=N nothing is dropped instead:
+//! the heaviest callstacks, 90% of the budget, are kept exactly, and the rest
+//! are merged bottom-up into their callers, shown as an `[other callstacks]`
+//! frame, until at most N callstacks are left. Memory totals are preserved.
+//! Merging always starts with the deepest callstack, and of those the
+//! lightest, ties broken by Callstack's ordering, so two dumps of the same
+//! data give the same result.
+//!
+//! With FIL_FULL_REPORT_STACKS=1 as well, every callstack is also written out
+//! unmerged, to a gzipped `.prof.gz` file next to the budgeted one.
+
+use crate::memorytracking::Callstack;
+use crate::util::new_hashmap;
+use ahash::RandomState as ARandomState;
+use std::cmp::Reverse;
+use std::collections::{BinaryHeap, HashMap};
+
+/// The maximum number of callstacks in a report, from FIL_MAX_REPORT_STACKS.
+/// Checked when the report is written.
+pub fn max_report_stacks() -> Option<usize> {
+    std::env::var("FIL_MAX_REPORT_STACKS")
+        .ok()
+        .and_then(|value| value.parse().ok())
+        .filter(|max| *max > 0)
+}
+
+/// Whether to also write out all the callstacks, via FIL_FULL_REPORT_STACKS=1.
+/// Only applies if there's a budget.
+pub fn full_report_stacks() -> bool {
+    std::env::var("FIL_FULL_REPORT_STACKS").as_deref() == Ok("1")
+}
+
+/// Merge callstacks until there are at most `max` of them, if possible; only
+/// synthetic callstacks and those without any callers can't be merged.
+pub fn fit_to_budget(
+    data: HashMap<Callstack, usize, ARandomState>,
+    max: usize,
+) -> HashMap<Callstack, usize, ARandomState> {
+    if data.len() <= max {
+        return data;
+    }
+    let mut sorted: Vec<(Callstack, usize)> = data.into_iter().collect();
+    sorted.sort_unstable_by(|a, b| b.1.cmp(&a.1).then_with(|| a.0.cmp(&b.0)));
+    let exact = max - (max / 10).max(1);
+    let mut result: HashMap<Callstack, usize, ARandomState> = new_hashmap();
+    result.extend(sorted.drain(..exact));
+    let budget = max - exact;
+
+    // The rest get merged, deepest and then lightest first. Items are indexed
+    // in order of creation, which is deterministic; merged-away items become
+    // None, and heap entries whose size doesn't match their item's are stale.
+    let mut items: Vec<Option<(Callstack, usize)>> = vec![];
+    let mut indexes: HashMap<Callstack, usize, ARandomState> = new_hashmap();
+    let mut heap = BinaryHeap::new();
+    for (callstack, size) in sorted {
+        heap.push((callstack.depth(), Reverse(size), Reverse(items.len())));
+        indexes.insert(callstack.clone(), items.len());
+        items.push(Some((callstack, size)));
+    }
+    let mut remaining = items.len();
+    while remaining > budget {
+        let Some((_, Reverse(size), Reverse(index))) = heap.pop() else {
+            break;
+        };
+        match &items[index] {
+            Some((_, current)) if *current == size => {}
+            _ => continue,
+        }
+        let Some(caller) = items[index]
+            .as_ref()
+            .and_then(|(callstack, _)| callstack.merged_into_caller())
+        else {
+            continue;
+        };
+        let (callstack, _) = items[index].take().expect("checked above");
+        indexes.remove(&callstack);
+        match indexes.get(&caller) {
+            Some(caller_index) => {
+                let caller_index = *caller_index;
+                let entry = items[caller_index].as_mut().expect("indexes are live");
+                entry.1 += size;
+                heap.push((entry.0.depth(), Reverse(entry.1), Reverse(caller_index)));
+                remaining -= 1;
+            }
+            None => {
+                heap.push((caller.depth(), Reverse(size), Reverse(items.len())));
+                indexes.insert(caller.clone(), items.len());
+                items.push(Some((caller, size)));
+            }
+        }
+    }
+    result.extend(items.into_iter().flatten());
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::fit_to_budget;
+    use crate::memorytracking::{
+        CallSiteId, Callstack, LineNumberInfo, VecFunctionLocations, WriteFunctionLocations,
+    };
+    use crate::util::new_hashmap;
+    use ahash::RandomState as ARandomState;
+    use std::collections::HashMap;
+
+    /// Lots of callstacks, from a few functions calling each other.
+    fn synthetic_data(functions: &mut VecFunctionLocations) -> Vec<(Callstack, usize)> {
+        let ids: Vec<_> = (0..6)
+            .map(|i| functions.add_function(format!("f{}.py", i), format!("f{}", i)))
+            .collect();
+        let mut data = vec![];
+        let mut seed: u64 = 12345;
+        for i in 0..5000u32 {
+            seed = seed
+                .wrapping_mul(6364136223846793005)
+                .wrapping_add(1442695040888963407);
+            let depth = 1 + (seed >> 33) as usize % 5;
+            let calls = (0..depth)
+                .map(|d| {
+                    CallSiteId::new(
+                        ids[(seed >> (8 + d * 3)) as usize % ids.len()],
+                        LineNumberInfo::LineNumber((i + d as u32) % 7 + 1),
+                    )
+                })
+                .collect();
+            data.push((
+                Callstack::from_vec(calls),
+                1 + (seed >> 40) as usize % 100_000,
+            ));
+        }
+        data.push((Callstack::unknown_native_thread(), 123));
+        data.push((Callstack::new(), 17));
+        data
+    }
+
+    fn to_hashmap(
+        data: impl Iterator<Item = (Callstack, usize)>,
+    ) -> HashMap<Callstack, usize, ARandomState> {
+        let mut result = new_hashmap();
+        for (callstack, size) in data {
+            *result.entry(callstack).or_insert(0) += size;
+        }
+        result
+    }
+
+    #[test]
+    fn totals_are_preserved() {
+        let mut functions = VecFunctionLocations::new();
+        let data = synthetic_data(&mut functions);
+        let original = to_hashmap(data.clone().into_iter());
+        let total: usize = original.values().sum();
+        for max in [1, 2, 10, 100, 1000] {
+            let budgeted = fit_to_budget(original.clone(), max);
+            assert_eq!(budgeted.values().sum::<usize>(), total);
+            // Tiny budgets can't be met, since there are two unmergeable
+            // callstacks besides the root [other callstacks]:
+            let allowed = if max < 30 { max + 2 } else { max };
+            assert!(budgeted.len() <= allowed, "{} > {}", budgeted.len(), max);
+            // The heaviest are kept exactly:
+            let mut heaviest: Vec<_> = original.iter().collect();
+            heaviest.sort_by(|a, b| b.1.cmp(a.1).then_with(|| a.0.cmp(b.0)));
+            for (callstack, size) in heaviest.into_iter().take(max * 9 / 10) {
+                assert_eq!(budgeted.get(callstack), Some(size));
+            }
+        }
+        // Small enough already:
+        assert_eq!(fit_to_budget(original.clone(), 10_000), original);
+    }
+
+    #[test]
+    fn deterministic() {
+        let mut functions = VecFunctionLocations::new();
+        let data = synthetic_data(&mut functions);
+        let forwards = fit_to_budget(to_hashmap(data.clone().into_iter()), 50);
+        let backwards = fit_to_budget(to_hashmap(data.into_iter().rev()), 50);
+        let sorted = |data: HashMap<Callstack, usize, ARandomState>| {
+            let mut data: Vec<_> = data.into_iter().collect();
+            data.sort();
+            data
+        };
+        assert_eq!(sorted(forwards), sorted(backwards));
+    }
+
+    #[test]
+    fn merged_into_callers() {
+        pyo3::prepare_freethreaded_python();
+        let mut functions = VecFunctionLocations::new();
+        let a = functions.add_function("a.py".to_string(), "a".to_string());
+        let b = functions.add_function("b.py".to_string(), "b".to_string());
+        let call_a = CallSiteId::new(a, LineNumberInfo::LineNumber(1));
+        let callstack = |calls: &[CallSiteId]| Callstack::from_vec(calls.to_vec());
+        let b_at = |line| CallSiteId::new(b, LineNumberInfo::LineNumber(line));
+        let data = to_hashmap(
+            vec![
+                (callstack(&[call_a]), 1000),
+                (callstack(&[call_a, b_at(2)]), 500),
+                (callstack(&[call_a, b_at(3)]), 30),
+                (callstack(&[call_a, b_at(4)]), 20),
+            ]
+            .into_iter(),
+        );
+        let functions = functions.to_reader();
+        let mut lines: Vec<String> = fit_to_budget(data, 3)
+            .into_iter()
+            .map(|(callstack, size)| {
+                format!(
+                    "{} {}",
+                    callstack.as_string(false, &functions, ";", &mut Default::default()),
+                    size
+                )
+            })
+            .collect();
+        lines.sort();
+        assert_eq!(
+            lines,
+            vec![
+                "a.py:1 (a) 1000",
+                "a.py:1 (a);[other callstacks] 50",
+                "a.py:1 (a);b.py:2 (b) 500",
+            ]
+        );
+    }
+}
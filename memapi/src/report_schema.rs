@@ -0,0 +1,214 @@
+//! `peak.json`, a machine-readable version of the peak memory report, for
+//! archiving reports or processing them with other tools. It's written when
+//! FIL_JSON_REPORT=1 is set, or zstd-compressed as `peak.json.zst` with
+//! FIL_JSON_REPORT=zstd, or when FIL_OUTPUT_FORMATS includes `json` or
+//! `json.zst` (see crate::output_formats). The settings are checked when the
+//! report is written.
+//!
+//! The structs here are the schema. Unknown fields are rejected when reading,
+//! so any change to them, even adding a field, needs FORMAT_VERSION bumped;
+//! validate() then tells reports in other formats apart from broken ones.
+
+use crate::output_formats::{self, OutputFormat};
+use crate::util::write_atomically_with;
+use serde::{Deserialize, Serialize};
+use std::borrow::Cow;
+use std::path::{Path, PathBuf};
+
+/// The version of the format written by this version of Fil.
+pub const FORMAT_VERSION: u32 = 1;
+
+/// How the JSON report should be written, if at all.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum JsonReport {
+    Plain,
+    Zstd,
+}
+
+/// The JSON report to write, from FIL_JSON_REPORT or FIL_OUTPUT_FORMATS.
+pub fn json_report() -> Option<JsonReport> {
+    match std::env::var("FIL_JSON_REPORT").as_deref() {
+        Ok("1") => Some(JsonReport::Plain),
+        Ok("zstd") => Some(JsonReport::Zstd),
+        _ if output_formats::requested(OutputFormat::ZstdJson) => Some(JsonReport::Zstd),
+        _ if output_formats::requested(OutputFormat::Json) => Some(JsonReport::Plain),
+        _ => None,
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct PeakReport {
+    /// FORMAT_VERSION of the Fil that wrote it.
+    pub format_version: u32,
+    /// Total tracked memory at peak.
+    pub peak_bytes: usize,
+    /// False if FIL_AGGREGATE_LINES=1 left out line numbers.
+    pub line_numbers: bool,
+    /// Largest first. Like in the flamegraph, callstacks with little memory
+    /// may have been left out, or merged, see crate::report_budget.
+    pub callstacks: Vec<CallstackBytes>,
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct CallstackBytes {
+    /// Outermost first, named as in the .prof file, e.g. "a.py:12 (load)".
+    pub frames: Vec<String>,
+    pub bytes: usize,
+}
+
+impl PeakReport {
+    pub fn new(
+        peak_bytes: usize,
+        line_numbers: bool,
+        callstacks: impl IntoIterator<Item = (Vec<String>, usize)>,
+    ) -> Self {
+        let mut callstacks: Vec<CallstackBytes> = callstacks
+            .into_iter()
+            .map(|(frames, bytes)| CallstackBytes { frames, bytes })
+            .collect();
+        // Sorted by frames too, so the same data always gives the same file:
+        callstacks.sort_by(|a, b| b.bytes.cmp(&a.bytes).then_with(|| a.frames.cmp(&b.frames)));
+        Self {
+            format_version: FORMAT_VERSION,
+            peak_bytes,
+            line_numbers,
+            callstacks,
+        }
+    }
+
+    /// Write peak.json or peak.json.zst to the given directory, returning the
+    /// path. Compression is streamed, so the compressed JSON is never in memory
+    /// all at once.
+    pub fn write(&self, directory_path: &Path, how: JsonReport) -> std::io::Result<PathBuf> {
+        let path = match how {
+            JsonReport::Plain => directory_path.join("peak.json"),
+            JsonReport::Zstd => directory_path.join("peak.json.zst"),
+        };
+        write_atomically_with(&path, |file| match how {
+            JsonReport::Plain => Ok(serde_json::to_writer(file, self)?),
+            JsonReport::Zstd => {
+                let mut encoder = zstd::Encoder::new(file, zstd::DEFAULT_COMPRESSION_LEVEL)?;
+                serde_json::to_writer(&mut encoder, self)?;
+                encoder.finish()?;
+                Ok(())
+            }
+        })?;
+        Ok(path)
+    }
+}
+
+/// The first bytes of a zstd frame.
+const ZSTD_MAGIC: [u8; 4] = [0x28, 0xb5, 0x2f, 0xfd];
+
+/// Parse a report, zstd-compressed or not, and check it matches the current
+/// schema and is consistent.
+pub fn validate(data: &[u8]) -> Result<PeakReport, String> {
+    let data = if data.starts_with(&ZSTD_MAGIC) {
+        Cow::Owned(zstd::decode_all(data).map_err(|e| format!("Corrupt zstd data: {}", e))?)
+    } else {
+        Cow::Borrowed(data)
+    };
+    // Check the version first, so reports in other formats get a clear error
+    // rather than complaints about fields:
+    #[derive(Deserialize)]
+    struct Version {
+        format_version: Option<u32>,
+    }
+    let version: Version =
+        serde_json::from_slice(&data).map_err(|e| format!("Not a JSON report: {}", e))?;
+    match version.format_version {
+        Some(FORMAT_VERSION) => {}
+        Some(other) => {
+            return Err(format!(
+                "Format version {} isn't supported, only {}",
+                other, FORMAT_VERSION
+            ))
+        }
+        None => return Err("No format_version".to_string()),
+    }
+    let report: PeakReport =
+        serde_json::from_slice(&data).map_err(|e| format!("Doesn't match the schema: {}", e))?;
+    for (i, callstack) in report.callstacks.iter().enumerate() {
+        if callstack.frames.is_empty() {
+            return Err(format!("Callstack {} has no frames", i));
+        }
+        if callstack.bytes == 0 {
+            return Err(format!("Callstack {} has no memory", i));
+        }
+    }
+    let total: usize = report.callstacks.iter().map(|c| c.bytes).sum();
+    if total > report.peak_bytes {
+        return Err(format!(
+            "Callstacks add up to {} bytes, more than the peak of {}",
+            total, report.peak_bytes
+        ));
+    }
+    Ok(report)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{validate, JsonReport, PeakReport, FORMAT_VERSION};
+
+    fn report() -> PeakReport {
+        PeakReport::new(
+            1000,
+            true,
+            vec![
+                (vec!["a.py:1 (main)".to_string()], 100),
+                (
+                    vec!["a.py:2 (main)".to_string(), "b.py:7 (load)".to_string()],
+                    800,
+                ),
+            ],
+        )
+    }
+
+    #[test]
+    fn round_trip() {
+        let report = report();
+        assert_eq!(report.format_version, FORMAT_VERSION);
+        assert_eq!(report.callstacks[0].bytes, 800);
+        let directory = tempfile::tempdir().unwrap();
+        for how in [JsonReport::Plain, JsonReport::Zstd] {
+            let path = report.write(directory.path(), how).unwrap();
+            let data = std::fs::read(path).unwrap();
+            assert_eq!(validate(&data), Ok(report.clone()));
+        }
+        let plain = std::fs::read(directory.path().join("peak.json")).unwrap();
+        let compressed = std::fs::read(directory.path().join("peak.json.zst")).unwrap();
+        assert_eq!(zstd::decode_all(&compressed[..]).unwrap(), plain);
+
+        // Corruption is noticed:
+        let truncated = &compressed[..compressed.len() - 4];
+        assert!(validate(truncated)
+            .unwrap_err()
+            .starts_with("Corrupt zstd data"));
+    }
+
+    #[test]
+    fn invalid_reports() {
+        let json = serde_json::to_value(report()).unwrap();
+        let check = |change: &dyn Fn(&mut serde_json::Value)| {
+            let mut json = json.clone();
+            change(&mut json);
+            validate(&serde_json::to_vec(&json).unwrap()).unwrap_err()
+        };
+        assert_eq!(
+            check(&|json| json["format_version"] = 2.into()),
+            "Format version 2 isn't supported, only 1"
+        );
+        assert!(check(&|json| json["extra"] = 1.into()).contains("unknown field `extra`"));
+        assert!(check(&|json| json["callstacks"][0]["extra"] = 1.into())
+            .contains("unknown field `extra`"));
+        assert!(check(&|json| json["peak_bytes"] = 10.into()).contains("more than the peak"));
+        assert!(
+            check(&|json| json["callstacks"][1]["frames"] = serde_json::json!([]))
+                .contains("no frames")
+        );
+        assert!(validate(b"not json").is_err());
+        assert!(validate(b"{}").unwrap_err().contains("No format_version"));
+    }
+}
@@ -0,0 +1,266 @@
+//! Continuous profiling in fixed windows, e.g. for long-running services. Set
+//! `FIL_ROTATE_WINDOW=10m` and every 10 minutes the window's peak is written
+//! to `window-<n>.svg` in the report directory, and a new window starts. Only
+//! the last `FIL_ROTATE_KEEP` windows (default 6) are kept, older ones are
+//! deleted. Durations are in seconds, or have an s/m/h suffix.
+//!
+//! A new window doesn't forget memory that's still live: it starts out with
+//! everything that's currently allocated, moved to callstacks under a
+//! `[carried over]` root frame, so long-lived memory stays visible next to
+//! what the window itself allocated. The window's peak starts over from
+//! there, all under the tracker lock, so no allocation is missed or counted
+//! in both windows.
+//!
+//! Only the peak starts over, so dumping the peak, e.g. at exit, gets the
+//! current window so far. Everything else is about the whole run:
+//! milestones, the cgroup watchdog and out-of-memory detection are about the
+//! running total, which a new window doesn't change. reset() starts the
+//! current window over without writing it out, and while a region is being
+//! profiled the window is extended until it ends.
+
+use serde::Serialize;
+use std::collections::VecDeque;
+use std::path::Path;
+use std::time::{Duration, Instant};
+
+/// How often to check for the end of a window, at most.
+const MAX_CHECK_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Parse a duration like "600", "90s", "10m" or "1.5h".
+pub fn parse_duration(value: &str) -> Result<Duration, String> {
+    let value = value.trim();
+    let digits = value
+        .find(|c: char| !(c.is_ascii_digit() || c == '.'))
+        .unwrap_or(value.len());
+    let (number, unit) = value.split_at(digits);
+    let multiplier = match unit.trim() {
+        "" | "s" => 1.0,
+        "m" => 60.0,
+        "h" => 3600.0,
+        _ => return Err(format!("unknown unit in {:?}", value)),
+    };
+    let seconds: f64 = number
+        .parse()
+        .map_err(|_| format!("{:?} isn't a duration", value))?;
+    if seconds <= 0.0 {
+        return Err(format!("{:?} isn't a duration", value));
+    }
+    Ok(Duration::from_secs_f64(seconds * multiplier))
+}
+
+/// A window that was written out, as listed in `metadata.json`.
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct WindowReport {
+    pub number: u64,
+    pub peak_bytes: usize,
+    /// When it ended, since tracking started.
+    pub seconds: f64,
+    pub path: String,
+}
+
+/// Written to `metadata.json`, with the windows that are still on disk.
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct RotationReport {
+    pub interval_seconds: f64,
+    /// The window this report is of.
+    pub current: u64,
+    /// When it started, since tracking started.
+    pub current_started_seconds: f64,
+    /// Oldest first.
+    pub windows: Vec<WindowReport>,
+}
+
+/// A window that just ended, to be written out.
+pub struct FinishedWindow {
+    pub number: u64,
+    /// The files to delete, without extension, now that there are too many.
+    pub expired: Vec<String>,
+}
+
+impl FinishedWindow {
+    /// The file name the window's report is written to, without extension.
+    pub fn base_filename(&self) -> String {
+        window_base_filename(self.number)
+    }
+}
+
+fn window_base_filename(number: u64) -> String {
+    format!("window-{}", number)
+}
+
+/// Which window we're in, see the module documentation.
+pub struct Rotation {
+    interval: Duration,
+    keep: usize,
+    // When tracking started, and when the current window did:
+    start: Instant,
+    window_start: Instant,
+    // The current window's number, starting from 1:
+    current: u64,
+    // Windows that were written and not deleted yet, oldest first:
+    written: VecDeque<WindowReport>,
+}
+
+impl Rotation {
+    pub fn new(interval: Duration, keep: usize) -> Self {
+        let now = Instant::now();
+        Self {
+            interval,
+            keep: keep.max(1),
+            start: now,
+            window_start: now,
+            current: 1,
+            written: VecDeque::new(),
+        }
+    }
+
+    /// Configure from FIL_ROTATE_WINDOW and FIL_ROTATE_KEEP, if the former is
+    /// set; values that can't be parsed are warned about and ignored.
+    pub fn from_env() -> Option<Self> {
+        let value = std::env::var("FIL_ROTATE_WINDOW").ok()?;
+        let interval = match parse_duration(&value) {
+            Ok(interval) => interval,
+            Err(e) => {
+                eprintln!("=fil-profile= Ignoring FIL_ROTATE_WINDOW: {}", e);
+                return None;
+            }
+        };
+        let keep = match std::env::var("FIL_ROTATE_KEEP") {
+            Ok(value) => value.trim().parse().unwrap_or_else(|_| {
+                eprintln!(
+                    "=fil-profile= Ignoring FIL_ROTATE_KEEP={:?}, expected a number of windows",
+                    value
+                );
+                6
+            }),
+            Err(_) => 6,
+        };
+        Some(Self::new(interval, keep))
+    }
+
+    /// How often the sampler thread should check is_due().
+    pub fn check_interval(&self) -> Duration {
+        (self.interval / 10).min(MAX_CHECK_INTERVAL)
+    }
+
+    /// Whether the current window is over.
+    pub fn is_due(&self) -> bool {
+        self.window_start.elapsed() >= self.interval
+    }
+
+    /// End the current window, whose peak was the given size and is about to
+    /// be written to the given directory, and start the next one.
+    pub fn rotate(&mut self, peak_bytes: usize, directory: &Path) -> FinishedWindow {
+        let number = self.current;
+        self.written.push_back(WindowReport {
+            number,
+            peak_bytes,
+            seconds: self.start.elapsed().as_secs_f64(),
+            path: directory
+                .join(format!("{}.svg", window_base_filename(number)))
+                .to_string_lossy()
+                .into_owned(),
+        });
+        let mut expired = vec![];
+        while self.written.len() > self.keep {
+            if let Some(window) = self.written.pop_front() {
+                expired.push(window_base_filename(window.number));
+            }
+        }
+        self.current += 1;
+        self.window_start = Instant::now();
+        FinishedWindow { number, expired }
+    }
+
+    /// The windows written so far, and which one is current.
+    pub fn report(&self) -> RotationReport {
+        RotationReport {
+            interval_seconds: self.interval.as_secs_f64(),
+            current: self.current,
+            current_started_seconds: self.window_start.duration_since(self.start).as_secs_f64(),
+            windows: self.written.iter().cloned().collect(),
+        }
+    }
+
+    /// Start the current window over, e.g. because the peak was reset.
+    pub fn reset(&mut self) {
+        self.window_start = Instant::now();
+    }
+}
+
+/// Delete the files an expired window was written to.
+pub fn delete_window(directory: &Path, base_filename: &str) {
+    for suffix in [".svg", "-reversed.svg", ".prof", "-full.prof.gz"] {
+        let path = directory.join(format!("{}{}", base_filename, suffix));
+        if let Err(e) = std::fs::remove_file(&path) {
+            if e.kind() != std::io::ErrorKind::NotFound {
+                eprintln!("=fil-profile= Error deleting {:?}: {}", path, e);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{delete_window, parse_duration, Rotation};
+    use std::path::Path;
+    use std::time::Duration;
+
+    #[test]
+    fn parsing() {
+        assert_eq!(parse_duration("600"), Ok(Duration::from_secs(600)));
+        assert_eq!(parse_duration("90s"), Ok(Duration::from_secs(90)));
+        assert_eq!(parse_duration("10m"), Ok(Duration::from_secs(600)));
+        assert_eq!(parse_duration("1.5h"), Ok(Duration::from_secs(5400)));
+        assert_eq!(parse_duration("0.5"), Ok(Duration::from_millis(500)));
+        assert!(parse_duration("10d").is_err());
+        assert!(parse_duration("m").is_err());
+        assert!(parse_duration("0").is_err());
+    }
+
+    #[test]
+    fn keeps_last_windows() {
+        let mut rotation = Rotation::new(Duration::from_secs(3600), 2);
+        assert!(!rotation.is_due());
+        assert_eq!(rotation.check_interval(), Duration::from_secs(1));
+        let directory = Path::new("/reports");
+        let first = rotation.rotate(100, directory);
+        assert_eq!(first.base_filename(), "window-1");
+        assert!(first.expired.is_empty());
+        assert!(rotation.rotate(200, directory).expired.is_empty());
+        let third = rotation.rotate(300, directory);
+        assert_eq!(third.number, 3);
+        assert_eq!(third.expired, vec!["window-1".to_string()]);
+        let report = rotation.report();
+        assert_eq!(report.current, 4);
+        assert_eq!(
+            report
+                .windows
+                .iter()
+                .map(|window| (window.number, window.peak_bytes))
+                .collect::<Vec<_>>(),
+            vec![(2, 200), (3, 300)]
+        );
+        assert_eq!(report.windows[0].path, "/reports/window-2.svg");
+    }
+
+    #[test]
+    fn deleting() {
+        let directory = tempfile::tempdir().unwrap();
+        for name in [
+            "window-1.svg",
+            "window-1-reversed.svg",
+            "window-1.prof",
+            "window-10.svg",
+        ] {
+            std::fs::write(directory.path().join(name), "").unwrap();
+        }
+        delete_window(directory.path(), "window-1");
+        let mut left: Vec<_> = std::fs::read_dir(directory.path())
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name().into_string().unwrap())
+            .collect();
+        left.sort();
+        assert_eq!(left, vec!["window-10.svg".to_string()]);
+    }
+}
@@ -0,0 +1,293 @@
+//! Where report files go. By default they're written to disk; native code can
+//! also register a sink with fil_register_output_sink(), which gets every file
+//! Fil writes handed to it, e.g. to upload reports to object storage from
+//! machines whose disk doesn't outlive the job. The files are still written
+//! too, since the HTML report is built from them.
+//!
+//! With a sink registered, each file is generated in memory by the same
+//! writers, and once it's complete it's written to disk and then passed to the
+//! callback in chunks of up to CHUNK_SIZE bytes, in order, followed by one call
+//! with a length of 0 to say it's done. Each call also gets the format, the
+//! file's extension, e.g. `svg`, `prof`, `prof.gz` or `json`, and the path it
+//! was written to, which identifies the file. If the callback
+//! returns anything but 0 the rest of that file isn't passed, and the failure
+//! is recorded so the dump can report it once it's done, see DumpScope.
+//!
+//! Files are only written outside the tracker lock, by the thread doing the
+//! dump, so the callback is too. That thread's allocations aren't tracked.
+
+use crate::util::write_file_atomically_with;
+use parking_lot::Mutex;
+use std::cell::Cell;
+use std::ffi::CString;
+use std::io::{self, Write};
+use std::os::raw::{c_char, c_int, c_void};
+use std::path::Path;
+
+/// How much of a file is passed to the callback at once, at most.
+pub const CHUNK_SIZE: usize = 1 << 20;
+
+/// Gets the file's format and path, a chunk of it and its length, and the user
+/// data pointer it was registered with. Returns 0 on success.
+pub type OutputSinkCallback = extern "C" fn(
+    format: *const c_char,
+    path: *const c_char,
+    data: *const u8,
+    length: usize,
+    user_data: *mut c_void,
+) -> c_int;
+
+/// Something files can be handed to, once they're complete.
+pub trait OutputSink {
+    fn write(&self, path: &Path, data: &[u8]) -> io::Result<()>;
+}
+
+/// The default sink, writing files atomically, see
+/// crate::util::write_atomically_with().
+pub struct FileSink;
+
+impl OutputSink for FileSink {
+    fn write(&self, path: &Path, data: &[u8]) -> io::Result<()> {
+        write_file_atomically_with(path, |writer| writer.write_all(data))
+    }
+}
+
+/// A callback registered with fil_register_output_sink().
+#[derive(Clone, Copy)]
+pub struct CallbackSink {
+    callback: OutputSinkCallback,
+    // Never dereferenced, just passed back to the callback:
+    user_data: usize,
+}
+
+impl CallbackSink {
+    pub fn new(callback: OutputSinkCallback, user_data: *mut c_void) -> Self {
+        Self {
+            callback,
+            user_data: user_data as usize,
+        }
+    }
+
+    /// Call the callback, turning failure into an error.
+    fn call(&self, format: &CString, path: &CString, data: &[u8]) -> io::Result<()> {
+        let data_pointer = if data.is_empty() {
+            std::ptr::null()
+        } else {
+            data.as_ptr()
+        };
+        match (self.callback)(
+            format.as_ptr(),
+            path.as_ptr(),
+            data_pointer,
+            data.len(),
+            self.user_data as *mut c_void,
+        ) {
+            0 => Ok(()),
+            code => Err(io::Error::other(format!(
+                "the output sink failed with code {}",
+                code
+            ))),
+        }
+    }
+}
+
+impl OutputSink for CallbackSink {
+    fn write(&self, path: &Path, data: &[u8]) -> io::Result<()> {
+        let format = CString::new(format_of(path))?;
+        let path = CString::new(path.to_string_lossy().into_owned())?;
+        for chunk in data.chunks(CHUNK_SIZE) {
+            self.call(&format, &path, chunk)?;
+        }
+        self.call(&format, &path, &[])
+    }
+}
+
+/// The file name's extension, everything after the first dot, so compressed
+/// files are e.g. `prof.gz`.
+fn format_of(path: &Path) -> String {
+    let name = path
+        .file_name()
+        .map(|name| name.to_string_lossy().into_owned())
+        .unwrap_or_default();
+    match name.split_once('.') {
+        Some((_, extension)) => extension.to_string(),
+        None => String::new(),
+    }
+}
+
+static REGISTERED: Mutex<Option<CallbackSink>> = Mutex::new(None);
+
+thread_local! {
+    // Failures during the innermost DumpScope on this thread:
+    static FAILURES: Cell<u64> = const { Cell::new(0) };
+}
+
+/// Hand files to the given sink from now on, as well as writing them, or stop
+/// if it's None.
+pub fn register(sink: Option<CallbackSink>) {
+    *REGISTERED.lock() = sink;
+}
+
+/// A dump in progress on this thread, so it can tell whether any of its own
+/// files weren't delivered. A dump started while another is in progress on the
+/// same thread, e.g. by a callback, gets its own scope, and its failures don't
+/// count towards the outer one; dumps on other threads have their own too.
+pub struct DumpScope {
+    outer_failures: u64,
+}
+
+impl DumpScope {
+    pub fn start() -> Self {
+        Self {
+            outer_failures: FAILURES.with(|failures| failures.replace(0)),
+        }
+    }
+
+    /// Whether the registered sink failed on any file written since start().
+    pub fn sink_failed(&self) -> bool {
+        FAILURES.with(|failures| failures.get()) > 0
+    }
+}
+
+impl Drop for DumpScope {
+    fn drop(&mut self) {
+        FAILURES.with(|failures| failures.set(self.outer_failures));
+    }
+}
+
+/// Write a file, whose contents come from the given writer, with the file
+/// sink and the registered one, if any.
+pub(crate) fn write_with<F>(path: &Path, write: F) -> io::Result<()>
+where
+    F: FnOnce(&mut dyn Write) -> io::Result<()>,
+{
+    // Copied, so registering doesn't wait for a slow callback:
+    let registered = *REGISTERED.lock();
+    write_to(registered, path, write)
+}
+
+fn write_to<F>(sink: Option<CallbackSink>, path: &Path, write: F) -> io::Result<()>
+where
+    F: FnOnce(&mut dyn Write) -> io::Result<()>,
+{
+    let Some(sink) = sink else {
+        return write_file_atomically_with(path, write);
+    };
+    let mut data = vec![];
+    write(&mut data)?;
+    let result = FileSink.write(path, &data);
+    // Reported via DumpScope rather than as an error, so it doesn't stop the
+    // rest of the report from being written:
+    if let Err(e) = sink.write(path, &data) {
+        eprintln!("=fil-profile= Output sink failed on {:?}: {}", path, e);
+        FAILURES.with(|failures| failures.set(failures.get() + 1));
+    }
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{format_of, write_to, CallbackSink, DumpScope, CHUNK_SIZE};
+    use parking_lot::Mutex;
+    use std::ffi::CStr;
+    use std::os::raw::{c_char, c_int, c_void};
+    use std::path::Path;
+
+    static RECEIVED: Mutex<Vec<(String, String, usize)>> = Mutex::new(vec![]);
+
+    extern "C" fn record(
+        format: *const c_char,
+        path: *const c_char,
+        _data: *const u8,
+        length: usize,
+        user_data: *mut c_void,
+    ) -> c_int {
+        let format = unsafe { CStr::from_ptr(format) }.to_string_lossy();
+        let path = unsafe { CStr::from_ptr(path) }.to_string_lossy();
+        let mut received = RECEIVED.lock();
+        received.push((format.into_owned(), path.into_owned(), length));
+        // Any user data means fail:
+        if user_data.is_null() {
+            0
+        } else {
+            1
+        }
+    }
+
+    #[test]
+    fn files_go_to_both_sinks() {
+        let directory = tempfile::tempdir().unwrap();
+        let path = directory.path().join("peak-memory.svg");
+        let tag = |length| {
+            (
+                "svg".to_string(),
+                path.to_string_lossy().into_owned(),
+                length,
+            )
+        };
+        let succeeding = Some(CallbackSink::new(record, std::ptr::null_mut()));
+        let data = vec![b'x'; CHUNK_SIZE + 10];
+        write_to(succeeding, &path, |writer| writer.write_all(&data)).unwrap();
+        assert_eq!(std::fs::read(&path).unwrap(), data);
+        assert_eq!(
+            std::mem::take(&mut *RECEIVED.lock()),
+            vec![tag(CHUNK_SIZE), tag(10), tag(0)]
+        );
+
+        // Failures are counted, and stop the rest of the file being passed,
+        // but the file is still written without error:
+        let failing = Some(CallbackSink::new(record, std::ptr::dangling_mut()));
+        let dump = DumpScope::start();
+        write_to(failing, &path, |writer| writer.write_all(b"new")).unwrap();
+        assert!(dump.sink_failed());
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), "new");
+        assert_eq!(std::mem::take(&mut *RECEIVED.lock()), vec![tag(3)]);
+        drop(dump);
+
+        let dump = DumpScope::start();
+        write_to(None, &path, |writer| writer.write_all(b"newer")).unwrap();
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), "newer");
+        assert!(RECEIVED.lock().is_empty());
+        assert!(!dump.sink_failed());
+    }
+
+    extern "C" fn fail(
+        _format: *const c_char,
+        _path: *const c_char,
+        _data: *const u8,
+        _length: usize,
+        _user_data: *mut c_void,
+    ) -> c_int {
+        1
+    }
+
+    #[test]
+    fn dump_scopes() {
+        let directory = tempfile::tempdir().unwrap();
+        let path = directory.path().join("peak-memory.svg");
+        let failing = Some(CallbackSink::new(fail, std::ptr::null_mut()));
+        let outer = DumpScope::start();
+        // A nested dump's failures are its own:
+        {
+            let inner = DumpScope::start();
+            write_to(failing, &path, |writer| writer.write_all(b"x")).unwrap();
+            assert!(inner.sink_failed());
+        }
+        assert!(!outer.sink_failed());
+        // And other threads' are theirs:
+        std::thread::spawn(move || {
+            let _dump = DumpScope::start();
+            write_to(failing, &path, |writer| writer.write_all(b"x")).unwrap();
+        })
+        .join()
+        .unwrap();
+        assert!(!outer.sink_failed());
+    }
+
+    #[test]
+    fn formats() {
+        assert_eq!(format_of(Path::new("/a/peak-memory.svg")), "svg");
+        assert_eq!(format_of(Path::new("/a.b/peak-memory.prof.gz")), "prof.gz");
+        assert_eq!(format_of(Path::new("/a/README")), "");
+    }
+}
@@ -0,0 +1,112 @@
+//! A sanity ceiling on the size of a single allocation. Nothing can allocate
+//! more than the address space, so a bigger size is a bug, e.g. a calloc()
+//! whose size overflowed, and recording it would make the totals and the peak
+//! meaningless. Such allocations are counted and otherwise ignored.
+//!
+//! The default is 128PiB, the most user space can address on x86-64 with
+//! 5-level paging; set FIL_MAX_ALLOCATION_SIZE to lower it, using the same
+//! sizes as FIL_MILESTONES.
+//!
+//! A failed allocation of a size under the ceiling still means Fil is out of
+//! memory, see filpreload's allocation_failed().
+
+use crate::milestones::parse_size;
+use serde::Serialize;
+
+/// The ceiling unless FIL_MAX_ALLOCATION_SIZE is set.
+pub const DEFAULT_MAX_ALLOCATION_SIZE: usize = 1 << 57;
+
+/// Written to `metadata.json` if any allocations were ignored.
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct RejectedAllocationsReport {
+    pub max_allocation_size: usize,
+    pub count: u64,
+    pub largest_bytes: usize,
+}
+
+impl RejectedAllocationsReport {
+    /// The line printed in the text output.
+    pub fn summary(&self) -> String {
+        format!(
+            "Ignored {} allocation(s) bigger than the maximum of {} bytes, the largest asking for {} bytes.",
+            self.count, self.max_allocation_size, self.largest_bytes
+        )
+    }
+}
+
+/// See the module documentation.
+pub struct SizeCeiling {
+    max_bytes: usize,
+    rejected: u64,
+    largest_rejected: usize,
+}
+
+impl SizeCeiling {
+    pub fn new(max_bytes: usize) -> Self {
+        Self {
+            max_bytes,
+            rejected: 0,
+            largest_rejected: 0,
+        }
+    }
+
+    /// Configure from FIL_MAX_ALLOCATION_SIZE; a value that can't be parsed is
+    /// warned about and ignored.
+    pub fn from_env() -> Self {
+        let max_bytes = match std::env::var("FIL_MAX_ALLOCATION_SIZE") {
+            Ok(value) => parse_size(&value).unwrap_or_else(|e| {
+                eprintln!("=fil-profile= Ignoring FIL_MAX_ALLOCATION_SIZE: {}", e);
+                DEFAULT_MAX_ALLOCATION_SIZE
+            }),
+            Err(_) => DEFAULT_MAX_ALLOCATION_SIZE,
+        };
+        Self::new(max_bytes)
+    }
+
+    /// Whether an allocation of the given size is plausible.
+    #[inline]
+    pub fn allows(&self, size: usize) -> bool {
+        size <= self.max_bytes
+    }
+
+    /// Count an allocation that wasn't allowed.
+    #[cold]
+    pub fn reject(&mut self, size: usize) {
+        self.rejected += 1;
+        self.largest_rejected = self.largest_rejected.max(size);
+    }
+
+    /// None if nothing was rejected.
+    pub fn report(&self) -> Option<RejectedAllocationsReport> {
+        (self.rejected > 0).then_some(RejectedAllocationsReport {
+            max_allocation_size: self.max_bytes,
+            count: self.rejected,
+            largest_bytes: self.largest_rejected,
+        })
+    }
+
+    /// Forget what was rejected, e.g. what a previous report covered.
+    pub fn reset(&mut self) {
+        self.rejected = 0;
+        self.largest_rejected = 0;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{SizeCeiling, DEFAULT_MAX_ALLOCATION_SIZE};
+
+    #[test]
+    fn counts_rejections() {
+        let mut ceiling = SizeCeiling::new(DEFAULT_MAX_ALLOCATION_SIZE);
+        assert!(ceiling.allows(DEFAULT_MAX_ALLOCATION_SIZE));
+        assert!(!ceiling.allows(usize::MAX));
+        assert_eq!(ceiling.report(), None);
+        ceiling.reject(usize::MAX);
+        ceiling.reject(usize::MAX / 2);
+        let report = ceiling.report().unwrap();
+        assert_eq!((report.count, report.largest_bytes), (2, usize::MAX));
+        ceiling.reset();
+        assert_eq!(ceiling.report(), None);
+    }
+}
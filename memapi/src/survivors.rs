@@ -0,0 +1,378 @@
+//! Memory that outlives reset(). When reset() is called between benchmark
+//! iterations, memory that leaks a little every iteration never shows up,
+//! since each iteration's report starts from scratch. Set FIL_SURVIVORS=N
+//! and allocations that are still live when reset() is called are added to
+//! totals per callstack and generation, i.e. the reset they were allocated
+//! before, instead of being forgotten. fil_dump_survivors() then writes
+//! `survivors.json` and `survivors.txt`, listing the callstacks with memory
+//! that's survived at least N resets, with bytes and counts per generation.
+//!
+//! Only those totals are kept here. To notice when survivors are freed their
+//! addresses are kept as the previous generation, see crate::generations,
+//! which isn't dropped on the next reset but carries them forward, and is
+//! still limited to MAX_RETAINED_ALLOCATIONS. If there are more survivors
+//! than that, they're all forgotten and counted as such in the report.
+//! Anonymous mmap()s aren't included.
+
+use crate::linecache::LineCacher;
+use crate::memorytracking::{Callstack, CallstackId, ReadFunctionLocations};
+use crate::util::{new_hashmap, write_atomically};
+use ahash::RandomState as ARandomState;
+use serde::Serialize;
+use std::collections::HashMap;
+use std::path::Path;
+
+/// How many callstacks to include in the report.
+const MAX_REPORTED_CALLSTACKS: usize = 50;
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize)]
+pub struct Tally {
+    pub count: usize,
+    pub bytes: usize,
+}
+
+/// Survivors from one callstack that were allocated in one generation.
+#[derive(Clone, Copy)]
+struct Bucket {
+    callstack_id: CallstackId,
+    generation: u64,
+    tally: Tally,
+}
+
+/// Totals of what survived reset(), see the module documentation.
+pub struct Survivors {
+    min_resets: u64,
+    // Indexed by bucket id, which is what the retained allocations have
+    // instead of their callstack id:
+    buckets: Vec<Bucket>,
+    ids: HashMap<(CallstackId, u64), CallstackId, ARandomState>,
+    // Ids of buckets that emptied out, to be reused:
+    unused_ids: Vec<CallstackId>,
+    // Survivors that were forgotten since there were too many:
+    forgotten: usize,
+}
+
+impl Survivors {
+    pub fn new(min_resets: u64) -> Self {
+        Self {
+            min_resets: min_resets.max(1),
+            buckets: vec![],
+            ids: new_hashmap(),
+            unused_ids: vec![],
+            forgotten: 0,
+        }
+    }
+
+    /// Configure from FIL_SURVIVORS, the number of resets to report survivors
+    /// of, if it's set; a value that can't be parsed is warned about and
+    /// ignored.
+    pub fn from_env() -> Option<Self> {
+        let value = std::env::var("FIL_SURVIVORS").ok()?;
+        match value.trim().parse() {
+            Ok(min_resets) if min_resets > 0 => Some(Self::new(min_resets)),
+            _ => {
+                eprintln!(
+                    "=fil-profile= Ignoring FIL_SURVIVORS={:?}, expected a number of resets",
+                    value
+                );
+                None
+            }
+        }
+    }
+
+    /// An allocation from the given callstack and generation survived a
+    /// reset. Returns the id of its bucket, for passing to free() later.
+    pub fn add(&mut self, callstack_id: CallstackId, generation: u64, bytes: usize) -> CallstackId {
+        let id = match self.ids.get(&(callstack_id, generation)) {
+            Some(id) => *id,
+            None => {
+                let bucket = Bucket {
+                    callstack_id,
+                    generation,
+                    tally: Tally::default(),
+                };
+                let id = match self.unused_ids.pop() {
+                    Some(id) => {
+                        self.buckets[id as usize] = bucket;
+                        id
+                    }
+                    None => {
+                        self.buckets.push(bucket);
+                        (self.buckets.len() - 1) as CallstackId
+                    }
+                };
+                self.ids.insert((callstack_id, generation), id);
+                id
+            }
+        };
+        let tally = &mut self.buckets[id as usize].tally;
+        tally.count += 1;
+        tally.bytes += bytes;
+        id
+    }
+
+    /// A survivor in the given bucket was freed.
+    pub fn free(&mut self, id: CallstackId, bytes: usize) {
+        let Some(bucket) = self.buckets.get_mut(id as usize) else {
+            return;
+        };
+        if bucket.tally.count == 0 {
+            return;
+        }
+        bucket.tally.count -= 1;
+        bucket.tally.bytes = bucket.tally.bytes.saturating_sub(bytes);
+        if bucket.tally.count == 0 {
+            self.ids.remove(&(bucket.callstack_id, bucket.generation));
+            self.unused_ids.push(id);
+        }
+    }
+
+    /// The survivors' addresses were dropped, so their frees can't be
+    /// matched anymore.
+    pub fn forget(&mut self) {
+        self.forgotten += self.live().count;
+        self.clear();
+    }
+
+    /// Start over, e.g. on shutdown.
+    pub fn clear(&mut self) {
+        self.buckets.clear();
+        self.ids.clear();
+        self.unused_ids.clear();
+    }
+
+    /// The total that's still live.
+    pub fn live(&self) -> Tally {
+        let mut total = Tally::default();
+        for bucket in &self.buckets {
+            total.count += bucket.tally.count;
+            total.bytes += bucket.tally.bytes;
+        }
+        total
+    }
+
+    /// Gather the data for the report, as of the given generation; resolving
+    /// callstacks into strings is done later by the returned closure, so it
+    /// can happen without locks held.
+    pub fn report<FL: ReadFunctionLocations>(
+        &self,
+        current_generation: u64,
+        id_to_callstack: &HashMap<CallstackId, &Callstack, ARandomState>,
+    ) -> impl FnOnce(&FL) -> SurvivorsReport {
+        let mut per_callstack: HashMap<CallstackId, Vec<GenerationSurvivors>, ARandomState> =
+            new_hashmap();
+        for bucket in &self.buckets {
+            if bucket.tally.count == 0 {
+                continue;
+            }
+            per_callstack
+                .entry(bucket.callstack_id)
+                .or_default()
+                .push(GenerationSurvivors {
+                    generation: bucket.generation,
+                    resets_survived: current_generation.saturating_sub(bucket.generation),
+                    count: bucket.tally.count,
+                    bytes: bucket.tally.bytes,
+                });
+        }
+        let min_resets = self.min_resets;
+        let mut top: Vec<(Callstack, Vec<GenerationSurvivors>, usize)> = per_callstack
+            .into_iter()
+            .filter_map(|(callstack_id, mut generations)| {
+                if !generations
+                    .iter()
+                    .any(|generation| generation.resets_survived >= min_resets)
+                {
+                    return None;
+                }
+                let old_bytes: usize = generations
+                    .iter()
+                    .filter(|generation| generation.resets_survived >= min_resets)
+                    .map(|generation| generation.bytes)
+                    .sum();
+                generations.sort_by_key(|generation| generation.generation);
+                let callstack = id_to_callstack.get(&callstack_id)?;
+                Some(((*callstack).clone(), generations, old_bytes))
+            })
+            .collect();
+        top.sort_by_key(|(_, generations, old_bytes)| {
+            std::cmp::Reverse((*old_bytes, generations.len()))
+        });
+        top.truncate(MAX_REPORTED_CALLSTACKS);
+        let forgotten = self.forgotten;
+        move |functions| {
+            let mut linecache = LineCacher::default();
+            SurvivorsReport {
+                min_resets,
+                resets: current_generation,
+                forgotten_allocations: forgotten,
+                callstacks: top
+                    .into_iter()
+                    .map(|(callstack, generations, _)| CallstackSurvivors {
+                        callstack: callstack.as_string(false, functions, ";", &mut linecache),
+                        count: generations.iter().map(|generation| generation.count).sum(),
+                        bytes: generations.iter().map(|generation| generation.bytes).sum(),
+                        generations,
+                    })
+                    .collect(),
+            }
+        }
+    }
+}
+
+/// What's still live of what one callstack allocated in one generation.
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct GenerationSurvivors {
+    /// How many resets there were before it was allocated.
+    pub generation: u64,
+    pub resets_survived: u64,
+    pub count: usize,
+    pub bytes: usize,
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct CallstackSurvivors {
+    pub callstack: String,
+    /// Of all generations, including those that haven't survived long enough
+    /// to be reported on their own.
+    pub count: usize,
+    pub bytes: usize,
+    /// Oldest first.
+    pub generations: Vec<GenerationSurvivors>,
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct SurvivorsReport {
+    /// Callstacks are listed if some of their memory survived this many
+    /// resets.
+    pub min_resets: u64,
+    /// How many resets there have been.
+    pub resets: u64,
+    /// Survivors that were forgotten since there were too many to keep
+    /// track of.
+    pub forgotten_allocations: usize,
+    /// Most bytes that survived min_resets first.
+    pub callstacks: Vec<CallstackSurvivors>,
+}
+
+impl SurvivorsReport {
+    /// A human-readable table.
+    pub fn to_table(&self) -> String {
+        let mut table = format!(
+            "Memory that survived at least {} of {} resets:\n",
+            self.min_resets, self.resets
+        );
+        if self.forgotten_allocations > 0 {
+            table.push_str(&format!(
+                "({} survivors were forgotten, since there were too many to track)\n",
+                self.forgotten_allocations
+            ));
+        }
+        table.push_str(&format!(
+            "\n{:>14} {:>10} {:>10}  callstack\n",
+            "bytes", "count", "resets"
+        ));
+        for cs in &self.callstacks {
+            table.push_str(&format!(
+                "{:>14} {:>10} {:>10}  {}\n",
+                cs.bytes, cs.count, "", cs.callstack
+            ));
+            for generation in &cs.generations {
+                table.push_str(&format!(
+                    "{:>14} {:>10} {:>10}\n",
+                    generation.bytes, generation.count, generation.resets_survived
+                ));
+            }
+        }
+        table
+    }
+
+    /// Write survivors.json and survivors.txt to the given directory.
+    pub fn write(&self, directory_path: &Path) -> std::io::Result<()> {
+        std::fs::create_dir_all(directory_path)?;
+        let json_path = directory_path.join("survivors.json");
+        let data = serde_json::to_vec_pretty(self)?;
+        write_atomically(&json_path, data)?;
+        write_atomically(&directory_path.join("survivors.txt"), self.to_table())?;
+        eprintln!(
+            "=fil-profile= Wrote memory that survived resets to {:?}",
+            json_path
+        );
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Survivors, Tally};
+    use crate::memorytracking::{
+        CallSiteId, Callstack, LineNumberInfo::LineNumber, VecFunctionLocations,
+    };
+    use crate::util::new_hashmap;
+
+    #[test]
+    fn tallies_and_frees() {
+        let mut survivors = Survivors::new(2);
+        let first = survivors.add(7, 0, 100);
+        assert_eq!(survivors.add(7, 0, 50), first);
+        let second = survivors.add(7, 1, 10);
+        assert_ne!(first, second);
+        assert_eq!(
+            survivors.live(),
+            Tally {
+                count: 3,
+                bytes: 160
+            }
+        );
+        survivors.free(second, 10);
+        // The emptied bucket's id is reused:
+        assert_eq!(survivors.add(8, 1, 20), second);
+        survivors.free(first, 100);
+        assert_eq!(
+            survivors.live(),
+            Tally {
+                count: 2,
+                bytes: 70
+            }
+        );
+        survivors.forget();
+        assert_eq!(survivors.live(), Tally::default());
+        assert_eq!(survivors.forgotten, 2);
+    }
+
+    #[test]
+    fn reports_old_enough_survivors() {
+        pyo3::prepare_freethreaded_python();
+        let mut functions = VecFunctionLocations::new();
+        let fid = functions.add_function("a".to_string(), "af".to_string());
+        let mut leaking = Callstack::new();
+        leaking.start_call(0, CallSiteId::new(fid, LineNumber(1)));
+        let mut young = Callstack::new();
+        young.start_call(0, CallSiteId::new(fid, LineNumber(2)));
+
+        let mut survivors = Survivors::new(2);
+        // Callstack 0 leaks 100 bytes every generation, callstack 1 only
+        // left something behind in the last one:
+        for generation in 0..4 {
+            survivors.add(0, generation, 100);
+        }
+        survivors.add(1, 3, 1000);
+        let mut id_to_callstack = new_hashmap();
+        id_to_callstack.insert(0, &leaking);
+        id_to_callstack.insert(1, &young);
+        let report = survivors.report(4, &id_to_callstack)(&functions);
+        assert_eq!(report.resets, 4);
+        assert_eq!(report.callstacks.len(), 1);
+        let cs = &report.callstacks[0];
+        assert_eq!(cs.callstack, "a:1 (af)");
+        assert_eq!((cs.count, cs.bytes), (4, 400));
+        assert_eq!(
+            cs.generations
+                .iter()
+                .map(|generation| generation.resets_survived)
+                .collect::<Vec<_>>(),
+            vec![4, 3, 2, 1]
+        );
+        assert!(report.to_table().contains("a:1 (af)"));
+    }
+}
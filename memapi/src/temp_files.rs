@@ -0,0 +1,339 @@
+//! Unlinked temporary files, e.g. dask's or a cache's spill files, which use
+//! memory for as long as their pages sit in the page cache.
+//!
+//! Opt-in, via FIL_TRACK_TEMP_FILES=1, and Linux only. A file counts once
+//! nothing else can see it: it was opened with O_TMPFILE, or created in the
+//! temporary directory ($TMPDIR, or /tmp) and then unlinked while still open.
+//! Its size is whatever ftruncate() or fallocate() last made it; data that's
+//! just written isn't counted. None of this is added to the peak. Instead the
+//! report summary gets a `[spilled to temp files]` line, as of when the most
+//! was spilled, with the callstacks that created the files.
+
+use crate::linecache::LineCacher;
+use crate::memorytracking::{Callstack, CallstackId, ReadFunctionLocations};
+use ahash::RandomState as ARandomState;
+use serde::Serialize;
+use std::collections::{BTreeMap, HashMap};
+use std::ffi::CStr;
+use std::os::raw::c_int;
+use std::path::{Path, PathBuf};
+
+/// A newly opened file that is, or may become, a temporary file.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum OpenedFile {
+    /// Opened with O_TMPFILE, so it never had a name.
+    Anonymous,
+    /// Created in the temporary directory; it counts once it's unlinked.
+    InTempDirectory(PathBuf),
+}
+
+struct TempFile {
+    // None once nothing else can see the file:
+    path: Option<PathBuf>,
+    callstack_id: CallstackId,
+    bytes: usize,
+}
+
+/// The open files that are, or may become, temporary files.
+#[derive(Default)]
+pub struct TempFiles {
+    // By file descriptor:
+    open: BTreeMap<c_int, TempFile>,
+    current_bytes: usize,
+    peak_bytes: usize,
+    // Bytes per creating callstack, as of peak_bytes:
+    peak_usage: BTreeMap<CallstackId, usize>,
+}
+
+impl TempFiles {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn opened(&mut self, fd: c_int, file: OpenedFile, callstack_id: CallstackId) {
+        // The descriptor may have been reused without us seeing the close(),
+        // e.g. via dup2():
+        self.closed(fd);
+        let path = match file {
+            OpenedFile::Anonymous => None,
+            OpenedFile::InTempDirectory(path) => Some(path),
+        };
+        self.open.insert(
+            fd,
+            TempFile {
+                path,
+                callstack_id,
+                bytes: 0,
+            },
+        );
+    }
+
+    /// The file at the given path was unlinked.
+    pub fn unlinked(&mut self, path: &Path) {
+        let mut added = 0;
+        for file in self.open.values_mut() {
+            if file.path.as_deref() == Some(path) {
+                file.path = None;
+                added += file.bytes;
+            }
+        }
+        self.add_bytes(added);
+    }
+
+    /// The file's size was set to the given number of bytes, or if grow_only
+    /// is set (for fallocate()), extended to at least that many.
+    pub fn resized(&mut self, fd: c_int, bytes: usize, grow_only: bool) {
+        let Some(file) = self.open.get_mut(&fd) else {
+            return;
+        };
+        let old_bytes = file.bytes;
+        file.bytes = if grow_only {
+            old_bytes.max(bytes)
+        } else {
+            bytes
+        };
+        if file.path.is_none() {
+            let new_bytes = file.bytes;
+            self.current_bytes -= old_bytes;
+            self.add_bytes(new_bytes);
+        }
+    }
+
+    /// The file descriptor was closed. Once it's unlinked the data is gone,
+    /// ignoring other descriptors for the same file.
+    pub fn closed(&mut self, fd: c_int) {
+        if let Some(file) = self.open.remove(&fd) {
+            if file.path.is_none() {
+                self.current_bytes -= file.bytes;
+            }
+        }
+    }
+
+    pub fn current_bytes(&self) -> usize {
+        self.current_bytes
+    }
+
+    fn add_bytes(&mut self, bytes: usize) {
+        self.current_bytes += bytes;
+        if self.current_bytes > self.peak_bytes {
+            // There are rarely more than a handful of temporary files, so a
+            // full snapshot is fine:
+            self.peak_bytes = self.current_bytes;
+            self.peak_usage.clear();
+            for file in self.open.values().filter(|file| file.path.is_none()) {
+                *self.peak_usage.entry(file.callstack_id).or_insert(0) += file.bytes;
+            }
+        }
+    }
+
+    /// Gather the data for the report; converting to text is left to the
+    /// returned closure, since that may need to call into Python.
+    pub fn report<FL: ReadFunctionLocations>(
+        &self,
+        id_to_callstack: &HashMap<CallstackId, &Callstack, ARandomState>,
+    ) -> impl FnOnce(&FL) -> TempFilesReport {
+        let usage: Vec<(Callstack, usize)> = self
+            .peak_usage
+            .iter()
+            .filter(|(_, bytes)| **bytes > 0)
+            .filter_map(|(callstack_id, bytes)| {
+                id_to_callstack
+                    .get(callstack_id)
+                    .map(|callstack| ((*callstack).clone(), *bytes))
+            })
+            .collect();
+        let peak_bytes = self.peak_bytes;
+        move |functions| {
+            let mut linecache = LineCacher::default();
+            let mut callstacks: Vec<TempFileCallstack> = usage
+                .into_iter()
+                .map(|(callstack, bytes)| TempFileCallstack {
+                    callstack: callstack.as_string(false, functions, ";", &mut linecache),
+                    innermost_frame: callstack.innermost_frame(functions),
+                    bytes,
+                })
+                .collect();
+            callstacks.sort_by(|a, b| {
+                b.bytes
+                    .cmp(&a.bytes)
+                    .then_with(|| a.callstack.cmp(&b.callstack))
+            });
+            TempFilesReport {
+                peak_bytes,
+                callstacks,
+            }
+        }
+    }
+}
+
+/// Bytes of temporary files created by one callstack.
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct TempFileCallstack {
+    /// The callstack, outermost frame first, separated by ";".
+    pub callstack: String,
+    /// Just the innermost frame.
+    pub innermost_frame: String,
+    pub bytes: usize,
+}
+
+/// The most that was spilled to temporary files at any one time, as written
+/// to `metadata.json`.
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct TempFilesReport {
+    pub peak_bytes: usize,
+    /// Largest first.
+    pub callstacks: Vec<TempFileCallstack>,
+}
+
+impl TempFilesReport {
+    /// The line printed in the text output.
+    pub fn summary(&self) -> String {
+        let mut summary = format!(
+            "[spilled to temp files] {:.1} MiB at most (not included in the peak)",
+            self.peak_bytes as f64 / (1024.0 * 1024.0)
+        );
+        if let Some(largest) = self.callstacks.first() {
+            summary.push_str(&format!(
+                ", largest from {} ({:.1} MiB)",
+                largest.innermost_frame,
+                largest.bytes as f64 / (1024.0 * 1024.0)
+            ));
+        }
+        summary
+    }
+}
+
+/// The temporary directory, $TMPDIR or /tmp, with symlinks resolved so it can
+/// be compared to the paths the kernel gives us.
+fn temp_directory() -> Option<PathBuf> {
+    let directory = std::env::var_os("TMPDIR")
+        .filter(|directory| !directory.is_empty())
+        .unwrap_or_else(|| "/tmp".into());
+    std::fs::canonicalize(directory).ok()
+}
+
+/// The path an open file descriptor refers to.
+fn fd_path(fd: c_int) -> Option<PathBuf> {
+    std::fs::read_link(format!("/proc/self/fd/{}", fd)).ok()
+}
+
+/// Whether a file the open() flags say is being created, and the descriptor
+/// it got, is a temporary file we should watch.
+#[cfg(target_os = "linux")]
+pub fn opened_file(fd: c_int, flags: c_int) -> Option<OpenedFile> {
+    if flags & libc::O_TMPFILE == libc::O_TMPFILE {
+        return Some(OpenedFile::Anonymous);
+    }
+    if flags & libc::O_CREAT == 0 {
+        return None;
+    }
+    let path = fd_path(fd)?;
+    if path.starts_with(temp_directory()?) {
+        Some(OpenedFile::InTempDirectory(path))
+    } else {
+        None
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn opened_file(_fd: c_int, _flags: c_int) -> Option<OpenedFile> {
+    None
+}
+
+/// The absolute path, with symlinks in the directory resolved, of a file that
+/// was just unlinked relative to the given directory descriptor (or AT_FDCWD).
+pub fn unlinked_path(directory_fd: c_int, path: &CStr) -> Option<PathBuf> {
+    use std::os::unix::ffi::OsStrExt;
+    let path = Path::new(std::ffi::OsStr::from_bytes(path.to_bytes()));
+    let path = if path.is_absolute() {
+        path.to_path_buf()
+    } else if directory_fd == libc::AT_FDCWD {
+        std::env::current_dir().ok()?.join(path)
+    } else {
+        fd_path(directory_fd)?.join(path)
+    };
+    let directory = std::fs::canonicalize(path.parent()?).ok()?;
+    Some(directory.join(path.file_name()?))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{opened_file, unlinked_path, OpenedFile, TempFiles};
+    use std::ffi::CString;
+    use std::os::unix::io::AsRawFd;
+    use std::path::PathBuf;
+
+    #[test]
+    fn only_unlinked_files_count() {
+        let mut files = TempFiles::new();
+        let path = PathBuf::from("/tmp/spill-1");
+        files.opened(3, OpenedFile::InTempDirectory(path.clone()), 1);
+        files.resized(3, 1000, false);
+        // Still visible, so it doesn't count yet:
+        assert_eq!(files.current_bytes(), 0);
+        files.unlinked(&path);
+        assert_eq!(files.current_bytes(), 1000);
+        // fallocate() only grows:
+        files.resized(3, 500, true);
+        assert_eq!(files.current_bytes(), 1000);
+        files.opened(4, OpenedFile::Anonymous, 2);
+        files.resized(4, 3000, false);
+        assert_eq!(files.current_bytes(), 4000);
+        // Shrinking doesn't lower the peak:
+        files.resized(4, 2000, false);
+        assert_eq!(files.current_bytes(), 3000);
+        files.closed(3);
+        files.closed(4);
+        assert_eq!(files.current_bytes(), 0);
+        assert_eq!(files.peak_bytes, 4000);
+        assert_eq!(
+            files.peak_usage,
+            [(1, 1000), (2, 3000)].into_iter().collect()
+        );
+        // Unknown descriptors are ignored:
+        files.resized(5, 100, false);
+        files.closed(5);
+        assert!(files.open.is_empty());
+    }
+
+    #[test]
+    fn reused_descriptor() {
+        let mut files = TempFiles::new();
+        files.opened(3, OpenedFile::Anonymous, 1);
+        files.resized(3, 1000, false);
+        // The close() was missed:
+        files.opened(3, OpenedFile::Anonymous, 2);
+        assert_eq!(files.current_bytes(), 0);
+    }
+
+    #[test]
+    fn paths() {
+        let directory = tempfile::tempdir().unwrap();
+        let path = directory.path().join("spill");
+        let file = std::fs::File::create(&path).unwrap();
+        let canonical = std::fs::canonicalize(&path).unwrap();
+        let spill = CString::new(path.to_str().unwrap()).unwrap();
+        assert_eq!(
+            unlinked_path(libc::AT_FDCWD, &spill),
+            Some(canonical.clone())
+        );
+        let dir = std::fs::File::open(directory.path()).unwrap();
+        assert_eq!(
+            unlinked_path(dir.as_raw_fd(), &CString::new("spill").unwrap()),
+            Some(canonical.clone())
+        );
+        // Not created, so not a temporary file whatever the directory:
+        assert_eq!(opened_file(file.as_raw_fd(), 0), None);
+        #[cfg(target_os = "linux")]
+        {
+            assert_eq!(
+                opened_file(file.as_raw_fd(), libc::O_CREAT),
+                Some(OpenedFile::InTempDirectory(canonical))
+            );
+            assert_eq!(
+                opened_file(file.as_raw_fd(), libc::O_TMPFILE),
+                Some(OpenedFile::Anonymous)
+            );
+        }
+    }
+}
@@ -0,0 +1,296 @@
+//! Per-thread memory statistics, for debugging situations like every worker
+//! thread holding its own copy of a model.
+//!
+//! Each thread keeps a running total of the tracked memory it allocated minus
+//! the tracked memory it freed, along with its high-water mark, in
+//! thread-local state. The totals are atomics only so dumps can read them from
+//! another thread; nothing is locked except when a thread first allocates and
+//! when it exits. Exited threads are moved to a list of retired threads, so
+//! they still show up in the report.
+//!
+//! Memory freed by a different thread than the one that allocated it counts
+//! against the thread that freed it, so a thread's total never goes below
+//! zero, but a thread that only hands memory off to others may look like it's
+//! holding on to it.
+
+use crate::util::{current_thread_id, write_atomically};
+use parking_lot::Mutex;
+use serde::Serialize;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::Instant;
+
+/// Only this many changes in the number of threads are kept.
+const MAX_THREAD_COUNT_CHANGES: usize = 10_000;
+
+/// One thread's running totals.
+struct ThreadStats {
+    thread_id: u64,
+    pthread: libc::pthread_t,
+    current_bytes: AtomicUsize,
+    peak_bytes: AtomicUsize,
+    allocations: AtomicU64,
+}
+
+impl ThreadStats {
+    fn summary(&self, name: Option<String>, exited: bool) -> ThreadSummary {
+        ThreadSummary {
+            thread_id: self.thread_id,
+            name,
+            peak_bytes: self.peak_bytes.load(Ordering::Relaxed),
+            current_bytes: self.current_bytes.load(Ordering::Relaxed),
+            allocations: self.allocations.load(Ordering::Relaxed),
+            exited,
+        }
+    }
+}
+
+/// A thread, as written to `threads.json`.
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct ThreadSummary {
+    pub thread_id: u64,
+    /// The OS-level thread name, if one was set.
+    pub name: Option<String>,
+    /// The most tracked memory the thread had allocated at once.
+    pub peak_bytes: usize,
+    /// At the time of the dump, or when the thread exited.
+    pub current_bytes: usize,
+    /// How many allocations the thread made.
+    pub allocations: u64,
+    /// Whether the thread had exited by the time of the dump.
+    pub exited: bool,
+}
+
+struct Registry {
+    started: Instant,
+    live: Vec<Arc<ThreadStats>>,
+    retired: Vec<ThreadSummary>,
+    max_live_threads: usize,
+    // (seconds since start, number of live threads):
+    thread_count: Vec<(f64, usize)>,
+}
+
+impl Registry {
+    fn thread_count_changed(&mut self) {
+        self.max_live_threads = self.max_live_threads.max(self.live.len());
+        if self.thread_count.len() < MAX_THREAD_COUNT_CHANGES {
+            self.thread_count
+                .push((self.started.elapsed().as_secs_f64(), self.live.len()));
+        }
+    }
+}
+
+lazy_static::lazy_static! {
+    static ref REGISTRY: Mutex<Registry> = Mutex::new(Registry {
+        started: Instant::now(),
+        live: vec![],
+        retired: vec![],
+        max_live_threads: 0,
+        thread_count: vec![],
+    });
+}
+
+/// Registers the thread on creation, and retires it when the thread exits.
+struct ThreadHandle(Arc<ThreadStats>);
+
+impl ThreadHandle {
+    fn register() -> Self {
+        let stats = Arc::new(ThreadStats {
+            thread_id: current_thread_id(),
+            pthread: unsafe { libc::pthread_self() },
+            current_bytes: AtomicUsize::new(0),
+            peak_bytes: AtomicUsize::new(0),
+            allocations: AtomicU64::new(0),
+        });
+        let mut registry = REGISTRY.lock();
+        registry.live.push(stats.clone());
+        registry.thread_count_changed();
+        Self(stats)
+    }
+}
+
+impl Drop for ThreadHandle {
+    fn drop(&mut self) {
+        let name = thread_name(self.0.pthread);
+        let mut registry = REGISTRY.lock();
+        registry.live.retain(|stats| !Arc::ptr_eq(stats, &self.0));
+        let summary = self.0.summary(name, true);
+        registry.retired.push(summary);
+        registry.thread_count_changed();
+    }
+}
+
+thread_local!(static THIS_THREAD: ThreadHandle = ThreadHandle::register());
+
+/// The name of the given thread, which must be alive.
+fn thread_name(thread: libc::pthread_t) -> Option<String> {
+    let mut buffer = [0 as libc::c_char; 64];
+    if unsafe { libc::pthread_getname_np(thread, buffer.as_mut_ptr(), buffer.len()) } != 0 {
+        return None;
+    }
+    let name = unsafe { std::ffi::CStr::from_ptr(buffer.as_ptr()) }.to_string_lossy();
+    if name.is_empty() {
+        None
+    } else {
+        Some(name.into_owned())
+    }
+}
+
+/// The current thread allocated some tracked memory.
+#[inline]
+pub fn record_allocation(bytes: usize) {
+    // Fails once the thread is exiting, at which point it's too late anyway:
+    let _ = THIS_THREAD.try_with(|thread| {
+        let stats = &thread.0;
+        stats.allocations.fetch_add(1, Ordering::Relaxed);
+        // Only this thread writes these, so there's no race:
+        let current = stats.current_bytes.load(Ordering::Relaxed) + bytes;
+        stats.current_bytes.store(current, Ordering::Relaxed);
+        if current > stats.peak_bytes.load(Ordering::Relaxed) {
+            stats.peak_bytes.store(current, Ordering::Relaxed);
+        }
+    });
+}
+
+/// The current thread freed some tracked memory.
+#[inline]
+pub fn record_free(bytes: usize) {
+    if bytes == 0 {
+        return;
+    }
+    let _ = THIS_THREAD.try_with(|thread| {
+        let stats = &thread.0;
+        let current = stats.current_bytes.load(Ordering::Relaxed);
+        stats
+            .current_bytes
+            .store(current.saturating_sub(bytes), Ordering::Relaxed);
+    });
+}
+
+/// Forget everything recorded so far, e.g. because tracking is restarting.
+/// Totals of threads that are running are zeroed, and exited threads are
+/// forgotten.
+pub fn reset() {
+    let mut registry = REGISTRY.lock();
+    for stats in &registry.live {
+        stats.current_bytes.store(0, Ordering::Relaxed);
+        stats.peak_bytes.store(0, Ordering::Relaxed);
+        stats.allocations.store(0, Ordering::Relaxed);
+    }
+    registry.retired.clear();
+    registry.max_live_threads = registry.live.len();
+    registry.thread_count.clear();
+    registry.thread_count_changed();
+}
+
+/// Gather the statistics of every thread, running or exited.
+pub fn report() -> ThreadsReport {
+    let registry = REGISTRY.lock();
+    // Exited threads can't be removed while the lock is held, so the live
+    // ones stay alive while their names are read:
+    let mut threads: Vec<ThreadSummary> = registry
+        .live
+        .iter()
+        .map(|stats| stats.summary(thread_name(stats.pthread), false))
+        .chain(registry.retired.iter().cloned())
+        .collect();
+    threads.sort_by_key(|thread| std::cmp::Reverse(thread.peak_bytes));
+    ThreadsReport {
+        threads,
+        max_live_threads: registry.max_live_threads,
+        thread_count: registry.thread_count.clone(),
+    }
+}
+
+#[derive(Debug, Serialize)]
+pub struct ThreadsReport {
+    /// Biggest peak first.
+    pub threads: Vec<ThreadSummary>,
+    /// The most threads that had allocated memory and were running at once.
+    pub max_live_threads: usize,
+    /// (seconds since tracking started, number of threads) every time the
+    /// number of threads changed.
+    pub thread_count: Vec<(f64, usize)>,
+}
+
+impl ThreadsReport {
+    /// A human-readable table.
+    pub fn to_table(&self) -> String {
+        let mut table = format!(
+            "At most {} threads were running at once.\n\n{:>10} {:>14} {:>14} {:>12}  name\n",
+            self.max_live_threads, "thread id", "peak bytes", "current bytes", "allocations"
+        );
+        for thread in &self.threads {
+            table.push_str(&format!(
+                "{:>10} {:>14} {:>14} {:>12}  {}{}\n",
+                thread.thread_id,
+                thread.peak_bytes,
+                thread.current_bytes,
+                thread.allocations,
+                thread.name.as_deref().unwrap_or("-"),
+                if thread.exited { " (exited)" } else { "" }
+            ));
+        }
+        table
+    }
+
+    /// Write threads.json and threads.txt to the given directory.
+    pub fn write(&self, directory_path: &std::path::Path) {
+        let json_path = directory_path.join("threads.json");
+        let result = serde_json::to_vec_pretty(self)
+            .map_err(std::io::Error::from)
+            .and_then(|data| write_atomically(&json_path, data))
+            .and_then(|_| write_atomically(&directory_path.join("threads.txt"), self.to_table()));
+        match result {
+            Ok(_) => eprintln!("=fil-profile= Wrote thread statistics to {:?}", json_path),
+            Err(e) => eprintln!("=fil-profile= Error writing thread statistics: {}", e),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{record_allocation, record_free, report};
+    use crate::util::current_thread_id;
+
+    #[test]
+    fn per_thread_peaks() {
+        let main_id = current_thread_id();
+        record_allocation(100);
+        let worker_id = std::thread::Builder::new()
+            .name("fil-worker".to_string())
+            .spawn(|| {
+                record_allocation(1000);
+                record_allocation(2000);
+                record_free(2500);
+                // Can't go negative:
+                record_free(2500);
+                current_thread_id()
+            })
+            .unwrap()
+            .join()
+            .unwrap();
+
+        let report = report();
+        let main = report
+            .threads
+            .iter()
+            .find(|thread| thread.thread_id == main_id)
+            .unwrap();
+        assert!(!main.exited);
+        assert!(main.peak_bytes >= 100);
+        // The worker exited, but still shows up:
+        let worker = report
+            .threads
+            .iter()
+            .find(|thread| thread.thread_id == worker_id)
+            .unwrap();
+        assert!(worker.exited);
+        assert_eq!(worker.name.as_deref(), Some("fil-worker"));
+        assert_eq!(worker.peak_bytes, 3000);
+        assert_eq!(worker.current_bytes, 0);
+        assert_eq!(worker.allocations, 2);
+        assert!(report.max_live_threads >= 2);
+        assert!(report.to_table().contains("fil-worker (exited)"));
+    }
+}
@@ -0,0 +1,537 @@
+//! Tracked memory over time, per top-level frame.
+//!
+//! Opt-in, via FIL_TIMELINE=1. A background thread periodically samples how
+//! many bytes are attributed to each root frame of the Python callstack, e.g.
+//! each top-level statement of a script. The number of stored samples is
+//! capped: once the cap is hit, adjacent samples are merged, halving the
+//! resolution.
+
+use crate::linecache::LineCacher;
+use crate::memorytracking::{Callstack, CallstackId, ReadFunctionLocations};
+use crate::util::{new_hashmap, write_atomically};
+use ahash::RandomState as ARandomState;
+use serde::Serialize;
+use std::collections::HashMap;
+use std::fmt::Write;
+use std::path::Path;
+use std::time::{Duration, Instant};
+
+/// How many frames at the start of a callstack identify its root. The root
+/// frame is only known once the runpy frames at the start are skipped, which
+/// requires Python, so we store a short prefix and figure out the root frame
+/// when writing the report.
+const ROOT_PREFIX_FRAMES: usize = 6;
+
+/// Maximum number of stored samples; must be even.
+const MAX_SAMPLES: usize = 2000;
+
+/// How many root frames get their own line in the report; the rest are lumped
+/// together.
+const MAX_REPORTED_ROOTS: usize = 10;
+
+const DEFAULT_INTERVAL: Duration = Duration::from_millis(100);
+
+/// Marks CallstackIds we don't know the root of.
+const UNKNOWN_ROOT: u32 = u32::MAX;
+
+#[derive(Clone, Debug, PartialEq)]
+struct Sample {
+    start_millis: u64,
+    end_millis: u64,
+    total_bytes: usize,
+    // (root index, bytes), only for roots with memory allocated:
+    bytes: Vec<(u32, usize)>,
+}
+
+impl Sample {
+    /// Merge with the following sample. We keep the larger of the two, so
+    /// that peaks don't get smoothed away.
+    fn merge(&mut self, next: Sample) {
+        let start_millis = self.start_millis;
+        if next.total_bytes > self.total_bytes {
+            *self = next;
+        } else {
+            self.end_millis = next.end_millis;
+        }
+        self.start_millis = start_millis;
+    }
+}
+
+/// Samples of memory usage per root frame.
+pub struct Timeline {
+    interval: Duration,
+    start: Instant,
+    // Indexed by CallstackId, index into roots:
+    callstack_roots: Vec<u32>,
+    roots: Vec<Callstack>,
+    root_ids: HashMap<Callstack, u32, ARandomState>,
+    samples: Vec<Sample>,
+    // How many raw samples each stored sample covers:
+    samples_per_point: usize,
+    // How many raw samples the last stored sample covers so far:
+    last_point_samples: usize,
+    // Reused between samples, indexed by root index:
+    scratch: Vec<usize>,
+}
+
+impl Timeline {
+    pub fn new(interval: Duration) -> Self {
+        Self {
+            interval,
+            start: Instant::now(),
+            callstack_roots: vec![],
+            roots: vec![],
+            root_ids: new_hashmap(),
+            samples: vec![],
+            samples_per_point: 1,
+            last_point_samples: 0,
+            scratch: vec![],
+        }
+    }
+
+    /// Create one if FIL_TIMELINE=1 is set. FIL_TIMELINE_INTERVAL_MS sets the
+    /// sampling interval.
+    pub fn from_env() -> Option<Self> {
+        if std::env::var("FIL_TIMELINE").as_deref() != Ok("1") {
+            return None;
+        }
+        let interval = std::env::var("FIL_TIMELINE_INTERVAL_MS")
+            .ok()
+            .and_then(|value| value.parse().ok())
+            .filter(|millis| *millis > 0)
+            .map(Duration::from_millis)
+            .unwrap_or(DEFAULT_INTERVAL);
+        Some(Self::new(interval))
+    }
+
+    /// How often samples should be taken.
+    pub fn interval(&self) -> Duration {
+        self.interval
+    }
+
+    /// Make sure we know the root of the given callstack.
+    pub fn add_callstack(&mut self, callstack_id: CallstackId, callstack: &Callstack) {
+        let index = callstack_id as usize;
+        if index < self.callstack_roots.len() && self.callstack_roots[index] != UNKNOWN_ROOT {
+            return;
+        }
+        let calls = callstack.to_vec();
+        let prefix = Callstack::from_vec(calls.into_iter().take(ROOT_PREFIX_FRAMES).collect());
+        let roots = &mut self.roots;
+        let root = *self.root_ids.entry(prefix).or_insert_with_key(|prefix| {
+            roots.push(prefix.clone());
+            (roots.len() - 1) as u32
+        });
+        if index >= self.callstack_roots.len() {
+            self.callstack_roots.resize(index + 1, UNKNOWN_ROOT);
+        }
+        self.callstack_roots[index] = root;
+    }
+
+    /// Record a sample, given current memory usage indexed by CallstackId.
+    pub fn sample<'a>(&mut self, current_memory_usage: impl Iterator<Item = &'a usize>) {
+        let now_millis = self.start.elapsed().as_millis() as u64;
+        self.sample_at(now_millis, current_memory_usage);
+    }
+
+    fn sample_at<'a>(
+        &mut self,
+        now_millis: u64,
+        current_memory_usage: impl Iterator<Item = &'a usize>,
+    ) {
+        self.scratch.clear();
+        self.scratch.resize(self.roots.len() + 1, 0);
+        // The extra final slot is for callstacks with unknown roots:
+        let unknown = self.roots.len();
+        for (index, bytes) in current_memory_usage.enumerate() {
+            if *bytes == 0 {
+                continue;
+            }
+            let root = match self.callstack_roots.get(index) {
+                Some(root) if *root != UNKNOWN_ROOT => *root as usize,
+                _ => unknown,
+            };
+            self.scratch[root] += bytes;
+        }
+        let bytes: Vec<(u32, usize)> = self
+            .scratch
+            .iter()
+            .enumerate()
+            .filter(|(_, bytes)| **bytes > 0)
+            .map(|(root, bytes)| (root as u32, *bytes))
+            .collect();
+        let start_millis = self
+            .samples
+            .last()
+            .map(|sample| sample.end_millis)
+            .unwrap_or(0);
+        self.record(Sample {
+            start_millis,
+            end_millis: now_millis,
+            total_bytes: bytes.iter().map(|(_, bytes)| bytes).sum(),
+            bytes,
+        });
+    }
+
+    fn record(&mut self, sample: Sample) {
+        if self.last_point_samples > 0 && self.last_point_samples < self.samples_per_point {
+            self.samples.last_mut().unwrap().merge(sample);
+            self.last_point_samples += 1;
+            return;
+        }
+        if self.samples.len() >= MAX_SAMPLES {
+            // Every stored sample is complete at this point, so we can merge
+            // them pairwise:
+            let samples = std::mem::take(&mut self.samples);
+            let mut samples = samples.into_iter();
+            while let Some(mut first) = samples.next() {
+                if let Some(second) = samples.next() {
+                    first.merge(second);
+                }
+                self.samples.push(first);
+            }
+            self.samples_per_point *= 2;
+        }
+        self.samples.push(sample);
+        self.last_point_samples = 1;
+    }
+
+    /// Start from scratch, e.g. after the tracker is reset. Known roots are
+    /// kept, since CallstackIds stay the same.
+    pub fn reset(&mut self) {
+        self.start = Instant::now();
+        self.samples.clear();
+        self.samples_per_point = 1;
+        self.last_point_samples = 0;
+    }
+
+    /// Gather the data for the report; resolving callstacks into strings is
+    /// done later by the returned closure, so it can happen without locks
+    /// held.
+    pub fn report<FL: ReadFunctionLocations>(&self) -> impl FnOnce(&FL) -> TimelineReport {
+        let roots = self.roots.clone();
+        let samples = self.samples.clone();
+        let interval_millis = self.interval.as_millis() as u64;
+        let samples_per_point = self.samples_per_point;
+        move |functions| {
+            // Different prefixes may well have the same root frame once runpy
+            // frames are skipped, so merge by label:
+            let mut linecache = LineCacher::default();
+            let mut labels: Vec<String> = vec![];
+            let mut label_ids: HashMap<String, usize, ARandomState> = new_hashmap();
+            let mut root_to_label: Vec<usize> = roots
+                .iter()
+                .map(|root| {
+                    let label = root
+                        .as_string(false, functions, "\n", &mut linecache)
+                        .lines()
+                        .next()
+                        .unwrap_or_default()
+                        .to_string();
+                    *label_ids.entry(label).or_insert_with_key(|label| {
+                        labels.push(label.clone());
+                        labels.len() - 1
+                    })
+                })
+                .collect();
+            // Unknown roots:
+            root_to_label.push(labels.len());
+            labels.push("[Unknown]".to_string());
+
+            let per_label = |sample: &Sample| {
+                let mut result = vec![0; labels.len()];
+                for (root, bytes) in &sample.bytes {
+                    result[root_to_label[*root as usize]] += bytes;
+                }
+                result
+            };
+
+            // Only keep the roots with the highest memory usage at any point:
+            let mut max_bytes = vec![0; labels.len()];
+            for sample in &samples {
+                for (label, bytes) in per_label(sample).into_iter().enumerate() {
+                    max_bytes[label] = max_bytes[label].max(bytes);
+                }
+            }
+            let mut ranked: Vec<usize> = (0..labels.len())
+                .filter(|label| max_bytes[*label] > 0)
+                .collect();
+            ranked.sort_by_key(|label| std::cmp::Reverse(max_bytes[*label]));
+            let other = ranked.len() > MAX_REPORTED_ROOTS;
+            ranked.truncate(MAX_REPORTED_ROOTS);
+            let mut reported_labels: Vec<String> =
+                ranked.iter().map(|label| labels[*label].clone()).collect();
+            if other {
+                reported_labels.push("[Other]".to_string());
+            }
+
+            let samples = samples
+                .iter()
+                .map(|sample| {
+                    let bytes = per_label(sample);
+                    let mut reported: Vec<usize> =
+                        ranked.iter().map(|label| bytes[*label]).collect();
+                    if other {
+                        reported.push(sample.total_bytes - reported.iter().sum::<usize>());
+                    }
+                    TimelineSample {
+                        start_millis: sample.start_millis,
+                        end_millis: sample.end_millis,
+                        total_bytes: sample.total_bytes,
+                        bytes: reported,
+                    }
+                })
+                .collect();
+            TimelineReport {
+                interval_millis,
+                samples_per_point,
+                roots: reported_labels,
+                samples,
+            }
+        }
+    }
+}
+
+#[derive(Debug, Serialize)]
+pub struct TimelineSample {
+    pub start_millis: u64,
+    pub end_millis: u64,
+    pub total_bytes: usize,
+    /// Bytes per root, in the same order as TimelineReport::roots.
+    pub bytes: Vec<usize>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct TimelineReport {
+    pub interval_millis: u64,
+    /// How many samples were merged into each reported sample.
+    pub samples_per_point: usize,
+    /// The root frames, largest first.
+    pub roots: Vec<String>,
+    pub samples: Vec<TimelineSample>,
+}
+
+const COLORS: [&str; MAX_REPORTED_ROOTS + 1] = [
+    "#4e79a7", "#f28e2b", "#e15759", "#76b7b2", "#59a14f", "#edc948", "#b07aa1", "#ff9da7",
+    "#9c755f", "#bab0ac", "#d3d3d3",
+];
+
+fn escape_html(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+impl TimelineReport {
+    /// A standalone HTML page with a stacked area chart.
+    pub fn to_html(&self) -> String {
+        const WIDTH: f64 = 1000.0;
+        const HEIGHT: f64 = 400.0;
+        let mib = |bytes: usize| bytes as f64 / (1024.0 * 1024.0);
+        let max_bytes = self
+            .samples
+            .iter()
+            .map(|sample| sample.total_bytes)
+            .max()
+            .unwrap_or(0)
+            .max(1);
+        let end_millis = self
+            .samples
+            .last()
+            .map(|sample| sample.end_millis)
+            .unwrap_or(0)
+            .max(1);
+        let x = |millis: u64| millis as f64 * WIDTH / end_millis as f64;
+        let y = |bytes: usize| HEIGHT - (bytes as f64 * HEIGHT / max_bytes as f64);
+
+        let mut chart = String::new();
+        if self.samples.is_empty() {
+            chart.push_str("<p>No samples were recorded.</p>");
+        } else {
+            // Each sample is drawn as a flat step covering its time range:
+            let xs: Vec<(f64, f64)> = self
+                .samples
+                .iter()
+                .map(|sample| (x(sample.start_millis), x(sample.end_millis)))
+                .collect();
+            let mut below = vec![0usize; self.samples.len()];
+            let _ = write!(
+                chart,
+                r#"<svg viewBox="0 0 {} {}" width="100%" preserveAspectRatio="none" style="border: 1px solid #ccc">"#,
+                WIDTH, HEIGHT
+            );
+            for (root, label) in self.roots.iter().enumerate() {
+                let above: Vec<usize> = below
+                    .iter()
+                    .zip(&self.samples)
+                    .map(|(below, sample)| below + sample.bytes[root])
+                    .collect();
+                let mut points = String::new();
+                for ((start, end), bytes) in xs.iter().zip(&above) {
+                    let _ = write!(
+                        points,
+                        "{:.1},{:.1} {:.1},{:.1} ",
+                        start,
+                        y(*bytes),
+                        end,
+                        y(*bytes)
+                    );
+                }
+                for ((start, end), bytes) in xs.iter().zip(&below).rev() {
+                    let _ = write!(
+                        points,
+                        "{:.1},{:.1} {:.1},{:.1} ",
+                        end,
+                        y(*bytes),
+                        start,
+                        y(*bytes)
+                    );
+                }
+                let _ = write!(
+                    chart,
+                    r#"<polygon points="{}" fill="{}"><title>{}</title></polygon>"#,
+                    points.trim_end(),
+                    COLORS[root % COLORS.len()],
+                    escape_html(label)
+                );
+                below = above;
+            }
+            chart.push_str("</svg>");
+            let _ = write!(
+                chart,
+                "<p>Y axis: 0 to {:.1} MiB. X axis: 0 to {:.1} seconds.</p>",
+                mib(max_bytes),
+                end_millis as f64 / 1000.0
+            );
+        }
+
+        let mut legend = String::from("<ul style=\"list-style: none\">");
+        for (root, label) in self.roots.iter().enumerate() {
+            let peak = self
+                .samples
+                .iter()
+                .map(|sample| sample.bytes[root])
+                .max()
+                .unwrap_or(0);
+            let _ = write!(
+                legend,
+                r#"<li><span style="background: {}">&nbsp;&nbsp;&nbsp;&nbsp;</span> <code>{}</code>: up to {:.1} MiB</li>"#,
+                COLORS[root % COLORS.len()],
+                escape_html(label),
+                mib(peak)
+            );
+        }
+        legend.push_str("</ul>");
+
+        format!(
+            r#"<!DOCTYPE html>
+<html>
+<head>
+  <meta charset="utf-8">
+  <title>Fil Memory Timeline</title>
+</head>
+<body style="font-family: sans-serif">
+<h1>Tracked memory over time, by top-level frame</h1>
+<p>Sampled every {} ms; each point covers {} sample(s), keeping the one with the most memory.</p>
+{}
+{}
+</body>
+</html>
+"#,
+            self.interval_millis, self.samples_per_point, chart, legend
+        )
+    }
+
+    /// Write timeline.json and timeline.html to the given directory.
+    pub fn write(&self, directory_path: &Path) {
+        let html_path = directory_path.join("timeline.html");
+        let result = serde_json::to_vec_pretty(self)
+            .map_err(std::io::Error::from)
+            .and_then(|data| write_atomically(&directory_path.join("timeline.json"), data))
+            .and_then(|_| write_atomically(&html_path, self.to_html()));
+        match result {
+            Ok(_) => eprintln!("=fil-profile= Wrote memory timeline to {:?}", html_path),
+            Err(e) => eprintln!("=fil-profile= Error writing memory timeline: {}", e),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Timeline, MAX_SAMPLES};
+    use crate::memorytracking::{
+        CallSiteId, Callstack, LineNumberInfo::LineNumber, VecFunctionLocations,
+    };
+    use std::time::Duration;
+
+    #[test]
+    fn samples_are_grouped_by_root() {
+        pyo3::prepare_freethreaded_python();
+        let mut functions = VecFunctionLocations::new();
+        let main = functions.add_function("main.py".to_string(), "<module>".to_string());
+        let f = functions.add_function("main.py".to_string(), "f".to_string());
+        let callstack = |root_line, nested| {
+            let mut cs = Callstack::new();
+            cs.start_call(0, CallSiteId::new(main, LineNumber(root_line)));
+            if nested {
+                cs.start_call(0, CallSiteId::new(f, LineNumber(100)));
+            }
+            cs
+        };
+        let mut timeline = Timeline::new(Duration::from_millis(10));
+        timeline.add_callstack(0, &callstack(1, false));
+        timeline.add_callstack(1, &callstack(1, true));
+        timeline.add_callstack(2, &callstack(2, true));
+        // CallstackId 3 is unknown.
+
+        timeline.sample_at(10, [100, 200, 0, 0].iter());
+        timeline.sample_at(20, [0, 0, 1000, 5].iter());
+        let report = timeline.report()(&functions);
+        assert_eq!(
+            report.roots,
+            vec!["main.py:2 (<module>)", "main.py:1 (<module>)", "[Unknown]"]
+        );
+        assert_eq!(report.samples.len(), 2);
+        assert_eq!(report.samples[0].bytes, vec![0, 300, 0]);
+        assert_eq!(report.samples[1].bytes, vec![1000, 0, 5]);
+        assert_eq!(report.samples[1].start_millis, 10);
+        assert_eq!(report.samples[1].end_millis, 20);
+        assert_eq!(report.samples[1].total_bytes, 1005);
+        let html = report.to_html();
+        assert!(html.contains("main.py:2 (&lt;module&gt;)"));
+        assert!(html.contains("<polygon"));
+
+        timeline.reset();
+        assert!(timeline.report()(&functions).samples.is_empty());
+    }
+
+    #[test]
+    fn samples_are_capped() {
+        let cs = Callstack::new();
+        let mut timeline = Timeline::new(Duration::from_millis(1));
+        timeline.add_callstack(0, &cs);
+        let total = MAX_SAMPLES * 3 + 1;
+        for i in 0..total {
+            // A spike in the middle shouldn't get merged away:
+            let bytes = if i == total / 2 { 1_000_000 } else { i };
+            timeline.sample_at(i as u64 + 1, [bytes].iter());
+        }
+        assert!(timeline.samples.len() <= MAX_SAMPLES);
+        assert_eq!(timeline.samples_per_point, 4);
+        assert_eq!(timeline.samples.first().unwrap().start_millis, 0);
+        assert_eq!(timeline.samples.last().unwrap().end_millis, total as u64);
+        // Samples are contiguous:
+        for pair in timeline.samples.windows(2) {
+            assert_eq!(pair[0].end_millis, pair[1].start_millis);
+        }
+        assert_eq!(
+            timeline
+                .samples
+                .iter()
+                .map(|sample| sample.total_bytes)
+                .max(),
+            Some(1_000_000)
+        );
+    }
+}
@@ -1,5 +1,8 @@
 use ahash::RandomState as ARandomState;
 use std::collections::HashMap;
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::path::{Path, PathBuf};
 
 lazy_static! {
     // If the PYTHONHASHSEED environment variable is set, we will use it as seed
@@ -19,13 +22,7 @@ lazy_static! {
 
 lazy_static! {
     pub static ref DEBUG_MODE: bool = match std::env::var("FIL_DEBUG") {
-        Ok(value) => {
-            if value == "1" {
-                true
-            } else {
-                false
-            }
-        }
+        Ok(value) => value == "1",
         _ => false,
     };
 }
@@ -39,3 +36,255 @@ pub fn new_hashmap<K, V>() -> HashMap<K, V, ARandomState> {
         None => HashMap::default(),
     }
 }
+
+/// Write to stderr with plain write() calls, ignoring errors. Unlike
+/// eprintln!(), this doesn't panic if stderr is closed.
+pub fn write_to_stderr(mut data: &[u8]) {
+    while !data.is_empty() {
+        let written = unsafe { libc::write(2, data.as_ptr() as *const libc::c_void, data.len()) };
+        if written <= 0 {
+            if written < 0
+                && std::io::Error::last_os_error().kind() == std::io::ErrorKind::Interrupted
+            {
+                continue;
+            }
+            return;
+        }
+        data = &data[written as usize..];
+    }
+}
+
+/// The most memory the process has ever had resident, in bytes, as reported
+/// by getrusage().
+pub fn peak_rss_bytes() -> Option<usize> {
+    let mut usage: libc::rusage = unsafe { std::mem::zeroed() };
+    if unsafe { libc::getrusage(libc::RUSAGE_SELF, &mut usage) } != 0 {
+        return None;
+    }
+    let max_rss = usage.ru_maxrss as usize;
+    // macOS reports bytes, Linux KiB:
+    if cfg!(target_os = "macos") {
+        Some(max_rss)
+    } else {
+        Some(max_rss * 1024)
+    }
+}
+
+/// The OS's id for the current thread, as returned by Python's
+/// threading.get_native_id(). Cached, since it's needed on every tracked
+/// allocation.
+pub fn current_thread_id() -> u64 {
+    thread_local!(static THREAD_ID: std::cell::Cell<u64> = const { std::cell::Cell::new(0) });
+    THREAD_ID.with(|cached| {
+        if cached.get() == 0 {
+            cached.set(os_thread_id());
+        }
+        cached.get()
+    })
+}
+
+#[cfg(target_os = "linux")]
+fn os_thread_id() -> u64 {
+    unsafe { libc::syscall(libc::SYS_gettid) as u64 }
+}
+
+#[cfg(target_os = "macos")]
+fn os_thread_id() -> u64 {
+    let mut id: u64 = 0;
+    unsafe { libc::pthread_threadid_np(0, &mut id) };
+    id
+}
+
+/// Suffix for report files that are still being written.
+const TEMPORARY_SUFFIX: &str = ".tmp";
+
+/// Extensions of the report files we write, so we only clean up our own
+/// temporary files.
+const REPORT_EXTENSIONS: &[&str] = &["prof", "svg", "json", "html", "txt", "gz"];
+
+fn temporary_path(path: &Path) -> PathBuf {
+    let mut name = path.file_name().unwrap_or_default().to_os_string();
+    name.push(TEMPORARY_SUFFIX);
+    path.with_file_name(name)
+}
+
+/// Write a file such that it's either fully written or not changed at all: we
+/// write to `<name>.tmp` in the same directory, and only once that's flushed
+/// to disk rename it into place. That way a process that gets killed mid-dump
+/// doesn't leave behind a truncated report.
+///
+/// If an output sink was registered the file is also handed to it, see
+/// crate::sinks.
+pub fn write_atomically_with<F>(path: &Path, write: F) -> std::io::Result<()>
+where
+    F: FnOnce(&mut dyn Write) -> std::io::Result<()>,
+{
+    crate::sinks::write_with(path, write)
+}
+
+/// Like write_atomically_with(), but only writing the file, i.e. the default
+/// sink.
+pub(crate) fn write_file_atomically_with<F>(path: &Path, write: F) -> std::io::Result<()>
+where
+    F: FnOnce(&mut dyn Write) -> std::io::Result<()>,
+{
+    let temporary_path = temporary_path(path);
+    let result = File::create(&temporary_path).and_then(|file| {
+        let mut writer = BufWriter::new(&file);
+        write(&mut writer)?;
+        writer.flush()?;
+        drop(writer);
+        file.sync_all()?;
+        std::fs::rename(&temporary_path, path)
+    });
+    if result.is_err() {
+        let _ = std::fs::remove_file(&temporary_path);
+    }
+    result
+}
+
+/// Like `std::fs::write()`, but atomic; see `write_atomically_with()`.
+pub fn write_atomically<D: AsRef<[u8]>>(path: &Path, data: D) -> std::io::Result<()> {
+    write_atomically_with(path, |writer| writer.write_all(data.as_ref()))
+}
+
+/// Remove temporary report files left behind by a previous run that didn't
+/// finish writing them.
+pub fn remove_stale_temporary_files(directory_path: &Path) {
+    let entries = match std::fs::read_dir(directory_path) {
+        Ok(entries) => entries,
+        Err(_) => return,
+    };
+    for entry in entries.flatten() {
+        let path = entry.path();
+        let is_stale = path
+            .file_name()
+            .and_then(|name| name.to_str())
+            .and_then(|name| name.strip_suffix(TEMPORARY_SUFFIX))
+            .and_then(|name| Path::new(name).extension())
+            .and_then(|extension| extension.to_str())
+            .map(|extension| REPORT_EXTENSIONS.contains(&extension))
+            .unwrap_or(false);
+        if is_stale {
+            let _ = std::fs::remove_file(path);
+        }
+    }
+}
+
+/// The local time, formatted for use in filenames, e.g. `20240311-142530`.
+pub fn timestamp_for_filename() -> String {
+    let mut buffer = [0u8; 32];
+    let length = unsafe {
+        let now = libc::time(std::ptr::null_mut());
+        let mut local: libc::tm = std::mem::zeroed();
+        libc::localtime_r(&now, &mut local);
+        libc::strftime(
+            buffer.as_mut_ptr() as *mut libc::c_char,
+            buffer.len(),
+            c"%Y%m%d-%H%M%S".as_ptr(),
+            &local,
+        )
+    };
+    String::from_utf8_lossy(&buffer[..length]).into_owned()
+}
+
+/// Create a new, uniquely named report directory inside `parent`, e.g.
+/// `fil-peak-20240311-142530-pid4242`, creating `parent` if necessary. If the
+/// name is already taken, e.g. by two dumps in the same second, a sequence
+/// number is appended.
+pub fn create_report_directory(parent: &Path, kind: &str) -> std::io::Result<PathBuf> {
+    let base_name = format!(
+        "fil-{}-{}-pid{}",
+        kind,
+        timestamp_for_filename(),
+        std::process::id()
+    );
+    create_numbered_directory(parent, &base_name)
+}
+
+fn create_numbered_directory(parent: &Path, base_name: &str) -> std::io::Result<PathBuf> {
+    std::fs::create_dir_all(parent)?;
+    let mut sequence = 0;
+    loop {
+        let name = if sequence == 0 {
+            base_name.to_string()
+        } else {
+            format!("{}-{}", base_name, sequence)
+        };
+        let path = parent.join(name);
+        // create_dir() fails if it already exists, so two dumps can't end up
+        // with the same directory:
+        match std::fs::create_dir(&path) {
+            Ok(()) => return Ok(path),
+            Err(e) if e.kind() == std::io::ErrorKind::AlreadyExists => sequence += 1,
+            Err(e) => return Err(e),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        create_numbered_directory, create_report_directory, remove_stale_temporary_files,
+        write_atomically, write_atomically_with,
+    };
+
+    #[test]
+    fn failed_write_leaves_previous_file_untouched() {
+        let directory = tempfile::tempdir().unwrap();
+        let path = directory.path().join("peak-memory.svg");
+        write_atomically(&path, "old report").unwrap();
+        let result = write_atomically_with(&path, |writer| {
+            writer.write_all(b"half of a new rep")?;
+            Err(std::io::Error::other("disk full"))
+        });
+        assert!(result.is_err());
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), "old report");
+        let files: Vec<_> = std::fs::read_dir(directory.path())
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name())
+            .collect();
+        assert_eq!(files, vec!["peak-memory.svg"]);
+
+        write_atomically(&path, "new report").unwrap();
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), "new report");
+    }
+
+    #[test]
+    fn stale_temporary_files_are_removed() {
+        let directory = tempfile::tempdir().unwrap();
+        for name in ["peak-memory.svg.tmp", "metadata.json.tmp", "user-data.tmp"] {
+            std::fs::write(directory.path().join(name), "").unwrap();
+        }
+        remove_stale_temporary_files(directory.path());
+        let files: Vec<_> = std::fs::read_dir(directory.path())
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name())
+            .collect();
+        assert_eq!(files, vec!["user-data.tmp"]);
+    }
+
+    #[test]
+    fn report_directories_dont_collide() {
+        let parent = tempfile::tempdir().unwrap();
+        let parent = parent.path().join("does/not/exist");
+        let first = create_numbered_directory(&parent, "fil-peak").unwrap();
+        let second = create_numbered_directory(&parent, "fil-peak").unwrap();
+        let third = create_numbered_directory(&parent, "fil-peak").unwrap();
+        assert_eq!(first, parent.join("fil-peak"));
+        assert_eq!(second, parent.join("fil-peak-1"));
+        assert_eq!(third, parent.join("fil-peak-2"));
+        assert!(third.is_dir());
+
+        let auto = create_report_directory(&parent, "peak").unwrap();
+        let name = auto.file_name().unwrap().to_str().unwrap().to_string();
+        assert!(name.starts_with("fil-peak-"), "{}", name);
+        assert!(
+            name.ends_with(&format!("-pid{}", std::process::id())),
+            "{}",
+            name
+        );
+        // fil-peak-YYYYMMDD-HHMMSS-pidN:
+        assert_eq!(name.split('-').count(), 5, "{}", name);
+    }
+}
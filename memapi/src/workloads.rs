@@ -0,0 +1,320 @@
+//! Representative workloads for measuring the tracker's own overhead, shared
+//! by the `tracker` benchmark and the `fil-bench` binary so both measure the
+//! same thing.
+//!
+//! They drive the tracker the way filpreload's allocation hooks do: each
+//! thread keeps its own Callstack, pushing and popping frames without taking
+//! any lock, and every allocation and free takes the one tracker lock and
+//! calls the same AllocationTracker::record_allocation() and record_free() as
+//! the hooks. Dumps gather a PeakReport under the lock and write it outside
+//! it, like filpreload's. Only built for tests and with the `bench` feature.
+//!
+//! Python isn't involved, so registering functions isn't measured and
+//! flamegraphs are rendered without source code, but Python still needs to be
+//! initialized for crate::linecache.
+
+use crate::memorytracking::{
+    AllocationKind, AllocationTracker, CallSiteId, Callstack, FunctionId, LineNumberInfo,
+    VecFunctionLocations,
+};
+use parking_lot::Mutex;
+use std::convert::Infallible;
+use std::path::{Path, PathBuf};
+
+/// Allocations per thread per round of the churn-style workloads.
+const ROUND_OPERATIONS: usize = 10_000;
+/// How many of them to keep live while churning.
+const CHURN_LIVE: usize = 1_000;
+/// Threads in the contention workload.
+const THREADS: usize = 16;
+/// How deep the deep-callstacks workload goes.
+const DEPTH: usize = 100;
+/// Unique callstacks in the dump workload.
+const DUMP_CALLSTACKS: usize = 1_000_000;
+
+/// Where a thread's made-up addresses start, so threads don't overlap.
+fn base_address(thread: usize) -> usize {
+    0x7f00_0000_0000 + (thread << 32)
+}
+
+/// Which workload to run.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Workload {
+    /// Single-threaded malloc()/free() churn, a window of live allocations.
+    Churn,
+    /// The same churn on 16 threads at once, all contending for the lock.
+    Contention,
+    /// Pushing and popping 100 frames, allocating along the way.
+    DeepCallstacks,
+    /// Memory that keeps climbing, so almost every allocation is a new peak.
+    PeakClimb,
+    /// Dumping the peak with 1M unique callstacks, of which the largest 10,000
+    /// end up in the flamegraph.
+    Dump,
+}
+
+impl Workload {
+    pub const ALL: [Workload; 5] = [
+        Workload::Churn,
+        Workload::Contention,
+        Workload::DeepCallstacks,
+        Workload::PeakClimb,
+        Workload::Dump,
+    ];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Workload::Churn => "churn",
+            Workload::Contention => "contention",
+            Workload::DeepCallstacks => "deep-callstacks",
+            Workload::PeakClimb => "peak-climb",
+            Workload::Dump => "dump",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<Self> {
+        Self::ALL
+            .into_iter()
+            .find(|workload| workload.name() == name)
+    }
+
+    /// What run_round() counts as an operation.
+    pub fn operation_name(self) -> &'static str {
+        match self {
+            Workload::Dump => "callstacks combined",
+            Workload::DeepCallstacks => "calls, returns, allocations and frees",
+            _ => "allocations and frees",
+        }
+    }
+}
+
+/// A tracker set up for a workload, behind a lock like filpreload's.
+pub struct Bench {
+    workload: Workload,
+    tracker: Mutex<AllocationTracker<VecFunctionLocations>>,
+    // Outermost first, DEPTH of them:
+    functions: Vec<FunctionId>,
+    directory: PathBuf,
+}
+
+impl Bench {
+    /// Set up the workload, configured by the environment like Fil itself.
+    /// Dumps and resets use the given directory.
+    pub fn new(workload: Workload, directory: &Path) -> Self {
+        let mut tracker = AllocationTracker::new(
+            directory.to_string_lossy().into_owned(),
+            VecFunctionLocations::new(),
+        );
+        let functions = (0..DEPTH)
+            .map(|i| {
+                tracker
+                    .functions
+                    .add_function("workload.py".to_string(), format!("function{}", i))
+            })
+            .collect();
+        let bench = Self {
+            workload,
+            tracker: Mutex::new(tracker),
+            functions,
+            directory: directory.to_path_buf(),
+        };
+        if workload == Workload::Dump {
+            bench.add_unique_callstacks();
+        }
+        bench
+    }
+
+    /// Roughly how much memory the tracker's own tables use.
+    pub fn footprint_bytes(&self) -> usize {
+        self.tracker.lock().footprint_bytes()
+    }
+
+    /// Run one round of the workload, returning how many operations it did.
+    pub fn run_round(&self) -> u64 {
+        match self.workload {
+            Workload::Churn => self.churn(0),
+            Workload::Contention => std::thread::scope(|scope| {
+                let threads: Vec<_> = (0..THREADS)
+                    .map(|thread| scope.spawn(move || self.churn(thread)))
+                    .collect();
+                threads
+                    .into_iter()
+                    .map(|thread| thread.join().unwrap())
+                    .sum()
+            }),
+            Workload::DeepCallstacks => self.deep_callstacks(),
+            Workload::PeakClimb => self.peak_climb(),
+            Workload::Dump => self.dump(),
+        }
+    }
+
+    /// A callstack a few frames deep, as a thread would have.
+    fn shallow_callstack(&self) -> Callstack {
+        let mut callstack = Callstack::new();
+        for (i, function) in self.functions[..3].iter().enumerate() {
+            callstack.start_call(
+                i as u32,
+                CallSiteId::new(*function, LineNumberInfo::LineNumber(1)),
+            );
+        }
+        callstack
+    }
+
+    /// Like filpreload's add_allocation() for malloc().
+    fn allocate(&self, callstack: &mut Callstack, address: usize, size: usize, line_number: u32) {
+        let _ = self.tracker.lock().record_allocation(
+            address,
+            size,
+            AllocationKind::Malloc,
+            |tracker| {
+                Ok::<_, Infallible>(tracker.callstack_id_for_allocation(callstack, line_number))
+            },
+        );
+    }
+
+    /// Like filpreload's free_allocation().
+    fn free(&self, address: usize) {
+        self.tracker.lock().record_free(address);
+    }
+
+    /// Allocate from a few lines in turn, keeping the last CHURN_LIVE live,
+    /// then free the rest.
+    fn churn(&self, thread: usize) -> u64 {
+        let mut callstack = self.shallow_callstack();
+        let address = |i: usize| base_address(thread) + i * 64;
+        for i in 0..ROUND_OPERATIONS {
+            // Loops tend to allocate from the same line a few times in a row:
+            let line_number = ((i / 8) % 10) as u32 + 1;
+            self.allocate(&mut callstack, address(i), 16 + (i % 7) * 100, line_number);
+            if i >= CHURN_LIVE {
+                self.free(address(i - CHURN_LIVE));
+            }
+        }
+        for i in ROUND_OPERATIONS - CHURN_LIVE..ROUND_OPERATIONS {
+            self.free(address(i));
+        }
+        2 * ROUND_OPERATIONS as u64
+    }
+
+    /// Call DEPTH functions deep and return again, allocating at every tenth
+    /// level on the way down and freeing on the way up.
+    fn deep_callstacks(&self) -> u64 {
+        let mut callstack = Callstack::new();
+        let mut operations = 0;
+        for _ in 0..(ROUND_OPERATIONS / DEPTH) {
+            for (depth, function) in self.functions.iter().enumerate() {
+                callstack.start_call(
+                    depth as u32,
+                    CallSiteId::new(*function, LineNumberInfo::LineNumber(1)),
+                );
+                if depth % 10 == 0 {
+                    self.allocate(&mut callstack, base_address(0) + depth * 64, 1000, 2);
+                    operations += 1;
+                }
+            }
+            for depth in (0..DEPTH).rev() {
+                if depth % 10 == 0 {
+                    self.free(base_address(0) + depth * 64);
+                    operations += 1;
+                }
+                callstack.finish_call();
+            }
+            operations += 2 * DEPTH as u64;
+        }
+        operations
+    }
+
+    /// Start over, then keep allocating with a little churn along the way, so
+    /// the peak keeps moving, and finally free everything.
+    fn peak_climb(&self) -> u64 {
+        self.tracker
+            .lock()
+            .reset(self.directory.to_string_lossy().into_owned());
+        let mut callstack = self.shallow_callstack();
+        let address = |i: usize| base_address(0) + i * 128;
+        let mut operations = 0;
+        for i in 0..ROUND_OPERATIONS {
+            let line_number = (i % 10) as u32 + 1;
+            self.allocate(&mut callstack, address(i), 4096, line_number);
+            operations += 1;
+            // Reallocating the previous allocation from another line:
+            if i % 4 == 0 && i > 0 {
+                self.free(address(i - 1));
+                self.allocate(&mut callstack, address(i - 1), 4096, line_number + 1);
+                operations += 2;
+            }
+        }
+        for i in 0..ROUND_OPERATIONS {
+            self.free(address(i));
+        }
+        operations + ROUND_OPERATIONS as u64
+    }
+
+    /// Give each of DUMP_CALLSTACKS callstacks a live allocation.
+    fn add_unique_callstacks(&self) {
+        let mut callstack = self.shallow_callstack();
+        for i in 0..DUMP_CALLSTACKS {
+            // Each i gets its own combination of the line calling the
+            // innermost function, which function that is, and the line in it
+            // that allocates:
+            let calling_line = (i % 1000) as u32;
+            let function = self.functions[3 + (i / 1000) % (DEPTH - 3)];
+            let allocating_line = (i / (1000 * (DEPTH - 3))) as u32;
+            callstack.start_call(
+                calling_line,
+                CallSiteId::new(function, LineNumberInfo::LineNumber(0)),
+            );
+            self.allocate(
+                &mut callstack,
+                base_address(0) + i * 64,
+                1000,
+                allocating_line,
+            );
+            callstack.finish_call();
+        }
+    }
+
+    /// Like filpreload's dump_to_flamegraph() for the peak: gather the report
+    /// under the lock, then write it without it.
+    fn dump(&self) -> u64 {
+        let report = self.tracker.lock().peak_report(true);
+        report.write(
+            &self.directory,
+            "peak-memory",
+            "Peak Tracked Memory Usage",
+            false,
+        );
+        DUMP_CALLSTACKS as u64
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Bench, Workload};
+
+    #[test]
+    fn names() {
+        for workload in Workload::ALL {
+            assert_eq!(Workload::from_name(workload.name()), Some(workload));
+        }
+        assert_eq!(Workload::from_name("other"), None);
+    }
+
+    #[test]
+    fn rounds_leave_nothing_behind() {
+        let directory = tempfile::tempdir().unwrap();
+        for workload in [
+            Workload::Churn,
+            Workload::Contention,
+            Workload::DeepCallstacks,
+            Workload::PeakClimb,
+        ] {
+            let bench = Bench::new(workload, directory.path());
+            assert!(bench.run_round() > 0);
+            assert!(bench.run_round() > 0);
+            let tracker = bench.tracker.lock();
+            assert_eq!(tracker.get_current_allocated_bytes(), 0, "{:?}", workload);
+            assert!(tracker.get_peak_allocated_bytes() > 0, "{:?}", workload);
+        }
+    }
+}
@@ -17,7 +17,7 @@ setup(
         "console_scripts": ["fil-profile=filprofiler._script:stage_1"],
     },
     package_data={
-        "filprofiler": ["licenses.txt"],
+        "filprofiler": ["licenses.txt", "fil.h"],
     },
     data_files=[
         (
@@ -31,7 +31,15 @@ setup(
             path="filpreload/Cargo.toml",
             debug=False,
             binding=Binding.PyO3,
-        )
+            features=["arrow-export"],
+        ),
+        # For profiling without LD_PRELOAD:
+        RustExtension(
+            "filprofiler._filprofiler_api",
+            path="filapi/Cargo.toml",
+            debug=False,
+            binding=Binding.PyO3,
+        ),
     ],
     use_scm_version=True,
     install_requires=["threadpoolctl"],
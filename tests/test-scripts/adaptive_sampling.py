@@ -0,0 +1,17 @@
+"""Create lots of small live allocations, enough to trigger adaptive sampling
+when run with a low FIL_ADAPTIVE_HIGH_WATER."""
+
+import ctypes
+
+libc = ctypes.CDLL(None)
+libc.malloc.restype = ctypes.c_void_p
+libc.free.argtypes = [ctypes.c_void_p]
+
+
+def make_small_allocations():
+    return [libc.malloc(100) for _ in range(200_000)]
+
+
+pointers = make_small_allocations()
+for pointer in pointers:
+    libc.free(pointer)
@@ -0,0 +1,18 @@
+"""One function allocates constantly, another allocates a lot but rarely."""
+
+import time
+
+
+def storm():
+    for _ in range(200_000):
+        _ = b"x" * 1000
+
+
+def big():
+    data = bytearray(50_000_000)
+    time.sleep(0.2)
+    return data
+
+
+storm()
+big()
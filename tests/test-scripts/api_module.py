@@ -0,0 +1,37 @@
+"""
+Profile without LD_PRELOAD using the _filprofiler_api module, calling the
+hook functions like a native hook layer would; for test_api_module.
+"""
+
+import ctypes
+import os
+import sys
+
+from filprofiler import _filprofiler_api as api
+
+hooks = ctypes.CDLL(api.__file__, mode=os.RTLD_NOW | os.RTLD_NOLOAD)
+hooks.filprofiler_api_start_call.argtypes = [
+    ctypes.c_uint64,
+    ctypes.c_uint32,
+    ctypes.c_uint32,
+]
+hooks.filprofiler_api_add_allocation.argtypes = [
+    ctypes.c_size_t,
+    ctypes.c_size_t,
+    ctypes.c_uint32,
+]
+hooks.filprofiler_api_free_allocation.argtypes = [ctypes.c_size_t]
+
+api.reset(sys.argv[1])
+outer = api.add_function("outer.py", "outer")
+inner = api.add_function("inner.py", "inner")
+api.start()
+hooks.filprofiler_api_start_call(outer, 0, 3)
+hooks.filprofiler_api_add_allocation(0x1000, 3000, 4)
+hooks.filprofiler_api_start_call(inner, 5, 7)
+hooks.filprofiler_api_add_allocation(0x2000, 2000, 8)
+hooks.filprofiler_api_finish_call()
+hooks.filprofiler_api_free_allocation(0x1000)
+hooks.filprofiler_api_finish_call()
+api.stop()
+api.dump_peak()
@@ -0,0 +1,30 @@
+/* An example of an extension with its own arena, reporting its allocations to
+ * Fil with the API in filprofiler/fil.h. The arena's memory is a static
+ * buffer, so Fil wouldn't see it otherwise. */
+
+#include "fil.h"
+#include <string.h>
+
+#define ARENA_SIZE (64 * 1024 * 1024)
+
+static char arena[ARENA_SIZE];
+static size_t arena_used = 0;
+static fil_api fil;
+
+int arena_init(void) { return fil_api_load(&fil); }
+
+void *arena_allocate(const char *context, size_t size) {
+  if (arena_used + size > ARENA_SIZE) {
+    return NULL;
+  }
+  fil.push_context(context);
+  void *result = arena + arena_used;
+  arena_used += size;
+  memset(result, 1, size);
+  fil.report_allocation("arena_extension", result, size);
+  fil.pop_context();
+  return result;
+}
+
+/* The arena doesn't reuse memory, it's just for show. */
+void arena_free(void *address) { fil.report_free("arena_extension", address); }
@@ -0,0 +1,23 @@
+"""Allocate memory from an extension's own arena, which reports it to Fil."""
+
+import ctypes
+import os
+
+C_CODE = ctypes.CDLL(os.path.join(os.path.dirname(__file__), "arena_extension.so"))
+C_CODE.arena_allocate.restype = ctypes.c_void_p
+C_CODE.arena_allocate.argtypes = [ctypes.c_char_p, ctypes.c_size_t]
+C_CODE.arena_free.argtypes = [ctypes.c_void_p]
+
+print("Fil API version", C_CODE.arena_init())
+
+
+def load_table():
+    return C_CODE.arena_allocate(b"load_table", 30 * 1024 * 1024)
+
+
+def scratch():
+    C_CODE.arena_free(C_CODE.arena_allocate(b"scratch", 20 * 1024 * 1024))
+
+
+scratch()
+table = load_table()
@@ -0,0 +1,21 @@
+"""
+Exercise the wrapped APIs, for test_bootstrap_failures; run with Fil
+preloaded but not profiling.
+"""
+
+import mmap
+import os
+import tempfile
+import threading
+
+data = bytearray(10 * 1024 * 1024)
+mapped = mmap.mmap(-1, 1024 * 1024)
+thread = threading.Thread(target=lambda: bytearray(1024))
+thread.start()
+thread.join()
+with tempfile.TemporaryFile() as f:
+    f.truncate(1024 * 1024)
+pid = os.fork()
+if pid == 0:
+    os._exit(0)
+os.waitpid(pid, 0)
@@ -0,0 +1,42 @@
+"""Allocate memory with mimalloc's own API, via a C library."""
+
+import ctypes
+import os
+
+C_CODE = ctypes.CDLL(os.path.join(os.path.dirname(__file__), "mimalloc_user.so"))
+C_CODE.mimalloc_allocate.restype = ctypes.c_void_p
+C_CODE.mimalloc_allocate.argtypes = [ctypes.c_size_t]
+C_CODE.mimalloc_reallocate.restype = ctypes.c_void_p
+C_CODE.mimalloc_reallocate.argtypes = [ctypes.c_void_p, ctypes.c_size_t]
+C_CODE.mimalloc_free.argtypes = [ctypes.c_void_p]
+C_CODE.mimalloc_allocate_aligned.restype = ctypes.c_void_p
+C_CODE.mimalloc_allocate_aligned.argtypes = [ctypes.c_size_t]
+C_CODE.mimalloc_reallocate_aligned.restype = ctypes.c_void_p
+C_CODE.mimalloc_reallocate_aligned.argtypes = [ctypes.c_void_p, ctypes.c_size_t]
+C_CODE.mimalloc_free_aligned.argtypes = [ctypes.c_void_p]
+
+
+def kept():
+    return C_CODE.mimalloc_allocate(30 * 1024 * 1024)
+
+
+def freed():
+    address = C_CODE.mimalloc_allocate(10 * 1024 * 1024)
+    address = C_CODE.mimalloc_reallocate(address, 20 * 1024 * 1024)
+    C_CODE.mimalloc_free(address)
+
+
+def kept_aligned():
+    address = C_CODE.mimalloc_allocate_aligned(5 * 1024 * 1024)
+    return C_CODE.mimalloc_reallocate_aligned(address, 15 * 1024 * 1024)
+
+
+def freed_aligned():
+    address = C_CODE.mimalloc_allocate_aligned(10 * 1024 * 1024)
+    C_CODE.mimalloc_free_aligned(address)
+
+
+live = kept()
+freed()
+live_aligned = kept_aligned()
+freed_aligned()
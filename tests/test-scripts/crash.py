@@ -0,0 +1,22 @@
+"""Allocate some memory, then segfault, for FIL_CRASH_HANDLER=1."""
+
+import ctypes
+import tempfile
+import time
+
+libc = ctypes.CDLL(None)
+libc.malloc.restype = ctypes.c_void_p
+
+
+def big():
+    return libc.malloc(50_000_000)
+
+
+pointer = big()
+# With FIL_TRACK_TEMP_FILES=1, the snapshot file mustn't be tracked like this
+# one, since that takes the tracker lock:
+temporary = tempfile.TemporaryFile()
+temporary.write(b"x" * 1000)
+# Give the crash snapshot a chance to be taken:
+time.sleep(2)
+ctypes.string_at(0)
@@ -0,0 +1,18 @@
+"""Allocate memory behind Fil's back, for test_drift_check."""
+
+import ctypes
+import sys
+import time
+
+# Wait for the baseline sample:
+time.sleep(0.5)
+if sys.argv[1:] == ["untracked"]:
+    # glibc's own malloc(), which Fil doesn't intercept:
+    libc_malloc = ctypes.CDLL(None).__libc_malloc
+    libc_malloc.restype = ctypes.c_void_p
+    libc_malloc.argtypes = [ctypes.c_size_t]
+    untracked = [libc_malloc(10 * 1024 * 1024) for _ in range(30)]
+    assert all(untracked)
+else:
+    tracked = bytearray(300 * 1024 * 1024)
+time.sleep(1)
@@ -0,0 +1,6 @@
+"""Attach tags to the report."""
+
+from filprofiler.api import add_metadata
+
+add_metadata("git_commit", "abc123")
+data = bytearray(10_000_000)
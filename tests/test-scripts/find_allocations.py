@@ -0,0 +1,27 @@
+"""Print what filprofiler.api.find_allocations_by_function() finds."""
+
+import ctypes
+import json
+
+from filprofiler.api import find_allocations_by_function
+
+
+def allocate():
+    return bytearray(3_000_000)
+
+
+data = [allocate() for _ in range(3)]
+addresses = [ctypes.addressof((ctypes.c_char * len(b)).from_buffer(b)) for b in data]
+
+print(
+    json.dumps(
+        {
+            "addresses": addresses,
+            "found": find_allocations_by_function(__file__, "allocate"),
+            "truncated": find_allocations_by_function(
+                __file__, "allocate", max_results=1
+            ),
+            "nonexistent": find_allocations_by_function(__file__, "nonexistent"),
+        }
+    )
+)
@@ -0,0 +1,14 @@
+"""Fragment the heap: allocate lots of small chunks, and free all but a few
+scattered ones, so the allocator can't return the memory to the OS."""
+
+import ctypes
+
+libc = ctypes.CDLL(None)
+libc.malloc.restype = ctypes.c_void_p
+libc.free.argtypes = [ctypes.c_void_p]
+
+pointers = [libc.malloc(1000) for _ in range(100_000)]
+kept = pointers[::100]
+for i, pointer in enumerate(pointers):
+    if i % 100 != 0:
+        libc.free(pointer)
@@ -0,0 +1,26 @@
+"""Allocate in one function and free in another, with free tracking turned on
+at runtime."""
+
+import ctypes
+
+from filprofiler.api import set_free_tracking
+
+libc = ctypes.CDLL(None)
+libc.malloc.restype = ctypes.c_void_p
+libc.free.argtypes = [ctypes.c_void_p]
+
+
+def loader():
+    return [libc.malloc(1_000_000) for _ in range(20)]
+
+
+def consumer(pointers):
+    for pointer in pointers:
+        libc.free(pointer)
+
+
+set_free_tracking(True)
+consumer(loader())
+set_free_tracking(False)
+# Not recorded:
+consumer(loader())
@@ -0,0 +1,25 @@
+"""Dump a per-line breakdown of a function to the given directory."""
+
+import sys
+from pathlib import Path
+
+from filprofiler.api import dump_function_detail
+
+
+def allocate(depth):
+    if depth > 0:
+        return allocate(depth - 1)
+    small = bytearray(10_000_000)
+    big = bytearray(30_000_000)
+    return small, big
+
+
+data = [allocate(2), allocate(0)]
+
+output = Path(sys.argv[1])
+dump_function_detail(__file__, "allocate", output)
+
+try:
+    dump_function_detail(__file__, "nonexistent", output / "nope")
+except RuntimeError as e:
+    print("Unknown function failed:", e)
@@ -0,0 +1,26 @@
+"""Allocations that fail because their size is too big to be real, for
+test_huge_sizes."""
+
+import ctypes
+
+libc = ctypes.CDLL(None)
+for name, argtypes in [
+    ("malloc", [ctypes.c_size_t]),
+    ("calloc", [ctypes.c_size_t, ctypes.c_size_t]),
+    ("realloc", [ctypes.c_void_p, ctypes.c_size_t]),
+    ("free", [ctypes.c_void_p]),
+]:
+    getattr(libc, name).argtypes = argtypes
+    getattr(libc, name).restype = ctypes.c_void_p
+
+SIZE_MAX = 2**64 - 1
+
+# Overflows, so calloc() fails:
+failed = [libc.calloc(2**62, 8)]
+failed.append(libc.malloc(SIZE_MAX))
+# A failed realloc() leaves the old allocation alone:
+small = libc.malloc(1024 * 1024)
+failed.append(libc.realloc(small, SIZE_MAX))
+data = bytearray(20 * 1024 * 1024)
+libc.free(small)
+print(failed)
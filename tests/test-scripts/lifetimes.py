@@ -0,0 +1,24 @@
+"""Allocations with different lifetimes, for FIL_LIFETIMES=1."""
+
+import ctypes
+import time
+
+libc = ctypes.CDLL(None)
+libc.malloc.restype = ctypes.c_void_p
+libc.free.argtypes = [ctypes.c_void_p]
+
+
+def scratch():
+    for _ in range(100):
+        libc.free(libc.malloc(100_000))
+
+
+def long_lived():
+    return [libc.malloc(1_000_000) for _ in range(10)]
+
+
+scratch()
+kept = long_lived()
+time.sleep(0.2)
+for pointer in kept[:5]:
+    libc.free(pointer)
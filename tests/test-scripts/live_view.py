@@ -0,0 +1,12 @@
+"""Hold on to some memory for a while, for test_live_view."""
+
+import time
+
+
+def hold():
+    data = bytearray(50 * 1024 * 1024)
+    time.sleep(1)
+    return data
+
+
+hold()
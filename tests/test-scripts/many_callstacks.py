@@ -0,0 +1,13 @@
+"""Allocate from thousands of different callstacks."""
+
+functions = {}
+for i in range(3000):
+    exec(f"def allocate_{i}():\n    return bytearray(10_000)", functions)
+
+
+def allocate_all():
+    return [functions[f"allocate_{i}"]() for i in range(3000)]
+
+
+big = bytearray(50_000_000)
+small = allocate_all()
@@ -0,0 +1,11 @@
+"""Allocate on several lines of the same function."""
+
+
+def allocate():
+    a = bytearray(10_000_000)
+    b = bytearray(20_000_000)
+    c = bytearray(30_000_000)
+    return a, b, c
+
+
+result = allocate()
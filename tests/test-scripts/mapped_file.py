@@ -0,0 +1,15 @@
+"""Memory-map a file, alongside normal allocations."""
+import mmap
+import os
+import tempfile
+
+directory = tempfile.mkdtemp()
+path = os.path.join(directory, "data.parquet")
+with open(path, "wb") as f:
+    f.truncate(1024 * 1024 * 40)
+with open(path, "rb") as f:
+    mapped = mmap.mmap(f.fileno(), 0, access=mmap.ACCESS_READ)
+data = bytearray(1024 * 1024 * 20)
+del mapped
+os.remove(path)
+os.rmdir(directory)
@@ -0,0 +1,16 @@
+"""Go over the FIL_MILESTONES set by test_milestones, twice."""
+
+
+def small():
+    return bytearray(40 * 1024 * 1024)
+
+
+def big():
+    return bytearray(100 * 1024 * 1024)
+
+
+a = small()
+b = big()
+del a, b
+a = small()
+b = big()
@@ -0,0 +1,28 @@
+/* A library that allocates with mimalloc's own API, rather than malloc(). */
+
+#include <mimalloc.h>
+#include <string.h>
+
+void *mimalloc_allocate(size_t size) {
+  void *result = mi_malloc(size);
+  memset(result, 1, size);
+  return result;
+}
+
+void *mimalloc_reallocate(void *address, size_t size) {
+  return mi_realloc(address, size);
+}
+
+void mimalloc_free(void *address) { mi_free(address); }
+
+void *mimalloc_allocate_aligned(size_t size) {
+  void *result = mi_malloc_aligned(size, 4096);
+  memset(result, 1, size);
+  return result;
+}
+
+void *mimalloc_reallocate_aligned(void *address, size_t size) {
+  return mi_realloc_aligned(address, size, 4096);
+}
+
+void mimalloc_free_aligned(void *address) { mi_free_aligned(address, 4096); }
@@ -0,0 +1,18 @@
+"""
+Call Fil's mimalloc wrappers without mimalloc being loaded, as a library
+checking for mimalloc with dlsym() might; for test_missing_bundled_allocator.
+"""
+
+import ctypes
+import errno
+
+program = ctypes.CDLL(None, use_errno=True)
+program.mi_malloc.restype = ctypes.c_void_p
+program.mi_malloc.argtypes = [ctypes.c_size_t]
+program.mi_free.argtypes = [ctypes.c_void_p]
+
+# Fails like an allocator that's out of memory:
+assert program.mi_malloc(1000) is None
+assert ctypes.get_errno() == errno.ENOMEM
+# There's nothing to pass it on to:
+program.mi_free(0x1000)
@@ -0,0 +1,38 @@
+"""Create objects that stay alive, and some that don't."""
+
+from filprofiler.api import track_instances, track_object
+
+
+@track_instances
+class Leaky:
+    def __init__(self, value):
+        self.value = value
+
+
+class Node:
+    pass
+
+
+LEAKED = []
+
+
+def leak():
+    for i in range(100):
+        LEAKED.append(Leaky(i))
+
+
+def temporary():
+    for i in range(50):
+        track_object(Node())
+
+
+def kept():
+    nodes = [Node() for _ in range(10)]
+    for node in nodes:
+        track_object(node)
+    return nodes
+
+
+leak()
+temporary()
+NODES = kept()
@@ -0,0 +1,87 @@
+"""
+Register an output sink via ctypes, and write reports to the given directory:
+one with the sink, one with a failing sink and one after unregistering it.
+What the sink received goes in the "sunk" subdirectory.
+"""
+
+import ctypes
+import json
+import os
+import sys
+
+from filprofiler._tracer import create_report
+
+if sys.platform == "linux":
+    preload = ctypes.PyDLL(None)
+else:
+    from filprofiler._utils import library_path
+
+    preload = ctypes.PyDLL(library_path("_filpreload"))
+
+SINK = ctypes.CFUNCTYPE(
+    ctypes.c_int,
+    ctypes.c_char_p,
+    ctypes.c_char_p,
+    ctypes.POINTER(ctypes.c_char),
+    ctypes.c_size_t,
+    ctypes.c_void_p,
+)
+# path -> (format, user data, chunks so far, chunks after it finished):
+received = {}
+
+
+def on_chunk(format, path, data, length, user_data):
+    format, user_data, chunks, extra = received.setdefault(
+        path.decode("utf-8"), (format.decode("utf-8"), user_data, [], None)
+    )
+    if extra is not None:
+        extra.append(length)
+    elif length == 0:
+        received[path.decode("utf-8")] = (format, user_data, chunks, [])
+    else:
+        chunks.append(ctypes.string_at(data, length))
+    return 0
+
+
+def fail(format, path, data, length, user_data):
+    return 1
+
+
+def allocate():
+    return bytearray(30_000_000)
+
+
+data = allocate()
+output = sys.argv[1]
+
+sink = SINK(on_chunk)
+preload.fil_register_output_sink(sink, ctypes.c_void_p(1234))
+report_dir = os.path.join(output, "report")
+create_report(report_dir)
+sunk = {}
+for path, (format, user_data, chunks, extra) in received.items():
+    name = os.path.relpath(path, report_dir)
+    sunk[name] = {"format": format, "user_data": user_data, "extra": extra}
+    sunk_path = os.path.join(output, "sunk", name)
+    os.makedirs(os.path.dirname(sunk_path), exist_ok=True)
+    with open(sunk_path, "wb") as f:
+        f.write(b"".join(chunks))
+
+failing = SINK(fail)
+preload.fil_register_output_sink(failing, None)
+try:
+    create_report(os.path.join(output, "failing"))
+except RuntimeError as e:
+    failure = str(e)
+else:
+    failure = None
+
+preload.fil_register_output_sink(None, None)
+received.clear()
+create_report(os.path.join(output, "unregistered"))
+
+print(
+    json.dumps(
+        {"sunk": sunk, "failure": failure, "after_unregistering": len(received)}
+    )
+)
@@ -0,0 +1,46 @@
+"""Register a peak callback via ctypes, and print what it was called with."""
+
+import ctypes
+import json
+import sys
+import threading
+
+if sys.platform == "linux":
+    preload = ctypes.PyDLL(None)
+else:
+    from filprofiler._utils import library_path
+
+    preload = ctypes.PyDLL(library_path("_filpreload"))
+
+CALLBACK = ctypes.CFUNCTYPE(None, ctypes.c_uint64, ctypes.c_char_p, ctypes.c_void_p)
+notifications = []
+called = threading.Event()
+
+
+def on_peak(peak_bytes, summary, user_data):
+    # Allocating here must not cause further notifications or deadlocks:
+    notifications.append((peak_bytes, summary.decode("utf-8"), user_data))
+    called.set()
+
+
+callback = CALLBACK(on_peak)
+preload.fil_register_peak_callback(
+    callback, ctypes.c_void_p(1234), ctypes.c_uint64(20_000_000)
+)
+
+
+def allocate_lots():
+    return bytearray(50_000_000)
+
+
+data = allocate_lots()
+called.wait(10)
+first = list(notifications)
+
+# Small growth shouldn't trigger another callback:
+called.clear()
+more = bytearray(1_000_000)
+called_again = called.wait(0.5)
+
+preload.fil_register_peak_callback(None, None, ctypes.c_uint64(0))
+print(json.dumps({"notifications": first, "called_again": called_again}))
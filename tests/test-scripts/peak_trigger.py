@@ -0,0 +1,9 @@
+"""The peak is reached by one big allocation, see test_peak_triggers."""
+
+
+def load():
+    return bytearray(50_000_000)
+
+
+data = load()
+del data
@@ -0,0 +1,28 @@
+"""Allocate inside nested phases."""
+
+import ctypes
+
+from filprofiler.api import phase
+
+libc = ctypes.CDLL(None)
+libc.malloc.restype = ctypes.c_void_p
+libc.free.argtypes = [ctypes.c_void_p]
+
+
+def load():
+    return libc.malloc(20_000_000)
+
+
+def train():
+    return libc.malloc(30_000_000)
+
+
+with phase("load data"):
+    data = load()
+with phase("train"):
+    with phase("epoch 3"):
+        model = train()
+
+# The peak was inside the phases:
+libc.free(model)
+libc.free(data)
@@ -0,0 +1,8 @@
+"""Imported by phases.py, allocates at import time."""
+
+import ctypes
+
+libc = ctypes.CDLL(None)
+libc.malloc.restype = ctypes.c_void_p
+
+IMPORT_TIME_DATA = libc.malloc(30_000_000)
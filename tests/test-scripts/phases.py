@@ -0,0 +1,15 @@
+"""Allocate during imports, main, and a custom phase."""
+
+import phase_import_helper
+from filprofiler.api import mark_phase
+
+
+def main():
+    libc = phase_import_helper.libc
+    main_data = libc.malloc(20_000_000)
+    mark_phase("loading")
+    loading_data = libc.malloc(10_000_000)
+    return main_data, loading_data
+
+
+result = main()
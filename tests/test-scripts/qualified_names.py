@@ -0,0 +1,25 @@
+"""Methods with the same name in different classes."""
+
+kept = []
+
+
+class Parser:
+    def process(self):
+        kept.append(bytearray(20 * 1024 * 1024))
+
+
+class Writer:
+    def process(self):
+        kept.append(bytearray(30 * 1024 * 1024))
+
+
+def outer():
+    def inner():
+        kept.append(bytearray(10 * 1024 * 1024))
+
+    inner()
+
+
+Parser().process()
+Writer().process()
+outer()
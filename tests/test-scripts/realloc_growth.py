@@ -0,0 +1,22 @@
+"""Grow a buffer by repeated doubling with realloc(), like many C extensions
+do when appending."""
+
+import ctypes
+
+libc = ctypes.CDLL(None)
+libc.malloc.restype = ctypes.c_void_p
+libc.realloc.restype = ctypes.c_void_p
+libc.realloc.argtypes = [ctypes.c_void_p, ctypes.c_size_t]
+libc.free.argtypes = [ctypes.c_void_p]
+
+
+def grow_buffer():
+    size = 1024
+    buffer = libc.malloc(size)
+    while size < 64 * 1024 * 1024:
+        size *= 2
+        buffer = libc.realloc(buffer, size)
+    return buffer
+
+
+libc.free(grow_buffer())
@@ -0,0 +1,28 @@
+"""Profile just a region of the program with filprofiler.api.profile_region()."""
+
+import sys
+from pathlib import Path
+
+from filprofiler.api import profile_region
+
+output = Path(sys.argv[1])
+before = bytearray(50_000_000)
+
+
+def in_region():
+    return bytearray(20_000_000)
+
+
+with profile_region(output / "excluded"):
+    # Freeing memory from before the region can't make its peak negative:
+    del before
+    kept = in_region()
+    # Regions can't be nested:
+    try:
+        with profile_region(output / "nested"):
+            pass
+    except RuntimeError as e:
+        print("Nested region failed:", e)
+
+with profile_region(output / "grouped", group_pre_existing=True):
+    in_region()
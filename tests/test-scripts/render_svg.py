@@ -0,0 +1,44 @@
+"""
+Render the peak flamegraph in memory a few ways, writing the results to the
+given directory.
+"""
+
+import json
+import sys
+from pathlib import Path
+
+from filprofiler._tracer import get_traced_memory, render_peak_svg
+
+
+def allocate():
+    return bytearray(30_000_000)
+
+
+def many_small():
+    return [bytearray(1000 + i) for i in range(300)]
+
+
+data = allocate()
+small = many_small()
+
+output = Path(sys.argv[1])
+svg = render_peak_svg()
+(output / "peak.svg").write_text(svg)
+
+# Rendering shouldn't leave anything behind:
+current, _ = get_traced_memory()
+for _ in range(5):
+    render_peak_svg()
+leftover_bytes = get_traced_memory()[0] - current
+
+# A size limit prunes narrow frames:
+(output / "pruned.svg").write_text(render_peak_svg(len(svg) - 1))
+
+print(
+    json.dumps(
+        {
+            "leftover_bytes": leftover_bytes,
+            "too_small_limit": render_peak_svg(100),
+        }
+    )
+)
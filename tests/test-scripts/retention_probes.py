@@ -0,0 +1,44 @@
+"""Register retention probes via ctypes; the report says what they return."""
+
+import ctypes
+import sys
+import threading
+
+if sys.platform == "linux":
+    preload = ctypes.PyDLL(None)
+else:
+    from filprofiler._utils import library_path
+
+    preload = ctypes.PyDLL(library_path("_filpreload"))
+
+PROBE = ctypes.CFUNCTYPE(ctypes.c_int64, ctypes.c_void_p)
+cache = {}
+
+
+def estimate_cache(user_data):
+    if user_data != 1234:
+        return -1
+    # Allocating here isn't tracked, and doesn't deadlock:
+    return sum(len(value) for value in list(cache.values()))
+
+
+def cant_tell(user_data):
+    return -1
+
+
+def hang(user_data):
+    threading.Event().wait(10)
+    return 0
+
+
+# References are kept, so the callbacks stay alive:
+probes = [PROBE(estimate_cache), PROBE(cant_tell), PROBE(hang)]
+preload.fil_register_retention_probe(b"my-cache", probes[0], ctypes.c_void_p(1234))
+preload.fil_register_retention_probe(b"broken", probes[1], None)
+preload.fil_register_retention_probe(b"slow", probes[2], None)
+# Unregistering:
+preload.fil_register_retention_probe(b"unregistered", probes[1], None)
+preload.fil_register_retention_probe(b"unregistered", None, None)
+
+for i in range(30):
+    cache[i] = bytearray(1_000_000)
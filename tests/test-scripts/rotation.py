@@ -0,0 +1,14 @@
+"""Allocate through several of the short FIL_ROTATE_WINDOW set by test_rotation."""
+
+import time
+
+
+def allocate(megabytes):
+    return bytearray(megabytes * 1024 * 1024)
+
+
+long_lived = allocate(30)
+for i in range(1, 6):
+    data = allocate(10 * i)
+    time.sleep(0.6)
+    del data
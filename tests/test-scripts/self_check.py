@@ -0,0 +1,46 @@
+"""
+Print what Fil's self-check finds every so often during random allocations.
+"""
+
+import ctypes
+import json
+import mmap
+import random
+
+from filprofiler._tracer import self_check
+
+libc = ctypes.CDLL(None)
+libc.malloc.restype = ctypes.c_void_p
+libc.malloc.argtypes = [ctypes.c_size_t]
+libc.realloc.restype = ctypes.c_void_p
+libc.realloc.argtypes = [ctypes.c_void_p, ctypes.c_size_t]
+libc.free.argtypes = [ctypes.c_void_p]
+
+rng = random.Random(12345)
+checks = []
+allocations = []
+mmaps = []
+for i in range(2000):
+    operation = rng.randrange(5)
+    if operation == 0 or not allocations:
+        allocations.append(libc.malloc(rng.randrange(1, 100_000)))
+    elif operation == 1:
+        libc.free(allocations.pop(rng.randrange(len(allocations))))
+    elif operation == 2:
+        index = rng.randrange(len(allocations))
+        allocations[index] = libc.realloc(
+            allocations[index], rng.randrange(1, 100_000)
+        )
+    elif operation == 3:
+        mmaps.append(mmap.mmap(-1, rng.randrange(1, 10) * 4096))
+    elif mmaps:
+        mmaps.pop(rng.randrange(len(mmaps))).close()
+    if i % 100 == 0:
+        checks.append(self_check())
+
+for address in allocations:
+    libc.free(address)
+for m in mmaps:
+    m.close()
+checks.append(self_check())
+print(json.dumps(checks))
@@ -0,0 +1,22 @@
+"""Many small allocations and one large one, for FIL_SMALL_ALLOCATIONS."""
+
+import ctypes
+
+libc = ctypes.CDLL(None)
+libc.malloc.restype = ctypes.c_void_p
+libc.free.argtypes = [ctypes.c_void_p]
+
+
+def small():
+    return [libc.malloc(500) for _ in range(20_000)]
+
+
+def large():
+    return libc.malloc(20_000_000)
+
+
+kept = small()
+big = large()
+libc.free(big)
+for pointer in kept:
+    libc.free(pointer)
@@ -0,0 +1,33 @@
+"""
+Leak a little memory between resets, and write survivors reports to the given
+directory: one after leaking, and one after freeing some of it.
+"""
+
+import os
+import sys
+
+from filprofiler._tracer import dump_survivors, preload
+
+leaked = []
+
+
+def leak():
+    leaked.append(bytearray(100_000))
+
+
+def temporary():
+    return len(bytearray(1_000_000))
+
+
+for _ in range(4):
+    leak()
+    temporary()
+    # Like a benchmark starting its next iteration:
+    preload.fil_reset(b"/tmp")
+
+output = sys.argv[1]
+dump_survivors(os.path.join(output, "leaked"))
+
+# Freed survivors no longer count:
+del leaked[:2]
+dump_survivors(os.path.join(output, "freed"))
@@ -0,0 +1,35 @@
+"""Create temporary files, for test_temp_files."""
+
+import os
+import tempfile
+
+MIB = 1024 * 1024
+
+
+def spill():
+    # Created in the temporary directory, then unlinked while open:
+    fd, path = tempfile.mkstemp()
+    os.ftruncate(fd, 30 * MIB)
+    os.unlink(path)
+    return fd
+
+
+def anonymous():
+    fd = os.open(tempfile.gettempdir(), os.O_TMPFILE | os.O_RDWR)
+    os.posix_fallocate(fd, 0, 20 * MIB)
+    return fd
+
+
+def visible():
+    # Closed before it's unlinked, so it never counts:
+    fd, path = tempfile.mkstemp()
+    os.ftruncate(fd, 100 * MIB)
+    os.close(fd)
+    os.unlink(path)
+
+
+visible()
+fds = [spill(), anonymous()]
+for fd in fds:
+    os.close(fd)
+os.close(spill())
@@ -0,0 +1,18 @@
+"""Worker threads that each hold a copy of some data, then exit."""
+
+from threading import Barrier, Thread
+
+barrier = Barrier(3)
+
+
+def worker():
+    data = bytearray(50_000_000)
+    barrier.wait()
+    del data
+
+
+threads = [Thread(target=worker) for _ in range(3)]
+for t in threads:
+    t.start()
+for t in threads:
+    t.join()
@@ -0,0 +1,19 @@
+"""Two top-level phases with different memory usage, for FIL_TIMELINE=1."""
+
+import ctypes
+import time
+
+libc = ctypes.CDLL(None)
+libc.malloc.restype = ctypes.c_void_p
+libc.free.argtypes = [ctypes.c_void_p]
+
+
+def phase(size):
+    pointers = [libc.malloc(size) for _ in range(10)]
+    time.sleep(0.3)
+    for pointer in pointers:
+        libc.free(pointer)
+
+
+phase(1_000_000)
+phase(3_000_000)
@@ -0,0 +1,12 @@
+"""Print what filprofiler.api.get_traced_memory() says around an allocation."""
+
+import json
+
+from filprofiler.api import get_traced_memory
+
+before = get_traced_memory()
+data = bytearray(50_000_000)
+during = get_traced_memory()
+del data
+after = get_traced_memory()
+print(json.dumps({"before": before, "during": during, "after": after}))
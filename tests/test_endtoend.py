@@ -4,12 +4,15 @@ from subprocess import check_call, check_output, CalledProcessError, run, PIPE
 from tempfile import mkdtemp, NamedTemporaryFile
 from pathlib import Path
 import os
+import signal
 import time
 import sys
-from typing import Union
+from typing import Tuple, Union
 import re
 import shutil
 from glob import glob
+import json
+import gzip
 from xml.etree import ElementTree
 
 import numpy._core.numeric
@@ -43,6 +46,24 @@ def profile(
     return output
 
 
+def profile_with_stdout(
+    *arguments: Union[str, Path], expect_exit_code=0, **kwargs
+) -> Tuple[Path, str]:
+    """
+    Like profile(), but also return what the script wrote to stdout, for
+    scripts that report what they saw from inside the profiled process.
+    """
+    output = Path(mkdtemp())
+    result = run(
+        ["fil-profile", "-o", str(output), "run"] + list(arguments),
+        stdout=PIPE,
+        encoding=sys.getdefaultencoding(),
+        **kwargs,
+    )
+    assert result.returncode == expect_exit_code
+    return output, result.stdout
+
+
 def test_threaded_allocation_tracking():
     """
     fil-profile tracks allocations from all threads.
@@ -56,8 +77,8 @@ def test_threaded_allocation_tracking():
 
     import threading
 
-    threading = (threading.__file__, "run", ANY)
-    ones = (numpy._core.numeric.__file__, "ones", ANY)
+    threading = (threading.__file__, "threading.Thread.run", ANY)
+    ones = (numpy._core.numeric.__file__, "numpy._core.numeric.ones", ANY)
     script = str(script)
     h = (script, "h", 7)
 
@@ -89,8 +110,8 @@ def test_thread_allocates_after_main_thread_is_done():
 
     import threading
 
-    threading = (threading.__file__, "run", ANY)
-    ones = (numpy._core.numeric.__file__, "ones", ANY)
+    threading = (threading.__file__, "threading.Thread.run", ANY)
+    ones = (numpy._core.numeric.__file__, "numpy._core.numeric.ones", ANY)
     script = str(script)
     thread1_path1 = ((script, "thread1", 9), ones)
 
@@ -167,6 +188,58 @@ def test_anonymous_mmap():
         ) == pytest.approx(63, 0.1)
 
 
+def test_mapped_files():
+    """
+    With FIL_TRACK_MAPPED_FILES=1, file-backed mmap()s are tracked separately
+    from the main peak, tagged with the file name.
+    """
+    script = TEST_SCRIPTS / "mapped_file.py"
+    output_dir = profile(script)
+    [report_dir] = output_dir.iterdir()
+    assert not (report_dir / "peak-memory-with-mapped-files.svg").exists()
+
+    env = os.environ.copy()
+    env["FIL_TRACK_MAPPED_FILES"] = "1"
+    output_dir = profile(script, env=env)
+    [report_dir] = output_dir.iterdir()
+    # The main peak doesn't include the mapped file:
+    allocations = get_allocations(
+        output_dir,
+        expected_files=[
+            "peak-memory.svg",
+            "peak-memory-reversed.svg",
+            "index.html",
+            "peak-memory.prof",
+            "peak-functions.tsv",
+            "metadata.json",
+            "reallocs.json",
+            "reallocs.txt",
+            "threads.json",
+            "threads.txt",
+            "peak-memory-with-mapped-files.svg",
+            "peak-memory-with-mapped-files-reversed.svg",
+            "peak-memory-with-mapped-files.prof",
+        ],
+    )
+    script = str(script)
+    assert match(
+        allocations, {((script, "<module>", 12),): big}, as_mb
+    ) == pytest.approx(20, 0.1)
+    assert "mapped file" not in (report_dir / "peak-memory.prof").read_text()
+
+    # The combined peak does:
+    combined = {}
+    with_mapped_files = report_dir / "peak-memory-with-mapped-files.prof"
+    for line in with_mapped_files.read_text().splitlines():
+        callstack, size = line.rsplit(" ", 1)
+        combined[callstack.split(";")[-1]] = int(size) / (1024 * 1024)
+    assert combined["[mapped file: data.parquet]"] == pytest.approx(40, 0.1)
+    assert combined[f"{script}:12 (<module>)"] == pytest.approx(20, 0.1)
+    assert (
+        "peak-memory-with-mapped-files.svg" in (report_dir / "index.html").read_text()
+    )
+
+
 def test_python_objects():
     """
     Python objects gets detected and tracked.
@@ -267,7 +340,7 @@ def test_out_of_memory():
         "out-of-memory.prof",
     )
 
-    ones = (numpy._core.numeric.__file__, "ones", ANY)
+    ones = (numpy._core.numeric.__file__, "numpy._core.numeric.ones", ANY)
     script = str(script)
     expected_small_alloc = ((script, "<module>", 9), ones)
     toobig_alloc = ((script, "<module>", 12), ones)
@@ -490,29 +563,24 @@ def test_jupyter(tmp_path):
     )
     output_dir = tmpdir / "fil-result"
 
-    # IFrame with SVG was included in output:
+    # IFrame with the SVG inline was included in output:
     with open(tmpdir / "jupyter.html") as f:
         html = f.read()
-    assert "<iframe" in html
-    [svg_path] = re.findall(r'src="([^"]*\.svg)"', html)
-
-    svg_path = tmpdir / Path(svg_path)
-    assert svg_path.exists()
-    with open(svg_path) as f:
-        # Make sure the source code is in the SVG:
-        assert (
-            "arr2 = numpy.ones((1024, 1024, 5), dtype=numpy.uint32)".replace(
-                " ", "\u00a0"  # non-breaking space
-            )
-            in f.read()
+    assert "<iframe srcdoc=" in html
+    # Make sure the source code is in the SVG:
+    assert (
+        "arr2 = numpy.ones((1024, 1024, 5), dtype=numpy.uint32)".replace(
+            " ", "\u00a0"  # non-breaking space
         )
+        in html
+    )
 
     # Allocations were tracked:
     allocations = get_allocations(output_dir)
     path = (
         (re.compile(".*ipy*"), "__magic_run_with_fil", 3),
         (re.compile(".*ipy.*"), "alloc", 4),
-        (numpy._core.numeric.__file__, "ones", ANY),
+        (numpy._core.numeric.__file__, "numpy._core.numeric.ones", ANY),
     )
     assert match(allocations, {path: big}, as_mb) == pytest.approx(48, 0.1)
     actual_path = None
@@ -527,7 +595,7 @@ def test_jupyter(tmp_path):
     assert actual_path[0][0] != actual_path[1][0]  # code is in different cells
     path2 = (
         (re.compile(".*ipy.*"), "__magic_run_with_fil", 2),
-        (numpy._core.numeric.__file__, "ones", ANY),
+        (numpy._core.numeric.__file__, "numpy._core.numeric.ones", ANY),
     )
     assert match(allocations, {path2: big}, as_mb) == pytest.approx(20, 0.1)
     # It's possible to run nbconvert again.
@@ -582,6 +650,924 @@ def test_api_import(tmpdir):
         api.profile(lambda: None, tmpdir)
 
 
+def test_get_traced_memory():
+    """
+    filprofiler.api.get_traced_memory() returns a coherent (current, peak)
+    pair.
+    """
+    script = TEST_SCRIPTS / "traced_memory.py"
+    output_dir, stdout = profile_with_stdout(script)
+    traced = json.loads(stdout)
+    for current, peak in traced.values():
+        assert current <= peak
+    assert traced["during"][0] >= 50_000_000
+    assert traced["after"][0] < 50_000_000
+    assert traced["after"][1] >= 50_000_000
+    # The report agrees about what the peak was:
+    allocations = get_allocations(output_dir)
+    assert as_mb(allocations[((str(script), "<module>", 8),)]) == pytest.approx(
+        47.7, 0.1
+    )
+
+
+def test_peak_triggers():
+    """
+    The allocation that reached the peak is recorded in metadata.json and
+    printed, and with FIL_PEAK_TRIGGERS=N the last N are kept.
+    """
+    script = TEST_SCRIPTS / "peak_trigger.py"
+    for keep in (None, "3"):
+        env = os.environ.copy()
+        if keep is not None:
+            env["FIL_PEAK_TRIGGERS"] = keep
+        output_dir = Path(mkdtemp())
+        result = run(
+            ["fil-profile", "-o", str(output_dir), "run", str(script)],
+            stderr=PIPE,
+            check=True,
+            encoding=sys.getdefaultencoding(),
+            env=env,
+        )
+        assert (
+            f"Peak was reached while executing: {script}:5 (load) (allocating 47.7 MiB on thread "
+            in result.stderr
+        )
+        [report_dir] = output_dir.iterdir()
+        with open(report_dir / "metadata.json") as f:
+            triggers = json.load(f)["peak_triggers"]
+        assert 1 <= len(triggers) <= int(keep or 1)
+        assert triggers[-1]["innermost_frame"] == f"{script}:5 (load)"
+        assert triggers[-1]["bytes"] >= 50_000_000
+        assert triggers[-1]["callstack"].endswith(
+            f"{script}:8 (<module>);{script}:5 (load)"
+        )
+        assert "Peak was reached while executing" in (
+            report_dir / "index.html"
+        ).read_text()
+
+
+def test_aggregate_lines():
+    """
+    With FIL_AGGREGATE_LINES=1, each function is one frame no matter how many
+    lines it allocated on, and the frames have no line numbers.
+    """
+    script = TEST_SCRIPTS / "many_lines.py"
+    by_line = get_allocations(profile(script))
+    for line, size in [(5, 10), (6, 20), (7, 30)]:
+        path = ((str(script), "<module>", 11), (str(script), "allocate", line))
+        assert as_mb(by_line[path]) == pytest.approx(size, 0.1)
+
+    env = os.environ.copy()
+    env["FIL_AGGREGATE_LINES"] = "1"
+    output_dir = profile(script, env=env)
+    aggregated = get_allocations(output_dir)
+    path = ((str(script), "<module>", None), (str(script), "allocate", None))
+    assert path in aggregated
+    assert as_mb(aggregated[path]) == pytest.approx(60, 0.1)
+    [report_dir] = output_dir.iterdir()
+    with open(report_dir / "metadata.json") as f:
+        assert json.load(f)["line_numbers"] is False
+
+
+def test_max_report_stacks():
+    """
+    With FIL_MAX_REPORT_STACKS, small callstacks are merged into their callers
+    rather than dropped, and FIL_FULL_REPORT_STACKS=1 also writes all of them
+    out, gzipped.
+    """
+    script = TEST_SCRIPTS / "many_callstacks.py"
+    env = os.environ.copy()
+    env["FIL_MAX_REPORT_STACKS"] = "50"
+    env["FIL_FULL_REPORT_STACKS"] = "1"
+    output_dir = profile(script, env=env)
+    [report_dir] = output_dir.iterdir()
+
+    def read_lines(f):
+        return [line.strip() for line in f if not line.startswith("# ")]
+
+    with open(report_dir / "peak-memory.prof") as f:
+        budgeted = read_lines(f)
+    assert len(budgeted) <= 50
+    assert any(
+        line.rsplit(" ", 1)[0].endswith(";[other callstacks]") for line in budgeted
+    )
+    # Nothing is dropped:
+    total = sum(int(line.rsplit(" ", 1)[1]) for line in budgeted)
+    assert total / (1024 * 1024) == pytest.approx(50 + 30, 0.1)
+
+    with gzip.open(report_dir / "peak-memory-full.prof.gz", "rt") as f:
+        full = read_lines(f)
+    assert len(full) > 3000
+    assert not any("[other callstacks]" in line for line in full)
+
+
+def test_json_report():
+    """
+    With FIL_JSON_REPORT=zstd, the peak is also written as a versioned,
+    zstd-compressed JSON report with the same callstacks as the .prof file.
+    """
+    zstandard = pytest.importorskip("zstandard")
+    script = TEST_SCRIPTS / "many_callstacks.py"
+    env = os.environ.copy()
+    env["FIL_JSON_REPORT"] = "zstd"
+    output_dir = profile(script, env=env)
+    [report_dir] = output_dir.iterdir()
+    with zstandard.open(report_dir / "peak.json.zst", "rt") as f:
+        report = json.load(f)
+    assert report["format_version"] == 1
+    assert report["line_numbers"]
+    sizes = [callstack["bytes"] for callstack in report["callstacks"]]
+    assert sizes == sorted(sizes, reverse=True)
+    assert sum(sizes) <= report["peak_bytes"]
+
+    with open(report_dir / "peak-memory.prof") as f:
+        prof = {
+            line.rsplit(" ", 1)[0]: int(line.rsplit(" ", 1)[1])
+            for line in f
+            if not line.startswith("# ")
+        }
+    assert {
+        ";".join(callstack["frames"]): callstack["bytes"]
+        for callstack in report["callstacks"]
+    } == prof
+
+
+def test_find_allocations_by_function():
+    """
+    filprofiler.api.find_allocations_by_function() returns the live
+    allocations from a given function.
+    """
+    _, stdout = profile_with_stdout(TEST_SCRIPTS / "find_allocations.py")
+    result = json.loads(stdout)
+    found, total = result["found"]
+    # With PYTHONMALLOC=malloc the bytearray objects themselves are included too:
+    assert total == len(found) >= 3
+    big = {address for (address, size) in found if size >= 3_000_000}
+    assert big == set(result["addresses"])
+
+    # Truncated results still give the total:
+    found, truncated_total = result["truncated"]
+    assert truncated_total == total
+    assert len(found) == 1
+
+    assert result["nonexistent"] == [[], 0]
+
+
+def test_function_detail():
+    """
+    filprofiler.api.dump_function_detail() writes a per-line report of a
+    single function.
+    """
+    detail_dir = Path(mkdtemp())
+    _, stdout = profile_with_stdout(
+        TEST_SCRIPTS / "function_detail.py", str(detail_dir)
+    )
+    with open(detail_dir / "function-detail.json") as f:
+        detail = json.load(f)
+    assert detail["function"] == "allocate"
+    lines = [(line["line"], line["bytes"] // 1_000_000) for line in detail["lines"]]
+    # Recursive calls are only counted once, at the innermost frame:
+    assert lines[:2] == [(13, 60), (12, 20)]
+    assert detail["lines"][0]["source"] == "big = bytearray(30_000_000)"
+    assert 79 < sum(line["percent"] for line in detail["lines"][:2]) <= 100.0
+    assert (
+        "big = bytearray(30_000_000)"
+        in (detail_dir / "function-detail.txt").read_text()
+    )
+
+    assert stdout.startswith("Unknown function failed:")
+    assert not (detail_dir / "nope").exists()
+
+
+def test_profile_region():
+    """
+    filprofiler.api.profile_region() writes a report of just the memory
+    allocated inside the region, optionally with the memory from before it
+    grouped together.
+    """
+    output_dir = Path(mkdtemp())
+    _, stdout = profile_with_stdout(TEST_SCRIPTS / "region.py", str(output_dir))
+    assert stdout.startswith("Nested region failed:")
+    assert not (output_dir / "nested").exists()
+
+    def innermost_frames(name):
+        """Map innermost frame to MiB at the region's peak."""
+        frames = {}
+        with open(output_dir / name / "peak-memory.prof") as f:
+            for line in f:
+                callstack, size = line.rsplit(" ", 1)
+                frame = callstack.split(";")[-1]
+                frames[frame] = frames.get(frame, 0) + int(size) / (1024 * 1024)
+        return frames
+
+    for name, pre_existing in [("excluded", "exclude"), ("grouped", "group")]:
+        assert (output_dir / name / "index.html").exists()
+        with open(output_dir / name / "metadata.json") as f:
+            assert json.load(f)["region"]["pre_existing"] == pre_existing
+
+    excluded = innermost_frames("excluded")
+    [region] = [size for frame, size in excluded.items() if "(in_region)" in frame]
+    assert 19 < region < 20
+    # The 50MB freed inside the region isn't there, nor is anything else big:
+    assert "[pre-existing]" not in excluded
+    assert sum(excluded.values()) < 21
+
+    grouped = innermost_frames("grouped")
+    [region] = [size for frame, size in grouped.items() if "(in_region)" in frame]
+    assert 19 < region < 20
+    # The 20MB kept from the first region is memory from before the second:
+    assert grouped["[pre-existing]"] > 19
+
+
+def test_exit_summary():
+    """
+    A summary of the peak is printed once the final report is written, unless
+    FIL_NO_SUMMARY=1 is set.
+    """
+    script = TEST_SCRIPTS / "traced_memory.py"
+    output_dir = Path(mkdtemp())
+    result = run(
+        ["fil-profile", "-o", str(output_dir), "run", str(script)],
+        stderr=PIPE,
+        check=True,
+        encoding=sys.getdefaultencoding(),
+    )
+    [summary] = [
+        line for line in result.stderr.splitlines() if "Peak tracked memory" in line
+    ]
+    assert "allocations, peak RSS" in summary
+    assert f"{script}:8 (<module>)" in result.stderr
+    [report_dir] = output_dir.iterdir()
+    assert f"Reports in {report_dir}: " in result.stderr
+
+    env = os.environ.copy()
+    env["FIL_NO_SUMMARY"] = "1"
+    result = run(
+        ["fil-profile", "-o", str(output_dir), "run", str(script)],
+        stderr=PIPE,
+        check=True,
+        encoding=sys.getdefaultencoding(),
+        env=env,
+    )
+    assert "Peak tracked memory" not in result.stderr
+
+
+def test_render_peak_svg():
+    """
+    The peak flamegraph can be rendered in memory, optionally pruned to fit a
+    size limit, without the result being tracked.
+    """
+    svg_dir = Path(mkdtemp())
+    _, stdout = profile_with_stdout(TEST_SCRIPTS / "render_svg.py", str(svg_dir))
+    result = json.loads(stdout)
+    svg = (svg_dir / "peak.svg").read_text()
+    assert svg.startswith("<?xml")
+    assert "(allocate)" in svg
+    assert "(many_small)" in svg
+    # The source code is included:
+    assert "bytearray(30_000_000)" in svg
+
+    # Nothing is left behind by rendering:
+    assert result["leftover_bytes"] < 1_000_000
+
+    pruned = (svg_dir / "pruned.svg").read_text()
+    assert len(pruned) < len(svg)
+    assert "(allocate)" in pruned
+
+    # Too small a limit fails:
+    assert result["too_small_limit"] is None
+
+
+def test_self_check():
+    """
+    The tracker's internal bookkeeping stays consistent across a random
+    sequence of allocations, frees, reallocs and mmaps.
+    """
+    _, stdout = profile_with_stdout(TEST_SCRIPTS / "self_check.py")
+    checks = json.loads(stdout)
+    assert len(checks) == 21
+    assert set(checks) == {0}
+
+
+def test_peak_callback():
+    """
+    A callback registered with fil_register_peak_callback() is called when
+    peak memory grows enough, with a summary of where memory was allocated.
+    """
+    _, stdout = profile_with_stdout(TEST_SCRIPTS / "peak_callback.py")
+    result = json.loads(stdout)
+    assert len(result["notifications"]) >= 1, "callback was never called"
+    peak_bytes, summary, user_data = result["notifications"][0]
+    assert peak_bytes >= 50_000_000
+    assert user_data == 1234
+    assert "allocate_lots" in summary
+    # Small growth doesn't trigger another callback:
+    assert not result["called_again"]
+
+
+def test_output_sink():
+    """
+    A sink registered with fil_register_output_sink() gets every report file
+    after it's written, and its failures are reported by the dump.
+    """
+    sink_dir = Path(mkdtemp())
+    _, stdout = profile_with_stdout(TEST_SCRIPTS / "output_sink.py", str(sink_dir))
+    result = json.loads(stdout)
+
+    # Everything is written to disk too, and what the sink got matches:
+    sunk = result["sunk"]
+    assert sunk["peak-memory.svg"]["format"] == "svg"
+    assert sunk["metadata.json"]["format"] == "json"
+    for name, details in sunk.items():
+        assert details["user_data"] == 1234
+        # The file was finished, and nothing came after that:
+        assert details["extra"] == [], name
+        assert (sink_dir / "sunk" / name).read_bytes() == (
+            sink_dir / "report" / name
+        ).read_bytes(), name
+    assert b"allocate" in (sink_dir / "sunk" / "peak-memory.svg").read_bytes()
+
+    # A failing sink is reported, but the files are still written:
+    assert "output sink failed" in result["failure"]
+    assert (sink_dir / "failing" / "peak-memory.svg").exists()
+
+    # And once unregistered it's not called:
+    assert result["after_unregistering"] == 0
+    assert (sink_dir / "unregistered" / "peak-memory.svg").exists()
+
+
+def test_qualified_names():
+    """
+    Frames are named after the qualified name of the function, so methods with
+    the same name in different classes aren't merged.
+    """
+    script = TEST_SCRIPTS / "qualified_names.py"
+    output_dir = profile(script)
+    allocations = get_allocations(output_dir)
+
+    script = str(script)
+    parser = ((script, "<module>", 23), (script, "Parser.process", 8))
+    writer = ((script, "<module>", 24), (script, "Writer.process", 13))
+    inner = (
+        (script, "<module>", 25),
+        (script, "outer", 20),
+        (script, "outer.<locals>.inner", 18),
+    )
+    for path, size in [(parser, 20), (writer, 30), (inner, 10)]:
+        assert match(allocations, {path: big}, as_mb) == pytest.approx(size, 0.1)
+
+
+def test_survivors():
+    """
+    With FIL_SURVIVORS, memory that stays live across resets is reported per
+    callstack and generation by fil_dump_survivors().
+    """
+    env = os.environ.copy()
+    env["FIL_SURVIVORS"] = "3"
+    survivors_dir = Path(mkdtemp())
+    profile(TEST_SCRIPTS / "survivors.py", str(survivors_dir), env=env)
+
+    def read_report(name):
+        with open(survivors_dir / name / "survivors.json") as f:
+            return json.load(f)
+
+    report = read_report("leaked")
+    # fil-profile resets too, before the script starts:
+    assert report["resets"] >= 4
+    assert report["min_resets"] == 3
+    [leaking] = [cs for cs in report["callstacks"] if "(leak)" in cs["callstack"]]
+    assert not [cs for cs in report["callstacks"] if "(temporary)" in cs["callstack"]]
+    assert [g["resets_survived"] for g in leaking["generations"]] == [4, 3, 2, 1]
+    assert all(g["bytes"] >= 100_000 for g in leaking["generations"])
+    with open(survivors_dir / "leaked" / "survivors.txt") as f:
+        assert "(leak)" in f.read()
+
+    # What's left after freeing is too young to report:
+    report = read_report("freed")
+    assert not [cs for cs in report["callstacks"] if "(leak)" in cs["callstack"]]
+
+
+def test_retention_probes():
+    """
+    Probes registered with fil_register_retention_probe() are called when the
+    report is written, and what they return is reported separately from the
+    peak, including probes that fail or hang.
+    """
+    env = os.environ.copy()
+    env["FIL_RETENTION_PROBE_TIMEOUT_MS"] = "500"
+    output_dir = Path(mkdtemp())
+    result = run(
+        [
+            "fil-profile",
+            "-o",
+            str(output_dir),
+            "--no-browser",
+            "run",
+            str(TEST_SCRIPTS / "retention_probes.py"),
+        ],
+        stderr=PIPE,
+        check=True,
+        encoding=sys.getdefaultencoding(),
+        env=env,
+    )
+    [metadata_path] = glob(str(output_dir / "*" / "metadata.json"))
+    with open(metadata_path) as f:
+        metadata = json.load(f)
+    assert metadata["retained_by_caches"] == [
+        {"name": "my-cache", "estimated_bytes": 30_000_000, "error": None},
+        {
+            "name": "broken",
+            "estimated_bytes": None,
+            "error": "the probe couldn't estimate it",
+        },
+        {"name": "slow", "estimated_bytes": None, "error": "timed out after 500 ms"},
+    ]
+    assert (
+        "[retained by cache: my-cache] ~28.6 MiB (an estimate, not included in the peak)"
+        in result.stderr
+    )
+
+
+def test_adaptive_sampling():
+    """
+    With lots of live allocations, small allocations get sampled, and the
+    report records that it happened.
+    """
+    env = os.environ.copy()
+    env["FIL_ADAPTIVE_HIGH_WATER"] = "50000"
+    output_dir = profile(TEST_SCRIPTS / "adaptive_sampling.py", env=env)
+    [metadata_path] = glob(str(output_dir / "*" / "metadata.json"))
+    with open(metadata_path) as f:
+        metadata = json.load(f)
+    assert metadata["adaptive_sampling"]["engaged"]
+    assert [t["engaged"] for t in metadata["adaptive_sampling"]["transitions"]] == [
+        True,
+        False,
+    ]
+    with open(Path(metadata_path).parent / "index.html") as f:
+        assert "estimate" in f.read()
+
+    # Without it, no sampling:
+    output_dir = profile(TEST_SCRIPTS / "adaptive_sampling.py")
+    [metadata_path] = glob(str(output_dir / "*" / "metadata.json"))
+    with open(metadata_path) as f:
+        assert not json.load(f)["adaptive_sampling"]["engaged"]
+
+
+@pytest.mark.skipif(
+    not sys.platform.startswith("linux"),
+    reason="Bundled allocators are only interposed on Linux",
+)
+def test_bundled_allocator():
+    """
+    Allocations made with mimalloc's own API are tracked with
+    FIL_BUNDLED_ALLOCATORS=1, including aligned ones, and either way Fil says
+    it found mimalloc.
+    """
+    script = TEST_SCRIPTS / "bundled_allocator.py"
+    env = os.environ.copy()
+    env["FIL_BUNDLED_ALLOCATORS"] = "1"
+    output_dir = profile(script, env=env)
+    allocations = get_allocations(output_dir)
+    # The peak is while freed_aligned()'s allocation is live:
+    for line, function, function_line, size in [
+        (39, "kept", 20, 30),
+        (41, "kept_aligned", 31, 15),
+        (42, "freed_aligned", 35, 10),
+    ]:
+        path = (
+            (str(script), "<module>", line),
+            (str(script), function, function_line),
+        )
+        assert as_mb(allocations[path]) == pytest.approx(size, 0.1)
+    [metadata_path] = glob(str(output_dir / "*" / "metadata.json"))
+    with open(metadata_path) as f:
+        [bundled] = json.load(f)["bundled_allocators"]
+    assert bundled["name"] == "mimalloc"
+    assert "mimalloc" in bundled["library"]
+    assert bundled["tracked"]
+
+    # Without it, the user is told how to track mimalloc's allocations:
+    output_dir = Path(mkdtemp())
+    result = run(
+        ["fil-profile", "-o", str(output_dir), "run", str(script)],
+        stderr=PIPE,
+        check=True,
+    )
+    assert b"Set FIL_BUNDLED_ALLOCATORS=1" in result.stderr
+    [metadata_path] = glob(str(output_dir / "*" / "metadata.json"))
+    with open(metadata_path) as f:
+        [bundled] = json.load(f)["bundled_allocators"]
+    assert not bundled["tracked"]
+
+
+@pytest.mark.skipif(
+    not sys.platform.startswith("linux"),
+    reason="Bundled allocators are only interposed on Linux",
+)
+def test_missing_bundled_allocator():
+    """
+    Fil's je_/mi_ wrappers are visible to dlsym() even if the allocator isn't
+    loaded; calling them then fails like running out of memory does, rather
+    than aborting.
+    """
+    script = TEST_SCRIPTS / "missing_bundled_allocator.py"
+    for tracking in ["0", "1"]:
+        env = os.environ.copy()
+        env["FIL_BUNDLED_ALLOCATORS"] = tracking
+        profile(script, env=env)
+
+
+def test_native_api():
+    """
+    Memory an extension reports with the API in fil.h shows up under its domain
+    and context, and without Fil the API does nothing.
+    """
+    script = TEST_SCRIPTS / "arena_extension.py"
+    output_dir = profile(script)
+    [peak_path] = glob(str(output_dir / "*" / "peak-memory.prof"))
+    with open(peak_path) as f:
+        [line] = [line for line in f if "[domain: arena_extension]" in line]
+    callstack, size = line.rsplit(" ", 1)
+    assert callstack.endswith(
+        "arena_extension.py:15 (load_table);"
+        "[context: load_table];[domain: arena_extension]"
+    )
+    assert int(size) / (1024 * 1024) == pytest.approx(30, 0.1)
+
+    result = run([sys.executable, str(script)], stdout=PIPE, check=True)
+    assert result.stdout == b"Fil API version 0\n"
+
+
+def test_allocator_stats():
+    """
+    Reports include allocator statistics, which show memory that was freed
+    but is still held by the allocator.
+    """
+    output_dir = profile(TEST_SCRIPTS / "fragmentation.py")
+    [metadata_path] = glob(str(output_dir / "*" / "metadata.json"))
+    with open(metadata_path) as f:
+        allocator = json.load(f)["allocator"]
+    stats = allocator["stats"]
+    assert stats is not None
+    if sys.platform == "linux":
+        # ~99MB was freed, but it's fragmented so glibc keeps most of it:
+        assert stats["free_bytes"] > 50_000_000
+        assert allocator["tracked_to_arena_ratio"] < 0.5
+    with open(Path(metadata_path).parent / "index.html") as f:
+        assert "Free, but not released to the OS" in f.read()
+
+
+def test_realloc_stats():
+    """
+    realloc() statistics identify the callstack growing a buffer.
+    """
+    output_dir = profile(TEST_SCRIPTS / "realloc_growth.py")
+    [reallocs_path] = glob(str(output_dir / "*" / "reallocs.json"))
+    with open(reallocs_path) as f:
+        reallocs = json.load(f)
+    top = reallocs["callstacks"][0]
+    assert "(grow_buffer)" in top["callstack"]
+    assert top["reallocs"] == 16
+    assert top["grew"] == 16
+    assert top["shrank"] == 0
+    assert top["bytes_grown"] == 64 * 1024 * 1024 - 1024
+    with open(Path(reallocs_path).parent / "reallocs.txt") as f:
+        assert "grow_buffer" in f.read()
+
+
+def test_thread_peaks():
+    """
+    Per-thread statistics include threads that exited before the dump.
+    """
+    output_dir = profile(TEST_SCRIPTS / "thread_peaks.py")
+    [threads_path] = glob(str(output_dir / "*" / "threads.json"))
+    with open(threads_path) as f:
+        report = json.load(f)
+    workers = [
+        thread
+        for thread in report["threads"]
+        if thread["exited"] and thread["peak_bytes"] >= 50_000_000
+    ]
+    assert len(workers) == 3
+    for worker in workers:
+        assert worker["current_bytes"] < 1_000_000
+    # The main thread plus the three workers:
+    assert report["max_live_threads"] >= 4
+    with open(Path(threads_path).parent / "threads.txt") as f:
+        assert "(exited)" in f.read()
+
+
+def test_environment():
+    """
+    Reports record the environment they were made in, and user tags.
+    """
+    output_dir = profile(TEST_SCRIPTS / "environment.py")
+    [metadata_path] = glob(str(output_dir / "*" / "metadata.json"))
+    with open(metadata_path) as f:
+        environment = json.load(f)["environment"]
+    assert environment["python_version"].split()[0] == sys.version.split()[0]
+    assert environment["user"] == {"git_commit": "abc123"}
+    assert "environment.py" in " ".join(environment["command_line"])
+    assert environment["cpu_count"] >= 1
+    report_dir = Path(metadata_path).parent
+    with open(report_dir / "peak-memory.prof") as f:
+        assert '# user={"git_commit":"abc123"}\n' in f.readlines()
+    with open(report_dir / "peak-memory.svg") as f:
+        assert "<!-- Fil environment: " in f.read()
+    with open(report_dir / "index.html") as f:
+        assert "abc123" in f.read()
+
+
+def test_free_tracking():
+    """
+    With free tracking turned on, the report says where memory was freed.
+    """
+    output_dir = profile(TEST_SCRIPTS / "frees.py")
+    [frees_path] = glob(str(output_dir / "*" / "frees.prof"))
+    freed_by_consumer = sum(
+        size_kb
+        for (callstack, size_kb) in get_allocations(
+            Path(frees_path), direct=True
+        ).items()
+        if callstack[-1][1] == "consumer"
+    )
+    # Only the first round of frees was recorded:
+    assert freed_by_consumer == pytest.approx(20_000_000 / 1024, 0.01)
+    with open(Path(frees_path).parent / "frees-pairs.json") as f:
+        top_pair = json.load(f)["pairs"][0]
+    assert "(loader)" in top_pair["allocated_by"]
+    assert "(consumer)" in top_pair["freed_by"]
+    assert top_pair["bytes"] == 20_000_000
+    assert (Path(frees_path).parent / "frees.svg").exists()
+
+
+def test_phases():
+    """
+    Allocations are tagged with the phase they happened in: imports, main, and
+    phases marked with the API.
+    """
+
+    def phase_mb(output_dir):
+        [metadata_path] = glob(str(output_dir / "*" / "metadata.json"))
+        with open(metadata_path) as f:
+            phases = json.load(f)["phases"]
+        return {phase["name"]: phase["peak_bytes"] / 1_000_000 for phase in phases}
+
+    def peak_lines(output_dir):
+        [peak_path] = glob(str(output_dir / "*" / "peak-memory.prof"))
+        with open(peak_path) as f:
+            return f.read().splitlines()
+
+    output_dir = profile(TEST_SCRIPTS / "phases.py")
+    mb = phase_mb(output_dir)
+    assert mb["imports"] == pytest.approx(30, 0.1)
+    assert mb["main"] == pytest.approx(20, 0.1)
+    assert mb["loading"] == pytest.approx(10, 0.1)
+    with open(Path(glob(str(output_dir / "*" / "index.html"))[0])) as f:
+        assert "Memory by phase" in f.read()
+    assert not any(line.startswith("[phase: ") for line in peak_lines(output_dir))
+
+    # Phases can be shown as a root frame:
+    env = os.environ.copy()
+    env["FIL_PHASE_FRAMES"] = "1"
+    lines = peak_lines(profile(TEST_SCRIPTS / "phases.py", env=env))
+    assert any(
+        line.startswith("[phase: imports];") and "phase_import_helper.py" in line
+        for line in lines
+    )
+    assert any(line.startswith("[phase: loading];") for line in lines)
+
+    # Or left out of the flamegraph, while still counting in the totals:
+    env = os.environ.copy()
+    env["FIL_EXCLUDE_PHASES"] = "imports"
+    output_dir = profile(TEST_SCRIPTS / "phases.py", env=env)
+    assert not any("phase_import_helper.py" in line for line in peak_lines(output_dir))
+    assert phase_mb(output_dir)["imports"] == pytest.approx(30, 0.1)
+
+
+def test_phase_frames():
+    """
+    Phases pushed with the context manager show up as root frames, and the
+    report says which phase the peak was in.
+    """
+    output_dir = profile(TEST_SCRIPTS / "phase_frames.py")
+    [peak_path] = glob(str(output_dir / "*" / "peak-memory.prof"))
+    with open(peak_path) as f:
+        lines = f.read().splitlines()
+    assert any(
+        line.startswith("[phase: load data];") and "(load)" in line for line in lines
+    )
+    assert any(
+        line.startswith("[phase: train];[phase: epoch 3];") and "(train)" in line
+        for line in lines
+    )
+    [metadata_path] = glob(str(output_dir / "*" / "metadata.json"))
+    with open(metadata_path) as f:
+        assert json.load(f)["peak_phase_frames"] == ["train", "epoch 3"]
+
+
+def test_object_counts():
+    """
+    Tracked Python objects are counted per callstack and type while they're
+    alive.
+    """
+    output_dir = profile(TEST_SCRIPTS / "objects.py")
+    [objects_path] = glob(str(output_dir / "*" / "objects.json"))
+    with open(objects_path) as f:
+        objects = json.load(f)
+    assert objects["total_objects"] == 110
+    types = {entry["type"]: entry["count"] for entry in objects["types"]}
+    assert types == {"__main__.Leaky": 100, "__main__.Node": 10}
+    by_function = {}
+    for entry in objects["callstacks"]:
+        # The API's own frames are left out:
+        assert "filprofiler" not in entry["callstack"]
+        function = entry["callstack"].split(";")[-1]
+        by_function[function] = entry["count"]
+    assert by_function == {
+        "{}:21 (leak)".format(TEST_SCRIPTS / "objects.py"): 100,
+        "{}:32 (kept)".format(TEST_SCRIPTS / "objects.py"): 10,
+    }
+    assert (Path(objects_path).parent / "objects.svg").exists()
+
+
+def test_peak_functions_table():
+    """
+    peak-functions.tsv lists self and inclusive bytes at peak for each line,
+    sorted by inclusive bytes.
+    """
+    output_dir = profile(TEST_SCRIPTS / "phase_frames.py")
+    [table_path] = glob(str(output_dir / "*" / "peak-functions.tsv"))
+    with open(table_path) as f:
+        lines = f.read().splitlines()
+    assert lines[0].split("\t") == [
+        "inclusive_bytes",
+        "self_bytes",
+        "filename",
+        "function",
+        "line",
+    ]
+    rows = [line.split("\t") for line in lines[1:]]
+    inclusive = [int(row[0]) for row in rows]
+    assert inclusive == sorted(inclusive, reverse=True)
+    by_function = {(row[3], row[4]): (int(row[0]), int(row[1])) for row in rows}
+    (train_inclusive, train_self) = by_function[("train", "17")]
+    assert train_self == train_inclusive == pytest.approx(30_000_000, 0.01)
+    (module_inclusive, module_self) = by_function[("<module>", "24")]
+    assert module_inclusive == pytest.approx(30_000_000, 0.01)
+    assert module_self == 0
+
+
+def test_small_allocations():
+    """
+    With FIL_SMALL_ALLOCATIONS set, allocations smaller than that are counted
+    under a single synthetic callstack, while larger ones keep their callstack.
+    """
+    env = os.environ.copy()
+    env["FIL_SMALL_ALLOCATIONS"] = "1024"
+    output_dir = profile(TEST_SCRIPTS / "small_allocations.py", env=env)
+    [prof_path] = glob(str(output_dir / "*" / "peak-memory.prof"))
+    by_callstack = {}
+    with open(prof_path) as f:
+        for line in f:
+            callstack, size = line.rsplit(" ", 1)
+            by_callstack[callstack] = int(size)
+    # ctypes does some small allocations of its own:
+    assert by_callstack["[small allocations < 1 KiB]"] >= 500 * 20_000
+    assert by_callstack["[small allocations < 1 KiB]"] < 500 * 20_000 * 1.1
+    [large_size] = [
+        size for (callstack, size) in by_callstack.items() if "(large)" in callstack
+    ]
+    assert large_size == 20_000_000
+    # Only the list holding the pointers is left for small():
+    small_bytes = sum(
+        size for (callstack, size) in by_callstack.items() if "(small)" in callstack
+    )
+    assert small_bytes < 500_000
+
+
+def test_lifetimes():
+    """
+    With FIL_LIFETIMES=1, allocation lifetimes are reported per callstack.
+    """
+    env = os.environ.copy()
+    env["FIL_LIFETIMES"] = "1"
+    output_dir = profile(TEST_SCRIPTS / "lifetimes.py", env=env)
+    [lifetimes_path] = glob(str(output_dir / "*" / "lifetimes.json"))
+    with open(lifetimes_path) as f:
+        lifetimes = json.load(f)
+    by_function = {}
+    for callstack in lifetimes["callstacks"]:
+        for function in ["scratch", "long_lived"]:
+            if f"({function})" in callstack["callstack"]:
+                by_function[function] = callstack
+    # ctypes makes some small allocations of its own on the same lines, so
+    # the numbers aren't exact:
+    scratch = by_function["scratch"]
+    assert scratch["allocated_bytes"] >= 100 * 100_000
+    assert scratch["never_freed_fraction"] < 0.1
+    assert scratch["median_lifetime"] in ("<1ms", "<10ms")
+    long_lived = by_function["long_lived"]
+    assert long_lived["allocated_bytes"] >= 10 * 1_000_000
+    assert long_lived["never_freed_fraction"] > 0.2
+    assert long_lived["median_lifetime"] == "<1s"
+    assert (Path(lifetimes_path).parent / "lifetimes.txt").exists()
+
+    # Off by default:
+    output_dir = profile(TEST_SCRIPTS / "lifetimes.py")
+    assert not glob(str(output_dir / "*" / "lifetimes.json"))
+
+
+def test_timeline():
+    """
+    With FIL_TIMELINE=1, memory over time is reported per top-level frame.
+    """
+    env = os.environ.copy()
+    env["FIL_TIMELINE"] = "1"
+    env["FIL_TIMELINE_INTERVAL_MS"] = "20"
+    output_dir = profile(TEST_SCRIPTS / "timeline.py", env=env)
+    [timeline_path] = glob(str(output_dir / "*" / "timeline.json"))
+    with open(timeline_path) as f:
+        timeline = json.load(f)
+    max_bytes = {
+        root: max(sample["bytes"][i] for sample in timeline["samples"])
+        for (i, root) in enumerate(timeline["roots"])
+    }
+    [first_phase] = [r for r in max_bytes if r.endswith("timeline.py:18 (<module>)")]
+    [second_phase] = [r for r in max_bytes if r.endswith("timeline.py:19 (<module>)")]
+    assert max_bytes[first_phase] == pytest.approx(10_000_000, 0.01)
+    assert max_bytes[second_phase] == pytest.approx(30_000_000, 0.01)
+    # The two phases don't overlap, give or take small leftovers:
+    first, second = (
+        timeline["roots"].index(first_phase),
+        timeline["roots"].index(second_phase),
+    )
+    for sample in timeline["samples"]:
+        assert min(sample["bytes"][first], sample["bytes"][second]) < 100_000
+    assert (Path(timeline_path).parent / "timeline.html").exists()
+
+    # Off by default:
+    output_dir = profile(TEST_SCRIPTS / "timeline.py")
+    assert not glob(str(output_dir / "*" / "timeline.json"))
+
+
+def test_allocation_rates():
+    """
+    With FIL_ALLOCATION_RATES=1, the callstacks that allocate most often are
+    reported.
+    """
+    env = os.environ.copy()
+    env["FIL_ALLOCATION_RATES"] = "1"
+    env["FIL_ALLOCATION_RATES_INTERVAL_MS"] = "50"
+    output_dir = profile(TEST_SCRIPTS / "allocation_rates.py", env=env)
+    [rates_path] = glob(str(output_dir / "*" / "allocation-rates.json"))
+    with open(rates_path) as f:
+        rates = json.load(f)
+    top = rates["callstacks"][0]
+    assert "(storm)" in top["callstack"]
+    assert top["calls"] >= 100_000
+    assert top["peak_per_second"] > top["average_per_second"]
+    assert rates["total_calls"] >= top["calls"]
+    report_dir = Path(rates_path).parent
+    assert (report_dir / "allocation-rates.svg").exists()
+    with open(report_dir / "allocation-rates.txt") as f:
+        assert "storm" in f.read()
+
+    # Off by default:
+    output_dir = profile(TEST_SCRIPTS / "allocation_rates.py")
+    assert not glob(str(output_dir / "*" / "allocation-rates.json"))
+
+
+def test_crash_handler():
+    """
+    With FIL_CRASH_HANDLER=1, a segfault still leaves behind the last peak
+    snapshot, including when temporary files are tracked, which wraps the
+    open() the handler uses.
+    """
+    for temp_files in ["0", "1"]:
+        env = os.environ.copy()
+        env["FIL_CRASH_HANDLER"] = "1"
+        env["FIL_TRACK_TEMP_FILES"] = temp_files
+        output_dir = profile(
+            TEST_SCRIPTS / "crash.py",
+            expect_exit_code=-signal.SIGSEGV,
+            env=env,
+            # The handler mustn't deadlock:
+            timeout=60,
+        )
+        [crash_path] = glob(str(output_dir / "*" / "crash-peak-memory.prof"))
+        big_kb = sum(
+            size_kb
+            for (callstack, size_kb) in get_allocations(
+                Path(crash_path), direct=True
+            ).items()
+            # Skip "[No Python stack]":
+            if isinstance(callstack, tuple) and callstack[-1][1] == "big"
+        )
+        assert big_kb == pytest.approx(50_000_000 / 1024, 0.01)
+
+
 def test_source_rendering():
     """
     Minimal tests that SVGs aren't completely broken in some edge cases, and
@@ -643,12 +1629,15 @@ def test_sigusr2():
     # shutdown.
     assert len(list(output_dir.iterdir())) == 2
 
-    sigusr2, final = sorted(output_dir.glob("*/peak-memory.prof"))
+    # The SIGUSR2 dump gets an automatically generated name:
+    [sigusr2] = output_dir.glob("fil-peak-*-pid*/peak-memory.prof")
+    [final] = set(output_dir.glob("*/peak-memory.prof")) - {sigusr2}
 
     # SIGUSR2 dump only has allocations up to that point
     script = str(script)
-    path1 = ((script, "<module>", 8), (numpy._core.numeric.__file__, "ones", ANY))
-    path2 = ((script, "<module>", 11), (numpy._core.numeric.__file__, "ones", ANY))
+    ones = (numpy._core.numeric.__file__, "numpy._core.numeric.ones", ANY)
+    path1 = ((script, "<module>", 8), ones)
+    path2 = ((script, "<module>", 11), ones)
 
     allocations_sigusr2 = get_allocations(sigusr2, direct=True)
     assert match(allocations_sigusr2, {path1: big}, as_mb) == pytest.approx(20, 0.1)
@@ -658,3 +1647,360 @@ def test_sigusr2():
     allocations_final = get_allocations(final, direct=True)
     assert match(allocations_final, {path1: big}, as_mb) == pytest.approx(20, 0.1)
     assert match(allocations_final, {path2: big}, as_mb) == pytest.approx(50, 0.1)
+
+
+def test_shortened_frame_names(tmpdir):
+    """
+    With FIL_SHORTEN_PATHS=1 and FIL_COLLAPSE_INIT=1, frames from
+    site-packages are shown relative to it, and __init__.py as the package.
+    """
+    package = Path(tmpdir) / "lib" / "site-packages" / "fakepkg"
+    package.mkdir(parents=True)
+    (package / "__init__.py").write_text(
+        "def allocate():\n    return bytearray(30 * 1024 * 1024)\n"
+    )
+    script = Path(tmpdir) / "script.py"
+    script.write_text(
+        "import sys\n"
+        "sys.path.insert(0, {!r})\n"
+        "import fakepkg\n"
+        "data = fakepkg.allocate()\n".format(str(package.parent))
+    )
+    env = dict(os.environ, FIL_SHORTEN_PATHS="1", FIL_COLLAPSE_INIT="1")
+    output_dir = profile(script, env=env)
+    allocations = get_allocations(output_dir)
+    [callstack] = [
+        callstack for callstack, size in allocations.items() if size > 29 * 1024
+    ]
+    assert callstack[-1] == ("<site>/fakepkg", "allocate", 2)
+
+
+def test_output_formats():
+    """
+    FIL_OUTPUT_FORMATS picks the extra report formats; the SVGs are always
+    written.
+    """
+    env = os.environ.copy()
+    env["FIL_OUTPUT_FORMATS"] = "svg,json"
+    output_dir = profile(TEST_SCRIPTS / "many_callstacks.py", env=env)
+    [report_dir] = output_dir.iterdir()
+    assert (report_dir / "peak-memory.svg").exists()
+    with open(report_dir / "peak.json") as f:
+        assert json.load(f)["format_version"] == 1
+    assert not (report_dir / "peak.arrow").exists()
+
+
+def test_arrow_report():
+    """
+    With FIL_OUTPUT_FORMATS=json,arrow, peak.arrow can be read by pyarrow, and
+    has the same callstacks as peak.json.
+    """
+    pyarrow_ipc = pytest.importorskip("pyarrow.ipc")
+    env = os.environ.copy()
+    env["FIL_OUTPUT_FORMATS"] = "json,arrow"
+    output_dir = profile(TEST_SCRIPTS / "many_callstacks.py", env=env)
+    [report_dir] = output_dir.iterdir()
+    arrow_path = report_dir / "peak.arrow"
+    if not arrow_path.exists():
+        pytest.skip("Fil was built without the arrow-export feature")
+
+    table = pyarrow_ipc.open_file(str(arrow_path)).read_all()
+    assert table.column_names == [
+        "callstack",
+        "bytes",
+        "allocation_count",
+        "thread_id",
+        "phase",
+    ]
+
+    def frame_name(frame):
+        if frame["line"] is not None:
+            return "{}:{} ({})".format(frame["file"], frame["line"], frame["function"])
+        elif frame["file"] == "":
+            return frame["function"]
+        else:
+            return "{} ({})".format(frame["file"], frame["function"])
+
+    from_arrow = sorted(
+        (
+            ([f"[phase: {row['phase']}]"] if row["phase"] is not None else [])
+            + [frame_name(frame) for frame in row["callstack"]],
+            row["bytes"],
+        )
+        for row in table.to_pylist()
+    )
+    with open(report_dir / "peak.json") as f:
+        from_json = sorted(
+            (callstack["frames"], callstack["bytes"])
+            for callstack in json.load(f)["callstacks"]
+        )
+    assert from_arrow == from_json
+    # Most of the 3000 functions, with the smallest callstacks left out:
+    assert len(from_arrow) > 2500
+
+    # Each of the 3000 functions has a live allocation, and the script is
+    # single-threaded:
+    rows = table.to_pylist()
+    assert None not in {row["allocation_count"] for row in rows}
+    assert sum(row["allocation_count"] for row in rows) > 3000
+    [thread_id] = {row["thread_id"] for row in rows}
+    assert thread_id is not None
+
+
+def test_temp_files():
+    """
+    With FIL_TRACK_TEMP_FILES=1, unlinked temporary files are reported
+    separately from the peak, with the callstacks that created them.
+    """
+    env = os.environ.copy()
+    env["FIL_TRACK_TEMP_FILES"] = "1"
+    output_dir = profile(TEST_SCRIPTS / "temp_files.py", env=env)
+    [metadata_path] = glob(str(output_dir / "*" / "metadata.json"))
+    with open(metadata_path) as f:
+        metadata = json.load(f)
+    temp_files = metadata["temp_files"]
+    # The 100MiB file was never unlinked while open, and the last 30MiB one
+    # was created after the others were closed:
+    assert temp_files["peak_bytes"] == 50 * 1024 * 1024
+    callstacks = [
+        (callstack["callstack"], callstack["bytes"])
+        for callstack in temp_files["callstacks"]
+    ]
+    assert len(callstacks) == 2
+    assert "(spill)" in callstacks[0][0] and callstacks[0][1] == 30 * 1024 * 1024
+    assert "(anonymous)" in callstacks[1][0] and callstacks[1][1] == 20 * 1024 * 1024
+
+    # Without the setting they're not tracked:
+    output_dir = profile(TEST_SCRIPTS / "temp_files.py")
+    [metadata_path] = glob(str(output_dir / "*" / "metadata.json"))
+    with open(metadata_path) as f:
+        assert json.load(f)["temp_files"] is None
+
+
+@pytest.mark.skipif(
+    not sys.platform.startswith("linux"),
+    reason="Bypasses Fil using glibc's __libc_malloc()",
+)
+def test_drift_check():
+    """
+    With FIL_DRIFT_CHECK=1, memory allocated without Fil seeing it produces a
+    warning in the report metadata.
+    """
+    env = os.environ.copy()
+    env["FIL_DRIFT_CHECK"] = "1"
+    env["FIL_DRIFT_INTERVAL_MS"] = "100"
+    env["FIL_DRIFT_SAMPLES"] = "2"
+    script = TEST_SCRIPTS / "drift.py"
+    output_dir = profile(script, "untracked", env=env)
+    [metadata_path] = glob(str(output_dir / "*" / "metadata.json"))
+    with open(metadata_path) as f:
+        drift = json.load(f)["drift"]
+    assert drift["settings"] == {"ratio": 0.5, "samples": 2}
+    assert drift["max_divergence_ratio"] > 0.5
+    sample = drift["warning"]["sample"]
+    assert sample["divergence_bytes"] > 250 * 1024 * 1024
+
+    # Tracked allocations don't count as drift:
+    output_dir = profile(script, env=env)
+    [metadata_path] = glob(str(output_dir / "*" / "metadata.json"))
+    with open(metadata_path) as f:
+        drift = json.load(f)["drift"]
+    assert drift["samples"] > 0
+    assert drift["warning"] is None
+
+
+def test_live_view():
+    """
+    With FIL_LIVE_VIEW=1, current memory usage is written to live/live.json
+    while the program runs, along with a page that shows it.
+    """
+    env = os.environ.copy()
+    env["FIL_LIVE_VIEW"] = "1"
+    env["FIL_LIVE_VIEW_INTERVAL_MS"] = "100"
+    output_dir = profile(TEST_SCRIPTS / "live_view.py", env=env)
+    live_dir = output_dir / "live"
+    assert "live.json" in (live_dir / "index.html").read_text()
+    with open(live_dir / "live.json") as f:
+        live = json.load(f)
+    assert live["format_version"] == 1
+    assert live["sequence"] >= 1
+    [callstack] = [
+        callstack
+        for callstack in live["callstacks"]
+        if "live_view.py:7 (hold)" in callstack["callstack"]
+    ]
+    assert callstack["bytes"] > 50 * 1024 * 1024 * 0.99
+
+
+def test_milestones():
+    """
+    With FIL_MILESTONES, a flamegraph is written the first time tracked memory
+    goes over each size, one per size even if a single allocation crosses
+    several.
+    """
+    env = os.environ.copy()
+    env["FIL_MILESTONES"] = "30MB,60MB,100MB"
+    output_dir = profile(TEST_SCRIPTS / "milestones.py", env=env)
+    with open(glob(str(output_dir / "*" / "metadata.json"))[0]) as f:
+        milestones = json.load(f)["milestones"]
+    assert [milestone["label"] for milestone in milestones] == ["30MB", "60MB", "100MB"]
+    script = str(TEST_SCRIPTS / "milestones.py")
+    small = ((script, "<module>", 12), (script, "small", 5))
+    big_ = ((script, "<module>", 13), (script, "big", 9))
+    for milestone, expected in [
+        ("30MB", {small}),
+        ("60MB", {small, big_}),
+        ("100MB", {small, big_}),
+    ]:
+        prof_path = output_dir / "*" / "milestone-{}.prof".format(milestone)
+        [prof_path] = glob(str(prof_path))
+        allocations = get_allocations(prof_path, direct=True)
+        assert {
+            path for path, size in allocations.items() if size > 1024
+        } == expected, milestone
+    # The milestone flamegraphs are in the directory tracking started with,
+    # and the report links to them:
+    index = glob(str(output_dir / "*" / "index.html"))
+    assert sum("milestone-60MB.svg" in Path(path).read_text() for path in index) == 1
+
+
+def test_huge_sizes():
+    """
+    Allocations too big to be real, like a calloc() whose size overflows, fail
+    without being taken for running out of memory, and don't affect the peak.
+    """
+    output_dir, stdout = profile_with_stdout(TEST_SCRIPTS / "huge_sizes.py")
+    # All three returned NULL:
+    assert stdout == "[None, None, None]\n"
+    [metadata_path] = glob(str(output_dir / "*" / "metadata.json"))
+    with open(metadata_path) as f:
+        rejected = json.load(f)["rejected_allocations"]
+    assert rejected["count"] == 3
+    assert rejected["largest_bytes"] == 2**64 - 1
+    sizes = {}
+    with open(Path(metadata_path).parent / "peak-memory.prof") as f:
+        for line in f:
+            if line.startswith("# "):
+                continue
+            callstack, size = line.rsplit(" ", 1)
+            if int(size) > 64 * 1024:
+                sizes[callstack] = int(size)
+    script = str(TEST_SCRIPTS / "huge_sizes.py")
+    assert sizes == {
+        # Put back by the failed realloc(), so it's under its callstack:
+        "{}:23 (<module>)".format(script): 1024 * 1024,
+        "{}:24 (<module>)".format(script): pytest.approx(20 * 1024 * 1024, 0.01),
+    }
+
+
+def test_rotation():
+    """
+    With FIL_ROTATE_WINDOW, each window's peak is written out, only the last
+    FIL_ROTATE_KEEP are kept, and memory that's still allocated when a window
+    starts is carried over into it.
+    """
+    env = os.environ.copy()
+    env["FIL_ROTATE_WINDOW"] = "0.5"
+    env["FIL_ROTATE_KEEP"] = "2"
+    output_dir = profile(TEST_SCRIPTS / "rotation.py", env=env)
+    [metadata_path] = glob(str(output_dir / "*" / "metadata.json"))
+    with open(metadata_path) as f:
+        rotation = json.load(f)["rotation"]
+    assert rotation["current"] >= 4
+    assert len(rotation["windows"]) == 2
+    for window in rotation["windows"]:
+        assert os.path.exists(window["path"])
+    directory = Path(rotation["windows"][0]["path"]).parent
+    assert not (directory / "window-1.svg").exists()
+    assert not (directory / "window-1.prof").exists()
+
+    # The long-lived allocation is carried over into later windows, as it is
+    # into the one the final report is of:
+    last = Path(rotation["windows"][-1]["path"]).with_suffix(".prof")
+    for prof_path in [last, Path(metadata_path).parent / "peak-memory.prof"]:
+        with open(prof_path) as f:
+            [line] = [line for line in f if "rotation.py:10 (<module>)" in line]
+        callstack, size = line.rsplit(" ", 1)
+        assert callstack.startswith("[carried over];")
+        assert int(size) / (1024 * 1024) == pytest.approx(30, 0.1)
+    with open(Path(metadata_path).parent / "index.html") as f:
+        assert "window-{}.svg".format(rotation["windows"][-1]["number"]) in f.read()
+
+
+def test_api_module():
+    """
+    The installed _filprofiler_api module profiles without LD_PRELOAD, with
+    the hooks called via their C entry points.
+    """
+    output_dir = Path(mkdtemp())
+    check_call([sys.executable, str(TEST_SCRIPTS / "api_module.py"), output_dir])
+    with open(output_dir / "peak-memory.prof") as f:
+        lines = sorted(line.strip() for line in f if not line.startswith("# "))
+    assert lines == [
+        "outer.py:4 (outer) 3000",
+        "outer.py:5 (outer);inner.py:8 (inner) 2000",
+    ]
+    assert (output_dir / "peak-memory.svg").exists()
+
+
+@pytest.mark.skipif(
+    not sys.platform.startswith("linux"),
+    reason="The checks are Linux-specific",
+)
+def test_bootstrap_failures():
+    """
+    If the environment breaks what interposition relies on, Fil passes
+    everything through and says why, rather than crashing. The failures are
+    simulated with __FIL_SIMULATE_BOOTSTRAP_FAILURE.
+    """
+    from filprofiler._utils import library_path
+
+    script = str(TEST_SCRIPTS / "bootstrap_fallback.py")
+    for failure, reason in [
+        ("namespace", "separate linker namespace"),
+        ("tls", "thread-local storage"),
+    ]:
+        env = os.environ.copy()
+        env["__FIL_SIMULATE_BOOTSTRAP_FAILURE"] = failure
+        # Profiling refuses to start, rather than silently recording nothing:
+        result = run(
+            ["fil-profile", "-o", mkdtemp(), "run", script],
+            env=env,
+            stdout=PIPE,
+            stderr=PIPE,
+        )
+        assert result.returncode == 1
+        stderr = result.stderr.decode("utf-8")
+        assert reason in stderr
+        assert "_filprofiler_api" in stderr
+
+        # An embedder that preloads us still works, untracked:
+        env["LD_PRELOAD"] = library_path("_filpreload")
+        result = run([sys.executable, script], env=env, stderr=PIPE)
+        assert result.returncode == 0
+        assert reason in result.stderr.decode("utf-8")
+
+    # A Python whose C API isn't visible is caught before preloading:
+    env = os.environ.copy()
+    env["__FIL_SIMULATE_BOOTSTRAP_FAILURE"] = "python_symbols"
+    result = run(
+        ["fil-profile", "-o", mkdtemp(), "run", script], env=env, stderr=PIPE
+    )
+    assert result.returncode == 1
+    assert b"statically linked interpreter" in result.stderr
+    assert b"dynamically linked Python" in result.stderr
+
+    # A broken dlsym(RTLD_NEXT) is worked around, and profiling still works:
+    env["__FIL_SIMULATE_BOOTSTRAP_FAILURE"] = "rtld_next"
+    output_dir = Path(mkdtemp())
+    script = str(TEST_SCRIPTS / "live_view.py")
+    result = run(
+        ["fil-profile", "-o", str(output_dir), "--no-browser", "run", script],
+        env=env,
+        stderr=PIPE,
+    )
+    assert result.returncode == 0
+    assert b"looking up functions in libc directly" in result.stderr
+    allocations = get_allocations(output_dir)
+    path = ((script, "<module>", 12), (script, "hold", 7))
+    assert match(allocations, {path: big}, as_mb) == pytest.approx(50, 0.1)
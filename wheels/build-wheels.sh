@@ -16,6 +16,7 @@ mkdir -p dist
 rm -f filprofiler/_filpreload.o
 rm -f filprofiler/_filpreload*.so
 rm -f filprofiler/_filpreload*.dylib
+rm -f filprofiler/_filprofiler_api*.so
 rm -rf build
 
 for PYBIN in /opt/python/cp{39,310,311,312,313}*/bin; do